@@ -0,0 +1,250 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// compare `cargo bench` with `cargo bench --no-default-features`,
+// each effect counts as a byte, so MB/s reads as millions of effects per second
+
+#![feature(generators, generator_trait, test)]
+
+extern crate test;
+
+use std::ops::{Generator, GeneratorState};
+use either::Either;
+use test::Bencher;
+use aeiou::{
+    Block, Context, Effect, Select, IntoBlock, perform, scope, take_all,
+    tasks::{Request, TaskId, SpawnOptions, TaskFailed, HasTaskFailed},
+};
+
+const EFFECTS: usize = 10_000;
+
+#[derive(Debug)]
+enum Effects {
+    Next,
+}
+
+enum Outputs {
+    Char(u8),
+}
+
+impl Effect for Outputs {
+    type Input = Effects;
+}
+
+struct Char(u8);
+
+impl Select<Char> for Outputs {
+    fn take(output: &Context<Self>) -> Option<Char> {
+        match output.take()? {
+            Outputs::Char(c) => Some(Char(c)),
+        }
+    }
+}
+
+fn parser(context: Context<Outputs>) -> impl Unpin + Generator<Yield = Effects, Return = u64> {
+    move || {
+        scope!(&context, "parser");
+        let mut sum = 0;
+        for _ in 0..EFFECTS {
+            let Char(c) = perform!(Effects::Next, &context);
+            sum += u64::from(c);
+        }
+        sum
+    }
+}
+
+#[bench]
+fn effects_per_second(b: &mut Bencher) {
+    b.bytes = EFFECTS as u64;
+    b.iter(|| {
+        let mut c = 0u8;
+        parser
+            .into_block()
+            .add_handler(move |Effects::Next| {
+                c = c.wrapping_add(1);
+                Ok(Outputs::Char(c))
+            })
+            .assert_handled()
+            .run()
+    });
+}
+
+const READINGS: usize = 100_000;
+
+// yielded while there is nothing to read, the driver below just resumes
+#[derive(Debug)]
+enum Wait {
+    Idle,
+}
+
+enum Readings {
+    Reading(u8),
+}
+
+impl Effect for Readings {
+    type Input = Wait;
+}
+
+// the sensor delivers every round whatever it has
+fn sensor() -> impl FnMut() -> Option<Readings> {
+    let mut n = 0;
+    move || {
+        if n == READINGS {
+            return None;
+        }
+        n += 1;
+        Some(Readings::Reading(n as u8))
+    }
+}
+
+fn one_at_a_time(context: Context<Readings>) -> impl Unpin + Generator<Yield = Wait, Return = u64> {
+    move || {
+        let mut sum = 0;
+        for _ in 0..READINGS {
+            while context.is_empty() {
+                yield Wait::Idle;
+            }
+            let Readings::Reading(r) = context.take().unwrap();
+            sum += u64::from(r);
+            yield Wait::Idle;
+        }
+        sum
+    }
+}
+
+fn batched(context: Context<Readings>) -> impl Unpin + Generator<Yield = Wait, Return = u64> {
+    move || {
+        let mut sum = 0;
+        let mut left = READINGS;
+        while left != 0 {
+            for Readings::Reading(r) in take_all!(&context, Wait::Idle) {
+                sum += u64::from(r);
+                left -= 1;
+            }
+        }
+        sum
+    }
+}
+
+// returns the sum and how many times the computation was resumed
+fn drive<G>(block: Block<Readings, G>) -> (u64, usize)
+where
+    G: Unpin + Generator<Yield = Wait, Return = u64>,
+{
+    let mut block = block.add_source(sensor());
+    let mut resumes = 1;
+    loop {
+        match block.resume() {
+            GeneratorState::Complete(sum) => break (sum, resumes),
+            GeneratorState::Yielded(Wait::Idle) => resumes += 1,
+        }
+    }
+}
+
+#[bench]
+fn readings_one_at_a_time(b: &mut Bencher) {
+    b.bytes = READINGS as u64;
+    b.iter(|| {
+        let (sum, resumes) = drive(one_at_a_time.into_block());
+        assert_eq!(resumes, READINGS + 1);
+        sum
+    });
+}
+
+#[bench]
+fn readings_batched(b: &mut Bencher) {
+    b.bytes = READINGS as u64;
+    b.iter(|| {
+        let (sum, resumes) = drive(batched.into_block());
+        assert_eq!(resumes, 1);
+        sum
+    });
+}
+
+const TASKS: u32 = 10;
+const ROUNDS: u32 = 1000;
+
+// the tasks are spawned once, then every round is the same
+#[derive(Debug)]
+enum Voices {
+    Spawn(u32),
+    Mix(u32),
+    Tick,
+}
+
+enum Samples {
+    Mixed(u32),
+    Failed,
+}
+
+#[derive(Clone)]
+struct Voice(u32);
+
+impl TaskId for Voice {
+    type Id = u32;
+
+    fn task_id(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Request for Voices {
+    type Task = Voice;
+    type Effect = Voices;
+
+    fn is_task(self) -> Result<Voice, Self> {
+        match self {
+            Voices::Spawn(n) => Ok(Voice(n)),
+            e => Err(e),
+        }
+    }
+
+    fn is_effect(self) -> Result<Voices, Self> {
+        Ok(self)
+    }
+}
+
+impl HasTaskFailed<u32> for Samples {
+    fn task_failed(_: TaskFailed<u32>) -> Self {
+        Samples::Failed
+    }
+}
+
+fn voice(
+    Voice(n): Voice,
+    context: Context<Samples>,
+) -> impl Unpin + Generator<Yield = Either<Voices, Samples>, Return = Result<(), ()>> {
+    move || {
+        for _ in 0..ROUNDS / TASKS {
+            yield Either::Left(Voices::Mix(n));
+            context.take();
+            yield Either::Left(Voices::Tick);
+        }
+        Ok(())
+    }
+}
+
+#[bench]
+fn scheduler_steady_rounds(b: &mut Bencher) {
+    b.bytes = u64::from(ROUNDS);
+    b.iter(|| {
+        let mut block = (|_: Context<Samples>| {
+            move || {
+                for n in 0..TASKS {
+                    yield Voices::Spawn(n);
+                }
+            }
+        })
+        .into_block()
+        .spawn_supervised(SpawnOptions::default(), voice)
+        .add_handler_(|effect| match effect {
+            Voices::Mix(n) => Ok(Samples::Mixed(n)),
+            other => Err(other),
+        });
+        let mut rounds = 0;
+        while let GeneratorState::Yielded(Voices::Tick) = block.resume() {
+            rounds += 1;
+        }
+        assert_eq!(rounds, ROUNDS);
+    });
+}
@@ -0,0 +1,113 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// A host event loop owns the thread and calls back for every event, the
+// computation is driven from inside the callback by a reactor.
+
+#![feature(generators, generator_trait)]
+
+use std::ops::Generator;
+use aeiou::{Context, Effect, IntoBlock, reactor::Reactor};
+
+#[derive(Debug)]
+pub enum Effects {
+    NextEvent,
+    Draw(String),
+    SetTitle(String),
+}
+
+#[derive(Debug)]
+pub enum Outputs {
+    Key(char),
+    Backspace,
+    Enter,
+    Drawn,
+    TitleSet,
+}
+
+impl Effect for Outputs {
+    type Input = Effects;
+}
+
+fn prompt(
+    context: Context<Outputs>,
+) -> impl Unpin + Generator<(), Yield = Effects, Return = String> {
+    move || {
+        let mut line = String::new();
+        yield Effects::SetTitle("type a line".to_string());
+        loop {
+            match context.take() {
+                Some(Outputs::Key(key)) => line.push(key),
+                Some(Outputs::Backspace) => {
+                    line.pop();
+                },
+                Some(Outputs::Enter) => return line,
+                Some(Outputs::Drawn) | Some(Outputs::TitleSet) => continue,
+                None => {
+                    yield Effects::NextEvent;
+                    continue;
+                },
+            }
+            yield Effects::Draw(format!("> {}", line));
+        }
+    }
+}
+
+enum HostEvent {
+    Keyboard(Outputs),
+    // the window manager applied the title set earlier
+    TitleApplied,
+}
+
+// stands for `EventLoop::run`, it only returns when the events run out
+fn run_host<F>(mut callback: F)
+where
+    F: FnMut(HostEvent),
+{
+    let mut events = "heloo".chars().map(Outputs::Key).collect::<Vec<_>>();
+    events.extend([
+        Outputs::Backspace,
+        Outputs::Backspace,
+        Outputs::Key('o'),
+        Outputs::Enter,
+    ]);
+    let mut events = events
+        .into_iter()
+        .map(HostEvent::Keyboard)
+        .collect::<Vec<_>>();
+    events.insert(2, HostEvent::TitleApplied);
+    for event in events {
+        callback(event);
+    }
+}
+
+fn main() {
+    let reactor = Reactor::new(prompt.into_block());
+    let mut title = None;
+    run_host(|event| {
+        match event {
+            HostEvent::Keyboard(output) => reactor.on_event(output).unwrap(),
+            HostEvent::TitleApplied => {
+                if let Some(title) = title.take() {
+                    println!("title: {}", title);
+                    reactor.on_event(Outputs::TitleSet).unwrap();
+                }
+            },
+        }
+        reactor
+            .poll_effects(|effect| match effect {
+                Effects::NextEvent => Ok(None),
+                Effects::Draw(text) => {
+                    println!("{}", text);
+                    Ok(Some(Outputs::Drawn))
+                },
+                // the title is applied asynchronously, answered with `TitleApplied`
+                Effects::SetTitle(text) => {
+                    title = Some(text);
+                    Ok(None)
+                },
+            })
+            .unwrap();
+    });
+    println!("line: {:?}", reactor.take_result());
+}
@@ -0,0 +1,77 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![feature(generators)]
+
+use std::net::SocketAddr;
+use aeiou::{Effect, Handler, IntoBlock, state_machine};
+
+#[derive(Debug)]
+pub enum Effects {
+    Connect(SocketAddr),
+    SendHello(SocketAddr),
+}
+
+#[derive(Effect)]
+#[input(Effects)]
+pub enum Outputs {
+    Connected(SocketAddr),
+    Failed(String),
+    HelloAcked(u32),
+    Rejected,
+}
+
+pub struct FlakyNetwork {
+    failures: usize,
+}
+
+impl Handler<Outputs> for FlakyNetwork {
+    fn handle(&mut self, effect: Effects) -> Result<Outputs, Effects> {
+        match effect {
+            Effects::Connect(addr) => {
+                if self.failures > 0 {
+                    self.failures -= 1;
+                    println!("connecting to {}: refused", addr);
+                    Ok(Outputs::Failed("connection refused".to_string()))
+                } else {
+                    println!("connecting to {}: ok", addr);
+                    Ok(Outputs::Connected(addr))
+                }
+            },
+            Effects::SendHello(addr) if addr.port() == 8224 => Ok(Outputs::HelloAcked(3)),
+            Effects::SendHello(_) => Ok(Outputs::Rejected),
+        }
+    }
+}
+
+fn main() {
+    let addr: SocketAddr = ([127, 0, 0, 1], 8224).into();
+    let handshake = state_machine! {
+        Effects => Outputs;
+        let mut attempts = 0;
+        start Connecting;
+        state Connecting {
+            perform Connect(addr);
+            on Connected(peer) => Greeting(peer);
+            on Failed(_) if attempts < 3 => { attempts += 1; } Connecting;
+            on Failed(reason) => Error(reason);
+        }
+        state Greeting(peer: SocketAddr) {
+            perform SendHello(peer);
+            on HelloAcked(version) => Ready(peer, version);
+            on Rejected => Error("hello rejected".to_string());
+        }
+        terminal Ready(peer: SocketAddr, version: u32) => Ok((peer, version));
+        terminal Error(reason: String) => Err(reason);
+    };
+
+    let result = handshake
+        .into_block()
+        .add_handler(FlakyNetwork { failures: 2 })
+        .assert_handled()
+        .run();
+    match result {
+        Ok((peer, version)) => println!("ready: {} speaks version {}", peer, version),
+        Err(reason) => println!("handshake failed: {}", reason),
+    }
+}
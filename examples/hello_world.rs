@@ -10,7 +10,7 @@ use std::{
     thread,
     time::Duration,
 };
-use aeiou::{Select, Context, Effect, Handler, IntoBlock, perform};
+use aeiou::{Ack, Select, Context, Effect, Handler, IntoBlock, acking_handler, perform};
 
 #[derive(Debug)]
 pub enum Effects {
@@ -21,7 +21,7 @@ pub enum Effects {
     Print(String),
 }
 
-#[derive(Effect, Select)]
+#[derive(Effect, Select, Ack)]
 #[input(Effects)]
 pub enum EffectsOutput {
     #[part(AcceptedTcp)]
@@ -30,6 +30,7 @@ pub enum EffectsOutput {
     #[part(ReadTcp)]
     ReadTcp(String),
     WrittenTcp,
+    #[ack(Print)]
     Printed,
 }
 
@@ -83,15 +84,23 @@ impl Handler<EffectsOutput> for TcpHandler {
     }
 }
 
-fn main() {
-    let server = |context: Context<EffectsOutput>| {
-        move || {
-            let AcceptedTcp(addr) = perform!(Effects::ListenTcp(8224), &context);
-            let ReadTcp(data) = perform!(Effects::ReadTcp(addr), &context);
-            perform!(Effects::Print(data));
-        }
-    };
+#[aeiou::main(
+    effect = EffectsOutput,
+    handlers(TcpHandler::default(), acking_handler(print)),
+)]
+fn server(context: Context<EffectsOutput>) {
+    let AcceptedTcp(addr) = perform!(Effects::ListenTcp(8224), &context);
+    let ReadTcp(data) = perform!(Effects::ReadTcp(addr), &context);
+    perform!(Effects::Print(data));
+}
 
+fn print(effect: Effects) {
+    if let Effects::Print(msg) = effect {
+        std::io::stdout().write_all(msg.as_bytes()).unwrap();
+    }
+}
+
+fn main() {
     let client = |_: Context<EffectsOutput>| {
         move || {
             let addr = ([127, 0, 0, 1], 8224).into();
@@ -100,20 +109,7 @@ fn main() {
         }
     };
 
-    let server_thread = thread::spawn(move || {
-        server
-            .into_block()
-            .add_handler(TcpHandler::default())
-            .add_handler(|effect| match effect {
-                Effects::Print(msg) => {
-                    std::io::stdout().write(msg.as_bytes()).unwrap();
-                    Ok(EffectsOutput::Printed)
-                },
-                _ => Err(effect),
-            })
-            .assert_handled()
-            .run()
-    });
+    let server_thread = thread::spawn(server);
     thread::sleep(Duration::from_millis(10));
     client
         .into_block()
@@ -0,0 +1,380 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// A key-value store over line based commands:
+//
+//   GET <key>
+//   SET <key> <value> [EX <seconds>]
+//   QUIT
+//
+// The root computation accepts connections and spawns a task per connection,
+// the tasks talk to the store and the network only through effects.
+
+#![feature(generators, generator_trait, never_type)]
+
+use std::{
+    collections::BTreeMap,
+    fmt, iter,
+    ops::Generator,
+    time::{Duration, Instant},
+};
+use either::Either;
+use aeiou::{
+    Context, Effect, EffectName, Handler, HandlerSetRef, HasPriority, Select, IntoBlock, Trace,
+    perform, deadline,
+    plugin::{HandlerPlugin, PluginConfig, Registry},
+    tasks::{Request, TaskId, TaskFailed, HasTaskFailed, SpawnOptions},
+    sim::{ConnId, NetEffect, NetOutput, VirtualNet},
+    sources::{FrameDecoder, Framing},
+};
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Req {
+    Spawn(Connection),
+    Net(NetEffect),
+    Store(StoreEffect),
+}
+
+impl EffectName for Req {
+    fn effect_name(&self) -> &'static str {
+        match self {
+            Req::Spawn(_) => "Spawn",
+            Req::Net(_) => "Net",
+            Req::Store(_) => "Store",
+        }
+    }
+}
+
+impl HasPriority for Req {}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connection(pub ConnId);
+
+impl TaskId for Connection {
+    type Id = ConnId;
+
+    fn task_id(&self) -> ConnId {
+        self.0
+    }
+}
+
+impl Request for Req {
+    type Task = Connection;
+    type Effect = Req;
+
+    fn is_task(self) -> Result<Connection, Self> {
+        match self {
+            Req::Spawn(connection) => Ok(connection),
+            other => Err(other),
+        }
+    }
+
+    fn is_effect(self) -> Result<Req, Self> {
+        match self {
+            Req::Spawn(_) => Err(self),
+            other => Ok(other),
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreEffect {
+    Get(String),
+    Set {
+        key: String,
+        value: String,
+        ttl: Option<Duration>,
+    },
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreOutput {
+    Value(Option<String>),
+    Stored,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Effect, Select)]
+#[input(Req)]
+pub enum Output {
+    #[part(NetReply)]
+    Net(NetOutput),
+    #[part(StoreReply)]
+    Store(StoreOutput),
+    Failed(TaskFailed<ConnId>),
+}
+
+pub struct NetReply(pub NetOutput);
+
+pub struct StoreReply(pub StoreOutput);
+
+impl HasTaskFailed<ConnId> for Output {
+    fn task_failed(failed: TaskFailed<ConnId>) -> Self {
+        Output::Failed(failed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Get(String),
+    Set(String, String, Option<Duration>),
+    Quit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    NotUtf8,
+    Empty,
+    Unknown(String),
+    Arity(&'static str),
+    Ttl(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::NotUtf8 => write!(f, "command is not utf-8"),
+            ParseError::Empty => write!(f, "empty command"),
+            ParseError::Unknown(name) => write!(f, "unknown command `{}`", name),
+            ParseError::Arity(name) => write!(f, "wrong number of arguments for `{}`", name),
+            ParseError::Ttl(ttl) => write!(f, "invalid ttl `{}`", ttl),
+        }
+    }
+}
+
+impl Command {
+    pub fn parse(line: &[u8]) -> Result<Self, ParseError> {
+        let line = std::str::from_utf8(line).map_err(|_| ParseError::NotUtf8)?;
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            [] => Err(ParseError::Empty),
+            ["GET", key] => Ok(Command::Get(key.to_string())),
+            ["GET", ..] => Err(ParseError::Arity("GET")),
+            ["SET", key, value] => Ok(Command::Set(key.to_string(), value.to_string(), None)),
+            ["SET", key, value, "EX", seconds] => {
+                let seconds = seconds
+                    .parse()
+                    .map_err(|_| ParseError::Ttl(seconds.to_string()))?;
+                let ttl = Some(Duration::from_secs(seconds));
+                Ok(Command::Set(key.to_string(), value.to_string(), ttl))
+            },
+            ["SET", ..] => Err(ParseError::Arity("SET")),
+            ["QUIT"] => Ok(Command::Quit),
+            ["QUIT", ..] => Err(ParseError::Arity("QUIT")),
+            [name, ..] => Err(ParseError::Unknown(name.to_string())),
+        }
+    }
+}
+
+struct Entry {
+    value: String,
+    expires: Option<Instant>,
+}
+
+// Owns the map, the entries past their ttl are dropped before every effect.
+#[derive(Default)]
+pub struct Store {
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Store {
+    pub fn handle(&mut self, effect: StoreEffect) -> StoreOutput {
+        self.expire();
+        match effect {
+            StoreEffect::Get(key) => {
+                StoreOutput::Value(self.entries.get(&key).map(|e| e.value.clone()))
+            },
+            StoreEffect::Set { key, value, ttl } => {
+                let expires = ttl.map(|ttl| deadline::now() + ttl);
+                self.entries.insert(key, Entry { value, expires });
+                StoreOutput::Stored
+            },
+        }
+    }
+
+    pub fn expire(&mut self) {
+        let now = deadline::now();
+        self.entries
+            .retain(|_, entry| entry.expires.map_or(true, |expires| expires > now));
+    }
+}
+
+// the network answered a connection effect with something meant for another one
+#[derive(Debug)]
+pub struct Unexpected(pub NetOutput);
+
+pub fn server(context: Context<Output>) -> impl Unpin + Generator<(), Yield = Req, Return = ()> {
+    move || loop {
+        yield Req::Net(NetEffect::Accept);
+        // a failed task reports to this context too, it is not the answer
+        match context.take_if(|output| matches!(output, Output::Net(_))) {
+            Some(Output::Net(NetOutput::Accepted(id))) => yield Req::Spawn(Connection(id)),
+            _ => break,
+        }
+    }
+}
+
+pub fn connection(
+    Connection(id): Connection,
+    context: Context<Output>,
+) -> impl Unpin + Generator<(), Yield = Either<Req, Output>, Return = Result<(), Unexpected>> {
+    move || {
+        let mut decoder = FrameDecoder::new(Framing::Lines);
+        loop {
+            let NetReply(reply) = perform!(Either::Left(Req::Net(NetEffect::Read(id))), &context);
+            let (frames, last) = match reply {
+                NetOutput::Data(bytes) => {
+                    decoder.push(&bytes);
+                    (iter::from_fn(|| decoder.next_frame()).collect(), false)
+                },
+                NetOutput::Pending => continue,
+                // a line without the newline at the end is still a command
+                NetOutput::Closed => (
+                    decoder
+                        .finish()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .collect::<Vec<_>>(),
+                    true,
+                ),
+                other => return Err(Unexpected(other)),
+            };
+            let mut quit = false;
+            for frame in frames {
+                let reply = match Command::parse(&frame) {
+                    Ok(Command::Get(key)) => {
+                        let effect = Req::Store(StoreEffect::Get(key));
+                        match perform!(Either::Left(effect), &context) {
+                            StoreReply(StoreOutput::Value(Some(value))) => {
+                                format!("VALUE {}\n", value)
+                            },
+                            _ => "NIL\n".to_string(),
+                        }
+                    },
+                    Ok(Command::Set(key, value, ttl)) => {
+                        let effect = Req::Store(StoreEffect::Set { key, value, ttl });
+                        let StoreReply(_) = perform!(Either::Left(effect), &context);
+                        "OK\n".to_string()
+                    },
+                    Ok(Command::Quit) => {
+                        quit = true;
+                        break;
+                    },
+                    Err(error) => format!("ERR {}\n", error),
+                };
+                let effect = Req::Net(NetEffect::Write(id, reply.into_bytes()));
+                match perform!(Either::Left(effect), &context) {
+                    NetReply(NetOutput::Written) => (),
+                    NetReply(other) => return Err(Unexpected(other)),
+                }
+            }
+            if last || quit {
+                break;
+            }
+        }
+        let NetReply(_) = perform!(Either::Left(Req::Net(NetEffect::Close(id))), &context);
+        Ok(())
+    }
+}
+
+pub fn handler(net: VirtualNet, store: Store) -> impl FnMut(Req) -> Result<Output, !> {
+    let mut store = store;
+    move |req| match req {
+        Req::Net(effect) => Ok(Output::Net(net.handle(effect))),
+        Req::Store(effect) => Ok(Output::Store(store.handle(effect))),
+        Req::Spawn(_) => unreachable!("the scheduler takes spawn requests"),
+    }
+}
+
+// the same handlers as plugins, for the capabilities manifest
+pub struct NetPlugin(pub VirtualNet);
+
+impl HandlerPlugin<Output> for NetPlugin {
+    fn name(&self) -> &'static str {
+        "net"
+    }
+
+    fn claims(&self) -> &'static [&'static str] {
+        &["Net"]
+    }
+
+    fn version(&self) -> &'static str {
+        env!("CARGO_PKG_VERSION")
+    }
+
+    fn build(&self, _: &PluginConfig) -> Box<dyn Handler<Output>> {
+        let net = self.0.clone();
+        Box::new(move |req| match req {
+            Req::Net(effect) => Ok(Output::Net(net.handle(effect))),
+            other => Err(other),
+        })
+    }
+}
+
+pub struct StorePlugin;
+
+impl HandlerPlugin<Output> for StorePlugin {
+    fn name(&self) -> &'static str {
+        "store"
+    }
+
+    fn claims(&self) -> &'static [&'static str] {
+        &["Store"]
+    }
+
+    fn version(&self) -> &'static str {
+        env!("CARGO_PKG_VERSION")
+    }
+
+    fn build(&self, _: &PluginConfig) -> Box<dyn Handler<Output>> {
+        let mut store = Store::default();
+        Box::new(move |req| match req {
+            Req::Store(effect) => Ok(Output::Store(store.handle(effect))),
+            other => Err(other),
+        })
+    }
+}
+
+pub fn registry(net: VirtualNet) -> Registry<Output> {
+    let mut registry = Registry::new(PluginConfig::new());
+    registry.register(NetPlugin(net)).register(StorePlugin);
+    registry
+}
+
+// Serves every client of the network until they are all done, the trace holds
+// the effects performed by the root and the connections.
+pub fn serve(net: VirtualNet, store: Store) -> Trace<Req> {
+    let (block, trace) = server
+        .into_block()
+        .spawn_supervised(SpawnOptions::default(), connection)
+        .traced();
+    block.add_handler_(handler(net, store)).run();
+    trace
+}
+
+// Like `serve` with handlers borrowing the network and the store, the caller
+// keeps the store with what the clients set.
+pub fn serve_scoped(net: &VirtualNet, store: &mut Store) -> Trace<Req> {
+    let (block, trace) = server
+        .into_block()
+        .spawn_supervised(SpawnOptions::default(), connection)
+        .traced();
+    let mut net_handler = |req: Req| match req {
+        Req::Net(effect) => Ok(Output::Net(net.handle(effect))),
+        other => Err(other),
+    };
+    let mut store_handler = |req: Req| match req {
+        Req::Store(effect) => Ok(Output::Store(store.handle(effect))),
+        other => Err(other),
+    };
+    block.run_scoped(
+        HandlerSetRef::new()
+            .with(&mut net_handler)
+            .with(&mut store_handler),
+    );
+    trace
+}
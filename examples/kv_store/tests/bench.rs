@@ -0,0 +1,135 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// The sample workload next to this file is captured from the sessions below,
+// regenerate it with `BENCH_WORKLOAD=overwrite` after a change of the example.
+
+#![cfg(feature = "bench")]
+
+use std::{env, fs, path::Path, time::Duration};
+use aeiou::{
+    IntoBlock,
+    deadline::TestClock,
+    bench_harness::{Harness, HandlerSet, HarnessConfig, Workload, SCRIPT, Metric, capture},
+    tasks::SpawnOptions,
+    trace::Recording,
+    sim::{ClientStep, VirtualNet},
+};
+use kv_store_example::{Output, Req, Store, StoreEffect, StoreOutput, connection, handler, server};
+
+const SAMPLE: &str = "tests/workloads/sessions.json";
+
+fn sample() -> Recording {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let json = fs::read_to_string(root.join(SAMPLE)).unwrap();
+    Recording::from_json(&json).unwrap()
+}
+
+fn captured() -> Recording {
+    // the captured times come from the test clock, the sample stays the same
+    let clock = TestClock::install();
+    let net = VirtualNet::with_clock(clock);
+    net.connect(vec![
+        ClientStep::send("SET a 1\nGE"),
+        ClientStep::Wait(Duration::from_millis(20)),
+        ClientStep::send("T b\n"),
+    ]);
+    net.connect(vec![
+        ClientStep::send("GET a\n"),
+        ClientStep::send("SET b 2\n"),
+        ClientStep::send("GET b\nQUIT\n"),
+    ]);
+    let mut live = handler(net, Store::default());
+    let (capturing, handle) = capture(
+        move |req: Req| Ok(live(req).unwrap_or_else(|never| match never {})),
+        1,
+    );
+    server
+        .into_block()
+        .spawn_supervised(SpawnOptions::default(), connection)
+        .add_handler(capturing)
+        .assert_handled()
+        .run();
+    handle.recording()
+}
+
+#[test]
+fn sample_is_current() {
+    let recording = captured();
+    if env::var("BENCH_WORKLOAD").as_deref() == Ok("overwrite") {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        fs::write(root.join(SAMPLE), recording.to_json() + "\n").unwrap();
+    }
+    assert_eq!(sample(), recording);
+}
+
+#[test]
+fn measurements_are_stable() {
+    let workload = Workload::<Req, Output>::from_trace(sample())
+        .unwrap()
+        .repeat(3)
+        .fan_out(4);
+    let config = HarnessConfig::new().wall_threshold(None);
+    let run = || {
+        // the writes go to a live store, the reads and the network come
+        // from the workload, the copies share the store
+        let mut store = Store::default();
+        let store = move |req| match req {
+            Req::Store(effect @ StoreEffect::Set { .. }) => Ok(Output::Store(store.handle(effect))),
+            other => Err(other),
+        };
+        let mut handlers = HandlerSet::new().layer("store", store);
+        let constructor = || {
+            server
+                .into_block()
+                .spawn_supervised(SpawnOptions::default(), connection)
+        };
+        Harness::run(&workload, constructor, &mut handlers, &config)
+    };
+
+    assert_eq!(workload.span(), Duration::from_millis(20));
+
+    let baseline = run();
+    assert_eq!(baseline.unfinished, 0);
+    assert_eq!(baseline.diverged, 0);
+    let steps = workload.steps().len() as u64;
+    assert_eq!(baseline.effects, steps * 12);
+    let stores = workload
+        .steps()
+        .iter()
+        .filter(|step| matches!(step.effect, Req::Store(StoreEffect::Set { .. })))
+        .count() as u64;
+    let counted = baseline.counted();
+    assert!(counted.contains(&(Metric::Dispatched("store".to_string()), stores * 12)));
+    assert!(counted.contains(&(
+        Metric::Dispatched(SCRIPT.to_string()),
+        (steps - stores) * 12
+    )));
+
+    for _ in 0..4 {
+        let again = run();
+        assert_eq!(again.counted(), counted);
+        assert!(
+            again.compare(&baseline).is_clean(),
+            "{}",
+            again.compare(&baseline)
+        );
+    }
+
+    // the captured store answered `GET a` with the value, a store told to
+    // forget everything makes the connections diverge from the workload
+    let forgetful = |req| match req {
+        Req::Store(StoreEffect::Get(_)) => Ok(Output::Store(StoreOutput::Value(None))),
+        other => Err(other),
+    };
+    let mut handlers = HandlerSet::new().layer("store", forgetful);
+    let constructor = || {
+        server
+            .into_block()
+            .spawn_supervised(SpawnOptions::default(), connection)
+    };
+    let current = Harness::run(&workload, constructor, &mut handlers, &config);
+    assert!(current.diverged > 0);
+    let summary = current.compare(&baseline);
+    assert!(summary.regressions().any(|d| d.metric == Metric::Diverged));
+}
@@ -0,0 +1,63 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![feature(generator_trait)]
+
+use std::ops::GeneratorState;
+use aeiou::{
+    HandlerTag, IntoBlock,
+    tasks::SpawnOptions,
+    sim::{ClientStep, NetEffect, VirtualNet},
+};
+use kv_store_example::{Output, Req, Store, connection, server};
+
+// the test answers the network between the rounds, the store is a handler
+#[test]
+fn paused_on_second_read() {
+    let net = VirtualNet::new();
+    net.connect(vec![ClientStep::send("SET a 1\n")]);
+    let second = net.connect(vec![ClientStep::send("GET a\n")]);
+    let mut store = Store::default();
+    let (block, _) = server
+        .into_block()
+        .spawn_supervised(SpawnOptions::default(), connection)
+        .with_progress();
+    let mut block = block.add_handler_tagged(
+        move |req| match req {
+            Req::Store(effect) => Ok(Output::Store(store.handle(effect))),
+            other => Err(other),
+        },
+        HandlerTag::new("store"),
+    );
+
+    let mut paused = None;
+    loop {
+        let effect = match block.resume() {
+            GeneratorState::Yielded(Req::Net(effect)) => effect,
+            GeneratorState::Yielded(other) => panic!("unhandled {:?}", other),
+            GeneratorState::Complete(()) => break,
+        };
+        if effect == NetEffect::Read(second) && paused.is_none() {
+            paused = Some(block.inspect().unwrap());
+        }
+        block.put(Output::Net(net.handle(effect)));
+    }
+
+    let snapshot = paused.unwrap();
+    assert_eq!(snapshot.live_tasks, 2);
+    assert_eq!(
+        snapshot.progress.as_ref().map(|p| p.pending_effects),
+        Some(1)
+    );
+    assert_eq!(snapshot.pending_outputs, 0);
+    let tags = snapshot
+        .handlers
+        .iter()
+        .map(|h| h.tag.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(tags, ["store"]);
+    assert!(snapshot
+        .summary()
+        .starts_with("2 live tasks, 0 pending outputs"));
+    assert_eq!(block.inspect().unwrap().live_tasks, 0);
+}
@@ -0,0 +1,22 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// The capabilities of the plugins compared with the golden file next to this
+// one, after a deliberate change regenerate it with `MANIFEST=overwrite`.
+
+use std::{env, fs, path::Path};
+use aeiou::sim::VirtualNet;
+use kv_store_example::registry;
+
+const GOLDEN: &str = "tests/manifest.txt";
+
+#[test]
+fn manifest_is_unchanged() {
+    let actual = registry(VirtualNet::new()).manifest().to_string();
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(GOLDEN);
+    if env::var("MANIFEST").as_deref() == Ok("overwrite") {
+        fs::write(path, &actual).unwrap();
+        return;
+    }
+    assert_eq!(actual, fs::read_to_string(path).unwrap());
+}
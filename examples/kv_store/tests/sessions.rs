@@ -0,0 +1,184 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::time::Duration;
+use aeiou::{
+    IntoBlock, scenario,
+    deadline::TestClock,
+    sim::{ClientStep, ConnId, NetEffect, NetOutput, VirtualNet},
+    tasks::SpawnOptions,
+};
+use kv_store_example::{
+    Output, Req, StoreEffect, StoreOutput, Store, connection, serve, serve_scoped, server,
+};
+
+fn received(net: &VirtualNet, id: ConnId) -> String {
+    String::from_utf8(net.received(id)).unwrap()
+}
+
+#[test]
+fn scripted_session() {
+    let net = VirtualNet::new();
+    let id = net.connect(vec![ClientStep::send("SET a 1\nGET a\nQUIT\n")]);
+    let trace = serve(net.clone(), Store::default());
+
+    let write = |reply: &str| Req::Net(NetEffect::Write(id, reply.as_bytes().to_vec()));
+    assert_eq!(
+        trace.events(),
+        vec![
+            Req::Net(NetEffect::Accept),
+            Req::Net(NetEffect::Read(id)),
+            Req::Net(NetEffect::Accept),
+            Req::Store(StoreEffect::Set {
+                key: "a".to_string(),
+                value: "1".to_string(),
+                ttl: None,
+            }),
+            write("OK\n"),
+            Req::Store(StoreEffect::Get("a".to_string())),
+            write("VALUE 1\n"),
+            Req::Net(NetEffect::Close(id)),
+        ],
+    );
+    assert_eq!(received(&net, id), "OK\nVALUE 1\n");
+    assert!(net.is_closed(id));
+}
+
+// the same session with the network and the store played by the test
+#[test]
+fn scenario_session() {
+    let id = ConnId(0);
+    let scenario = scenario! {
+        expect Req::Net(NetEffect::Accept) => respond Output::Net(NetOutput::Accepted(id));
+        expect Req::Net(NetEffect::Read(conn)) if *conn == id
+            => respond Output::Net(NetOutput::Data(b"SET a 1\nGET a\nQUIT\n".to_vec()));
+        expect Req::Net(NetEffect::Accept) => respond Output::Net(NetOutput::Shutdown);
+        expect Req::Store(StoreEffect::Set { key, value, ttl: None }) if key == "a" && value == "1"
+            => respond Output::Store(StoreOutput::Stored);
+        expect Req::Net(NetEffect::Write(_, reply)) if reply == b"OK\n"
+            => respond Output::Net(NetOutput::Written);
+        expect Req::Store(StoreEffect::Get(key)) if key == "a"
+            => respond Output::Store(StoreOutput::Value(Some("1".to_string())));
+        expect Req::Net(NetEffect::Write(_, reply)) if reply == b"VALUE 1\n"
+            => respond Output::Net(NetOutput::Written);
+        expect Req::Net(NetEffect::Close(conn)) if *conn == id
+            => respond Output::Net(NetOutput::Closed);
+        expect_complete;
+    };
+    scenario.run(
+        server
+            .into_block()
+            .spawn_supervised(SpawnOptions::default(), connection),
+    );
+}
+
+#[test]
+fn concurrent_connections() {
+    let net = VirtualNet::new();
+    // the command of the first client is cut between two reads
+    let a = net.connect(vec![
+        ClientStep::send("SET a 1\nGE"),
+        ClientStep::send("T b\n"),
+    ]);
+    let b = net.connect(vec![
+        ClientStep::send("GET a\n"),
+        ClientStep::send("SET b 2\n"),
+        ClientStep::send("GET b\n"),
+    ]);
+    let trace = serve(net.clone(), Store::default());
+
+    assert_eq!(received(&net, a), "OK\nNIL\n");
+    assert_eq!(received(&net, b), "VALUE 1\nOK\nVALUE 2\n");
+    assert!(net.is_closed(a) && net.is_closed(b));
+
+    // the connections took turns rather than running one after the other
+    let reads = trace
+        .events()
+        .into_iter()
+        .filter_map(|req| match req {
+            Req::Net(NetEffect::Read(id)) => Some(id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(reads, vec![a, b, a, b, a, b, b]);
+}
+
+// the handlers borrow the network and the store, the trace is the one of `serve`
+#[test]
+fn scoped_connections() {
+    let clients = |net: &VirtualNet| {
+        let a = net.connect(vec![
+            ClientStep::send("SET a 1\nGE"),
+            ClientStep::send("T b\n"),
+        ]);
+        let b = net.connect(vec![
+            ClientStep::send("GET a\n"),
+            ClientStep::send("SET b 2\n"),
+            ClientStep::send("GET b\n"),
+        ]);
+        (a, b)
+    };
+    let owned = VirtualNet::new();
+    clients(&owned);
+    let expected = serve(owned, Store::default()).events();
+
+    let net = VirtualNet::new();
+    let (a, b) = clients(&net);
+    let mut store = Store::default();
+    let trace = serve_scoped(&net, &mut store);
+
+    assert_eq!(trace.events(), expected);
+    assert_eq!(received(&net, a), "OK\nNIL\n");
+    assert_eq!(received(&net, b), "VALUE 1\nOK\nVALUE 2\n");
+    assert!(net.is_closed(a) && net.is_closed(b));
+    assert_eq!(
+        store.handle(StoreEffect::Get("b".to_string())),
+        StoreOutput::Value(Some("2".to_string())),
+    );
+}
+
+#[test]
+fn ttl_expiry() {
+    let clock = TestClock::install();
+    let net = VirtualNet::with_clock(clock);
+    let id = net.connect(vec![
+        ClientStep::send("SET session x EX 5\nSET plain y\n"),
+        ClientStep::Wait(Duration::from_secs(4)),
+        ClientStep::send("GET session\n"),
+        ClientStep::Wait(Duration::from_secs(2)),
+        ClientStep::send("GET session\nGET plain\n"),
+    ]);
+    serve(net.clone(), Store::default());
+    TestClock::uninstall();
+
+    assert_eq!(received(&net, id), "OK\nOK\nVALUE x\nNIL\nVALUE y\n");
+}
+
+#[test]
+fn malformed_commands() {
+    let net = VirtualNet::new();
+    let id = net.connect(vec![
+        ClientStep::send("FROB x\n"),
+        ClientStep::send("GET\n\n"),
+        ClientStep::send("SET a b EX soon\n"),
+        ClientStep::send(&b"\xff\n"[..]),
+        // the last line has no newline, the end of the stream ends it
+        ClientStep::send("SET a b\nGET a"),
+    ]);
+    serve(net.clone(), Store::default());
+
+    assert_eq!(
+        received(&net, id),
+        [
+            "ERR unknown command `FROB`\n",
+            "ERR wrong number of arguments for `GET`\n",
+            "ERR empty command\n",
+            "ERR invalid ttl `soon`\n",
+            "ERR command is not utf-8\n",
+            "OK\n",
+            "VALUE b\n",
+        ]
+        .concat(),
+    );
+    assert!(net.is_closed(id));
+}
@@ -0,0 +1,68 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![feature(generators)]
+
+use aeiou::{Context, Effect, IntoBlock, wait_any, web::BrowserSource};
+use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
+
+#[derive(Debug)]
+pub enum Effects {
+    Render(u32),
+    Idle,
+}
+
+#[derive(Effect)]
+#[input(Effects)]
+pub enum Outputs {
+    Clicked,
+    Reset,
+    Rendered,
+}
+
+#[wasm_bindgen]
+pub struct Counter(BrowserSource<Outputs>);
+
+#[wasm_bindgen]
+impl Counter {
+    pub fn click(&self) {
+        self.0.push(Outputs::Clicked);
+    }
+
+    pub fn reset(&self) {
+        self.0.push(Outputs::Reset);
+    }
+}
+
+#[wasm_bindgen]
+pub fn start(render: js_sys::Function) -> Counter {
+    let events = BrowserSource::new();
+    let counter = |context: Context<Outputs>| {
+        move || {
+            let mut count = 0;
+            loop {
+                wait_any!(&context, Effects::Idle);
+                match context.take() {
+                    Some(Outputs::Clicked) => count += 1,
+                    Some(Outputs::Reset) => count = 0,
+                    _ => continue,
+                }
+                yield Effects::Render(count);
+            }
+        }
+    };
+    counter
+        .into_block()
+        .add_source(events.clone().into_source())
+        .add_handler(move |effect| match effect {
+            Effects::Render(count) => {
+                render
+                    .call1(&JsValue::NULL, &count.into())
+                    .expect("render callback failed");
+                Ok(Outputs::Rendered)
+            },
+            e => Err(e),
+        })
+        .run_on_animation_frame();
+    Counter(events)
+}
@@ -0,0 +1,4 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// only the tests, see `tests/core.rs`
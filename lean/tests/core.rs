@@ -0,0 +1,140 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![feature(generators, generator_trait)]
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    ops::{Generator, GeneratorState},
+};
+use aeiou::{
+    Context, Effect, EffectName, Select, IntoBlock, HistoryConfig, Compaction, Sum, perform,
+    perform_counted, scope, stats::StatsReport,
+};
+
+#[derive(Debug, EffectName)]
+enum Effects {
+    Read(usize),
+    Log(String),
+}
+
+#[derive(Debug, Effect, Select)]
+#[input(Effects)]
+enum Outputs {
+    #[part(Data)]
+    Data(Vec<u8>),
+    Logged,
+}
+
+struct Data(Vec<u8>);
+
+fn handler(effect: Effects) -> Result<Outputs, Effects> {
+    match effect {
+        Effects::Read(len) => Ok(Outputs::Data(vec![7; len])),
+        e => Err(e),
+    }
+}
+
+fn computation(
+    context: Context<Outputs>,
+) -> impl Unpin + Generator<Yield = Effects, Return = usize> {
+    move || {
+        scope!(&context, "reader");
+        let mut total = 0;
+        for len in 1..4 {
+            scope!(&context, "read({})", len);
+            let Data(data) = perform_counted!(Effects::Read(len), &context);
+            total += data.len();
+            perform!(Effects::Log(format!("{} bytes", data.len())));
+            context.take();
+        }
+        total
+    }
+}
+
+#[test]
+fn handlers_chain() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let total = computation
+        .into_block()
+        .add_handler(handler)
+        .add_handler({
+            let log = log.clone();
+            move |effect| match effect {
+                Effects::Log(line) => {
+                    log.borrow_mut().push(line);
+                    Ok(Outputs::Logged)
+                },
+                e => Err(e),
+            }
+        })
+        .assert_handled()
+        .run();
+    assert_eq!(total, 6);
+    assert_eq!(*log.borrow(), ["1 bytes", "2 bytes", "3 bytes"]);
+}
+
+// the diagnostic layers are installed the same way, without the feature they do nothing
+#[test]
+fn diagnostics_still_compile() {
+    let config = HistoryConfig::new(2).compaction(Compaction::PerVariantCounts);
+    let (block, history) = computation.into_block().with_history(config);
+    let (block, accounting) = block.with_accounting();
+    let total = block
+        .add_handler(|effect| match effect {
+            Effects::Log(_) => Ok(Outputs::Logged),
+            e => handler(e),
+        })
+        .assert_handled()
+        .run();
+    assert_eq!(total, 6);
+    assert!(history.raw().len() <= 2);
+    accounting.assert_quiescent();
+}
+
+#[test]
+fn stats_compiled_out() {
+    let mut block = computation.into_block().with_stats().add_handler(handler);
+    while let GeneratorState::Yielded(Effects::Log(_)) = block.resume() {
+        block.put(Outputs::Logged);
+    }
+    // a round for every log and the last one, none when built alone
+    let stats = block.stats();
+    assert!(stats == StatsReport::default() || stats.rounds() == 4);
+}
+
+#[test]
+#[should_panic(expected = "unhandled: Log")]
+fn unhandled() {
+    computation
+        .into_block()
+        .add_handler(handler)
+        .assert_handled()
+        .run();
+}
+
+#[test]
+fn family_check_compiled_out() {
+    if aeiou::DIAGNOSTICS {
+        return;
+    }
+    // the right family answers the left one
+    let swapped = |effect: Sum<Effects, Effects>| match effect {
+        Sum::Left(_) => Ok(Sum::Right(Outputs::Logged)),
+        other => Err(other),
+    };
+    let mut block = (|_: Context<Sum<Outputs, Outputs>>| {
+        || {
+            perform!(Sum::Left(Effects::Read(1)));
+        }
+    })
+    .into_block()
+    .add_lifted_handler(swapped);
+    // put unchecked
+    block.resume();
+    assert!(matches!(
+        block.context().take(),
+        Some(Sum::Right(Outputs::Logged))
+    ));
+}
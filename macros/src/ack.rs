@@ -0,0 +1,93 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use proc_macro2::TokenStream;
+use quote::quote;
+
+pub fn expand(input: syn::DeriveInput) -> syn::Result<TokenStream> {
+    let syn::DeriveInput {
+        attrs,
+        vis,
+        ident,
+        generics,
+        data,
+    } = input;
+    if !generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            generics,
+            "`Ack` cannot be derived for generic enums",
+        ));
+    }
+    let data = match data {
+        syn::Data::Enum(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "`Ack` can only be derived for enums",
+            ))
+        },
+    };
+    let input = match attrs.iter().find(|a| a.path.is_ident("input")) {
+        Some(input) => input.parse_args::<syn::Path>()?,
+        None => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "`Ack` needs the effect enum in `#[input(..)]`",
+            ))
+        },
+    };
+
+    // the unit variant and the effect variant it acknowledges
+    let mut acked = Vec::new();
+    for variant in &data.variants {
+        let attr = match variant.attrs.iter().find(|a| a.path.is_ident("ack")) {
+            Some(attr) => attr,
+            None => continue,
+        };
+        if !matches!(variant.fields, syn::Fields::Unit) {
+            return Err(syn::Error::new_spanned(
+                variant,
+                "an `#[ack]` variant carries no data",
+            ));
+        }
+        acked.push((&variant.ident, attr.parse_args::<syn::Ident>()?));
+    }
+
+    let parts = acked.iter().map(|(variant, _)| {
+        quote! {
+            #vis struct #variant;
+
+            impl aeiou::Select<#variant> for #ident {
+                fn try_take(output: &aeiou::Context<Self>) -> aeiou::TakeResult<#variant, Self> {
+                    match output.take() {
+                        None => aeiou::TakeResult::Empty,
+                        Some(#ident::#variant) => aeiou::TakeResult::Matched(#variant),
+                        Some(other) => aeiou::TakeResult::Mismatched(other),
+                    }
+                }
+            }
+
+            impl aeiou::AckPart<#ident> for #variant {
+                fn ack() -> #ident {
+                    #ident::#variant
+                }
+            }
+        }
+    });
+    let arms = acked
+        .iter()
+        .map(|(variant, effect)| quote!(#input::#effect { .. } => Some(#ident::#variant)));
+    Ok(quote! {
+        #(#parts)*
+
+        impl aeiou::Ack for #ident {
+            fn ack_for(effect: &#input) -> Option<Self> {
+                match effect {
+                    #(#arms,)*
+                    #[allow(unreachable_patterns)]
+                    _ => None,
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,309 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// The opt-in checks of `#[derive(Effect, Select)]`, configured on the enum
+//
+//     #[effect(checks(debug, part_visibility, naming = "snake_case_parts"))]
+//     #[effect(deny_unknown_attrs)]
+//
+// `Effect` checks `debug` and the attributes of the enum, `Select` checks the
+// parts and the attributes of the variants, so each error is reported once.
+
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote, quote_spanned};
+use syn::{parse::ParseStream, spanned::Spanned, Token};
+
+const EFFECT_ATTRS: &[&str] = &["input", "effect"];
+const SELECT_ATTRS: &[&str] = &["part"];
+const CONVENTIONS: &[&str] = &["snake_case_parts"];
+
+#[derive(Default)]
+pub struct Checks {
+    debug: Option<Span>,
+    part_visibility: bool,
+    naming: Option<syn::LitStr>,
+    deny_unknown_attrs: bool,
+}
+
+impl Checks {
+    pub fn parse(attrs: &[syn::Attribute]) -> syn::Result<Self> {
+        let mut checks = Checks::default();
+        for attr in attrs.iter().filter(|a| a.path.is_ident("effect")) {
+            attr.parse_args_with(|input: ParseStream| {
+                while !input.is_empty() {
+                    let key = input.parse::<syn::Ident>()?;
+                    if key == "checks" {
+                        let content;
+                        syn::parenthesized!(content in input);
+                        checks.parse_checks(&content)?;
+                    } else if key == "deny_unknown_attrs" {
+                        checks.deny_unknown_attrs = true;
+                    } else {
+                        return Err(syn::Error::new_spanned(
+                            key,
+                            "expected `checks(..)` or `deny_unknown_attrs`",
+                        ));
+                    }
+                    if !input.is_empty() {
+                        input.parse::<Token![,]>()?;
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(checks)
+    }
+
+    fn parse_checks(&mut self, input: ParseStream) -> syn::Result<()> {
+        while !input.is_empty() {
+            let key = input.parse::<syn::Ident>()?;
+            if key == "debug" {
+                self.debug = Some(key.span());
+            } else if key == "part_visibility" {
+                self.part_visibility = true;
+            } else if key == "naming" {
+                input.parse::<Token![=]>()?;
+                let convention = input.parse::<syn::LitStr>()?;
+                if !CONVENTIONS.contains(&convention.value().as_str()) {
+                    return Err(syn::Error::new_spanned(
+                        convention,
+                        format!("unknown convention, expected one of {:?}", CONVENTIONS),
+                    ));
+                }
+                self.naming = Some(convention);
+            } else {
+                return Err(syn::Error::new_spanned(
+                    key,
+                    "expected `debug`, `part_visibility` or `naming = \"..\"`",
+                ));
+            }
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        Ok(())
+    }
+
+    // the checks of `#[derive(Effect)]`
+    pub fn effect(&self, input: &syn::DeriveInput) -> syn::Result<TokenStream> {
+        let mut errors = Errors::default();
+        if self.deny_unknown_attrs {
+            for attr in &input.attrs {
+                errors.push(unknown(attr, EFFECT_ATTRS));
+            }
+            for attrs in helper_positions(&input.data) {
+                for attr in attrs {
+                    if attr.path.is_ident("input") || attr.path.is_ident("effect") {
+                        errors.push(Err(misplaced(attr)));
+                    }
+                }
+            }
+        }
+        errors.finish()?;
+
+        // a derive only sees the attributes after its own, the `Debug` derive
+        // is usually before it, so the compiler is asked instead
+        let t = match self.debug {
+            Some(span) if !derives_debug(&input.attrs) => {
+                let ident = &input.ident;
+                let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+                quote_spanned! {span=>
+                    #[allow(dead_code)]
+                    const _: () = {
+                        fn assert_debug<T: ::core::fmt::Debug + ?Sized>() {}
+                        fn check #impl_generics () #where_clause {
+                            assert_debug::<#ident #ty_generics>();
+                        }
+                    };
+                }
+            },
+            _ => TokenStream::new(),
+        };
+        Ok(t)
+    }
+
+    // the checks of `#[derive(Select)]`
+    pub fn select(&self, input: &syn::DeriveInput) -> syn::Result<TokenStream> {
+        let variants = match &input.data {
+            syn::Data::Enum(data) => &data.variants,
+            _ => return Ok(TokenStream::new()),
+        };
+        let mut errors = Errors::default();
+        if self.deny_unknown_attrs {
+            for attr in input.attrs.iter().filter(|a| a.path.is_ident("part")) {
+                errors.push(Err(misplaced(attr)));
+            }
+            for variant in variants {
+                for attr in &variant.attrs {
+                    errors.push(unknown(attr, SELECT_ATTRS));
+                }
+                for attr in variant.fields.iter().flat_map(|f| &f.attrs) {
+                    if attr.path.is_ident("part") {
+                        errors.push(Err(misplaced(attr)));
+                    }
+                }
+            }
+        }
+
+        let mut probes = Vec::new();
+        for variant in variants {
+            let part = match variant.attrs.iter().find(|a| a.path.is_ident("part")) {
+                Some(part) => part,
+                None => continue,
+            };
+            let ty = match part.parse_args::<syn::Ident>() {
+                // the part is the field itself, no path is written
+                Ok(mode) if mode == "borrowed" => match variant.fields.iter().next() {
+                    Some(field) => field.ty.clone(),
+                    None => continue,
+                },
+                _ => {
+                    let ty = part.parse_args::<syn::Type>()?;
+                    if let (Some(_), syn::Type::Path(path)) = (&self.naming, &ty) {
+                        errors.push(snake_case_parts(&path.path));
+                    }
+                    ty
+                },
+            };
+            // a less visible part is an error in the public interface of the
+            // probe, the compiler cannot tell for an item in a private module;
+            // E0446 before Rust 1.74, the denied `private_interfaces` lint since
+            if self.part_visibility && !matches!(input.vis, syn::Visibility::Inherited) {
+                let vis = &input.vis;
+                let name = format!("__aeiou_part_probe_{}_{}", input.ident, variant.ident);
+                let probe = format_ident!("{}", name.to_uppercase());
+                probes.push(quote_spanned! {ty.span()=>
+                    #[doc(hidden)]
+                    #[allow(unknown_lints, dead_code, non_upper_case_globals)]
+                    #[deny(private_interfaces)]
+                    #vis const #probe: ::core::marker::PhantomData<#ty> =
+                        ::core::marker::PhantomData;
+                });
+            }
+        }
+        errors.finish()?;
+        Ok(quote!(#(#probes)*))
+    }
+}
+
+#[derive(Default)]
+struct Errors(Option<syn::Error>);
+
+impl Errors {
+    fn push(&mut self, result: syn::Result<()>) {
+        if let Err(error) = result {
+            match &mut self.0 {
+                Some(errors) => errors.combine(error),
+                None => self.0 = Some(error),
+            }
+        }
+    }
+
+    fn finish(self) -> syn::Result<()> {
+        self.0.map_or(Ok(()), Err)
+    }
+}
+
+fn derives_debug(attrs: &[syn::Attribute]) -> bool {
+    attrs
+        .iter()
+        .filter(|a| a.path.is_ident("derive"))
+        .filter_map(|a| {
+            a.parse_args_with(syn::punctuated::Punctuated::<syn::Path, Token![,]>::parse_terminated)
+                .ok()
+        })
+        .flatten()
+        .any(|path| path.segments.last().is_some_and(|s| s.ident == "Debug"))
+}
+
+// the attributes of the variants and of the fields
+fn helper_positions(data: &syn::Data) -> Vec<&Vec<syn::Attribute>> {
+    match data {
+        syn::Data::Enum(data) => data
+            .variants
+            .iter()
+            .flat_map(|v| {
+                Some(&v.attrs)
+                    .into_iter()
+                    .chain(v.fields.iter().map(|f| &f.attrs))
+            })
+            .collect(),
+        syn::Data::Struct(data) => data.fields.iter().map(|f| &f.attrs).collect(),
+        syn::Data::Union(data) => data.fields.named.iter().map(|f| &f.attrs).collect(),
+    }
+}
+
+fn misplaced(attr: &syn::Attribute) -> syn::Error {
+    let name = attr
+        .path
+        .get_ident()
+        .map(ToString::to_string)
+        .unwrap_or_default();
+    let place = if name == "part" {
+        "a variant"
+    } else {
+        "the enum"
+    };
+    syn::Error::new_spanned(attr, format!("`#[{}]` is only read on {}", name, place))
+}
+
+// a name one edit away from a helper is taken for a typo of it
+fn unknown(attr: &syn::Attribute, known: &[&str]) -> syn::Result<()> {
+    let name = match attr.path.get_ident() {
+        Some(ident) => ident.to_string(),
+        None => return Ok(()),
+    };
+    match known.iter().find(|k| **k != name && one_edit(k, &name)) {
+        Some(helper) => Err(syn::Error::new_spanned(
+            &attr.path,
+            format!("unknown attribute `{}`, did you mean `{}`?", name, helper),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn one_edit(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if long.len() - short.len() > 1 {
+        return false;
+    }
+    let prefix = short.iter().zip(long).take_while(|(x, y)| x == y).count();
+    if short.len() == long.len() {
+        // a substitution or two neighbours swapped
+        short[prefix + 1..] == long[prefix + 1..]
+            || (prefix + 1 < short.len()
+                && short[prefix] == long[prefix + 1]
+                && short[prefix + 1] == long[prefix]
+                && short[prefix + 2..] == long[prefix + 2..])
+    } else {
+        short[prefix..] == long[prefix + 1..]
+    }
+}
+
+// `module::Type`, the modules in snake case and the type in camel case
+fn snake_case_parts(path: &syn::Path) -> syn::Result<()> {
+    let mut segments = path.segments.iter().peekable();
+    while let Some(segment) = segments.next() {
+        let name = segment.ident.to_string();
+        let is_type = segments.peek().is_none();
+        let fits = if is_type {
+            name.starts_with(|c: char| c.is_ascii_uppercase()) && !name.contains('_')
+        } else {
+            name.chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        };
+        if !fits {
+            let expected = if is_type {
+                "a camel case type"
+            } else {
+                "a snake case module"
+            };
+            return Err(syn::Error::new_spanned(
+                &segment.ident,
+                format!("`naming = \"snake_case_parts\"` expects {} here", expected),
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use proc_macro2::TokenStream;
+use quote::quote;
+
+fn is_mutating(attrs: &[syn::Attribute]) -> syn::Result<bool> {
+    match attrs.iter().find(|a| a.path.is_ident("mutating")) {
+        Some(attr) if !attr.tokens.is_empty() => {
+            Err(syn::Error::new_spanned(attr, "expected `#[mutating]`"))
+        },
+        Some(_) => Ok(true),
+        None => Ok(false),
+    }
+}
+
+pub fn expand(input: syn::DeriveInput) -> syn::Result<TokenStream> {
+    let syn::DeriveInput {
+        ident,
+        generics,
+        data,
+        ..
+    } = input;
+    let data = match data {
+        syn::Data::Enum(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "`Classify` can only be derived for enums",
+            ))
+        },
+    };
+
+    let mut arms = Vec::new();
+    for variant in &data.variants {
+        let variant_ident = &variant.ident;
+        let class = if is_mutating(&variant.attrs)? {
+            quote!(aeiou::EffectClass::Mutating)
+        } else {
+            quote!(aeiou::EffectClass::ReadOnly)
+        };
+        arms.push(quote!(#ident::#variant_ident { .. } => #class));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics aeiou::Classify for #ident #ty_generics #where_clause {
+            fn effect_class(&self) -> aeiou::EffectClass {
+                match *self {
+                    #(#arms,)*
+                }
+            }
+        }
+    })
+}
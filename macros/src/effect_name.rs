@@ -0,0 +1,63 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use super::schema::doc;
+
+pub fn expand(input: syn::DeriveInput) -> syn::Result<TokenStream> {
+    let syn::DeriveInput {
+        ident,
+        generics,
+        data,
+        ..
+    } = input;
+    let data = match data {
+        syn::Data::Enum(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "`EffectName` can only be derived for enums",
+            ))
+        },
+    };
+
+    let arms = data.variants.iter().map(|variant| {
+        let variant_ident = &variant.ident;
+        let name = variant_ident.to_string();
+        quote!(#ident::#variant_ident { .. } => #name)
+    });
+    let descriptions = data.variants.iter().map(|variant| {
+        let variant_ident = &variant.ident;
+        let name = variant_ident.to_string();
+        let doc = doc(&variant.attrs);
+        // the positions of the tuple fields
+        let fields = variant.fields.iter().enumerate().map(|(index, field)| {
+            field
+                .ident
+                .as_ref()
+                .map_or_else(|| index.to_string(), ToString::to_string)
+        });
+        quote! {
+            #ident::#variant_ident { .. } => {
+                aeiou::EffectDescription::new(#name, #doc, &[#(#fields),*])
+            }
+        }
+    });
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics aeiou::EffectName for #ident #ty_generics #where_clause {
+            fn effect_name(&self) -> &'static str {
+                match *self {
+                    #(#arms,)*
+                }
+            }
+
+            fn describe(&self) -> aeiou::EffectDescription {
+                match *self {
+                    #(#descriptions,)*
+                }
+            }
+        }
+    })
+}
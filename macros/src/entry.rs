@@ -0,0 +1,162 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::{
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    Token,
+};
+
+// `effect = T, handlers(a, b), startup_policy = p` in any order
+pub struct Args {
+    effect: Option<syn::Type>,
+    handlers: Vec<syn::Expr>,
+    startup_policy: Option<syn::Expr>,
+}
+
+impl Parse for Args {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = Args {
+            effect: None,
+            handlers: Vec::new(),
+            startup_policy: None,
+        };
+        let mut handlers = None;
+        while !input.is_empty() {
+            let key = input.parse::<syn::Ident>()?;
+            let duplicate = || syn::Error::new_spanned(&key, format!("duplicate `{}`", key));
+            if key == "effect" {
+                input.parse::<Token![=]>()?;
+                if args.effect.is_some() {
+                    return Err(duplicate());
+                }
+                args.effect = Some(input.parse()?);
+            } else if key == "handlers" {
+                let content;
+                syn::parenthesized!(content in input);
+                if handlers.is_some() {
+                    return Err(duplicate());
+                }
+                handlers = Some(Punctuated::<syn::Expr, Token![,]>::parse_terminated(
+                    &content,
+                )?);
+            } else if key == "startup_policy" {
+                input.parse::<Token![=]>()?;
+                if args.startup_policy.is_some() {
+                    return Err(duplicate());
+                }
+                args.startup_policy = Some(input.parse()?);
+            } else {
+                return Err(syn::Error::new_spanned(
+                    key,
+                    "expected `effect`, `handlers` or `startup_policy`",
+                ));
+            }
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        args.handlers = handlers.into_iter().flatten().collect();
+        Ok(args)
+    }
+}
+
+// the single argument, `ctx: Context<T>`
+fn context_arg(sig: &syn::Signature) -> syn::Result<&syn::PatType> {
+    const EXPECTED: &str = "expected a single argument `ctx: Context<_>`";
+
+    let mut inputs = sig.inputs.iter();
+    let arg = match (inputs.next(), inputs.next()) {
+        (Some(arg), None) => arg,
+        (None, _) => return Err(syn::Error::new_spanned(&sig.ident, EXPECTED)),
+        (Some(_), Some(extra)) => return Err(syn::Error::new_spanned(extra, EXPECTED)),
+    };
+    let arg = match arg {
+        syn::FnArg::Typed(arg) => arg,
+        syn::FnArg::Receiver(receiver) => return Err(syn::Error::new_spanned(receiver, EXPECTED)),
+    };
+    let is_context = match &*arg.ty {
+        syn::Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .map_or(false, |s| s.ident == "Context"),
+        _ => false,
+    };
+    if !is_context {
+        return Err(syn::Error::new_spanned(&arg.ty, EXPECTED));
+    }
+    Ok(arg)
+}
+
+pub fn expand(args: Args, function: syn::ItemFn) -> syn::Result<TokenStream> {
+    let syn::ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = function;
+    // there is no driver for async computations yet
+    if let Some(asyncness) = &sig.asyncness {
+        return Err(syn::Error::new_spanned(
+            asyncness,
+            "`#[aeiou::main]` does not support `async fn` yet, write the generator body",
+        ));
+    }
+    if !sig.generics.params.is_empty() || sig.generics.where_clause.is_some() {
+        return Err(syn::Error::new_spanned(
+            &sig.generics,
+            "`#[aeiou::main]` cannot be generic",
+        ));
+    }
+    let arg = context_arg(&sig)?;
+    let effect = args.effect.as_ref().ok_or_else(|| {
+        syn::Error::new(
+            Span::call_site(),
+            "missing the output type, `#[aeiou::main(effect = ...)]`",
+        )
+    })?;
+
+    let ident = &sig.ident;
+    let pat = &arg.pat;
+    let ty = &arg.ty;
+    let output = match &sig.output {
+        syn::ReturnType::Default => quote!(()),
+        syn::ReturnType::Type(_, ty) => quote!(#ty),
+    };
+    // the computation as one would write it by hand
+    let computation = quote! {
+        let computation = |#pat: #ty| move || #block;
+        let block: aeiou::Block<#effect, _> = aeiou::IntoBlock::into_block(computation);
+    };
+    let handlers = &args.handlers;
+    let t = match &args.startup_policy {
+        None => quote! {
+            #(#attrs)*
+            #vis fn #ident() -> #output {
+                #computation
+                block
+                    #(.add_handler(#handlers))*
+                    .assert_handled()
+                    .run()
+            }
+        },
+        // the handlers are tagged with their expressions for the health checks
+        Some(policy) => {
+            let tags = handlers.iter().map(|h| quote!(#h).to_string());
+            quote! {
+                #(#attrs)*
+                #vis fn #ident() -> ::std::result::Result<#output, aeiou::StartupError> {
+                    #computation
+                    block
+                        #(.add_handler_tagged(#handlers, aeiou::HandlerTag::new(#tags)))*
+                        .assert_handled()
+                        .run_checked(#policy)
+                }
+            }
+        },
+    };
+    Ok(t)
+}
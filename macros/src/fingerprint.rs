@@ -0,0 +1,122 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+
+fn is_skip(attr: &syn::Attribute) -> syn::Result<bool> {
+    if !attr.path.is_ident("fingerprint") {
+        return Ok(false);
+    }
+    let arg = attr.parse_args::<syn::Ident>()?;
+    if arg == "skip" {
+        Ok(true)
+    } else {
+        Err(syn::Error::new_spanned(arg, "expected `skip`"))
+    }
+}
+
+fn reject_skip(attrs: &[syn::Attribute]) -> syn::Result<()> {
+    for attr in attrs {
+        if is_skip(attr)? {
+            return Err(syn::Error::new_spanned(
+                attr,
+                "`#[fingerprint(skip)]` is only allowed on fields",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn skipped(field: &syn::Field) -> syn::Result<bool> {
+    let mut skip = false;
+    for attr in &field.attrs {
+        skip |= is_skip(attr)?;
+    }
+    Ok(skip)
+}
+
+// returns the destructuring pattern and the statements hashing the kept fields
+fn fields(fields: &syn::Fields) -> syn::Result<(TokenStream, Vec<TokenStream>)> {
+    let mut bindings = Vec::new();
+    let mut hashes = Vec::new();
+    for (index, field) in fields.iter().enumerate() {
+        let binding = format_ident!("field_{}", index);
+        let member = match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = syn::Index::from(index);
+                quote!(#index)
+            },
+        };
+        if skipped(field)? {
+            bindings.push(quote!(#member: _));
+        } else {
+            bindings.push(quote!(#member: #binding));
+            hashes.push(quote!(aeiou::EffectFingerprint::fingerprint(#binding, hasher);));
+        }
+    }
+    Ok((quote!({ #(#bindings,)* .. }), hashes))
+}
+
+pub fn expand(input: syn::DeriveInput) -> syn::Result<TokenStream> {
+    let syn::DeriveInput {
+        attrs,
+        ident,
+        mut generics,
+        data,
+        ..
+    } = input;
+    reject_skip(&attrs)?;
+
+    let body = match data {
+        syn::Data::Enum(e) => {
+            let mut arms = Vec::new();
+            for variant in &e.variants {
+                reject_skip(&variant.attrs)?;
+                let name = &variant.ident;
+                let label = name.to_string();
+                let (pattern, hashes) = fields(&variant.fields)?;
+                arms.push(quote! {
+                    #ident::#name #pattern => {
+                        aeiou::EffectFingerprint::fingerprint(#label, hasher);
+                        #(#hashes)*
+                    }
+                });
+            }
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        },
+        syn::Data::Struct(s) => {
+            let (pattern, hashes) = fields(&s.fields)?;
+            quote! {
+                let #ident #pattern = self;
+                #(#hashes)*
+            }
+        },
+        syn::Data::Union(u) => {
+            return Err(syn::Error::new_spanned(
+                u.union_token,
+                "`EffectFingerprint` cannot be derived for unions",
+            ))
+        },
+    };
+
+    for param in generics.type_params_mut() {
+        param.bounds.push(syn::parse_quote!(aeiou::EffectFingerprint));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics aeiou::EffectFingerprint for #ident #ty_generics #where_clause {
+            fn fingerprint<H>(&self, hasher: &mut H)
+            where
+                H: core::hash::Hasher,
+            {
+                #body
+            }
+        }
+    })
+}
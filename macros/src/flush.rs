@@ -0,0 +1,97 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use proc_macro2::TokenStream;
+use quote::quote;
+
+#[derive(Clone, Copy)]
+pub enum Marker {
+    Flush,
+    Flushed,
+}
+
+impl Marker {
+    fn name(self) -> &'static str {
+        match self {
+            Marker::Flush => "Flush",
+            Marker::Flushed => "Flushed",
+        }
+    }
+}
+
+// the variant with the single unnamed field of the marker type
+fn holds(variant: &syn::Variant, marker: Marker) -> bool {
+    match &variant.fields {
+        syn::Fields::Unnamed(fields) if fields.unnamed.len() == 1 => match &fields.unnamed[0].ty {
+            syn::Type::Path(ty) => ty
+                .path
+                .segments
+                .last()
+                .map_or(false, |s| s.ident == marker.name()),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+pub fn expand(input: syn::DeriveInput, marker: Marker) -> syn::Result<TokenStream> {
+    let syn::DeriveInput {
+        ident,
+        generics,
+        data,
+        ..
+    } = input;
+    let trait_name = format!("Has{}", marker.name());
+    let data = match data {
+        syn::Data::Enum(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                format!("`{}` can only be derived for enums", trait_name),
+            ))
+        },
+    };
+
+    let mut variants = data.variants.iter().filter(|v| holds(v, marker));
+    let variant = match (variants.next(), variants.next()) {
+        (Some(variant), None) => &variant.ident,
+        (None, _) => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                format!("no variant holds `aeiou::{}`", marker.name()),
+            ))
+        },
+        (Some(_), Some(second)) => {
+            return Err(syn::Error::new_spanned(
+                second,
+                format!("more than one variant holds `aeiou::{}`", marker.name()),
+            ))
+        },
+    };
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let body = match marker {
+        Marker::Flush => quote! {
+            impl #impl_generics aeiou::HasFlush for #ident #ty_generics #where_clause {
+                fn flush() -> Self {
+                    #ident::#variant(aeiou::Flush)
+                }
+
+                fn is_flush(&self) -> bool {
+                    matches!(self, #ident::#variant(_))
+                }
+            }
+        },
+        Marker::Flushed => quote! {
+            impl #impl_generics aeiou::HasFlushed for #ident #ty_generics #where_clause {
+                fn flushed() -> Self {
+                    #ident::#variant(aeiou::Flushed)
+                }
+
+                fn is_flushed(&self) -> bool {
+                    matches!(self, #ident::#variant(_))
+                }
+            }
+        },
+    };
+    Ok(body)
+}
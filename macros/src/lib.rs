@@ -3,9 +3,16 @@
 
 // TODO: error handling
 
-#[proc_macro_derive(Effect, attributes(input))]
+mod checks;
+
+#[proc_macro_derive(Effect, attributes(input, effect))]
 pub fn derive_effect(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let syn::DeriveInput { attrs, ident, .. } = syn::parse_macro_input!(input);
+    let input = syn::parse_macro_input!(input as syn::DeriveInput);
+    let checks = match checks::Checks::parse(&input.attrs).and_then(|c| c.effect(&input)) {
+        Ok(checks) => checks,
+        Err(error) => return error.into_compile_error().into(),
+    };
+    let syn::DeriveInput { attrs, ident, .. } = input;
 
     let input_ty = match attrs.iter().find(|a| a.path.is_ident("input")) {
         Some(limit) => limit.parse_args::<syn::Type>().unwrap(),
@@ -16,37 +23,203 @@ pub fn derive_effect(input: proc_macro::TokenStream) -> proc_macro::TokenStream
         impl Effect for #ident {
             type Input = #input_ty;
         }
+        #checks
     };
     t.into()
 }
 
-#[proc_macro_derive(Select, attributes(part))]
+#[proc_macro_derive(Select, attributes(part, effect))]
 pub fn derive_composable(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let syn::DeriveInput { ident, data, .. } = syn::parse_macro_input!(input);
+    let input = syn::parse_macro_input!(input as syn::DeriveInput);
+    let checks = match checks::Checks::parse(&input.attrs).and_then(|c| c.select(&input)) {
+        Ok(checks) => checks,
+        Err(error) => return error.into_compile_error().into(),
+    };
+    let syn::DeriveInput {
+        ident,
+        generics,
+        data,
+        ..
+    } = input;
+
+    // the context owns its outputs, a borrowed payload fails far from here
+    if let Some(lifetime) = generics.lifetimes().next() {
+        return syn::Error::new_spanned(
+            lifetime,
+            "outputs cannot borrow from the handler, put the payload with \
+             `Context::put_scoped` and project it with `#[part(borrowed)]`",
+        )
+        .into_compile_error()
+        .into();
+    }
 
     let it = match data {
         syn::Data::Enum(e) => e.variants.into_iter().filter_map(|v| {
             let ident = v.ident;
+            let fields = v.fields;
             v.attrs
                 .into_iter()
                 .find(|a| a.path.is_ident("part"))
-                .map(|part| (part.parse_args::<syn::Type>().unwrap(), ident))
+                .map(|part| match part.parse_args::<syn::Ident>() {
+                    // the part is the `Scoped` view itself
+                    Ok(mode) if mode == "borrowed" => match fields.iter().next() {
+                        Some(field) => Ok((field.ty.clone(), ident, quote::quote!(v))),
+                        None => Err(syn::Error::new_spanned(
+                            &part,
+                            format!("`#[part(borrowed)]` projects a field, `{}` has none", ident),
+                        )),
+                    },
+                    _ => {
+                        let ty = part.parse_args::<syn::Type>().unwrap();
+                        let project = quote::quote!(#ty(v));
+                        Ok((ty, ident, project))
+                    },
+                })
         }),
         _ => panic!(),
     };
-    let (ty, id): (Vec<syn::Type>, Vec<syn::Ident>) = it.unzip();
+    let parts = match it.collect::<syn::Result<Vec<_>>>() {
+        Ok(parts) => parts,
+        Err(error) => return error.into_compile_error().into(),
+    };
+    let (ty, (id, project)): (Vec<syn::Type>, (Vec<syn::Ident>, Vec<_>)) = parts
+        .into_iter()
+        .map(|(ty, id, project)| (ty, (id, project)))
+        .unzip();
 
     let t = quote::quote! {
         #(
         impl Select<#ty> for #ident {
-            fn take(output: &aeiou::Context<Self>) -> Option<#ty> {
-                match output.take()? {
-                    #ident::#id(v) => Some(#ty(v)),
-                    _ => None,
+            fn try_take(output: &aeiou::Context<Self>) -> aeiou::TakeResult<#ty, Self> {
+                match output.take() {
+                    None => aeiou::TakeResult::Empty,
+                    Some(#ident::#id(v)) => aeiou::TakeResult::Matched(#project),
+                    #[allow(unreachable_patterns)]
+                    Some(other) => aeiou::TakeResult::Mismatched(other),
                 }
             }
         }
         )*
+        #checks
     };
     t.into()
 }
+
+mod state_machine;
+
+#[proc_macro]
+pub fn state_machine(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    syn::parse_macro_input!(input as state_machine::Machine)
+        .expand()
+        .into()
+}
+
+mod fingerprint;
+
+#[proc_macro_derive(EffectFingerprint, attributes(fingerprint))]
+pub fn derive_fingerprint(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    fingerprint::expand(syn::parse_macro_input!(input))
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+mod schema;
+
+#[proc_macro_derive(EffectSchema, attributes(part, input))]
+pub fn derive_schema(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    schema::expand(syn::parse_macro_input!(input))
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+mod effect_name;
+
+#[proc_macro_derive(EffectName)]
+pub fn derive_effect_name(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    effect_name::expand(syn::parse_macro_input!(input))
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+mod payload_size;
+
+#[proc_macro_derive(PayloadSize, attributes(payload))]
+pub fn derive_payload_size(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    payload_size::expand(syn::parse_macro_input!(input))
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+mod classify;
+
+#[proc_macro_derive(Classify, attributes(mutating))]
+pub fn derive_classify(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    classify::expand(syn::parse_macro_input!(input))
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+mod flush;
+
+#[proc_macro_derive(HasFlush)]
+pub fn derive_has_flush(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    flush::expand(syn::parse_macro_input!(input), flush::Marker::Flush)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(HasFlushed)]
+pub fn derive_has_flushed(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    flush::expand(syn::parse_macro_input!(input), flush::Marker::Flushed)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+mod ack;
+
+#[proc_macro_derive(Ack, attributes(input, ack))]
+pub fn derive_ack(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    ack::expand(syn::parse_macro_input!(input))
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+mod shed;
+
+#[proc_macro_derive(Sheddable, attributes(input, sheddable))]
+pub fn derive_sheddable(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    shed::expand(syn::parse_macro_input!(input))
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+mod session;
+
+#[proc_macro_derive(WithSession, attributes(session))]
+pub fn derive_with_session(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    session::expand(syn::parse_macro_input!(input))
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+mod mock;
+
+#[proc_macro_derive(MockDefaults, attributes(output))]
+pub fn derive_mock_defaults(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    mock::expand(syn::parse_macro_input!(input))
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+mod entry;
+
+#[proc_macro_attribute]
+pub fn main(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let args = syn::parse_macro_input!(attr as entry::Args);
+    entry::expand(args, syn::parse_macro_input!(item))
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
@@ -0,0 +1,151 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use proc_macro2::TokenStream;
+use quote::{quote, format_ident};
+
+// `ReadTcp` is `read_tcp`, `HTTPGet` is `http_get`
+fn snake_case(name: &str) -> String {
+    let chars = name.chars().collect::<Vec<_>>();
+    let mut snake = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let after_lower = !chars[i - 1].is_uppercase() && chars[i - 1] != '_';
+            let before_lower = chars.get(i + 1).map_or(false, |n| n.is_lowercase());
+            if after_lower || (before_lower && chars[i - 1] != '_') {
+                snake.push('_');
+            }
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+pub fn expand(input: syn::DeriveInput) -> syn::Result<TokenStream> {
+    let syn::DeriveInput {
+        attrs,
+        vis,
+        ident,
+        generics,
+        data,
+    } = input;
+    if !generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            generics,
+            "`MockDefaults` cannot be derived for generic enums",
+        ));
+    }
+    let data = match data {
+        syn::Data::Enum(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "`MockDefaults` can only be derived for enums",
+            ))
+        },
+    };
+    let output = match attrs.iter().find(|a| a.path.is_ident("output")) {
+        Some(output) => output.parse_args::<syn::Path>()?,
+        None => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "`MockDefaults` needs the output enum in `#[output(..)]`",
+            ))
+        },
+    };
+    let mock = match output.segments.last() {
+        Some(last) => format_ident!("{}Mock", last.ident),
+        None => return Err(syn::Error::new_spanned(output, "expected the output enum")),
+    };
+
+    let mut names = Vec::<(String, &syn::Ident)>::new();
+    let mut fields = Vec::new();
+    let mut setters = Vec::new();
+    let mut arms = Vec::new();
+    for variant in &data.variants {
+        let name = &variant.ident;
+        let field = snake_case(&name.to_string());
+        if let Some((_, other)) = names.iter().find(|(f, _)| *f == field) {
+            return Err(syn::Error::new_spanned(
+                name,
+                format!(
+                    "`{}` and `{}` both make the mock field `{}`",
+                    other, name, field
+                ),
+            ));
+        }
+        names.push((field.clone(), name));
+        let setter = format_ident!("on_{}", field);
+        let field = format_ident!("{}", field);
+        let tys = variant.fields.iter().map(|f| &f.ty).collect::<Vec<_>>();
+        let bindings = (0..tys.len())
+            .map(|i| format_ident!("a{}", i))
+            .collect::<Vec<_>>();
+        let pattern = match &variant.fields {
+            syn::Fields::Named(named) => {
+                let names = named.named.iter().map(|f| &f.ident);
+                quote!(#ident::#name { #(#names: #bindings),* })
+            },
+            syn::Fields::Unnamed(_) => quote!(#ident::#name(#(#bindings),*)),
+            syn::Fields::Unit => quote!(#ident::#name),
+        };
+        let variant_name = name.to_string();
+        fields.push(quote! {
+            pub #field: Option<Box<dyn FnMut(#(#tys),*) -> #output>>
+        });
+        setters.push(quote! {
+            pub fn #setter<F>(mut self, f: F) -> Self
+            where
+                F: FnMut(#(#tys),*) -> #output + 'static,
+            {
+                self.#field = Some(Box::new(f));
+                self
+            }
+        });
+        arms.push(quote! {
+            #pattern => match &mut self.#field {
+                Some(f) => Ok(f(#(#bindings),*)),
+                None => self.__fallback.respond(#variant_name, #pattern),
+            }
+        });
+    }
+    let fields_none = names.iter().map(|(field, _)| format_ident!("{}", field));
+
+    Ok(quote! {
+        #vis struct #mock {
+            #(#fields,)*
+            __fallback: aeiou::MockFallback<#output>,
+        }
+
+        impl Default for #mock {
+            fn default() -> Self {
+                #mock {
+                    #(#fields_none: None,)*
+                    __fallback: Default::default(),
+                }
+            }
+        }
+
+        impl #mock {
+            #(#setters)*
+
+            pub fn defaults(mut self, strategy: aeiou::MockDefault<#output>) -> Self {
+                self.__fallback.set(strategy);
+                self
+            }
+
+            // the variants skipped by `MockDefault::record_and_skip`, in order
+            pub fn skipped(&self) -> &[&'static str] {
+                self.__fallback.skipped()
+            }
+        }
+
+        impl aeiou::Handler<#output> for #mock {
+            fn handle(&mut self, effect: #ident) -> Result<#output, #ident> {
+                match effect {
+                    #(#arms,)*
+                }
+            }
+        }
+    })
+}
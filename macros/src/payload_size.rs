@@ -0,0 +1,84 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+
+fn is_payload(field: &syn::Field) -> syn::Result<bool> {
+    match field.attrs.iter().find(|a| a.path.is_ident("payload")) {
+        Some(attr) if !attr.tokens.is_empty() => {
+            Err(syn::Error::new_spanned(attr, "expected `#[payload]`"))
+        },
+        Some(_) => Ok(true),
+        None => Ok(false),
+    }
+}
+
+// returns the destructuring pattern and the lengths of the payload fields
+fn fields(fields: &syn::Fields) -> syn::Result<(TokenStream, Vec<TokenStream>)> {
+    let mut bindings = Vec::new();
+    let mut lengths = Vec::new();
+    for (index, field) in fields.iter().enumerate() {
+        if !is_payload(field)? {
+            continue;
+        }
+        let binding = format_ident!("field_{}", index);
+        let member = match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = syn::Index::from(index);
+                quote!(#index)
+            },
+        };
+        bindings.push(quote!(#member: #binding));
+        lengths.push(quote!(#binding.len()));
+    }
+    Ok((quote!({ #(#bindings,)* .. }), lengths))
+}
+
+pub fn expand(input: syn::DeriveInput) -> syn::Result<TokenStream> {
+    let syn::DeriveInput {
+        ident,
+        generics,
+        data,
+        ..
+    } = input;
+
+    let body = match data {
+        syn::Data::Enum(e) => {
+            let mut arms = Vec::new();
+            for variant in &e.variants {
+                let name = &variant.ident;
+                let (pattern, lengths) = fields(&variant.fields)?;
+                arms.push(quote!(#ident::#name #pattern => 0 #(+ #lengths)*,));
+            }
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        },
+        syn::Data::Struct(s) => {
+            let (pattern, lengths) = fields(&s.fields)?;
+            quote! {
+                let #ident #pattern = self;
+                0 #(+ #lengths)*
+            }
+        },
+        syn::Data::Union(u) => {
+            return Err(syn::Error::new_spanned(
+                u.union_token,
+                "`PayloadSize` cannot be derived for unions",
+            ))
+        },
+    };
+
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics aeiou::PayloadSize for #ident #ty_generics #where_clause {
+            fn payload_size(&self) -> usize {
+                #body
+            }
+        }
+    })
+}
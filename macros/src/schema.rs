@@ -0,0 +1,128 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use proc_macro2::TokenStream;
+use quote::{quote, ToTokens};
+
+// `quote` separates every token, collapse it back to how the type is written
+fn type_name<T>(ty: &T) -> String
+where
+    T: ToTokens,
+{
+    let mut name = ty.to_token_stream().to_string();
+    for (from, to) in [
+        (" < ", "<"),
+        ("< ", "<"),
+        (" <", "<"),
+        (" >", ">"),
+        (" ,", ","),
+        (" :: ", "::"),
+        (":: ", "::"),
+        ("& ", "&"),
+        ("( ", "("),
+        (" )", ")"),
+        ("[ ", "["),
+        (" ]", "]"),
+        (" ;", ";"),
+    ] {
+        name = name.replace(from, to);
+    }
+    name
+}
+
+pub(crate) fn doc(attrs: &[syn::Attribute]) -> String {
+    attrs
+        .iter()
+        .filter(|a| a.path.is_ident("doc"))
+        .filter_map(|a| match a.parse_meta() {
+            Ok(syn::Meta::NameValue(syn::MetaNameValue {
+                lit: syn::Lit::Str(s),
+                ..
+            })) => Some(s.value().trim().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn parsed_type(attrs: &[syn::Attribute], name: &str) -> syn::Result<Option<String>> {
+    match attrs.iter().find(|a| a.path.is_ident(name)) {
+        Some(attr) => Ok(Some(type_name(&attr.parse_args::<syn::Type>()?))),
+        None => Ok(None),
+    }
+}
+
+fn option(value: Option<String>) -> TokenStream {
+    match value {
+        Some(value) => quote!(Some(#value.to_string())),
+        None => quote!(None),
+    }
+}
+
+pub fn expand(input: syn::DeriveInput) -> syn::Result<TokenStream> {
+    let syn::DeriveInput {
+        attrs,
+        ident,
+        generics,
+        data,
+        ..
+    } = input;
+    let data = match data {
+        syn::Data::Enum(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "`EffectSchema` can only be derived for enums",
+            ))
+        },
+    };
+
+    let mut variants = Vec::new();
+    for variant in &data.variants {
+        let name = variant.ident.to_string();
+        let doc = doc(&variant.attrs);
+        let part = match parsed_type(&variant.attrs, "part")? {
+            // the part of a borrowed variant is its `Scoped` view
+            Some(part) if part == "borrowed" => {
+                variant.fields.iter().next().map(|f| type_name(&f.ty))
+            },
+            part => part,
+        };
+        let part = option(part);
+        let fields = variant.fields.iter().map(|field| {
+            let name = option(field.ident.as_ref().map(ToString::to_string));
+            let ty = type_name(&field.ty);
+            quote! {
+                aeiou::schema::FieldSchema {
+                    name: #name,
+                    ty: #ty.to_string(),
+                }
+            }
+        });
+        variants.push(quote! {
+            aeiou::schema::VariantSchema {
+                name: #name.to_string(),
+                fields: vec![#(#fields),*],
+                part: #part,
+                doc: #doc.to_string(),
+            }
+        });
+    }
+
+    let name = ident.to_string();
+    let doc = doc(&attrs);
+    let input = option(parsed_type(&attrs, "input")?);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #ident #ty_generics #where_clause {
+            pub fn schema() -> aeiou::schema::EffectSchema {
+                aeiou::schema::EffectSchema {
+                    name: #name.to_string(),
+                    input: #input,
+                    doc: #doc.to_string(),
+                    variants: vec![#(#variants),*],
+                }
+            }
+        }
+    })
+}
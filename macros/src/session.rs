@@ -0,0 +1,133 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use proc_macro2::TokenStream;
+use quote::{quote, format_ident};
+
+fn is_close(variant: &syn::Variant) -> syn::Result<bool> {
+    match variant.attrs.iter().find(|a| a.path.is_ident("session")) {
+        Some(attr) => {
+            let marker = attr.parse_args::<syn::Ident>()?;
+            if marker != "close" {
+                return Err(syn::Error::new_spanned(
+                    marker,
+                    "expected `#[session(close)]`",
+                ));
+            }
+            Ok(true)
+        },
+        None => Ok(false),
+    }
+}
+
+// the index of the field marked `#[session]`
+fn key_field(variant: &syn::Variant) -> syn::Result<Option<(usize, &syn::Field)>> {
+    let mut marked = variant
+        .fields
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| f.attrs.iter().any(|a| a.path.is_ident("session")));
+    match (marked.next(), marked.next()) {
+        (_, Some((_, field))) => Err(syn::Error::new_spanned(
+            field,
+            "a variant has at most one `#[session]` field",
+        )),
+        (marked, None) => Ok(marked),
+    }
+}
+
+pub fn expand(input: syn::DeriveInput) -> syn::Result<TokenStream> {
+    let syn::DeriveInput {
+        ident,
+        generics,
+        data,
+        ..
+    } = input;
+    if !generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            generics,
+            "`WithSession` cannot be derived for generic enums",
+        ));
+    }
+    let data = match data {
+        syn::Data::Enum(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "`WithSession` can only be derived for enums",
+            ))
+        },
+    };
+
+    let key = format_ident!("key");
+    let mut key_ty = None;
+    let mut close = None;
+    let mut arms = Vec::new();
+    for variant in &data.variants {
+        let name = &variant.ident;
+        let field = key_field(variant)?;
+        if is_close(variant)? {
+            if close.is_some() {
+                return Err(syn::Error::new_spanned(
+                    variant,
+                    "only one variant is `#[session(close)]`",
+                ));
+            }
+            if variant.fields.len() != 1 || field.is_none() {
+                return Err(syn::Error::new_spanned(
+                    variant,
+                    "the `#[session(close)]` variant carries the session key alone",
+                ));
+            }
+            close = Some(variant);
+        }
+        let (index, field) = match field {
+            Some(field) => field,
+            None => continue,
+        };
+        key_ty.get_or_insert(&field.ty);
+        let pattern = match &field.ident {
+            Some(field) => quote!(#ident::#name { #field: #key, .. }),
+            None => {
+                let skipped = (0..index).map(|_| quote!(_));
+                quote!(#ident::#name(#(#skipped,)* #key, ..))
+            },
+        };
+        arms.push(quote!(#pattern => Some(#key)));
+    }
+    let close = match close {
+        Some(close) => close,
+        None => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "`WithSession` needs a `#[session(close)]` variant",
+            ))
+        },
+    };
+    let close_name = &close.ident;
+    let closing = match &close.fields {
+        syn::Fields::Named(fields) => {
+            let field = &fields.named[0].ident;
+            quote!(#ident::#close_name { #field: #key })
+        },
+        _ => quote!(#ident::#close_name(#key)),
+    };
+
+    Ok(quote! {
+        impl aeiou::WithSession for #ident {
+            type Key = #key_ty;
+
+            fn key(&self) -> Option<&Self::Key> {
+                match self {
+                    #(#arms,)*
+                    #[allow(unreachable_patterns)]
+                    _ => None,
+                }
+            }
+
+            fn close_session(#key: Self::Key) -> Self {
+                #closing
+            }
+        }
+    })
+}
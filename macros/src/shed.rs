@@ -0,0 +1,75 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::punctuated::Punctuated;
+
+pub fn expand(input: syn::DeriveInput) -> syn::Result<TokenStream> {
+    let syn::DeriveInput {
+        attrs,
+        ident,
+        generics,
+        data,
+        ..
+    } = input;
+    if !generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            generics,
+            "`Sheddable` cannot be derived for generic enums",
+        ));
+    }
+    let data = match data {
+        syn::Data::Enum(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "`Sheddable` can only be derived for enums",
+            ))
+        },
+    };
+    let input = match attrs.iter().find(|a| a.path.is_ident("input")) {
+        Some(input) => input.parse_args::<syn::Path>()?,
+        None => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "`Sheddable` needs the effect enum in `#[input(..)]`",
+            ))
+        },
+    };
+
+    // the unit variant answering the effect variants it lists when shed
+    let mut arms = Vec::new();
+    for variant in &data.variants {
+        let attr = match variant.attrs.iter().find(|a| a.path.is_ident("sheddable")) {
+            Some(attr) => attr,
+            None => continue,
+        };
+        if !matches!(variant.fields, syn::Fields::Unit) {
+            return Err(syn::Error::new_spanned(
+                variant,
+                "a `#[sheddable]` variant carries no data",
+            ));
+        }
+        let effects =
+            attr.parse_args_with(Punctuated::<syn::Ident, syn::Token![,]>::parse_terminated)?;
+        let variant = &variant.ident;
+        arms.extend(
+            effects
+                .iter()
+                .map(|effect| quote!(#input::#effect { .. } => Some(#ident::#variant))),
+        );
+    }
+
+    Ok(quote! {
+        impl aeiou::Sheddable for #ident {
+            fn shed(effect: &#input) -> Option<Self> {
+                match effect {
+                    #(#arms,)*
+                    #[allow(unreachable_patterns)]
+                    _ => None,
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,403 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::collections::{BTreeMap, BTreeSet};
+use proc_macro2::{Span, TokenStream};
+use syn::{
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    token, Token,
+};
+
+mod kw {
+    syn::custom_keyword!(start);
+    syn::custom_keyword!(state);
+    syn::custom_keyword!(terminal);
+    syn::custom_keyword!(perform);
+    syn::custom_keyword!(on);
+}
+
+pub struct Machine {
+    effects: syn::Path,
+    outputs: syn::Path,
+    data: Vec<syn::Stmt>,
+    start: Target,
+    states: Vec<State>,
+}
+
+struct Target {
+    name: syn::Ident,
+    args: Vec<syn::Expr>,
+}
+
+struct State {
+    name: syn::Ident,
+    fields: Vec<(syn::Ident, syn::Type)>,
+    body: Body,
+}
+
+enum Body {
+    Terminal(syn::Expr),
+    Running {
+        perform: syn::Expr,
+        transitions: Vec<Transition>,
+    },
+}
+
+struct Transition {
+    pattern: syn::Pat,
+    guard: Option<syn::Expr>,
+    action: Option<syn::Block>,
+    target: Target,
+}
+
+impl Parse for Target {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        let args = if input.peek(token::Paren) {
+            let content;
+            syn::parenthesized!(content in input);
+            Punctuated::<syn::Expr, Token![,]>::parse_terminated(&content)?
+                .into_iter()
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Ok(Target { name, args })
+    }
+}
+
+struct Field(syn::Ident, syn::Type);
+
+impl Parse for Field {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![:]>()?;
+        Ok(Field(name, input.parse()?))
+    }
+}
+
+impl Parse for Transition {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        input.parse::<kw::on>()?;
+        let pattern = input.parse()?;
+        let guard = if input.peek(Token![if]) {
+            input.parse::<Token![if]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        input.parse::<Token![=>]>()?;
+        let action = if input.peek(token::Brace) {
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        let target = input.parse()?;
+        input.parse::<Token![;]>()?;
+        Ok(Transition {
+            pattern,
+            guard,
+            action,
+            target,
+        })
+    }
+}
+
+impl Parse for State {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let terminal = if input.peek(kw::terminal) {
+            input.parse::<kw::terminal>()?;
+            true
+        } else {
+            input.parse::<kw::state>()?;
+            false
+        };
+        let name = input.parse::<syn::Ident>()?;
+        let fields = if input.peek(token::Paren) {
+            let content;
+            syn::parenthesized!(content in input);
+            Punctuated::<Field, Token![,]>::parse_terminated(&content)?
+                .into_iter()
+                .map(|Field(name, ty)| (name, ty))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let body = if terminal {
+            input.parse::<Token![=>]>()?;
+            let value = input.parse()?;
+            input.parse::<Token![;]>()?;
+            Body::Terminal(value)
+        } else {
+            let content;
+            syn::braced!(content in input);
+            content.parse::<kw::perform>()?;
+            let perform = content.parse()?;
+            content.parse::<Token![;]>()?;
+            let mut transitions = Vec::new();
+            while !content.is_empty() {
+                transitions.push(content.parse()?);
+            }
+            if transitions.is_empty() {
+                return Err(syn::Error::new(
+                    name.span(),
+                    format!("state `{}` has no transitions", name),
+                ));
+            }
+            Body::Running {
+                perform,
+                transitions,
+            }
+        };
+        Ok(State { name, fields, body })
+    }
+}
+
+impl Parse for Machine {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let effects = input.parse()?;
+        input.parse::<Token![=>]>()?;
+        let outputs = input.parse()?;
+        input.parse::<Token![;]>()?;
+        let mut data = Vec::new();
+        while input.peek(Token![let]) {
+            data.push(input.parse()?);
+        }
+        input.parse::<kw::start>()?;
+        let start = input.parse()?;
+        input.parse::<Token![;]>()?;
+        let mut states = Vec::new();
+        while !input.is_empty() {
+            states.push(input.parse()?);
+        }
+        let machine = Machine {
+            effects,
+            outputs,
+            data,
+            start,
+            states,
+        };
+        machine.check()?;
+        Ok(machine)
+    }
+}
+
+impl Machine {
+    fn check(&self) -> syn::Result<()> {
+        let mut errors = Vec::new();
+        let mut targets = BTreeMap::new();
+        for state in &self.states {
+            let name = state.name.to_string();
+            if targets.contains_key(&name) {
+                errors.push(syn::Error::new(
+                    state.name.span(),
+                    format!("state `{}` is declared twice", name),
+                ));
+                continue;
+            }
+            let next = match &state.body {
+                Body::Terminal(_) => Vec::new(),
+                Body::Running { transitions, .. } => {
+                    transitions.iter().map(|t| &t.target.name).collect()
+                },
+            };
+            targets.insert(name, next);
+        }
+
+        let undeclared = |target: &syn::Ident| {
+            syn::Error::new(
+                target.span(),
+                format!("transition to undeclared state `{}`", target),
+            )
+        };
+        if !targets.contains_key(&self.start.name.to_string()) {
+            errors.push(undeclared(&self.start.name));
+        }
+        for next in targets.values() {
+            for target in next {
+                if !targets.contains_key(&target.to_string()) {
+                    errors.push(undeclared(target));
+                }
+            }
+        }
+
+        let mut reachable = BTreeSet::new();
+        let mut stack = vec![self.start.name.to_string()];
+        while let Some(name) = stack.pop() {
+            if let Some(next) = targets.get(&name) {
+                if reachable.insert(name) {
+                    stack.extend(next.iter().map(ToString::to_string));
+                }
+            }
+        }
+        for state in &self.states {
+            if !reachable.contains(&state.name.to_string()) {
+                errors.push(syn::Error::new(
+                    state.name.span(),
+                    format!(
+                        "state `{}` is unreachable from `{}`",
+                        state.name, self.start.name
+                    ),
+                ));
+            }
+        }
+
+        let mut errors = errors.into_iter();
+        match errors.next() {
+            None => Ok(()),
+            Some(mut first) => {
+                errors.for_each(|e| first.combine(e));
+                Err(first)
+            },
+        }
+    }
+
+    pub fn expand(self) -> TokenStream {
+        let Machine {
+            effects,
+            outputs,
+            data,
+            start,
+            states,
+        } = self;
+        let context = syn::Ident::new("context", Span::mixed_site());
+        let current = syn::Ident::new("current", Span::mixed_site());
+        let ty = syn::Ident::new("StateMachineState", Span::mixed_site());
+
+        let construct = |target: &Target| {
+            let Target { name, args } = target;
+            if args.is_empty() {
+                quote::quote!(#ty::#name)
+            } else {
+                quote::quote!(#ty::#name(#(#args),*))
+            }
+        };
+
+        let variants = states.iter().map(|state| {
+            let name = &state.name;
+            let types = state.fields.iter().map(|(_, ty)| ty);
+            if state.fields.is_empty() {
+                quote::quote!(#name)
+            } else {
+                quote::quote!(#name(#(#types),*))
+            }
+        });
+
+        let arms = states.iter().map(|state| {
+            let name = &state.name;
+            let bindings = state.fields.iter().map(|(binding, _)| binding);
+            let pattern = if state.fields.is_empty() {
+                quote::quote!(#ty::#name)
+            } else {
+                quote::quote!(#ty::#name(#(#bindings),*))
+            };
+            match &state.body {
+                Body::Terminal(value) => quote::quote! {
+                    #pattern => return #value,
+                },
+                Body::Running {
+                    perform,
+                    transitions,
+                } => {
+                    let perform = prefix_expr(&effects, perform.clone());
+                    let transitions = transitions.iter().map(|t| {
+                        let pattern = prefix_pat(&outputs, t.pattern.clone());
+                        let guard = t.guard.as_ref().map(|g| quote::quote!(if #g));
+                        let action = t.action.as_ref().map_or(&[][..], |a| &a.stmts);
+                        let target = construct(&t.target);
+                        quote::quote! {
+                            Some(#pattern) #guard => {
+                                #(#action)*
+                                #target
+                            },
+                        }
+                    });
+                    let message = format!("unexpected output in state `{}`", name);
+                    let scope = name.to_string();
+                    quote::quote! {
+                        #pattern => {
+                            let _scope = aeiou::Context::enter_scope(&#context, #scope);
+                            aeiou::perform!(#perform);
+                            match #context.take() {
+                                #(#transitions)*
+                                _ => panic!(#message),
+                            }
+                        },
+                    }
+                },
+            }
+        });
+
+        let start = construct(&start);
+        quote::quote! {
+            move |#context: aeiou::Context<#outputs>| {
+                enum #ty {
+                    #(#variants,)*
+                }
+
+                move || {
+                    #(#data)*
+                    let mut #current = #start;
+                    loop {
+                        #current = match #current {
+                            #(#arms)*
+                        };
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn prefix(base: &syn::Path, path: &mut syn::Path) {
+    if path.leading_colon.is_none() && path.segments.len() == 1 {
+        let mut full = base.clone();
+        full.segments.extend(path.segments.iter().cloned());
+        *path = full;
+    }
+}
+
+fn prefix_expr(base: &syn::Path, expr: syn::Expr) -> syn::Expr {
+    let mut expr = expr;
+    match &mut expr {
+        syn::Expr::Path(e) => prefix(base, &mut e.path),
+        syn::Expr::Call(e) => {
+            if let syn::Expr::Path(f) = &mut *e.func {
+                prefix(base, &mut f.path);
+            }
+        },
+        syn::Expr::Struct(e) => prefix(base, &mut e.path),
+        _ => (),
+    }
+    expr
+}
+
+fn prefix_pat(base: &syn::Path, pat: syn::Pat) -> syn::Pat {
+    let mut pat = pat;
+    match &mut pat {
+        syn::Pat::Path(p) => prefix(base, &mut p.path),
+        syn::Pat::TupleStruct(p) => prefix(base, &mut p.path),
+        syn::Pat::Struct(p) => prefix(base, &mut p.path),
+        // a bare capitalized identifier names a unit variant of the output
+        syn::Pat::Ident(p) if p.subpat.is_none() && is_variant(&p.ident) => {
+            let mut path = syn::Path::from(p.ident.clone());
+            prefix(base, &mut path);
+            pat = syn::Pat::Path(syn::PatPath {
+                attrs: Vec::new(),
+                qself: None,
+                path,
+            });
+        },
+        _ => (),
+    }
+    pat
+}
+
+fn is_variant(ident: &syn::Ident) -> bool {
+    ident
+        .to_string()
+        .chars()
+        .next()
+        .map_or(false, char::is_uppercase)
+}
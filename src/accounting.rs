@@ -0,0 +1,526 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    ops::Generator,
+    collections::BTreeMap,
+    fmt,
+};
+use super::{block::Block, identity::Identity};
+#[cfg(feature = "diagnostics")]
+use super::context::Context;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum Category {
+    Outputs,
+    // outputs of `put_deferred` and what observers deferred, not delivered yet
+    Deferred,
+    Tasks,
+    // sessions whose close effect is not performed yet
+    Cleanups,
+    // the raw entries of `with_history`, while the block runs
+    History,
+    // effects `prefetch` yielded ahead of the computation
+    Prefetched,
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Category::Outputs => write!(f, "pending context outputs"),
+            Category::Deferred => write!(f, "deferred outputs and effects"),
+            Category::Tasks => write!(f, "live tasks"),
+            Category::Cleanups => write!(f, "registered cleanups"),
+            Category::History => write!(f, "history entries"),
+            Category::Prefetched => write!(f, "prefetched effects"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum Counter {
+    Expired,
+    // responses dropped from the window of an `IdempotentHandler`
+    Evicted,
+    // checks made by the layers of `with_invariant`
+    InvariantChecks,
+    // effects `memoize` answered from its cache
+    MemoHits,
+    // effects withdrawn because their token is cancelled, and tasks stopped
+    CancelledEffects,
+    CancelledTasks,
+    // tasks dropped by `spawn_contained` after a panic
+    PanickedTasks,
+    // handlers `run_checked` started while degraded
+    DegradedHandlers,
+    // resources a `PoolHandler` opened, reused, queued acquisitions for and
+    // closed after idling
+    PoolOpened,
+    PoolReused,
+    PoolQueued,
+    PoolExpired,
+    // reservations of a `FixedQuotaHandler` expired before they were used
+    QuotaExpired,
+    // speculative effects of `prefetch` the computation performed, and the
+    // ones dropped when it did not
+    PrefetchHits,
+    PrefetchDiscarded,
+    // counted for each identity, the effects `stamp_meta` stamped with it and
+    // the ones an `IdentityPolicy` denied to it
+    Performed,
+    Denied,
+    // outputs and effects over their limit in `with_size_limits`, rejected,
+    // truncated or let through
+    PayloadRejected,
+    PayloadTruncated,
+    PayloadOversized,
+    // outputs nothing took by the time the block completed
+    LeftoverOutputs,
+}
+
+impl fmt::Display for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Counter::Expired => write!(f, "expired effects"),
+            Counter::Evicted => write!(f, "evicted idempotent responses"),
+            Counter::InvariantChecks => write!(f, "invariant checks"),
+            Counter::MemoHits => write!(f, "memoized responses"),
+            Counter::CancelledEffects => write!(f, "cancelled effects"),
+            Counter::CancelledTasks => write!(f, "cancelled tasks"),
+            Counter::PanickedTasks => write!(f, "panicked tasks"),
+            Counter::DegradedHandlers => write!(f, "degraded handlers"),
+            Counter::PoolOpened => write!(f, "opened pool resources"),
+            Counter::PoolReused => write!(f, "reused pool resources"),
+            Counter::PoolQueued => write!(f, "queued pool acquisitions"),
+            Counter::PoolExpired => write!(f, "expired pool resources"),
+            Counter::QuotaExpired => write!(f, "expired quota reservations"),
+            Counter::PrefetchHits => write!(f, "prefetched responses"),
+            Counter::PrefetchDiscarded => write!(f, "discarded prefetches"),
+            Counter::Performed => write!(f, "performed effects"),
+            Counter::Denied => write!(f, "denied effects"),
+            Counter::PayloadRejected => write!(f, "rejected payloads"),
+            Counter::PayloadTruncated => write!(f, "truncated payloads"),
+            Counter::PayloadOversized => write!(f, "oversized payloads"),
+            Counter::LeftoverOutputs => write!(f, "leftover outputs"),
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Usage {
+    pub live: usize,
+    pub high_water: usize,
+}
+
+// a `perform_counted!` site, the rounds count from the yield of the effect
+// to the response, so an answer on the next resume is one round
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct SiteStats {
+    pub file: &'static str,
+    pub line: u32,
+    pub count: u64,
+    pub avg_wait_rounds: f64,
+}
+
+// a source of `with_sources`, a round it used its whole budget and had more
+// is a round it starved
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SourceStats {
+    pub delivered: u64,
+    pub starved: u64,
+    pub terminated: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct SiteCounts {
+    count: u64,
+    rounds: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemReport(
+    BTreeMap<Category, Usage>,
+    BTreeMap<Counter, u64>,
+    BTreeMap<(&'static str, u32), SiteCounts>,
+    BTreeMap<(Identity, Counter), u64>,
+    BTreeMap<usize, SourceStats>,
+);
+
+impl MemReport {
+    pub fn get(&self, category: Category) -> Usage {
+        self.0.get(&category).cloned().unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Category, Usage)> + '_ {
+        self.0.iter().map(|(c, u)| (*c, *u))
+    }
+
+    pub fn count(&self, counter: Counter) -> u64 {
+        self.1.get(&counter).cloned().unwrap_or_default()
+    }
+
+    pub fn count_for(&self, identity: &Identity, counter: Counter) -> u64 {
+        self.3
+            .get(&(identity.clone(), counter))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // by the index of the source in `with_sources`
+    pub fn source(&self, index: usize) -> SourceStats {
+        self.4.get(&index).cloned().unwrap_or_default()
+    }
+
+    pub(crate) fn counters(&self) -> impl Iterator<Item = (Counter, u64)> + '_ {
+        self.1.iter().map(|(c, n)| (*c, *n))
+    }
+
+    // the most performed first
+    pub fn sites(&self) -> Vec<SiteStats> {
+        let mut sites = self
+            .2
+            .iter()
+            .map(|(&(file, line), counts)| SiteStats {
+                file,
+                line,
+                count: counts.count,
+                avg_wait_rounds: counts.rounds as f64 / counts.count as f64,
+            })
+            .collect::<Vec<_>>();
+        sites.sort_by(|a, b| b.count.cmp(&a.count));
+        sites
+    }
+
+    pub fn leaks(&self) -> impl Iterator<Item = (Category, usize)> + '_ {
+        self.iter()
+            .filter(|(_, usage)| usage.live != 0)
+            .map(|(c, usage)| (c, usage.live))
+    }
+}
+
+impl fmt::Display for MemReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (category, usage) in self.iter() {
+            writeln!(
+                f,
+                "{}: {} live, {} high-water",
+                category, usage.live, usage.high_water
+            )?;
+        }
+        for (counter, count) in &self.1 {
+            writeln!(f, "{}: {}", counter, count)?;
+        }
+        for ((identity, counter), count) in &self.3 {
+            writeln!(f, "{}: {}: {}", identity, counter, count)?;
+        }
+        for (index, stats) in &self.4 {
+            write!(
+                f,
+                "source {}: {} delivered, {} rounds starved",
+                index, stats.delivered, stats.starved
+            )?;
+            match stats.terminated {
+                Some(round) => writeln!(f, ", terminated in round {}", round)?,
+                None => writeln!(f)?,
+            }
+        }
+        for site in self.sites() {
+            writeln!(
+                f,
+                "{}:{}: {} performed, {:.2} rounds to respond",
+                site.file, site.line, site.count, site.avg_wait_rounds
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Accounting(Rc<RefCell<MemReport>>);
+
+impl Accounting {
+    pub fn report(&self) -> MemReport {
+        self.0.borrow().clone()
+    }
+
+    pub fn assert_quiescent(&self) {
+        let report = self.report();
+        let leaks = report
+            .leaks()
+            .map(|(category, live)| format!("{}: {}", category, live))
+            .collect::<Vec<_>>();
+        if !leaks.is_empty() {
+            panic!("not quiescent, {}", leaks.join(", "));
+        }
+    }
+
+    pub(crate) fn insert(&self, category: Category) {
+        let mut report = self.0.borrow_mut();
+        let usage = report.0.entry(category).or_default();
+        usage.live += 1;
+        usage.high_water = usage.high_water.max(usage.live);
+    }
+
+    pub(crate) fn remove(&self, category: Category) {
+        let mut report = self.0.borrow_mut();
+        let usage = report.0.entry(category).or_default();
+        usage.live = usage.live.saturating_sub(1);
+    }
+
+    pub(crate) fn bump(&self, counter: Counter) {
+        *self.0.borrow_mut().1.entry(counter).or_default() += 1;
+    }
+
+    pub(crate) fn bump_for(&self, identity: &Identity, counter: Counter) {
+        let mut report = self.0.borrow_mut();
+        *report.3.entry((identity.clone(), counter)).or_default() += 1;
+    }
+
+    pub(crate) fn source<F>(&self, index: usize, f: F)
+    where
+        F: FnOnce(&mut SourceStats),
+    {
+        f(self.0.borrow_mut().4.entry(index).or_default())
+    }
+
+    #[cfg(feature = "diagnostics")]
+    fn site(&self, file: &'static str, line: u32, rounds: u64) {
+        let mut report = self.0.borrow_mut();
+        let counts = report.2.entry((file, line)).or_default();
+        counts.count += 1;
+        counts.rounds += rounds;
+    }
+
+    #[cfg(feature = "diagnostics")]
+    pub(crate) fn set(&self, category: Category, live: usize) {
+        let mut report = self.0.borrow_mut();
+        let usage = report.0.entry(category).or_default();
+        usage.live = live;
+        usage.high_water = usage.high_water.max(live);
+    }
+}
+
+impl<T, G> Block<T, G>
+where
+    G: Unpin + Generator<()>,
+{
+    // without diagnostics the accounting is never updated, the report stays empty
+    pub fn with_accounting(self) -> (Self, Accounting) {
+        let accounting = self.context().install_accounting();
+        (self, accounting)
+    }
+}
+
+// the location is the one of the macro invocation calling this
+#[cfg(feature = "diagnostics")]
+#[doc(hidden)]
+#[track_caller]
+pub fn count_site<T>(context: &Context<T>, rounds: u64) {
+    let location = std::panic::Location::caller();
+    context.account(|a| a.site(location.file(), location.line(), rounds));
+}
+
+// Like `perform!`, also counts the site in the accounting. The second form
+// yields `idle` until the response is there, counting the rounds it takes.
+#[cfg(feature = "diagnostics")]
+#[macro_export]
+macro_rules! perform_counted {
+    ($e:expr, $ctx:expr) => {{
+        let output = $crate::perform!($e, $ctx);
+        $crate::count_site($ctx, 1);
+        output
+    }};
+    ($e:expr, $ctx:expr, $idle:expr) => {{
+        yield $e;
+        let mut rounds = 1;
+        let output = loop {
+            match $crate::Select::take($ctx) {
+                Some(output) => break output,
+                None => {
+                    rounds += 1;
+                    yield $idle;
+                },
+            }
+        };
+        $crate::count_site($ctx, rounds);
+        output
+    }};
+}
+
+// nothing is counted, the sites are plain `perform!`
+#[cfg(not(feature = "diagnostics"))]
+#[macro_export]
+macro_rules! perform_counted {
+    ($e:expr, $ctx:expr) => {
+        $crate::perform!($e, $ctx)
+    };
+    ($e:expr, $ctx:expr, $idle:expr) => {{
+        yield $e;
+        loop {
+            match $crate::Select::take($ctx) {
+                Some(output) => break output,
+                None => yield $idle,
+            }
+        }
+    }};
+}
+
+#[cfg(all(test, feature = "diagnostics"))]
+mod tests {
+    use std::ops::GeneratorState;
+    use crate::{Context, Effect, Select, IntoBlock, HistoryConfig, perform, perform_counted};
+    use super::Category;
+
+    #[derive(Debug)]
+    enum Effects {
+        Get,
+        Slow,
+        Idle,
+    }
+
+    enum Outputs {
+        Got(u32),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    struct Got(u32);
+
+    impl Select<Got> for Outputs {
+        fn take(output: &Context<Self>) -> Option<Got> {
+            match output.take()? {
+                Outputs::Got(v) => Some(Got(v)),
+            }
+        }
+    }
+
+    fn handler(effect: Effects) -> Result<Outputs, Effects> {
+        match effect {
+            Effects::Get => Ok(Outputs::Got(42)),
+            other => Err(other),
+        }
+    }
+
+    #[test]
+    fn quiescent() {
+        let computation = |context: Context<Outputs>| {
+            move || {
+                let Got(a) = perform!(Effects::Get, &context);
+                let Got(b) = perform!(Effects::Get, &context);
+                assert_eq!(a + b, 84);
+            }
+        };
+        let (block, accounting) = computation
+            .into_block()
+            .add_handler(handler)
+            .assert_handled()
+            .with_accounting();
+        block.run();
+        accounting.assert_quiescent();
+        assert_eq!(accounting.report().get(Category::Outputs).high_water, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "pending context outputs: 1")]
+    fn leaky() {
+        let computation = |_: Context<Outputs>| {
+            move || {
+                perform!(Effects::Get);
+            }
+        };
+        let (block, accounting) = computation
+            .into_block()
+            .add_handler(handler)
+            .assert_handled()
+            .with_accounting();
+        block.run();
+        accounting.assert_quiescent();
+    }
+
+    #[test]
+    #[should_panic(expected = "deferred outputs and effects: 1")]
+    fn leaky_deferred() {
+        let computation = |context: Context<Outputs>| {
+            move || {
+                let Got(_) = perform!(Effects::Get, &context);
+                // nothing takes after it
+                context.put_deferred(Outputs::Got(0));
+            }
+        };
+        let (block, accounting) = computation
+            .into_block()
+            .add_handler(handler)
+            .assert_handled()
+            .with_accounting();
+        block.run();
+        accounting.assert_quiescent();
+    }
+
+    #[test]
+    fn history_entries() {
+        let computation = |context: Context<Outputs>| {
+            move || {
+                for _ in 0..5 {
+                    let Got(_) = perform!(Effects::Get, &context);
+                }
+            }
+        };
+        let (block, history) = computation
+            .into_block()
+            .with_history(HistoryConfig::new(3));
+        let (block, accounting) = block.add_handler(handler).assert_handled().with_accounting();
+        block.run();
+        // the handle keeps them, the block does not
+        accounting.assert_quiescent();
+        assert_eq!(accounting.report().get(Category::History).high_water, 3);
+        assert_eq!(history.raw().len(), 3);
+    }
+
+    #[test]
+    fn sites() {
+        let computation = |context: Context<Outputs>| {
+            move || {
+                for i in 0..5 {
+                    let Got(_) = perform_counted!(Effects::Get, &context, Effects::Idle);
+                    if i % 2 == 0 {
+                        let Got(_) = perform_counted!(Effects::Slow, &context, Effects::Idle);
+                    }
+                }
+            }
+        };
+        let (mut block, accounting) = computation.into_block().with_accounting();
+        // the slow effect is answered on the second idle after it
+        let mut delay = None;
+        while let GeneratorState::Yielded(effect) = block.resume() {
+            match effect {
+                Effects::Get => block.put(Outputs::Got(1)),
+                Effects::Slow => delay = Some(2),
+                Effects::Idle => match delay.take() {
+                    Some(1) => block.put(Outputs::Got(2)),
+                    Some(n) => delay = Some(n - 1),
+                    None => unreachable!(),
+                },
+            }
+        }
+        let sites = accounting.report().sites();
+        let summary = sites
+            .iter()
+            .map(|s| (s.file, s.count, s.avg_wait_rounds))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [("src/accounting.rs", 5, 1.0), ("src/accounting.rs", 3, 3.0)]
+        );
+        assert_eq!(sites[1].line, sites[0].line + 2);
+    }
+}
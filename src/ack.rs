@@ -0,0 +1,71 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::mem;
+use super::{
+    computation::{Effect, TakeResult},
+    context::Context,
+};
+
+// The output that acknowledges a fire-and-forget effect, the derive maps every
+// `#[ack(EffectVariant)]` unit variant to the effect variant it names.
+pub trait Ack
+where
+    Self: Effect + Sized,
+{
+    fn ack_for(effect: &Self::Input) -> Option<Self>;
+
+    // `Ack::of::<Printed>()` in a handler, the part names the variant
+    fn of<P>() -> Self
+    where
+        P: AckPart<Self>,
+    {
+        P::ack()
+    }
+}
+
+// the unit part the derive generates for an acknowledging variant
+pub trait AckPart<E> {
+    fn ack() -> E;
+}
+
+// A handler of the acknowledged effects from a closure that only does the
+// side effect, the acknowledgement is the one `Ack::ack_for` gives.
+pub fn acking_handler<E, F>(f: F) -> impl FnMut(E::Input) -> Result<E, E::Input>
+where
+    E: Ack,
+    F: FnMut(E::Input),
+{
+    let mut f = f;
+    move |effect| match E::ack_for(&effect) {
+        Some(ack) => {
+            f(effect);
+            Ok(ack)
+        },
+        None => Err(effect),
+    }
+}
+
+#[doc(hidden)]
+pub fn expected<E>(context: &Context<E>, effect: &E::Input) -> E
+where
+    E: Ack,
+{
+    let _ = context;
+    E::ack_for(effect).expect("the effect is not acknowledged, mark its output `#[ack(..)]`")
+}
+
+// another output in front is mismatched, like a part that does not match
+#[doc(hidden)]
+pub fn acknowledged<E>(context: &Context<E>, expected: E) -> TakeResult<(), E>
+where
+    E: Ack,
+{
+    match context.take() {
+        None => TakeResult::Empty,
+        Some(ack) if mem::discriminant(&ack) == mem::discriminant(&expected) => {
+            TakeResult::Matched(())
+        },
+        Some(other) => TakeResult::Mismatched(other),
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::Cell,
+    ops::{Generator, GeneratorState},
+};
+use super::block::Block;
+
+#[derive(Default)]
+struct Counts {
+    rounds: Cell<u64>,
+    total: Cell<u64>,
+    steady: Cell<u64>,
+    worst: Cell<u64>,
+}
+
+// the allocations made inside the block, by round
+#[derive(Clone, Default)]
+pub struct AllocTracking(Rc<Counts>);
+
+impl AllocTracking {
+    pub fn rounds(&self) -> u64 {
+        self.0.rounds.get()
+    }
+
+    pub fn allocations(&self) -> u64 {
+        self.0.total.get()
+    }
+
+    // in the rounds after the warm-up
+    pub fn steady(&self) -> u64 {
+        self.0.steady.get()
+    }
+
+    // the most one round after the warm-up made
+    pub fn worst_steady_round(&self) -> u64 {
+        self.0.worst.get()
+    }
+}
+
+impl<T, G> Block<T, G>
+where
+    G: Unpin + Generator<()>,
+{
+    // Every resume of the block is a round, `counter` gives the allocations so
+    // far, usually from a counting global allocator of a test binary. The
+    // layer goes last, so the handlers are counted too. It allocates nothing
+    // itself.
+    pub fn with_alloc_tracking(
+        self,
+        warm_up: u64,
+        counter: fn() -> u64,
+    ) -> (
+        Block<T, impl Unpin + Generator<(), Return = G::Return, Yield = G::Yield>>,
+        AllocTracking,
+    ) {
+        let context = self.context();
+        let tracking = AllocTracking::default();
+        let counts = tracking.0.clone();
+        let mut s = self;
+        let generator = move || loop {
+            let before = counter();
+            let step = s.resume();
+            let made = counter() - before;
+            let round = counts.rounds.get() + 1;
+            counts.rounds.set(round);
+            counts.total.set(counts.total.get() + made);
+            if round > warm_up {
+                counts.steady.set(counts.steady.get() + made);
+                counts.worst.set(counts.worst.get().max(made));
+            }
+            match step {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(y) => yield y,
+            }
+        };
+        (Block::new(context, generator), tracking)
+    }
+}
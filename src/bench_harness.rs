@@ -0,0 +1,707 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt,
+    ops::{Generator, GeneratorState},
+    time::{Duration, Instant},
+};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use super::{
+    block::Block,
+    computation::{Effect, Handler, dispatch},
+    deadline,
+    health::HealthStatus,
+    plugin::{ResourceKey, Resources},
+    trace::{Recording, MigrateError},
+};
+
+// One effect of a captured run and the response it got. `at` is the time
+// since the first effect on the clock of `deadline`, a test clock makes it
+// exact.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Step<I, E> {
+    pub effect: I,
+    pub response: E,
+    pub at: Duration,
+}
+
+// the layer name the responses taken from the workload are counted under
+pub const SCRIPT: &str = "script";
+
+#[derive(Clone)]
+pub struct CaptureHandle(Rc<RefCell<Recording>>);
+
+impl CaptureHandle {
+    pub fn recording(&self) -> Recording {
+        self.0.borrow().clone()
+    }
+}
+
+pub struct Capturing<H> {
+    inner: H,
+    start: Option<Instant>,
+    recording: CaptureHandle,
+}
+
+// like `trace::record`, but keeps the effects next to the responses
+pub fn capture<H>(handler: H, version: u32) -> (Capturing<H>, CaptureHandle) {
+    let recording = CaptureHandle(Rc::new(RefCell::new(Recording {
+        version,
+        events: Vec::new(),
+    })));
+    let capturing = Capturing {
+        inner: handler,
+        start: None,
+        recording: recording.clone(),
+    };
+    (capturing, recording)
+}
+
+impl<E, H> Handler<E> for Capturing<H>
+where
+    E: Effect + Clone + Serialize,
+    E::Input: Clone + Serialize,
+    H: Handler<E>,
+{
+    fn handle(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        let now = deadline::now();
+        let start = *self.start.get_or_insert(now);
+        let response = self.inner.handle(effect.clone())?;
+        let step = Step {
+            effect,
+            response: response.clone(),
+            at: now - start,
+        };
+        let event = serde_json::to_value(step).expect("captured step must serialize");
+        self.recording.0.borrow_mut().events.push(event);
+        Ok(response)
+    }
+
+    fn init(&mut self) {
+        self.inner.init()
+    }
+
+    fn finish(&mut self) {
+        self.inner.finish()
+    }
+
+    fn health_check(&mut self) -> HealthStatus {
+        self.inner.health_check()
+    }
+
+    fn provides(&self) -> &'static [ResourceKey] {
+        self.inner.provides()
+    }
+
+    fn requires(&self) -> &'static [ResourceKey] {
+        self.inner.requires()
+    }
+
+    fn init_with(&mut self, resources: &mut Resources) {
+        self.inner.init_with(resources)
+    }
+
+    fn flush(&mut self) -> Result<(), E::Input> {
+        self.inner.flush()
+    }
+}
+
+// A captured trace ready to be replayed, `repeat` and `fan_out` scale it
+// without capturing anything new.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workload<I, E> {
+    steps: Vec<Step<I, E>>,
+    repeat: usize,
+    fan_out: usize,
+}
+
+impl<I, E> Workload<I, E>
+where
+    I: DeserializeOwned,
+    E: DeserializeOwned,
+{
+    pub fn from_trace(trace: Recording) -> Result<Self, MigrateError> {
+        let steps = trace
+            .events
+            .into_iter()
+            .enumerate()
+            .map(|(index, event)| {
+                serde_json::from_value(event).map_err(|e| MigrateError::Decode {
+                    index: Some(index),
+                    message: e.to_string(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Workload {
+            steps,
+            repeat: 1,
+            fan_out: 1,
+        })
+    }
+}
+
+impl<I, E> Workload<I, E> {
+    // every simulated task runs the computation `times` times one after the other
+    pub fn repeat(self, times: usize) -> Self {
+        Workload {
+            repeat: times.max(1),
+            ..self
+        }
+    }
+
+    // `tasks` copies of the computation, resumed in turn every round
+    pub fn fan_out(self, tasks: usize) -> Self {
+        Workload {
+            fan_out: tasks.max(1),
+            ..self
+        }
+    }
+
+    pub fn steps(&self) -> &[Step<I, E>] {
+        &self.steps
+    }
+
+    // the captured time from the first effect to the last one
+    pub fn span(&self) -> Duration {
+        self.steps.last().map(|step| step.at).unwrap_or_default()
+    }
+}
+
+type Layer<E> = (String, Box<dyn Handler<E>>);
+
+// The handlers under measurement, tried in order before the workload answers.
+// A handler that answers takes the place of the captured response.
+pub struct HandlerSet<E>(Vec<Layer<E>>);
+
+impl<E> Default for HandlerSet<E> {
+    fn default() -> Self {
+        HandlerSet(Vec::new())
+    }
+}
+
+impl<E> HandlerSet<E>
+where
+    E: Effect,
+{
+    pub fn new() -> Self {
+        HandlerSet::default()
+    }
+
+    pub fn layer<H>(mut self, name: &str, handler: H) -> Self
+    where
+        H: Handler<E> + 'static,
+    {
+        self.0.push((name.to_string(), Box::new(handler)));
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HarnessConfig {
+    budget: u64,
+    counter: Option<fn() -> u64>,
+    counted: f64,
+    wall: Option<f64>,
+}
+
+impl Default for HarnessConfig {
+    fn default() -> Self {
+        HarnessConfig {
+            budget: 1 << 20,
+            counter: None,
+            counted: 0.0,
+            wall: Some(0.25),
+        }
+    }
+}
+
+impl HarnessConfig {
+    pub fn new() -> Self {
+        HarnessConfig::default()
+    }
+
+    // the rounds until the run is cut off
+    pub fn budget(self, rounds: u64) -> Self {
+        HarnessConfig {
+            budget: rounds,
+            ..self
+        }
+    }
+
+    // same as the counter of `with_alloc_tracking`
+    pub fn count_allocations(self, counter: fn() -> u64) -> Self {
+        HarnessConfig {
+            counter: Some(counter),
+            ..self
+        }
+    }
+
+    // the relative change of a counted metric `compare` reports, any change by default
+    pub fn counted_threshold(self, ratio: f64) -> Self {
+        HarnessConfig {
+            counted: ratio,
+            ..self
+        }
+    }
+
+    // `None` leaves the wall time out of `compare`
+    pub fn wall_threshold(self, ratio: Option<f64>) -> Self {
+        HarnessConfig {
+            wall: ratio,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Metric {
+    Rounds,
+    Resumes,
+    Effects,
+    // the effects that differ from the captured ones at their position
+    Diverged,
+    Allocations,
+    // the tasks that ran past the end of the workload or out of budget
+    Unfinished,
+    Dispatched(String),
+    WallMicros,
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Metric::Rounds => write!(f, "rounds"),
+            Metric::Resumes => write!(f, "resumes"),
+            Metric::Effects => write!(f, "effects"),
+            Metric::Diverged => write!(f, "diverged effects"),
+            Metric::Allocations => write!(f, "allocations"),
+            Metric::Unfinished => write!(f, "unfinished tasks"),
+            Metric::Dispatched(layer) => write!(f, "dispatched to `{}`", layer),
+            Metric::WallMicros => write!(f, "wall time (us)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct BenchReport {
+    pub rounds: u64,
+    pub resumes: u64,
+    pub effects: u64,
+    pub diverged: u64,
+    pub allocations: Option<u64>,
+    pub dispatched: BTreeMap<String, u64>,
+    pub wall: Duration,
+    pub unfinished: u64,
+    counted: f64,
+    wall_threshold: Option<f64>,
+}
+
+impl BenchReport {
+    // everything but the wall time, the same on every run of one workload
+    pub fn counted(&self) -> Vec<(Metric, u64)> {
+        let mut metrics = vec![
+            (Metric::Rounds, self.rounds),
+            (Metric::Resumes, self.resumes),
+            (Metric::Effects, self.effects),
+            (Metric::Diverged, self.diverged),
+        ];
+        metrics.extend(self.allocations.map(|a| (Metric::Allocations, a)));
+        metrics.push((Metric::Unfinished, self.unfinished));
+        metrics.extend(
+            self.dispatched
+                .iter()
+                .map(|(layer, n)| (Metric::Dispatched(layer.clone()), *n)),
+        );
+        metrics
+    }
+
+    // `self` against the `baseline`, with the thresholds of the run of `self`
+    pub fn compare(&self, baseline: &BenchReport) -> RegressionSummary {
+        let before = baseline.counted().into_iter().collect::<BTreeMap<_, _>>();
+        let after = self.counted().into_iter().collect::<BTreeMap<_, _>>();
+        let mut metrics = before.keys().chain(after.keys()).collect::<Vec<_>>();
+        metrics.sort();
+        metrics.dedup();
+        let mut deltas = metrics
+            .into_iter()
+            .map(|metric| {
+                let baseline = before.get(metric).copied().unwrap_or(0);
+                let current = after.get(metric).copied().unwrap_or(0);
+                Delta::new(metric.clone(), baseline, current, Some(self.counted))
+            })
+            .collect::<Vec<_>>();
+        let micros = |d: Duration| d.as_micros() as u64;
+        deltas.push(Delta::new(
+            Metric::WallMicros,
+            micros(baseline.wall),
+            micros(self.wall),
+            self.wall_threshold,
+        ));
+        RegressionSummary(deltas)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delta {
+    pub metric: Metric,
+    pub baseline: u64,
+    pub current: u64,
+    // the change is larger than the threshold
+    pub beyond: bool,
+}
+
+impl Delta {
+    fn new(metric: Metric, baseline: u64, current: u64, threshold: Option<f64>) -> Self {
+        let change = if baseline == 0 {
+            if current == 0 {
+                0.0
+            } else {
+                f64::INFINITY
+            }
+        } else {
+            (current as f64 - baseline as f64).abs() / baseline as f64
+        };
+        let beyond = threshold.map_or(false, |threshold| change > threshold);
+        Delta {
+            metric,
+            baseline,
+            current,
+            beyond,
+        }
+    }
+
+    // relative, positive if it grew
+    pub fn change(&self) -> f64 {
+        (self.current as f64 - self.baseline as f64) / self.baseline.max(1) as f64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegressionSummary(Vec<Delta>);
+
+impl RegressionSummary {
+    pub fn deltas(&self) -> &[Delta] {
+        &self.0
+    }
+
+    // the metrics that grew beyond the threshold
+    pub fn regressions(&self) -> impl Iterator<Item = &Delta> {
+        self.0.iter().filter(|d| d.beyond && d.current > d.baseline)
+    }
+
+    pub fn improvements(&self) -> impl Iterator<Item = &Delta> {
+        self.0.iter().filter(|d| d.beyond && d.current < d.baseline)
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.regressions().next().is_none()
+    }
+}
+
+impl fmt::Display for RegressionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for delta in self.0.iter().filter(|d| d.beyond) {
+            writeln!(
+                f,
+                "{}: {} -> {} ({:+.1}%)",
+                delta.metric,
+                delta.baseline,
+                delta.current,
+                delta.change() * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct Task<B> {
+    block: B,
+    // the position in the workload
+    cursor: usize,
+    runs: usize,
+}
+
+pub struct Harness;
+
+impl Harness {
+    // Drives the blocks of `constructor` answering from the workload, the
+    // handlers of the set go first. One round resumes every simulated task
+    // once, a finished task starts over until it ran `repeat` times.
+    pub fn run<E, F, G>(
+        workload: &Workload<E::Input, E>,
+        constructor: F,
+        handlers: &mut HandlerSet<E>,
+        config: &HarnessConfig,
+    ) -> BenchReport
+    where
+        E: Effect + Clone,
+        E::Input: PartialEq,
+        F: FnMut() -> Block<E, G>,
+        G: Unpin + Generator<(), Yield = E::Input>,
+    {
+        let mut constructor = constructor;
+        let counter = config.counter.unwrap_or(|| 0);
+        let mut report = BenchReport {
+            rounds: 0,
+            resumes: 0,
+            effects: 0,
+            diverged: 0,
+            allocations: config.counter.map(|_| 0),
+            dispatched: BTreeMap::new(),
+            wall: Duration::ZERO,
+            unfinished: 0,
+            counted: config.counted,
+            wall_threshold: config.wall,
+        };
+        for (name, _) in &handlers.0 {
+            report.dispatched.insert(name.clone(), 0);
+        }
+        report.dispatched.insert(SCRIPT.to_string(), 0);
+        let mut allocations = 0;
+
+        for (_, handler) in &mut handlers.0 {
+            handler.init();
+        }
+        let start = Instant::now();
+        let mut tasks = (0..workload.fan_out)
+            .map(|_| Task {
+                block: constructor(),
+                cursor: 0,
+                runs: 0,
+            })
+            .collect::<Vec<_>>();
+        while !tasks.is_empty() {
+            if report.rounds == config.budget {
+                report.unfinished += tasks.len() as u64;
+                break;
+            }
+            report.rounds += 1;
+            let mut index = 0;
+            while index < tasks.len() {
+                let task = &mut tasks[index];
+                let before = counter();
+                report.resumes += 1;
+                let effect = match task.block.resume() {
+                    GeneratorState::Yielded(effect) => effect,
+                    GeneratorState::Complete(_) => {
+                        task.runs += 1;
+                        if task.runs < workload.repeat {
+                            task.block = constructor();
+                            task.cursor = 0;
+                            index += 1;
+                        } else {
+                            tasks.remove(index);
+                        }
+                        allocations += counter() - before;
+                        continue;
+                    },
+                };
+                report.effects += 1;
+                let position = task.cursor;
+                task.cursor += 1;
+                let mut effect = Some(effect);
+                for (name, handler) in &mut handlers.0 {
+                    match dispatch(
+                        &mut **handler,
+                        &task.block.context(),
+                        effect.take().unwrap(),
+                    ) {
+                        Ok(response) => {
+                            task.block.put(response);
+                            *report.dispatched.get_mut(name).unwrap() += 1;
+                            break;
+                        },
+                        Err(unhandled) => effect = Some(unhandled),
+                    }
+                }
+                let mut finished = false;
+                if let Some(effect) = effect {
+                    match workload.steps.get(position) {
+                        Some(step) => {
+                            if step.effect != effect {
+                                report.diverged += 1;
+                            }
+                            task.block.put(step.response.clone());
+                            *report.dispatched.get_mut(SCRIPT).unwrap() += 1;
+                        },
+                        None => finished = true,
+                    }
+                }
+                allocations += counter() - before;
+                if finished {
+                    report.unfinished += 1;
+                    tasks.remove(index);
+                } else {
+                    index += 1;
+                }
+            }
+        }
+        report.wall = start.elapsed();
+        for (_, handler) in &mut handlers.0 {
+            handler.finish();
+        }
+        if let Some(total) = &mut report.allocations {
+            *total = allocations;
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Generator;
+    use serde::{Serialize, Deserialize};
+    use crate::{Context, Effect, Select, IntoBlock, perform};
+    use super::{
+        Workload, Harness, HandlerSet, HarnessConfig, Metric, Delta, Recording, SCRIPT, capture,
+    };
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Effects {
+        Fetch(u32),
+        Log(String),
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Outputs {
+        Fetched(u32),
+        Logged,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    struct Fetched(u32);
+
+    impl Select<Fetched> for Outputs {
+        fn take(output: &Context<Self>) -> Option<Fetched> {
+            match output.take()? {
+                Outputs::Fetched(n) => Some(Fetched(n)),
+                other => {
+                    output.put_front(other);
+                    None
+                },
+            }
+        }
+    }
+
+    fn summer(
+        context: Context<Outputs>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = u32> {
+        move || {
+            let mut sum = 0;
+            for key in 0..3 {
+                let Fetched(n) = perform!(Effects::Fetch(key), &context);
+                sum += n;
+                yield Effects::Log(format!("{}", sum));
+                context.take();
+            }
+            sum
+        }
+    }
+
+    fn captured() -> Recording {
+        let (capturing, handle) = capture(
+            |effect| match effect {
+                Effects::Fetch(key) => Ok(Outputs::Fetched(key * 10)),
+                Effects::Log(_) => Ok(Outputs::Logged),
+            },
+            1,
+        );
+        let sum = summer
+            .into_block()
+            .add_handler(capturing)
+            .assert_handled()
+            .run();
+        assert_eq!(sum, 30);
+        handle.recording()
+    }
+
+    #[test]
+    fn counts_are_stable() {
+        let workload = Workload::<Effects, Outputs>::from_trace(captured()).unwrap();
+        assert_eq!(workload.steps().len(), 6);
+        let workload = workload.repeat(2).fan_out(3);
+        let config = HarnessConfig::new().wall_threshold(None);
+        let run = || {
+            let logs = |effect| match effect {
+                Effects::Log(_) => Ok(Outputs::Logged),
+                other => Err(other),
+            };
+            let mut handlers = HandlerSet::new().layer("logs", logs);
+            Harness::run(&workload, || summer.into_block(), &mut handlers, &config)
+        };
+        let first = run();
+        assert_eq!(first.effects, 36);
+        assert_eq!(first.diverged, 0);
+        assert_eq!(first.unfinished, 0);
+        // six effects and the completion, twice, the tasks go side by side
+        assert_eq!(first.rounds, 14);
+        assert_eq!(first.resumes, 42);
+        let counted = first.counted();
+        assert!(counted.contains(&(Metric::Dispatched("logs".to_string()), 18)));
+        assert!(counted.contains(&(Metric::Dispatched(SCRIPT.to_string()), 18)));
+        for _ in 0..3 {
+            let again = run();
+            assert_eq!(again.counted(), counted);
+            assert!(again.compare(&first).is_clean());
+        }
+    }
+
+    #[test]
+    fn regression_is_reported() {
+        let workload = Workload::<Effects, Outputs>::from_trace(captured()).unwrap();
+        let config = HarnessConfig::new().wall_threshold(None);
+        let baseline = Harness::run(
+            &workload,
+            || summer.into_block(),
+            &mut HandlerSet::new(),
+            &config,
+        );
+        // the same workload with every effect going through one more layer
+        let mut handlers = HandlerSet::new().layer("noisy", Err::<Outputs, Effects>);
+        let current = Harness::run(&workload, || summer.into_block(), &mut handlers, &config);
+        assert!(current.compare(&baseline).is_clean());
+
+        // a workload the computation outgrows, it is cut at the end
+        let recording = captured();
+        let short = Workload::<Effects, Outputs>::from_trace(Recording {
+            events: recording.events.into_iter().take(4).collect(),
+            ..recording
+        })
+        .unwrap();
+        let current = Harness::run(
+            &short,
+            || summer.into_block(),
+            &mut HandlerSet::new(),
+            &config,
+        );
+        assert_eq!(current.unfinished, 1);
+        let summary = current.compare(&baseline);
+        let metrics = |deltas: Vec<&Delta>| {
+            deltas
+                .into_iter()
+                .map(|d| d.metric.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            metrics(summary.regressions().collect()),
+            [Metric::Unfinished]
+        );
+        assert_eq!(
+            metrics(summary.improvements().collect()),
+            [
+                Metric::Rounds,
+                Metric::Resumes,
+                Metric::Effects,
+                Metric::Dispatched(SCRIPT.to_string()),
+            ],
+        );
+    }
+}
@@ -1,65 +1,166 @@
 // Copyright 2021 Vladislav Melnik
 // SPDX-License-Identifier: MIT
 
-use std::{pin::Pin, ops::{Generator, GeneratorState}};
-use super::context::Context;
+use std::{
+    iter,
+    pin::Pin,
+    ops::{Generator, GeneratorState},
+};
+use super::{context::Context, identity::Identity, sealed::Sealed, stats};
 
 pub struct Block<T, G>
 where
-    G: Unpin + Generator<(), Return = ()>,
+    G: Unpin + Generator<()>,
 {
     context: Context<T>,
     generator: G,
 }
 
+/// Turns a computation, a closure from a context to a generator, into a block.
+///
+/// ```
+/// #![feature(generators, generator_trait)]
+/// use std::ops::GeneratorState;
+/// use aeiou::{Context, IntoBlock};
+///
+/// aeiou::simple_effects! {
+///     Effects { Ping }
+///     Outputs { Pong }
+/// }
+///
+/// let mut block = (|context: Context<Outputs>| {
+///     move || {
+///         yield Effects::Ping;
+///         context.take()
+///     }
+/// })
+/// .into_block();
+/// assert_eq!(block.resume(), GeneratorState::Yielded(Effects::Ping));
+/// block.put(Outputs::Pong);
+/// assert_eq!(block.resume(), GeneratorState::Complete(Some(Outputs::Pong)));
+/// ```
 pub trait IntoBlock<T, G>
 where
-    G: Unpin + Generator<(), Return = ()>,
+    Self: Sealed<(T, G)>,
+    G: Unpin + Generator<()>,
 {
     fn into_block(self) -> Block<T, G>;
+
+    // the effects of the block and of the tasks it spawns are attributed to
+    // `identity`, see `EffectMeta::identity`
+    fn into_block_as(self, identity: Identity) -> Block<T, G>
+    where
+        Self: Sized,
+    {
+        let block = self.into_block();
+        block.context().set_identity(Some(identity));
+        block
+    }
+}
+
+impl<F, T, G> Sealed<(T, G)> for F
+where
+    F: FnOnce(Context<T>) -> G,
+    G: Unpin + Generator<()>,
+{
 }
 
 impl<F, T, G> IntoBlock<T, G> for F
 where
     F: FnOnce(Context<T>) -> G,
-    G: Unpin + Generator<(), Return = ()>,
+    G: Unpin + Generator<()>,
 {
     fn into_block(self) -> Block<T, G> {
         let context = Context::empty();
-        Block {
-            context: context.clone(),
-            generator: self(context),
-        }
+        Block::new(context.clone(), self(context))
     }
 }
 
-impl<T, G> Block<T, G>
+// Like `IntoBlock` for a computation that takes arguments along with the
+// context, so one constructor is instantiated with different ones.
+pub trait IntoBlockWith<Args, T, G>
 where
-    G: Unpin + Generator<(), Return = (), Yield = !>,
+    Self: Sealed<(Args, T, G)>,
+    G: Unpin + Generator<()>,
 {
-    pub fn run(self) {
-        let Block { mut generator, .. } = self;
-        match Pin::new(&mut generator).resume(()) {
-            GeneratorState::Complete(()) => (),
-            GeneratorState::Yielded(_) => unreachable!(),
-        }
+    fn into_block_with(self, args: Args) -> Block<T, G>;
+}
+
+impl<F, Args, T, G> Sealed<(Args, T, G)> for F
+where
+    F: FnOnce(Args, Context<T>) -> G,
+    G: Unpin + Generator<()>,
+{
+}
+
+impl<F, Args, T, G> IntoBlockWith<Args, T, G> for F
+where
+    F: FnOnce(Args, Context<T>) -> G,
+    G: Unpin + Generator<()>,
+{
+    fn into_block_with(self, args: Args) -> Block<T, G> {
+        (move |context: Context<T>| self(args, context)).into_block()
     }
 }
 
 impl<T, G> Block<T, G>
 where
-    G: Unpin + Generator<(), Return = ()>,
+    G: Unpin + Generator<(), Yield = !>,
 {
-    // TODO: remove this
-    pub(super) fn new(context: Context<T>, generator: G) -> Self {
-        Block {
+    pub fn run(self) -> G::Return {
+        let Block {
             context,
-            generator,
-        }
+            mut generator,
+        } = self;
+        let round = stats::begin_round(&context);
+        let r = {
+            let _resuming = context.resuming();
+            match Pin::new(&mut generator).resume(()) {
+                GeneratorState::Complete(r) => r,
+                GeneratorState::Yielded(_) => unreachable!(),
+            }
+        };
+        stats::end_round(&context, round);
+        context.complete_run();
+        r
+    }
+
+    // Like `run`, takes the outputs nothing took, they are still in the
+    // context after `run` for whoever holds it.
+    pub fn run_drained(self) -> (G::Return, Vec<T>) {
+        let context = self.context.clone();
+        let r = self.run();
+        (r, iter::from_fn(|| context.take()).collect())
+    }
+}
+
+impl<T, G> Block<T, G>
+where
+    G: Unpin + Generator<()>,
+{
+    // every layer wraps the generator of the block below it, a block of its
+    // own comes from `IntoBlock`, so the pair is never exposed
+    pub(crate) fn new(context: Context<T>, generator: G) -> Self {
+        context.stamp_run();
+        Block { context, generator }
     }
 
     pub fn resume(&mut self) -> GeneratorState<G::Yield, G::Return> {
-        Pin::new(&mut self.generator).resume(())
+        self.context.flush_deferred();
+        self.context.begin_resume();
+        let round = stats::begin_round(&self.context);
+        let state = {
+            let _resuming = self.context.resuming();
+            let state = Pin::new(&mut self.generator).resume(());
+            // the scoped outputs put before this resume end with it
+            self.context.end_resume();
+            state
+        };
+        stats::end_round(&self.context, round);
+        if let GeneratorState::Complete(_) = &state {
+            self.context.complete_run();
+        }
+        state
     }
 
     pub fn put(&self, value: T) {
@@ -69,4 +170,9 @@ where
     pub fn context(&self) -> Context<T> {
         self.context.clone()
     }
+
+    // the run `ContextReuse` names, the layers of a block share it
+    pub fn run_id(&self) -> u64 {
+        self.context.run_id().expect("stamped by `Block::new`")
+    }
 }
@@ -0,0 +1,468 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    fmt,
+    error::Error,
+    collections::{BTreeMap, BTreeSet},
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+        mpsc::{self, Receiver, TryRecvError},
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+use super::{
+    computation::{Effect, Handler},
+    meta::{EffectQueue, HasPriority, QueueOrder},
+    two_phase::{OpId, TwoPhase},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorkerFailure<I> {
+    // the wrapped handler does not handle the effect
+    Declined(I),
+    // the worker panicked, on this effect or before it got to it
+    Panicked,
+}
+
+pub trait HasBlockingOutcome
+where
+    Self: Sized + TwoPhase,
+{
+    fn completed(id: OpId, outcome: Result<Self, WorkerFailure<Self::Input>>) -> Self;
+    // the queue of the worker has no room, the effect is given back to be
+    // yielded again later
+    fn queue_full(effect: Self::Input) -> Self;
+}
+
+// the effect a computation yields while it waits for an operation
+pub trait BlockingWait {
+    fn waits_for(&self) -> Option<OpId>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerPanicked(pub String);
+
+impl fmt::Display for WorkerPanicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the blocking handler panicked: {}", self.0)
+    }
+}
+
+impl Error for WorkerPanicked {}
+
+pub struct BlockingWorker(JoinHandle<()>);
+
+impl BlockingWorker {
+    // Waits for the worker, it handles what is left in the queue once the
+    // adapter is dropped, so drop the block first.
+    pub fn join(self) -> Result<(), WorkerPanicked> {
+        self.0.join().map_err(|payload| {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(ToString::to_string)
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            WorkerPanicked(message)
+        })
+    }
+}
+
+type Outcome<E> = Result<E, WorkerFailure<<E as Effect>::Input>>;
+
+struct Job<I>(OpId, I);
+
+impl<I> HasPriority for Job<I>
+where
+    I: HasPriority,
+{
+    fn priority(&self) -> u8 {
+        self.1.priority()
+    }
+
+    fn due(&self) -> Option<Instant> {
+        self.1.due()
+    }
+}
+
+struct Queue<I> {
+    jobs: EffectQueue<Job<I>>,
+    depth: usize,
+    // the worker waits for a job
+    idle: bool,
+    // the adapter is gone, the worker stops once the queue is empty
+    closed: bool,
+    // the worker is gone
+    stopped: bool,
+}
+
+// the worker takes the most urgent job first, `HasPriority` decides
+struct Shared<I> {
+    queue: Mutex<Queue<I>>,
+    changed: Condvar,
+}
+
+impl<I> Shared<I> {
+    // the handler never runs under the lock, a panic does not poison it
+    fn lock(&self) -> MutexGuard<'_, Queue<I>> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// marks the worker stopped, also when the handler panics
+struct Stopped<I>(Arc<Shared<I>>);
+
+impl<I> Drop for Stopped<I> {
+    fn drop(&mut self) {
+        self.0.lock().stopped = true;
+    }
+}
+
+// Never blocks the thread driving the block. An effect goes to the queue
+// and is acknowledged, the wait for it is answered with the completion once
+// the worker is done. Until then the wait is declined, so it reaches the
+// driver like any idle effect. The scheduler moves the answer to the context
+// of the task that waits.
+pub struct BlockingAdapter<E>
+where
+    E: Effect,
+{
+    shared: Arc<Shared<E::Input>>,
+    results: Receiver<(OpId, Outcome<E>)>,
+    next: u64,
+    in_flight: BTreeSet<OpId>,
+    ready: BTreeMap<OpId, Outcome<E>>,
+}
+
+// `handler` runs on a thread of its own, `queue_depth` effects wait for it
+// at most, with zero they are taken only while the worker is idle
+pub fn blocking_adapter<E, H>(
+    handler: H,
+    queue_depth: usize,
+) -> (BlockingAdapter<E>, BlockingWorker)
+where
+    E: HasBlockingOutcome + Send + 'static,
+    E::Input: HasPriority + Send + 'static,
+    H: Handler<E> + Send + 'static,
+{
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            jobs: EffectQueue::new(QueueOrder::Priority),
+            depth: queue_depth,
+            idle: false,
+            closed: false,
+            stopped: false,
+        }),
+        changed: Condvar::new(),
+    });
+    let (done, results) = mpsc::channel();
+    let worker = {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let _stopped = Stopped(shared.clone());
+            let mut handler = handler;
+            loop {
+                let mut queue = shared.lock();
+                let Job(id, effect) = loop {
+                    if let Some(job) = queue.jobs.pop() {
+                        queue.idle = false;
+                        break job;
+                    }
+                    if queue.closed {
+                        return;
+                    }
+                    queue.idle = true;
+                    queue = shared
+                        .changed
+                        .wait(queue)
+                        .unwrap_or_else(PoisonError::into_inner);
+                };
+                drop(queue);
+                let outcome = handler.handle(effect).map_err(WorkerFailure::Declined);
+                // the adapter is gone, the queue is drained all the same
+                let _ = done.send((id, outcome));
+            }
+        })
+    };
+    let adapter = BlockingAdapter {
+        shared,
+        results,
+        next: 0,
+        in_flight: BTreeSet::new(),
+        ready: BTreeMap::new(),
+    };
+    (adapter, BlockingWorker(worker))
+}
+
+impl<E> BlockingAdapter<E>
+where
+    E: Effect,
+{
+    // whether the worker is still there
+    fn collect(&mut self) -> bool {
+        loop {
+            match self.results.try_recv() {
+                Ok((id, outcome)) => {
+                    self.ready.insert(id, outcome);
+                },
+                Err(TryRecvError::Empty) => break true,
+                Err(TryRecvError::Disconnected) => break false,
+            }
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+impl<E> Drop for BlockingAdapter<E>
+where
+    E: Effect,
+{
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+impl<E> Handler<E> for BlockingAdapter<E>
+where
+    E: HasBlockingOutcome,
+    E::Input: BlockingWait + HasPriority,
+{
+    fn handle(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        if let Some(id) = effect.waits_for() {
+            let alive = self.collect();
+            if let Some(outcome) = self.ready.remove(&id) {
+                self.in_flight.remove(&id);
+                return Ok(E::completed(id, outcome));
+            }
+            if !alive && self.in_flight.remove(&id) {
+                return Ok(E::completed(id, Err(WorkerFailure::Panicked)));
+            }
+            return Err(effect);
+        }
+        let mut queue = self.shared.lock();
+        if queue.stopped {
+            // nobody would answer, the effect goes on down the chain
+            return Err(effect);
+        }
+        if queue.jobs.len() >= queue.depth && !(queue.idle && queue.jobs.is_empty()) {
+            return Ok(E::queue_full(effect));
+        }
+        let id = OpId(self.next);
+        self.next += 1;
+        queue.jobs.push(Job(id, effect));
+        drop(queue);
+        self.shared.changed.notify_one();
+        self.in_flight.insert(id);
+        Ok(E::ack(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread};
+    use crate::{Effect, Handler, HasPriority, OpId, TwoPhase};
+    use super::{BlockingWait, HasBlockingOutcome, WorkerFailure, WorkerPanicked, blocking_adapter};
+
+    #[derive(Debug, PartialEq)]
+    enum Effects {
+        Job(u32),
+        Urgent(u32),
+        Wait(OpId),
+    }
+
+    impl HasPriority for Effects {
+        fn priority(&self) -> u8 {
+            match self {
+                Effects::Urgent(_) => 1,
+                _ => 0,
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Accepted(OpId),
+        Full(Effects),
+        Completed(OpId, Result<u32, WorkerFailure<Effects>>),
+        Done(u32),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl TwoPhase for Outputs {
+        fn ack(id: OpId) -> Self {
+            Outputs::Accepted(id)
+        }
+
+        fn is_ack(&self) -> Option<OpId> {
+            match self {
+                Outputs::Accepted(id) => Some(*id),
+                _ => None,
+            }
+        }
+
+        fn is_completion(&self) -> Option<OpId> {
+            match self {
+                Outputs::Completed(id, _) => Some(*id),
+                _ => None,
+            }
+        }
+    }
+
+    impl HasBlockingOutcome for Outputs {
+        fn completed(id: OpId, outcome: Result<Self, WorkerFailure<Effects>>) -> Self {
+            let outcome = outcome.map(|output| match output {
+                Outputs::Done(n) => n,
+                other => panic!("the worker answered {:?}", other),
+            });
+            Outputs::Completed(id, outcome)
+        }
+
+        fn queue_full(effect: Effects) -> Self {
+            Outputs::Full(effect)
+        }
+    }
+
+    impl BlockingWait for Effects {
+        fn waits_for(&self) -> Option<OpId> {
+            match self {
+                Effects::Wait(id) => Some(*id),
+                _ => None,
+            }
+        }
+    }
+
+    fn wait<H>(adapter: &mut H, id: OpId) -> Outputs
+    where
+        H: Handler<Outputs>,
+    {
+        loop {
+            match adapter.handle(Effects::Wait(id)) {
+                Ok(output) => break output,
+                Err(_) => thread::yield_now(),
+            }
+        }
+    }
+
+    #[test]
+    fn backpressure() {
+        let (started, worker_started) = mpsc::channel();
+        let (release, gate) = mpsc::channel::<()>();
+        let handler = move |effect| match effect {
+            Effects::Job(n) => {
+                started.send(()).unwrap();
+                gate.recv().unwrap();
+                Ok(Outputs::Done(n * 2))
+            },
+            other => Err(other),
+        };
+        let (mut adapter, worker) = blocking_adapter(handler, 1);
+        assert_eq!(
+            adapter.handle(Effects::Job(1)),
+            Ok(Outputs::Accepted(OpId(0)))
+        );
+        // the worker holds the first job, the second one waits in the queue
+        worker_started.recv().unwrap();
+        assert_eq!(
+            adapter.handle(Effects::Job(2)),
+            Ok(Outputs::Accepted(OpId(1)))
+        );
+        assert_eq!(
+            adapter.handle(Effects::Job(3)),
+            Ok(Outputs::Full(Effects::Job(3)))
+        );
+        assert_eq!(
+            adapter.handle(Effects::Wait(OpId(0))),
+            Err(Effects::Wait(OpId(0)))
+        );
+
+        release.send(()).unwrap();
+        assert_eq!(
+            wait(&mut adapter, OpId(0)),
+            Outputs::Completed(OpId(0), Ok(2))
+        );
+        worker_started.recv().unwrap();
+        assert_eq!(
+            adapter.handle(Effects::Job(3)),
+            Ok(Outputs::Accepted(OpId(2)))
+        );
+        assert_eq!(adapter.in_flight(), 2);
+
+        // dropped with two jobs left, the worker still handles them
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        drop(adapter);
+        assert_eq!(worker.join(), Ok(()));
+    }
+
+    #[test]
+    fn urgent_first() {
+        let (started, worker_started) = mpsc::channel();
+        let (release, gate) = mpsc::channel::<()>();
+        let handler = move |effect| match effect {
+            Effects::Job(n) | Effects::Urgent(n) => {
+                started.send(n).unwrap();
+                gate.recv().unwrap();
+                Ok(Outputs::Done(n))
+            },
+            other => Err(other),
+        };
+        let (mut adapter, worker) = blocking_adapter(handler, 6);
+        adapter.handle(Effects::Job(0)).unwrap();
+        assert_eq!(worker_started.recv(), Ok(0));
+        // queued last, taken first
+        for n in 1..6 {
+            adapter.handle(Effects::Job(n)).unwrap();
+        }
+        adapter.handle(Effects::Urgent(6)).unwrap();
+        for _ in 0..7 {
+            release.send(()).unwrap();
+        }
+        drop(adapter);
+        assert_eq!(worker.join(), Ok(()));
+        let order = worker_started.try_iter().collect::<Vec<_>>();
+        assert_eq!(order, [6, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn panic_is_typed() {
+        let handler = |effect| match effect {
+            Effects::Job(13) => panic!("unlucky"),
+            Effects::Job(n) => Ok(Outputs::Done(n)),
+            other => Err(other),
+        };
+        let (mut adapter, worker) = blocking_adapter(handler, 4);
+        assert_eq!(
+            adapter.handle(Effects::Job(1)),
+            Ok(Outputs::Accepted(OpId(0)))
+        );
+        assert_eq!(
+            wait(&mut adapter, OpId(0)),
+            Outputs::Completed(OpId(0), Ok(1))
+        );
+        assert_eq!(
+            adapter.handle(Effects::Job(13)),
+            Ok(Outputs::Accepted(OpId(1)))
+        );
+        assert_eq!(
+            wait(&mut adapter, OpId(1)),
+            Outputs::Completed(OpId(1), Err(WorkerFailure::Panicked))
+        );
+        // answered once, the id is not known any more
+        assert_eq!(
+            adapter.handle(Effects::Wait(OpId(1))),
+            Err(Effects::Wait(OpId(1)))
+        );
+        // nobody is left to take it
+        assert_eq!(adapter.handle(Effects::Job(2)), Err(Effects::Job(2)));
+        drop(adapter);
+        assert_eq!(worker.join(), Err(WorkerPanicked("unlucky".to_string())));
+    }
+}
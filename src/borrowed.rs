@@ -0,0 +1,228 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    fmt,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    computation::{self, Effect, Handler},
+    context::Context,
+};
+
+// The handlers of one `Block::run_scoped`, offered every effect in the order
+// they were added. Nothing captures them, the run only borrows them, so they
+// may borrow the locals of the caller in turn.
+pub struct HandlerSetRef<'env, E>
+where
+    E: Effect,
+{
+    handlers: Vec<&'env mut dyn Handler<E>>,
+}
+
+impl<'env, E> Default for HandlerSetRef<'env, E>
+where
+    E: Effect,
+{
+    fn default() -> Self {
+        HandlerSetRef {
+            handlers: Vec::new(),
+        }
+    }
+}
+
+impl<'env, E> HandlerSetRef<'env, E>
+where
+    E: Effect,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<H>(mut self, handler: &'env mut H) -> Self
+    where
+        H: Handler<E> + 'env,
+    {
+        self.handlers.push(handler);
+        self
+    }
+
+    fn offer(&mut self, context: &Context<E>, effect: E::Input) -> Result<E, E::Input> {
+        let mut effect = effect;
+        for handler in &mut self.handlers {
+            effect = match computation::dispatch(&mut **handler, context, effect) {
+                Ok(output) => return Ok(output),
+                Err(declined) => declined,
+            };
+        }
+        Err(effect)
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>,
+    G::Yield: fmt::Debug,
+{
+    // Like `.add_handler(..).assert_handled().run()` with the handlers of the
+    // set, the loop here dispatches rather than a layer. The handlers are
+    // initialized before the first resume and finished, the last added first,
+    // when the block completes.
+    pub fn run_scoped(self, handlers: HandlerSetRef<'_, E>) -> G::Return {
+        let context = self.context();
+        let mut handlers = handlers;
+        let mut s = self;
+        handlers
+            .handlers
+            .iter_mut()
+            .for_each(|handler| handler.init());
+        let r = loop {
+            match s.resume() {
+                GeneratorState::Complete(r) => break r,
+                GeneratorState::Yielded(effect) => match handlers.offer(&context, effect) {
+                    Ok(output) => s.put(output),
+                    Err(effect) => {
+                        let path = context.scope_path();
+                        if path.is_empty() {
+                            panic!("unhandled: {:?}", effect);
+                        }
+                        panic!("unhandled: {:?} in {}", effect, path);
+                    },
+                },
+            }
+        };
+        handlers
+            .handlers
+            .iter_mut()
+            .rev()
+            .for_each(|handler| handler.finish());
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Generator;
+    use crate::{Context, Effect, Handler, IntoBlock, perform};
+    use super::HandlerSetRef;
+
+    #[derive(Debug, PartialEq)]
+    enum Effects {
+        Read(u32),
+        Log(&'static str),
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Value(u32),
+        Logged,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    fn summed(
+        context: Context<Outputs>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = u32> {
+        move || {
+            let mut sum = 0;
+            for key in 1..=3 {
+                perform!(Effects::Log("read"));
+                perform!(Effects::Read(key));
+                while let Some(output) = context.take() {
+                    if let Outputs::Value(value) = output {
+                        sum += value;
+                    }
+                }
+            }
+            sum
+        }
+    }
+
+    #[test]
+    fn borrowed_log() {
+        let mut log = Vec::<String>::new();
+        let mut logging = |effect: Effects| match effect {
+            Effects::Log(line) => {
+                log.push(line.to_string());
+                Ok(Outputs::Logged)
+            },
+            other => Err(other),
+        };
+        let mut reading = |effect: Effects| match effect {
+            Effects::Read(key) => Ok(Outputs::Value(key * 10)),
+            other => Err(other),
+        };
+        let sum = summed
+            .into_block()
+            .run_scoped(HandlerSetRef::new().with(&mut logging).with(&mut reading));
+        assert_eq!(sum, 60);
+        // the run is over, so is the borrow
+        log.push("done".to_string());
+        assert_eq!(log, ["read", "read", "read", "done"]);
+    }
+
+    // records its lifecycle into a log of the test
+    struct Recording<'a> {
+        name: &'static str,
+        log: &'a mut Vec<String>,
+    }
+
+    impl<'a> Handler<Outputs> for Recording<'a> {
+        fn handle(&mut self, effect: Effects) -> Result<Outputs, Effects> {
+            match effect {
+                Effects::Log(line) if self.name == "log" => {
+                    self.log.push(format!("log {}", line));
+                    Ok(Outputs::Logged)
+                },
+                Effects::Read(key) if self.name == "read" => Ok(Outputs::Value(key)),
+                other => Err(other),
+            }
+        }
+
+        fn init(&mut self) {
+            self.log.push(format!("init {}", self.name));
+        }
+
+        fn finish(&mut self) {
+            self.log.push(format!("finish {}", self.name));
+        }
+    }
+
+    #[test]
+    fn lifecycle() {
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+        let mut log = Recording {
+            name: "log",
+            log: &mut first,
+        };
+        let mut read = Recording {
+            name: "read",
+            log: &mut second,
+        };
+        let sum = summed
+            .into_block()
+            .run_scoped(HandlerSetRef::new().with(&mut log).with(&mut read));
+        assert_eq!(sum, 6);
+        assert_eq!(
+            first,
+            ["init log", "log read", "log read", "log read", "finish log"]
+        );
+        assert_eq!(second, ["init read", "finish read"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unhandled: Read(1)")]
+    fn unhandled() {
+        let mut logging = |effect: Effects| match effect {
+            Effects::Log(_) => Ok(Outputs::Logged),
+            other => Err(other),
+        };
+        summed
+            .into_block()
+            .run_scoped(HandlerSetRef::new().with(&mut logging));
+    }
+}
@@ -0,0 +1,492 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// Effects served to another process over a socket. Every frame is a JSON
+// object after its length, four bytes big endian, unless both sides agree on
+// another codec. The side sending effects starts with `{"bridge": 1}` and the
+// other side answers the same version. Then each `{"effect": ..}` gets
+// `{"output": ..}` or `{"unhandled": true}` back, and `{"done": true}` ends a
+// computation.
+
+use std::{
+    fmt,
+    error::Error,
+    io::{self, Read, Write},
+    marker::PhantomData,
+    net::{SocketAddr, TcpListener, TcpStream},
+    ops::{Generator, GeneratorState},
+    thread,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    path::PathBuf,
+    os::unix::net::{UnixListener, UnixStream},
+};
+use serde::{
+    Serialize, Deserialize,
+    de::{DeserializeOwned, IgnoredAny},
+};
+use serde_json::Value;
+use super::{
+    block::Block,
+    codec::{self, Codec, CodecError, JsonCodec},
+    computation::{Effect, Handler},
+};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(50);
+// the backoff doubles at most this many times
+const MAX_DOUBLINGS: u32 = 6;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Frame<T> {
+    Bridge(u32),
+    Effect(T),
+    Output(T),
+    Unhandled(bool),
+    Done(bool),
+}
+
+impl<T> Frame<T> {
+    fn kind(&self) -> &'static str {
+        match self {
+            Frame::Bridge(_) => "bridge",
+            Frame::Effect(_) => "effect",
+            Frame::Output(_) => "output",
+            Frame::Unhandled(_) => "unhandled",
+            Frame::Done(_) => "done",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BridgeError {
+    Io(io::Error),
+    Version { found: u32 },
+    Protocol(String),
+    Codec(CodecError),
+    // the remote declined an effect of a served computation, nobody else can handle it
+    Unhandled(Value),
+}
+
+impl fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BridgeError::Io(error) => write!(f, "bridge io: {}", error),
+            BridgeError::Version { found } => write!(
+                f,
+                "the remote speaks bridge version {}, expected {}",
+                found, PROTOCOL_VERSION
+            ),
+            BridgeError::Protocol(message) => write!(f, "bridge protocol: {}", message),
+            BridgeError::Codec(error) => write!(f, "bridge frame: {}", error),
+            BridgeError::Unhandled(effect) => write!(f, "the remote declined {}", effect),
+        }
+    }
+}
+
+impl Error for BridgeError {}
+
+impl From<io::Error> for BridgeError {
+    fn from(error: io::Error) -> Self {
+        BridgeError::Io(error)
+    }
+}
+
+impl From<CodecError> for BridgeError {
+    fn from(error: CodecError) -> Self {
+        BridgeError::Codec(error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BridgeTimeout {
+    // the effect as it was sent
+    pub effect: String,
+    pub after: Duration,
+}
+
+// Given to the computation instead of the response. The connection is
+// dropped either way, the next effect reconnects after the backoff, so
+// `retry` on `Disconnected` sends the effect again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BridgeFailure {
+    Timeout(BridgeTimeout),
+    Disconnected,
+}
+
+pub trait HasBridgeFailure
+where
+    Self: Effect,
+{
+    fn bridge_failure(failure: BridgeFailure) -> Self;
+}
+
+// a socket address is TCP, anything else the path of a unix socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BridgeAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl BridgeAddr {
+    pub fn parse(path_or_addr: &str) -> Result<Self, BridgeError> {
+        if let Ok(addr) = path_or_addr.parse() {
+            return Ok(BridgeAddr::Tcp(addr));
+        }
+        #[cfg(unix)]
+        {
+            Ok(BridgeAddr::Unix(path_or_addr.into()))
+        }
+        #[cfg(not(unix))]
+        {
+            let message = format!("{} is not a socket address", path_or_addr);
+            Err(io::Error::new(io::ErrorKind::InvalidInput, message).into())
+        }
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn connect(addr: &BridgeAddr) -> io::Result<Self> {
+        match addr {
+            BridgeAddr::Tcp(addr) => TcpStream::connect(addr).map(Stream::Tcp),
+            #[cfg(unix)]
+            BridgeAddr::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn write_frame<C, T>(&mut self, codec: &C, frame: &Frame<T>) -> Result<(), BridgeError>
+    where
+        C: Codec,
+        T: Serialize,
+    {
+        let mut bytes = Vec::new();
+        codec.encode(frame, &mut bytes)?;
+        codec::write_frame(codec, self, &bytes)?;
+        Ok(())
+    }
+
+    fn read_frame<C, T>(&mut self, codec: &C) -> Result<Frame<T>, BridgeError>
+    where
+        C: Codec,
+        T: DeserializeOwned,
+    {
+        let bytes = codec::read_frame(codec, self)?;
+        Ok(codec.decode(&bytes)?)
+    }
+
+    // the side sending effects opens the conversation
+    fn handshake<C>(&mut self, codec: &C) -> Result<(), BridgeError>
+    where
+        C: Codec,
+    {
+        self.write_frame(codec, &Frame::<()>::Bridge(PROTOCOL_VERSION))?;
+        match self.read_frame::<_, IgnoredAny>(codec)? {
+            Frame::Bridge(PROTOCOL_VERSION) => Ok(()),
+            Frame::Bridge(found) => Err(BridgeError::Version { found }),
+            other => Err(unexpected(&other)),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+fn unexpected<T>(frame: &Frame<T>) -> BridgeError {
+    BridgeError::Protocol(format!("unexpected {} frame", frame.kind()))
+}
+
+// the effect in error messages, whatever the codec
+fn render<T>(effect: &T) -> Value
+where
+    T: Serialize,
+{
+    serde_json::to_value(effect).expect("bridged effects must serialize")
+}
+
+fn timed_out(error: &BridgeError) -> bool {
+    match error {
+        BridgeError::Io(error) => matches!(
+            error.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+        _ => false,
+    }
+}
+
+// A handler in another process. Each effect waits for its response at most
+// the timeout, a broken connection is opened again on the next effect.
+pub struct SocketBridgeHandler<E, C = JsonCodec> {
+    addr: BridgeAddr,
+    codec: C,
+    stream: Option<Stream>,
+    timeout: Duration,
+    backoff: Duration,
+    // connections lost in a row, each doubles the wait before the next attempt
+    failures: u32,
+    phantom: PhantomData<E>,
+}
+
+impl<E> SocketBridgeHandler<E> {
+    // connects right away, so a wrong address or version is found here
+    pub fn connect(path_or_addr: &str) -> Result<Self, BridgeError> {
+        Self::connect_with(path_or_addr, JsonCodec)
+    }
+}
+
+impl<E, C> SocketBridgeHandler<E, C>
+where
+    C: Codec,
+{
+    // the remote must speak the same codec, the handshake is in it as well
+    pub fn connect_with(path_or_addr: &str, codec: C) -> Result<Self, BridgeError> {
+        let mut handler = SocketBridgeHandler {
+            addr: BridgeAddr::parse(path_or_addr)?,
+            codec,
+            stream: None,
+            timeout: DEFAULT_TIMEOUT,
+            backoff: DEFAULT_BACKOFF,
+            failures: 0,
+            phantom: PhantomData,
+        };
+        handler.stream = Some(handler.open()?);
+        Ok(handler)
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Self {
+        let mut s = SocketBridgeHandler { timeout, ..self };
+        // the stream opened by `connect`, dropped if it refuses, the next
+        // effect connects again
+        if let Some(stream) = &s.stream {
+            if stream.set_read_timeout(Some(timeout)).is_err() {
+                s.stream = None;
+            }
+        }
+        s
+    }
+
+    // the first wait before reconnecting
+    pub fn backoff(self, backoff: Duration) -> Self {
+        SocketBridgeHandler { backoff, ..self }
+    }
+
+    fn open(&self) -> Result<Stream, BridgeError> {
+        let mut stream = Stream::connect(&self.addr)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.handshake(&self.codec)?;
+        Ok(stream)
+    }
+
+    fn reconnect(&mut self) -> Result<&mut Stream, BridgeError> {
+        if self.stream.is_none() {
+            if self.failures > 0 {
+                let doublings = (self.failures - 1).min(MAX_DOUBLINGS);
+                thread::sleep(self.backoff * 2u32.pow(doublings));
+            }
+            self.stream = Some(self.open()?);
+        }
+        Ok(self.stream.as_mut().expect("connected just now"))
+    }
+
+    fn exchange<T, O>(&mut self, effect: &T) -> Result<Frame<O>, BridgeError>
+    where
+        T: Serialize,
+        O: DeserializeOwned,
+    {
+        self.reconnect()?;
+        let SocketBridgeHandler { codec, stream, .. } = self;
+        let stream = stream.as_mut().expect("connected just now");
+        stream.write_frame(codec, &Frame::Effect(effect))?;
+        stream.read_frame(codec)
+    }
+}
+
+impl<E, C> Handler<E> for SocketBridgeHandler<E, C>
+where
+    E: HasBridgeFailure + DeserializeOwned,
+    E::Input: Serialize,
+    C: Codec,
+{
+    fn handle(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        let frame = self.exchange::<_, E>(&effect);
+        if matches!(frame, Ok(Frame::Output(_)) | Ok(Frame::Unhandled(_))) {
+            self.failures = 0;
+        } else {
+            // a late response must not be taken for the next one
+            self.stream = None;
+        }
+        match frame {
+            Ok(Frame::Output(output)) => Ok(output),
+            Ok(Frame::Unhandled(_)) => Err(effect),
+            Err(BridgeError::Codec(error)) => {
+                panic!(
+                    "the remote answered {} with a bad output: {}",
+                    render(&effect),
+                    error
+                )
+            },
+            Err(error) if timed_out(&error) => {
+                let timeout = BridgeTimeout {
+                    effect: render(&effect).to_string(),
+                    after: self.timeout,
+                };
+                Ok(E::bridge_failure(BridgeFailure::Timeout(timeout)))
+            },
+            Ok(_) | Err(_) => {
+                self.failures += 1;
+                Ok(E::bridge_failure(BridgeFailure::Disconnected))
+            },
+        }
+    }
+}
+
+pub enum BridgeListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl BridgeListener {
+    pub fn bind(path_or_addr: &str) -> Result<Self, BridgeError> {
+        match BridgeAddr::parse(path_or_addr)? {
+            BridgeAddr::Tcp(addr) => Ok(BridgeListener::Tcp(TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            BridgeAddr::Unix(path) => Ok(BridgeListener::Unix(UnixListener::bind(path)?)),
+        }
+    }
+
+    fn accept(&self) -> io::Result<Stream> {
+        match self {
+            BridgeListener::Tcp(listener) => listener.accept().map(|(s, _)| Stream::Tcp(s)),
+            #[cfg(unix)]
+            BridgeListener::Unix(listener) => listener.accept().map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+}
+
+// Runs computations for a handler in another process, the remote connects
+// and answers the effects.
+pub struct BridgeServer;
+
+impl BridgeServer {
+    // every connection gets a block of its own, until one fails
+    pub fn serve<E, G, F>(block_constructor: F, listener: BridgeListener) -> BridgeError
+    where
+        E: Effect + DeserializeOwned,
+        E::Input: Serialize,
+        G: Unpin + Generator<(), Yield = E::Input>,
+        F: FnMut() -> Block<E, G>,
+    {
+        Self::serve_with(block_constructor, listener, JsonCodec)
+    }
+
+    pub fn serve_with<E, G, F, C>(
+        block_constructor: F,
+        listener: BridgeListener,
+        codec: C,
+    ) -> BridgeError
+    where
+        E: Effect + DeserializeOwned,
+        E::Input: Serialize,
+        G: Unpin + Generator<(), Yield = E::Input>,
+        F: FnMut() -> Block<E, G>,
+        C: Codec,
+    {
+        let mut block_constructor = block_constructor;
+        loop {
+            if let Err(error) = Self::serve_one_with(&mut block_constructor, &listener, &codec) {
+                return error;
+            }
+        }
+    }
+
+    // one connection, the computation runs until it is done
+    pub fn serve_one<E, G, F>(
+        block_constructor: &mut F,
+        listener: &BridgeListener,
+    ) -> Result<G::Return, BridgeError>
+    where
+        E: Effect + DeserializeOwned,
+        E::Input: Serialize,
+        G: Unpin + Generator<(), Yield = E::Input>,
+        F: FnMut() -> Block<E, G>,
+    {
+        Self::serve_one_with(block_constructor, listener, JsonCodec)
+    }
+
+    pub fn serve_one_with<E, G, F, C>(
+        block_constructor: &mut F,
+        listener: &BridgeListener,
+        codec: C,
+    ) -> Result<G::Return, BridgeError>
+    where
+        E: Effect + DeserializeOwned,
+        E::Input: Serialize,
+        G: Unpin + Generator<(), Yield = E::Input>,
+        F: FnMut() -> Block<E, G>,
+        C: Codec,
+    {
+        let mut stream = listener.accept()?;
+        stream.handshake(&codec)?;
+        let mut block = block_constructor();
+        loop {
+            let effect = match block.resume() {
+                GeneratorState::Complete(r) => {
+                    stream.write_frame(&codec, &Frame::<()>::Done(true))?;
+                    break Ok(r);
+                },
+                GeneratorState::Yielded(effect) => effect,
+            };
+            stream.write_frame(&codec, &Frame::Effect(&effect))?;
+            match stream.read_frame::<_, E>(&codec)? {
+                Frame::Output(output) => block.put(output),
+                Frame::Unhandled(_) => break Err(BridgeError::Unhandled(render(&effect))),
+                other => break Err(unexpected(&other)),
+            }
+        }
+    }
+}
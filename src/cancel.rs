@@ -0,0 +1,216 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{fmt, rc::Rc, cell::Cell};
+use super::{accounting::Counter, context::Context};
+
+struct Node {
+    cancelled: Cell<bool>,
+    parent: Option<CancelToken>,
+}
+
+// Cancelled once and for good, a child is cancelled with its parent. The
+// clones are the same token.
+#[derive(Clone)]
+pub struct CancelToken(Rc<Node>);
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        CancelToken(Rc::new(Node {
+            cancelled: Cell::new(false),
+            parent: None,
+        }))
+    }
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn child(&self) -> Self {
+        CancelToken(Rc::new(Node {
+            cancelled: Cell::new(false),
+            parent: Some(self.clone()),
+        }))
+    }
+
+    /// Whether this call cancelled the token, cancelling it again does nothing.
+    ///
+    /// ```
+    /// use aeiou::CancelToken;
+    ///
+    /// let parent = CancelToken::new();
+    /// let child = parent.child();
+    /// assert!(parent.cancel());
+    /// assert!(child.is_cancelled());
+    /// assert!(!child.cancel());
+    /// assert!(!parent.cancel());
+    /// ```
+    pub fn cancel(&self) -> bool {
+        if self.is_cancelled() {
+            return false;
+        }
+        self.0.cancelled.set(true);
+        true
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        let mut node = &self.0;
+        loop {
+            if node.cancelled.get() {
+                break true;
+            }
+            match &node.parent {
+                Some(parent) => node = &parent.0,
+                None => break false,
+            }
+        }
+    }
+}
+
+impl PartialEq for CancelToken {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CancelToken {}
+
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+// The start of `perform_cancellable!`, the handlers see the token while the
+// effect is delivered. `None` if it is cancelled already, the effect is not
+// yielded then.
+#[doc(hidden)]
+pub fn attach<T>(context: &Context<T>, token: &CancelToken) -> Option<u64> {
+    if token.is_cancelled() {
+        context.account(|a| a.bump(Counter::CancelledEffects));
+        return None;
+    }
+    context.set_cancel_token(Some(token.clone()));
+    Some(context.produced())
+}
+
+// the responses to a cancelled effect are withdrawn, they go to the orphan callback
+#[doc(hidden)]
+pub fn detach<T>(context: &Context<T>, token: &CancelToken, before: u64) -> Option<T> {
+    context.set_cancel_token(None);
+    if !token.is_cancelled() {
+        return context.take();
+    }
+    for _ in before..context.produced() {
+        if let Some(response) = context.take_last() {
+            context.orphan(response);
+        }
+    }
+    context.account(|a| a.bump(Counter::CancelledEffects));
+    None
+}
+
+#[macro_export]
+macro_rules! perform_cancellable {
+    ($e:expr, $ctx:expr, $token:expr) => {{
+        let token = $crate::CancelToken::clone(&$token);
+        match $crate::cancel_attach($ctx, &token) {
+            Some(before) => {
+                yield $e;
+                $crate::cancel_detach($ctx, &token, before)
+            },
+            None => None,
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell};
+    use crate::{Context, Effect, CancelToken, Counter, Handler, IntoBlock};
+
+    #[derive(Debug)]
+    enum Effects {
+        // copies the chunks one by one, checking the token between them
+        Copy(u32),
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Copied(u32),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    struct Copier {
+        // the token cancelled after the chunk with this number
+        cancel_after: u32,
+        chunks: Rc<RefCell<Vec<u32>>>,
+    }
+
+    impl Handler<Outputs> for Copier {
+        fn handle(&mut self, effect: Effects) -> Result<Outputs, Effects> {
+            let Effects::Copy(n) = effect;
+            self.chunks.borrow_mut().extend(0..n);
+            Ok(Outputs::Copied(n))
+        }
+
+        fn handle_cancellable(
+            &mut self,
+            token: &CancelToken,
+            effect: Effects,
+        ) -> Result<Outputs, Effects> {
+            let Effects::Copy(n) = effect;
+            let mut copied = 0;
+            while copied < n && !token.is_cancelled() {
+                self.chunks.borrow_mut().push(copied);
+                copied += 1;
+                if copied == self.cancel_after {
+                    token.cancel();
+                }
+            }
+            Ok(Outputs::Copied(copied))
+        }
+    }
+
+    #[test]
+    fn handler_stops_early() {
+        let chunks = Rc::new(RefCell::new(Vec::new()));
+        let orphans = Rc::new(RefCell::new(Vec::new()));
+        let token = CancelToken::new();
+        let (block, accounting) = (|context: Context<Outputs>| {
+            let token = token.clone();
+            let orphans = orphans.clone();
+            move || {
+                context.on_orphan(move |o| orphans.borrow_mut().push(o));
+                let first = perform_cancellable!(Effects::Copy(2), &context, token.child());
+                let second = perform_cancellable!(Effects::Copy(10), &context, token);
+                let third = perform_cancellable!(Effects::Copy(10), &context, token.child());
+                (first, second, third)
+            }
+        })
+        .into_block()
+        .add_handler(Copier {
+            cancel_after: 5,
+            chunks: chunks.clone(),
+        })
+        .assert_handled()
+        .with_accounting();
+        let (first, second, third) = block.run();
+        assert_eq!(first, Some(Outputs::Copied(2)));
+        // the partial response is withdrawn, the third effect is never performed
+        assert_eq!(second, None);
+        assert_eq!(third, None);
+        assert_eq!(*orphans.borrow(), [Outputs::Copied(5)]);
+        assert_eq!(*chunks.borrow(), [0, 1, 0, 1, 2, 3, 4]);
+        if cfg!(feature = "diagnostics") {
+            assert_eq!(accounting.report().count(Counter::CancelledEffects), 2);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+//! Which output a computation was reacting to when it performed an effect.
+//!
+//! Every output put into the context of a block gets a sequence number, the
+//! tasks of the block share the numbering. An effect is caused by the output
+//! its performer took last, the root and every task separately, a task starts
+//! from the output its spawner took last. The history records the cause of
+//! each effect and the outputs put while the effect was handled, so the chain
+//! goes on from the effect to the outputs answering it.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+};
+use super::history::HistoryEntry;
+
+/// The causality edges of a trace, the effects are named by their index in
+/// it. With a compacting history the index is the one in `History::raw`.
+///
+/// ```
+/// use aeiou::{HistoryEntry, causality};
+///
+/// let trace: Vec<HistoryEntry> = Vec::new();
+/// let graph = causality::graph(&trace);
+/// assert!(graph.roots().is_empty());
+/// assert!(graph.descendants(1).is_empty());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CausalGraph {
+    causes: Vec<Option<u64>>,
+    outputs: Vec<Range<u64>>,
+    // the effects each output caused
+    caused: BTreeMap<u64, Vec<usize>>,
+}
+
+pub fn graph(trace: &[HistoryEntry]) -> CausalGraph {
+    let mut graph = CausalGraph::default();
+    for (index, entry) in trace.iter().enumerate() {
+        graph.causes.push(entry.cause);
+        graph.outputs.push(entry.outputs.clone());
+        if let Some(cause) = entry.cause {
+            graph.caused.entry(cause).or_default().push(index);
+        }
+    }
+    graph
+}
+
+impl CausalGraph {
+    // the effects performed before their performer took any output
+    pub fn roots(&self) -> Vec<usize> {
+        (0..self.causes.len())
+            .filter(|index| self.causes[*index].is_none())
+            .collect()
+    }
+
+    pub fn cause(&self, effect: usize) -> Option<u64> {
+        self.causes.get(effect).cloned().flatten()
+    }
+
+    // the outputs put while the effect was handled
+    pub fn outputs(&self, effect: usize) -> Range<u64> {
+        self.outputs.get(effect).cloned().unwrap_or(0..0)
+    }
+
+    pub fn caused_by(&self, output: u64) -> &[usize] {
+        self.caused.get(&output).map_or(&[], Vec::as_slice)
+    }
+
+    // everything the output caused, directly or through the outputs of the
+    // effects it caused, in the order of the trace
+    pub fn descendants(&self, output: u64) -> Vec<usize> {
+        let mut found = BTreeSet::new();
+        let mut pending = vec![output];
+        while let Some(output) = pending.pop() {
+            for &effect in self.caused_by(output) {
+                if found.insert(effect) {
+                    pending.extend(self.outputs(effect));
+                }
+            }
+        }
+        found.into_iter().collect()
+    }
+}
+
+#[cfg(all(test, feature = "diagnostics"))]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{
+        Context, EffectMeta, HistoryConfig, IntoBlock, WithMeta,
+        tasks::{Request, TaskAction, TaskId},
+    };
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Op {
+        Accept,
+        Ping,
+        Write,
+    }
+
+    #[derive(Debug)]
+    enum Req {
+        Perform(WithMeta<Op>),
+        Spawn(Job),
+    }
+
+    #[derive(Debug)]
+    struct Job;
+
+    impl TaskId for Job {
+        type Id = ();
+
+        fn task_id(&self) -> Self::Id {}
+    }
+
+    impl Request for Req {
+        type Task = Job;
+        type Effect = WithMeta<Op>;
+
+        fn is_task(self) -> Result<Self::Task, Self> {
+            match self {
+                Req::Spawn(job) => Ok(job),
+                other => Err(other),
+            }
+        }
+
+        fn is_effect(self) -> Result<Self::Effect, Self> {
+            match self {
+                Req::Perform(effect) => Ok(effect),
+                other => Err(other),
+            }
+        }
+
+        fn meta_mut(&mut self) -> Option<&mut EffectMeta> {
+            match self {
+                Req::Perform(effect) => Some(&mut effect.meta),
+                Req::Spawn(_) => None,
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Response {
+        Accepted,
+        Pong,
+        Written,
+    }
+
+    fn perform(op: Op) -> Req {
+        Req::Perform(WithMeta::new(op))
+    }
+
+    // Two accepts, the first one spawns a task pinging, the second one is
+    // answered with a write, and so is the pong of the task.
+    fn root(context: Context<Response>) -> impl Unpin + Generator<(), Yield = Req, Return = ()> {
+        move || {
+            yield perform(Op::Accept);
+            yield perform(Op::Accept);
+            assert_eq!(context.take(), Some(Response::Accepted));
+            yield Req::Spawn(Job);
+            assert_eq!(context.take(), Some(Response::Accepted));
+            yield perform(Op::Write);
+            assert_eq!(context.take(), Some(Response::Pong));
+            yield perform(Op::Write);
+        }
+    }
+
+    #[test]
+    fn fan_out_across_spawn() {
+        let stamped = Rc::new(RefCell::new(Vec::new()));
+        let (block, history) = root
+            .into_block()
+            .spawn(|Job| move || yield TaskAction::<_, Response, ()>::Perform(perform(Op::Ping)))
+            .with_history(HistoryConfig::new(16));
+        block
+            .add_handler_({
+                let stamped = stamped.clone();
+                move |effect: WithMeta<Op>| {
+                    stamped
+                        .borrow_mut()
+                        .push((effect.effect, effect.meta.cause()));
+                    Ok::<_, !>(match effect.effect {
+                        Op::Accept => Response::Accepted,
+                        Op::Ping => Response::Pong,
+                        Op::Write => Response::Written,
+                    })
+                }
+            })
+            .run();
+
+        // the outputs are numbered from 1 as they are put
+        let expected = [
+            (Op::Accept, None),
+            (Op::Accept, None),
+            (Op::Ping, Some(1)),
+            (Op::Write, Some(2)),
+            (Op::Write, Some(3)),
+        ];
+        assert_eq!(*stamped.borrow(), expected);
+        let trace = history.raw();
+        let edges = trace
+            .iter()
+            .map(|e| (e.cause, e.outputs.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            edges,
+            [
+                (None, 1..2),
+                (None, 2..3),
+                (Some(1), 3..4),
+                (Some(2), 4..5),
+                (Some(3), 5..6),
+            ],
+        );
+
+        let graph = super::graph(&trace);
+        assert_eq!(graph.roots(), [0, 1]);
+        assert_eq!(graph.caused_by(1), [2]);
+        // the first accept caused the ping of the task and, through the pong,
+        // the second write
+        assert_eq!(graph.descendants(1), [2, 4]);
+        assert_eq!(graph.descendants(2), [3]);
+        assert!(graph.descendants(5).is_empty());
+    }
+
+    #[test]
+    fn put_back_is_not_a_cause() {
+        let context = Context::empty();
+        context.put(1);
+        context.put(2);
+        assert_eq!(context.take(), Some(1));
+        assert_eq!(context.cause(), Some(1));
+        assert_eq!(context.take(), Some(2));
+        context.put_front(2);
+        assert_eq!(context.cause(), Some(1));
+        // it keeps its number
+        assert_eq!(context.take(), Some(2));
+        assert_eq!(context.cause(), Some(2));
+    }
+}
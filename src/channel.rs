@@ -0,0 +1,266 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{rc::Rc, cell::RefCell, collections::VecDeque, marker::PhantomData};
+use super::computation::{Effect, Handler};
+
+pub trait SendEffect<T>
+where
+    Self: Sized,
+{
+    fn into_send(self) -> Result<T, Self>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendOutcome<T> {
+    Sent,
+    Full(T),
+    Disconnected(T),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Receive<T> {
+    Received(T),
+    Closed,
+}
+
+struct Shared<T> {
+    queue: VecDeque<T>,
+    bound: Option<usize>,
+    sender: bool,
+    receiver: bool,
+}
+
+pub struct SenderHandler<T, E> {
+    shared: Rc<RefCell<Shared<T>>>,
+    phantom_data: PhantomData<E>,
+}
+
+pub struct ReceiverSource<T> {
+    shared: Rc<RefCell<Shared<T>>>,
+    closed: bool,
+}
+
+pub fn effect_channel<T, E>(bound: Option<usize>) -> (SenderHandler<T, E>, ReceiverSource<T>) {
+    let shared = Rc::new(RefCell::new(Shared {
+        queue: VecDeque::new(),
+        bound,
+        sender: true,
+        receiver: true,
+    }));
+    let sender = SenderHandler {
+        shared: shared.clone(),
+        phantom_data: PhantomData,
+    };
+    let receiver = ReceiverSource {
+        shared,
+        closed: false,
+    };
+    (sender, receiver)
+}
+
+impl<T, E> Handler<E> for SenderHandler<T, E>
+where
+    E: Effect + From<SendOutcome<T>>,
+    E::Input: SendEffect<T>,
+{
+    fn handle(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        let value = effect.into_send()?;
+        let mut shared = self.shared.borrow_mut();
+        let outcome = if !shared.receiver {
+            SendOutcome::Disconnected(value)
+        } else if shared.bound.map_or(false, |b| shared.queue.len() >= b) {
+            SendOutcome::Full(value)
+        } else {
+            shared.queue.push_back(value);
+            SendOutcome::Sent
+        };
+        Ok(outcome.into())
+    }
+}
+
+impl<T, E> Drop for SenderHandler<T, E> {
+    fn drop(&mut self) {
+        self.shared.borrow_mut().sender = false;
+    }
+}
+
+impl<T> ReceiverSource<T> {
+    pub fn poll(&mut self) -> Option<Receive<T>> {
+        if self.closed {
+            return None;
+        }
+        let mut shared = self.shared.borrow_mut();
+        match shared.queue.pop_front() {
+            Some(value) => Some(Receive::Received(value)),
+            None if !shared.sender => {
+                self.closed = true;
+                Some(Receive::Closed)
+            },
+            None => None,
+        }
+    }
+
+    pub fn into_source<E>(self) -> impl FnMut() -> Option<E>
+    where
+        E: From<Receive<T>>,
+    {
+        let mut s = self;
+        move || s.poll().map(E::from)
+    }
+}
+
+impl<T> Drop for ReceiverSource<T> {
+    fn drop(&mut self) {
+        self.shared.borrow_mut().receiver = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        cell::RefCell,
+        ops::{Generator, GeneratorState},
+    };
+    use crate::{Context, Effect, IntoBlock, wait_any};
+    use super::{SendEffect, SendOutcome, Receive, effect_channel};
+
+    #[derive(Debug)]
+    enum ProducerEffect {
+        Send(u32),
+        Idle,
+    }
+
+    impl SendEffect<u32> for ProducerEffect {
+        fn into_send(self) -> Result<u32, Self> {
+            match self {
+                ProducerEffect::Send(value) => Ok(value),
+                s => Err(s),
+            }
+        }
+    }
+
+    struct ProducerOutput(SendOutcome<u32>);
+
+    impl Effect for ProducerOutput {
+        type Input = ProducerEffect;
+    }
+
+    impl From<SendOutcome<u32>> for ProducerOutput {
+        fn from(v: SendOutcome<u32>) -> Self {
+            ProducerOutput(v)
+        }
+    }
+
+    #[derive(Debug)]
+    enum ConsumerEffect {
+        Idle,
+    }
+
+    struct ConsumerOutput(Receive<u32>);
+
+    impl Effect for ConsumerOutput {
+        type Input = ConsumerEffect;
+    }
+
+    impl From<Receive<u32>> for ConsumerOutput {
+        fn from(v: Receive<u32>) -> Self {
+            ConsumerOutput(v)
+        }
+    }
+
+    #[derive(Default)]
+    struct Log {
+        full: usize,
+        disconnected: Vec<u32>,
+        received: Vec<u32>,
+        closed: bool,
+    }
+
+    fn producer(
+        context: Context<ProducerOutput>,
+        log: Rc<RefCell<Log>>,
+        count: u32,
+    ) -> impl Unpin + Generator<(), Yield = ProducerEffect, Return = ()> {
+        move || {
+            for i in 0..count {
+                loop {
+                    yield ProducerEffect::Send(i);
+                    match context.take() {
+                        Some(ProducerOutput(SendOutcome::Sent)) => break,
+                        Some(ProducerOutput(SendOutcome::Full(_))) => {
+                            log.borrow_mut().full += 1;
+                            yield ProducerEffect::Idle;
+                        },
+                        Some(ProducerOutput(SendOutcome::Disconnected(v))) => {
+                            log.borrow_mut().disconnected.push(v);
+                            break;
+                        },
+                        None => unreachable!(),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_robin() {
+        let log = Rc::new(RefCell::new(Log::default()));
+        let (sender, receiver) = effect_channel(Some(2));
+        let consumer = {
+            let log = log.clone();
+            move |context: Context<ConsumerOutput>| {
+                move || loop {
+                    wait_any!(&context, ConsumerEffect::Idle);
+                    match context.take() {
+                        Some(ConsumerOutput(Receive::Received(v))) => {
+                            log.borrow_mut().received.push(v)
+                        },
+                        Some(ConsumerOutput(Receive::Closed)) => {
+                            log.borrow_mut().closed = true;
+                            break;
+                        },
+                        None => unreachable!(),
+                    }
+                }
+            }
+        };
+        let producer_log = log.clone();
+        let mut producer = Some(
+            (move |context| producer(context, producer_log, 5))
+                .into_block()
+                .add_handler(sender),
+        );
+        let mut consumer = Some(consumer.into_block().add_source(receiver.into_source()));
+        while producer.is_some() || consumer.is_some() {
+            if let Some(block) = producer.as_mut() {
+                if let GeneratorState::Complete(()) = block.resume() {
+                    producer = None;
+                }
+            }
+            if let Some(block) = consumer.as_mut() {
+                if let GeneratorState::Complete(()) = block.resume() {
+                    consumer = None;
+                }
+            }
+        }
+        let log = log.borrow();
+        assert_eq!(log.received, vec![0, 1, 2, 3, 4]);
+        assert!(log.full > 0);
+        assert!(log.closed);
+    }
+
+    #[test]
+    fn disconnected() {
+        let log = Rc::new(RefCell::new(Log::default()));
+        let (sender, receiver) = effect_channel(None);
+        drop(receiver);
+        let producer_log = log.clone();
+        let mut producer = (move |context| producer(context, producer_log, 3))
+            .into_block()
+            .add_handler(sender);
+        while let GeneratorState::Yielded(_) = producer.resume() {}
+        assert_eq!(log.borrow().disconnected, vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    computation::{self, Effect, EffectName, Handler},
+};
+
+pub enum Fault<E> {
+    // swallow the response, the computation is resumed with nothing
+    Drop,
+    // hold the response for the given number of rounds
+    Delay(u64),
+    Duplicate,
+    Corrupt(Rc<dyn Fn(E) -> E>),
+}
+
+impl<E> Fault<E> {
+    pub fn corrupt<F>(f: F) -> Self
+    where
+        F: Fn(E) -> E + 'static,
+    {
+        Fault::Corrupt(Rc::new(f))
+    }
+
+    pub fn kind(&self) -> FaultKind {
+        match self {
+            Fault::Drop => FaultKind::Drop,
+            Fault::Delay(rounds) => FaultKind::Delay(*rounds),
+            Fault::Duplicate => FaultKind::Duplicate,
+            Fault::Corrupt(_) => FaultKind::Corrupt,
+        }
+    }
+}
+
+impl<E> Clone for Fault<E> {
+    fn clone(&self) -> Self {
+        match self {
+            Fault::Drop => Fault::Drop,
+            Fault::Delay(rounds) => Fault::Delay(*rounds),
+            Fault::Duplicate => Fault::Duplicate,
+            Fault::Corrupt(f) => Fault::Corrupt(f.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    Drop,
+    Delay(u64),
+    Duplicate,
+    Corrupt,
+}
+
+pub struct ChaosPlan<E> {
+    faults: BTreeMap<&'static str, Vec<(f64, Fault<E>)>>,
+}
+
+impl<E> Default for ChaosPlan<E> {
+    fn default() -> Self {
+        ChaosPlan {
+            faults: BTreeMap::new(),
+        }
+    }
+}
+
+impl<E> ChaosPlan<E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // probabilities of one effect class add up, at most one fault is injected
+    pub fn inject(mut self, effect: &'static str, fault: Fault<E>, probability: f64) -> Self {
+        self.faults
+            .entry(effect)
+            .or_default()
+            .push((probability, fault));
+        self
+    }
+
+    fn pick(&self, effect: &str, roll: f64) -> Option<&Fault<E>> {
+        let mut acc = 0.0;
+        for (probability, fault) in self.faults.get(effect)? {
+            acc += probability;
+            if roll < acc {
+                return Some(fault);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InjectedFault {
+    pub round: u64,
+    pub effect: &'static str,
+    pub fault: FaultKind,
+}
+
+impl fmt::Display for InjectedFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "round {}: {:?} on {}",
+            self.round, self.fault, self.effect
+        )
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct FaultLog(Rc<RefCell<Vec<InjectedFault>>>);
+
+impl FaultLog {
+    pub fn entries(&self) -> Vec<InjectedFault> {
+        self.0.borrow().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+}
+
+impl fmt::Display for FaultLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in self.0.borrow().iter() {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+// splitmix64, small and fixed so a seed reproduces the same faults
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+pub struct ChaosHandler<E, H> {
+    inner: H,
+    plan: ChaosPlan<E>,
+    rng: Rng,
+    log: FaultLog,
+}
+
+impl<E, H> ChaosHandler<E, H>
+where
+    E: Effect,
+    H: Handler<E>,
+{
+    pub fn wrap(inner: H, plan: ChaosPlan<E>, rng_seed: u64) -> Self {
+        ChaosHandler {
+            inner,
+            plan,
+            rng: Rng(rng_seed),
+            log: FaultLog::default(),
+        }
+    }
+
+    pub fn log(&self) -> FaultLog {
+        self.log.clone()
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + Clone,
+    E::Input: EffectName,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // like `add_handler`, but the responses of the wrapped handler go
+    // through the plan, the log stays readable after the run
+    pub fn add_chaos_handler<H>(
+        self,
+        chaos: ChaosHandler<E, H>,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        H: Handler<E>,
+    {
+        let context = self.context();
+        let mut chaos = chaos;
+        let mut held = VecDeque::<(u64, E)>::new();
+        let mut round = 0;
+        let mut s = self;
+        let generator = move || loop {
+            round += 1;
+            // delayed responses are released in order, like a source
+            while held.front().map_or(false, |(due, _)| *due <= round) {
+                let (_, output) = held.pop_front().expect("checked above");
+                s.put(output);
+            }
+            let effect = match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => effect,
+            };
+            let name = effect.effect_name();
+            let output = match computation::dispatch(&mut chaos.inner, &s.context(), effect) {
+                Ok(output) => output,
+                Err(unhandled) => {
+                    yield unhandled;
+                    continue;
+                },
+            };
+            let fault = if chaos.plan.faults.contains_key(name) {
+                let roll = chaos.rng.next_f64();
+                chaos.plan.pick(name, roll).cloned()
+            } else {
+                None
+            };
+            if let Some(fault) = &fault {
+                chaos.log.0.borrow_mut().push(InjectedFault {
+                    round,
+                    effect: name,
+                    fault: fault.kind(),
+                });
+            }
+            match fault {
+                None => s.put(output),
+                Some(Fault::Drop) => drop(output),
+                Some(Fault::Delay(rounds)) => {
+                    let due = round + rounds;
+                    let at = held.iter().position(|(d, _)| *d > due);
+                    held.insert(at.unwrap_or(held.len()), (due, output));
+                },
+                Some(Fault::Duplicate) => {
+                    s.put(output.clone());
+                    s.put(output);
+                },
+                Some(Fault::Corrupt(f)) => s.put(f(output)),
+            }
+        };
+        Block::new(context, generator)
+    }
+}
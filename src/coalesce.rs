@@ -0,0 +1,217 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::ops::{Generator, GeneratorState};
+use super::{block::Block, computation::Effect};
+
+macro_rules! release {
+    ($context:expr, $effect:expr) => {{
+        let before = $context.produced();
+        yield $effect;
+        for _ in before..$context.produced() {
+            if let Some(response) = $context.take_last() {
+                $context.orphan(response);
+            }
+        }
+    }};
+}
+
+pub trait Mergeable {
+    fn mergeable(&self) -> bool;
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    E::Input: Mergeable,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Mergeable effects are one-way: the generator is resumed right away and
+    // the single response to a released merged effect goes to the orphan callback.
+    pub fn coalesce<M>(
+        self,
+        window: usize,
+        merge: M,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        M: FnMut(E::Input, E::Input) -> Result<E::Input, (E::Input, E::Input)>,
+    {
+        let context = self.context();
+        let mut merge = merge;
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            move || {
+                let mut buffer = None;
+                let mut absorbed = 0;
+                loop {
+                    let effect = match s.resume() {
+                        GeneratorState::Complete(r) => {
+                            if let Some(last) = buffer.take() {
+                                release!(context, last);
+                            }
+                            return r;
+                        },
+                        GeneratorState::Yielded(effect) => effect,
+                    };
+                    if !effect.mergeable() {
+                        if let Some(last) = buffer.take() {
+                            release!(context, last);
+                        }
+                        absorbed = 0;
+                        yield effect;
+                        continue;
+                    }
+                    let effect = match buffer.take() {
+                        None => effect,
+                        Some(last) => match merge(last, effect) {
+                            Ok(merged) => merged,
+                            Err((last, effect)) => {
+                                release!(context, last);
+                                absorbed = 0;
+                                effect
+                            },
+                        },
+                    };
+                    absorbed += 1;
+                    if absorbed >= window {
+                        absorbed = 0;
+                        release!(context, effect);
+                    } else {
+                        buffer = Some(effect);
+                    }
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{Context, Effect, Select, IntoBlock, perform};
+    use super::Mergeable;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        WriteTcp(u16, Vec<u8>),
+        ReadTcp(u16),
+    }
+
+    impl Mergeable for Effects {
+        fn mergeable(&self) -> bool {
+            matches!(self, Effects::WriteTcp(..))
+        }
+    }
+
+    #[derive(Debug)]
+    enum Outputs {
+        Written,
+        Read(Vec<u8>),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    struct Read(Vec<u8>);
+
+    impl Select<Read> for Outputs {
+        fn take(output: &Context<Self>) -> Option<Read> {
+            match output.take()? {
+                Outputs::Read(data) => Some(Read(data)),
+                _ => None,
+            }
+        }
+    }
+
+    fn merge(last: Effects, next: Effects) -> Result<Effects, (Effects, Effects)> {
+        match (last, next) {
+            (Effects::WriteTcp(a, mut data), Effects::WriteTcp(b, more)) if a == b => {
+                data.extend(more);
+                Ok(Effects::WriteTcp(a, data))
+            },
+            pair => Err(pair),
+        }
+    }
+
+    fn run<G>(computation: impl FnOnce(Context<Outputs>) -> G) -> (Vec<Effects>, usize)
+    where
+        G: Unpin + Generator<(), Yield = Effects, Return = ()>,
+    {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let orphans = Rc::new(RefCell::new(0));
+        let block = computation.into_block().coalesce(16, merge);
+        block.context().on_orphan({
+            let orphans = orphans.clone();
+            move |_| *orphans.borrow_mut() += 1
+        });
+        block
+            .add_handler({
+                let log = log.clone();
+                move |effect: Effects| {
+                    log.borrow_mut().push(effect.clone());
+                    match effect {
+                        Effects::WriteTcp(..) => Ok(Outputs::Written),
+                        Effects::ReadTcp(_) => Ok(Outputs::Read(b"pong".to_vec())),
+                    }
+                }
+            })
+            .assert_handled()
+            .run();
+        let log = log.borrow().clone();
+        let orphans = *orphans.borrow();
+        (log, orphans)
+    }
+
+    #[test]
+    fn adjacent_writes() {
+        let (log, orphans) = run(|_| {
+            move || {
+                for byte in 0..10 {
+                    perform!(Effects::WriteTcp(8224, vec![byte]));
+                }
+            }
+        });
+        assert_eq!(log, vec![Effects::WriteTcp(8224, (0..10).collect())]);
+        assert_eq!(orphans, 1);
+    }
+
+    #[test]
+    fn different_addresses() {
+        let (log, _) = run(|_| {
+            move || {
+                perform!(Effects::WriteTcp(1, vec![0]));
+                perform!(Effects::WriteTcp(1, vec![1]));
+                perform!(Effects::WriteTcp(2, vec![2]));
+            }
+        });
+        assert_eq!(
+            log,
+            vec![
+                Effects::WriteTcp(1, vec![0, 1]),
+                Effects::WriteTcp(2, vec![2])
+            ],
+        );
+    }
+
+    #[test]
+    fn response_flushes() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let (log, _) = run(|context| {
+            let seen = seen.clone();
+            move || {
+                perform!(Effects::WriteTcp(1, b"pi".to_vec()));
+                perform!(Effects::WriteTcp(1, b"ng".to_vec()));
+                let Read(data) = perform!(Effects::ReadTcp(1), &context);
+                seen.borrow_mut().push(data);
+            }
+        });
+        assert_eq!(
+            log,
+            vec![Effects::WriteTcp(1, b"ping".to_vec()), Effects::ReadTcp(1)],
+        );
+        assert_eq!(*seen.borrow(), vec![b"pong".to_vec()]);
+    }
+}
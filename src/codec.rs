@@ -0,0 +1,476 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// How the bridge, the journal and the trace exports turn values into bytes.
+// JSON is the default everywhere, a binary codec is stored as hex where the
+// storage is text.
+
+use std::{
+    fmt::{self, Write as _},
+    error::Error,
+};
+#[cfg(feature = "bridge")]
+use std::io;
+use serde::{Serialize, de::DeserializeOwned};
+use super::{
+    sources::Framing,
+    trace::{MigrateError, MigrationChain},
+};
+
+#[derive(Debug)]
+pub enum CodecError {
+    Encode(String),
+    // `offset` is the first byte of the frame the decoder did not accept
+    Decode { offset: usize, message: String },
+    // a `VersionedCodec` frame the codec has no way to read
+    Version { found: u32, expected: u32 },
+    Migrate(MigrateError),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Encode(message) => write!(f, "cannot encode: {}", message),
+            CodecError::Decode { offset, message } => {
+                write!(f, "cannot decode at byte {}: {}", offset, message)
+            },
+            CodecError::Version { found, expected } => write!(
+                f,
+                "frame version {} without a migration to version {}",
+                found, expected
+            ),
+            CodecError::Migrate(error) => write!(f, "cannot migrate the frame: {}", error),
+        }
+    }
+}
+
+impl Error for CodecError {}
+
+impl From<MigrateError> for CodecError {
+    fn from(error: MigrateError) -> Self {
+        CodecError::Migrate(error)
+    }
+}
+
+pub trait Codec {
+    fn encode<T>(&self, value: &T, buffer: &mut Vec<u8>) -> Result<(), CodecError>
+    where
+        T: Serialize;
+
+    fn decode<T>(&self, bytes: &[u8]) -> Result<T, CodecError>
+    where
+        T: DeserializeOwned;
+
+    // how the frames are told apart on a stream, a `Lines` codec never
+    // writes a newline
+    fn frame(&self) -> Framing {
+        Framing::LengthPrefixed
+    }
+
+    // the encoding is UTF-8 text without newlines, kept as it is where the
+    // storage is text
+    fn is_text(&self) -> bool {
+        false
+    }
+}
+
+impl<C> Codec for &C
+where
+    C: Codec,
+{
+    fn encode<T>(&self, value: &T, buffer: &mut Vec<u8>) -> Result<(), CodecError>
+    where
+        T: Serialize,
+    {
+        (**self).encode(value, buffer)
+    }
+
+    fn decode<T>(&self, bytes: &[u8]) -> Result<T, CodecError>
+    where
+        T: DeserializeOwned,
+    {
+        (**self).decode(bytes)
+    }
+
+    fn frame(&self) -> Framing {
+        (**self).frame()
+    }
+
+    fn is_text(&self) -> bool {
+        (**self).is_text()
+    }
+}
+
+// compact JSON, so a value is a single line
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T>(&self, value: &T, buffer: &mut Vec<u8>) -> Result<(), CodecError>
+    where
+        T: Serialize,
+    {
+        serde_json::to_writer(buffer, value).map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode<T>(&self, bytes: &[u8]) -> Result<T, CodecError>
+    where
+        T: DeserializeOwned,
+    {
+        serde_json::from_slice(bytes).map_err(|error| {
+            // the error knows the line and the column, counted from one
+            let line_start = bytes
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| **b == b'\n')
+                .map(|(i, _)| i + 1)
+                .nth(error.line().saturating_sub(2))
+                .filter(|_| error.line() > 1)
+                .unwrap_or(0);
+            let offset = (line_start + error.column().saturating_sub(1)).min(bytes.len());
+            CodecError::Decode {
+                offset,
+                message: error.to_string(),
+            }
+        })
+    }
+
+    fn is_text(&self) -> bool {
+        true
+    }
+}
+
+// MessagePack with the field names, so a struct reads like its JSON
+#[cfg(feature = "rmp")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MsgpackCodec;
+
+#[cfg(feature = "rmp")]
+impl Codec for MsgpackCodec {
+    fn encode<T>(&self, value: &T, buffer: &mut Vec<u8>) -> Result<(), CodecError>
+    where
+        T: Serialize,
+    {
+        let mut serializer = rmp_serde::Serializer::new(buffer).with_struct_map();
+        value
+            .serialize(&mut serializer)
+            .map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode<T>(&self, bytes: &[u8]) -> Result<T, CodecError>
+    where
+        T: DeserializeOwned,
+    {
+        let mut cursor = std::io::Cursor::new(bytes);
+        rmp_serde::from_read(&mut cursor).map_err(|error| CodecError::Decode {
+            // the marker that failed is read already
+            offset: (cursor.position() as usize).saturating_sub(1),
+            message: error.to_string(),
+        })
+    }
+}
+
+// Every frame starts with the version, four bytes big endian. A frame of an
+// older version goes through the migrations, any other one is refused before
+// its payload is read.
+#[derive(Clone)]
+pub struct VersionedCodec<C> {
+    inner: C,
+    version: u32,
+    migrations: Option<MigrationChain>,
+}
+
+impl<C> VersionedCodec<C> {
+    pub fn new(inner: C, version: u32) -> Self {
+        VersionedCodec {
+            inner,
+            version,
+            migrations: None,
+        }
+    }
+
+    // the migrations work on the JSON value, whatever the codec
+    pub fn migrations(self, chain: MigrationChain) -> Self {
+        VersionedCodec {
+            migrations: Some(chain),
+            ..self
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+impl<C> Codec for VersionedCodec<C>
+where
+    C: Codec,
+{
+    fn encode<T>(&self, value: &T, buffer: &mut Vec<u8>) -> Result<(), CodecError>
+    where
+        T: Serialize,
+    {
+        buffer.extend_from_slice(&self.version.to_be_bytes());
+        self.inner.encode(value, buffer)
+    }
+
+    fn decode<T>(&self, bytes: &[u8]) -> Result<T, CodecError>
+    where
+        T: DeserializeOwned,
+    {
+        let (found, payload) = match bytes {
+            [a, b, c, d, payload @ ..] => (u32::from_be_bytes([*a, *b, *c, *d]), payload),
+            _ => {
+                return Err(CodecError::Decode {
+                    offset: bytes.len(),
+                    message: "the frame ends before its version".to_string(),
+                })
+            },
+        };
+        // the offsets are in the whole frame
+        let shift = |error: CodecError| match error {
+            CodecError::Decode { offset, message } => CodecError::Decode {
+                offset: offset + 4,
+                message,
+            },
+            error => error,
+        };
+        if found == self.version {
+            return self.inner.decode(payload).map_err(shift);
+        }
+        match &self.migrations {
+            Some(chain) if found < self.version => {
+                let value = self.inner.decode(payload).map_err(shift)?;
+                let value = chain.migrate(value, found, self.version)?;
+                serde_json::from_value(value).map_err(|e| CodecError::Decode {
+                    offset: 4,
+                    message: e.to_string(),
+                })
+            },
+            _ => Err(CodecError::Version {
+                found,
+                expected: self.version,
+            }),
+        }
+    }
+
+    fn frame(&self) -> Framing {
+        // the version is binary
+        Framing::LengthPrefixed
+    }
+}
+
+#[cfg(feature = "bridge")]
+pub(crate) fn write_frame<C, W>(codec: &C, w: &mut W, bytes: &[u8]) -> io::Result<()>
+where
+    C: Codec,
+    W: io::Write,
+{
+    match codec.frame() {
+        Framing::LengthPrefixed => {
+            w.write_all(&(bytes.len() as u32).to_be_bytes())?;
+            w.write_all(bytes)?;
+        },
+        Framing::Lines => {
+            if bytes.contains(&b'\n') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a line framed codec wrote a newline",
+                ));
+            }
+            w.write_all(bytes)?;
+            w.write_all(b"\n")?;
+        },
+    }
+    w.flush()
+}
+
+// the bytes of one frame, a stream is not buffered, so a line is read a byte
+// at a time
+#[cfg(feature = "bridge")]
+pub(crate) fn read_frame<C, R>(codec: &C, r: &mut R) -> io::Result<Vec<u8>>
+where
+    C: Codec,
+    R: io::Read,
+{
+    match codec.frame() {
+        Framing::LengthPrefixed => {
+            let mut length = [0; 4];
+            r.read_exact(&mut length)?;
+            let mut bytes = vec![0; u32::from_be_bytes(length) as usize];
+            r.read_exact(&mut bytes)?;
+            Ok(bytes)
+        },
+        Framing::Lines => {
+            let mut bytes = Vec::new();
+            let mut byte = [0];
+            loop {
+                r.read_exact(&mut byte)?;
+                if byte[0] == b'\n' {
+                    break Ok(bytes);
+                }
+                bytes.push(byte[0]);
+            }
+        },
+    }
+}
+
+// where only text is stored, the bytes of a binary codec are written in hex
+pub(crate) fn to_text<C>(codec: &C, bytes: Vec<u8>) -> Option<String>
+where
+    C: Codec,
+{
+    if codec.is_text() {
+        String::from_utf8(bytes).ok()
+    } else {
+        let mut text = String::with_capacity(bytes.len() * 2);
+        for b in &bytes {
+            write!(text, "{:02x}", b).expect("a `String` takes every write");
+        }
+        Some(text)
+    }
+}
+
+pub(crate) fn from_text<C>(codec: &C, text: &str) -> Option<Vec<u8>>
+where
+    C: Codec,
+{
+    if codec.is_text() {
+        return Some(text.as_bytes().to_vec());
+    }
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Serialize, Deserialize};
+    use crate::migrations;
+    use super::{Codec, CodecError, JsonCodec, VersionedCodec};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Outputs {
+        Sum(i32),
+        Greeting { name: String },
+    }
+
+    fn round_trip<C>(codec: C)
+    where
+        C: Codec,
+    {
+        let values = vec![
+            Outputs::Sum(-3),
+            Outputs::Greeting {
+                name: "hi".to_string(),
+            },
+        ];
+        let mut buffer = Vec::new();
+        codec.encode(&values, &mut buffer).unwrap();
+        assert_eq!(codec.decode::<Vec<Outputs>>(&buffer).unwrap(), values);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        round_trip(JsonCodec);
+        round_trip(VersionedCodec::new(JsonCodec, 3));
+    }
+
+    #[cfg(feature = "rmp")]
+    #[test]
+    fn msgpack_round_trip() {
+        round_trip(super::MsgpackCodec);
+        round_trip(VersionedCodec::new(super::MsgpackCodec, 3));
+    }
+
+    #[test]
+    fn corrupted_json_offset() {
+        let error = JsonCodec.decode::<Vec<u32>>(b"[1,2,x]").unwrap_err();
+        assert!(
+            matches!(error, CodecError::Decode { offset: 5, .. }),
+            "{}",
+            error
+        );
+        let versioned = VersionedCodec::new(JsonCodec, 1);
+        let mut frame = 1u32.to_be_bytes().to_vec();
+        frame.extend_from_slice(b"[1,2,x]");
+        let error = versioned.decode::<Vec<u32>>(&frame).unwrap_err();
+        assert!(
+            matches!(error, CodecError::Decode { offset: 9, .. }),
+            "{}",
+            error
+        );
+    }
+
+    #[cfg(feature = "rmp")]
+    #[test]
+    fn corrupted_msgpack_offset() {
+        let codec = super::MsgpackCodec;
+        let mut buffer = Vec::new();
+        codec.encode(&vec![1u32, 2, 3], &mut buffer).unwrap();
+        // the second element becomes a reserved marker
+        buffer[2] = 0xc1;
+        let error = codec.decode::<Vec<u32>>(&buffer).unwrap_err();
+        assert!(
+            matches!(error, CodecError::Decode { offset: 2, .. }),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn unknown_version_refused() {
+        let codec = VersionedCodec::new(JsonCodec, 2);
+        // the payload is not even JSON, it is never read
+        let mut frame = 7u32.to_be_bytes().to_vec();
+        frame.extend_from_slice(b"\xff\xff");
+        let error = codec.decode::<Outputs>(&frame).unwrap_err();
+        assert!(matches!(
+            error,
+            CodecError::Version {
+                found: 7,
+                expected: 2
+            }
+        ));
+
+        // an older one without migrations as well
+        let mut frame = 1u32.to_be_bytes().to_vec();
+        frame.extend_from_slice(b"\xff\xff");
+        let error = codec.decode::<Outputs>(&frame).unwrap_err();
+        assert!(matches!(
+            error,
+            CodecError::Version {
+                found: 1,
+                expected: 2
+            }
+        ));
+    }
+
+    #[test]
+    fn older_version_migrated() {
+        fn rename(
+            value: serde_json::Value,
+            _: u32,
+        ) -> Result<serde_json::Value, crate::trace::MigrateError> {
+            let name = value["Greeting"]["text"].clone();
+            Ok(serde_json::json!({ "Greeting": { "name": name } }))
+        }
+
+        let old = VersionedCodec::new(JsonCodec, 1);
+        let mut frame = Vec::new();
+        old.encode(
+            &serde_json::json!({ "Greeting": { "text": "hi" } }),
+            &mut frame,
+        )
+        .unwrap();
+        let codec = VersionedCodec::new(JsonCodec, 2).migrations(migrations![1 => rename]);
+        let expected = Outputs::Greeting {
+            name: "hi".to_string(),
+        };
+        assert_eq!(codec.decode::<Outputs>(&frame).unwrap(), expected);
+    }
+}
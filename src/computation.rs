@@ -4,26 +4,302 @@
 use std::{
     ops::{Generator, GeneratorState},
     cell::RefCell,
-    fmt,
+    any, fmt,
+};
+use super::{
+    block::Block,
+    cancel::CancelToken,
+    context::Context,
+    explain::EffectDescription,
+    idempotency::IdempotencyToken,
+    health::HealthStatus,
+    plugin::{ResourceKey, Resources},
+    sink::ResponseSink,
 };
-use super::{block::Block, context::Context};
 
+/// Links an output type to the effects it answers.
+///
+/// ```
+/// use aeiou::Effect;
+///
+/// enum Effects {
+///     Read,
+/// }
+///
+/// enum Outputs {
+///     Data(String),
+/// }
+///
+/// impl Effect for Outputs {
+///     type Input = Effects;
+/// }
+/// ```
 pub trait Effect {
     type Input;
+
+    // the families composed in the type, more than one only for a `Sum`
+    const FAMILIES: usize = 1;
+
+    // the index among them of the family of an output, and of an effect
+    fn family(&self) -> usize {
+        0
+    }
+
+    fn input_family(effect: &Self::Input) -> usize {
+        let _ = effect;
+        0
+    }
+
+    fn family_name(family: usize) -> &'static str {
+        let _ = family;
+        any::type_name::<Self>()
+    }
+}
+
+// a stable name for the class of an effect, usually the variant name
+pub trait EffectName {
+    fn effect_name(&self) -> &'static str;
+
+    // the derive takes the summary and the details from the doc comment
+    fn describe(&self) -> EffectDescription {
+        EffectDescription::new(self.effect_name(), "", &[])
+    }
 }
 
+/// What `Select::try_take` found at the front of the queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TakeResult<Part, E> {
+    Empty,
+    Matched(Part),
+    // taken out of the queue, the caller decides whether to put it back
+    Mismatched(E),
+}
+
+impl<Part, E> TakeResult<Part, E> {
+    pub fn map<F, P>(self, f: F) -> TakeResult<P, E>
+    where
+        F: FnOnce(Part) -> P,
+    {
+        match self {
+            TakeResult::Empty => TakeResult::Empty,
+            TakeResult::Matched(part) => TakeResult::Matched(f(part)),
+            TakeResult::Mismatched(output) => TakeResult::Mismatched(output),
+        }
+    }
+
+    pub fn matched(self) -> Option<Part> {
+        match self {
+            TakeResult::Matched(part) => Some(part),
+            _ => None,
+        }
+    }
+}
+
+/// Projects a part out of the next output. An output that does not match is put
+/// back by `take`, so other parts can still take it, and handed to the caller
+/// by `try_take`. Each method defaults to the other, implement at least one.
+///
+/// ```
+/// use aeiou::{Context, Select, TakeResult};
+///
+/// aeiou::simple_effects! {
+///     Effects { Read }
+///     Outputs { Data(String), Closed }
+/// }
+///
+/// struct Line(String);
+///
+/// impl Select<Line> for Outputs {
+///     fn try_take(output: &Context<Self>) -> TakeResult<Line, Self> {
+///         match output.take() {
+///             None => TakeResult::Empty,
+///             Some(Outputs::Data(line)) => TakeResult::Matched(Line(line)),
+///             Some(other) => TakeResult::Mismatched(other),
+///         }
+///     }
+/// }
+///
+/// let context = Context::empty();
+/// context.put(Outputs::Closed);
+/// assert!(<Outputs as Select<Line>>::take(&context).is_none());
+/// assert_eq!(context.take(), Some(Outputs::Closed));
+/// ```
 pub trait Select<Part>
 where
     Self: Sized + Effect,
 {
-    fn take(output: &Context<Self>) -> Option<Part>;
+    fn take(output: &Context<Self>) -> Option<Part> {
+        match Self::try_take(output) {
+            TakeResult::Empty => None,
+            TakeResult::Matched(part) => Some(part),
+            TakeResult::Mismatched(other) => {
+                output.put_front(other);
+                None
+            },
+        }
+    }
+
+    // an impl of `take` that drops what it does not match looks empty here
+    fn try_take(output: &Context<Self>) -> TakeResult<Part, Self> {
+        match Self::take(output) {
+            Some(part) => TakeResult::Matched(part),
+            None => match output.take() {
+                Some(other) => TakeResult::Mismatched(other),
+                None => TakeResult::Empty,
+            },
+        }
+    }
 }
 
+/// Answers an effect with an output or gives it back to the next handler.
+/// Any `FnMut(E::Input) -> Result<E, E::Input>` closure is a handler.
+///
+/// ```
+/// use aeiou::Handler;
+///
+/// aeiou::simple_effects! {
+///     Effects { Double(u32), Print(String) }
+///     Outputs { Number(u32) }
+/// }
+///
+/// let mut double = |effect| match effect {
+///     Effects::Double(n) => Ok(Outputs::Number(n * 2)),
+///     other => Err(other),
+/// };
+/// assert_eq!(double.handle(Effects::Double(2)), Ok(Outputs::Number(4)));
+/// assert!(double.handle(Effects::Print("x".to_string())).is_err());
+/// ```
+///
+/// A handler with state implements the trait directly.
+///
+/// ```
+/// use aeiou::Handler;
+///
+/// aeiou::simple_effects! {
+///     Effects { Next }
+///     Outputs { Number(u32) }
+/// }
+///
+/// #[derive(Default)]
+/// struct Counter(u32);
+///
+/// impl Handler<Outputs> for Counter {
+///     fn handle(&mut self, effect: Effects) -> Result<Outputs, Effects> {
+///         let Effects::Next = effect;
+///         self.0 += 1;
+///         Ok(Outputs::Number(self.0))
+///     }
+/// }
+///
+/// let mut counter = Counter::default();
+/// counter.handle(Effects::Next).unwrap();
+/// assert_eq!(counter.handle(Effects::Next), Ok(Outputs::Number(2)));
+/// ```
 pub trait Handler<E>
 where
     E: Effect,
 {
     fn handle(&mut self, effect: E::Input) -> Result<E, E::Input>;
+
+    // called instead of `handle` under `idempotency_tokens`, the token is the
+    // same on every delivery of one logical effect
+    fn handle_idempotent(
+        &mut self,
+        token: IdempotencyToken,
+        effect: E::Input,
+    ) -> Result<E, E::Input> {
+        let _ = token;
+        self.handle(effect)
+    }
+
+    // called instead of `handle` for an effect with a cancel token, a long
+    // operation can check it and stop early
+    fn handle_cancellable(&mut self, token: &CancelToken, effect: E::Input) -> Result<E, E::Input> {
+        let _ = token;
+        self.handle(effect)
+    }
+
+    // called instead of `handle` by `add_streaming_handler`, a handler that
+    // does not stream finishes the sink with its one output
+    fn handle_streaming(
+        &mut self,
+        effect: E::Input,
+        sink: ResponseSink<E>,
+    ) -> Result<(), E::Input> {
+        let output = self.handle(effect)?;
+        sink.finish(output);
+        Ok(())
+    }
+
+    // lifecycle hooks, called when the handler is swapped in and out
+    fn init(&mut self) {}
+
+    fn finish(&mut self) {}
+
+    // asked by `run_checked` right after `init`, and again on its rechecks
+    fn health_check(&mut self) -> HealthStatus {
+        HealthStatus::Healthy
+    }
+
+    // whether `health_check` is implemented, for the capabilities manifest
+    fn checks_health(&self) -> bool {
+        false
+    }
+
+    // The resources the handler puts in `init_with` and the ones it takes
+    // there. `add_registry` initializes the providers first, the order the
+    // effects are offered in stays as given.
+    fn provides(&self) -> &'static [ResourceKey] {
+        &[]
+    }
+
+    fn requires(&self) -> &'static [ResourceKey] {
+        &[]
+    }
+
+    fn init_with(&mut self, resources: &mut Resources) {
+        let _ = resources;
+        self.init()
+    }
+
+    // called on a flush barrier by `add_flushing_handler`, an error is an effect
+    // the handler still holds, it is performed before the handler is asked again
+    fn flush(&mut self) -> Result<(), E::Input> {
+        Ok(())
+    }
+}
+
+// how a run ends on an effect nobody answered, or on an answer a layer
+// refuses to put, with the scope the effect came from
+pub(crate) fn report_unhandled<T, R>(context: &Context<T>, report: R) -> !
+where
+    R: fmt::Display,
+{
+    let path = context.scope_path();
+    if path.is_empty() {
+        panic!("unhandled: {}", report);
+    }
+    panic!("unhandled: {} in {}", report, path);
+}
+
+// offers the effect with its cancel token, or with the token of the logical
+// effect, if there is one
+pub(crate) fn dispatch<E, H>(
+    handler: &mut H,
+    context: &Context<E>,
+    effect: E::Input,
+) -> Result<E, E::Input>
+where
+    E: Effect,
+    H: Handler<E> + ?Sized,
+{
+    if let Some(token) = context.cancel_token() {
+        return handler.handle_cancellable(&token, effect);
+    }
+    match context.token() {
+        Some(token) => handler.handle_idempotent(token, effect),
+        None => handler.handle(effect),
+    }
 }
 
 impl<F, E> Handler<E> for F
@@ -36,32 +312,144 @@ where
     }
 }
 
+// checked before a handler is offered an effect, a rejected effect skips it
+pub trait EffectFilter<I> {
+    fn admits(&self, effect: &I) -> bool;
+
+    // the admitted variants, when the filter looks at nothing else
+    fn variants(&self) -> Option<&[&'static str]> {
+        None
+    }
+}
+
+impl<I, F> EffectFilter<I> for F
+where
+    F: Fn(&I) -> bool,
+{
+    fn admits(&self, effect: &I) -> bool {
+        self(effect)
+    }
+}
+
+// built by `filter_variants!`, the names are the ones `EffectName` gives
+pub struct VariantFilter<I> {
+    variants: &'static [&'static str],
+    admits: fn(&I) -> bool,
+}
+
+impl<I> VariantFilter<I> {
+    pub fn new(variants: &'static [&'static str], admits: fn(&I) -> bool) -> Self {
+        VariantFilter { variants, admits }
+    }
+}
+
+impl<I> EffectFilter<I> for VariantFilter<I> {
+    fn admits(&self, effect: &I) -> bool {
+        (self.admits)(effect)
+    }
+
+    fn variants(&self) -> Option<&[&'static str]> {
+        Some(self.variants)
+    }
+}
+
 impl<E, G> Block<E, G>
 where
     E: Effect,
-    G: Unpin + Generator<(), Return = (), Yield = E::Input>,
+    G: Unpin + Generator<(), Yield = E::Input>,
     G::Yield: fmt::Debug,
 {
-    pub fn assert_handled(self) -> Block<E, impl Unpin + Generator<(), Return = (), Yield = !>> {
+    /// Ends the handler chain, an effect that reaches it panics.
+    ///
+    /// ```should_panic
+    /// #![feature(generators)]
+    /// use aeiou::{Context, IntoBlock, perform};
+    ///
+    /// aeiou::simple_effects! {
+    ///     Effects { Read, Write }
+    ///     Outputs { Done }
+    /// }
+    ///
+    /// (|_: Context<Outputs>| {
+    ///     move || {
+    ///         perform!(Effects::Read);
+    ///         perform!(Effects::Write);
+    ///     }
+    /// })
+    /// .into_block()
+    /// .add_handler(|effect| match effect {
+    ///     Effects::Read => Ok(Outputs::Done),
+    ///     other => Err(other),
+    /// })
+    /// .assert_handled()
+    /// .run();
+    /// ```
+    pub fn assert_handled(
+        self,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = !>> {
         let context = self.context();
         let mut s = self;
+        // the loop ends on the first effect, it only gives the `yield` a place
+        #[allow(clippy::never_loop)]
         let generator = move || loop {
             match s.resume() {
-                GeneratorState::Complete(()) => break,
+                GeneratorState::Complete(r) => return r,
                 GeneratorState::Yielded(effects) => {
-                    panic!("unhandled: {:?}", effects);
+                    report_unhandled(&s.context(), format_args!("{:?}", effects));
                     #[allow(unreachable_code)]
-                    yield loop {}
+                    yield unreachable!()
                 },
             }
         };
         Block::new(context, generator)
     }
 
+    /// Wraps the block so `handler` sees its effects first, the ones it declines go
+    /// to the next handler.
+    ///
+    /// ```
+    /// #![feature(generators)]
+    /// use std::{rc::Rc, cell::RefCell};
+    /// use aeiou::{Context, IntoBlock, perform};
+    ///
+    /// aeiou::simple_effects! {
+    ///     Effects { Read, Log(&'static str) }
+    ///     Outputs { Data(u8), Logged }
+    ///     parts { Data(u8) => Byte }
+    /// }
+    ///
+    /// let log = Rc::new(RefCell::new(Vec::new()));
+    /// let byte = (|context: Context<Outputs>| {
+    ///     move || {
+    ///         let Byte(byte) = perform!(Effects::Read, &context);
+    ///         perform!(Effects::Log("read"));
+    ///         byte
+    ///     }
+    /// })
+    /// .into_block()
+    /// .add_handler(|effect| match effect {
+    ///     Effects::Read => Ok(Outputs::Data(7)),
+    ///     other => Err(other),
+    /// })
+    /// .add_handler({
+    ///     let log = log.clone();
+    ///     move |effect| match effect {
+    ///         Effects::Log(line) => {
+    ///             log.borrow_mut().push(line);
+    ///             Ok(Outputs::Logged)
+    ///         },
+    ///         other => Err(other),
+    ///     }
+    /// })
+    /// .assert_handled()
+    /// .run();
+    /// assert_eq!(byte, 7);
+    /// assert_eq!(*log.borrow(), ["read"]);
+    /// ```
     pub fn add_handler<H>(
         self,
         handler: H,
-    ) -> Block<E, impl Unpin + Generator<(), Return = (), Yield = E::Input>>
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
     where
         H: Handler<E>,
     {
@@ -70,10 +458,10 @@ where
         let mut s = self;
         let generator = move || loop {
             match s.resume() {
-                GeneratorState::Complete(()) => return,
+                GeneratorState::Complete(r) => return r,
                 GeneratorState::Yielded(effects) => {
                     let mut h = handler.borrow_mut();
-                    match h.handle(effects) {
+                    match dispatch(&mut *h, &s.context(), effects) {
                         Ok(handled) => s.put(handled),
                         Err(unhandled) => {
                             drop(h);
@@ -85,4 +473,31 @@ where
         };
         Block::new(context, generator)
     }
+
+    pub fn add_handler_filtered<F, H>(
+        self,
+        filter: F,
+        handler: H,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        F: EffectFilter<E::Input>,
+        H: Handler<E>,
+    {
+        let context = self.context();
+        let mut handler = handler;
+        let mut s = self;
+        let generator = move || loop {
+            match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) if !filter.admits(&effect) => yield effect,
+                GeneratorState::Yielded(effect) => {
+                    match dispatch(&mut handler, &s.context(), effect) {
+                        Ok(handled) => s.put(handled),
+                        Err(unhandled) => yield unhandled,
+                    }
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
 }
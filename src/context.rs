@@ -1,21 +1,1093 @@
 // Copyright 2021 Vladislav Melnik
 // SPDX-License-Identifier: MIT
 
-use std::{rc::Rc, cell::RefCell};
+use std::{
+    rc::Rc,
+    cell::{Cell, Ref, RefCell, RefMut},
+    collections::VecDeque,
+    any::Any,
+    mem,
+    ops::{Deref, DerefMut},
+};
+use super::{
+    accounting::{Accounting, Category, Counter},
+    cancel::CancelToken,
+    computation::{Select, TakeResult},
+    idempotency::IdempotencyToken,
+    reuse::{self, ContextReuse},
+};
 
-pub struct Context<T>(Rc<RefCell<Option<T>>>);
+pub type SortKey<T> = Box<dyn Fn(&T) -> u64>;
+
+pub type CoalesceKey<T> = Box<dyn Fn(&T) -> Option<u64>>;
+
+type Orphan<T> = Box<dyn FnMut(T)>;
+
+// checks an output before anything sees it, with the counter to bump
+pub(crate) type Guard<T> = Box<dyn FnMut(T) -> (T, Option<Counter>)>;
+
+type Claimed<T> = Box<dyn Fn(&T) -> bool>;
+
+// A mutable borrow naming its operation in `busy` while it lasts, a borrow
+// that ends, like the one of the watches in `put`, does not hide the
+// operation still holding another cell.
+struct BusyMut<'a, X> {
+    value: RefMut<'a, X>,
+    busy: &'a Cell<&'static str>,
+    previous: &'static str,
+}
+
+impl<X> Deref for BusyMut<'_, X> {
+    type Target = X;
+
+    fn deref(&self) -> &X {
+        &self.value
+    }
+}
+
+impl<X> DerefMut for BusyMut<'_, X> {
+    fn deref_mut(&mut self) -> &mut X {
+        &mut self.value
+    }
+}
+
+impl<X> Drop for BusyMut<'_, X> {
+    fn drop(&mut self) {
+        self.busy.set(self.previous);
+    }
+}
+
+// a narrow view puts into its parent, and moves the parent outputs it can
+// project into its own queue before it is read
+struct Upstream<T> {
+    put: Box<dyn Fn(T)>,
+    pull: Box<dyn Fn() -> Vec<(u64, T)>>,
+    taken: Box<dyn Fn(u64)>,
+}
+
+// sees the output with its sequence number
+#[cfg(feature = "diagnostics")]
+pub(crate) type Watch<T> = Box<dyn FnMut(u64, &T)>;
+
+#[derive(Default)]
+pub enum OrderPolicy<T> {
+    #[default]
+    Fifo,
+    Lifo,
+    KeyedStableSort(SortKey<T>),
+    CoalesceLatest(CoalesceKey<T>),
+}
+
+/// The queue of outputs shared by a computation and the handlers around it.
+///
+/// ```
+/// use aeiou::Context;
+///
+/// let context = Context::empty();
+/// let handler_side = context.clone();
+/// handler_side.put("response");
+/// assert_eq!(context.len(), 1);
+/// assert_eq!(context.take(), Some("response"));
+/// assert!(context.is_empty());
+/// ```
+pub struct Context<T>(Rc<State<T>>);
+
+struct State<T> {
+    // with the sequence numbers of the outputs
+    queue: RefCell<VecDeque<(u64, T)>>,
+    // only borrowed to push or to swap out, never while user code runs
+    deferred: RefCell<Vec<T>>,
+    // the operation holding a borrow, for the reentrancy message
+    busy: Cell<&'static str>,
+    order: RefCell<OrderPolicy<T>>,
+    orphan: RefCell<Option<Orphan<T>>>,
+    guard: RefCell<Option<Guard<T>>>,
+    // the live claims, the bulk operations skip the outputs they match
+    claims: RefCell<Vec<(u64, Claimed<T>)>>,
+    next_claim: Cell<u64>,
+    #[cfg(feature = "diagnostics")]
+    watch: RefCell<Vec<Watch<T>>>,
+    #[cfg(feature = "diagnostics")]
+    accounting: RefCell<Option<Accounting>>,
+    extensions: RefCell<Vec<Rc<dyn Any>>>,
+    produced: Cell<u64>,
+    // the last sequence number given to an output, shared with the contexts
+    // of the tasks so the numbers are unique in the whole block
+    sequence: RefCell<Rc<Cell<u64>>>,
+    // the sequence number of the output taken last, and of the one before it
+    // for `put_front` to restore
+    cause: Cell<Option<u64>>,
+    before: Cell<Option<u64>>,
+    // the outputs put back with `put_front`, taken before the others whatever
+    // the order policy, the one put back last first
+    fronted: RefCell<Vec<u64>>,
+    closed: Cell<bool>,
+    // the source layers whose source is not done yet
+    sources: Cell<usize>,
+    upstream: Option<Upstream<T>>,
+    // the token of the logical effect being delivered
+    token: Cell<Option<IdempotencyToken>>,
+    // the cancel token of the effect being delivered
+    cancel: RefCell<Option<CancelToken>>,
+    // how many outputs `take_batch` may still drain before the next resume
+    batch_budget: Cell<usize>,
+    batched: Cell<usize>,
+    // the yields of the computation, a scoped output is valid until the next
+    epoch: Rc<Cell<u64>>,
+    // the blocks of this context being resumed, none between the rounds
+    resuming: Cell<usize>,
+    // the run of the block made for it, and the runs driving that block when
+    // it completed
+    run: Cell<Option<u64>>,
+    completed: RefCell<Option<Vec<u64>>>,
+    #[cfg(feature = "async")]
+    waker: RefCell<Option<std::task::Waker>>,
+    #[cfg(feature = "async")]
+    parked: Cell<bool>,
+}
 
 impl<T> Context<T> {
     pub fn empty() -> Self {
-        Context(Rc::new(RefCell::new(None)))
+        Self::with_upstream(None)
+    }
+
+    fn with_upstream(upstream: Option<Upstream<T>>) -> Self {
+        Context(Rc::new(State {
+            queue: RefCell::new(VecDeque::new()),
+            deferred: RefCell::new(Vec::new()),
+            busy: Cell::new(""),
+            order: RefCell::new(OrderPolicy::Fifo),
+            orphan: RefCell::new(None),
+            guard: RefCell::new(None),
+            claims: RefCell::new(Vec::new()),
+            next_claim: Cell::new(0),
+            #[cfg(feature = "diagnostics")]
+            watch: RefCell::new(Vec::new()),
+            #[cfg(feature = "diagnostics")]
+            accounting: RefCell::new(None),
+            extensions: RefCell::new(Vec::new()),
+            produced: Cell::new(0),
+            sequence: RefCell::new(Rc::new(Cell::new(0))),
+            cause: Cell::new(None),
+            before: Cell::new(None),
+            fronted: RefCell::new(Vec::new()),
+            closed: Cell::new(false),
+            sources: Cell::new(0),
+            upstream,
+            token: Cell::new(None),
+            cancel: RefCell::new(None),
+            batch_budget: Cell::new(usize::MAX),
+            batched: Cell::new(0),
+            epoch: Rc::new(Cell::new(0)),
+            resuming: Cell::new(0),
+            run: Cell::new(None),
+            completed: RefCell::new(None),
+            #[cfg(feature = "async")]
+            waker: RefCell::new(None),
+            #[cfg(feature = "async")]
+            parked: Cell::new(false),
+        }))
     }
 
+    /// ```
+    /// use aeiou::{Context, OrderPolicy};
+    ///
+    /// let context = Context::empty();
+    /// context.set_order(OrderPolicy::Lifo);
+    /// context.put(1);
+    /// context.put(2);
+    /// assert_eq!(context.take(), Some(2));
+    /// ```
+    pub fn set_order(&self, policy: OrderPolicy<T>) {
+        *self.borrow_mut(&self.0.order, "set_order") = policy;
+    }
+
+    pub fn on_orphan<F>(&self, f: F)
+    where
+        F: FnMut(T) + 'static,
+    {
+        *self.borrow_mut(&self.0.orphan, "on_orphan") = Some(Box::new(f));
+    }
+
+    /// Takes the next output, the first one put under the default FIFO order.
+    ///
+    /// ```
+    /// use aeiou::Context;
+    ///
+    /// let context = Context::empty();
+    /// context.put(1);
+    /// context.put(2);
+    /// assert_eq!(context.take(), Some(1));
+    /// assert_eq!(context.take(), Some(2));
+    /// assert_eq!(context.take(), None);
+    /// ```
     pub fn take(&self) -> Option<T> {
-        self.0.borrow_mut().take()
+        self.take_if(|_| true)
     }
 
+    /// ```
+    /// use aeiou::Context;
+    ///
+    /// let context = Context::empty();
+    /// context.put('a');
+    /// assert_eq!(context.len(), 1);
+    /// ```
     pub fn put(&self, value: T) {
-        *self.0.borrow_mut() = Some(value);
+        self.check_run("put");
+        if let Some(upstream) = &self.0.upstream {
+            return (upstream.put)(value);
+        }
+        let seq = self.next_sequence();
+        self.put_numbered(seq, value);
+    }
+
+    // like `put`, for an output moved between the contexts of a block, it
+    // keeps its sequence number
+    pub(crate) fn put_numbered(&self, seq: u64, value: T) {
+        let value = self.guarded(value);
+        #[cfg(feature = "diagnostics")]
+        for watch in self.borrow_mut(&self.0.watch, "put").iter_mut() {
+            watch(seq, &value);
+        }
+        self.0.produced.set(self.0.produced.get() + 1);
+        let mut queue = self.borrow_mut(&self.0.queue, "put");
+        let position = match &*self.borrow(&self.0.order, "put") {
+            OrderPolicy::CoalesceLatest(key) => {
+                key(&value).and_then(|k| queue.iter().position(|(_, v)| key(v) == Some(k)))
+            },
+            _ => None,
+        };
+        let replaced = match position {
+            Some(position) => Some(std::mem::replace(&mut queue[position], (seq, value)).1),
+            None => {
+                queue.push_back((seq, value));
+                None
+            },
+        };
+        drop(queue);
+        match replaced {
+            Some(old) => self.orphan(old),
+            None => self.account(|a| a.insert(Category::Outputs)),
+        }
+    }
+
+    /// Takes the first output matching the predicate, the rest keep their order.
+    ///
+    /// ```
+    /// use aeiou::Context;
+    ///
+    /// let context = Context::empty();
+    /// for n in 1..=4 {
+    ///     context.put(n);
+    /// }
+    /// assert_eq!(context.take_if(|n| n % 2 == 0), Some(2));
+    /// assert_eq!(std::iter::from_fn(|| context.take()).collect::<Vec<_>>(), [1, 3, 4]);
+    /// ```
+    pub fn take_if<F>(&self, mut f: F) -> Option<T>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.check_run("take");
+        self.flush_deferred();
+        self.pull();
+        let mut queue = self.borrow_mut(&self.0.queue, "take");
+        let mut fronted = self.borrow_mut(&self.0.fronted, "take");
+        // the ones taken by other means are forgotten here
+        fronted.retain(|seq| queue.iter().any(|(s, _)| s == seq));
+        let position = fronted
+            .iter()
+            .rev()
+            .find_map(|seq| queue.iter().position(|(s, v)| s == seq && f(v)));
+        let position = match position {
+            Some(position) => position,
+            None => self.by_order(&queue, f)?,
+        };
+        let (seq, value) = queue.remove(position)?;
+        fronted.retain(|s| *s != seq);
+        drop(fronted);
+        drop(queue);
+        self.taken(seq);
+        self.account(|a| a.remove(Category::Outputs));
+        Some(value)
+    }
+
+    // the position of the output the order policy gives next
+    fn by_order<F>(&self, queue: &VecDeque<(u64, T)>, mut f: F) -> Option<usize>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        match &*self.borrow(&self.0.order, "take") {
+            OrderPolicy::Fifo | OrderPolicy::CoalesceLatest(_) => {
+                queue.iter().position(|(_, v)| f(v))
+            },
+            OrderPolicy::Lifo => queue.iter().rposition(|(_, v)| f(v)),
+            OrderPolicy::KeyedStableSort(key) => queue
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, v))| f(v))
+                .min_by_key(|(position, (_, v))| (key(v), *position))
+                .map(|(position, _)| position),
+        }
+    }
+
+    /// Takes up to `max` outputs in the order `take` would give them, so a whole
+    /// round is consumed in one resume. The batch budget bounds it further.
+    ///
+    /// ```
+    /// use aeiou::Context;
+    ///
+    /// let context = Context::empty();
+    /// (1..=5).for_each(|n| context.put(n));
+    /// assert_eq!(context.take_batch(3), [1, 2, 3]);
+    /// assert_eq!(context.take_batch(usize::MAX), [4, 5]);
+    /// ```
+    pub fn take_batch(&self, max: usize) -> Vec<T> {
+        let mut batch = Vec::new();
+        self.take_batch_into(&mut batch, max);
+        batch
+    }
+
+    // like `take_batch`, appends to a buffer the caller keeps between rounds,
+    // so a steady loop does not allocate, returns how many were taken
+    pub fn take_batch_into(&self, buffer: &mut Vec<T>, max: usize) -> usize {
+        let left = self
+            .0
+            .batch_budget
+            .get()
+            .saturating_sub(self.0.batched.get());
+        let before = buffer.len();
+        buffer.extend((0..max.min(left)).map_while(|_| self.take()));
+        let taken = buffer.len() - before;
+        self.0.batched.set(self.0.batched.get() + taken);
+        taken
+    }
+
+    // the most `take_batch` drains between two resumes of the computation,
+    // so one busy task does not hold the others back
+    pub fn set_batch_budget(&self, budget: usize) {
+        self.0.batch_budget.set(budget);
+    }
+
+    pub(crate) fn begin_resume(&self) {
+        self.0.batched.set(0);
+    }
+
+    pub(crate) fn end_resume(&self) {
+        self.0.epoch.set(self.0.epoch.get() + 1);
+    }
+
+    pub(crate) fn epoch(&self) -> Rc<Cell<u64>> {
+        self.0.epoch.clone()
+    }
+
+    /// Puts back an output so it is taken next, whatever the order policy.
+    ///
+    /// ```
+    /// use aeiou::Context;
+    ///
+    /// let context = Context::empty();
+    /// context.put("later");
+    /// context.put_front("first");
+    /// assert_eq!(context.take(), Some("first"));
+    /// ```
+    pub fn put_front(&self, value: T) {
+        // it is the output taken last, it keeps its number and is not a cause
+        let seq = match self.0.cause.replace(self.0.before.get()) {
+            Some(seq) => seq,
+            None => self.next_sequence(),
+        };
+        let mut queue = self.borrow_mut(&self.0.queue, "put_front");
+        match &*self.borrow(&self.0.order, "put_front") {
+            OrderPolicy::Lifo => queue.push_back((seq, value)),
+            _ => queue.push_front((seq, value)),
+        }
+        drop(queue);
+        self.borrow_mut(&self.0.fronted, "put_front").push(seq);
+        self.account(|a| a.insert(Category::Outputs));
+    }
+
+    pub(crate) fn take_last(&self) -> Option<T> {
+        self.take_last_numbered().map(|(_, value)| value)
+    }
+
+    pub(crate) fn take_last_numbered(&self) -> Option<(u64, T)> {
+        let last = self.borrow_mut(&self.0.queue, "take").pop_back()?;
+        self.account(|a| a.remove(Category::Outputs));
+        Some(last)
+    }
+
+    /// Takes every output matching the predicate, in the order they are
+    /// queued, the rest keep their order. Claimed outputs are left alone.
+    ///
+    /// ```
+    /// use aeiou::Context;
+    ///
+    /// let context = Context::empty();
+    /// (1..=5).for_each(|n| context.put(n));
+    /// assert_eq!(context.drain_matching(|n| n % 2 == 1), [1, 3, 5]);
+    /// assert_eq!(context.take_batch(usize::MAX), [2, 4]);
+    /// ```
+    pub fn drain_matching<F>(&self, mut f: F) -> Vec<T>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.extract(false, "drain_matching", |v| {
+            if f(&v) {
+                Ok(Some(v))
+            } else {
+                Err(v)
+            }
+        })
+    }
+
+    // like `drain_matching`, the claimed outputs are drained too
+    pub fn drain_including_claimed<F>(&self, mut f: F) -> Vec<T>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.extract(true, "drain_including_claimed", |v| {
+            if f(&v) {
+                Ok(Some(v))
+            } else {
+                Err(v)
+            }
+        })
+    }
+
+    /// Drops every output not matching the predicate, except the claimed ones.
+    ///
+    /// ```
+    /// use aeiou::Context;
+    ///
+    /// let context = Context::empty();
+    /// context.put(("old", 1));
+    /// context.put(("new", 2));
+    /// context.put(("old", 3));
+    /// context.retain(|(connection, _)| *connection == "new");
+    /// assert_eq!(context.take_batch(usize::MAX), [("new", 2)]);
+    /// ```
+    pub fn retain<F>(&self, mut f: F)
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.extract(
+            false,
+            "retain",
+            |v| if f(&v) { Err(v) } else { Ok(None::<()>) },
+        );
+    }
+
+    /// Takes every output `Select` projects to the part, the rest keep their
+    /// order. Claimed outputs are left alone.
+    ///
+    /// ```
+    /// use aeiou::{Context, Select};
+    ///
+    /// aeiou::simple_effects! {
+    ///     Effects { Read }
+    ///     Outputs { Data(String), Closed }
+    /// }
+    ///
+    /// struct Line(String);
+    ///
+    /// impl Select<Line> for Outputs {
+    ///     fn take(output: &Context<Self>) -> Option<Line> {
+    ///         match output.take()? {
+    ///             Outputs::Data(line) => Some(Line(line)),
+    ///             other => {
+    ///                 output.put_front(other);
+    ///                 None
+    ///             },
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let context = Context::empty();
+    /// context.put(Outputs::Data("a".to_string()));
+    /// context.put(Outputs::Closed);
+    /// context.put(Outputs::Data("b".to_string()));
+    /// let lines = context.drain_parts::<Line>();
+    /// assert_eq!(lines.iter().map(|Line(l)| l.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+    /// assert_eq!(context.len(), 1);
+    /// ```
+    pub fn drain_parts<Part>(&self) -> Vec<Part>
+    where
+        T: Select<Part>,
+    {
+        // every output is projected on its own, so a mismatch stays in place
+        let scratch = Context::empty();
+        self.extract(false, "drain_parts", |v| {
+            scratch.put(v);
+            match T::try_take(&scratch) {
+                TakeResult::Matched(part) => Ok(Some(part)),
+                TakeResult::Mismatched(v) => Err(v),
+                // the impl dropped it, like `take` would
+                TakeResult::Empty => Ok(None),
+            }
+        })
+    }
+
+    /// Shows every output in the order they are queued without taking it.
+    ///
+    /// ```
+    /// use aeiou::Context;
+    ///
+    /// let context = Context::empty();
+    /// context.put(1);
+    /// context.put(2);
+    /// let mut sum = 0;
+    /// context.iter_with(|n| sum += n);
+    /// assert_eq!((sum, context.len()), (3, 2));
+    /// ```
+    pub fn iter_with<F>(&self, f: F)
+    where
+        F: FnMut(&T),
+    {
+        self.flush_deferred();
+        self.pull();
+        let queue = self.borrow(&self.0.queue, "iter_with");
+        // a shared borrow does not set it, a reentrant `put` names this one
+        self.0.busy.set("iter_with");
+        queue.iter().map(|(_, v)| v).for_each(f);
+    }
+
+    /// Claims the outputs matching the predicate for the code that takes them
+    /// one by one, `drain_matching`, `retain` and `drain_parts` leave them in
+    /// the queue until the claim drops.
+    ///
+    /// ```
+    /// use aeiou::Context;
+    ///
+    /// let context = Context::empty();
+    /// context.put(1);
+    /// context.put(2);
+    /// let claim = context.claim(|n| *n == 2);
+    /// context.retain(|_| false);
+    /// assert_eq!(context.take(), Some(2));
+    /// drop(claim);
+    /// ```
+    pub fn claim<F>(&self, f: F) -> Claim<T>
+    where
+        F: Fn(&T) -> bool + 'static,
+    {
+        let id = self.0.next_claim.get();
+        self.0.next_claim.set(id + 1);
+        self.borrow_mut(&self.0.claims, "claim")
+            .push((id, Box::new(f)));
+        Claim {
+            context: self.clone(),
+            id,
+        }
+    }
+
+    // Moves out every output `f` takes, in queue order, `f` gives back the
+    // others to stay in place. The claimed ones are not shown to `f` unless
+    // `claimed`. `f` runs with the queue borrowed, so the context panics with
+    // the usual message if `f` reenters it.
+    fn extract<X, F>(&self, claimed: bool, operation: &'static str, mut f: F) -> Vec<X>
+    where
+        F: FnMut(T) -> Result<Option<X>, T>,
+    {
+        self.flush_deferred();
+        self.pull();
+        let mut queue = self.borrow_mut(&self.0.queue, operation);
+        let claims = self.borrow(&self.0.claims, operation);
+        let mut extracted = Vec::new();
+        let mut removed = 0;
+        let mut last = None;
+        let mut position = 0;
+        while position < queue.len() {
+            if !claimed && claims.iter().any(|(_, claim)| claim(&queue[position].1)) {
+                position += 1;
+                continue;
+            }
+            let (seq, value) = queue.remove(position).expect("checked above");
+            match f(value) {
+                Ok(x) => {
+                    if x.is_some() {
+                        last = Some(seq);
+                    }
+                    extracted.extend(x);
+                    removed += 1;
+                },
+                Err(value) => {
+                    queue.insert(position, (seq, value));
+                    position += 1;
+                },
+            }
+        }
+        drop(claims);
+        drop(queue);
+        if let Some(seq) = last {
+            self.taken(seq);
+        }
+        for _ in 0..removed {
+            self.account(|a| a.remove(Category::Outputs));
+        }
+        extracted
+    }
+
+    pub(crate) fn orphan(&self, value: T) {
+        let orphan = self.0.orphan.borrow_mut().take();
+        if let Some(mut orphan) = orphan {
+            orphan(value);
+            let mut slot = self.0.orphan.borrow_mut();
+            if slot.is_none() {
+                *slot = Some(orphan);
+            }
+        }
+    }
+
+    // one guard per context, the last one set
+    pub(crate) fn set_guard(&self, guard: Guard<T>) {
+        *self.borrow_mut(&self.0.guard, "set_guard") = Some(guard);
+    }
+
+    // the guard is taken out while it runs, like the orphan callback
+    fn guarded(&self, value: T) -> T {
+        let guard = self.0.guard.borrow_mut().take();
+        let mut guard = match guard {
+            Some(guard) => guard,
+            None => return value,
+        };
+        let (value, counter) = guard(value);
+        let mut slot = self.0.guard.borrow_mut();
+        if slot.is_none() {
+            *slot = Some(guard);
+        }
+        drop(slot);
+        if let Some(counter) = counter {
+            self.account(|a| a.bump(counter));
+        }
+        value
+    }
+
+    /// Puts an output without touching the queue, it is moved there before
+    /// the next resume of the block or the next take. This is the only method
+    /// of the context that is safe to call from a `Drop` impl or from a callback
+    /// the context itself runs, the others panic when reentered.
+    ///
+    /// ```
+    /// use aeiou::Context;
+    ///
+    /// struct Cancel(Context<&'static str>);
+    ///
+    /// impl Drop for Cancel {
+    ///     fn drop(&mut self) {
+    ///         self.0.put_deferred("cancelled");
+    ///     }
+    /// }
+    ///
+    /// let context = Context::empty();
+    /// drop(Cancel(context.clone()));
+    /// assert_eq!(context.take(), Some("cancelled"));
+    /// ```
+    pub fn put_deferred(&self, value: T) {
+        self.0.deferred.borrow_mut().push(value);
+        self.account(|a| a.insert(Category::Deferred));
+    }
+
+    pub(crate) fn flush_deferred(&self) {
+        let mut deferred = mem::take(&mut *self.0.deferred.borrow_mut());
+        for value in deferred.drain(..) {
+            self.account(|a| a.remove(Category::Deferred));
+            self.put(value);
+        }
+        // the buffer goes back with its capacity, unless more was deferred meanwhile
+        let mut slot = self.0.deferred.borrow_mut();
+        if slot.is_empty() {
+            *slot = deferred;
+        }
+    }
+
+    /// Promises that no output arrives anymore unless the computation performs
+    /// an effect for it. The outputs already put are still taken in order, the
+    /// context closes by itself once every source added to the block is done.
+    ///
+    /// ```
+    /// use aeiou::Context;
+    ///
+    /// let context = Context::empty();
+    /// context.put("last");
+    /// context.close();
+    /// assert!(context.is_closed());
+    /// assert_eq!(context.take(), Some("last"));
+    /// ```
+    pub fn close(&self) {
+        self.0.closed.set(true);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.0.closed.get()
+    }
+
+    pub(crate) fn open_source(&self) {
+        self.0.sources.set(self.0.sources.get() + 1);
+    }
+
+    pub(crate) fn source_done(&self) {
+        let sources = self.0.sources.get() - 1;
+        self.0.sources.set(sources);
+        if sources == 0 {
+            self.close();
+        }
+    }
+
+    /// A view of the context that holds only the outputs `project` accepts.
+    /// What is put into the view is embedded and put into this context, other
+    /// outputs stay here in their order.
+    ///
+    /// ```
+    /// use aeiou::Context;
+    ///
+    /// #[derive(Debug, PartialEq)]
+    /// enum Outputs {
+    ///     Conn(u32),
+    ///     ConfigChanged,
+    /// }
+    ///
+    /// let context = Context::empty();
+    /// let conn = context.narrow(Outputs::Conn, |output| match output {
+    ///     Outputs::Conn(n) => Ok(n),
+    ///     other => Err(other),
+    /// });
+    /// context.put(Outputs::ConfigChanged);
+    /// conn.put(7);
+    /// assert_eq!(conn.take(), Some(7));
+    /// assert_eq!(conn.take(), None);
+    /// assert_eq!(context.take(), Some(Outputs::ConfigChanged));
+    /// ```
+    pub fn narrow<U, M, P>(&self, embed: M, project: P) -> Context<U>
+    where
+        T: 'static,
+        M: Fn(U) -> T + 'static,
+        P: Fn(T) -> Result<U, T> + 'static,
+    {
+        let parent = self.clone();
+        let put = move |value| parent.put(embed(value));
+        let parent = self.clone();
+        let pull = move || {
+            parent.flush_deferred();
+            parent.pull();
+            let mut queue = parent.borrow_mut(&parent.0.queue, "narrow");
+            let mut narrowed = Vec::new();
+            for (seq, value) in mem::take(&mut *queue) {
+                match project(value) {
+                    Ok(value) => narrowed.push((seq, value)),
+                    Err(value) => queue.push_back((seq, value)),
+                }
+            }
+            drop(queue);
+            for _ in &narrowed {
+                parent.account(|a| a.remove(Category::Outputs));
+            }
+            narrowed
+        };
+        let parent = self.clone();
+        let taken = move |seq| parent.taken(seq);
+        Context::with_upstream(Some(Upstream {
+            put: Box::new(put),
+            pull: Box::new(pull),
+            taken: Box::new(taken),
+        }))
+    }
+
+    fn pull(&self) {
+        if let Some(upstream) = &self.0.upstream {
+            let pulled = (upstream.pull)();
+            let count = pulled.len();
+            self.borrow_mut(&self.0.queue, "take").extend(pulled);
+            for _ in 0..count {
+                self.account(|a| a.insert(Category::Outputs));
+            }
+        }
+    }
+
+    pub(crate) fn count_if<F>(&self, f: F) -> usize
+    where
+        F: FnMut(&&T) -> bool,
+    {
+        self.pull();
+        self.borrow(&self.0.queue, "len")
+            .iter()
+            .map(|(_, v)| v)
+            .filter(f)
+            .count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pull();
+        self.borrow(&self.0.queue, "len").len() + self.0.deferred.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn borrow<'a, X>(&self, cell: &'a RefCell<X>, operation: &'static str) -> Ref<'a, X> {
+        match cell.try_borrow() {
+            Ok(value) => value,
+            Err(_) => self.reentered(operation),
+        }
+    }
+
+    fn borrow_mut<'a, X>(
+        &'a self,
+        cell: &'a RefCell<X>,
+        operation: &'static str,
+    ) -> BusyMut<'a, X> {
+        match cell.try_borrow_mut() {
+            Ok(value) => BusyMut {
+                value,
+                busy: &self.0.busy,
+                previous: self.0.busy.replace(operation),
+            },
+            Err(_) => self.reentered(operation),
+        }
+    }
+
+    fn reentered(&self, operation: &'static str) -> ! {
+        panic!(
+            "`Context::{}` called while `Context::{}` is in progress, \
+             use `Context::put_deferred` from `Drop` impls and callbacks",
+            operation,
+            self.0.busy.get(),
+        )
+    }
+
+    #[cfg(feature = "diagnostics")]
+    pub(crate) fn install_accounting(&self) -> Accounting {
+        let mut slot = self.0.accounting.borrow_mut();
+        slot.get_or_insert_with(|| {
+            let accounting = Accounting::default();
+            let deferred = self.0.deferred.borrow().len();
+            accounting.set(Category::Outputs, self.len() - deferred);
+            accounting.set(Category::Deferred, deferred);
+            accounting
+        })
+        .clone()
+    }
+
+    // for what is not reached through the context, like the guards of sessions
+    #[cfg(feature = "diagnostics")]
+    pub(crate) fn accounting(&self) -> Option<Accounting> {
+        self.0.accounting.borrow().clone()
+    }
+
+    #[cfg(not(feature = "diagnostics"))]
+    pub(crate) fn accounting(&self) -> Option<Accounting> {
+        None
+    }
+
+    // without diagnostics the accounting is detached and stays empty
+    #[cfg(not(feature = "diagnostics"))]
+    pub(crate) fn install_accounting(&self) -> Accounting {
+        Accounting::default()
+    }
+
+    #[cfg(feature = "diagnostics")]
+    // every watch sees every output before it is queued
+    pub(crate) fn add_watch(&self, watch: Watch<T>) {
+        self.borrow_mut(&self.0.watch, "add_watch").push(watch);
+    }
+
+    pub(crate) fn token(&self) -> Option<IdempotencyToken> {
+        self.0.token.get()
+    }
+
+    pub(crate) fn set_token(&self, token: Option<IdempotencyToken>) {
+        self.0.token.set(token);
+    }
+
+    pub(crate) fn cancel_token(&self) -> Option<CancelToken> {
+        self.0.cancel.borrow().clone()
+    }
+
+    pub(crate) fn set_cancel_token(&self, token: Option<CancelToken>) {
+        *self.0.cancel.borrow_mut() = token;
+    }
+
+    pub(crate) fn produced(&self) -> u64 {
+        self.0.produced.get()
+    }
+
+    #[cfg(feature = "diagnostics")]
+    // the sequence number of the output put last in this block
+    pub(crate) fn sequence(&self) -> u64 {
+        self.0.sequence.borrow().get()
+    }
+
+    // a task numbers its outputs in the sequence of the block spawning it
+    pub(crate) fn share_sequence<U>(&self, parent: &Context<U>) {
+        *self.0.sequence.borrow_mut() = parent.0.sequence.borrow().clone();
+    }
+
+    fn next_sequence(&self) -> u64 {
+        let sequence = self.0.sequence.borrow();
+        sequence.set(sequence.get() + 1);
+        sequence.get()
+    }
+
+    // The sequence number of the output the computation took last, the cause
+    // of the effects it performs until it takes another one.
+    pub(crate) fn cause(&self) -> Option<u64> {
+        self.0.cause.get()
+    }
+
+    // the schedulers switch it between the generators sharing the context
+    pub(crate) fn set_cause(&self, cause: Option<u64>) {
+        self.0.before.set(None);
+        self.0.cause.set(cause);
+    }
+
+    fn taken(&self, seq: u64) {
+        self.0.before.set(self.0.cause.replace(Some(seq)));
+        if let Some(upstream) = &self.0.upstream {
+            (upstream.taken)(seq);
+        }
+    }
+
+    pub(crate) fn extension<X, F>(&self, f: F) -> Rc<X>
+    where
+        X: 'static,
+        F: FnOnce() -> X,
+    {
+        let mut extensions = self.0.extensions.borrow_mut();
+        if let Some(x) = extensions.iter().find_map(|x| x.clone().downcast().ok()) {
+            return x;
+        }
+        let x = Rc::new(f());
+        extensions.push(x.clone());
+        x
+    }
+
+    // like `extension`, but nothing is installed if it is not there
+    pub(crate) fn find_extension<X>(&self) -> Option<Rc<X>>
+    where
+        X: 'static,
+    {
+        let extensions = self.0.extensions.borrow();
+        extensions.iter().find_map(|x| x.clone().downcast().ok())
+    }
+
+    pub(crate) fn resuming(&self) -> Resuming<'_> {
+        self.0.resuming.set(self.0.resuming.get() + 1);
+        let run = self.0.run.get();
+        if let Some(run) = run {
+            reuse::enter(run);
+        }
+        Resuming(&self.0.resuming, run.is_some())
+    }
+
+    pub(crate) fn is_resuming(&self) -> bool {
+        self.0.resuming.get() > 0
+    }
+
+    // the first block made for the context stamps it, the layers share it
+    pub(crate) fn stamp_run(&self) {
+        if self.0.run.get().is_none() {
+            self.0.run.set(Some(reuse::next_run()));
+        }
+    }
+
+    pub(crate) fn run_id(&self) -> Option<u64> {
+        self.0.run.get()
+    }
+
+    // the outermost block of the run completed, the outputs nothing took are
+    // counted as left over
+    pub(crate) fn complete_run(&self) {
+        if self.0.run.get().is_none() || self.is_resuming() {
+            return;
+        }
+        *self.0.completed.borrow_mut() = Some(reuse::running());
+        for _ in 0..self.len() {
+            self.account(|a| a.bump(Counter::LeftoverOutputs));
+        }
+    }
+
+    fn check_run(&self, access: &'static str) {
+        let owner = match self.0.run.get() {
+            Some(owner) => owner,
+            None => return,
+        };
+        let user = match &*self.0.completed.borrow() {
+            Some(drivers) => match reuse::current() {
+                Some(user) if user != owner && !drivers.contains(&user) => user,
+                _ => return,
+            },
+            None => return,
+        };
+        std::panic::panic_any(ContextReuse {
+            owner,
+            user,
+            access,
+        });
+    }
+
+    #[cfg(feature = "diagnostics")]
+    pub(crate) fn account<F>(&self, f: F)
+    where
+        F: FnOnce(&Accounting),
+    {
+        if let Some(accounting) = &*self.0.accounting.borrow() {
+            f(accounting)
+        }
+    }
+
+    #[cfg(not(feature = "diagnostics"))]
+    #[inline(always)]
+    pub(crate) fn account<F>(&self, f: F)
+    where
+        F: FnOnce(&Accounting),
+    {
+        let _ = f;
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T> Context<T> {
+    pub(crate) fn set_waker(&self, waker: &std::task::Waker) {
+        *self.0.waker.borrow_mut() = Some(waker.clone());
+    }
+
+    pub(crate) fn waker(&self) -> Option<std::task::Waker> {
+        self.0.waker.borrow().clone()
+    }
+
+    pub(crate) fn park(&self) {
+        self.0.parked.set(true);
+    }
+
+    pub(crate) fn unpark(&self) -> bool {
+        self.0.parked.replace(false)
+    }
+}
+
+#[must_use = "the outputs are claimed until it drops"]
+pub struct Claim<T> {
+    context: Context<T>,
+    id: u64,
+}
+
+impl<T> Drop for Claim<T> {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.context
+            .borrow_mut(&self.context.0.claims, "claim")
+            .retain(|(claim, _)| *claim != id);
+    }
+}
+
+// the resume is over when it drops, even if the generator panics
+pub(crate) struct Resuming<'a>(&'a Cell<usize>, bool);
+
+impl Drop for Resuming<'_> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+        if self.1 {
+            reuse::leave();
+        }
     }
 }
 
@@ -24,3 +1096,230 @@ impl<Output> Clone for Context<Output> {
         Context(self.0.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        cell::RefCell,
+        panic::{self, AssertUnwindSafe},
+    };
+    use super::{Context, OrderPolicy};
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Output {
+        MouseMoved(u32, u32),
+        Key(char),
+        Data(u64, &'static str),
+    }
+
+    fn drain(context: &Context<Output>) -> Vec<Output> {
+        std::iter::from_fn(|| context.take()).collect()
+    }
+
+    #[test]
+    fn fifo_and_lifo() {
+        let context = Context::empty();
+        context.put(Output::Key('a'));
+        context.put(Output::Key('b'));
+        context.put_front(Output::Key('c'));
+        assert_eq!(
+            drain(&context),
+            vec![Output::Key('c'), Output::Key('a'), Output::Key('b')]
+        );
+
+        context.set_order(OrderPolicy::Lifo);
+        context.put(Output::Key('a'));
+        context.put(Output::Key('b'));
+        context.put_front(Output::Key('c'));
+        assert_eq!(
+            drain(&context),
+            vec![Output::Key('c'), Output::Key('b'), Output::Key('a')]
+        );
+    }
+
+    #[test]
+    fn keyed_stable_sort() {
+        let context = Context::empty();
+        context.set_order(OrderPolicy::KeyedStableSort(Box::new(|o| match o {
+            Output::Data(connection, _) => *connection,
+            _ => 0,
+        })));
+        context.put(Output::Data(2, "x"));
+        context.put(Output::Data(1, "y"));
+        context.put(Output::Data(2, "z"));
+        context.put(Output::Data(1, "w"));
+        assert_eq!(
+            context.take_if(|o| matches!(o, Output::Data(2, _))),
+            Some(Output::Data(2, "x")),
+        );
+        assert_eq!(
+            drain(&context),
+            vec![
+                Output::Data(1, "y"),
+                Output::Data(1, "w"),
+                Output::Data(2, "z")
+            ],
+        );
+    }
+
+    #[test]
+    fn keyed_put_front() {
+        let context = Context::empty();
+        context.set_order(OrderPolicy::KeyedStableSort(Box::new(|o| match o {
+            Output::Data(connection, _) => *connection,
+            _ => 0,
+        })));
+        context.put(Output::Data(1, "y"));
+        context.put(Output::Data(2, "x"));
+        let taken = context
+            .take_if(|o| matches!(o, Output::Data(2, _)))
+            .unwrap();
+        context.put_front(taken);
+        assert_eq!(context.take(), Some(Output::Data(2, "x")));
+        assert_eq!(context.take(), Some(Output::Data(1, "y")));
+    }
+
+    #[test]
+    fn coalesce_latest() {
+        let replaced = Rc::new(RefCell::new(Vec::new()));
+        let context = Context::empty();
+        context.set_order(OrderPolicy::CoalesceLatest(Box::new(|o| match o {
+            Output::MouseMoved(..) => Some(0),
+            _ => None,
+        })));
+        context.on_orphan({
+            let replaced = replaced.clone();
+            move |o| replaced.borrow_mut().push(o)
+        });
+        context.put(Output::MouseMoved(0, 0));
+        context.put(Output::Key('a'));
+        context.put(Output::MouseMoved(1, 1));
+        context.put(Output::MouseMoved(2, 2));
+        assert_eq!(context.len(), 2);
+        assert_eq!(
+            drain(&context),
+            vec![Output::MouseMoved(2, 2), Output::Key('a')]
+        );
+        assert_eq!(
+            *replaced.borrow(),
+            vec![Output::MouseMoved(0, 0), Output::MouseMoved(1, 1)],
+        );
+    }
+
+    struct Cancel(Context<Output>);
+
+    impl Drop for Cancel {
+        fn drop(&mut self) {
+            self.0.put_deferred(Output::Key('x'));
+        }
+    }
+
+    #[test]
+    fn deferred_during_panic() {
+        let context = Context::empty();
+        context.put(Output::Key('a'));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let inner = context.clone();
+            // the guard drops while the queue is borrowed by `take_if`
+            context.take_if(move |_| {
+                let _guard = Cancel(inner.clone());
+                panic!("handler failed")
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(context.len(), 2);
+        assert_eq!(drain(&context), vec![Output::Key('a'), Output::Key('x')]);
+    }
+
+    #[test]
+    #[should_panic(expected = "`Context::put` called while `Context::put` is in progress")]
+    fn reentrant_put() {
+        let context = Context::empty();
+        let inner = context.clone();
+        context.set_order(OrderPolicy::CoalesceLatest(Box::new(move |_| {
+            inner.put(Output::Key('b'));
+            None
+        })));
+        context.put(Output::Key('a'));
+    }
+
+    #[test]
+    fn drain_and_retain() {
+        let context = Context::empty();
+        context.put(Output::Data(1, "a"));
+        context.put(Output::Key('x'));
+        context.put(Output::Data(2, "b"));
+        context.put(Output::Data(1, "c"));
+        context.put_deferred(Output::Data(1, "d"));
+        assert_eq!(
+            context.drain_matching(|o| matches!(o, Output::Data(1, _))),
+            vec![
+                Output::Data(1, "a"),
+                Output::Data(1, "c"),
+                Output::Data(1, "d")
+            ],
+        );
+        let mut seen = Vec::new();
+        context.iter_with(|o| seen.push(format!("{:?}", o)));
+        assert_eq!(seen, ["Key('x')", "Data(2, \"b\")"]);
+
+        // a reconnect drops everything of the old connection
+        context.put(Output::Data(3, "e"));
+        context.put(Output::MouseMoved(0, 0));
+        context.retain(|o| !matches!(o, Output::Data(2, _) | Output::MouseMoved(..)));
+        assert_eq!(
+            drain(&context),
+            vec![Output::Key('x'), Output::Data(3, "e")]
+        );
+    }
+
+    #[test]
+    fn claimed_outputs() {
+        let context = Context::empty();
+        context.put(Output::Data(1, "a"));
+        context.put(Output::Data(2, "b"));
+        context.put(Output::Key('x'));
+        let claim = context.claim(|o| matches!(o, Output::Data(2, _)));
+        assert_eq!(
+            context.drain_matching(|o| matches!(o, Output::Data(..))),
+            vec![Output::Data(1, "a")],
+        );
+        context.retain(|_| false);
+        assert_eq!(context.len(), 1);
+        assert_eq!(
+            context.drain_including_claimed(|_| true),
+            vec![Output::Data(2, "b")]
+        );
+
+        context.put(Output::Data(2, "c"));
+        drop(claim);
+        context.retain(|_| false);
+        assert!(context.is_empty());
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "`Context::put` called while `Context::drain_matching` is in progress"
+    )]
+    fn reentrant_drain() {
+        let context = Context::empty();
+        context.put(Output::Key('a'));
+        let inner = context.clone();
+        context.drain_matching(|_| {
+            inner.put(Output::Key('b'));
+            true
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "`Context::take` called while `Context::iter_with` is in progress")]
+    fn reentrant_iter() {
+        let context = Context::empty();
+        context.put(Output::Key('a'));
+        let inner = context.clone();
+        context.iter_with(|_| {
+            inner.take();
+        });
+    }
+}
@@ -0,0 +1,542 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::{Cell, RefCell},
+    time::{Duration, Instant},
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    accounting::Counter,
+    block::Block,
+    computation::{Effect, EffectName},
+    explain::EffectDescription,
+    idempotency::Idempotent,
+    lazy::CheapClone,
+};
+
+thread_local! {
+    static CLOCK: RefCell<Option<Rc<dyn TimeSource>>> = RefCell::new(None);
+}
+
+// Where `now` takes the time from. A virtual time source is still measured
+// in `Instant`s, so deadlines and ttls work under any of them.
+pub trait TimeSource {
+    fn now(&self) -> Instant;
+
+    // a round of the `count_rounds` layer passed
+    fn tick(&self) {}
+
+    // a virtual time source moves instead of blocking the thread
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+
+    // like `sleep`, but an unpark of the thread may end it early
+    fn park(&self, duration: Duration) {
+        self.sleep(duration);
+    }
+}
+
+pub struct WallClock;
+
+impl TimeSource for WallClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn park(&self, duration: Duration) {
+        std::thread::park_timeout(duration);
+    }
+}
+
+// replaces the real clock on this thread until `uninstall`
+pub fn install<S>(source: S)
+where
+    S: TimeSource + 'static,
+{
+    CLOCK.with(|c| *c.borrow_mut() = Some(Rc::new(source)));
+}
+
+pub fn uninstall() {
+    CLOCK.with(|c| *c.borrow_mut() = None);
+}
+
+pub(crate) fn is_installed() -> bool {
+    CLOCK.with(|clock| clock.borrow().is_some())
+}
+
+pub fn now() -> Instant {
+    CLOCK
+        .with(|clock| clock.borrow().as_ref().map(|clock| clock.now()))
+        .unwrap_or_else(Instant::now)
+}
+
+fn tick() {
+    let clock = CLOCK.with(|clock| clock.borrow().clone());
+    if let Some(clock) = clock {
+        clock.tick();
+    }
+}
+
+pub fn sleep(duration: Duration) {
+    let clock = CLOCK.with(|clock| clock.borrow().clone());
+    match clock {
+        Some(clock) => clock.sleep(duration),
+        None => std::thread::sleep(duration),
+    }
+}
+
+pub fn park(duration: Duration) {
+    let clock = CLOCK.with(|clock| clock.borrow().clone());
+    match clock {
+        Some(clock) => clock.park(duration),
+        None => std::thread::park_timeout(duration),
+    }
+}
+
+#[derive(Clone)]
+pub struct TestClock(Rc<Cell<Instant>>);
+
+impl TestClock {
+    pub fn install() -> Self {
+        let clock = TestClock(Rc::new(Cell::new(Instant::now())));
+        install(clock.clone());
+        clock
+    }
+
+    pub fn uninstall() {
+        uninstall();
+    }
+
+    pub fn now(&self) -> Instant {
+        self.0.get()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.0.set(self.0.get() + by);
+    }
+}
+
+impl TimeSource for TestClock {
+    fn now(&self) -> Instant {
+        TestClock::now(self)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+// the length of a round on the `RoundCounter`, only there to convert
+pub const ROUND: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Rounds(pub u64);
+
+impl Rounds {
+    // the rounds a duration takes, a started round counts
+    pub fn of(duration: Duration) -> Self {
+        let round = ROUND.as_nanos();
+        Rounds(((duration.as_nanos() + round - 1) / round) as u64)
+    }
+}
+
+impl From<Rounds> for Duration {
+    fn from(Rounds(rounds): Rounds) -> Self {
+        Duration::from_nanos(ROUND.as_nanos() as u64 * rounds)
+    }
+}
+
+struct Counted {
+    origin: Instant,
+    rounds: Cell<u64>,
+}
+
+// Virtual time that only moves when a round passes, every run of the same
+// computation sees the same times relative to the start.
+#[derive(Clone)]
+pub struct RoundCounter(Rc<Counted>);
+
+impl RoundCounter {
+    pub fn install() -> Self {
+        let counter = RoundCounter(Rc::new(Counted {
+            origin: Instant::now(),
+            rounds: Cell::new(0),
+        }));
+        install(counter.clone());
+        counter
+    }
+
+    pub fn rounds(&self) -> Rounds {
+        Rounds(self.0.rounds.get())
+    }
+
+    // the rounds passed from `since` to now
+    pub fn since(&self, since: Instant) -> Rounds {
+        Rounds::of(TimeSource::now(self) - since)
+    }
+}
+
+impl TimeSource for RoundCounter {
+    fn now(&self) -> Instant {
+        self.0.origin + Duration::from(self.rounds())
+    }
+
+    fn tick(&self) {
+        self.0.rounds.set(self.0.rounds.get() + 1);
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let Rounds(rounds) = Rounds::of(duration);
+        self.0.rounds.set(self.0.rounds.get() + rounds);
+    }
+}
+
+pub trait HasDeadline {
+    fn deadline(&self) -> Option<Instant>;
+    fn set_deadline(&mut self, deadline: Instant);
+
+    fn expired(&self) -> bool {
+        self.deadline().map_or(false, |deadline| now() >= deadline)
+    }
+}
+
+pub trait HasDeadlineExceeded
+where
+    Self: Sized + Effect,
+{
+    fn deadline_exceeded(effect: Self::Input) -> Self;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deadlined<I> {
+    pub inner: I,
+    pub deadline: Option<Instant>,
+}
+
+impl<I> Deadlined<I> {
+    pub fn new(inner: I) -> Self {
+        Deadlined {
+            inner,
+            deadline: None,
+        }
+    }
+}
+
+impl<I> Idempotent for Deadlined<I>
+where
+    I: Idempotent,
+{
+    fn is_idempotent(&self) -> bool {
+        self.inner.is_idempotent()
+    }
+}
+
+impl<I> EffectName for Deadlined<I>
+where
+    I: EffectName,
+{
+    fn effect_name(&self) -> &'static str {
+        self.inner.effect_name()
+    }
+
+    fn describe(&self) -> EffectDescription {
+        self.inner.describe()
+    }
+}
+
+impl<I> HasDeadline for Deadlined<I> {
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+}
+
+#[macro_export]
+macro_rules! with_deadline {
+    ($e:expr, $duration:expr) => {{
+        let mut e = $e;
+        $crate::deadline::HasDeadline::set_deadline(&mut e, $crate::deadline::now() + $duration);
+        e
+    }};
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    E::Input: HasDeadline,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    pub fn enforce_deadlines(
+        self,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        E: HasDeadlineExceeded,
+    {
+        let context = self.context();
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            move || loop {
+                match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(effect) if effect.expired() => {
+                        context.account(|a| a.bump(Counter::Expired));
+                        context.put(E::deadline_exceeded(effect));
+                    },
+                    GeneratorState::Yielded(effect) => yield effect,
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+
+    // the attempts get a `CheapClone` of the effect, a `Lazy` payload is built
+    // once for all of them
+    pub fn retry<F>(
+        self,
+        attempts: usize,
+        failed: F,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        E::Input: CheapClone + Idempotent,
+        F: Fn(&E) -> bool,
+    {
+        let context = self.context();
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            move || loop {
+                let effect = match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(effect) => effect,
+                };
+                if !effect.is_idempotent() {
+                    yield effect;
+                    continue;
+                }
+                for attempt in 1.. {
+                    let before = context.produced();
+                    yield effect.cheap_clone();
+                    if context.produced() == before || attempt >= attempts || effect.expired() {
+                        break;
+                    }
+                    match context.take_last() {
+                        Some(output) if failed(&output) => context.orphan(output),
+                        Some(output) => {
+                            context.put(output);
+                            break;
+                        },
+                        None => break,
+                    }
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Every effect passing this layer is a round, the installed time source
+    // ticks once it is handled. Only `RoundCounter` moves on a tick.
+    pub fn count_rounds(
+        self,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>> {
+        let context = self.context();
+        let mut s = self;
+        let generator = move || loop {
+            match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => {
+                    yield effect;
+                    tick();
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, time::Duration};
+    use crate::{Context, Effect, Idempotent, IntoBlock, Counter, with_deadline};
+    use super::{Deadlined, HasDeadlineExceeded, TestClock, RoundCounter, Rounds, now};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Request {
+        Fetch,
+        Idle,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Fetched,
+        Failed,
+        DeadlineExceeded,
+    }
+
+    impl Effect for Outputs {
+        type Input = Deadlined<Request>;
+    }
+
+    impl Idempotent for Request {}
+
+    impl HasDeadlineExceeded for Outputs {
+        fn deadline_exceeded(_: Deadlined<Request>) -> Self {
+            Outputs::DeadlineExceeded
+        }
+    }
+
+    #[test]
+    fn deferred_past_deadline() {
+        let clock = TestClock::install();
+        let calls = Rc::new(RefCell::new(0));
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let computation = {
+            let seen = seen.clone();
+            move |context: Context<Outputs>| {
+                move || {
+                    let fetch =
+                        with_deadline!(Deadlined::new(Request::Fetch), Duration::from_millis(200));
+                    yield Deadlined::new(Request::Idle);
+                    context.take();
+                    yield fetch;
+                    seen.borrow_mut().extend(context.take());
+                }
+            }
+        };
+        let (block, accounting) = computation
+            .into_block()
+            .enforce_deadlines()
+            .with_accounting();
+        block
+            .add_handler({
+                let calls = calls.clone();
+                move |effect: Deadlined<Request>| match effect.inner {
+                    Request::Idle => {
+                        clock.advance(Duration::from_millis(300));
+                        Ok(Outputs::Fetched)
+                    },
+                    Request::Fetch => {
+                        *calls.borrow_mut() += 1;
+                        Ok(Outputs::Fetched)
+                    },
+                }
+            })
+            .assert_handled()
+            .run();
+        assert_eq!(*calls.borrow(), 0);
+        assert_eq!(*seen.borrow(), vec![Outputs::DeadlineExceeded]);
+        if cfg!(feature = "diagnostics") {
+            assert_eq!(accounting.report().count(Counter::Expired), 1);
+        }
+    }
+
+    #[test]
+    fn retry_until_deadline() {
+        let clock = TestClock::install();
+        let start = clock.now();
+        let attempts = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let computation = {
+            let seen = seen.clone();
+            move |context: Context<Outputs>| {
+                move || {
+                    yield with_deadline!(
+                        Deadlined::new(Request::Fetch),
+                        Duration::from_millis(200)
+                    );
+                    seen.borrow_mut().extend(context.take());
+                }
+            }
+        };
+        computation
+            .into_block()
+            .retry(10, |output| *output == Outputs::Failed)
+            .add_handler({
+                let attempts = attempts.clone();
+                move |_| {
+                    attempts.borrow_mut().push(clock.now() - start);
+                    clock.advance(Duration::from_millis(50));
+                    Ok(Outputs::Failed)
+                }
+            })
+            .assert_handled()
+            .run();
+        let ms = Duration::from_millis;
+        assert_eq!(*attempts.borrow(), vec![ms(0), ms(50), ms(100), ms(150)]);
+        assert_eq!(*seen.borrow(), vec![Outputs::Failed]);
+    }
+
+    // the attempts in rounds from the start and what the computation saw,
+    // `advance` runs after every attempt
+    fn timeout_and_retry<A>(advance: A) -> (Vec<Rounds>, Vec<Outputs>)
+    where
+        A: Fn() + 'static,
+    {
+        let start = now();
+        let attempts = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let computation = {
+            let seen = seen.clone();
+            move |context: Context<Outputs>| {
+                move || {
+                    yield with_deadline!(Deadlined::new(Request::Fetch), Duration::from(Rounds(3)));
+                    seen.borrow_mut().extend(context.take());
+                }
+            }
+        };
+        computation
+            .into_block()
+            .retry(10, |output| *output == Outputs::Failed)
+            .count_rounds()
+            .add_handler({
+                let attempts = attempts.clone();
+                move |_| {
+                    attempts.borrow_mut().push(Rounds::of(now() - start));
+                    advance();
+                    Ok(Outputs::Failed)
+                }
+            })
+            .assert_handled()
+            .run();
+        let attempts = attempts.borrow().clone();
+        let seen = seen.borrow_mut().drain(..).collect();
+        (attempts, seen)
+    }
+
+    #[test]
+    fn rounds_like_test_clock() {
+        RoundCounter::install();
+        let in_rounds = timeout_and_retry(|| ());
+        let clock = TestClock::install();
+        let on_clock = timeout_and_retry(move || clock.advance(Duration::from(Rounds(1))));
+        TestClock::uninstall();
+        assert_eq!(in_rounds.0, [Rounds(0), Rounds(1), Rounds(2)]);
+        assert_eq!(in_rounds, on_clock);
+    }
+
+    #[test]
+    fn rounds_reproducible() {
+        let runs = (0..100)
+            .map(|_| {
+                let counter = RoundCounter::install();
+                let run = timeout_and_retry(|| ());
+                (run, counter.rounds())
+            })
+            .collect::<Vec<_>>();
+        super::uninstall();
+        assert!(runs.iter().all(|run| *run == runs[0]));
+        assert_eq!(runs[0].1, Rounds(3));
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{any, fmt};
+use super::sealed::Sealed;
+
+// Formats with `Debug` when the type has it and names the type otherwise, the
+// impl for the reference is only picked when the one for the value does not apply.
+pub struct Describe<'a, T>(pub &'a T);
+
+impl<T> Sealed for Describe<'_, T> {}
+
+impl<T> Sealed for &Describe<'_, T> {}
+
+pub trait ViaDebug
+where
+    Self: Sealed,
+{
+    fn describe(&self) -> String;
+}
+
+impl<T> ViaDebug for Describe<'_, T>
+where
+    T: fmt::Debug,
+{
+    fn describe(&self) -> String {
+        format!("{:?}", self.0)
+    }
+}
+
+pub trait ViaTypeName
+where
+    Self: Sealed,
+{
+    fn describe(&self) -> String;
+}
+
+impl<T> ViaTypeName for &Describe<'_, T> {
+    fn describe(&self) -> String {
+        format!("a value of `{}`", any::type_name::<T>())
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! describe {
+    ($value:expr) => {{
+        #[allow(unused_imports)]
+        use $crate::describe::{ViaDebug as _, ViaTypeName as _};
+        (&$crate::describe::Describe(&$value)).describe()
+    }};
+}
+
+// The tail of `perform!`, the effect is only used for the panic message.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! take_part {
+    ($result:expr, $e:expr) => {
+        match $result {
+            $crate::TakeResult::Matched(part) => part,
+            $crate::TakeResult::Empty => {
+                panic!("no output in the context after `{}`", stringify!($e))
+            },
+            $crate::TakeResult::Mismatched(output) => panic!(
+                "unexpected output after `{}`: {}",
+                stringify!($e),
+                $crate::describe!(output)
+            ),
+        }
+    };
+}
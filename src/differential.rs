@@ -0,0 +1,398 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    computation::{Effect, EffectName, Handler},
+    context::Context,
+    fingerprint::Trace,
+};
+
+// the effects of both runs from the first one that differs
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct TailDiff<I> {
+    pub step: usize,
+    pub original: Vec<I>,
+    pub explored: Vec<I>,
+}
+
+impl<I> TailDiff<I>
+where
+    I: PartialEq,
+{
+    pub fn between(original: &[I], explored: &[I], from: usize) -> Option<Self>
+    where
+        I: Clone,
+    {
+        let original = original.get(from..).unwrap_or_default();
+        let explored = explored.get(from..).unwrap_or_default();
+        if original == explored {
+            return None;
+        }
+        let common = original
+            .iter()
+            .zip(explored)
+            .take_while(|(a, b)| a == b)
+            .count();
+        Some(TailDiff {
+            step: from + common,
+            original: original[common..].to_vec(),
+            explored: explored[common..].to_vec(),
+        })
+    }
+}
+
+// an effect of one run and the response to it after the normalizers, `None`
+// when the handler gave it back, which ends the run
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Step<I, E> {
+    pub effect: I,
+    pub response: Option<E>,
+}
+
+// how often both handlers answered an effect of the variant the same, only
+// the steps where both runs performed the same effect count
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct VariantStats {
+    pub equal: usize,
+    pub different: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct ComparisonReport<I, E> {
+    pub old: Vec<Step<I, E>>,
+    pub new: Vec<Step<I, E>>,
+    // the first step that differs in the effect or the response
+    pub first_divergence: Option<usize>,
+    pub variants: BTreeMap<&'static str, VariantStats>,
+    // the effects only one of the runs performed, `original` is the old one
+    pub tail: Option<TailDiff<I>>,
+}
+
+impl<I, E> ComparisonReport<I, E>
+where
+    I: PartialEq + Clone + EffectName,
+    E: PartialEq,
+{
+    fn new(old: Vec<Step<I, E>>, new: Vec<Step<I, E>>) -> Self {
+        let first_divergence = (0..old.len().max(new.len())).find(|i| old.get(*i) != new.get(*i));
+        let mut variants = BTreeMap::<_, VariantStats>::new();
+        for (a, b) in old.iter().zip(&new) {
+            if a.effect != b.effect {
+                break;
+            }
+            let stats = variants.entry(a.effect.effect_name()).or_default();
+            if a.response == b.response {
+                stats.equal += 1;
+            } else {
+                stats.different += 1;
+            }
+        }
+        let effects =
+            |steps: &[Step<I, E>]| steps.iter().map(|s| s.effect.clone()).collect::<Vec<_>>();
+        let tail = TailDiff::between(&effects(&old), &effects(&new), 0);
+        ComparisonReport {
+            old,
+            new,
+            first_divergence,
+            variants,
+            tail,
+        }
+    }
+
+    pub fn is_equivalent(&self) -> bool {
+        self.first_divergence.is_none()
+    }
+}
+
+impl<I, E> ComparisonReport<I, E>
+where
+    I: PartialEq + Clone + EffectName + fmt::Debug,
+    E: PartialEq + fmt::Debug,
+{
+    pub fn assert_equivalent(&self) {
+        let step = match self.first_divergence {
+            Some(step) => step,
+            None => return,
+        };
+        let describe = |steps: &[Step<I, E>]| match steps.get(step) {
+            None => "performed nothing".to_string(),
+            Some(Step {
+                effect,
+                response: None,
+            }) => format!("{:?} was unhandled", effect),
+            Some(Step {
+                effect,
+                response: Some(response),
+            }) => format!("{:?} was answered {:?}", effect, response),
+        };
+        panic!(
+            "the handlers diverge at step {}, old: {}, new: {}",
+            step,
+            describe(&self.old),
+            describe(&self.new),
+        );
+    }
+}
+
+type Normalizer<E> = fn(&mut E);
+
+// Runs the computation once with each handler, every run has its own block and
+// a fresh handler, so a different answer can change what is performed next.
+// A run stops after as many effects as the recorded workload has.
+pub struct Differential<E, F> {
+    constructor: F,
+    normalizers: Vec<Normalizer<E>>,
+}
+
+impl<E, F, G> Differential<E, F>
+where
+    E: Effect + Clone + PartialEq,
+    E::Input: Clone + PartialEq + EffectName,
+    F: Fn(Context<E>) -> G,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    pub fn new(constructor: F) -> Self {
+        Differential {
+            constructor,
+            normalizers: Vec::new(),
+        }
+    }
+
+    // applied to both responses before they are compared, the computation
+    // still gets the response as it is
+    pub fn normalize(mut self, normalizer: Normalizer<E>) -> Self {
+        self.normalizers.push(normalizer);
+        self
+    }
+
+    pub fn compare<O, N>(
+        &self,
+        workload: Trace<E::Input>,
+        old: O,
+        new: N,
+    ) -> ComparisonReport<E::Input, E>
+    where
+        O: Handler<E>,
+        N: Handler<E>,
+    {
+        let budget = workload.events().len();
+        ComparisonReport::new(self.drive(budget, old), self.drive(budget, new))
+    }
+
+    fn drive<H>(&self, budget: usize, handler: H) -> Vec<Step<E::Input, E>>
+    where
+        H: Handler<E>,
+    {
+        let context = Context::empty();
+        let mut block = Block::new(context.clone(), (self.constructor)(context));
+        let mut handler = handler;
+        handler.init();
+        let mut steps = Vec::new();
+        while steps.len() < budget {
+            let effect = match block.resume() {
+                GeneratorState::Complete(_) => break,
+                GeneratorState::Yielded(effect) => effect,
+            };
+            match handler.handle(effect.clone()) {
+                Ok(response) => {
+                    let mut normalized = response.clone();
+                    self.normalizers.iter().for_each(|f| f(&mut normalized));
+                    steps.push(Step {
+                        effect,
+                        response: Some(normalized),
+                    });
+                    block.put(response);
+                },
+                Err(effect) => {
+                    steps.push(Step {
+                        effect,
+                        response: None,
+                    });
+                    break;
+                },
+            }
+        }
+        handler.finish();
+        steps
+    }
+}
+
+pub fn compare<E, F, G, O, N>(
+    constructor: F,
+    workload: Trace<E::Input>,
+    old: O,
+    new: N,
+) -> ComparisonReport<E::Input, E>
+where
+    E: Effect + Clone + PartialEq,
+    E::Input: Clone + PartialEq + EffectName,
+    F: Fn(Context<E>) -> G,
+    G: Unpin + Generator<(), Yield = E::Input>,
+    O: Handler<E>,
+    N: Handler<E>,
+{
+    Differential::new(constructor).compare(workload, old, new)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Generator;
+    use crate::{Context, Effect, EffectName, Handler, IntoBlock, Trace, perform};
+    use super::{Differential, Step, VariantStats, compare};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Count,
+        Stamp,
+    }
+
+    impl EffectName for Effects {
+        fn effect_name(&self) -> &'static str {
+            match self {
+                Effects::Count => "Count",
+                Effects::Stamp => "Stamp",
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Outputs {
+        Counted(u32),
+        Stamped { count: u32, at: u64 },
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    // counts up to five, then stamps the count
+    fn computation(
+        context: Context<Outputs>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || loop {
+            perform!(Effects::Count);
+            if let Some(Outputs::Counted(n)) = context.take() {
+                if n >= 5 {
+                    perform!(Effects::Stamp);
+                    context.take();
+                    break;
+                }
+            }
+        }
+    }
+
+    // the new implementation counts in steps of `step` after `from` calls
+    struct Counter {
+        count: u32,
+        step: u32,
+        from: u32,
+        at: u64,
+    }
+
+    fn counter(step: u32, from: u32, at: u64) -> Counter {
+        Counter {
+            count: 0,
+            step,
+            from,
+            at,
+        }
+    }
+
+    impl Handler<Outputs> for Counter {
+        fn handle(&mut self, effect: Effects) -> Result<Outputs, Effects> {
+            match effect {
+                Effects::Count => {
+                    self.count += if self.count >= self.from {
+                        self.step
+                    } else {
+                        1
+                    };
+                    Ok(Outputs::Counted(self.count))
+                },
+                Effects::Stamp => Ok(Outputs::Stamped {
+                    count: self.count,
+                    at: self.at,
+                }),
+            }
+        }
+    }
+
+    fn workload() -> Trace<Effects> {
+        let (block, trace) = computation.into_block().traced();
+        block.add_handler(counter(1, 0, 0)).assert_handled().run();
+        trace
+    }
+
+    #[test]
+    fn identical() {
+        let report = compare(computation, workload(), counter(1, 0, 7), counter(1, 0, 7));
+        report.assert_equivalent();
+        assert_eq!(report.old.len(), 6);
+        assert_eq!(
+            report.variants["Count"],
+            VariantStats {
+                equal: 5,
+                different: 0
+            },
+        );
+        assert_eq!(report.tail, None);
+    }
+
+    #[test]
+    fn divergence_localized() {
+        let report = compare(computation, workload(), counter(1, 0, 0), counter(2, 2, 0));
+        // the third count is answered 4 instead of 3, the new run stamps after 4 counts
+        assert_eq!(report.first_divergence, Some(2));
+        assert_eq!(
+            report.variants["Count"],
+            VariantStats {
+                equal: 2,
+                different: 2
+            },
+        );
+        let tail = report.tail.clone().unwrap();
+        assert_eq!(tail.step, 4);
+        assert_eq!(tail.original, [Effects::Count, Effects::Stamp]);
+        assert_eq!(tail.explored, [Effects::Stamp]);
+        assert_eq!(
+            report.new[2],
+            Step {
+                effect: Effects::Count,
+                response: Some(Outputs::Counted(4)),
+            },
+        );
+        let message = std::panic::catch_unwind(|| report.assert_equivalent())
+            .unwrap_err()
+            .downcast::<String>()
+            .unwrap();
+        assert_eq!(
+            *message,
+            "the handlers diverge at step 2, old: Count was answered Counted(3), new: Count was answered Counted(4)",
+        );
+    }
+
+    #[test]
+    fn normalized() {
+        let differential = Differential::new(computation);
+        let report = differential.compare(workload(), counter(1, 0, 100), counter(1, 0, 200));
+        assert_eq!(report.first_divergence, Some(5));
+        let report = differential
+            .normalize(|output| {
+                if let Outputs::Stamped { at, .. } = output {
+                    *at = 0;
+                }
+            })
+            .compare(workload(), counter(1, 0, 100), counter(1, 0, 200));
+        report.assert_equivalent();
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// Declares a minimal effect and output pair so a doc example fits in a few
+// lines. Every part in `parts` must name a single field output variant.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! simple_effects {
+    (
+        $effects:ident { $($e:ident $(($($et:ty),*))?),* $(,)? }
+        $outputs:ident { $($o:ident $(($($ot:ty),*))?),* $(,)? }
+        $(parts { $($pv:ident($pt:ty) => $part:ident),* $(,)? })?
+    ) => {
+        #[derive(Debug, Clone, PartialEq)]
+        #[allow(dead_code)]
+        enum $effects {
+            $($e $(($($et),*))?),*
+        }
+
+        #[derive(Debug, Clone, PartialEq)]
+        #[allow(dead_code)]
+        enum $outputs {
+            $($o $(($($ot),*))?),*
+        }
+
+        impl $crate::Effect for $outputs {
+            type Input = $effects;
+        }
+
+        $($(
+        #[derive(Debug, PartialEq)]
+        struct $part($pt);
+
+        impl $crate::Select<$part> for $outputs {
+            fn try_take(output: &$crate::Context<Self>) -> $crate::TakeResult<$part, Self> {
+                match output.take() {
+                    None => $crate::TakeResult::Empty,
+                    Some($outputs::$pv(v)) => $crate::TakeResult::Matched($part(v)),
+                    #[allow(unreachable_patterns)]
+                    Some(other) => $crate::TakeResult::Mismatched(other),
+                }
+            }
+        }
+        )*)?
+    };
+}
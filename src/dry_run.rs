@@ -0,0 +1,314 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    fmt::{self, Write},
+    ops::{Generator, GeneratorState},
+};
+use super::{block::Block, computation::Effect};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EffectClass {
+    ReadOnly,
+    Mutating,
+}
+
+impl fmt::Display for EffectClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EffectClass::ReadOnly => write!(f, "read-only"),
+            EffectClass::Mutating => write!(f, "mutating"),
+        }
+    }
+}
+
+// `#[derive(Classify)]` makes the variants marked `#[mutating]` mutating and
+// the rest read-only
+pub trait Classify {
+    fn effect_class(&self) -> EffectClass;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DryRunEntry {
+    pub class: EffectClass,
+    // formatted when it was yielded, a `Lazy` payload is not built for it
+    pub effect: String,
+    pub scope: String,
+    // see `causality`
+    pub cause: Option<u64>,
+}
+
+// The effects a dry run saw in the order they were performed, the read-only
+// ones reached the handlers, the mutating ones were suppressed.
+#[derive(Clone, Default)]
+pub struct DryRunReport(Rc<RefCell<Vec<DryRunEntry>>>);
+
+impl DryRunReport {
+    pub fn entries(&self) -> Vec<DryRunEntry> {
+        self.0.borrow().clone()
+    }
+
+    // what a real run would have done
+    pub fn suppressed(&self) -> Vec<DryRunEntry> {
+        self.0
+            .borrow()
+            .iter()
+            .filter(|entry| entry.class == EffectClass::Mutating)
+            .cloned()
+            .collect()
+    }
+
+    // the mutating effects first, each class in the order of the run
+    pub fn to_text(&self) -> String {
+        let entries = self.0.borrow();
+        let mut text = String::new();
+        for (class, verb) in [
+            (EffectClass::Mutating, "suppressed"),
+            (EffectClass::ReadOnly, "performed"),
+        ] {
+            let class_entries = entries.iter().filter(|entry| entry.class == class);
+            let count = class_entries.clone().count();
+            if count == 0 {
+                continue;
+            }
+            let _ = writeln!(text, "{}, {} {}:", class, count, verb);
+            for entry in class_entries {
+                let _ = write!(text, "  {}", entry.effect);
+                if !entry.scope.is_empty() {
+                    let _ = write!(text, " in {}", entry.scope);
+                }
+                if let Some(cause) = entry.cause {
+                    let _ = write!(text, " after #{}", cause);
+                }
+                text.push('\n');
+            }
+        }
+        text
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    E::Input: fmt::Debug,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // A mutating effect never reaches the handlers, the output `synthesize`
+    // gives for it is put instead, as if a handler succeeded. The read-only
+    // ones are yielded as they are. Both are recorded in the report.
+    pub fn dry_run<C, S>(
+        self,
+        classify: C,
+        synthesize: S,
+    ) -> (
+        Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>,
+        DryRunReport,
+    )
+    where
+        C: Fn(&E::Input) -> EffectClass,
+        S: Fn(&E::Input) -> E,
+    {
+        let report = DryRunReport::default();
+        let context = self.context();
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            let report = report.clone();
+            move || loop {
+                let effect = match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(effect) => effect,
+                };
+                let class = classify(&effect);
+                report.0.borrow_mut().push(DryRunEntry {
+                    class,
+                    effect: format!("{:?}", effect),
+                    scope: context.scope_path(),
+                    cause: context.cause(),
+                });
+                match class {
+                    EffectClass::ReadOnly => yield effect,
+                    EffectClass::Mutating => context.put(synthesize(&effect)),
+                }
+            }
+        };
+        (Block::new(context, generator), report)
+    }
+
+    // classified by `Classify`, usually derived
+    pub fn dry_run_classified<S>(
+        self,
+        synthesize: S,
+    ) -> (
+        Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>,
+        DryRunReport,
+    )
+    where
+        E::Input: Classify,
+        S: Fn(&E::Input) -> E,
+    {
+        self.dry_run(<E::Input as Classify>::effect_class, synthesize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{Context, Effect, IntoBlock, Lazy, perform};
+    use super::{Classify, EffectClass};
+
+    #[derive(Debug, Clone)]
+    enum Effects {
+        List,
+        Delete(u32),
+        Upload(Lazy<Vec<u8>>),
+        Notify(String),
+    }
+
+    impl Classify for Effects {
+        fn effect_class(&self) -> EffectClass {
+            match self {
+                Effects::List => EffectClass::ReadOnly,
+                _ => EffectClass::Mutating,
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Listed(Vec<u32>),
+        Done,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    // deletes the odd entries and uploads a summary
+    fn cleanup(
+        context: Context<Outputs>,
+        summary: Lazy<Vec<u8>>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            let _scope = context.enter_scope("cleanup");
+            perform!(Effects::List);
+            let listed = match context.take() {
+                Some(Outputs::Listed(listed)) => listed,
+                other => panic!("{:?}", other),
+            };
+            let mut deleted = 0;
+            for id in listed.into_iter().filter(|id| id % 2 == 1) {
+                perform!(Effects::Delete(id));
+                assert_eq!(context.take(), Some(Outputs::Done));
+                deleted += 1;
+            }
+            perform!(Effects::Upload(summary.clone()));
+            assert_eq!(context.take(), Some(Outputs::Done));
+            perform!(Effects::Notify(format!("deleted {}", deleted)));
+            assert_eq!(context.take(), Some(Outputs::Done));
+        }
+    }
+
+    fn read(effect: Effects) -> Result<Outputs, Effects> {
+        match effect {
+            Effects::List => Ok(Outputs::Listed(vec![1, 2, 3, 4])),
+            effect => Err(effect),
+        }
+    }
+
+    #[test]
+    fn mutations_suppressed() {
+        let summary = Lazy::new(|| b"summary".to_vec());
+        let handled = Rc::new(RefCell::new(Vec::new()));
+        let (block, report) = {
+            let summary = summary.clone();
+            move |context: Context<Outputs>| cleanup(context, summary)
+        }
+        .into_block()
+        .dry_run(Classify::effect_class, |_| Outputs::Done);
+        block
+            .add_handler({
+                let handled = handled.clone();
+                move |effect: Effects| {
+                    handled.borrow_mut().push(format!("{:?}", effect));
+                    read(effect)
+                }
+            })
+            .assert_handled()
+            .run();
+
+        assert_eq!(*handled.borrow(), ["List"]);
+        assert!(!summary.is_forced());
+        let suppressed = report.suppressed();
+        let effects = suppressed
+            .iter()
+            .map(|e| e.effect.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            effects,
+            [
+                "Delete(1)",
+                "Delete(3)",
+                "Upload(Lazy(\"unforced\"))",
+                "Notify(\"deleted 2\")",
+            ]
+        );
+        // the listing is the first output, each delete is answered by one
+        let causes = suppressed.iter().map(|e| e.cause).collect::<Vec<_>>();
+        assert_eq!(causes, [Some(1), Some(2), Some(3), Some(4)]);
+        if cfg!(feature = "diagnostics") {
+            assert!(suppressed.iter().all(|e| e.scope == "cleanup"));
+        }
+
+        let text = report.to_text();
+        let scope = if cfg!(feature = "diagnostics") { " in cleanup" } else { "" };
+        assert!(text.starts_with(&format!(
+            "mutating, 4 suppressed:\n  Delete(1){} after #1\n",
+            scope
+        )));
+        assert!(text.ends_with(&format!("read-only, 1 performed:\n  List{}\n", scope)));
+    }
+
+    #[test]
+    fn real_run_matches_report() {
+        let (block, report) =
+            (|context: Context<Outputs>| cleanup(context, Lazy::new(|| b"summary".to_vec())))
+                .into_block()
+                .dry_run(Classify::effect_class, |_| Outputs::Done);
+        block.add_handler(read).assert_handled().run();
+
+        let summary = Lazy::new(|| b"summary".to_vec());
+        let recorded = Rc::new(RefCell::new(Vec::new()));
+        (|context: Context<Outputs>| cleanup(context, summary.clone()))
+            .into_block()
+            .add_handler({
+                let recorded = recorded.clone();
+                move |effect: Effects| {
+                    if effect.effect_class() == EffectClass::Mutating {
+                        recorded.borrow_mut().push(format!("{:?}", effect));
+                    }
+                    match effect {
+                        Effects::Upload(mut payload) => {
+                            assert_eq!(payload.force(), b"summary");
+                            Ok(Outputs::Done)
+                        },
+                        Effects::Delete(_) | Effects::Notify(_) => Ok(Outputs::Done),
+                        effect => read(effect),
+                    }
+                }
+            })
+            .assert_handled()
+            .run();
+
+        assert!(summary.is_forced());
+        let predicted = report
+            .suppressed()
+            .into_iter()
+            .map(|e| e.effect)
+            .collect::<Vec<_>>();
+        assert_eq!(*recorded.borrow(), predicted);
+    }
+}
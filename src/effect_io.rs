@@ -0,0 +1,565 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::io::{self, Read, Write};
+use super::{computation::Effect, context::Context};
+
+// the effects of a stream, like the ones of a socket or a file
+pub trait IoEffects {
+    type Key: Clone;
+
+    fn read(key: Self::Key, max: usize) -> Self;
+    fn write(key: Self::Key, data: Vec<u8>) -> Self;
+}
+
+// the responses to `IoEffects`, another output is given back
+pub trait IoOutputs
+where
+    Self: Sized,
+{
+    // empty at the end of the stream
+    fn read_result(self) -> Result<io::Result<Vec<u8>>, Self>;
+    // the bytes written from the front of the data
+    fn write_result(self) -> Result<io::Result<usize>, Self>;
+}
+
+fn mismatched(what: &str) -> io::Error {
+    let message = format!("the response is not the one to a {}", what);
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// `io::Read` over effects for code written against it. The computation fills
+// the reader with `fill_reader!`, in between the reader serves the code from
+// its buffer and gives `WouldBlock` when there is nothing to serve.
+pub struct EffectReader<K> {
+    key: K,
+    buffer: Vec<u8>,
+    position: usize,
+    eof: bool,
+    error: Option<io::Error>,
+}
+
+impl<K> EffectReader<K>
+where
+    K: Clone,
+{
+    pub fn new(key: K) -> Self {
+        EffectReader {
+            key,
+            buffer: Vec::new(),
+            position: 0,
+            eof: false,
+            error: None,
+        }
+    }
+
+    // the bytes filled and not read yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.position
+    }
+
+    // the stream ended or failed, `fill_reader!` performs nothing more
+    pub fn is_done(&self) -> bool {
+        self.eof
+    }
+
+    /// Runs a parser that is not resumable, on `WouldBlock` it is rewound to
+    /// where it started, so it runs again from there after the next fill.
+    ///
+    /// ```
+    /// #![feature(generators)]
+    /// use std::io::{self, Read};
+    /// use aeiou::{Context, Effect, IntoBlock, fill_reader};
+    /// use aeiou::effect_io::{EffectReader, IoEffects, IoOutputs};
+    ///
+    /// #[derive(Debug)]
+    /// enum Effects {
+    ///     Read(usize),
+    ///     Write(Vec<u8>),
+    /// }
+    ///
+    /// impl IoEffects for Effects {
+    ///     type Key = ();
+    ///
+    ///     fn read((): (), max: usize) -> Self {
+    ///         Effects::Read(max)
+    ///     }
+    ///
+    ///     fn write((): (), data: Vec<u8>) -> Self {
+    ///         Effects::Write(data)
+    ///     }
+    /// }
+    ///
+    /// struct Chunk(Vec<u8>);
+    ///
+    /// impl Effect for Chunk {
+    ///     type Input = Effects;
+    /// }
+    ///
+    /// impl IoOutputs for Chunk {
+    ///     fn read_result(self) -> Result<io::Result<Vec<u8>>, Self> {
+    ///         Ok(Ok(self.0))
+    ///     }
+    ///
+    ///     fn write_result(self) -> Result<io::Result<usize>, Self> {
+    ///         Err(self)
+    ///     }
+    /// }
+    ///
+    /// // reads the whole stream like `serde_json::from_reader` does
+    /// fn parse<R: Read>(reader: &mut R) -> io::Result<String> {
+    ///     let mut text = String::new();
+    ///     reader.read_to_string(&mut text)?;
+    ///     Ok(text.trim().to_string())
+    /// }
+    ///
+    /// let mut chunks = vec![&b" 42"[..], b"\n", b""].into_iter();
+    /// let text = (|context: Context<Chunk>| {
+    ///     move || {
+    ///         let mut reader = EffectReader::new(());
+    ///         loop {
+    ///             match reader.attempt(parse) {
+    ///                 Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+    ///                     fill_reader!(reader, &context, 16);
+    ///                 },
+    ///                 result => break result,
+    ///             }
+    ///         }
+    ///     }
+    /// })
+    /// .into_block()
+    /// .add_handler(|_: Effects| Ok(Chunk(chunks.next().unwrap().to_vec())))
+    /// .assert_handled()
+    /// .run();
+    /// assert_eq!(text.unwrap(), "42");
+    /// ```
+    pub fn attempt<T, F>(&mut self, parse: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut Self) -> io::Result<T>,
+    {
+        let start = self.position;
+        let result = parse(self);
+        match &result {
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => self.position = start,
+            _ => {
+                self.buffer.drain(..self.position);
+                self.position = 0;
+            },
+        }
+        result
+    }
+}
+
+impl<K> Read for EffectReader<K> {
+    // The buffered bytes come first, then the error of the stream once, then
+    // the end of it. `WouldBlock` when the stream goes on and nothing is filled.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = &self.buffer[self.position..];
+        if !available.is_empty() {
+            let length = available.len().min(buf.len());
+            buf[..length].copy_from_slice(&available[..length]);
+            self.position += length;
+            return Ok(length);
+        }
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        if self.eof {
+            Ok(0)
+        } else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn fill_request<E, K>(
+    context: &Context<E>,
+    reader: &EffectReader<K>,
+    max: usize,
+) -> Option<E::Input>
+where
+    E: Effect,
+    E::Input: IoEffects<Key = K>,
+    K: Clone,
+{
+    let _ = context;
+    if reader.eof {
+        None
+    } else {
+        Some(E::Input::read(reader.key.clone(), max))
+    }
+}
+
+#[doc(hidden)]
+pub fn filled<E, K>(context: &Context<E>, reader: &mut EffectReader<K>) -> usize
+where
+    E: IoOutputs,
+{
+    let result = match context.take().map(E::read_result) {
+        Some(Ok(result)) => result,
+        Some(Err(other)) => {
+            context.put_front(other);
+            Err(mismatched("read"))
+        },
+        None => Err(mismatched("read")),
+    };
+    match result {
+        Ok(data) if data.is_empty() => {
+            reader.eof = true;
+            0
+        },
+        Ok(data) => {
+            reader.buffer.extend_from_slice(&data);
+            data.len()
+        },
+        Err(error) => {
+            reader.eof = true;
+            reader.error = Some(error);
+            0
+        },
+    }
+}
+
+// Performs one read of at most `max` bytes into the reader, the value is the
+// number of bytes filled, zero at the end of the stream or on its error.
+#[macro_export]
+macro_rules! fill_reader {
+    ($reader:expr, $ctx:expr, $max:expr) => {{
+        match $crate::effect_io::fill_request($ctx, &$reader, $max) {
+            None => 0,
+            Some(effect) => {
+                yield effect;
+                $crate::effect_io::filled($ctx, &mut $reader)
+            },
+        }
+    }};
+}
+
+// `io::Write` over effects, `flush_writer!` performs the writes of what the
+// code wrote into the buffer.
+pub struct EffectWriter<K> {
+    key: K,
+    buffer: Vec<u8>,
+    capacity: usize,
+    error: Option<io::Error>,
+}
+
+impl<K> EffectWriter<K>
+where
+    K: Clone,
+{
+    // takes up to `capacity` bytes between the flushes
+    pub fn new(key: K, capacity: usize) -> Self {
+        EffectWriter {
+            key,
+            buffer: Vec::with_capacity(capacity),
+            capacity,
+            error: None,
+        }
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+impl<K> Write for EffectWriter<K> {
+    // `WouldBlock` when the buffer is full, `flush_writer!` empties it
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        let length = (self.capacity - self.buffer.len()).min(buf.len());
+        if length == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.buffer.extend_from_slice(&buf[..length]);
+        Ok(length)
+    }
+
+    // only `flush_writer!` performs the writes, here the buffer must be empty
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn flush_request<E, K>(context: &Context<E>, writer: &EffectWriter<K>) -> Option<E::Input>
+where
+    E: Effect,
+    E::Input: IoEffects<Key = K>,
+    K: Clone,
+{
+    let _ = context;
+    if writer.buffer.is_empty() || writer.error.is_some() {
+        None
+    } else {
+        Some(E::Input::write(writer.key.clone(), writer.buffer.clone()))
+    }
+}
+
+#[doc(hidden)]
+pub fn written<E, K>(context: &Context<E>, writer: &mut EffectWriter<K>)
+where
+    E: IoOutputs,
+{
+    let result = match context.take().map(E::write_result) {
+        Some(Ok(result)) => result,
+        Some(Err(other)) => {
+            context.put_front(other);
+            Err(mismatched("write"))
+        },
+        None => Err(mismatched("write")),
+    };
+    match result {
+        Ok(0) => writer.error = Some(io::ErrorKind::WriteZero.into()),
+        Ok(length) => {
+            writer.buffer.drain(..length.min(writer.buffer.len()));
+        },
+        Err(error) => writer.error = Some(error),
+    }
+}
+
+#[doc(hidden)]
+pub fn flushed<K>(writer: &mut EffectWriter<K>) -> io::Result<()> {
+    match writer.error.take() {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+// Performs writes until the writer is empty, a short write is followed by
+// another one with the rest. The value is the error of the stream, if any.
+#[macro_export]
+macro_rules! flush_writer {
+    ($writer:expr, $ctx:expr) => {{
+        loop {
+            match $crate::effect_io::flush_request($ctx, &$writer) {
+                None => break $crate::effect_io::flushed(&mut $writer),
+                Some(effect) => {
+                    yield effect;
+                    $crate::effect_io::written($ctx, &mut $writer);
+                },
+            }
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        cell::RefCell,
+        collections::VecDeque,
+        io::{self, Read, Write},
+        ops::Generator,
+    };
+    use crate::{Context, Effect, IntoBlock, fill_reader, flush_writer};
+    use super::{EffectReader, EffectWriter, IoEffects, IoOutputs};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Read(u32, usize),
+        Write(u32, Vec<u8>),
+    }
+
+    impl IoEffects for Effects {
+        type Key = u32;
+
+        fn read(key: u32, max: usize) -> Self {
+            Effects::Read(key, max)
+        }
+
+        fn write(key: u32, data: Vec<u8>) -> Self {
+            Effects::Write(key, data)
+        }
+    }
+
+    #[derive(Debug)]
+    enum Outputs {
+        Data(Vec<u8>),
+        Written(usize),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl IoOutputs for Outputs {
+        fn read_result(self) -> Result<io::Result<Vec<u8>>, Self> {
+            match self {
+                Outputs::Data(data) => Ok(Ok(data)),
+                other => Err(other),
+            }
+        }
+
+        fn write_result(self) -> Result<io::Result<usize>, Self> {
+            match self {
+                Outputs::Written(length) => Ok(Ok(length)),
+                other => Err(other),
+            }
+        }
+    }
+
+    // a parser of some other crate, it knows nothing of effects: a count and
+    // that many big endian numbers
+    fn parse_records<R>(reader: &mut R) -> io::Result<Vec<u32>>
+    where
+        R: Read,
+    {
+        let mut count = [0; 1];
+        reader.read_exact(&mut count)?;
+        (0..count[0])
+            .map(|_| {
+                let mut number = [0; 4];
+                reader.read_exact(&mut number)?;
+                Ok(u32::from_be_bytes(number))
+            })
+            .collect()
+    }
+
+    fn parser(
+        context: Context<Outputs>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = (io::Result<Vec<u32>>, usize)> {
+        move || {
+            let mut reader = EffectReader::new(7);
+            let mut fills = 0;
+            let records = loop {
+                match reader.attempt(parse_records) {
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                        fill_reader!(reader, &context, 4);
+                        fills += 1;
+                    },
+                    result => break result,
+                }
+            };
+            (records, fills)
+        }
+    }
+
+    fn scripted(
+        chunks: &[&[u8]],
+        log: &Rc<RefCell<Vec<Effects>>>,
+    ) -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+        let mut chunks = chunks.iter().map(|c| c.to_vec()).collect::<VecDeque<_>>();
+        let log = log.clone();
+        move |effect| {
+            log.borrow_mut().push(effect.clone());
+            match effect {
+                Effects::Read(_, max) => {
+                    let chunk = chunks.pop_front().unwrap_or_default();
+                    assert!(chunk.len() <= max);
+                    Ok(Outputs::Data(chunk))
+                },
+                Effects::Write(_, data) => Ok(Outputs::Written(data.len())),
+            }
+        }
+    }
+
+    #[test]
+    fn awkward_chunks() {
+        let log = Rc::default();
+        let chunks: &[&[u8]] = &[&[2], &[0, 0], &[0, 1, 0, 0], &[1], &[2]];
+        let (records, fills) = parser
+            .into_block()
+            .add_handler(scripted(chunks, &log))
+            .assert_handled()
+            .run();
+        assert_eq!(records.unwrap(), [1, 0x0102]);
+        assert_eq!(fills, chunks.len());
+        assert!(log.borrow().iter().all(|e| *e == Effects::Read(7, 4)));
+    }
+
+    #[test]
+    fn refill_ends() {
+        // the stream ends in the middle of the second number
+        let log = Rc::default();
+        let chunks: &[&[u8]] = &[&[2, 0, 0], &[0, 1, 0]];
+        let (records, fills) = parser
+            .into_block()
+            .add_handler(scripted(chunks, &log))
+            .assert_handled()
+            .run();
+        assert_eq!(records.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        // the empty read is the end, nothing is performed after it
+        assert_eq!(fills, 3);
+        assert_eq!(log.borrow().len(), 3);
+    }
+
+    #[test]
+    fn writer_flushing() {
+        let log = Rc::default();
+        let written = (|context: Context<Outputs>| {
+            move || {
+                let mut writer = EffectWriter::new(3, 8);
+                let data = (0..12).collect::<Vec<u8>>();
+                let mut at = 0;
+                while at < data.len() {
+                    match writer.write(&data[at..]) {
+                        Ok(length) => at += length,
+                        Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                            flush_writer!(writer, &context)?;
+                        },
+                        Err(error) => return Err(error),
+                    }
+                }
+                assert!(writer.flush().is_err());
+                flush_writer!(writer, &context)?;
+                writer.flush()?;
+                // nothing buffered, nothing performed
+                flush_writer!(writer, &context)?;
+                Ok(writer.buffered())
+            }
+        })
+        .into_block()
+        .add_handler(scripted(&[], &log))
+        .assert_handled()
+        .run();
+        assert_eq!(written.unwrap(), 0);
+        assert_eq!(
+            *log.borrow(),
+            [
+                Effects::Write(3, (0..8).collect()),
+                Effects::Write(3, (8..12).collect()),
+            ],
+        );
+    }
+
+    #[test]
+    fn short_writes() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let result = (|context: Context<Outputs>| {
+            move || {
+                let mut writer = EffectWriter::new(1, 16);
+                writer.write_all(b"abcdefgh")?;
+                flush_writer!(writer, &context)
+            }
+        })
+        .into_block()
+        .add_handler({
+            let log = log.clone();
+            move |effect: Effects| {
+                log.borrow_mut().push(effect.clone());
+                match effect {
+                    Effects::Write(_, data) => Ok(Outputs::Written(data.len().min(5))),
+                    other => Err(other),
+                }
+            }
+        })
+        .assert_handled()
+        .run();
+        assert!(result.is_ok());
+        assert_eq!(
+            *log.borrow(),
+            [
+                Effects::Write(1, b"abcdefgh".to_vec()),
+                Effects::Write(1, b"fgh".to_vec()),
+            ],
+        );
+    }
+}
@@ -0,0 +1,528 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::BTreeMap,
+    time::Duration,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    computation::{self, Effect, EffectName, Handler},
+    deadline::{self, HasDeadline, Rounds},
+    explain::EffectDescription,
+    idempotency::IdempotencyToken,
+    lazy::CheapClone,
+};
+
+// the wait before a retry, the first retry is 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backoff {
+    #[default]
+    None,
+    Fixed(Duration),
+    // doubles every retry, never more than `max`
+    Exponential {
+        base: Duration,
+        max: Duration,
+    },
+}
+
+impl Backoff {
+    pub fn delay(&self, retry: u32) -> Duration {
+        match *self {
+            Backoff::None => Duration::ZERO,
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { base, max } => {
+                let factor = 1u32
+                    .checked_shl(retry.saturating_sub(1))
+                    .unwrap_or(u32::MAX);
+                base.saturating_mul(factor).min(max)
+            },
+        }
+    }
+}
+
+// The stages an effect of a class goes through once the primary handler fails
+// it. The default ladder has none, the failure is passed on as it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Ladder {
+    retries: u32,
+    backoff: Backoff,
+    fallback: bool,
+    fail: bool,
+}
+
+impl Ladder {
+    pub fn new() -> Self {
+        Ladder::default()
+    }
+
+    pub fn retry(self, retries: u32, backoff: Backoff) -> Self {
+        Ladder {
+            retries,
+            backoff,
+            ..self
+        }
+    }
+
+    pub fn fallback(self) -> Self {
+        Ladder {
+            fallback: true,
+            ..self
+        }
+    }
+
+    // responds with `HasEscalationFailed::escalation_failed` at the end
+    pub fn fail(self) -> Self {
+        Ladder { fail: true, ..self }
+    }
+}
+
+type Predicate<T> = Box<dyn Fn(&T) -> bool>;
+
+// A handler fails an effect when it declines it or when the output is one
+// `failed` picks. The ladder of an effect is the one of its class, of the
+// first predicate it matches, or the default one.
+pub struct EscalationPolicy<E>
+where
+    E: Effect,
+{
+    classes: BTreeMap<&'static str, Ladder>,
+    predicates: Vec<(Predicate<E::Input>, Ladder)>,
+    otherwise: Ladder,
+    failed: Predicate<E>,
+}
+
+pub struct EscalationPolicyBuilder<E>(EscalationPolicy<E>)
+where
+    E: Effect;
+
+impl<E> EscalationPolicy<E>
+where
+    E: Effect,
+{
+    pub fn builder() -> EscalationPolicyBuilder<E> {
+        EscalationPolicyBuilder(EscalationPolicy {
+            classes: BTreeMap::new(),
+            predicates: Vec::new(),
+            otherwise: Ladder::default(),
+            failed: Box::new(|_| false),
+        })
+    }
+
+    fn ladder(&self, effect: &E::Input) -> Ladder
+    where
+        E::Input: EffectName,
+    {
+        self.classes
+            .get(effect.effect_name())
+            .or_else(|| {
+                self.predicates
+                    .iter()
+                    .find(|(predicate, _)| predicate(effect))
+                    .map(|(_, ladder)| ladder)
+            })
+            .cloned()
+            .unwrap_or(self.otherwise)
+    }
+}
+
+impl<E> EscalationPolicyBuilder<E>
+where
+    E: Effect,
+{
+    // the class is the `EffectName` of the effect
+    pub fn class(self, name: &'static str, ladder: Ladder) -> Self {
+        let mut policy = self.0;
+        policy.classes.insert(name, ladder);
+        EscalationPolicyBuilder(policy)
+    }
+
+    pub fn when<P>(self, predicate: P, ladder: Ladder) -> Self
+    where
+        P: Fn(&E::Input) -> bool + 'static,
+    {
+        let mut policy = self.0;
+        policy.predicates.push((Box::new(predicate), ladder));
+        EscalationPolicyBuilder(policy)
+    }
+
+    pub fn otherwise(self, ladder: Ladder) -> Self {
+        EscalationPolicyBuilder(EscalationPolicy {
+            otherwise: ladder,
+            ..self.0
+        })
+    }
+
+    pub fn failed<F>(self, failed: F) -> Self
+    where
+        F: Fn(&E) -> bool + 'static,
+    {
+        EscalationPolicyBuilder(EscalationPolicy {
+            failed: Box::new(failed),
+            ..self.0
+        })
+    }
+
+    pub fn build(self) -> EscalationPolicy<E> {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Retry,
+    Fallback,
+    FailureOutput,
+    // no stage resolved it, the failure is passed on
+    Unresolved,
+}
+
+// An effect the primary handler failed at least once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Incident {
+    pub effect: &'static str,
+    pub description: EffectDescription,
+    pub token: Option<IdempotencyToken>,
+    // deliveries to the primary handler, the first one and the retries
+    pub attempts: u32,
+    pub resolved_by: Stage,
+    // the stages left were skipped, the deadline of the effect passed
+    pub deadline_passed: bool,
+    // from the first delivery to the resolution, the backoff included
+    pub elapsed: Duration,
+}
+
+impl Incident {
+    // the rounds spent under the `RoundCounter`
+    pub fn rounds(&self) -> Rounds {
+        Rounds::of(self.elapsed)
+    }
+}
+
+pub trait HasEscalationFailed
+where
+    Self: Sized + Effect,
+{
+    fn escalation_failed(effect: Self::Input, incident: &Incident) -> Self;
+}
+
+#[derive(Clone, Default)]
+pub struct IncidentLog(Rc<RefCell<Vec<Incident>>>);
+
+impl IncidentLog {
+    pub fn incidents(&self) -> Vec<Incident> {
+        self.0.borrow().clone()
+    }
+
+    // the incidents after the first `n`
+    pub(crate) fn since(&self, n: usize) -> Vec<Incident> {
+        self.0.borrow().iter().skip(n).cloned().collect()
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: HasEscalationFailed,
+    E::Input: CheapClone + EffectName + HasDeadline,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // The primary handler sees the effects first, like it would under
+    // `add_handler`. Once it fails one the ladder of the policy goes on from
+    // there, a retry is delivered with the same idempotency token.
+    pub fn add_handler_escalated<H1, H2>(
+        self,
+        primary: H1,
+        fallback: Option<H2>,
+        policy: EscalationPolicy<E>,
+    ) -> (
+        Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>,
+        IncidentLog,
+    )
+    where
+        H1: Handler<E>,
+        H2: Handler<E>,
+    {
+        let context = self.context();
+        let log = IncidentLog::default();
+        let mut primary = primary;
+        let mut fallback = fallback;
+        let mut s = self;
+        let generator = {
+            let log = log.clone();
+            move || loop {
+                let effect = match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(effect) => effect,
+                };
+                let context = s.context();
+                let ladder = policy.ladder(&effect);
+                let start = deadline::now();
+                let mut attempts = 0;
+                let mut failure = None;
+                let mut deadline_passed = false;
+                let resolved = loop {
+                    attempts += 1;
+                    match computation::dispatch(&mut primary, &context, effect.cheap_clone()) {
+                        Ok(output) if !(policy.failed)(&output) => break Some(output),
+                        Ok(output) => failure = Some(output),
+                        Err(_) => (),
+                    }
+                    if attempts > ladder.retries {
+                        break None;
+                    }
+                    let delay = ladder.backoff.delay(attempts);
+                    if effect
+                        .deadline()
+                        .map_or(false, |d| deadline::now() + delay >= d)
+                    {
+                        deadline_passed = true;
+                        break None;
+                    }
+                    deadline::sleep(delay);
+                };
+                let (stage, output) = match resolved {
+                    Some(output) if attempts == 1 => {
+                        s.put(output);
+                        continue;
+                    },
+                    Some(output) => (Stage::Retry, Some(output)),
+                    None => {
+                        deadline_passed |= effect.expired();
+                        let rescued = match fallback.as_mut() {
+                            Some(fallback) if ladder.fallback && !deadline_passed => {
+                                computation::dispatch(fallback, &context, effect.cheap_clone())
+                                    .ok()
+                                    .filter(|output| !(policy.failed)(output))
+                            },
+                            _ => None,
+                        };
+                        match rescued {
+                            Some(output) => (Stage::Fallback, Some(output)),
+                            None if ladder.fail => (Stage::FailureOutput, None),
+                            None => (Stage::Unresolved, failure),
+                        }
+                    },
+                };
+                let incident = Incident {
+                    effect: effect.effect_name(),
+                    description: effect.describe(),
+                    token: context.token(),
+                    attempts,
+                    resolved_by: stage,
+                    deadline_passed,
+                    elapsed: deadline::now() - start,
+                };
+                log.0.borrow_mut().push(incident.clone());
+                match (stage, output) {
+                    (Stage::FailureOutput, _) => s.put(E::escalation_failed(effect, &incident)),
+                    (_, Some(output)) => s.put(output),
+                    // declined every time, the next handler may know better
+                    (_, None) => yield effect,
+                }
+            }
+        };
+        (Block::new(context, generator), log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, time::Duration};
+    use crate::{
+        Context, Effect, EffectDescription, EffectName, IntoBlock, IdempotencyToken, Handler,
+        deadline::{Deadlined, TestClock},
+        with_deadline,
+    };
+    use super::{Backoff, EscalationPolicy, HasEscalationFailed, Incident, IncidentLog, Ladder, Stage};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Request {
+        Fetch(u32),
+    }
+
+    impl EffectName for Request {
+        fn effect_name(&self) -> &'static str {
+            "Fetch"
+        }
+
+        fn describe(&self) -> EffectDescription {
+            EffectDescription::new("Fetch", "Fetches a page from the origin.", &["0"])
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Outputs {
+        Fetched(u32, &'static str),
+        Error,
+        GaveUp(u32, u32),
+    }
+
+    impl Effect for Outputs {
+        type Input = Deadlined<Request>;
+    }
+
+    impl HasEscalationFailed for Outputs {
+        fn escalation_failed(effect: Deadlined<Request>, incident: &Incident) -> Self {
+            let Request::Fetch(n) = effect.inner;
+            Outputs::GaveUp(n, incident.attempts)
+        }
+    }
+
+    // fails the first `failures` deliveries, or all of them
+    struct Flaky {
+        failures: Option<u32>,
+        delivered: Rc<RefCell<Vec<Option<IdempotencyToken>>>>,
+    }
+
+    impl Handler<Outputs> for Flaky {
+        fn handle(&mut self, effect: Deadlined<Request>) -> Result<Outputs, Deadlined<Request>> {
+            self.delivered.borrow_mut().push(None);
+            let Request::Fetch(n) = effect.inner;
+            match &mut self.failures {
+                Some(0) => Ok(Outputs::Fetched(n, "primary")),
+                Some(left) => {
+                    *left -= 1;
+                    Ok(Outputs::Error)
+                },
+                None => Err(effect),
+            }
+        }
+
+        fn handle_idempotent(
+            &mut self,
+            token: IdempotencyToken,
+            effect: Deadlined<Request>,
+        ) -> Result<Outputs, Deadlined<Request>> {
+            let result = self.handle(effect);
+            *self.delivered.borrow_mut().last_mut().unwrap() = Some(token);
+            result
+        }
+    }
+
+    fn policy(fallback: bool) -> EscalationPolicy<Outputs> {
+        let ladder = Ladder::new().retry(2, Backoff::Fixed(Duration::from_millis(10)));
+        let ladder = if fallback { ladder.fallback() } else { ladder };
+        EscalationPolicy::builder()
+            .class("Fetch", ladder.fail())
+            .failed(|output| *output == Outputs::Error)
+            .build()
+    }
+
+    fn run(
+        failures: Option<u32>,
+        fallback_works: bool,
+        deadline: Duration,
+    ) -> (Vec<Outputs>, IncidentLog, Vec<Option<IdempotencyToken>>) {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let delivered = Rc::new(RefCell::new(Vec::new()));
+        let computation = {
+            let seen = seen.clone();
+            move |context: Context<Outputs>| {
+                move || {
+                    for n in 0..2 {
+                        yield with_deadline!(Deadlined::new(Request::Fetch(n)), deadline);
+                        seen.borrow_mut().extend(context.take());
+                    }
+                }
+            }
+        };
+        let primary = Flaky {
+            failures,
+            delivered: delivered.clone(),
+        };
+        let fallback = move |effect: Deadlined<Request>| {
+            let Request::Fetch(n) = effect.inner;
+            if fallback_works {
+                Ok(Outputs::Fetched(n, "fallback"))
+            } else {
+                Err(effect)
+            }
+        };
+        let (block, log) = computation
+            .into_block()
+            .idempotency_tokens()
+            .add_handler_escalated(primary, Some(fallback), policy(true));
+        block.assert_handled().run();
+        let seen = seen.borrow().clone();
+        let delivered = delivered.borrow().clone();
+        (seen, log, delivered)
+    }
+
+    #[test]
+    fn resolved_by_retry() {
+        let clock = TestClock::install();
+        let start = clock.now();
+        let (seen, log, delivered) = run(Some(2), true, Duration::from_secs(1));
+        assert_eq!(
+            seen,
+            [
+                Outputs::Fetched(0, "primary"),
+                Outputs::Fetched(1, "primary")
+            ]
+        );
+        let incidents = log.incidents();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].attempts, 3);
+        assert_eq!(incidents[0].resolved_by, Stage::Retry);
+        assert_eq!(incidents[0].elapsed, Duration::from_millis(20));
+        assert_eq!(clock.now() - start, Duration::from_millis(20));
+        // the retries of the first fetch carry its token
+        let first = Some(IdempotencyToken(0));
+        assert_eq!(delivered, [first, first, first, Some(IdempotencyToken(1))]);
+    }
+
+    #[test]
+    fn resolved_by_fallback() {
+        let _clock = TestClock::install();
+        let (seen, log, _) = run(None, true, Duration::from_secs(1));
+        assert_eq!(
+            seen,
+            [
+                Outputs::Fetched(0, "fallback"),
+                Outputs::Fetched(1, "fallback")
+            ]
+        );
+        let incidents = log.incidents();
+        assert_eq!(incidents.len(), 2);
+        assert!(incidents
+            .iter()
+            .all(|i| i.attempts == 3 && i.resolved_by == Stage::Fallback && !i.deadline_passed));
+    }
+
+    #[test]
+    fn failure_output() {
+        let _clock = TestClock::install();
+        let (seen, log, _) = run(None, false, Duration::from_secs(1));
+        assert_eq!(seen, [Outputs::GaveUp(0, 3), Outputs::GaveUp(1, 3)]);
+        let incidents = log.incidents();
+        assert_eq!(
+            incidents
+                .iter()
+                .map(|i| (i.effect, i.resolved_by))
+                .collect::<Vec<_>>(),
+            [("Fetch", Stage::FailureOutput); 2],
+        );
+    }
+
+    #[test]
+    fn stops_at_deadline() {
+        let _clock = TestClock::install();
+        // the second retry would wait past the deadline, the fallback is skipped
+        let (seen, log, delivered) = run(None, true, Duration::from_millis(15));
+        assert_eq!(seen, [Outputs::GaveUp(0, 2), Outputs::GaveUp(1, 2)]);
+        assert_eq!(delivered.len(), 4);
+        let incident = &log.incidents()[0];
+        assert!(incident.deadline_passed);
+        assert_eq!(incident.elapsed, Duration::from_millis(10));
+        // through `Deadlined`
+        assert_eq!(
+            incident.description.summary,
+            "Fetches a page from the origin."
+        );
+    }
+}
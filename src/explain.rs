@@ -0,0 +1,297 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    fmt,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    computation::{Effect, EffectName, report_unhandled},
+    latency::{self, LatencyViolation},
+    routing::{self, HandlerTag},
+};
+
+// What a variant means, `#[derive(EffectName)]` takes it from the doc comment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EffectDescription {
+    pub name: &'static str,
+    // the first line of the doc comment, empty without one
+    pub summary: &'static str,
+    details: &'static str,
+    // the positions of the tuple fields
+    pub fields: &'static [&'static str],
+}
+
+impl EffectDescription {
+    // the lines of `doc` after the first one are the details
+    pub fn new(name: &'static str, doc: &'static str, fields: &'static [&'static str]) -> Self {
+        let (summary, details) = match doc.trim().split_once('\n') {
+            Some((summary, details)) => (summary.trim(), details.trim()),
+            None => (doc.trim(), ""),
+        };
+        EffectDescription {
+            name,
+            summary,
+            details,
+            fields,
+        }
+    }
+
+    pub fn details(&self) -> &'static str {
+        self.details
+    }
+}
+
+impl fmt::Display for EffectDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.summary.is_empty() {
+            write!(f, "`{}`", self.name)
+        } else {
+            write!(f, "`{}` ({})", self.name, self.summary)
+        }
+    }
+}
+
+impl<I> LatencyViolation<I>
+where
+    I: EffectName,
+{
+    pub fn description(&self) -> EffectDescription {
+        self.effect.describe()
+    }
+}
+
+// an effect waiting for its response, as `explain_pending` finds it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PendingExplanation {
+    pub description: EffectDescription,
+    // rounds it waits for its response so far
+    pub age: u32,
+    pub scope: String,
+    // the custodians holding effects, empty when the driver of the block has them
+    pub custody: Vec<&'static str>,
+    // the first tagged handler whose filter admits the variant
+    pub handler: Option<HandlerTag>,
+}
+
+impl fmt::Display for PendingExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} waits for {} rounds", self.description, self.age)?;
+        if !self.scope.is_empty() {
+            write!(f, " in `{}`", self.scope)?;
+        }
+        if !self.custody.is_empty() {
+            write!(f, ", held by {}", self.custody.join(", "))?;
+        }
+        if let Some(handler) = &self.handler {
+            write!(f, ", claimed by `{}`", handler.name())?;
+        }
+        Ok(())
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + 'static,
+    E::Input: EffectName + 'static,
+    G: Unpin + Generator<()>,
+{
+    // The effects `with_latency_slo` sees waiting, oldest first. Like
+    // `inspect`, empty without the layer or the diagnostics.
+    pub fn explain_pending(&self) -> Vec<PendingExplanation> {
+        let context = self.context();
+        let (chain, _) = routing::chain(&context);
+        let custody = latency::holding(&context);
+        latency::waiting(&context, |effect: &E::Input, age, scope| {
+            let name = effect.effect_name();
+            let handler = chain
+                .iter()
+                .find(|(_, variants)| variants.as_ref().map_or(false, |v| v.contains(&name)))
+                .map(|(tag, _)| *tag);
+            PendingExplanation {
+                description: effect.describe(),
+                age,
+                scope: scope.to_string(),
+                custody: custody.clone(),
+                handler,
+            }
+        })
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>,
+    G::Yield: EffectName + fmt::Debug,
+{
+    // like `assert_handled`, the panic message has the summary of the variant
+    pub fn assert_handled_described(
+        self,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = !>> {
+        let context = self.context();
+        let mut s = self;
+        // like in `assert_handled`
+        #[allow(clippy::never_loop)]
+        let generator = move || loop {
+            match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => {
+                    let summary = effect.describe().summary;
+                    let mut message = format!("{:?}", effect);
+                    if !summary.is_empty() {
+                        message += &format!(" ({})", summary);
+                    }
+                    report_unhandled(&s.context(), message);
+                    #[allow(unreachable_code)]
+                    yield unreachable!()
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        cell::RefCell,
+        ops::{Generator, GeneratorState},
+    };
+    use crate::{Context, Custody, Effect, EffectName, HandlerTag, IntoBlock, Responds, perform};
+    use super::EffectDescription;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Get(u32),
+        Put(u32),
+    }
+
+    impl EffectName for Effects {
+        fn effect_name(&self) -> &'static str {
+            match self {
+                Effects::Get(_) => "Get",
+                Effects::Put(_) => "Put",
+            }
+        }
+
+        // what the derive writes
+        fn describe(&self) -> EffectDescription {
+            match self {
+                Effects::Get(_) => EffectDescription::new("Get", "Reads a key.", &["0"]),
+                Effects::Put(_) => EffectDescription::new(
+                    "Put",
+                    "Writes a key.\n\nThe store answers once it is durable.",
+                    &["0"],
+                ),
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Got(u32),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl Responds for Outputs {
+        fn responds_to(&self, effect: &Effects) -> bool {
+            match (self, effect) {
+                (Outputs::Got(n), Effects::Get(m)) => n == m,
+                _ => false,
+            }
+        }
+    }
+
+    struct Disk(RefCell<usize>);
+
+    impl Custody for Disk {
+        fn name(&self) -> &'static str {
+            "disk"
+        }
+
+        fn held(&self) -> usize {
+            *self.0.borrow()
+        }
+    }
+
+    fn computation(
+        context: Context<Outputs>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            let _scope = context.enter_scope("sync");
+            perform!(Effects::Put(1));
+            perform!(Effects::Get(1));
+            assert_eq!(context.take(), Some(Outputs::Got(1)));
+        }
+    }
+
+    #[test]
+    fn details() {
+        let put = Effects::Put(1).describe();
+        assert_eq!(put.summary, "Writes a key.");
+        assert_eq!(put.details(), "The store answers once it is durable.");
+        assert_eq!(put.fields, ["0"]);
+        assert_eq!(put.to_string(), "`Put` (Writes a key.)");
+        let bare = EffectDescription::new("Idle", "", &[]);
+        assert_eq!((bare.summary, bare.details()), ("", ""));
+        assert_eq!(bare.to_string(), "`Idle`");
+    }
+
+    // the disk takes the puts and never answers
+    #[test]
+    fn stalled() {
+        let (block, _) = computation.into_block().with_latency_slo(10, |_| ());
+        let block = block
+            .add_handler_tagged_filtered(
+                crate::filter_variants!(Effects::Put),
+                Err::<Outputs, _>,
+                HandlerTag::new("store"),
+            )
+            .add_handler_tagged(Err::<Outputs, _>, HandlerTag::new("log"));
+        let disk = Rc::new(Disk(RefCell::new(0)));
+        block.context().add_custody(&disk);
+        let mut block = block;
+        assert_eq!(block.resume(), GeneratorState::Yielded(Effects::Put(1)));
+        *disk.0.borrow_mut() += 1;
+        assert_eq!(block.resume(), GeneratorState::Yielded(Effects::Get(1)));
+        let pending = block.explain_pending();
+        if !cfg!(feature = "diagnostics") {
+            assert!(pending.is_empty());
+            return;
+        }
+        assert_eq!(pending.len(), 2);
+        let (put, get) = (&pending[0], &pending[1]);
+        assert_eq!((put.description.name, put.age), ("Put", 1));
+        assert_eq!(put.handler, Some(HandlerTag::new("store")));
+        assert_eq!(put.custody, ["disk"]);
+        assert_eq!(
+            put.to_string(),
+            "`Put` (Writes a key.) waits for 1 rounds in `sync`, held by disk, claimed by `store`"
+        );
+        // no filter claims it, the unfiltered handler is not responsible
+        assert_eq!(
+            (get.description.name, get.age, get.handler),
+            ("Get", 0, None)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "unhandled: Get(1) (Reads a key.)")]
+    fn unhandled_summary() {
+        (|_: Context<Outputs>| {
+            move || {
+                perform!(Effects::Get(1));
+            }
+        })
+        .into_block()
+        .assert_handled_described()
+        .run();
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::ops::{Generator, GeneratorState, ControlFlow};
+use super::{
+    block::Block,
+    computation::{Select, TakeResult},
+    context::Context,
+};
+
+// the success part is tried first, a response matching neither is handed back
+impl<E, T, Err> Select<Result<T, Err>> for E
+where
+    E: Select<T> + Select<Err>,
+{
+    fn try_take(output: &Context<Self>) -> TakeResult<Result<T, Err>, Self> {
+        match <E as Select<T>>::try_take(output) {
+            TakeResult::Mismatched(other) => {
+                output.put_front(other);
+                <E as Select<Err>>::try_take(output).map(Err)
+            },
+            result => result.map(Ok),
+        }
+    }
+}
+
+pub type BoxedGenerator<Y, R> = Box<dyn Unpin + Generator<(), Yield = Y, Return = R>>;
+
+pub type FallibleBlock<T, Y, R, Err> = Block<T, BoxedGenerator<Y, Result<R, Err>>>;
+
+impl<T, G> Block<T, G>
+where
+    T: 'static,
+    G: Unpin + Generator<()> + 'static,
+{
+    pub fn boxed(self) -> Block<T, BoxedGenerator<G::Yield, G::Return>> {
+        let context = self.context();
+        let mut s = self;
+        Block::new(
+            context,
+            Box::new(move || loop {
+                match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(y) => yield y,
+                }
+            }),
+        )
+    }
+}
+
+// lets `call!` resume the callee without the caller enabling `generator_trait`
+#[doc(hidden)]
+pub fn resume<G>(callee: &mut G) -> ControlFlow<G::Return, G::Yield>
+where
+    G: Unpin + Generator<()>,
+{
+    match std::pin::Pin::new(callee).resume(()) {
+        GeneratorState::Yielded(y) => ControlFlow::Continue(y),
+        GeneratorState::Complete(r) => ControlFlow::Break(r),
+    }
+}
+
+#[macro_export]
+macro_rules! perform_try {
+    ($e:expr, $ctx:expr) => {{
+        yield $e;
+        $crate::take_part!(
+            <_ as $crate::Select<::std::result::Result<_, _>>>::try_take($ctx),
+            $e
+        )
+    }};
+}
+
+// Runs a subroutine in place, the effects it yields pass through and its
+// return value is the value of the macro, so `call!(callee)?` propagates errors.
+#[macro_export]
+macro_rules! call {
+    ($callee:expr) => {{
+        let mut callee = $callee;
+        loop {
+            match $crate::resume_callee(&mut callee) {
+                ::std::ops::ControlFlow::Continue(y) => yield y,
+                ::std::ops::ControlFlow::Break(r) => break r,
+            }
+        }
+    }};
+}
@@ -0,0 +1,574 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    error::Error,
+    fmt,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    computation::{Effect, EffectName, Handler, dispatch},
+    explain::EffectDescription,
+    meta::WithMeta,
+};
+#[cfg(feature = "diagnostics")]
+use super::computation::report_unhandled;
+
+// Two families of effects in one block, the effects and the outputs of the
+// first family on the left, `Sum<A, Sum<B, C>>` composes three.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sum<A, B> {
+    Left(A),
+    Right(B),
+}
+
+// The families are numbered from the left through the nested sums, the ones
+// of `A` first, so only the tags on the way to the value are read.
+impl<A, B> Effect for Sum<A, B>
+where
+    A: Effect,
+    B: Effect,
+{
+    type Input = Sum<A::Input, B::Input>;
+
+    const FAMILIES: usize = A::FAMILIES + B::FAMILIES;
+
+    fn family(&self) -> usize {
+        match self {
+            Sum::Left(output) => output.family(),
+            Sum::Right(output) => A::FAMILIES + output.family(),
+        }
+    }
+
+    fn input_family(effect: &Self::Input) -> usize {
+        match effect {
+            Sum::Left(effect) => A::input_family(effect),
+            Sum::Right(effect) => A::FAMILIES + B::input_family(effect),
+        }
+    }
+
+    fn family_name(family: usize) -> &'static str {
+        if family < A::FAMILIES {
+            A::family_name(family)
+        } else {
+            B::family_name(family - A::FAMILIES)
+        }
+    }
+}
+
+impl<A, B> EffectName for Sum<A, B>
+where
+    A: EffectName,
+    B: EffectName,
+{
+    fn effect_name(&self) -> &'static str {
+        match self {
+            Sum::Left(effect) => effect.effect_name(),
+            Sum::Right(effect) => effect.effect_name(),
+        }
+    }
+
+    fn describe(&self) -> EffectDescription {
+        match self {
+            Sum::Left(effect) => effect.describe(),
+            Sum::Right(effect) => effect.describe(),
+        }
+    }
+}
+
+// A lifted handler answering an effect of one family of a `Sum` with an
+// output of another, reported like an unhandled effect, see
+// `Block::add_lifted_handler`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FamilyMismatch {
+    pub effect: EffectDescription,
+    // the types of the outputs, like `Effect::family_name`
+    pub effect_family: &'static str,
+    pub output_family: &'static str,
+}
+
+impl fmt::Display for FamilyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of `{}` answered with an output of `{}`",
+            self.effect, self.effect_family, self.output_family
+        )
+    }
+}
+
+impl Error for FamilyMismatch {}
+
+struct LiftLeft<H>(H);
+
+impl<A, B, H> Handler<Sum<A, B>> for LiftLeft<H>
+where
+    A: Effect,
+    B: Effect,
+    H: Handler<A>,
+{
+    fn handle(
+        &mut self,
+        effect: Sum<A::Input, B::Input>,
+    ) -> Result<Sum<A, B>, Sum<A::Input, B::Input>> {
+        match effect {
+            Sum::Left(effect) => self.0.handle(effect).map(Sum::Left).map_err(Sum::Left),
+            other => Err(other),
+        }
+    }
+
+    fn init(&mut self) {
+        self.0.init()
+    }
+
+    fn finish(&mut self) {
+        self.0.finish()
+    }
+}
+
+struct LiftRight<H>(H);
+
+impl<A, B, H> Handler<Sum<A, B>> for LiftRight<H>
+where
+    A: Effect,
+    B: Effect,
+    H: Handler<B>,
+{
+    fn handle(
+        &mut self,
+        effect: Sum<A::Input, B::Input>,
+    ) -> Result<Sum<A, B>, Sum<A::Input, B::Input>> {
+        match effect {
+            Sum::Right(effect) => self.0.handle(effect).map(Sum::Right).map_err(Sum::Right),
+            other => Err(other),
+        }
+    }
+
+    fn init(&mut self) {
+        self.0.init()
+    }
+
+    fn finish(&mut self) {
+        self.0.finish()
+    }
+}
+
+impl<A, B> Sum<A, B>
+where
+    A: Effect,
+    B: Effect,
+{
+    // the handler of one family answering the effects of that family
+    pub fn lift_left<H>(handler: H) -> impl Handler<Self>
+    where
+        H: Handler<A>,
+    {
+        LiftLeft(handler)
+    }
+
+    pub fn lift_right<H>(handler: H) -> impl Handler<Self>
+    where
+        H: Handler<B>,
+    {
+        LiftRight(handler)
+    }
+}
+
+impl<A, B, G> Block<Sum<A, B>, G>
+where
+    A: Effect,
+    B: Effect,
+    A::Input: EffectName,
+    B::Input: EffectName,
+    G: Unpin + Generator<(), Yield = Sum<A::Input, B::Input>>,
+    G::Yield: fmt::Debug,
+{
+    // Like `add_handler` with a handler of the whole sum, a lifted one or one
+    // lifting by hand. With diagnostics an output of another family than the
+    // one of the effect it answers is not put, the run ends on a
+    // `FamilyMismatch` like on an unhandled effect rather than at the
+    // `perform!` of some other part. The check is one comparison of the
+    // family indices per output, without diagnostics the output is put
+    // unchecked.
+    pub fn add_lifted_handler<H>(
+        self,
+        handler: H,
+    ) -> Block<Sum<A, B>, impl Unpin + Generator<(), Return = G::Return, Yield = G::Yield>>
+    where
+        H: Handler<Sum<A, B>>,
+    {
+        let context = self.context();
+        let mut handler = handler;
+        let mut s = self;
+        let generator = move || loop {
+            match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => {
+                    // read before the handler takes it
+                    #[cfg(feature = "diagnostics")]
+                    let (family, description) =
+                        (Sum::<A, B>::input_family(&effect), effect.describe());
+                    match dispatch(&mut handler, &s.context(), effect) {
+                        Ok(output) => {
+                            #[cfg(feature = "diagnostics")]
+                            if output.family() != family {
+                                let mismatch = FamilyMismatch {
+                                    effect: description,
+                                    effect_family: Sum::<A, B>::family_name(family),
+                                    output_family: Sum::<A, B>::family_name(output.family()),
+                                };
+                                report_unhandled(&s.context(), mismatch);
+                            }
+                            s.put(output)
+                        },
+                        Err(unhandled) => yield unhandled,
+                    }
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+impl<E, G, A, B> Block<E, G>
+where
+    E: Effect<Input = WithMeta<Sum<A, B>>>,
+    G: Unpin + Generator<(), Yield = WithMeta<Sum<A, B>>>,
+{
+    // tells the layers outside the family of each effect, see `EffectMeta::family`,
+    // `S` is the sum of the outputs answering them
+    pub fn stamp_family<S>(
+        self,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = WithMeta<Sum<A, B>>>>
+    where
+        S: Effect<Input = Sum<A, B>>,
+    {
+        let context = self.context();
+        let mut s = self;
+        let generator = move || loop {
+            match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(mut effect) => {
+                    effect.meta.family = Some(S::input_family(&effect.effect));
+                    yield effect;
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+// The `Select` impls of a `Sum` forwarding to the ones of its families, the
+// part is taken only from an output of the family named with it.
+#[macro_export]
+macro_rules! compose_effects {
+    ($sum:ty { $($family:ident => $part:ty),+ $(,)? }) => {
+        $(
+            impl $crate::Select<$part> for $sum {
+                fn try_take(output: &$crate::Context<Self>) -> $crate::TakeResult<$part, Self> {
+                    match output.take() {
+                        None => $crate::TakeResult::Empty,
+                        Some($crate::Sum::$family(value)) => {
+                            let family = $crate::Context::empty();
+                            family.put(value);
+                            match $crate::Select::<$part>::try_take(&family) {
+                                $crate::TakeResult::Matched(part) => {
+                                    debug_assert!(
+                                        family.take().is_none(),
+                                        "`{}` projected more than one output",
+                                        stringify!($part),
+                                    );
+                                    $crate::TakeResult::Matched(part)
+                                },
+                                $crate::TakeResult::Mismatched(value) => {
+                                    $crate::TakeResult::Mismatched($crate::Sum::$family(value))
+                                },
+                                $crate::TakeResult::Empty => $crate::TakeResult::Empty,
+                            }
+                        },
+                        Some(other) => {
+                            debug_assert!(
+                                !matches!(other, $crate::Sum::$family(_)),
+                                "`{}` skipped an output of its family",
+                                stringify!($part),
+                            );
+                            $crate::TakeResult::Mismatched(other)
+                        },
+                    }
+                }
+            }
+        )+
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        any,
+        rc::Rc,
+        cell::{Cell, RefCell},
+        ops::Generator,
+    };
+    use crate::{Context, Effect, EffectName, IntoBlock, Select, WithMeta, perform};
+    use super::Sum;
+    #[cfg(feature = "diagnostics")]
+    use super::FamilyMismatch;
+
+    #[derive(Debug)]
+    enum ClockEffect {
+        Now,
+    }
+
+    impl EffectName for ClockEffect {
+        fn effect_name(&self) -> &'static str {
+            "Now"
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Clock {
+        Time(u64),
+    }
+
+    impl Effect for Clock {
+        type Input = ClockEffect;
+    }
+
+    struct Time(u64);
+
+    impl Select<Time> for Clock {
+        fn take(output: &Context<Self>) -> Option<Time> {
+            output.take().map(|Clock::Time(t)| Time(t))
+        }
+    }
+
+    #[derive(Debug)]
+    enum StoreEffect {
+        Get(u32),
+    }
+
+    impl EffectName for StoreEffect {
+        fn effect_name(&self) -> &'static str {
+            "Get"
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Store {
+        Value(u32),
+    }
+
+    impl Effect for Store {
+        type Input = StoreEffect;
+    }
+
+    struct Stored(u32);
+
+    impl Select<Stored> for Store {
+        fn take(output: &Context<Self>) -> Option<Stored> {
+            output.take().map(|Store::Value(v)| Stored(v))
+        }
+    }
+
+    type Outputs = Sum<Clock, Store>;
+
+    compose_effects!(Sum<Clock, Store> {
+        Left => Time,
+        Right => Stored,
+    });
+
+    fn stamped(
+        context: Context<Outputs>,
+    ) -> impl Unpin + Generator<(), Yield = Sum<ClockEffect, StoreEffect>, Return = u64> {
+        move || {
+            let Time(time) = perform!(Sum::Left(ClockEffect::Now), &context);
+            let Stored(value) = perform!(Sum::Right(StoreEffect::Get(4)), &context);
+            time + u64::from(value)
+        }
+    }
+
+    fn clock(effect: ClockEffect) -> Result<Clock, ClockEffect> {
+        match effect {
+            ClockEffect::Now => Ok(Clock::Time(100)),
+        }
+    }
+
+    fn store(effect: StoreEffect) -> Result<Store, StoreEffect> {
+        match effect {
+            StoreEffect::Get(key) => Ok(Store::Value(key * 10)),
+        }
+    }
+
+    #[test]
+    fn composed() {
+        let r = stamped
+            .into_block()
+            .add_lifted_handler(Sum::lift_left(clock))
+            .add_lifted_handler(Sum::lift_right(store))
+            .assert_handled()
+            .run();
+        assert_eq!(r, 140);
+    }
+
+    // answers the clock with the store
+    fn swapped(
+        effect: Sum<ClockEffect, StoreEffect>,
+    ) -> Result<Outputs, Sum<ClockEffect, StoreEffect>> {
+        match effect {
+            Sum::Left(ClockEffect::Now) => Ok(Sum::Right(Store::Value(100))),
+            Sum::Right(effect) => store(effect).map(Sum::Right).map_err(Sum::Right),
+        }
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn mismatch() {
+        let mismatch = FamilyMismatch {
+            effect: ClockEffect::Now.describe(),
+            effect_family: any::type_name::<Clock>(),
+            output_family: any::type_name::<Store>(),
+        };
+        assert!(mismatch.to_string().starts_with("`Now` of `"));
+        let run = std::panic::catch_unwind(|| {
+            stamped
+                .into_block()
+                .add_lifted_handler(swapped)
+                .assert_handled()
+                .run()
+        });
+        let message = *run.unwrap_err().downcast::<String>().unwrap();
+        assert_eq!(message, format!("unhandled: {}", mismatch));
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected output")]
+    fn unchecked() {
+        // a plain handler puts it, the `perform!` of the clock finds the store
+        stamped
+            .into_block()
+            .add_handler(swapped)
+            .assert_handled()
+            .run();
+    }
+
+    #[derive(Debug)]
+    struct Answered;
+
+    impl Effect for Answered {
+        type Input = WithMeta<Sum<ClockEffect, StoreEffect>>;
+    }
+
+    #[test]
+    fn family_meta() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let handler = {
+            let seen = seen.clone();
+            move |effect: WithMeta<Sum<ClockEffect, StoreEffect>>| {
+                seen.borrow_mut().push(effect.meta.family);
+                Ok(Answered)
+            }
+        };
+        (|_: Context<Answered>| {
+            || {
+                perform!(WithMeta::new(Sum::Left(ClockEffect::Now)));
+                perform!(WithMeta::new(Sum::Right(StoreEffect::Get(1))));
+            }
+        })
+        .into_block()
+        .stamp_family::<Outputs>()
+        .add_handler(handler)
+        .assert_handled()
+        .run();
+        assert_eq!(*seen.borrow(), [Some(0), Some(1)]);
+    }
+
+    #[derive(Debug)]
+    enum LogEffect {
+        Line,
+    }
+
+    impl EffectName for LogEffect {
+        fn effect_name(&self) -> &'static str {
+            "Line"
+        }
+    }
+
+    // counts the reads of its family
+    #[derive(Debug, PartialEq)]
+    struct Log(Rc<Cell<usize>>);
+
+    impl Effect for Log {
+        type Input = LogEffect;
+
+        fn family(&self) -> usize {
+            self.0.set(self.0.get() + 1);
+            0
+        }
+    }
+
+    type Nested = Sum<Clock, Sum<Store, Log>>;
+
+    #[test]
+    fn nested_families() {
+        assert_eq!(Nested::FAMILIES, 3);
+        let log = Sum::Right(Sum::Right(Log(Rc::default())));
+        assert_eq!(Nested::family(&log), 2);
+        assert_eq!(
+            Nested::input_family(&Sum::Right(Sum::Left(StoreEffect::Get(1)))),
+            1
+        );
+        assert_eq!(Nested::family_name(0), any::type_name::<Clock>());
+        assert_eq!(Nested::family_name(1), any::type_name::<Store>());
+        assert_eq!(Nested::family_name(2), any::type_name::<Log>());
+    }
+
+    // the store answered with the log, the outer tags are the same
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    #[should_panic(expected = "unhandled: `Get` of `")]
+    fn nested_mismatch() {
+        (|_: Context<Nested>| {
+            || {
+                perform!(Sum::Right(Sum::Left(StoreEffect::Get(1))));
+            }
+        })
+        .into_block()
+        .add_lifted_handler(|effect| match effect {
+            Sum::Right(Sum::Left(_)) => Ok(Sum::Right(Sum::Right(Log(Rc::default())))),
+            other => Err(other),
+        })
+        .assert_handled()
+        .run();
+    }
+
+    #[test]
+    fn one_check_per_output() {
+        let reads = Rc::new(Cell::new(0));
+        let handler = {
+            let reads = reads.clone();
+            move |effect| match effect {
+                Sum::Right(Sum::Right(LogEffect::Line)) => {
+                    Ok(Sum::Right(Sum::Right(Log(reads.clone()))))
+                },
+                other => Err(other),
+            }
+        };
+        let block = (|context: Context<Nested>| {
+            move || {
+                for _ in 0..3 {
+                    perform!(Sum::Right(Sum::Right(LogEffect::Line)));
+                    context.take();
+                }
+            }
+        })
+        .into_block()
+        .add_lifted_handler(handler);
+        block.assert_handled().run();
+        // the family of an output is read once, to compare it with the one of
+        // its effect, and only with the diagnostics
+        let expected = if cfg!(feature = "diagnostics") { 3 } else { 0 };
+        assert_eq!(reads.get(), expected);
+    }
+}
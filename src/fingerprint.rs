@@ -0,0 +1,272 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::{Cell, RefCell},
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    ops::{Generator, GeneratorState},
+};
+use super::block::Block;
+
+pub trait EffectFingerprint {
+    fn fingerprint<H>(&self, hasher: &mut H)
+    where
+        H: Hasher;
+}
+
+macro_rules! via_hash {
+    ($($ty:ty),*) => {
+        $(
+        impl EffectFingerprint for $ty {
+            fn fingerprint<H>(&self, hasher: &mut H)
+            where
+                H: Hasher,
+            {
+                Hash::hash(self, hasher)
+            }
+        }
+        )*
+    };
+}
+
+via_hash!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    str,
+    String,
+    IpAddr,
+    SocketAddr
+);
+
+impl<T> EffectFingerprint for &T
+where
+    T: EffectFingerprint + ?Sized,
+{
+    fn fingerprint<H>(&self, hasher: &mut H)
+    where
+        H: Hasher,
+    {
+        (**self).fingerprint(hasher)
+    }
+}
+
+impl<T> EffectFingerprint for Box<T>
+where
+    T: EffectFingerprint + ?Sized,
+{
+    fn fingerprint<H>(&self, hasher: &mut H)
+    where
+        H: Hasher,
+    {
+        (**self).fingerprint(hasher)
+    }
+}
+
+impl<T> EffectFingerprint for [T]
+where
+    T: EffectFingerprint,
+{
+    fn fingerprint<H>(&self, hasher: &mut H)
+    where
+        H: Hasher,
+    {
+        hasher.write_usize(self.len());
+        for item in self {
+            item.fingerprint(hasher);
+        }
+    }
+}
+
+impl<T> EffectFingerprint for Vec<T>
+where
+    T: EffectFingerprint,
+{
+    fn fingerprint<H>(&self, hasher: &mut H)
+    where
+        H: Hasher,
+    {
+        self.as_slice().fingerprint(hasher)
+    }
+}
+
+impl<T> EffectFingerprint for Option<T>
+where
+    T: EffectFingerprint,
+{
+    fn fingerprint<H>(&self, hasher: &mut H)
+    where
+        H: Hasher,
+    {
+        match self {
+            None => hasher.write_u8(0),
+            Some(value) => {
+                hasher.write_u8(1);
+                value.fingerprint(hasher);
+            },
+        }
+    }
+}
+
+// FNV-1a, unlike `DefaultHasher` it is fixed across compiler versions
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    // fixed endianness so the value does not depend on the platform
+    fn write_u16(&mut self, value: u16) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, value: u128) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub(crate) fn of<I>(event: &I) -> u64
+where
+    I: EffectFingerprint,
+{
+    let mut hasher = StableHasher::default();
+    event.fingerprint(&mut hasher);
+    hasher.finish()
+}
+
+fn fold<I>(acc: u64, event: &I) -> u64
+where
+    I: EffectFingerprint,
+{
+    let mut hasher = StableHasher(acc);
+    hasher.write_u64(of(event));
+    hasher.finish()
+}
+
+#[derive(Clone)]
+pub struct Trace<I>(Rc<RefCell<Vec<I>>>);
+
+impl<I> Trace<I> {
+    pub fn events(&self) -> Vec<I>
+    where
+        I: Clone,
+    {
+        self.0.borrow().clone()
+    }
+
+    pub fn fingerprint(&self) -> u64
+    where
+        I: EffectFingerprint,
+    {
+        self.0.borrow().iter().fold(StableHasher::default().0, fold)
+    }
+}
+
+#[derive(Clone)]
+pub struct Fingerprint(Rc<Cell<u64>>);
+
+impl Fingerprint {
+    pub fn value(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+impl<T, G> Block<T, G>
+where
+    G: Unpin + Generator<()>,
+{
+    pub fn traced(
+        self,
+    ) -> (
+        Block<T, impl Unpin + Generator<(), Return = G::Return, Yield = G::Yield>>,
+        Trace<G::Yield>,
+    )
+    where
+        G::Yield: Clone,
+    {
+        let trace = Trace(Rc::default());
+        let context = self.context();
+        let mut s = self;
+        let generator = {
+            let trace = trace.clone();
+            move || loop {
+                match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(effect) => {
+                        trace.0.borrow_mut().push(effect.clone());
+                        yield effect;
+                    },
+                }
+            }
+        };
+        (Block::new(context, generator), trace)
+    }
+
+    // same value as `Trace::fingerprint` without keeping the effects
+    pub fn fingerprinted(
+        self,
+    ) -> (
+        Block<T, impl Unpin + Generator<(), Return = G::Return, Yield = G::Yield>>,
+        Fingerprint,
+    )
+    where
+        G::Yield: EffectFingerprint,
+    {
+        let fingerprint = Fingerprint(Rc::new(Cell::new(StableHasher::default().0)));
+        let context = self.context();
+        let mut s = self;
+        let generator = {
+            let fingerprint = fingerprint.clone();
+            move || loop {
+                match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(effect) => {
+                        fingerprint.0.set(fold(fingerprint.0.get(), &effect));
+                        yield effect;
+                    },
+                }
+            }
+        };
+        (Block::new(context, generator), fingerprint)
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    cell::Cell,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    computation::{Effect, Handler},
+    context::Context,
+};
+
+// the barrier, an effect enum holds it in one of its variants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flush;
+
+// the acknowledgement of the barrier, held by a variant of the output enum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flushed;
+
+pub trait HasFlush
+where
+    Self: Sized,
+{
+    fn flush() -> Self;
+    fn is_flush(&self) -> bool;
+}
+
+pub trait HasFlushed
+where
+    Self: Sized,
+{
+    fn flushed() -> Self;
+    fn is_flushed(&self) -> bool;
+}
+
+// the barriers of the computation, a layer answering effects on its own does
+// not answer a barrier and does not reuse the answers from before one
+#[derive(Default)]
+struct Barriers {
+    in_flight: Cell<bool>,
+    performed: Cell<u64>,
+}
+
+pub(crate) fn barrier_in_flight<T>(context: &Context<T>) -> bool {
+    context
+        .find_extension::<Barriers>()
+        .map_or(false, |barriers| barriers.in_flight.get())
+}
+
+pub(crate) fn barriers_performed<T>(context: &Context<T>) -> u64 {
+    context
+        .find_extension::<Barriers>()
+        .map_or(0, |barriers| barriers.performed.get())
+}
+
+#[doc(hidden)]
+pub fn barrier<E>(context: &Context<E>) -> E::Input
+where
+    E: Effect,
+    E::Input: HasFlush,
+{
+    let barriers = context.extension(Barriers::default);
+    barriers.in_flight.set(true);
+    barriers.performed.set(barriers.performed.get() + 1);
+    E::Input::flush()
+}
+
+// earlier responses left in the queue stay there in their order
+#[doc(hidden)]
+pub fn acknowledged<E>(context: &Context<E>) -> Flushed
+where
+    E: HasFlushed,
+{
+    if let Some(barriers) = context.find_extension::<Barriers>() {
+        barriers.in_flight.set(false);
+    }
+    match context.take_if(E::is_flushed) {
+        Some(_) => Flushed,
+        None => {
+            panic!("the flush barrier is not acknowledged, add `acknowledge_flush` to the block")
+        },
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + HasFlushed,
+    E::Input: HasFlush,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Like `add_handler`, but a barrier that reaches the handler first runs
+    // `Handler::flush`. Every effect the handler gives back there is performed
+    // before it is asked again, its response goes to the orphan callback, and
+    // only then the barrier goes to the next handler.
+    pub fn add_flushing_handler<H>(
+        self,
+        handler: H,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        H: Handler<E>,
+    {
+        let context = self.context();
+        let mut handler = handler;
+        let mut s = self;
+        let generator = move || loop {
+            let effect = match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => effect,
+            };
+            if !effect.is_flush() {
+                match handler.handle(effect) {
+                    Ok(handled) => s.put(handled),
+                    Err(unhandled) => yield unhandled,
+                }
+                continue;
+            }
+            while let Err(held) = handler.flush() {
+                let before = s.context().produced();
+                yield held;
+                for _ in before..s.context().produced() {
+                    if let Some(response) = s.context().take_last() {
+                        s.context().orphan(response);
+                    }
+                }
+            }
+            yield effect;
+        };
+        Block::new(context, generator)
+    }
+
+    // Ends the barrier, the flush that reached this layer passed every layer
+    // and handler below it.
+    pub fn acknowledge_flush(
+        self,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>> {
+        let context = self.context();
+        let mut s = self;
+        let generator = move || loop {
+            match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) if effect.is_flush() => s.put(E::flushed()),
+                GeneratorState::Yielded(effect) => yield effect,
+            }
+        };
+        Block::new(context, generator)
+    }
+}
@@ -0,0 +1,306 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    pin::Pin,
+    ops::{Generator, GeneratorState},
+};
+use super::{ack::Ack, context::Context};
+
+// Transforms the yields of a computation before it becomes a block, the
+// wrappers are generators themselves, so `(|context| computation(context)
+// .map_yield(upgrade)).into_block()` works like the plain computation.
+pub trait GeneratorExt
+where
+    Self: Sized + Unpin + Generator<()>,
+{
+    // like an older effect enum upgraded to the one of the handlers
+    fn map_yield<F, Y>(self, f: F) -> MapYield<Self, F>
+    where
+        F: FnMut(Self::Yield) -> Y,
+    {
+        MapYield { inner: self, f }
+    }
+
+    // A dropped yield resumes the generator right away with nothing put, only
+    // fire-and-forget effects may be dropped. A `perform!` waiting for an
+    // output of a dropped effect finds none.
+    fn filter_yield<P>(self, predicate: P) -> FilterYield<Self, P>
+    where
+        P: FnMut(&Self::Yield) -> bool,
+    {
+        FilterYield {
+            inner: self,
+            predicate,
+            dropped: None,
+        }
+    }
+
+    // like `filter_yield`, a dropped effect is acknowledged as `Ack` gives, so
+    // `perform_ack!` takes the acknowledgement as if a handler put it
+    fn filter_yield_acked<E, P>(self, context: &Context<E>, predicate: P) -> FilterYield<Self, P>
+    where
+        E: Ack<Input = Self::Yield> + 'static,
+        P: FnMut(&Self::Yield) -> bool,
+    {
+        let context = context.clone();
+        let ack = move |effect: &E::Input| {
+            let ack = E::ack_for(effect)
+                .expect("a dropped effect is not acknowledged, mark its output `#[ack(..)]`");
+            context.put(ack);
+        };
+        FilterYield {
+            inner: self,
+            predicate,
+            dropped: Some(Box::new(ack)),
+        }
+    }
+
+    fn inspect<F>(self, f: F) -> Inspect<Self, F>
+    where
+        F: FnMut(&Self::Yield),
+    {
+        Inspect { inner: self, f }
+    }
+}
+
+impl<G> GeneratorExt for G where G: Unpin + Generator<()> {}
+
+pub struct MapYield<G, F> {
+    inner: G,
+    f: F,
+}
+
+impl<G, F, Y> Generator<()> for MapYield<G, F>
+where
+    G: Unpin + Generator<()>,
+    F: Unpin + FnMut(G::Yield) -> Y,
+{
+    type Yield = Y;
+    type Return = G::Return;
+
+    fn resume(self: Pin<&mut Self>, arg: ()) -> GeneratorState<Self::Yield, Self::Return> {
+        let s = self.get_mut();
+        match Pin::new(&mut s.inner).resume(arg) {
+            GeneratorState::Yielded(y) => GeneratorState::Yielded((s.f)(y)),
+            GeneratorState::Complete(r) => GeneratorState::Complete(r),
+        }
+    }
+}
+
+type Dropped<Y> = Box<dyn FnMut(&Y)>;
+
+pub struct FilterYield<G, P>
+where
+    G: Generator<()>,
+{
+    inner: G,
+    predicate: P,
+    // acknowledges a dropped effect
+    dropped: Option<Dropped<G::Yield>>,
+}
+
+impl<G, P> Generator<()> for FilterYield<G, P>
+where
+    G: Unpin + Generator<()>,
+    P: Unpin + FnMut(&G::Yield) -> bool,
+{
+    type Yield = G::Yield;
+    type Return = G::Return;
+
+    fn resume(self: Pin<&mut Self>, arg: ()) -> GeneratorState<Self::Yield, Self::Return> {
+        let s = self.get_mut();
+        loop {
+            match Pin::new(&mut s.inner).resume(arg) {
+                GeneratorState::Yielded(y) if (s.predicate)(&y) => {
+                    return GeneratorState::Yielded(y)
+                },
+                GeneratorState::Yielded(y) => {
+                    if let Some(dropped) = &mut s.dropped {
+                        dropped(&y);
+                    }
+                },
+                GeneratorState::Complete(r) => return GeneratorState::Complete(r),
+            }
+        }
+    }
+}
+
+pub struct Inspect<G, F> {
+    inner: G,
+    f: F,
+}
+
+impl<G, F> Generator<()> for Inspect<G, F>
+where
+    G: Unpin + Generator<()>,
+    F: Unpin + FnMut(&G::Yield),
+{
+    type Yield = G::Yield;
+    type Return = G::Return;
+
+    fn resume(self: Pin<&mut Self>, arg: ()) -> GeneratorState<Self::Yield, Self::Return> {
+        let s = self.get_mut();
+        let state = Pin::new(&mut s.inner).resume(arg);
+        if let GeneratorState::Yielded(y) = &state {
+            (s.f)(y);
+        }
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{Ack, Context, Effect, IntoBlock, perform, perform_ack};
+    use super::GeneratorExt;
+
+    // what the computation was written against
+    #[derive(Debug)]
+    enum OldEffects {
+        Read(u32),
+        Trace(&'static str),
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Read { key: u32, consistent: bool },
+        Trace(&'static str),
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Value(u32),
+        Traced,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl Ack for Outputs {
+        fn ack_for(effect: &Effects) -> Option<Self> {
+            match effect {
+                Effects::Trace(_) => Some(Outputs::Traced),
+                _ => None,
+            }
+        }
+    }
+
+    fn upgrade(effect: OldEffects) -> Effects {
+        match effect {
+            OldEffects::Read(key) => Effects::Read {
+                key,
+                consistent: false,
+            },
+            OldEffects::Trace(line) => Effects::Trace(line),
+        }
+    }
+
+    fn summed(
+        context: Context<Outputs>,
+    ) -> impl Unpin + Generator<(), Yield = OldEffects, Return = u32> {
+        move || {
+            let mut sum = 0;
+            for key in 1..=3 {
+                perform!(OldEffects::Trace("read"));
+                perform!(OldEffects::Read(key));
+                while let Some(output) = context.take() {
+                    if let Outputs::Value(value) = output {
+                        sum += value;
+                    }
+                }
+            }
+            sum
+        }
+    }
+
+    type Log = Rc<RefCell<Vec<Effects>>>;
+
+    fn recording(log: &Log) -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+        let log = log.clone();
+        move |effect| {
+            log.borrow_mut().push(effect.clone());
+            match effect {
+                Effects::Read { key, consistent } => {
+                    assert!(!consistent);
+                    Ok(Outputs::Value(key * 10))
+                },
+                Effects::Trace(_) => Ok(Outputs::Traced),
+            }
+        }
+    }
+
+    #[test]
+    fn upgraded() {
+        let log = Log::default();
+        let total = (|context| summed(context).map_yield(upgrade))
+            .into_block()
+            .add_handler(recording(&log))
+            .assert_handled()
+            .run();
+        assert_eq!(total, 60);
+        assert_eq!(log.borrow().len(), 6);
+    }
+
+    #[test]
+    fn traces_dropped() {
+        let log = Log::default();
+        let total = (|context| {
+            summed(context)
+                .map_yield(upgrade)
+                .filter_yield(|effect| !matches!(effect, Effects::Trace(_)))
+        })
+        .into_block()
+        .add_handler(recording(&log))
+        .assert_handled()
+        .run();
+        assert_eq!(total, 60);
+        assert!(log
+            .borrow()
+            .iter()
+            .all(|effect| matches!(effect, Effects::Read { .. })));
+        assert_eq!(log.borrow().len(), 3);
+    }
+
+    #[test]
+    fn traces_acked() {
+        let log = Log::default();
+        let traced = (|context: Context<Outputs>| {
+            let acks = context.clone();
+            let computation = move || {
+                let first = perform_ack!(Effects::Trace("first"), &context);
+                let second = perform_ack!(Effects::Trace("second"), &context);
+                (first, second)
+            };
+            computation.filter_yield_acked(&acks, |effect| *effect != Effects::Trace("first"))
+        })
+        .into_block()
+        .add_handler(recording(&log))
+        .assert_handled()
+        .run();
+        assert_eq!(
+            (traced.0.matched(), traced.1.matched()),
+            (Some(()), Some(()))
+        );
+        assert_eq!(*log.borrow(), [Effects::Trace("second")]);
+    }
+
+    #[test]
+    fn inspected_once() {
+        let log = Log::default();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        (|context| {
+            let seen = seen.clone();
+            summed(context)
+                .map_yield(upgrade)
+                .inspect(move |effect| seen.borrow_mut().push(effect.clone()))
+        })
+        .into_block()
+        .add_handler(recording(&log))
+        .assert_handled()
+        .run();
+        assert_eq!(*seen.borrow(), *log.borrow());
+        assert_eq!(seen.borrow().len(), 6);
+    }
+}
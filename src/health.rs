@@ -0,0 +1,356 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{error::Error, fmt, mem, ops::Generator};
+use super::{
+    accounting::Counter,
+    block::Block,
+    computation::{Effect, Handler},
+    routing::{self, HandlerTag},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthStatus {
+    Healthy,
+    Degraded(String),
+    Unhealthy(String),
+}
+
+// a handler went from one kind of status to another, the message alone
+// changing is not a transition
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HealthTransition {
+    pub tag: HandlerTag,
+    pub from: HealthStatus,
+    pub to: HealthStatus,
+    pub round: u64,
+}
+
+type OnTransition = Box<dyn FnMut(HealthTransition)>;
+
+pub struct StartupPolicy {
+    proceed_degraded: bool,
+    recheck: Option<(u64, OnTransition)>,
+}
+
+impl StartupPolicy {
+    // a degraded handler aborts the start like an unhealthy one
+    pub fn strict() -> Self {
+        StartupPolicy {
+            proceed_degraded: false,
+            recheck: None,
+        }
+    }
+
+    // a degraded handler is counted in `Counter::DegradedHandlers` and the
+    // computation starts
+    pub fn permissive() -> Self {
+        StartupPolicy {
+            proceed_degraded: true,
+            ..StartupPolicy::strict()
+        }
+    }
+
+    // Checks the handlers again every `rounds` rounds of the tagged handlers,
+    // between two dispatches. Whatever the status, the computation goes on.
+    pub fn recheck_every<F>(self, rounds: u64, on_transition: F) -> Self
+    where
+        F: FnMut(HealthTransition) + 'static,
+    {
+        assert!(rounds > 0, "recheck every zero rounds");
+        StartupPolicy {
+            recheck: Some((rounds, Box::new(on_transition))),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartupError {
+    Unhealthy { tag: HandlerTag, message: String },
+    Degraded { tag: HandlerTag, message: String },
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::Unhealthy { tag, message } => {
+                write!(f, "handler `{}` is unhealthy: {}", tag.name(), message)
+            },
+            StartupError::Degraded { tag, message } => {
+                write!(f, "handler `{}` is degraded: {}", tag.name(), message)
+            },
+        }
+    }
+}
+
+impl Error for StartupError {}
+
+// the last status of every tagged handler, checked by the dispatching layer
+pub(crate) struct Recheck {
+    every: u64,
+    round: u64,
+    statuses: Vec<HealthStatus>,
+    on_transition: OnTransition,
+}
+
+impl Recheck {
+    pub(crate) fn round<'a, E, I>(&mut self, handlers: I)
+    where
+        E: Effect + 'a,
+        I: Iterator<Item = (HandlerTag, &'a mut dyn Handler<E>)>,
+    {
+        self.round += 1;
+        if self.round % self.every != 0 {
+            return;
+        }
+        for ((tag, handler), last) in handlers.zip(self.statuses.iter_mut()) {
+            let status = handler.health_check();
+            let from = mem::replace(last, status.clone());
+            if mem::discriminant(&status) != mem::discriminant(&from) {
+                (self.on_transition)(HealthTransition {
+                    tag,
+                    from,
+                    to: status,
+                    round: self.round,
+                });
+            }
+        }
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + 'static,
+    E::Input: 'static,
+    G: Unpin + Generator<(), Yield = !>,
+{
+    // Initializes the tagged handlers in installation order and checks each
+    // one right after its `init`. An aborted start finishes the handlers
+    // initialized so far in reverse, the computation is never resumed. Once
+    // the computation completes the handlers finish in reverse as well.
+    pub fn run_checked(self, policy: StartupPolicy) -> Result<G::Return, StartupError> {
+        let context = self.context();
+        let StartupPolicy {
+            proceed_degraded,
+            recheck,
+        } = policy;
+        let statuses = routing::with_tagged(&context, |handlers| {
+            let mut statuses = Vec::with_capacity(handlers.len());
+            for (index, (tag, handler)) in handlers.iter_mut().enumerate() {
+                handler.init();
+                let status = handler.health_check();
+                let error = match &status {
+                    HealthStatus::Healthy => None,
+                    HealthStatus::Degraded(_) if proceed_degraded => None,
+                    HealthStatus::Degraded(message) => Some(StartupError::Degraded {
+                        tag: *tag,
+                        message: message.clone(),
+                    }),
+                    HealthStatus::Unhealthy(message) => Some(StartupError::Unhealthy {
+                        tag: *tag,
+                        message: message.clone(),
+                    }),
+                };
+                if let Some(error) = error {
+                    for (_, handler) in handlers[..=index].iter_mut().rev() {
+                        handler.finish();
+                    }
+                    return Err(error);
+                }
+                statuses.push(status);
+            }
+            Ok(statuses)
+        })?;
+        for status in &statuses {
+            if let HealthStatus::Degraded(_) = status {
+                context.account(|a| a.bump(Counter::DegradedHandlers));
+            }
+        }
+        if let Some((every, on_transition)) = recheck {
+            routing::set_recheck(
+                &context,
+                Recheck {
+                    every,
+                    round: 0,
+                    statuses,
+                    on_transition,
+                },
+            );
+        }
+        let r = self.run();
+        routing::with_tagged(&context, |handlers| {
+            for (_, handler) in handlers.iter_mut().rev() {
+                handler.finish();
+            }
+        });
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{Context, Effect, Handler, HandlerTag, IntoBlock};
+    use super::{HealthStatus, HealthTransition, StartupError, StartupPolicy};
+
+    #[derive(Debug)]
+    struct Query(u32);
+
+    #[derive(Debug, PartialEq)]
+    struct Row(u32);
+
+    impl Effect for Row {
+        type Input = Query;
+    }
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    // the status after so many handled queries
+    struct Mock {
+        name: &'static str,
+        log: Log,
+        health: fn(u32) -> HealthStatus,
+        handled: u32,
+    }
+
+    impl Handler<Row> for Mock {
+        fn handle(&mut self, Query(n): Query) -> Result<Row, Query> {
+            self.handled += 1;
+            Ok(Row(n))
+        }
+
+        fn init(&mut self) {
+            self.log.borrow_mut().push(format!("init {}", self.name));
+        }
+
+        fn finish(&mut self) {
+            self.log.borrow_mut().push(format!("finish {}", self.name));
+        }
+
+        fn health_check(&mut self) -> HealthStatus {
+            (self.health)(self.handled)
+        }
+    }
+
+    fn mock(name: &'static str, log: &Log, health: fn(u32) -> HealthStatus) -> Mock {
+        Mock {
+            name,
+            log: log.clone(),
+            health,
+            handled: 0,
+        }
+    }
+
+    fn queries(
+        context: Context<Row>,
+        n: u32,
+        log: Log,
+    ) -> impl Unpin + Generator<(), Yield = Query, Return = u32> {
+        move || {
+            log.borrow_mut().push("resumed".to_string());
+            let mut sum = 0;
+            for i in 0..n {
+                yield Query(i);
+                sum += context.take().map_or(0, |Row(m)| m);
+            }
+            sum
+        }
+    }
+
+    fn healthy(_: u32) -> HealthStatus {
+        HealthStatus::Healthy
+    }
+
+    fn degraded(_: u32) -> HealthStatus {
+        HealthStatus::Degraded("replica lag".to_string())
+    }
+
+    #[test]
+    fn unhealthy_aborts() {
+        let log = Log::default();
+        let result = (|context| queries(context, 3, log.clone()))
+            .into_block()
+            .add_handler_tagged(mock("cache", &log, healthy), HandlerTag::new("cache"))
+            .add_handler_tagged(
+                mock("db", &log, |_| {
+                    HealthStatus::Unhealthy("refused".to_string())
+                }),
+                HandlerTag::new("db"),
+            )
+            .add_handler_tagged(mock("metrics", &log, healthy), HandlerTag::new("metrics"))
+            .assert_handled()
+            .run_checked(StartupPolicy::permissive());
+        let error = result.unwrap_err();
+        assert_eq!(
+            error,
+            StartupError::Unhealthy {
+                tag: HandlerTag::new("db"),
+                message: "refused".to_string(),
+            },
+        );
+        assert_eq!(error.to_string(), "handler `db` is unhealthy: refused");
+        assert_eq!(
+            *log.borrow(),
+            ["init cache", "init db", "finish db", "finish cache"],
+        );
+    }
+
+    #[test]
+    fn degraded_by_policy() {
+        let run = |policy| {
+            let log = Log::default();
+            let result = (|context| queries(context, 3, log.clone()))
+                .into_block()
+                .add_handler_tagged(mock("db", &log, degraded), HandlerTag::new("db"))
+                .assert_handled()
+                .run_checked(policy);
+            (result, log.take())
+        };
+        let (result, log) = run(StartupPolicy::permissive());
+        assert_eq!(result, Ok(3));
+        assert_eq!(log, ["init db", "resumed", "finish db"]);
+        let (result, log) = run(StartupPolicy::strict());
+        assert!(matches!(result, Err(StartupError::Degraded { .. })));
+        assert_eq!(log, ["init db", "finish db"]);
+    }
+
+    #[test]
+    fn transitions() {
+        let log = Log::default();
+        let transitions = Rc::new(RefCell::new(Vec::new()));
+        let policy = StartupPolicy::strict().recheck_every(2, {
+            let transitions = transitions.clone();
+            move |t: HealthTransition| transitions.borrow_mut().push((t.from, t.to, t.round))
+        });
+        // degraded for a while in the middle of the run, the message changes
+        // without a transition
+        let flapping = |handled| match handled {
+            5..=8 => HealthStatus::Degraded(format!("lag {}", handled)),
+            _ => HealthStatus::Healthy,
+        };
+        let sum = (|context| queries(context, 20, log.clone()))
+            .into_block()
+            .add_handler_tagged(mock("db", &log, flapping), HandlerTag::new("db"))
+            .assert_handled()
+            .run_checked(policy)
+            .unwrap();
+        assert_eq!(sum, 190);
+        assert_eq!(
+            *transitions.borrow(),
+            [
+                (
+                    HealthStatus::Healthy,
+                    HealthStatus::Degraded("lag 5".to_string()),
+                    6
+                ),
+                (
+                    HealthStatus::Degraded("lag 7".to_string()),
+                    HealthStatus::Healthy,
+                    10
+                ),
+            ],
+        );
+    }
+}
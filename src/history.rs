@@ -0,0 +1,430 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    ops::{Generator, Range},
+};
+#[cfg(feature = "diagnostics")]
+use std::ops::GeneratorState;
+use super::{block::Block, context::Context};
+#[cfg(feature = "diagnostics")]
+use super::accounting::Category;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Compaction {
+    #[default]
+    PerVariantCounts,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HistoryConfig {
+    pub raw_capacity: usize,
+    pub compaction: Compaction,
+}
+
+impl HistoryConfig {
+    pub fn new(raw_capacity: usize) -> Self {
+        HistoryConfig {
+            raw_capacity,
+            compaction: Compaction::default(),
+        }
+    }
+
+    pub fn compaction(self, compaction: Compaction) -> Self {
+        HistoryConfig { compaction, ..self }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HistoryEntry {
+    pub round: u64,
+    pub effect: String,
+    // the sequence number of the output the performer took last, and the ones
+    // of the outputs put while the effect was handled, see `causality`
+    pub cause: Option<u64>,
+    pub outputs: Range<u64>,
+}
+
+struct Inner {
+    #[cfg_attr(not(feature = "diagnostics"), allow(dead_code))]
+    config: HistoryConfig,
+    raw: VecDeque<HistoryEntry>,
+    counters: BTreeMap<String, u64>,
+    rounds: u64,
+}
+
+#[cfg(feature = "diagnostics")]
+impl Inner {
+    fn record(&mut self, effect: String, cause: Option<u64>, sequence: u64) {
+        let round = self.rounds;
+        let outputs = (sequence + 1)..(sequence + 1);
+        self.raw.push_back(HistoryEntry {
+            round,
+            effect,
+            cause,
+            outputs,
+        });
+        while self.raw.len() > self.config.raw_capacity {
+            let old = self.raw.pop_front().expect("raw history is not empty");
+            match self.config.compaction {
+                Compaction::PerVariantCounts => {
+                    *self.counters.entry(variant(&old.effect)).or_default() += 1;
+                },
+            }
+        }
+    }
+
+    // the effect of this round is handled, unless it is compacted already
+    fn answered(&mut self, sequence: u64) {
+        let round = self.rounds;
+        if let Some(entry) = self.raw.back_mut().filter(|e| e.round == round) {
+            entry.outputs.end = sequence + 1;
+        }
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+fn variant(effect: &str) -> String {
+    effect
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_' || *c == ':')
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct History(Rc<RefCell<Inner>>);
+
+#[cfg(feature = "diagnostics")]
+#[derive(Default)]
+struct Installed(RefCell<Option<History>>);
+
+impl History {
+    fn new(config: HistoryConfig) -> Self {
+        History(Rc::new(RefCell::new(Inner {
+            config,
+            raw: VecDeque::with_capacity(config.raw_capacity + 1),
+            counters: BTreeMap::new(),
+            rounds: 0,
+        })))
+    }
+
+    pub fn raw(&self) -> Vec<HistoryEntry> {
+        self.0.borrow().raw.iter().cloned().collect()
+    }
+
+    pub fn counters(&self) -> BTreeMap<String, u64> {
+        self.0.borrow().counters.clone()
+    }
+
+    pub fn rounds(&self) -> u64 {
+        self.0.borrow().rounds
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn export<W>(&self, w: W) -> std::io::Result<()>
+    where
+        W: std::io::Write,
+    {
+        self.export_with(w, super::codec::JsonCodec)
+    }
+
+    // the same summary in another encoding
+    #[cfg(feature = "serde")]
+    pub fn export_with<W, C>(&self, w: W, codec: C) -> std::io::Result<()>
+    where
+        W: std::io::Write,
+        C: super::codec::Codec,
+    {
+        let mut w = w;
+        let inner = self.0.borrow();
+        let raw = inner
+            .raw
+            .iter()
+            .map(|e| {
+                serde_json::json!({
+                    "round": e.round,
+                    "effect": e.effect,
+                    "cause": e.cause,
+                    "outputs": [e.outputs.start, e.outputs.end],
+                })
+            })
+            .collect::<Vec<_>>();
+        let summary = serde_json::json!({
+            "rounds": inner.rounds,
+            "counters": inner.counters,
+            "raw": raw,
+        });
+        let mut bytes = Vec::new();
+        codec
+            .encode(&summary, &mut bytes)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        w.write_all(&bytes)
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+pub(crate) fn installed<T>(context: &Context<T>) -> Option<History> {
+    context
+        .find_extension::<Installed>()
+        .and_then(|installed| installed.0.borrow().clone())
+}
+
+#[cfg(not(feature = "diagnostics"))]
+pub(crate) fn installed<T>(context: &Context<T>) -> Option<History> {
+    let _ = context;
+    None
+}
+
+#[cfg(feature = "diagnostics")]
+impl<T, G> Block<T, G>
+where
+    G: Unpin + Generator<()>,
+    G::Yield: fmt::Debug,
+{
+    pub fn with_history(
+        self,
+        config: HistoryConfig,
+    ) -> (
+        Block<T, impl Unpin + Generator<(), Return = G::Return, Yield = G::Yield>>,
+        History,
+    ) {
+        let history = History::new(config);
+        let context = self.context();
+        *context.extension(Installed::default).0.borrow_mut() = Some(history.clone());
+        let mut s = self;
+        let generator = {
+            let history = history.clone();
+            move || loop {
+                history.0.borrow_mut().rounds += 1;
+                match s.resume() {
+                    GeneratorState::Complete(r) => {
+                        // the entries stay with the handle, the block holds none
+                        s.context().account(|a| a.set(Category::History, 0));
+                        return r;
+                    },
+                    GeneratorState::Yielded(effect) => {
+                        history.0.borrow_mut().record(
+                            format!("{:?}", effect),
+                            s.context().cause(),
+                            s.context().sequence(),
+                        );
+                        let entries = history.0.borrow().raw.len();
+                        s.context().account(|a| a.set(Category::History, entries));
+                        yield effect;
+                        history.0.borrow_mut().answered(s.context().sequence());
+                    },
+                }
+            }
+        };
+        (Block::new(context, generator), history)
+    }
+}
+
+// without diagnostics nothing is recorded and no panic hook is installed
+#[cfg(not(feature = "diagnostics"))]
+impl<T, G> Block<T, G>
+where
+    G: Unpin + Generator<()>,
+    G::Yield: fmt::Debug,
+{
+    pub fn with_history(self, config: HistoryConfig) -> (Self, History) {
+        (self, History::new(config))
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn on_panic_export<P>(self, path: P) -> Self
+    where
+        P: Into<std::path::PathBuf>,
+    {
+        let _ = path;
+        self
+    }
+}
+
+#[cfg(all(feature = "serde", feature = "diagnostics"))]
+mod panic_export {
+    use std::{
+        cell::RefCell,
+        fs::File,
+        panic::{self, PanicInfo},
+        path::PathBuf,
+        sync::Arc,
+        ops::{Generator, GeneratorState},
+    };
+    use crate::block::Block;
+    use super::{History, Installed};
+
+    type Hook = Box<dyn Fn(&PanicInfo<'_>) + Sync + Send + 'static>;
+
+    thread_local! {
+        static EXPORT: RefCell<Vec<(History, PathBuf)>> = RefCell::new(Vec::new());
+    }
+
+    struct HookGuard(Option<Arc<Hook>>);
+
+    impl HookGuard {
+        fn install(history: History, path: PathBuf) -> Self {
+            EXPORT.with(|e| e.borrow_mut().push((history, path)));
+            let previous = Arc::new(panic::take_hook());
+            let hook = previous.clone();
+            panic::set_hook(Box::new(move |info| {
+                EXPORT.with(|e| {
+                    if let Ok(exports) = e.try_borrow() {
+                        for (history, path) in exports.iter() {
+                            if let Ok(file) = File::create(path) {
+                                let _ = history.export(file);
+                            }
+                        }
+                    }
+                });
+                hook(info)
+            }));
+            HookGuard(Some(previous))
+        }
+    }
+
+    impl Drop for HookGuard {
+        fn drop(&mut self) {
+            EXPORT.with(|e| e.borrow_mut().pop());
+            // the hook cannot be replaced while unwinding, it stays as a passthrough
+            if std::thread::panicking() {
+                return;
+            }
+            if let Some(previous) = self.0.take() {
+                // dropping our hook releases its reference to the previous one
+                drop(panic::take_hook());
+                match Arc::try_unwrap(previous) {
+                    Ok(previous) => panic::set_hook(previous),
+                    Err(previous) => panic::set_hook(Box::new(move |info| previous(info))),
+                }
+            }
+        }
+    }
+
+    impl<T, G> Block<T, G>
+    where
+        G: Unpin + Generator<()>,
+    {
+        pub fn on_panic_export<P>(
+            self,
+            path: P,
+        ) -> Block<T, impl Unpin + Generator<(), Return = G::Return, Yield = G::Yield>>
+        where
+            P: Into<PathBuf>,
+        {
+            let path = path.into();
+            let context = self.context();
+            let history = context
+                .extension(Installed::default)
+                .0
+                .borrow()
+                .clone()
+                .expect("install `with_history` before `on_panic_export`");
+            let mut s = self;
+            let generator = move || {
+                let _guard = HookGuard::install(history, path);
+                loop {
+                    match s.resume() {
+                        GeneratorState::Complete(r) => return r,
+                        GeneratorState::Yielded(effect) => yield effect,
+                    }
+                }
+            };
+            Block::new(context, generator)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "diagnostics"))]
+mod tests {
+    use std::ops::Generator;
+    use crate::{Context, Effect, IntoBlock};
+    use super::{HistoryConfig, HistoryEntry, Compaction};
+
+    #[derive(Debug)]
+    enum Effects {
+        Read(u32),
+        Write(usize),
+        Crash,
+    }
+
+    struct Outputs;
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    fn workload(
+        _: Context<Outputs>,
+        crash: bool,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            for i in 0..5 {
+                yield Effects::Read(i);
+                yield Effects::Write(1);
+            }
+            if crash {
+                yield Effects::Crash;
+            }
+        }
+    }
+
+    fn config() -> HistoryConfig {
+        HistoryConfig::new(3).compaction(Compaction::PerVariantCounts)
+    }
+
+    fn handler(effect: Effects) -> Result<Outputs, Effects> {
+        match effect {
+            Effects::Crash => panic!("deliberate"),
+            _ => Ok(Outputs),
+        }
+    }
+
+    #[test]
+    fn compaction() {
+        let (block, history) = (|context| workload(context, false))
+            .into_block()
+            .with_history(config());
+        block.add_handler(handler).assert_handled().run();
+        let counters = history.counters();
+        assert_eq!(counters.get("Read"), Some(&4));
+        assert_eq!(counters.get("Write"), Some(&3));
+        let effects = history
+            .raw()
+            .into_iter()
+            .map(|HistoryEntry { effect, .. }| effect)
+            .collect::<Vec<_>>();
+        assert_eq!(effects, vec!["Write(1)", "Read(4)", "Write(1)"]);
+        assert_eq!(history.rounds(), 11);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn panic_export() {
+        let path = std::env::temp_dir().join(format!("aeiou-history-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (block, _) = (|context| workload(context, true))
+            .into_block()
+            .with_history(config());
+        let block = block.on_panic_export(&path);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            block.add_handler(handler).assert_handled().run()
+        }));
+        assert!(result.is_err());
+        let export: serde_json::Value =
+            serde_json::from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(export["counters"]["Read"], 4);
+        assert_eq!(export["counters"]["Write"], 4);
+        assert_eq!(export["raw"][2]["effect"], "Crash");
+        assert_eq!(export["rounds"], 11);
+    }
+}
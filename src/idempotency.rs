@@ -0,0 +1,310 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::VecDeque,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    accounting::Accounting,
+    block::Block,
+    computation::{Effect, Handler},
+    health::HealthStatus,
+    plugin::{ResourceKey, Resources},
+};
+
+// the sequence number of a logical effect, counted from the start of the
+// computation, so a resumed run gives the replayed effects the same tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IdempotencyToken(pub u64);
+
+// whether delivering the effect again does no more than delivering it once,
+// `retry` and `memoize` deliver an effect that is not exactly once
+pub trait Idempotent {
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Gives every effect the computation yields a token, the handlers outside
+    // see it in `Handler::handle_idempotent`. Layers outside this one that
+    // deliver the effect again, like `retry`, deliver it with the same token.
+    pub fn idempotency_tokens(
+        self,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>> {
+        let context = self.context();
+        let mut sequence = 0;
+        let mut s = self;
+        let generator = move || loop {
+            match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => {
+                    s.context().set_token(Some(IdempotencyToken(sequence)));
+                    sequence += 1;
+                    yield effect;
+                    s.context().set_token(None);
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+// Remembers the responses of the last `window` tokens, a delivery with a
+// remembered token gets the same response and the inner handler is not asked.
+// Effects without a token always go to the inner handler.
+pub struct IdempotentHandler<E, H> {
+    inner: H,
+    window: usize,
+    responses: VecDeque<(IdempotencyToken, E)>,
+    accounting: Option<Accounting>,
+}
+
+impl<E, H> IdempotentHandler<E, H>
+where
+    E: Effect + Clone,
+    H: Handler<E>,
+{
+    pub fn new(inner: H, window: usize) -> Self {
+        IdempotentHandler {
+            inner,
+            window,
+            responses: VecDeque::new(),
+            accounting: None,
+        }
+    }
+
+    // evictions are counted as `Counter::Evicted`
+    pub fn report_to(self, accounting: &Accounting) -> Self {
+        IdempotentHandler {
+            accounting: Some(accounting.clone()),
+            ..self
+        }
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    fn evict(&mut self) {
+        while self.responses.len() > self.window {
+            self.responses.pop_front();
+            if let Some(accounting) = &self.accounting {
+                count_eviction(accounting);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+fn count_eviction(accounting: &Accounting) {
+    accounting.bump(super::accounting::Counter::Evicted);
+}
+
+#[cfg(not(feature = "diagnostics"))]
+#[inline(always)]
+fn count_eviction(accounting: &Accounting) {
+    let _ = accounting;
+}
+
+impl<E, H> Handler<E> for IdempotentHandler<E, H>
+where
+    E: Effect + Clone,
+    H: Handler<E>,
+{
+    fn handle(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        self.inner.handle(effect)
+    }
+
+    fn handle_idempotent(
+        &mut self,
+        token: IdempotencyToken,
+        effect: E::Input,
+    ) -> Result<E, E::Input> {
+        // the least recently delivered token is evicted first
+        if let Some(position) = self.responses.iter().position(|(t, _)| *t == token) {
+            let remembered = self.responses.remove(position).expect("found above");
+            let response = remembered.1.clone();
+            self.responses.push_back(remembered);
+            return Ok(response);
+        }
+        let response = self.inner.handle_idempotent(token, effect)?;
+        self.responses.push_back((token, response.clone()));
+        self.evict();
+        Ok(response)
+    }
+
+    fn init(&mut self) {
+        self.inner.init()
+    }
+
+    fn finish(&mut self) {
+        self.inner.finish()
+    }
+
+    fn health_check(&mut self) -> HealthStatus {
+        self.inner.health_check()
+    }
+
+    fn provides(&self) -> &'static [ResourceKey] {
+        self.inner.provides()
+    }
+
+    fn requires(&self) -> &'static [ResourceKey] {
+        self.inner.requires()
+    }
+
+    fn init_with(&mut self, resources: &mut Resources) {
+        self.inner.init_with(resources)
+    }
+
+    fn flush(&mut self) -> Result<(), E::Input> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, time::Instant};
+    use crate::{
+        Accounting, Context, Counter, Effect, Handler, IntoBlock, perform, deadline::HasDeadline,
+    };
+    use super::{Idempotent, IdempotencyToken, IdempotentHandler};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Send(&'static str),
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Outputs {
+        Sent(u32),
+        Failed,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl Idempotent for Effects {}
+
+    impl HasDeadline for Effects {
+        fn deadline(&self) -> Option<Instant> {
+            None
+        }
+
+        fn set_deadline(&mut self, _: Instant) {}
+    }
+
+    fn sender(
+        sent: &Rc<RefCell<Vec<&'static str>>>,
+    ) -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+        let sent = sent.clone();
+        move |Effects::Send(message)| {
+            sent.borrow_mut().push(message);
+            Ok(Outputs::Sent(sent.borrow().len() as u32))
+        }
+    }
+
+    // the first acknowledgement is lost on the way back
+    struct LostAck<H> {
+        inner: H,
+        seen: Rc<RefCell<Vec<Outputs>>>,
+    }
+
+    impl<H> Handler<Outputs> for LostAck<H>
+    where
+        H: Handler<Outputs>,
+    {
+        fn handle(&mut self, effect: Effects) -> Result<Outputs, Effects> {
+            self.inner.handle(effect)
+        }
+
+        fn handle_idempotent(
+            &mut self,
+            token: IdempotencyToken,
+            effect: Effects,
+        ) -> Result<Outputs, Effects> {
+            let output = self.inner.handle_idempotent(token, effect)?;
+            self.seen.borrow_mut().push(output.clone());
+            if self.seen.borrow().len() == 1 {
+                Ok(Outputs::Failed)
+            } else {
+                Ok(output)
+            }
+        }
+    }
+
+    #[test]
+    fn retried_once() {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let received = (|context: Context<Outputs>| {
+            move || {
+                perform!(Effects::Send("a"));
+                context.take()
+            }
+        })
+        .into_block()
+        .idempotency_tokens()
+        .retry(3, |output| *output == Outputs::Failed)
+        .add_handler(LostAck {
+            inner: IdempotentHandler::new(sender(&sent), 4),
+            seen: seen.clone(),
+        })
+        .assert_handled()
+        .run();
+        assert_eq!(*sent.borrow(), ["a"]);
+        assert_eq!(*seen.borrow(), [Outputs::Sent(1), Outputs::Sent(1)]);
+        assert_eq!(received, Some(Outputs::Sent(1)));
+    }
+
+    #[test]
+    fn fresh_token() {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let mut handler = IdempotentHandler::new(sender(&sent), 4);
+        let first = handler.handle_idempotent(IdempotencyToken(0), Effects::Send("a"));
+        let again = handler.handle_idempotent(IdempotencyToken(0), Effects::Send("a"));
+        let fresh = handler.handle_idempotent(IdempotencyToken(1), Effects::Send("a"));
+        assert_eq!(first, again);
+        assert_eq!(fresh, Ok(Outputs::Sent(2)));
+        assert_eq!(*sent.borrow(), ["a", "a"]);
+    }
+
+    #[test]
+    fn eviction_boundary() {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let accounting = Accounting::default();
+        let mut handler = IdempotentHandler::new(sender(&sent), 2).report_to(&accounting);
+        for token in 0..2 {
+            handler
+                .handle_idempotent(IdempotencyToken(token), Effects::Send("a"))
+                .unwrap();
+        }
+        // the window is full, nothing is evicted yet and 0 is the most recent now
+        handler
+            .handle_idempotent(IdempotencyToken(0), Effects::Send("a"))
+            .unwrap();
+        assert_eq!(sent.borrow().len(), 2);
+        handler
+            .handle_idempotent(IdempotencyToken(2), Effects::Send("a"))
+            .unwrap();
+        // 1 was evicted, 0 is still there
+        handler
+            .handle_idempotent(IdempotencyToken(0), Effects::Send("a"))
+            .unwrap();
+        assert_eq!(sent.borrow().len(), 3);
+        handler
+            .handle_idempotent(IdempotencyToken(1), Effects::Send("a"))
+            .unwrap();
+        assert_eq!(sent.borrow().len(), 4);
+        if cfg!(feature = "diagnostics") {
+            assert_eq!(accounting.report().count(Counter::Evicted), 2);
+        }
+    }
+}
@@ -0,0 +1,356 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    fmt,
+};
+use super::{
+    accounting::{Accounting, Counter},
+    computation::{Effect, EffectName, Handler},
+    context::Context,
+    meta::WithMeta,
+};
+
+// Who performs the effects of a block, given when the block is built. Tasks
+// run as the identity of the block spawning them with their name appended,
+// `tenant-a/task-42`. Nothing inside the computation can change it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Identity(Arc<str>);
+
+impl Identity {
+    pub fn new<S>(name: S) -> Self
+    where
+        S: Into<Arc<str>>,
+    {
+        Identity(name.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub(crate) fn child(&self, name: &str) -> Self {
+        Identity(format!("{}/{}", self.0, name).into())
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Default)]
+struct Assigned(RefCell<Option<Identity>>);
+
+impl<T> Context<T> {
+    // read only, the block is given its identity when it is built
+    pub fn identity(&self) -> Option<Identity> {
+        self.find_extension::<Assigned>()?.0.borrow().clone()
+    }
+
+    pub(crate) fn set_identity(&self, identity: Option<Identity>) {
+        *self.extension(Assigned::default).0.borrow_mut() = identity;
+    }
+}
+
+// the identity a task runs as, without a name it keeps the one of the parent
+pub(crate) fn task_identity(parent: Option<Identity>, name: Option<String>) -> Option<Identity> {
+    match (parent, name) {
+        (Some(parent), Some(name)) => Some(parent.child(&name)),
+        (None, Some(name)) => Some(Identity::new(name)),
+        (parent, None) => parent,
+    }
+}
+
+pub trait HasDenied
+where
+    Self: Effect,
+{
+    fn denied(effect: Self::Input, identity: Identity) -> Self;
+}
+
+// Denies effects by the identity in their metadata and the variant name,
+// the others are declined to the handlers added after it. It is added right
+// after `stamp_meta`, effects without an identity are never denied.
+#[derive(Default)]
+pub struct IdentityPolicy {
+    denied: BTreeMap<Identity, BTreeSet<&'static str>>,
+    accounting: Option<Accounting>,
+}
+
+impl IdentityPolicy {
+    pub fn new() -> Self {
+        IdentityPolicy::default()
+    }
+
+    pub fn deny(mut self, identity: Identity, variants: &[&'static str]) -> Self {
+        self.denied
+            .entry(identity)
+            .or_default()
+            .extend(variants.iter().cloned());
+        self
+    }
+
+    // bumps `Counter::Denied` of the identity
+    pub fn report_to(self, accounting: &Accounting) -> Self {
+        IdentityPolicy {
+            accounting: Some(accounting.clone()),
+            ..self
+        }
+    }
+
+    pub fn is_denied(&self, identity: &Identity, variant: &str) -> bool {
+        self.denied
+            .get(identity)
+            .map_or(false, |variants| variants.contains(variant))
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+fn count(accounting: &Accounting, identity: &Identity, counter: Counter) {
+    accounting.bump_for(identity, counter);
+}
+
+#[cfg(not(feature = "diagnostics"))]
+#[inline(always)]
+fn count(accounting: &Accounting, identity: &Identity, counter: Counter) {
+    let _ = (accounting, identity, counter);
+}
+
+impl<E, I> Handler<E> for IdentityPolicy
+where
+    E: Effect<Input = WithMeta<I>> + HasDenied,
+    I: EffectName,
+{
+    fn handle(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        let identity = match effect.meta.identity() {
+            Some(identity) if self.is_denied(identity, effect.effect.effect_name()) => {
+                identity.clone()
+            },
+            _ => return Err(effect),
+        };
+        if let Some(accounting) = &self.accounting {
+            count(accounting, &identity, Counter::Denied);
+        }
+        Ok(E::denied(effect, identity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, collections::BTreeMap, ops::Generator};
+    use crate::{
+        Accounting, Context, Counter, Effect, EffectName, IntoBlock, WithMeta, perform,
+        perform_task,
+        tasks::{HasTaskFailed, Request, SpawnOptions, TaskFailed, TaskId},
+    };
+    use super::{HasDenied, Identity, IdentityPolicy};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Read,
+        Write(u32),
+    }
+
+    impl EffectName for Effects {
+        fn effect_name(&self) -> &'static str {
+            match self {
+                Effects::Read => "Read",
+                Effects::Write(_) => "Write",
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Done,
+        Denied(Identity),
+        Failed(TaskFailed<u32>),
+    }
+
+    impl Effect for Outputs {
+        type Input = WithMeta<Effects>;
+    }
+
+    impl HasDenied for Outputs {
+        fn denied(effect: WithMeta<Effects>, identity: Identity) -> Self {
+            let _ = effect;
+            Outputs::Denied(identity)
+        }
+    }
+
+    impl HasTaskFailed<u32> for Outputs {
+        fn task_failed(failed: TaskFailed<u32>) -> Self {
+            Outputs::Failed(failed)
+        }
+    }
+
+    type Counts = Rc<RefCell<BTreeMap<Option<Identity>, u32>>>;
+
+    fn count(counts: &Counts, effect: &WithMeta<Effects>) {
+        let identity = effect.meta.identity().cloned();
+        *counts.borrow_mut().entry(identity).or_default() += 1;
+    }
+
+    fn writer(
+        context: Context<Outputs>,
+        writes: u32,
+    ) -> impl Unpin + Generator<(), Yield = WithMeta<Effects>, Return = Vec<Outputs>> {
+        move || {
+            let mut outputs = Vec::new();
+            perform!(WithMeta::new(Effects::Read));
+            outputs.extend(context.take());
+            for i in 0..writes {
+                perform!(WithMeta::new(Effects::Write(i)));
+                outputs.extend(context.take());
+            }
+            outputs
+        }
+    }
+
+    #[test]
+    fn shared_counting_handler() {
+        let counts = Counts::default();
+        // one handler for all the blocks
+        let handler = |effect: WithMeta<Effects>| {
+            count(&counts, &effect);
+            Ok::<_, WithMeta<Effects>>(Outputs::Done)
+        };
+        let a = Identity::new("tenant-a");
+        let b = Identity::new("tenant-b");
+        for (identity, writes) in [(&a, 1), (&b, 3)] {
+            (move |context: Context<Outputs>| writer(context, writes))
+                .into_block_as(identity.clone())
+                .stamp_meta()
+                .add_handler(handler)
+                .assert_handled()
+                .run();
+        }
+        // a block built without one performs as nobody
+        (|context: Context<Outputs>| writer(context, 0))
+            .into_block()
+            .stamp_meta()
+            .add_handler(handler)
+            .assert_handled()
+            .run();
+        let counts = counts.borrow();
+        assert_eq!(counts.get(&Some(a)), Some(&2));
+        assert_eq!(counts.get(&Some(b)), Some(&4));
+        assert_eq!(counts.get(&None), Some(&1));
+    }
+
+    #[test]
+    fn policy_by_identity() {
+        let a = Identity::new("tenant-a");
+        let b = Identity::new("tenant-b");
+        let accounting = Accounting::default();
+        let run = |identity: &Identity| {
+            let policy = IdentityPolicy::new()
+                .deny(b.clone(), &["Write"])
+                .report_to(&accounting);
+            (|context: Context<Outputs>| writer(context, 2))
+                .into_block_as(identity.clone())
+                .stamp_meta()
+                .add_handler(policy)
+                .add_handler(|_: WithMeta<Effects>| Ok(Outputs::Done))
+                .assert_handled()
+                .run()
+        };
+        assert_eq!(run(&a), [Outputs::Done, Outputs::Done, Outputs::Done]);
+        assert_eq!(
+            run(&b),
+            [
+                Outputs::Done,
+                Outputs::Denied(b.clone()),
+                Outputs::Denied(b.clone()),
+            ],
+        );
+        let report = accounting.report();
+        let denied = if cfg!(feature = "diagnostics") { 2 } else { 0 };
+        assert_eq!(report.count_for(&a, Counter::Denied), 0);
+        assert_eq!(report.count_for(&b, Counter::Denied), denied);
+    }
+
+    #[derive(Debug, Clone)]
+    struct Job(u32);
+
+    impl TaskId for Job {
+        type Id = u32;
+
+        fn task_id(&self) -> u32 {
+            self.0
+        }
+
+        fn task_name(&self) -> Option<String> {
+            Some(format!("task-{}", self.0))
+        }
+    }
+
+    #[derive(Debug)]
+    enum Req {
+        Spawn(Job),
+        Effect(WithMeta<Effects>),
+    }
+
+    impl Request for Req {
+        type Task = Job;
+        type Effect = WithMeta<Effects>;
+
+        fn is_task(self) -> Result<Job, Self> {
+            match self {
+                Req::Spawn(job) => Ok(job),
+                other => Err(other),
+            }
+        }
+
+        fn is_effect(self) -> Result<WithMeta<Effects>, Self> {
+            match self {
+                Req::Effect(effect) => Ok(effect),
+                other => Err(other),
+            }
+        }
+
+        fn meta_mut(&mut self) -> Option<&mut crate::EffectMeta> {
+            match self {
+                Req::Effect(effect) => Some(&mut effect.meta),
+                Req::Spawn(_) => None,
+            }
+        }
+    }
+
+    #[test]
+    fn task_attribution() {
+        let counts = Counts::default();
+        let root = |_: Context<Outputs>| {
+            move || {
+                yield Req::Spawn(Job(42));
+                yield Req::Effect(WithMeta::new(Effects::Read));
+            }
+        };
+        let task = |job: Job, context: Context<Outputs>| {
+            move || {
+                let expected = Identity::new(format!("tenant-a/task-{}", job.0));
+                assert_eq!(context.identity(), Some(expected));
+                perform_task!(Req::Effect(WithMeta::new(Effects::Write(job.0))));
+                Ok::<_, ()>(())
+            }
+        };
+        root.into_block_as(Identity::new("tenant-a"))
+            .spawn_supervised(SpawnOptions::default(), task)
+            .add_handler_(|effect: WithMeta<Effects>| {
+                count(&counts, &effect);
+                Ok::<_, !>(Outputs::Done)
+            })
+            .run();
+        let counts = counts.borrow();
+        assert_eq!(counts.get(&Some(Identity::new("tenant-a"))), Some(&1));
+        assert_eq!(
+            counts.get(&Some(Identity::new("tenant-a/task-42"))),
+            Some(&1),
+        );
+    }
+}
@@ -0,0 +1,529 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    any::Any,
+    rc::Rc,
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    computation::{Effect, Handler},
+    context::Context,
+    fingerprint::{self, EffectFingerprint},
+};
+
+// what a memoized call performed, and the result it returned
+struct Entry<E>
+where
+    E: Effect,
+{
+    trace: Vec<(E::Input, Option<E>)>,
+    result: Rc<dyn Any>,
+}
+
+// The results of `memoized_call!` kept from one run to the next, a block uses
+// it once `with_incremental` installs it. An entry is keyed by the key given
+// to the call, it holds every effect the call performed and its response.
+pub struct IncrementalCache<E>(Rc<RefCell<BTreeMap<u64, Entry<E>>>>)
+where
+    E: Effect;
+
+impl<E> Clone for IncrementalCache<E>
+where
+    E: Effect,
+{
+    fn clone(&self) -> Self {
+        IncrementalCache(self.0.clone())
+    }
+}
+
+impl<E> Default for IncrementalCache<E>
+where
+    E: Effect,
+{
+    fn default() -> Self {
+        IncrementalCache(Rc::default())
+    }
+}
+
+impl<E> IncrementalCache<E>
+where
+    E: Effect,
+{
+    pub fn new() -> Self {
+        IncrementalCache::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+}
+
+struct Installed<E>(IncrementalCache<E>)
+where
+    E: Effect;
+
+// the old response of the effect performed again for the validation
+struct Validating<E>(RefCell<Option<E>>);
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + 'static,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // the cache `memoized_call!` looks in, without it the calls always run
+    pub fn with_incremental(self, cache: &IncrementalCache<E>) -> Self {
+        let cache = cache.clone();
+        self.context().extension(move || Installed(cache));
+        self
+    }
+}
+
+pub enum Validation<E> {
+    Unchanged,
+    Changed(E),
+}
+
+// A handler that tells whether the response to an effect changed cheaper than
+// by answering it again, like by the modification time of a file.
+// `memoized_call!` asks it only for the effects it performs again to check
+// a cached result.
+pub trait ValidatingHandler<E>
+where
+    Self: Handler<E>,
+    E: Effect,
+{
+    // by default the effect is answered again and the responses are compared
+    fn validate(&mut self, effect: E::Input, old: &E) -> Result<Validation<E>, E::Input> {
+        let _ = old;
+        self.handle(effect).map(Validation::Changed)
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + Clone + 'static,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Like `add_handler`, but an effect performed again to check a cached
+    // result goes to `ValidatingHandler::validate`.
+    pub fn add_validating_handler<H>(
+        self,
+        handler: H,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        H: ValidatingHandler<E>,
+    {
+        let context = self.context();
+        let mut handler = handler;
+        let mut s = self;
+        let generator = move || loop {
+            let effect = match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => effect,
+            };
+            let old = s
+                .context()
+                .find_extension::<Validating<E>>()
+                .and_then(|validating| validating.0.borrow().clone());
+            let handled = match old {
+                None => handler.handle(effect),
+                Some(old) => match handler.validate(effect, &old) {
+                    Ok(Validation::Unchanged) => Ok(old),
+                    Ok(Validation::Changed(new)) => Ok(new),
+                    Err(effect) => Err(effect),
+                },
+            };
+            match handled {
+                Ok(handled) => s.put(handled),
+                Err(unhandled) => yield unhandled,
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+// the state of one `memoized_call!`, first it performs again what the cached
+// call did, then it runs the call if anything changed
+#[doc(hidden)]
+pub struct MemoizedCall<E, R>
+where
+    E: Effect,
+{
+    context: Context<E>,
+    key: u64,
+    cache: Option<IncrementalCache<E>>,
+    pending: VecDeque<(E::Input, Option<E>)>,
+    // the effect performed and the responses produced before it
+    current: Option<(E::Input, Option<E>, u64)>,
+    changed: bool,
+    trace: Vec<(E::Input, Option<E>)>,
+    result: Option<R>,
+}
+
+impl<E, R> MemoizedCall<E, R>
+where
+    E: Effect + Clone + EffectFingerprint + 'static,
+    E::Input: Clone,
+    R: Clone + 'static,
+{
+    pub fn new<K>(context: &Context<E>, key: &K) -> Self
+    where
+        K: EffectFingerprint,
+    {
+        let key = fingerprint::of(key);
+        let cache = context
+            .find_extension::<Installed<E>>()
+            .map(|installed| installed.0.clone());
+        // a result of another type under the key is a miss
+        let cached = cache.as_ref().and_then(|cache| {
+            let entries = cache.0.borrow();
+            let entry = entries.get(&key)?;
+            let result = entry.result.downcast_ref::<R>()?.clone();
+            Some((entry.trace.iter().cloned().collect(), result))
+        });
+        let (pending, result, changed) = match cached {
+            Some((trace, result)) => (trace, Some(result), false),
+            None => (VecDeque::new(), None, true),
+        };
+        MemoizedCall {
+            context: context.clone(),
+            key,
+            cache,
+            pending,
+            current: None,
+            changed,
+            trace: Vec::new(),
+            result,
+        }
+    }
+
+    // the next effect to perform again, `None` once the cached result is
+    // checked or turned out stale
+    pub fn next_validation(&mut self) -> Option<E::Input> {
+        if self.changed {
+            return None;
+        }
+        let (effect, old) = self.pending.pop_front()?;
+        if let Some(old) = &old {
+            let validating = self.context.extension(|| Validating(RefCell::new(None)));
+            *validating.0.borrow_mut() = Some(old.clone());
+        }
+        let before = self.context.produced();
+        self.current = Some((effect.clone(), old, before));
+        Some(effect)
+    }
+
+    pub fn validated(&mut self) {
+        if let Some(validating) = self.context.find_extension::<Validating<E>>() {
+            validating.0.borrow_mut().take();
+        }
+        if let Some((_, old, before)) = self.current.take() {
+            let new = if self.context.produced() != before {
+                self.context.take_last()
+            } else {
+                None
+            };
+            if fingerprint::of(&old) != fingerprint::of(&new) {
+                self.changed = true;
+            }
+        }
+    }
+
+    // the cached result, when every effect it depends on answered the same
+    pub fn cached(&mut self) -> Option<R> {
+        if self.changed {
+            None
+        } else {
+            self.result.take()
+        }
+    }
+
+    pub fn record(&mut self, effect: &E::Input) {
+        let before = self.context.produced();
+        self.current = Some((effect.clone(), None, before));
+    }
+
+    // the response stays in the context for the call to take
+    pub fn recorded(&mut self) {
+        if let Some((effect, _, before)) = self.current.take() {
+            let response = if self.context.produced() != before {
+                self.context.take_last().map(|response| {
+                    self.context.put(response.clone());
+                    response
+                })
+            } else {
+                None
+            };
+            self.trace.push((effect, response));
+        }
+    }
+
+    pub fn store(self, result: R) -> R {
+        if let Some(cache) = &self.cache {
+            let entry = Entry {
+                trace: self.trace,
+                result: Rc::new(result.clone()),
+            };
+            cache.0.borrow_mut().insert(self.key, entry);
+        }
+        result
+    }
+}
+
+// Calls a subroutine like `call!`, under `Block::with_incremental` its result
+// is cached by the key. Next time the effects the call performed are performed
+// again first, when every response is the same the cached result is returned
+// and the subroutine is not even created. A memoized call inside another one
+// is a part of it, when it changes the outer one runs again too.
+#[macro_export]
+macro_rules! memoized_call {
+    ($key:expr, $callee:expr, $ctx:expr) => {{
+        let mut memoized = $crate::MemoizedCall::new($ctx, &$key);
+        while let Some(effect) = memoized.next_validation() {
+            yield effect;
+            memoized.validated();
+        }
+        match memoized.cached() {
+            Some(result) => result,
+            None => {
+                let mut callee = $callee;
+                loop {
+                    match $crate::resume_callee(&mut callee) {
+                        ::std::ops::ControlFlow::Continue(y) => {
+                            memoized.record(&y);
+                            yield y;
+                            memoized.recorded();
+                        },
+                        ::std::ops::ControlFlow::Break(r) => break memoized.store(r),
+                    }
+                }
+            },
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        cell::{Cell, RefCell},
+        collections::BTreeMap,
+        hash::Hasher,
+        ops::Generator,
+    };
+    use crate::{Context, Effect, EffectFingerprint, Handler, IntoBlock, memoized_call};
+    use super::{IncrementalCache, ValidatingHandler, Validation};
+
+    #[derive(Debug, Clone)]
+    enum Effects {
+        Read(&'static str),
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Outputs {
+        Content(String),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl EffectFingerprint for Outputs {
+        fn fingerprint<H>(&self, hasher: &mut H)
+        where
+            H: Hasher,
+        {
+            let Outputs::Content(content) = self;
+            hasher.write(content.as_bytes());
+        }
+    }
+
+    type Files = Rc<RefCell<BTreeMap<&'static str, String>>>;
+    type Resumes = Rc<RefCell<BTreeMap<&'static str, u32>>>;
+
+    fn read(
+        context: Context<Outputs>,
+        resumes: Resumes,
+        name: &'static str,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = String> {
+        move || {
+            *resumes.borrow_mut().entry(name).or_default() += 1;
+            yield Effects::Read(name);
+            match context.take() {
+                Some(Outputs::Content(content)) => content.to_uppercase(),
+                None => String::new(),
+            }
+        }
+    }
+
+    fn report(
+        context: Context<Outputs>,
+        resumes: Resumes,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = String> {
+        move || {
+            let mut report = String::new();
+            for name in ["a", "b", "c"] {
+                let section =
+                    memoized_call!(name, read(context.clone(), resumes.clone(), name), &context);
+                report.push_str(&section);
+            }
+            report
+        }
+    }
+
+    fn reader(files: &Files) -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+        let files = files.clone();
+        move |effect| {
+            let Effects::Read(name) = effect;
+            Ok(Outputs::Content(files.borrow()[name].clone()))
+        }
+    }
+
+    fn files() -> Files {
+        let files = [("a", "x"), ("b", "y"), ("c", "z")];
+        Rc::new(RefCell::new(
+            files.iter().map(|(n, c)| (*n, c.to_string())).collect(),
+        ))
+    }
+
+    #[test]
+    fn reruns_changed_only() {
+        let files = files();
+        let resumes = Resumes::default();
+        let cache = IncrementalCache::new();
+        let run = || {
+            let resumes = resumes.clone();
+            (move |context: Context<Outputs>| report(context, resumes))
+                .into_block()
+                .with_incremental(&cache)
+                .add_handler(reader(&files))
+                .assert_handled()
+                .run()
+        };
+        assert_eq!(run(), "XYZ");
+        assert_eq!(cache.len(), 3);
+
+        // nothing changed, no call is resumed
+        resumes.borrow_mut().clear();
+        assert_eq!(run(), "XYZ");
+        assert!(resumes.borrow().is_empty());
+
+        files.borrow_mut().insert("b", "w".to_string());
+        assert_eq!(run(), "XWZ");
+        assert_eq!(*resumes.borrow(), [("b", 1)].iter().cloned().collect());
+    }
+
+    #[test]
+    fn nested_invalidation() {
+        let files = files();
+        let resumes = Resumes::default();
+        let cache = IncrementalCache::new();
+        let run = || {
+            let resumes = resumes.clone();
+            (move |context: Context<Outputs>| {
+                move || {
+                    let outer = {
+                        let (context, resumes) = (context.clone(), resumes.clone());
+                        move || {
+                            *resumes.borrow_mut().entry("outer").or_default() += 1;
+                            let inner = memoized_call!(
+                                "inner",
+                                read(context.clone(), resumes.clone(), "a"),
+                                &context
+                            );
+                            yield Effects::Read("b");
+                            context.take();
+                            format!("[{}]", inner)
+                        }
+                    };
+                    memoized_call!("outer", outer, &context)
+                }
+            })
+            .into_block()
+            .with_incremental(&cache)
+            .add_handler(reader(&files))
+            .assert_handled()
+            .run()
+        };
+        assert_eq!(run(), "[X]");
+        resumes.borrow_mut().clear();
+        assert_eq!(run(), "[X]");
+        assert!(resumes.borrow().is_empty());
+
+        // the change is in the inner call, the outer one runs again as well
+        files.borrow_mut().insert("a", "v".to_string());
+        assert_eq!(run(), "[V]");
+        assert_eq!(resumes.borrow()["outer"], 1);
+        assert_eq!(resumes.borrow()["a"], 1);
+    }
+
+    // answers the validations by the version of the file, not by its content
+    struct Versioned {
+        files: Files,
+        versions: BTreeMap<&'static str, u32>,
+        reads: Rc<Cell<u32>>,
+    }
+
+    impl Handler<Outputs> for Versioned {
+        fn handle(&mut self, effect: Effects) -> Result<Outputs, Effects> {
+            let Effects::Read(name) = effect;
+            self.reads.set(self.reads.get() + 1);
+            Ok(Outputs::Content(self.files.borrow()[name].clone()))
+        }
+    }
+
+    impl ValidatingHandler<Outputs> for Versioned {
+        fn validate(
+            &mut self,
+            effect: Effects,
+            old: &Outputs,
+        ) -> Result<Validation<Outputs>, Effects> {
+            let _ = old;
+            let Effects::Read(name) = &effect;
+            match self.versions.get(name) {
+                Some(0) | None => Ok(Validation::Unchanged),
+                Some(_) => self.handle(effect).map(Validation::Changed),
+            }
+        }
+    }
+
+    #[test]
+    fn validating_handler() {
+        let files = files();
+        let resumes = Resumes::default();
+        let cache = IncrementalCache::new();
+        let run = |versions: &[(&'static str, u32)]| {
+            let resumes = resumes.clone();
+            let reads = Rc::new(Cell::new(0));
+            let handler = Versioned {
+                files: files.clone(),
+                versions: versions.iter().cloned().collect(),
+                reads: reads.clone(),
+            };
+            let report = (move |context: Context<Outputs>| report(context, resumes))
+                .into_block()
+                .with_incremental(&cache)
+                .add_validating_handler(handler)
+                .assert_handled()
+                .run();
+            (report, reads.get())
+        };
+        assert_eq!(run(&[]), ("XYZ".to_string(), 3));
+        // unchanged versions are never read
+        assert_eq!(run(&[]), ("XYZ".to_string(), 0));
+        files.borrow_mut().insert("c", "q".to_string());
+        assert_eq!(run(&[("c", 1)]), ("XYQ".to_string(), 2));
+    }
+}
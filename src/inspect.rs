@@ -0,0 +1,359 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{collections::BTreeMap, error::Error, fmt, ops::Generator, time::Duration};
+use super::{
+    accounting::Usage,
+    block::Block,
+    computation::{Effect, EffectName},
+    context::Context,
+    deadline,
+    history::{self, HistoryEntry},
+    latency,
+    meta::HasPriority,
+    progress::{self, ProgressSnapshot},
+    routing,
+};
+
+// an effect yielded out of the block and not answered yet, as the latency
+// layer sees it
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct InFlight {
+    pub effect: String,
+    pub priority: u8,
+    // left until the deadline, zero once it passed
+    pub due_in: Option<Duration>,
+    // rounds it waits for its response so far
+    pub age: u32,
+    pub scope: String,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HandlerSummary {
+    pub tag: String,
+    // the variants the handler is filtered by, `None` if it sees everything
+    pub variants: Option<Vec<String>>,
+}
+
+// Everything inspectable about a block between two rounds. The parts come from
+// the layers installed, a part of a layer that is not there stays empty, most
+// of them need the diagnostics.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct RuntimeSnapshot {
+    pub live_tasks: usize,
+    pub pending_outputs: usize,
+    pub in_flight: Vec<InFlight>,
+    // the custodians holding effects
+    pub custody: Vec<String>,
+    // the tagged handlers in installation order
+    pub handlers: Vec<HandlerSummary>,
+    pub routes: usize,
+    // the raw tail of the history and the counts of compacted effects
+    pub history: Vec<HistoryEntry>,
+    pub compacted: BTreeMap<String, u64>,
+    pub usage: BTreeMap<String, Usage>,
+    pub counters: BTreeMap<String, u64>,
+    pub scope: String,
+    pub progress: Option<ProgressSnapshot>,
+}
+
+impl RuntimeSnapshot {
+    // one paragraph for a log line
+    pub fn summary(&self) -> String {
+        let mut parts = vec![
+            format!("{} live tasks", self.live_tasks),
+            format!("{} pending outputs", self.pending_outputs),
+        ];
+        let mut in_flight = format!("{} effects in flight", self.in_flight.len());
+        if let Some(oldest) = self.in_flight.iter().max_by_key(|f| f.age) {
+            in_flight += &format!(", the oldest `{}` for {} rounds", oldest.effect, oldest.age);
+        }
+        if !self.custody.is_empty() {
+            in_flight += &format!(", held by {}", self.custody.join(", "));
+        }
+        parts.push(in_flight);
+        let tags = self
+            .handlers
+            .iter()
+            .map(|h| h.tag.as_str())
+            .collect::<Vec<_>>();
+        if !tags.is_empty() {
+            parts.push(format!("handlers {}", tags.join(", ")));
+        }
+        if let Some(progress) = &self.progress {
+            parts.push(format!("{} rounds", progress.rounds));
+            if let Some((reached, total)) = progress.milestones {
+                parts.push(format!("{} of {} milestones", reached, total));
+            }
+        }
+        if let Some(last) = self.history.last() {
+            parts.push(format!("last effect `{}`", last.effect));
+        }
+        if !self.scope.is_empty() {
+            parts.push(format!("in `{}`", self.scope));
+        }
+        parts.join(", ") + "."
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InspectError {
+    // a handler or the computation asked while the block is resumed
+    MidDispatch,
+}
+
+impl fmt::Display for InspectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InspectError::MidDispatch => {
+                write!(
+                    f,
+                    "the block is inspected mid-dispatch, inspect it between rounds"
+                )
+            },
+        }
+    }
+}
+
+impl Error for InspectError {}
+
+impl<E> Context<E>
+where
+    E: Effect + 'static,
+    E::Input: EffectName + HasPriority + 'static,
+{
+    // Every part is read at once, so it is the state at the end of one round.
+    pub fn inspect(&self) -> Result<RuntimeSnapshot, InspectError> {
+        if self.is_resuming() {
+            return Err(InspectError::MidDispatch);
+        }
+        let now = deadline::now();
+        let in_flight = latency::waiting(self, |effect: &E::Input, age, scope| InFlight {
+            effect: effect.effect_name().to_string(),
+            priority: effect.priority(),
+            due_in: effect.due().map(|due| due.saturating_duration_since(now)),
+            age,
+            scope: scope.to_string(),
+        });
+        let (handlers, routes) = routing::chain(self);
+        let handlers = handlers
+            .into_iter()
+            .map(|(tag, variants)| HandlerSummary {
+                tag: tag.name().to_string(),
+                variants: variants.map(|v| v.into_iter().map(ToString::to_string).collect()),
+            })
+            .collect();
+        let (history, compacted) =
+            history::installed(self).map_or_else(Default::default, |h| (h.raw(), h.counters()));
+        let mut usage = BTreeMap::new();
+        let mut counters = BTreeMap::new();
+        self.account(|accounting| {
+            let report = accounting.report();
+            usage = report
+                .iter()
+                .map(|(category, u)| (format!("{:?}", category), u))
+                .collect();
+            counters = report
+                .counters()
+                .map(|(counter, n)| (format!("{:?}", counter), n))
+                .collect();
+        });
+        Ok(RuntimeSnapshot {
+            live_tasks: progress::live(self),
+            pending_outputs: self.len(),
+            in_flight,
+            custody: latency::holding(self)
+                .into_iter()
+                .map(ToString::to_string)
+                .collect(),
+            handlers,
+            routes,
+            history,
+            compacted,
+            usage,
+            counters,
+            scope: self.scope_path(),
+            progress: progress::published(self),
+        })
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + 'static,
+    E::Input: EffectName + HasPriority + 'static,
+    G: Unpin + Generator<()>,
+{
+    // between `run_until` pauses or between two `resume` calls
+    pub fn inspect(&self) -> Result<RuntimeSnapshot, InspectError> {
+        self.context().inspect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{
+        Context, Effect, EffectName, HandlerTag, HasPriority, IntoBlock, Responds, HistoryConfig,
+        perform,
+    };
+    use super::InspectError;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Get(u32),
+        Put(u32),
+        Idle,
+    }
+
+    impl EffectName for Effects {
+        fn effect_name(&self) -> &'static str {
+            match self {
+                Effects::Get(_) => "Get",
+                Effects::Put(_) => "Put",
+                Effects::Idle => "Idle",
+            }
+        }
+    }
+
+    impl HasPriority for Effects {
+        fn priority(&self) -> u8 {
+            match self {
+                Effects::Put(_) => 1,
+                _ => 0,
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Got(u32),
+        Done,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl Responds for Outputs {
+        fn responds_to(&self, effect: &Effects) -> bool {
+            match (self, effect) {
+                (Outputs::Got(n), Effects::Get(m)) => n == m,
+                (Outputs::Done, Effects::Put(_)) => true,
+                _ => false,
+            }
+        }
+
+        fn expects_response(effect: &Effects) -> bool {
+            *effect != Effects::Idle
+        }
+    }
+
+    fn computation(
+        context: Context<Outputs>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = u32> {
+        move || {
+            perform!(Effects::Put(1));
+            assert_eq!(context.take(), Some(Outputs::Done));
+            let _scope = context.enter_scope("get");
+            perform!(Effects::Get(7));
+            loop {
+                match context.take() {
+                    Some(Outputs::Got(n)) => break n,
+                    _ => yield Effects::Idle,
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mid_dispatch() {
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let context = Rc::new(RefCell::new(None::<Context<Outputs>>));
+        let block = computation.into_block().add_handler_tagged(
+            {
+                let errors = errors.clone();
+                let context = context.clone();
+                move |effect| {
+                    let context = context.borrow().clone().unwrap();
+                    errors.borrow_mut().push(context.inspect().unwrap_err());
+                    match effect {
+                        Effects::Get(n) => Ok(Outputs::Got(n)),
+                        _ => Ok(Outputs::Done),
+                    }
+                }
+            },
+            HandlerTag::new("all"),
+        );
+        *context.borrow_mut() = Some(block.context());
+        assert_eq!(block.assert_handled().run(), 7);
+        assert_eq!(*errors.borrow(), [InspectError::MidDispatch; 2]);
+    }
+
+    #[test]
+    fn between_rounds() {
+        use std::ops::GeneratorState;
+
+        let (block, _) = computation.into_block().with_latency_slo(10, |_| ());
+        let (block, _) = block.with_history(HistoryConfig::new(4));
+        let (block, _) = block.with_progress();
+        let mut block = block.add_handler_tagged(
+            |effect| match effect {
+                Effects::Put(_) => Ok(Outputs::Done),
+                other => Err(other),
+            },
+            HandlerTag::new("puts"),
+        );
+        assert_eq!(block.resume(), GeneratorState::Yielded(Effects::Get(7)));
+        let snapshot = block.inspect().unwrap();
+        assert_eq!(snapshot.handlers.len(), 1);
+        assert_eq!(snapshot.handlers[0].tag, "puts");
+        assert_eq!(snapshot.pending_outputs, 0);
+        assert_eq!(snapshot.progress.as_ref().map(|p| p.rounds), Some(2));
+        if cfg!(feature = "diagnostics") {
+            let in_flight = &snapshot.in_flight;
+            assert_eq!(in_flight.len(), 1);
+            assert_eq!((in_flight[0].effect.as_str(), in_flight[0].age), ("Get", 0));
+            assert_eq!(in_flight[0].scope, "get");
+            assert_eq!(snapshot.history.len(), 2);
+            assert!(snapshot.summary().contains("1 effects in flight"));
+        }
+        assert!(snapshot.summary().contains("handlers puts"));
+
+        block.put(Outputs::Got(7));
+        assert_eq!(block.inspect().unwrap().pending_outputs, 1);
+        assert_eq!(block.resume(), GeneratorState::Complete(7));
+        let snapshot = block.inspect().unwrap();
+        assert!(snapshot.in_flight.is_empty());
+        assert_eq!(snapshot.progress.map(|p| p.complete), Some(true));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn round_trip() {
+        let (block, _) = computation.into_block().with_latency_slo(10, |_| ());
+        let (block, _) = block.with_progress();
+        let mut block = block.add_handler_tagged_filtered(
+            crate::filter_variants!(Effects::Put),
+            |_| Ok(Outputs::Done),
+            HandlerTag::new("puts"),
+        );
+        let _ = block.resume();
+        let snapshot = block.inspect().unwrap();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(
+            serde_json::from_str::<super::RuntimeSnapshot>(&json).unwrap(),
+            snapshot
+        );
+        assert_eq!(
+            snapshot.handlers[0].variants.as_deref(),
+            Some(&["Put".to_string()][..])
+        );
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{any::Any, collections::BTreeMap, error::Error, fmt, ops::Generator};
+#[cfg(feature = "diagnostics")]
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    ops::GeneratorState,
+};
+use super::{block::Block, computation::Effect, context::Context};
+#[cfg(feature = "diagnostics")]
+use super::accounting::Counter;
+
+// what the computation stored with `Context::snapshot`, by key
+#[cfg(feature = "diagnostics")]
+#[derive(Default)]
+struct Snapshots(RefCell<BTreeMap<&'static str, Box<dyn Any>>>);
+
+// the read-only view an invariant is checked against
+pub struct InvariantCtx<'a, E>
+where
+    E: Effect,
+{
+    effect: Option<&'a E::Input>,
+    output: Option<&'a E>,
+    scope: String,
+    locals: &'a BTreeMap<&'static str, Box<dyn Any>>,
+    round: u64,
+    effects: u64,
+    outputs: u64,
+}
+
+impl<'a, E> InvariantCtx<'a, E>
+where
+    E: Effect,
+{
+    // the effect just yielded, `None` when the check is for a put
+    pub fn effect(&self) -> Option<&E::Input> {
+        self.effect
+    }
+
+    // the output just put, `None` when the check is for a yield
+    pub fn output(&self) -> Option<&E> {
+        self.output
+    }
+
+    pub fn scope_path(&self) -> &str {
+        &self.scope
+    }
+
+    // the last snapshot under the key, if it is of type `X`
+    pub fn local<X>(&self, key: &str) -> Option<&X>
+    where
+        X: 'static,
+    {
+        self.locals.get(key).and_then(|value| value.downcast_ref())
+    }
+
+    // how many times the computation was resumed
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    // the effects and outputs seen so far, this one included
+    pub fn effects(&self) -> u64 {
+        self.effects
+    }
+
+    pub fn outputs(&self) -> u64 {
+        self.outputs
+    }
+}
+
+// the panic payload of a failed invariant, with the context it failed in
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct InvariantViolation {
+    pub message: String,
+    pub effect: Option<String>,
+    pub output: Option<String>,
+    pub scope: String,
+    pub round: u64,
+    pub effects: u64,
+    pub outputs: u64,
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invariant violated: {}", self.message)?;
+        if let Some(effect) = &self.effect {
+            write!(f, ", after effect {}", effect)?;
+        }
+        if let Some(output) = &self.output {
+            write!(f, ", after output {}", output)?;
+        }
+        if !self.scope.is_empty() {
+            write!(f, ", in {}", self.scope)?;
+        }
+        write!(
+            f,
+            ", round {}, {} effects, {} outputs",
+            self.round, self.effects, self.outputs
+        )
+    }
+}
+
+impl Error for InvariantViolation {}
+
+#[cfg(feature = "diagnostics")]
+impl<T> Context<T> {
+    // a value of the computation's own state for invariants to read, replaces
+    // the previous snapshot under the key
+    pub fn snapshot<X>(&self, key: &'static str, value: X)
+    where
+        X: 'static,
+    {
+        let snapshots = self.extension(Snapshots::default);
+        snapshots.0.borrow_mut().insert(key, Box::new(value));
+    }
+}
+
+#[cfg(not(feature = "diagnostics"))]
+impl<T> Context<T> {
+    #[inline(always)]
+    pub fn snapshot<X>(&self, key: &'static str, value: X)
+    where
+        X: 'static,
+    {
+        let _ = (key, value);
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+type Check<E> = Box<dyn Fn(&InvariantCtx<'_, E>) -> Result<(), String>>;
+
+// shared by the layer and the watch on its context, which must not hold the
+// context itself
+#[cfg(feature = "diagnostics")]
+struct Invariant<E>
+where
+    E: Effect,
+{
+    check: Check<E>,
+    scope: Box<dyn Fn() -> String>,
+    snapshots: Rc<Snapshots>,
+    round: Cell<u64>,
+    effects: Cell<u64>,
+    outputs: Cell<u64>,
+    // checks the watch made, the layer reports them to the accounting
+    unreported: Cell<u64>,
+}
+
+#[cfg(feature = "diagnostics")]
+impl<E> Invariant<E>
+where
+    E: Effect + fmt::Debug,
+    E::Input: fmt::Debug,
+{
+    fn check(&self, effect: Option<&E::Input>, output: Option<&E>) {
+        self.unreported.set(self.unreported.get() + 1);
+        let locals = self.snapshots.0.borrow();
+        let view = InvariantCtx {
+            effect,
+            output,
+            scope: (self.scope)(),
+            locals: &locals,
+            round: self.round.get(),
+            effects: self.effects.get(),
+            outputs: self.outputs.get(),
+        };
+        if let Err(message) = (self.check)(&view) {
+            let violation = InvariantViolation {
+                message,
+                effect: effect.map(|e| format!("{:?}", e)),
+                output: output.map(|o| format!("{:?}", o)),
+                scope: view.scope,
+                round: view.round,
+                effects: view.effects,
+                outputs: view.outputs,
+            };
+            drop(locals);
+            std::panic::panic_any(violation);
+        }
+    }
+
+    fn report(&self, context: &Context<E>) {
+        let checks = self.unreported.replace(0);
+        context.account(|a| (0..checks).for_each(|_| a.bump(Counter::InvariantChecks)));
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+impl<E, G> Block<E, G>
+where
+    E: Effect + fmt::Debug + 'static,
+    E::Input: fmt::Debug + 'static,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Checks `check` after every effect the block yields and every output put
+    // in its context. A failed check panics with an `InvariantViolation`, so
+    // `on_panic_export` still writes the history. Layers of invariants compose,
+    // each one checks its own. Without diagnostics the block is returned as is.
+    pub fn with_invariant<F>(
+        self,
+        check: F,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        F: Fn(&InvariantCtx<'_, E>) -> Result<(), String> + 'static,
+    {
+        let context = self.context();
+        let invariant = Rc::new(Invariant {
+            check: Box::new(check) as Check<E>,
+            scope: Box::new(context.scope_reader()),
+            snapshots: context.extension(Snapshots::default),
+            round: Cell::new(0),
+            effects: Cell::new(0),
+            outputs: Cell::new(0),
+            unreported: Cell::new(0),
+        });
+        context.add_watch(Box::new({
+            let invariant = invariant.clone();
+            move |_, output| {
+                invariant.outputs.set(invariant.outputs.get() + 1);
+                invariant.check(None, Some(output));
+            }
+        }));
+        let mut s = self;
+        let generator = move || loop {
+            invariant.round.set(invariant.round.get() + 1);
+            let step = s.resume();
+            invariant.report(&s.context());
+            match step {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => {
+                    invariant.effects.set(invariant.effects.get() + 1);
+                    invariant.check(Some(&effect), None);
+                    invariant.report(&s.context());
+                    yield effect;
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(not(feature = "diagnostics"))]
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<()>,
+{
+    #[inline(always)]
+    pub fn with_invariant<F>(self, check: F) -> Self
+    where
+        F: Fn(&InvariantCtx<'_, E>) -> Result<(), String> + 'static,
+    {
+        let _ = check;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        ops::Generator,
+    };
+    use crate::{Context, Counter, Effect, IntoBlock, perform};
+    use super::InvariantViolation;
+
+    #[derive(Debug)]
+    enum Effects {
+        Handshake,
+        Write(&'static str),
+    }
+
+    #[derive(Debug)]
+    enum Outputs {
+        Shook,
+        Written,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    fn handler(effect: Effects) -> Result<Outputs, Effects> {
+        match effect {
+            Effects::Handshake => Ok(Outputs::Shook),
+            Effects::Write(_) => Ok(Outputs::Written),
+        }
+    }
+
+    fn session(
+        context: Context<Outputs>,
+        handshake_first: bool,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            let _scope = context.enter_scope("session");
+            if handshake_first {
+                perform!(Effects::Handshake);
+                context.snapshot("shook", true);
+            }
+            perform!(Effects::Write("hello"));
+            perform!(Effects::Write("bye"));
+        }
+    }
+
+    // no write before the session stored that the handshake is done
+    fn handshake_before_write(view: &super::InvariantCtx<'_, Outputs>) -> Result<(), String> {
+        match view.effect() {
+            Some(Effects::Write(_)) if view.local::<bool>("shook") != Some(&true) => {
+                Err("write before the handshake".to_string())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    #[test]
+    fn misordered() {
+        let block = (|context| session(context, false))
+            .into_block()
+            .with_invariant(handshake_before_write)
+            .add_handler(handler)
+            .assert_handled();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| block.run()));
+        if !cfg!(feature = "diagnostics") {
+            assert!(result.is_ok());
+            return;
+        }
+        let payload = result.unwrap_err();
+        let violation = payload.downcast_ref::<InvariantViolation>().unwrap();
+        assert_eq!(violation.message, "write before the handshake");
+        assert_eq!(violation.effect.as_deref(), Some("Write(\"hello\")"));
+        assert_eq!(violation.output, None);
+        assert_eq!(violation.scope, "session");
+        assert_eq!((violation.round, violation.effects), (1, 1));
+    }
+
+    #[test]
+    fn composed_and_counted() {
+        let (block, accounting) = (|context| session(context, true))
+            .into_block()
+            .with_invariant(handshake_before_write)
+            .with_invariant(|view| match view.outputs() {
+                n if n > 3 => Err(format!("{} outputs", n)),
+                _ => Ok(()),
+            })
+            .add_handler(handler)
+            .assert_handled()
+            .with_accounting();
+        block.run();
+        // an effect and an output per perform, seen by both invariants
+        let checks = if cfg!(feature = "diagnostics") { 12 } else { 0 };
+        assert_eq!(accounting.report().count(Counter::InvariantChecks), checks);
+    }
+}
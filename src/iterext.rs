@@ -0,0 +1,369 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::collections::BTreeMap;
+use super::{
+    computation::{Select, TakeResult},
+    context::Context,
+    two_phase::OpId,
+};
+
+// the items of an iterator in `Vec`s of `size`, the last one may be shorter
+pub struct Chunks<I> {
+    inner: I,
+    size: usize,
+}
+
+pub fn chunks<I>(iter: I, size: usize) -> Chunks<I::IntoIter>
+where
+    I: IntoIterator,
+{
+    assert!(size > 0, "chunks of zero items");
+    Chunks {
+        inner: iter.into_iter(),
+        size,
+    }
+}
+
+impl<I> Iterator for Chunks<I>
+where
+    I: Iterator,
+{
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = self.inner.by_ref().take(self.size).collect::<Vec<_>>();
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(chunk)
+        }
+    }
+}
+
+// a part of a response that names the operation it answers
+pub trait Correlated {
+    fn op_id(&self) -> OpId;
+}
+
+// The items of effects already performed, waiting for their parts. The parts
+// may come in any order, they are paired by the id `track` gave the item.
+pub struct PendingBatch<Item, Part> {
+    next: u64,
+    waiting: BTreeMap<OpId, Item>,
+    ready: BTreeMap<OpId, (Item, Part)>,
+}
+
+impl<Item, Part> Default for PendingBatch<Item, Part> {
+    fn default() -> Self {
+        PendingBatch {
+            next: 0,
+            waiting: BTreeMap::new(),
+            ready: BTreeMap::new(),
+        }
+    }
+}
+
+impl<Item, Part> PendingBatch<Item, Part> {
+    pub fn new() -> Self {
+        PendingBatch::default()
+    }
+
+    // the id goes into the effect, the response carries it back
+    pub fn track(&mut self, item: Item) -> OpId {
+        let id = OpId(self.next);
+        self.next += 1;
+        self.waiting.insert(id, item);
+        id
+    }
+
+    // the part is given back if no item waits for it
+    pub fn fill(&mut self, id: OpId, part: Part) -> Result<(), Part> {
+        match self.waiting.remove(&id) {
+            Some(item) => {
+                self.ready.insert(id, (item, part));
+                Ok(())
+            },
+            None => Err(part),
+        }
+    }
+
+    // the items without their part yet
+    pub fn waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    // the pairs so far in the order the items were tracked
+    pub fn drain(&mut self) -> Vec<(Item, Part)> {
+        std::mem::take(&mut self.ready).into_values().collect()
+    }
+}
+
+impl<Item, Part> PendingBatch<Item, Part>
+where
+    Part: Correlated,
+{
+    // Takes every part out of the context, the other outputs stay there in
+    // their order. Gives back the parts no item waits for.
+    pub fn fill_from<E>(&mut self, context: &Context<E>) -> Vec<Part>
+    where
+        E: Select<Part>,
+    {
+        let mut skipped = Vec::new();
+        let mut strays = Vec::new();
+        loop {
+            match E::try_take(context) {
+                TakeResult::Matched(part) => strays.extend(self.fill(part.op_id(), part).err()),
+                TakeResult::Mismatched(output) => skipped.push(output),
+                TakeResult::Empty => break,
+            }
+        }
+        for output in skipped {
+            context.put(output);
+        }
+        strays
+    }
+}
+
+/// Performs an effect for every item, the body gets the selected part.
+///
+/// ```
+/// #![feature(generators)]
+/// use aeiou::{Context, IntoBlock, for_each_perform};
+///
+/// aeiou::simple_effects! {
+///     Effects { Square(u32) }
+///     Outputs { Squared(u32) }
+///     parts { Squared(u32) => Square }
+/// }
+///
+/// let sum = (|context: Context<Outputs>| {
+///     move || {
+///         let mut sum = 0;
+///         for_each_perform!(1..4, &context, |n| Effects::Square(n) => Square |Square(s)| {
+///             sum += s;
+///         });
+///         sum
+///     }
+/// })
+/// .into_block()
+/// .add_handler(|Effects::Square(n)| Ok(Outputs::Squared(n * n)))
+/// .assert_handled()
+/// .run();
+/// assert_eq!(sum, 14);
+/// ```
+#[macro_export]
+macro_rules! for_each_perform {
+    ($iter:expr, $ctx:expr, |$item:pat| $e:expr => $part_ty:ty |$part:pat| $body:expr) => {{
+        for $item in $iter {
+            let $part: $part_ty = $crate::perform!($e, $ctx);
+            $body
+        }
+    }};
+}
+
+// one effect for every `n` items, the last chunk may be shorter
+#[macro_export]
+macro_rules! chunked_perform {
+    ($iter:expr, $n:expr, $ctx:expr, |$chunk:pat| $e:expr => $part_ty:ty |$part:pat| $body:expr) => {{
+        $crate::for_each_perform!(
+            $crate::iterext::chunks($iter, $n),
+            $ctx,
+            |$chunk| $e => $part_ty |$part| $body
+        )
+    }};
+}
+
+// Yields the idle effect until every item of the batch has its part,
+// evaluates to the `(item, part)` pairs in the order the items were tracked.
+// A part answering no item of the batch is dropped.
+#[macro_export]
+macro_rules! zip_responses {
+    ($batch:expr, $ctx:expr, $idle:expr) => {{
+        loop {
+            let _ = $crate::iterext::PendingBatch::fill_from(&mut $batch, $ctx);
+            if $crate::iterext::PendingBatch::is_complete(&$batch) {
+                break $crate::iterext::PendingBatch::drain(&mut $batch);
+            }
+            yield $idle;
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{Context, Effect, Select, IntoBlock, OpId, perform};
+    use super::{Correlated, PendingBatch};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Fetch(OpId, &'static str),
+        Store(Vec<u32>),
+        Idle,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Queued,
+        Value(OpId, usize),
+        Stored(usize),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Value(OpId, usize);
+
+    impl Correlated for Value {
+        fn op_id(&self) -> OpId {
+            self.0
+        }
+    }
+
+    impl Select<Value> for Outputs {
+        fn take(output: &Context<Self>) -> Option<Value> {
+            match output.take()? {
+                Outputs::Value(id, n) => Some(Value(id, n)),
+                other => {
+                    output.put_front(other);
+                    None
+                },
+            }
+        }
+    }
+
+    struct Stored(usize);
+
+    impl Select<Stored> for Outputs {
+        fn take(output: &Context<Self>) -> Option<Stored> {
+            match output.take()? {
+                Outputs::Stored(n) => Some(Stored(n)),
+                other => {
+                    output.put_front(other);
+                    None
+                },
+            }
+        }
+    }
+
+    // queues the fetches and answers them last first while the computation idles
+    fn handler(
+        effects: Rc<RefCell<Vec<Effects>>>,
+    ) -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+        let mut queued = Vec::new();
+        move |effect| {
+            effects.borrow_mut().push(effect.clone());
+            match effect {
+                Effects::Fetch(id, key) => {
+                    queued.push((id, key));
+                    Ok(Outputs::Queued)
+                },
+                Effects::Store(chunk) => Ok(Outputs::Stored(chunk.len())),
+                Effects::Idle => match queued.pop() {
+                    Some((id, key)) => Ok(Outputs::Value(id, key.len())),
+                    None => Ok(Outputs::Queued),
+                },
+            }
+        }
+    }
+
+    fn fetcher(
+        context: Context<Outputs>,
+        keys: Vec<&'static str>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = Vec<(&'static str, Value)>> {
+        move || {
+            let mut batch = PendingBatch::new();
+            for key in keys {
+                let id = batch.track(key);
+                perform!(Effects::Fetch(id, key));
+            }
+            let pairs = zip_responses!(batch, &context, Effects::Idle);
+            // the acknowledgements stay
+            while context.take() == Some(Outputs::Queued) {}
+            assert!(context.is_empty());
+            pairs
+        }
+    }
+
+    #[test]
+    fn out_of_order() {
+        let effects = Rc::default();
+        let pairs = (|context| fetcher(context, vec!["a", "bb", "ccc"]))
+            .into_block()
+            .add_handler(handler(Rc::clone(&effects)))
+            .assert_handled()
+            .run();
+        assert_eq!(
+            pairs,
+            [
+                ("a", Value(OpId(0), 1)),
+                ("bb", Value(OpId(1), 2)),
+                ("ccc", Value(OpId(2), 3)),
+            ],
+        );
+        assert_eq!(effects.borrow().len(), 6);
+    }
+
+    #[test]
+    fn chunk_boundaries() {
+        let effects = Rc::new(RefCell::new(Vec::new()));
+        let stored = (|context: Context<Outputs>| {
+            move || {
+                let mut stored = Vec::new();
+                chunked_perform!(0..7, 3, &context, |chunk| Effects::Store(chunk) => Stored |Stored(n)| {
+                    stored.push(n);
+                });
+                stored
+            }
+        })
+        .into_block()
+        .add_handler(handler(effects.clone()))
+        .assert_handled()
+        .run();
+        assert_eq!(stored, [3, 3, 1]);
+        assert_eq!(
+            *effects.borrow(),
+            [
+                Effects::Store(vec![0, 1, 2]),
+                Effects::Store(vec![3, 4, 5]),
+                Effects::Store(vec![6]),
+            ],
+        );
+    }
+
+    #[test]
+    fn empty() {
+        let effects = Rc::new(RefCell::new(Vec::new()));
+        let stored = (|context: Context<Outputs>| {
+            move || {
+                let mut stored = 0;
+                chunked_perform!(Vec::new(), 2, &context, |chunk| Effects::Store(chunk) => Stored |Stored(n)| {
+                    stored += n;
+                });
+                for_each_perform!(Vec::new(), &context, |chunk| Effects::Store(chunk) => Stored |Stored(n)| {
+                    stored += n;
+                });
+                stored
+            }
+        })
+        .into_block()
+        .add_handler(handler(effects.clone()))
+        .assert_handled()
+        .run();
+        assert_eq!(stored, 0);
+        let pairs = (|context| fetcher(context, Vec::new()))
+            .into_block()
+            .add_handler(handler(effects.clone()))
+            .assert_handled()
+            .run();
+        assert!(pairs.is_empty());
+        assert!(effects.borrow().is_empty());
+    }
+}
@@ -0,0 +1,487 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    ops::{Generator, GeneratorState},
+};
+use super::{block::Block, computation::Effect, fingerprint::EffectFingerprint};
+#[cfg(feature = "serde")]
+use super::codec::{self, Codec, JsonCodec};
+
+pub trait EffectJournal {
+    fn record(&mut self, seq: u64, effect_hash: u64) -> io::Result<()>;
+    fn recorded_hash(&self, seq: u64) -> Option<u64>;
+
+    fn already_performed(&self, seq: u64, effect_hash: u64) -> bool {
+        self.recorded_hash(seq) == Some(effect_hash)
+    }
+
+    fn record_response(&mut self, seq: u64, response: String) -> io::Result<()> {
+        let _ = (seq, response);
+        Ok(())
+    }
+
+    fn recorded_response(&self, seq: u64) -> Option<String> {
+        let _ = seq;
+        None
+    }
+}
+
+#[derive(Default, Clone)]
+struct Entries {
+    hashes: BTreeMap<u64, u64>,
+    responses: BTreeMap<u64, String>,
+}
+
+#[derive(Default, Clone)]
+pub struct MemoryJournal(Rc<RefCell<Entries>>);
+
+impl MemoryJournal {
+    pub fn new() -> Self {
+        MemoryJournal::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().hashes.is_empty()
+    }
+}
+
+impl EffectJournal for MemoryJournal {
+    fn record(&mut self, seq: u64, effect_hash: u64) -> io::Result<()> {
+        self.0.borrow_mut().hashes.insert(seq, effect_hash);
+        Ok(())
+    }
+
+    fn recorded_hash(&self, seq: u64) -> Option<u64> {
+        self.0.borrow().hashes.get(&seq).cloned()
+    }
+
+    fn record_response(&mut self, seq: u64, response: String) -> io::Result<()> {
+        self.0.borrow_mut().responses.insert(seq, response);
+        Ok(())
+    }
+
+    fn recorded_response(&self, seq: u64) -> Option<String> {
+        self.0.borrow().responses.get(&seq).cloned()
+    }
+}
+
+// Append-only, one record per line: `E <seq> <hash>` or `R <seq> <response>`,
+// a torn last line left by a crash is ignored on open.
+pub struct FileJournal {
+    file: File,
+    entries: Entries,
+}
+
+impl FileJournal {
+    pub fn open<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let mut entries = Entries::default();
+        if let Ok(file) = File::open(path.as_ref()) {
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                let mut parts = line.splitn(3, ' ');
+                let (kind, seq, value) = match (parts.next(), parts.next(), parts.next()) {
+                    (Some(kind), Some(seq), Some(value)) => (kind, seq, value),
+                    _ => continue,
+                };
+                let seq = match seq.parse() {
+                    Ok(seq) => seq,
+                    Err(_) => continue,
+                };
+                match (kind, value.parse()) {
+                    ("E", Ok(hash)) => {
+                        entries.hashes.insert(seq, hash);
+                    },
+                    ("R", _) => {
+                        entries.responses.insert(seq, value.to_string());
+                    },
+                    _ => (),
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileJournal { file, entries })
+    }
+}
+
+impl EffectJournal for FileJournal {
+    fn record(&mut self, seq: u64, effect_hash: u64) -> io::Result<()> {
+        writeln!(self.file, "E {} {}", seq, effect_hash)?;
+        self.file.sync_data()?;
+        self.entries.hashes.insert(seq, effect_hash);
+        Ok(())
+    }
+
+    fn recorded_hash(&self, seq: u64) -> Option<u64> {
+        self.entries.hashes.get(&seq).cloned()
+    }
+
+    fn record_response(&mut self, seq: u64, response: String) -> io::Result<()> {
+        if response.contains('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "journal response must be a single line",
+            ));
+        }
+        writeln!(self.file, "R {} {}", seq, response)?;
+        self.file.sync_data()?;
+        self.entries.responses.insert(seq, response);
+        Ok(())
+    }
+
+    fn recorded_response(&self, seq: u64) -> Option<String> {
+        self.entries.responses.get(&seq).cloned()
+    }
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    Diverged { seq: u64, recorded: u64, found: u64 },
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Io(error) => write!(f, "journal io: {}", error),
+            JournalError::Diverged {
+                seq,
+                recorded,
+                found,
+            } => write!(
+                f,
+                "effect {} diverged from the journal: recorded {:016x}, found {:016x}",
+                seq, recorded, found
+            ),
+        }
+    }
+}
+
+impl Error for JournalError {}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    E::Input: EffectFingerprint,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Effects already in the journal are not delivered again, which suits
+    // one-way effects; use `exactly_once_with_responses` when the response matters.
+    pub fn exactly_once<J>(
+        self,
+        journal: J,
+    ) -> Block<
+        E,
+        impl Unpin + Generator<(), Return = Result<G::Return, JournalError>, Yield = E::Input>,
+    >
+    where
+        J: EffectJournal,
+    {
+        self.journaled(journal, |_| None, |_| None)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn exactly_once_with_responses<J>(
+        self,
+        journal: J,
+    ) -> Block<
+        E,
+        impl Unpin + Generator<(), Return = Result<G::Return, JournalError>, Yield = E::Input>,
+    >
+    where
+        J: EffectJournal,
+        E: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        self.exactly_once_with_codec(journal, JsonCodec)
+    }
+
+    // the journal is text, the responses of a binary codec are kept in hex
+    #[cfg(feature = "serde")]
+    pub fn exactly_once_with_codec<J, C>(
+        self,
+        journal: J,
+        codec: C,
+    ) -> Block<
+        E,
+        impl Unpin + Generator<(), Return = Result<G::Return, JournalError>, Yield = E::Input>,
+    >
+    where
+        J: EffectJournal,
+        E: serde::Serialize + serde::de::DeserializeOwned,
+        C: Codec,
+    {
+        let codec = Rc::new(codec);
+        let loading = codec.clone();
+        self.journaled(
+            journal,
+            move |response| {
+                let mut bytes = Vec::new();
+                codec.encode(response, &mut bytes).ok()?;
+                codec::to_text(&*codec, bytes)
+            },
+            move |response| {
+                let bytes = codec::from_text(&*loading, response)?;
+                loading.decode(&bytes).ok()
+            },
+        )
+    }
+
+    fn journaled<J, S, L>(
+        self,
+        journal: J,
+        save: S,
+        load: L,
+    ) -> Block<
+        E,
+        impl Unpin + Generator<(), Return = Result<G::Return, JournalError>, Yield = E::Input>,
+    >
+    where
+        J: EffectJournal,
+        S: Fn(&E) -> Option<String>,
+        L: Fn(&str) -> Option<E>,
+    {
+        let context = self.context();
+        let mut journal = journal;
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            move || {
+                let mut seq = 0;
+                loop {
+                    let effect = match s.resume() {
+                        GeneratorState::Complete(r) => return Ok(r),
+                        GeneratorState::Yielded(effect) => effect,
+                    };
+                    let hash = super::fingerprint::of(&effect);
+                    match journal.recorded_hash(seq) {
+                        Some(recorded) if recorded != hash => {
+                            return Err(JournalError::Diverged {
+                                seq,
+                                recorded,
+                                found: hash,
+                            });
+                        },
+                        Some(_) => {
+                            let response = journal.recorded_response(seq);
+                            if let Some(response) = response.as_deref().and_then(&load) {
+                                context.put(response);
+                            }
+                        },
+                        None => {
+                            let before = context.produced();
+                            yield effect;
+                            journal.record(seq, hash).map_err(JournalError::Io)?;
+                            if context.produced() > before {
+                                if let Some(response) = context.take_last() {
+                                    let saved = save(&response);
+                                    context.put(response);
+                                    if let Some(saved) = saved {
+                                        journal
+                                            .record_response(seq, saved)
+                                            .map_err(JournalError::Io)?;
+                                    }
+                                }
+                            }
+                        },
+                    }
+                    seq += 1;
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        cell::RefCell,
+        hash::Hasher,
+        panic::{self, AssertUnwindSafe},
+        ops::Generator,
+    };
+    use crate::{Context, Effect, EffectFingerprint, IntoBlock};
+    use super::{EffectJournal, MemoryJournal, FileJournal, JournalError};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Charge(u32),
+    }
+
+    impl EffectFingerprint for Effects {
+        fn fingerprint<H>(&self, hasher: &mut H)
+        where
+            H: Hasher,
+        {
+            let Effects::Charge(amount) = self;
+            amount.fingerprint(hasher);
+        }
+    }
+
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Receipt(u32),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    fn payments(
+        context: Context<Outputs>,
+        amounts: Vec<u32>,
+        crash_after: Option<usize>,
+        receipts: Rc<RefCell<Vec<Outputs>>>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            for (index, amount) in amounts.into_iter().enumerate() {
+                yield Effects::Charge(amount);
+                receipts.borrow_mut().extend(context.take());
+                if crash_after == Some(index) {
+                    panic!("crashed before the snapshot");
+                }
+            }
+        }
+    }
+
+    fn run<J>(
+        journal: J,
+        amounts: Vec<u32>,
+        crash_after: Option<usize>,
+        charged: &Rc<RefCell<Vec<u32>>>,
+    ) -> (Result<(), JournalError>, Vec<Outputs>)
+    where
+        J: EffectJournal,
+    {
+        let receipts = Rc::new(RefCell::new(Vec::new()));
+        let computation = {
+            let receipts = receipts.clone();
+            move |context| payments(context, amounts, crash_after, receipts)
+        };
+        let handler = {
+            let charged = charged.clone();
+            move |Effects::Charge(amount)| {
+                charged.borrow_mut().push(amount);
+                Ok(Outputs::Receipt(amount))
+            }
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            #[cfg(feature = "serde")]
+            let block = computation
+                .into_block()
+                .exactly_once_with_responses(journal);
+            #[cfg(not(feature = "serde"))]
+            let block = computation.into_block().exactly_once(journal);
+            block.add_handler(handler).assert_handled().run()
+        }))
+        .unwrap_or(Ok(()));
+        let receipts = receipts.take();
+        (result, receipts)
+    }
+
+    #[test]
+    fn crash_between_record_and_snapshot() {
+        let journal = MemoryJournal::new();
+        let charged = Rc::new(RefCell::new(Vec::new()));
+        let (result, _) = run(journal.clone(), vec![10, 20, 30], Some(1), &charged);
+        assert!(result.is_ok());
+        assert_eq!(journal.len(), 2);
+        let (result, receipts) = run(journal, vec![10, 20, 30], None, &charged);
+        assert!(result.is_ok());
+        assert_eq!(*charged.borrow(), vec![10, 20, 30]);
+        #[cfg(feature = "serde")]
+        assert_eq!(
+            receipts,
+            vec![
+                Outputs::Receipt(10),
+                Outputs::Receipt(20),
+                Outputs::Receipt(30)
+            ],
+        );
+        #[cfg(not(feature = "serde"))]
+        assert_eq!(receipts, vec![Outputs::Receipt(30)]);
+    }
+
+    #[test]
+    fn diverged() {
+        let journal = MemoryJournal::new();
+        let charged = Rc::new(RefCell::new(Vec::new()));
+        let _ = run(journal.clone(), vec![10], None, &charged);
+        let (result, _) = run(journal, vec![11], None, &charged);
+        match result {
+            Err(JournalError::Diverged { seq: 0, .. }) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(*charged.borrow(), vec![10]);
+    }
+
+    #[test]
+    fn file_journal_survives_reopen() {
+        let path = std::env::temp_dir().join(format!("aeiou-journal-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let charged = Rc::new(RefCell::new(Vec::new()));
+        let journal = FileJournal::open(&path).unwrap();
+        let _ = run(journal, vec![5, 6], Some(0), &charged);
+        let journal = FileJournal::open(&path).unwrap();
+        assert!(journal.already_performed(0, crate::fingerprint::of(&Effects::Charge(5))));
+        let (result, _) = run(journal, vec![5, 6], None, &charged);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_ok());
+        assert_eq!(*charged.borrow(), vec![5, 6]);
+    }
+
+    #[cfg(feature = "rmp")]
+    #[test]
+    fn msgpack_responses_replayed() {
+        use crate::codec::MsgpackCodec;
+
+        let journal = MemoryJournal::new();
+        let charged = Rc::new(RefCell::new(Vec::new()));
+        let run = |journal: MemoryJournal, crash_after| {
+            let receipts = Rc::new(RefCell::new(Vec::new()));
+            let computation = {
+                let receipts = receipts.clone();
+                move |context| payments(context, vec![10, 20], crash_after, receipts)
+            };
+            let handler = {
+                let charged = charged.clone();
+                move |Effects::Charge(amount)| {
+                    charged.borrow_mut().push(amount);
+                    Ok(Outputs::Receipt(amount))
+                }
+            };
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                computation
+                    .into_block()
+                    .exactly_once_with_codec(journal, MsgpackCodec)
+                    .add_handler(handler)
+                    .assert_handled()
+                    .run()
+            }));
+            receipts.take()
+        };
+        run(journal.clone(), Some(0));
+        let stored = journal.recorded_response(0).unwrap();
+        assert!(stored.bytes().all(|b| b.is_ascii_hexdigit()));
+        let receipts = run(journal, None);
+        assert_eq!(*charged.borrow(), [10, 20]);
+        assert_eq!(receipts, [Outputs::Receipt(10), Outputs::Receipt(20)]);
+    }
+}
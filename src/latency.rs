@@ -0,0 +1,470 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{rc::Rc, cell::RefCell, ops::Generator};
+#[cfg(feature = "diagnostics")]
+use std::{rc::Weak, collections::VecDeque, ops::GeneratorState};
+use super::{block::Block, computation::Effect, context::Context};
+
+// Tells which effect an output answers, the responses may come in any order.
+pub trait Responds
+where
+    Self: Effect,
+{
+    fn responds_to(&self, effect: &Self::Input) -> bool;
+
+    // effects like an idle hint wait for no response and are not tracked
+    fn expects_response(effect: &Self::Input) -> bool {
+        let _ = effect;
+        true
+    }
+}
+
+// A layer or a driver that keeps effects until it answers them, it is named in
+// the violations found while it holds any.
+pub trait Custody {
+    fn name(&self) -> &'static str;
+
+    fn held(&self) -> usize;
+}
+
+// the custodians registered in a context, held weakly so they may go away
+#[cfg(feature = "diagnostics")]
+#[derive(Default)]
+struct Custodians(RefCell<Vec<Weak<dyn Custody>>>);
+
+#[cfg(feature = "diagnostics")]
+impl Custodians {
+    fn holding(&self) -> Vec<&'static str> {
+        let mut custodians = self.0.borrow_mut();
+        custodians.retain(|c| c.strong_count() > 0);
+        custodians
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|c| c.held() > 0)
+            .map(|c| c.name())
+            .collect()
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+impl<T> Context<T> {
+    pub fn add_custody<C>(&self, custodian: &Rc<C>)
+    where
+        C: Custody + 'static,
+    {
+        let custodian = Rc::downgrade(custodian) as Weak<dyn Custody>;
+        let custodians = self.extension(Custodians::default);
+        custodians.0.borrow_mut().push(custodian);
+    }
+}
+
+#[cfg(not(feature = "diagnostics"))]
+impl<T> Context<T> {
+    #[inline(always)]
+    pub fn add_custody<C>(&self, custodian: &Rc<C>)
+    where
+        C: Custody + 'static,
+    {
+        let _ = custodian;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LatencyViolation<I> {
+    pub effect: I,
+    // rounds the effect waits for its response so far
+    pub age: u32,
+    // the custodians holding effects when the violation was found, empty when
+    // the driver of the block has them
+    pub custody: Vec<&'static str>,
+    // the scope path of the performer when it yielded the effect
+    pub scope: String,
+}
+
+// the nearest rank percentiles of the ages of the answered effects
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LatencySummary {
+    pub answered: usize,
+    pub p50: u32,
+    pub p95: u32,
+    pub max: u32,
+}
+
+// the ages the layer measured, read at the end of the run
+#[derive(Default, Clone)]
+pub struct Latency(Rc<RefCell<Vec<u32>>>);
+
+impl Latency {
+    pub fn summary(&self) -> LatencySummary {
+        let mut ages = self.0.borrow().clone();
+        ages.sort_unstable();
+        let rank = |percent: usize| match ages.len() {
+            0 => 0,
+            n => ages[(percent * n + 99) / 100 - 1],
+        };
+        LatencySummary {
+            answered: ages.len(),
+            p50: rank(50),
+            p95: rank(95),
+            max: ages.last().cloned().unwrap_or(0),
+        }
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+struct Waiting<I> {
+    since: u32,
+    effect: I,
+    scope: String,
+    reported: bool,
+}
+
+// shared by the layer and the watch on its context
+#[cfg(feature = "diagnostics")]
+struct Tracker<I> {
+    round: u32,
+    waiting: VecDeque<Waiting<I>>,
+}
+
+// the tracker of the last layer installed, for `Context::inspect`
+#[cfg(feature = "diagnostics")]
+struct Installed<I>(RefCell<Option<Rc<RefCell<Tracker<I>>>>>);
+
+#[cfg(feature = "diagnostics")]
+impl<I> Default for Installed<I> {
+    fn default() -> Self {
+        Installed(RefCell::new(None))
+    }
+}
+
+// the effects waiting for their responses, with their age and scope
+#[cfg(feature = "diagnostics")]
+pub(crate) fn waiting<T, I, F, R>(context: &Context<T>, f: F) -> Vec<R>
+where
+    I: 'static,
+    F: FnMut(&I, u32, &str) -> R,
+{
+    let mut f = f;
+    let tracker = context
+        .find_extension::<Installed<I>>()
+        .and_then(|installed| installed.0.borrow().clone());
+    match tracker {
+        Some(tracker) => {
+            let tracker = tracker.borrow();
+            tracker
+                .waiting
+                .iter()
+                .map(|w| f(&w.effect, tracker.round - w.since, &w.scope))
+                .collect()
+        },
+        None => Vec::new(),
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+pub(crate) fn holding<T>(context: &Context<T>) -> Vec<&'static str> {
+    context
+        .find_extension::<Custodians>()
+        .map_or_else(Vec::new, |custodians| custodians.holding())
+}
+
+#[cfg(not(feature = "diagnostics"))]
+pub(crate) fn waiting<T, I, F, R>(context: &Context<T>, f: F) -> Vec<R>
+where
+    F: FnMut(&I, u32, &str) -> R,
+{
+    let _ = (context, f);
+    Vec::new()
+}
+
+#[cfg(not(feature = "diagnostics"))]
+pub(crate) fn holding<T>(context: &Context<T>) -> Vec<&'static str> {
+    let _ = context;
+    Vec::new()
+}
+
+#[cfg(feature = "diagnostics")]
+impl<E, G> Block<E, G>
+where
+    E: Responds + 'static,
+    E::Input: Clone + 'static,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Every resume of the block is a round. An effect waits from the round it
+    // is yielded in until an output responding to it is put, `on_violation`
+    // is called once for every effect waiting more than `k_rounds`. The layer
+    // goes right after `into_block`, so it sees the effects before handlers
+    // answer them. Without diagnostics nothing is measured.
+    pub fn with_latency_slo<F>(
+        self,
+        k_rounds: u32,
+        on_violation: F,
+    ) -> (
+        Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>,
+        Latency,
+    )
+    where
+        F: FnMut(LatencyViolation<E::Input>),
+    {
+        let context = self.context();
+        let latency = Latency::default();
+        let tracker = Rc::new(RefCell::new(Tracker {
+            round: 0,
+            waiting: VecDeque::<Waiting<E::Input>>::new(),
+        }));
+        *context.extension(Installed::default).0.borrow_mut() = Some(tracker.clone());
+        context.add_watch(Box::new({
+            let tracker = tracker.clone();
+            let ages = latency.0.clone();
+            move |_, output: &E| {
+                let mut tracker = tracker.borrow_mut();
+                let answered = tracker
+                    .waiting
+                    .iter()
+                    .position(|w| output.responds_to(&w.effect));
+                if let Some(waiting) = answered.and_then(|i| tracker.waiting.remove(i)) {
+                    ages.borrow_mut().push(tracker.round - waiting.since);
+                }
+            }
+        }));
+        let custodians = context.extension(Custodians::default);
+        let scope = context.scope_reader();
+        let mut on_violation = on_violation;
+        let mut s = self;
+        let generator = move || loop {
+            let late = {
+                let mut tracker = tracker.borrow_mut();
+                tracker.round += 1;
+                let round = tracker.round;
+                tracker
+                    .waiting
+                    .iter_mut()
+                    .filter(|w| !w.reported && round - w.since > k_rounds)
+                    .map(|w| {
+                        w.reported = true;
+                        (w.effect.clone(), round - w.since, w.scope.clone())
+                    })
+                    .collect::<Vec<_>>()
+            };
+            if !late.is_empty() {
+                let custody = custodians.holding();
+                for (effect, age, scope) in late {
+                    on_violation(LatencyViolation {
+                        effect,
+                        age,
+                        custody: custody.clone(),
+                        scope,
+                    });
+                }
+            }
+            match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => {
+                    if E::expects_response(&effect) {
+                        let mut tracker = tracker.borrow_mut();
+                        let since = tracker.round;
+                        tracker.waiting.push_back(Waiting {
+                            since,
+                            effect: effect.clone(),
+                            scope: scope(),
+                            reported: false,
+                        });
+                    }
+                    yield effect;
+                },
+            }
+        };
+        (Block::new(context, generator), latency)
+    }
+}
+
+#[cfg(not(feature = "diagnostics"))]
+impl<E, G> Block<E, G>
+where
+    E: Responds,
+    G: Unpin + Generator<()>,
+{
+    #[inline(always)]
+    pub fn with_latency_slo<F>(self, k_rounds: u32, on_violation: F) -> (Self, Latency)
+    where
+        F: FnMut(LatencyViolation<E::Input>),
+    {
+        let _ = (k_rounds, on_violation);
+        (self, Latency::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        cell::RefCell,
+        ops::{Generator, GeneratorState},
+    };
+    use crate::{Block, Context, Effect, IntoBlock};
+    use super::{Custody, LatencySummary, LatencyViolation, Responds};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Fetch(u32),
+        Ask(u32),
+        Idle,
+    }
+
+    #[derive(Debug)]
+    enum Outputs {
+        Fetched(u32),
+        Answer(u32),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl Responds for Outputs {
+        fn responds_to(&self, effect: &Effects) -> bool {
+            match (self, effect) {
+                (Outputs::Fetched(n), Effects::Fetch(m)) => n == m,
+                (Outputs::Answer(n), Effects::Ask(m)) => n == m,
+                _ => false,
+            }
+        }
+
+        fn expects_response(effect: &Effects) -> bool {
+            *effect != Effects::Idle
+        }
+    }
+
+    // fetches take as many rounds as the disk is told
+    #[derive(Default)]
+    struct Disk(RefCell<Vec<(u32, u32)>>);
+
+    impl Custody for Disk {
+        fn name(&self) -> &'static str {
+            "disk"
+        }
+
+        fn held(&self) -> usize {
+            self.0.borrow().len()
+        }
+    }
+
+    // performs everything at once, one effect a round, then waits
+    fn pipelined(
+        context: Context<Outputs>,
+        effects: Vec<Effects>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            let mut expected = effects.len();
+            for effect in effects.clone() {
+                let _scope = match &effect {
+                    Effects::Ask(_) => context.enter_scope("ask"),
+                    _ => context.enter_scope("fetch"),
+                };
+                yield effect;
+            }
+            while expected > 0 {
+                while context.take().is_some() {
+                    expected -= 1;
+                }
+                if expected > 0 {
+                    yield Effects::Idle;
+                }
+            }
+        }
+    }
+
+    // the disk answers a fetch `delays[n]` rounds late, the driver itself
+    // answers every ask after three rounds
+    fn drive<G>(block: Block<Outputs, G>, delays: &[u32])
+    where
+        G: Unpin + Generator<(), Yield = Effects, Return = ()>,
+    {
+        let disk = Rc::new(Disk::default());
+        block.context().add_custody(&disk);
+        let mut asks = Vec::new();
+        let mut block = block;
+        let mut round = 0;
+        loop {
+            round += 1;
+            match block.resume() {
+                GeneratorState::Complete(()) => break,
+                GeneratorState::Yielded(Effects::Fetch(n)) => {
+                    disk.0.borrow_mut().push((round + delays[n as usize], n))
+                },
+                GeneratorState::Yielded(Effects::Ask(n)) => asks.push((round + 3, n)),
+                GeneratorState::Yielded(Effects::Idle) => (),
+            }
+            disk.0.borrow_mut().retain(|&(due, n)| {
+                if due == round {
+                    block.put(Outputs::Fetched(n));
+                }
+                due != round
+            });
+            asks.retain(|&(due, n)| {
+                if due == round {
+                    block.put(Outputs::Answer(n));
+                }
+                due != round
+            });
+        }
+    }
+
+    #[test]
+    fn custody() {
+        let violations = Rc::new(RefCell::new(Vec::new()));
+        let effects = vec![Effects::Fetch(0), Effects::Fetch(1), Effects::Ask(0)];
+        let (block, _) = (move |context| pipelined(context, effects))
+            .into_block()
+            .with_latency_slo(2, {
+                let violations = violations.clone();
+                move |violation| violations.borrow_mut().push(violation)
+            });
+        drive(block, &[4, 1]);
+        if !cfg!(feature = "diagnostics") {
+            return;
+        }
+        // the fetch is late while the disk has it, the ask is late after the
+        // disk gave it back
+        let expected = vec![
+            LatencyViolation {
+                effect: Effects::Fetch(0),
+                age: 3,
+                custody: vec!["disk"],
+                scope: "fetch".to_string(),
+            },
+            LatencyViolation {
+                effect: Effects::Ask(0),
+                age: 3,
+                custody: vec![],
+                scope: "ask".to_string(),
+            },
+        ];
+        assert_eq!(*violations.borrow(), expected);
+    }
+
+    #[test]
+    fn percentiles() {
+        let delays = [0, 1, 4, 2, 0, 3, 1, 0, 2, 5];
+        let effects = (0..10).map(Effects::Fetch).collect::<Vec<_>>();
+        let (block, latency) = (move |context| pipelined(context, effects))
+            .into_block()
+            .with_latency_slo(10, |violation| panic!("{:?}", violation));
+        drive(block, &delays);
+        // sorted ages 0 0 0 1 1 2 2 3 4 5, the ranks are the fifth and the tenth
+        let summary = if cfg!(feature = "diagnostics") {
+            LatencySummary {
+                answered: 10,
+                p50: 1,
+                p95: 5,
+                max: 5,
+            }
+        } else {
+            LatencySummary::default()
+        };
+        assert_eq!(latency.summary(), summary);
+    }
+}
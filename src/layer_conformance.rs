@@ -0,0 +1,766 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    hash::Hasher,
+    ops::Generator,
+    time::Duration,
+    error::Error,
+    fmt,
+};
+use super::{
+    accounting::Counter,
+    block::{Block, IntoBlock},
+    chaos::Rng,
+    coalesce::Mergeable,
+    computation::Effect,
+    context::Context,
+    deadline::{self, HasDeadlineExceeded},
+    fallible::BoxedGenerator,
+    fingerprint::{self, EffectFingerprint},
+    flush::{self, HasFlush, HasFlushed},
+    idempotency::Idempotent,
+    load::{LoadMetric, ShedPolicy, Sheddable},
+    memo::MemoPolicy,
+    meta::{EffectMeta, WithMeta},
+};
+
+// The effects of the workload. `Work` and `Optional` are answered once each,
+// `Note` is one-way and mergeable, `Read` is answered the same every time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    Work(u64),
+    Read(u64),
+    Note(Vec<u64>),
+    Optional(u64),
+    Flush,
+}
+
+impl Probe {
+    fn id(&self) -> u64 {
+        match self {
+            Probe::Work(id) | Probe::Optional(id) => *id,
+            _ => 0,
+        }
+    }
+}
+
+impl Idempotent for Probe {
+    fn is_idempotent(&self) -> bool {
+        !matches!(self, Probe::Note(_) | Probe::Optional(_))
+    }
+}
+
+impl Mergeable for Probe {
+    fn mergeable(&self) -> bool {
+        matches!(self, Probe::Note(_))
+    }
+}
+
+impl EffectFingerprint for Probe {
+    fn fingerprint<H>(&self, hasher: &mut H)
+    where
+        H: Hasher,
+    {
+        match self {
+            Probe::Work(id) => {
+                hasher.write_u8(0);
+                hasher.write_u64(*id);
+            },
+            Probe::Read(key) => {
+                hasher.write_u8(1);
+                hasher.write_u64(*key);
+            },
+            Probe::Note(ids) => {
+                hasher.write_u8(2);
+                ids.fingerprint(hasher);
+            },
+            Probe::Optional(id) => {
+                hasher.write_u8(3);
+                hasher.write_u64(*id);
+            },
+            Probe::Flush => hasher.write_u8(4),
+        }
+    }
+}
+
+impl HasFlush for WithMeta<Probe> {
+    fn flush() -> Self {
+        WithMeta::new(Probe::Flush)
+    }
+
+    fn is_flush(&self) -> bool {
+        self.effect == Probe::Flush
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeOutput {
+    Done(u64),
+    Failed(u64),
+    Value(u64),
+    Noted,
+    Shed(u64),
+    Expired(u64),
+    Flushed,
+}
+
+impl ProbeOutput {
+    // the `Work` or `Optional` the output answers
+    fn answers(&self) -> Option<u64> {
+        match self {
+            ProbeOutput::Done(id)
+            | ProbeOutput::Failed(id)
+            | ProbeOutput::Shed(id)
+            | ProbeOutput::Expired(id) => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+impl Effect for ProbeOutput {
+    type Input = WithMeta<Probe>;
+}
+
+impl HasFlushed for ProbeOutput {
+    fn flushed() -> Self {
+        ProbeOutput::Flushed
+    }
+
+    fn is_flushed(&self) -> bool {
+        *self == ProbeOutput::Flushed
+    }
+}
+
+impl Sheddable for ProbeOutput {
+    fn shed(effect: &WithMeta<Probe>) -> Option<Self> {
+        match effect.effect {
+            Probe::Optional(id) => Some(ProbeOutput::Shed(id)),
+            _ => None,
+        }
+    }
+}
+
+impl HasDeadlineExceeded for ProbeOutput {
+    fn deadline_exceeded(effect: WithMeta<Probe>) -> Self {
+        ProbeOutput::Expired(effect.effect.id())
+    }
+}
+
+pub type ProbeBlock = Block<ProbeOutput, BoxedGenerator<WithMeta<Probe>, ()>>;
+
+// What a layer promises when it is composed with others, checked by `check`:
+// - conservation, every effect the computation performs is handled, answered
+//   by a layer or reported to the orphan callback, exactly once unless the
+//   layer says it `redelivers`;
+// - metadata, the priority and the deadline reach the handler as they were
+//   performed, a merged effect carries `EffectMeta::merged` of its parts;
+// - barrier, a flush reaches the handlers only after everything performed
+//   before it did, a layer never answers it.
+pub trait LayerContract {
+    fn name(&self) -> String;
+
+    // the layer delivers an effect the handler answered as failed again
+    fn redelivers(&self) -> bool {
+        false
+    }
+
+    fn apply(&self, block: ProbeBlock) -> ProbeBlock;
+}
+
+// a custom layer under a name, `("mine", |block: ProbeBlock| ...)`
+impl<F> LayerContract for (&'static str, F)
+where
+    F: Fn(ProbeBlock) -> ProbeBlock,
+{
+    fn name(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn apply(&self, block: ProbeBlock) -> ProbeBlock {
+        (self.1)(block)
+    }
+}
+
+// `Block::coalesce` with the window, the notes are merged
+#[derive(Debug, Clone, Copy)]
+pub struct Coalesce(pub usize);
+
+impl LayerContract for Coalesce {
+    fn name(&self) -> String {
+        format!("coalesce({})", self.0)
+    }
+
+    fn apply(&self, block: ProbeBlock) -> ProbeBlock {
+        block
+            .coalesce(self.0, |last, effect| match (last, effect) {
+                (
+                    WithMeta {
+                        effect: Probe::Note(mut ids),
+                        meta,
+                    },
+                    WithMeta {
+                        effect: Probe::Note(later),
+                        meta: later_meta,
+                    },
+                ) => {
+                    ids.extend(later);
+                    Ok(WithMeta {
+                        effect: Probe::Note(ids),
+                        meta: meta.merged(later_meta),
+                    })
+                },
+                pair => Err(pair),
+            })
+            .boxed()
+    }
+}
+
+// `Block::memoize` keyed by the fingerprint of the effect
+#[derive(Debug, Clone, Copy)]
+pub struct Memoize;
+
+impl LayerContract for Memoize {
+    fn name(&self) -> String {
+        "memoize".to_string()
+    }
+
+    fn apply(&self, block: ProbeBlock) -> ProbeBlock {
+        block
+            .memoize(MemoPolicy::Forever, |effect: &WithMeta<Probe>| {
+                Some(fingerprint::of(&effect.effect))
+            })
+            .boxed()
+    }
+}
+
+// `Block::shed_when` always overloaded
+#[derive(Debug, Clone, Copy)]
+pub struct ShedAll;
+
+impl LayerContract for ShedAll {
+    fn name(&self) -> String {
+        "shed_when".to_string()
+    }
+
+    fn apply(&self, block: ProbeBlock) -> ProbeBlock {
+        let policy = ShedPolicy {
+            metric: LoadMetric::EffectsPerRound,
+            threshold: -1.0,
+        };
+        block.shed_when(policy).boxed()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EnforceDeadlines;
+
+impl LayerContract for EnforceDeadlines {
+    fn name(&self) -> String {
+        "enforce_deadlines".to_string()
+    }
+
+    fn apply(&self, block: ProbeBlock) -> ProbeBlock {
+        block.enforce_deadlines().boxed()
+    }
+}
+
+// `Block::retry` with the attempts, `ProbeOutput::Failed` is a failure
+#[derive(Debug, Clone, Copy)]
+pub struct Retry(pub usize);
+
+impl LayerContract for Retry {
+    fn name(&self) -> String {
+        format!("retry({})", self.0)
+    }
+
+    fn redelivers(&self) -> bool {
+        true
+    }
+
+    fn apply(&self, block: ProbeBlock) -> ProbeBlock {
+        block
+            .retry(self.0, |output| matches!(output, ProbeOutput::Failed(_)))
+            .boxed()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StampMeta;
+
+impl LayerContract for StampMeta {
+    fn name(&self) -> String {
+        "stamp_meta".to_string()
+    }
+
+    fn apply(&self, block: ProbeBlock) -> ProbeBlock {
+        block.stamp_meta().boxed()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IdempotencyTokens;
+
+impl LayerContract for IdempotencyTokens {
+    fn name(&self) -> String {
+        "idempotency_tokens".to_string()
+    }
+
+    fn apply(&self, block: ProbeBlock) -> ProbeBlock {
+        block.idempotency_tokens().boxed()
+    }
+}
+
+// the built-in layers the random stacks are made of
+pub fn builtin() -> Vec<Box<dyn LayerContract>> {
+    vec![
+        Box::new(Coalesce(3)),
+        Box::new(Memoize),
+        Box::new(ShedAll),
+        Box::new(EnforceDeadlines),
+        Box::new(Retry(3)),
+        Box::new(StampMeta),
+        Box::new(IdempotencyTokens),
+    ]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invariant {
+    Conservation,
+    Metadata,
+    Barrier,
+}
+
+impl fmt::Display for Invariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Invariant::Conservation => write!(f, "conservation"),
+            Invariant::Metadata => write!(f, "metadata"),
+            Invariant::Barrier => write!(f, "barrier"),
+        }
+    }
+}
+
+// the first violation of a run, the stack is innermost first and the seed
+// reproduces the workload with `check_stack`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ContractViolation {
+    pub stack: Vec<String>,
+    pub seed: u64,
+    pub invariant: Invariant,
+    pub detail: String,
+}
+
+impl fmt::Display for ContractViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the stack [{}] with seed {} violates {}: {}",
+            self.stack.join(", "),
+            self.seed,
+            self.invariant,
+            self.detail,
+        )
+    }
+}
+
+impl Error for ContractViolation {}
+
+const STEPS: usize = 48;
+const ALONE: u64 = 8;
+const STACKS: u64 = 32;
+const DEPTH: u64 = 4;
+
+// what the computation performed and got, and what reached the handler
+#[derive(Default)]
+struct Ledger {
+    issued: BTreeMap<u64, EffectMeta>,
+    answerable: BTreeSet<u64>,
+    reads: usize,
+    notes_issued: BTreeSet<u64>,
+    notes_handled: BTreeSet<u64>,
+    flushes: usize,
+    flushes_handled: usize,
+    deliveries: BTreeMap<u64, u32>,
+    done: BTreeSet<u64>,
+    answers: Vec<ProbeOutput>,
+    orphans: Vec<ProbeOutput>,
+    violations: Vec<(Invariant, String)>,
+}
+
+impl Ledger {
+    fn violation(&mut self, invariant: Invariant, detail: String) {
+        self.violations.push((invariant, detail));
+    }
+
+    // the notes are merged by `EffectMeta::merged`, the others are one each
+    fn check_meta(&mut self, ids: &[u64], meta: &EffectMeta) {
+        let parts = ids.iter().filter_map(|id| self.issued.get(id));
+        let expected = parts.fold(None, |merged: Option<EffectMeta>, part| {
+            Some(match merged {
+                None => part.clone(),
+                Some(merged) => merged.merged(part.clone()),
+            })
+        });
+        if let Some(expected) = expected {
+            if (expected.priority, expected.deadline) != (meta.priority, meta.deadline) {
+                let detail = format!(
+                    "{:?} reached the handler with priority {} and deadline {:?}, \
+                     performed with {} and {:?}",
+                    ids, meta.priority, meta.deadline, expected.priority, expected.deadline,
+                );
+                self.violation(Invariant::Metadata, detail);
+            }
+        }
+    }
+
+    // after the flush is acknowledged, it reached the handler after the
+    // notes performed before it
+    fn check_barrier(&mut self) {
+        if self.flushes_handled != self.flushes {
+            let detail = format!("the flush {} never reached the handler", self.flushes);
+            self.violation(Invariant::Barrier, detail);
+        }
+        let pending = self
+            .notes_issued
+            .difference(&self.notes_handled)
+            .cloned()
+            .collect::<Vec<_>>();
+        if !pending.is_empty() {
+            let detail = format!("the notes {:?} are held past the flush", pending);
+            self.violation(Invariant::Barrier, detail);
+        }
+    }
+}
+
+// a seeded mix of the probes, some expired and some due later
+fn workload(seed: u64) -> Vec<WithMeta<Probe>> {
+    let mut rng = Rng(seed);
+    let now = deadline::now();
+    let later = now + Duration::from_secs(3600);
+    let mut next = 1;
+    let mut steps = Vec::with_capacity(STEPS);
+    for _ in 0..STEPS {
+        let roll = rng.next_u64() % 16;
+        let priority = (rng.next_u64() % 4) as u8;
+        let deadline = match rng.next_u64() % 4 {
+            0 => Some(now),
+            1 => Some(later + Duration::from_secs(rng.next_u64() % 60)),
+            _ => None,
+        };
+        let id = next;
+        next += 1;
+        let (probe, deadline) = match roll {
+            0..=5 => (Probe::Work(id), deadline),
+            6..=8 => (Probe::Read(rng.next_u64() % 4), None),
+            // the notes are one-way, an expired one would be answered
+            9..=12 => (Probe::Note(vec![id]), deadline.filter(|d| *d > now)),
+            13..=14 => (Probe::Optional(id), deadline),
+            _ => (Probe::Flush, None),
+        };
+        let mut step = WithMeta::new(probe).with_priority(priority);
+        if let Some(deadline) = deadline {
+            step = step.with_deadline(deadline);
+        }
+        steps.push(step);
+    }
+    steps
+}
+
+fn drain(context: &Context<ProbeOutput>, ledger: &RefCell<Ledger>) {
+    while let Some(output) = context.take() {
+        ledger.borrow_mut().answers.push(output);
+    }
+}
+
+fn computation(
+    context: Context<ProbeOutput>,
+    ledger: Rc<RefCell<Ledger>>,
+    steps: Vec<WithMeta<Probe>>,
+) -> impl Unpin + Generator<(), Yield = WithMeta<Probe>, Return = ()> {
+    move || {
+        for step in steps {
+            {
+                let mut ledger = ledger.borrow_mut();
+                match &step.effect {
+                    Probe::Work(id) | Probe::Optional(id) => {
+                        ledger.answerable.insert(*id);
+                        ledger.issued.insert(*id, step.meta.clone());
+                    },
+                    Probe::Note(ids) => {
+                        ledger.notes_issued.extend(ids.iter().cloned());
+                        for id in ids {
+                            ledger.issued.insert(*id, step.meta.clone());
+                        }
+                    },
+                    Probe::Read(_) => ledger.reads += 1,
+                    Probe::Flush => ledger.flushes += 1,
+                }
+            }
+            if step.is_flush() {
+                yield flush::barrier(&context);
+                flush::acknowledged(&context);
+                ledger.borrow_mut().check_barrier();
+            } else {
+                yield step;
+            }
+            drain(&context, &ledger);
+        }
+        // the last flush releases whatever the layers still hold
+        ledger.borrow_mut().flushes += 1;
+        yield flush::barrier(&context);
+        flush::acknowledged(&context);
+        ledger.borrow_mut().check_barrier();
+        drain(&context, &ledger);
+    }
+}
+
+// fails the first delivery of every fifth `Work`, so the layers delivering
+// again have something to do
+fn terminal(
+    ledger: Rc<RefCell<Ledger>>,
+    redelivers: bool,
+) -> impl FnMut(WithMeta<Probe>) -> Result<ProbeOutput, WithMeta<Probe>> {
+    move |effect: WithMeta<Probe>| {
+        let mut ledger = ledger.borrow_mut();
+        // `acknowledge_flush` answers it
+        if effect.is_flush() {
+            ledger.flushes_handled += 1;
+            return Err(effect);
+        }
+        match effect.effect {
+            Probe::Work(id) => {
+                ledger.check_meta(&[id], &effect.meta);
+                let deliveries = {
+                    let deliveries = ledger.deliveries.entry(id).or_default();
+                    *deliveries += 1;
+                    *deliveries
+                };
+                if deliveries > 1 && !redelivers {
+                    let detail = format!("the work {} is delivered {} times", id, deliveries);
+                    ledger.violation(Invariant::Conservation, detail);
+                }
+                if ledger.done.contains(&id) {
+                    let detail = format!("the work {} is delivered again after it was done", id);
+                    ledger.violation(Invariant::Conservation, detail);
+                }
+                if id % 5 == 0 && deliveries == 1 {
+                    Ok(ProbeOutput::Failed(id))
+                } else {
+                    ledger.done.insert(id);
+                    Ok(ProbeOutput::Done(id))
+                }
+            },
+            Probe::Read(key) => Ok(ProbeOutput::Value(key)),
+            Probe::Note(ids) => {
+                ledger.check_meta(&ids, &effect.meta);
+                for id in ids {
+                    if !ledger.notes_handled.insert(id) {
+                        let detail = format!("the note {} is handled twice", id);
+                        ledger.violation(Invariant::Conservation, detail);
+                    }
+                }
+                Ok(ProbeOutput::Noted)
+            },
+            Probe::Optional(id) => {
+                ledger.check_meta(&[id], &effect.meta);
+                Ok(ProbeOutput::Done(id))
+            },
+            Probe::Flush => Err(effect),
+        }
+    }
+}
+
+// Runs the workload of the seed through the layers, the first one is the
+// innermost, it sees the effects first.
+pub fn check_stack(stack: &[&dyn LayerContract], seed: u64) -> Result<(), ContractViolation> {
+    let ledger = Rc::new(RefCell::new(Ledger::default()));
+    let steps = workload(seed);
+    let (block, accounting) = {
+        let ledger = ledger.clone();
+        move |context: Context<ProbeOutput>| computation(context, ledger, steps)
+    }
+    .into_block()
+    .with_accounting();
+    block.context().on_orphan({
+        let ledger = ledger.clone();
+        move |output| ledger.borrow_mut().orphans.push(output)
+    });
+    let block = stack
+        .iter()
+        .fold(block.boxed(), |block, layer| layer.apply(block));
+    let redelivers = stack.iter().any(|layer| layer.redelivers());
+    block
+        .add_handler(terminal(ledger.clone(), redelivers))
+        .acknowledge_flush()
+        .assert_handled()
+        .run();
+
+    let mut ledger = ledger.borrow_mut();
+    for id in ledger.answerable.clone() {
+        let answers = ledger
+            .answers
+            .iter()
+            .filter(|output| output.answers() == Some(id))
+            .count();
+        if answers != 1 {
+            let detail = format!("the effect {} got {} answers", id, answers);
+            ledger.violation(Invariant::Conservation, detail);
+        }
+    }
+    let values = ledger
+        .answers
+        .iter()
+        .filter(|output| matches!(output, ProbeOutput::Value(_)))
+        .count();
+    if values != ledger.reads {
+        let detail = format!("{} reads got {} values", ledger.reads, values);
+        ledger.violation(Invariant::Conservation, detail);
+    }
+    if ledger.notes_issued != ledger.notes_handled {
+        let detail = format!(
+            "the notes {:?} are never handled",
+            ledger
+                .notes_issued
+                .difference(&ledger.notes_handled)
+                .collect::<Vec<_>>(),
+        );
+        ledger.violation(Invariant::Conservation, detail);
+    }
+    if cfg!(feature = "diagnostics") {
+        let expired = ledger
+            .answers
+            .iter()
+            .chain(&ledger.orphans)
+            .filter(|output| matches!(output, ProbeOutput::Expired(_)))
+            .count() as u64;
+        let counted = accounting.report().count(Counter::Expired);
+        if counted != expired {
+            let detail = format!("{} expired effects are counted {} times", expired, counted);
+            ledger.violation(Invariant::Conservation, detail);
+        }
+    }
+
+    let first = ledger.violations.drain(..).next();
+    match first {
+        None => Ok(()),
+        Some((invariant, detail)) => Err(ContractViolation {
+            stack: stack.iter().map(|layer| layer.name()).collect(),
+            seed,
+            invariant,
+            detail,
+        }),
+    }
+}
+
+// Runs the layer alone and in random stacks of up to four with the built-in
+// layers, each built-in at most once and at a random place around it.
+pub fn try_check(layer: &dyn LayerContract) -> Result<(), ContractViolation> {
+    for seed in 0..ALONE {
+        check_stack(&[layer], seed)?;
+    }
+    let name = layer.name();
+    let others = builtin()
+        .into_iter()
+        .filter(|other| other.name() != name)
+        .collect::<Vec<_>>();
+    for seed in 0..STACKS {
+        let mut rng = Rng(seed ^ 0x5eed);
+        let mut pool = others
+            .iter()
+            .map(|other| other.as_ref())
+            .collect::<Vec<_>>();
+        let mut stack = Vec::new();
+        for _ in 1..(1 + rng.next_u64() % DEPTH) {
+            if pool.is_empty() {
+                break;
+            }
+            let index = (rng.next_u64() % pool.len() as u64) as usize;
+            stack.push(pool.remove(index));
+        }
+        let at = (rng.next_u64() % (stack.len() as u64 + 1)) as usize;
+        stack.insert(at, layer);
+        check_stack(&stack, seed)?;
+    }
+    Ok(())
+}
+
+/// Checks that the layer keeps the contract of the built-in ones, panics with
+/// the first `ContractViolation`.
+///
+/// ```
+/// use aeiou::layer_conformance::{self, ProbeBlock};
+///
+/// layer_conformance::check(("stamp twice", |block: ProbeBlock| {
+///     block.stamp_meta().stamp_meta().boxed()
+/// }));
+/// ```
+pub fn check<L>(layer: L)
+where
+    L: LayerContract,
+{
+    if let Err(violation) = try_check(&layer) {
+        panic!("{}", violation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::WithMeta;
+    use super::{
+        builtin, check, check_stack, try_check, Coalesce, EnforceDeadlines, Invariant,
+        LayerContract, Memoize, Probe, ProbeBlock, ProbeOutput, Retry,
+    };
+
+    #[test]
+    fn builtin_layers() {
+        for layer in builtin() {
+            if let Err(violation) = try_check(layer.as_ref()) {
+                panic!("{}", violation);
+            }
+        }
+    }
+
+    // a memo hit answered the second flush, so the note the coalescing layer
+    // outside of it held was still held after the flush
+    #[test]
+    fn memo_does_not_answer_flush() {
+        for seed in 0..16 {
+            check_stack(&[&Memoize as &dyn LayerContract, &Coalesce(8)], seed).unwrap();
+        }
+    }
+
+    // an expired effect is counted once whichever side of `retry` it expires
+    #[test]
+    fn expired_counted_once() {
+        for seed in 0..16 {
+            check_stack(&[&EnforceDeadlines as &dyn LayerContract, &Retry(3)], seed).unwrap();
+            check_stack(&[&Retry(3) as &dyn LayerContract, &EnforceDeadlines], seed).unwrap();
+        }
+    }
+
+    #[test]
+    fn custom_layer() {
+        check(("stamp twice", |block: ProbeBlock| {
+            block.stamp_meta().stamp_meta().boxed()
+        }));
+
+        // answers the notes on its own, so they never reach the handler
+        let swallow = ("swallow notes", |block: ProbeBlock| {
+            block
+                .add_handler(|effect: WithMeta<Probe>| match effect.effect {
+                    Probe::Note(_) => Ok(ProbeOutput::Noted),
+                    _ => Err(effect),
+                })
+                .boxed()
+        });
+        let violation = try_check(&swallow).unwrap_err();
+        assert_eq!(violation.stack, ["swallow notes"]);
+        assert_eq!(violation.seed, 0);
+        assert_eq!(violation.invariant, Invariant::Barrier);
+    }
+}
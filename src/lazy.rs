@@ -0,0 +1,281 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{rc::Rc, cell::RefCell, hash::Hasher, mem, fmt};
+use super::fingerprint::EffectFingerprint;
+
+// What the layers re-delivering an effect need of it, a copy that does not
+// do the work again. A `Lazy` payload is copied unbuilt, the copies share
+// the value once one of them is forced.
+pub trait CheapClone {
+    fn cheap_clone(&self) -> Self;
+}
+
+impl<T> CheapClone for T
+where
+    T: Clone,
+{
+    fn cheap_clone(&self) -> Self {
+        self.clone()
+    }
+}
+
+enum State<T> {
+    Unbuilt(Box<dyn FnOnce() -> T>),
+    // the builder is running, forcing the payload from inside it is a bug
+    Building,
+    Built(Rc<T>),
+}
+
+/// A payload of an effect built only when a handler needs it. The layers
+/// dropping or answering the effect on their own never build it.
+///
+/// ```
+/// use aeiou::Lazy;
+///
+/// let mut payload = Lazy::new(|| vec![0u8; 1024]);
+/// let mut copy = payload.clone();
+/// assert!(!payload.is_forced());
+/// assert_eq!(copy.force().len(), 1024);
+/// // the copies share the value
+/// assert!(payload.is_forced());
+/// assert_eq!(payload.force().len(), 1024);
+/// ```
+pub struct Lazy<T> {
+    state: Rc<RefCell<State<T>>>,
+    // the value once this copy forced it or found it forced
+    value: Option<Rc<T>>,
+}
+
+impl<T> Clone for Lazy<T> {
+    fn clone(&self) -> Self {
+        Lazy {
+            state: self.state.clone(),
+            value: self.value.clone(),
+        }
+    }
+}
+
+impl<T> Lazy<T> {
+    pub fn new<F>(build: F) -> Self
+    where
+        F: FnOnce() -> T + 'static,
+    {
+        Lazy {
+            state: Rc::new(RefCell::new(State::Unbuilt(Box::new(build)))),
+            value: None,
+        }
+    }
+
+    pub fn ready(value: T) -> Self {
+        let value = Rc::new(value);
+        Lazy {
+            state: Rc::new(RefCell::new(State::Built(value.clone()))),
+            value: Some(value),
+        }
+    }
+
+    // builds the payload unless a copy of it did
+    pub fn force(&mut self) -> &T {
+        if self.value.is_none() {
+            let state = mem::replace(&mut *self.state.borrow_mut(), State::Building);
+            let value = match state {
+                State::Unbuilt(build) => Rc::new(build()),
+                State::Built(value) => value,
+                State::Building => panic!("`Lazy::force` called while the payload is built"),
+            };
+            *self.state.borrow_mut() = State::Built(value.clone());
+            self.value = Some(value);
+        }
+        self.value.as_ref().expect("the value is set above")
+    }
+
+    pub fn is_forced(&self) -> bool {
+        matches!(&*self.state.borrow(), State::Built(_))
+    }
+
+    fn built(&self) -> Option<Rc<T>> {
+        match &*self.state.borrow() {
+            State::Built(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Lazy<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.built() {
+            Some(value) => f.debug_tuple("Lazy").field(&value).finish(),
+            None => f.debug_tuple("Lazy").field(&"unforced").finish(),
+        }
+    }
+}
+
+// An unforced payload is a placeholder, the same for all of them, and it is
+// not built for the fingerprint. So the fingerprint of an effect changes once
+// a handler forces its payload, compare the ones taken at the same point.
+impl<T> EffectFingerprint for Lazy<T>
+where
+    T: EffectFingerprint,
+{
+    fn fingerprint<H>(&self, hasher: &mut H)
+    where
+        H: Hasher,
+    {
+        match self.built() {
+            None => hasher.write_u8(0),
+            Some(value) => {
+                hasher.write_u8(1);
+                value.fingerprint(hasher);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::Cell, hash::Hasher, ops::Generator};
+    use crate::{
+        Context, Effect, EffectFingerprint, EffectName, HasDenied, Identity, IdentityPolicy,
+        Idempotent, IntoBlock, StableHasher, WithMeta, perform,
+    };
+    use super::Lazy;
+
+    #[derive(Debug, Clone)]
+    enum Effects {
+        Publish(Lazy<Vec<u8>>),
+    }
+
+    impl Idempotent for Effects {}
+
+    impl EffectName for Effects {
+        fn effect_name(&self) -> &'static str {
+            "Publish"
+        }
+    }
+
+    impl EffectFingerprint for Effects {
+        fn fingerprint<H>(&self, hasher: &mut H)
+        where
+            H: Hasher,
+        {
+            let Effects::Publish(payload) = self;
+            payload.fingerprint(hasher)
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Outputs {
+        Published(usize),
+        Dropped,
+        Denied,
+    }
+
+    impl Effect for Outputs {
+        type Input = WithMeta<Effects>;
+    }
+
+    impl HasDenied for Outputs {
+        fn denied(effect: WithMeta<Effects>, identity: Identity) -> Self {
+            let _ = (effect, identity);
+            Outputs::Denied
+        }
+    }
+
+    // counts the builds of the payload
+    fn publisher(
+        context: Context<Outputs>,
+        builds: Rc<Cell<u32>>,
+    ) -> impl Unpin + Generator<(), Yield = WithMeta<Effects>, Return = Option<Outputs>> {
+        move || {
+            let payload = Lazy::new(move || {
+                builds.set(builds.get() + 1);
+                b"serialized".to_vec()
+            });
+            perform!(WithMeta::new(Effects::Publish(payload)));
+            context.take()
+        }
+    }
+
+    #[test]
+    fn denied_never_built() {
+        let builds = Rc::new(Cell::new(0));
+        let tenant = Identity::new("tenant");
+        let output = {
+            let builds = builds.clone();
+            move |context: Context<Outputs>| publisher(context, builds)
+        }
+        .into_block_as(tenant.clone())
+        .stamp_meta()
+        .add_handler(IdentityPolicy::new().deny(tenant, &["Publish"]))
+        .add_handler(|effect: WithMeta<Effects>| {
+            let Effects::Publish(mut payload) = effect.effect;
+            Ok(Outputs::Published(payload.force().len()))
+        })
+        .assert_handled()
+        .run();
+        assert_eq!(output, Some(Outputs::Denied));
+        assert_eq!(builds.get(), 0);
+    }
+
+    #[test]
+    fn built_once_across_retry() {
+        let builds = Rc::new(Cell::new(0));
+        let attempts = Rc::new(Cell::new(0));
+        let output = {
+            let builds = builds.clone();
+            move |context: Context<Outputs>| publisher(context, builds)
+        }
+        .into_block()
+        .retry(3, |output| *output == Outputs::Dropped)
+        .add_handler({
+            let attempts = attempts.clone();
+            move |effect: WithMeta<Effects>| {
+                let Effects::Publish(mut payload) = effect.effect;
+                let len = payload.force().len();
+                attempts.set(attempts.get() + 1);
+                // the first delivery is lost after the payload is built
+                if attempts.get() == 1 {
+                    Ok(Outputs::Dropped)
+                } else {
+                    Ok(Outputs::Published(len))
+                }
+            }
+        })
+        .assert_handled()
+        .run();
+        assert_eq!(output, Some(Outputs::Published(10)));
+        assert_eq!(attempts.get(), 2);
+        assert_eq!(builds.get(), 1);
+    }
+
+    fn fingerprint(effect: &Effects) -> u64 {
+        let mut hasher = StableHasher::default();
+        effect.fingerprint(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn fingerprint_of_unforced() {
+        let mut payload = Lazy::new(|| b"a".to_vec());
+        let effect = Effects::Publish(payload.clone());
+        let other = Effects::Publish(Lazy::new(|| b"b".to_vec()));
+        // unforced payloads are all the same placeholder
+        let unforced = fingerprint(&effect);
+        assert_eq!(unforced, fingerprint(&other));
+        assert_eq!(format!("{:?}", effect), r#"Publish(Lazy("unforced"))"#);
+
+        // forcing a copy changes the fingerprint of the effect holding another
+        payload.force();
+        let forced = fingerprint(&effect);
+        assert_ne!(forced, unforced);
+        assert_eq!(
+            forced,
+            fingerprint(&Effects::Publish(Lazy::ready(b"a".to_vec())))
+        );
+        assert_eq!(format!("{:?}", effect), "Publish(Lazy([97]))");
+    }
+}
@@ -7,24 +7,518 @@
 #[cfg(feature = "aeiou-macros")]
 pub use aeiou_macros::*;
 
+// The supertrait of the traits only this crate implements, the module is
+// private so nobody else can name it.
+mod sealed {
+    pub trait Sealed<M = ()> {}
+}
+
+#[doc(hidden)]
+pub mod doctest_support;
+
+#[doc(hidden)]
+pub mod describe;
+
 mod computation;
-pub use self::computation::{Handler, Effect, Select};
+pub use self::computation::{
+    Handler, Effect, EffectName, EffectFilter, VariantFilter, Select, TakeResult,
+};
 
 mod context;
-pub use self::context::Context;
+pub use self::context::{Context, OrderPolicy, Claim};
 
 mod block;
-pub use self::block::{Block, IntoBlock};
+pub use self::block::{Block, IntoBlock, IntoBlockWith};
+
+mod template;
+pub use self::template::Template;
+
+mod script;
+pub use self::script::{Script, ScriptStep, Expecting, ScriptRunner};
+
+mod scenario;
+pub use self::scenario::{Scenario, Divergence};
+#[doc(hidden)]
+pub use self::scenario::duration_literal;
+
+mod accounting;
+pub use self::accounting::{
+    Accounting, Category, Counter, MemReport, Usage, SiteStats, SourceStats,
+};
+#[cfg(feature = "diagnostics")]
+#[doc(hidden)]
+pub use self::accounting::count_site;
+// the tests of `aeiou-lean` run with the diagnostics when the features are
+// unified with the rest of the workspace
+#[doc(hidden)]
+pub const DIAGNOSTICS: bool = cfg!(feature = "diagnostics");
+
+mod source;
+pub use self::source::{Source, OutputSource, SourcePoll, SourceFairness};
+
+pub mod sources;
+
+mod fingerprint;
+pub use self::fingerprint::{EffectFingerprint, StableHasher, Trace, Fingerprint};
+
+mod journal;
+pub use self::journal::{EffectJournal, MemoryJournal, FileJournal, JournalError};
+
+mod history;
+pub use self::history::{History, HistoryConfig, HistoryEntry, Compaction};
+
+mod scope;
+pub use self::scope::ScopeGuard;
+
+mod scoped;
+pub use self::scoped::{Scoped, ScopeExpired, BufferPool, PooledBuf};
+
+#[cfg(feature = "diagnostics")]
+mod pause;
+#[cfg(feature = "diagnostics")]
+pub use self::pause::{Event, PauseReason};
+
+mod swap;
+pub use self::swap::{SwapHandle, SwapError};
+
+mod health;
+pub use self::health::{HealthStatus, HealthTransition, StartupPolicy, StartupError};
+
+mod routing;
+pub use self::routing::HandlerTag;
+
+pub mod deadline;
+
+pub mod escalation;
+
+pub mod sim;
+
+pub mod chaos;
+
+pub mod schema;
+
+mod coalesce;
+pub use self::coalesce::Mergeable;
+
+mod idempotency;
+pub use self::idempotency::{Idempotent, IdempotencyToken, IdempotentHandler};
+
+mod pool;
+pub use self::pool::{PoolHandler, PoolConfig, PoolOp, Poolable};
+
+mod memo;
+pub use self::memo::{MemoPolicy, InvalidationHandle};
+
+mod invariant;
+pub use self::invariant::{InvariantCtx, InvariantViolation};
+
+mod observe;
+pub use self::observe::{ObserverConfig, ObserverCtx, ObserverLoop};
+
+mod size_limit;
+pub use self::size_limit::{
+    PayloadSize, PayloadTooLarge, HasPayloadTooLarge, Truncation, MarkTruncated, SizeLimit,
+    SizeLimits,
+};
+
+mod dry_run;
+pub use self::dry_run::{EffectClass, Classify, DryRunEntry, DryRunReport};
+
+mod teardown;
+pub use self::teardown::{HandlerFinished, HasHandlerFinished};
+
+mod explain;
+pub use self::explain::{EffectDescription, PendingExplanation};
+
+mod reuse;
+pub use self::reuse::ContextReuse;
+
+mod borrowed;
+pub use self::borrowed::HandlerSetRef;
+
+mod family;
+pub use self::family::{Sum, FamilyMismatch};
+
+mod flush;
+pub use self::flush::{Flush, Flushed, HasFlush, HasFlushed};
+#[doc(hidden)]
+pub use self::flush::{barrier as flush_barrier, acknowledged as flush_acknowledged};
+
+mod ack;
+pub use self::ack::{Ack, AckPart, acking_handler};
+#[doc(hidden)]
+pub use self::ack::{expected as ack_expected, acknowledged as ack_acknowledged};
+
+mod mock;
+pub use self::mock::MockDefault;
+#[doc(hidden)]
+pub use self::mock::MockFallback;
+
+mod mirror;
+pub use self::mirror::{MirrorOutcome, DivergenceReport, MirrorPanic, MirrorLog};
+
+mod load;
+pub use self::load::{LoadEffect, LoadQuery, LoadReport, LoadMetric, ShedPolicy, Sheddable};
+
+mod two_phase;
+pub use self::two_phase::{OpId, TwoPhase, Operation, TwoPhaseHandler, Completer};
+
+mod progress;
+pub use self::progress::{
+    HasProgress, Progressing, ProgressReporter, ProgressSnapshot, ProgressHandle,
+};
+#[doc(hidden)]
+pub use self::progress::{declare_milestones, reach_milestone};
+
+mod manifest;
+pub use self::manifest::{CapabilitiesManifest, Capability, CapabilityChange};
+
+mod inspect;
+pub use self::inspect::{RuntimeSnapshot, InFlight, HandlerSummary, InspectError};
+
+mod sampling;
+pub use self::sampling::{SampleConfig, Detail, Trigger, Capture, Captures};
+
+mod session;
+pub use self::session::{SessionKey, WithSession, Session, SessionError};
+
+mod pace;
+pub use self::pace::{PaceConfig, PaceHandle};
+
+mod sink;
+pub use self::sink::{ResponseSink, SinkState};
+
+mod prefetch;
+pub use self::prefetch::PrefetchConfig;
+
+mod latency;
+pub use self::latency::{Responds, Custody, LatencyViolation, LatencySummary, Latency};
+
+mod alloc_tracking;
+pub use self::alloc_tracking::AllocTracking;
+
+mod cancel;
+pub use self::cancel::CancelToken;
+
+mod meta;
+pub use self::meta::{EffectMeta, WithMeta, HasPriority, EffectQueue, QueueOrder, Inversion};
+#[doc(hidden)]
+pub use self::cancel::{attach as cancel_attach, detach as cancel_detach};
+
+mod identity;
+pub use self::identity::{Identity, IdentityPolicy, HasDenied};
+
+mod lazy;
+pub use self::lazy::{Lazy, CheapClone};
 
-pub mod new;
+mod incremental;
+pub use self::incremental::{IncrementalCache, ValidatingHandler, Validation};
+#[doc(hidden)]
+pub use self::incremental::MemoizedCall;
 
+pub mod reactor;
+
+pub mod plugin;
+
+#[doc(hidden)]
+pub mod trampoline;
+pub use self::trampoline::{PushFrame, FrameId};
+
+mod fallible;
+pub use self::fallible::{FallibleBlock, BoxedGenerator};
+#[doc(hidden)]
+pub use self::fallible::resume as resume_callee;
+
+#[cfg(feature = "async")]
+mod stream;
+#[cfg(feature = "async")]
+pub use self::stream::OutputStream;
+
+pub mod tasks;
+
+// the old name of `tasks`, kept for one release
+#[deprecated(note = "renamed to `tasks`")]
+pub mod new {
+    pub use super::tasks::*;
+}
+
+pub mod channel;
+
+pub mod iterext;
+
+pub mod gen_ext;
+
+pub mod stats;
+
+pub mod blocking;
+
+pub mod effect_io;
+
+pub mod quota;
+
+pub mod differential;
+
+pub mod layer_conformance;
+
+pub mod causality;
+
+#[cfg(feature = "serde")]
+pub mod trace;
+
+#[cfg(feature = "serde")]
+pub mod codec;
+
+#[cfg(feature = "bridge")]
+pub mod bridge;
+
+#[cfg(feature = "bench")]
+pub mod bench_harness;
+
+#[cfg(feature = "resolve")]
+pub mod resolve;
+
+#[cfg(feature = "wasm")]
+pub mod web;
+
+/// Yields an effect. With a context it also takes the selected part of the response.
+///
+/// ```
+/// #![feature(generators)]
+/// use aeiou::{Context, IntoBlock, perform};
+///
+/// aeiou::simple_effects! {
+///     Effects { Read }
+///     Outputs { Data(String) }
+///     parts { Data(String) => Line }
+/// }
+///
+/// let line = (|context: Context<Outputs>| {
+///     move || {
+///         let Line(line) = perform!(Effects::Read, &context);
+///         line
+///     }
+/// })
+/// .into_block()
+/// .add_handler(|Effects::Read| Ok(Outputs::Data("hello".to_string())))
+/// .assert_handled()
+/// .run();
+/// assert_eq!(line, "hello");
+/// ```
+///
+/// Without a context the response stays in the queue.
+///
+/// ```
+/// #![feature(generators)]
+/// use aeiou::{Context, IntoBlock, perform};
+///
+/// aeiou::simple_effects! {
+///     Effects { Print(&'static str) }
+///     Outputs { Printed }
+/// }
+///
+/// let pending = (|context: Context<Outputs>| {
+///     move || {
+///         perform!(Effects::Print("a"));
+///         perform!(Effects::Print("b"));
+///         context.len()
+///     }
+/// })
+/// .into_block()
+/// .add_handler(|Effects::Print(_)| Ok(Outputs::Printed))
+/// .assert_handled()
+/// .run();
+/// assert_eq!(pending, 2);
+/// ```
 #[macro_export]
 macro_rules! perform {
     ($e:expr, $ctx:expr) => {{
         yield $e;
-        aeiou::Select::take($ctx).unwrap()
+        $crate::take_part!($crate::Select::try_take($ctx), $e)
     }};
     ($e:expr) => {{
         yield $e;
     }};
 }
+
+// A filter admitting the listed variants, whatever their fields are.
+#[macro_export]
+macro_rules! filter_variants {
+    ($($ty:ident :: $variant:ident)|+) => {
+        $crate::VariantFilter::new(&[$(stringify!($variant)),+], |effect| {
+            matches!(effect, $($ty::$variant { .. })|+)
+        })
+    };
+}
+
+// Outputs matching none of the parts stay in the queue in their order.
+#[macro_export]
+macro_rules! perform_select {
+    ($e:expr, $ctx:expr, { $($part_name:ident: $part:ty => $body:expr),+ $(,)? }) => {{
+        {
+            #[allow(dead_code)]
+            trait PerformSelectDistinctParts {}
+            $(impl PerformSelectDistinctParts for $part {})+
+        }
+        yield $e;
+        let mut skipped = ::std::vec::Vec::new();
+        let selected = loop {
+            $(
+            match <_ as $crate::Select<$part>>::try_take($ctx) {
+                $crate::TakeResult::Matched($part_name) => break $body,
+                $crate::TakeResult::Mismatched(output) => $crate::Context::put_front($ctx, output),
+                $crate::TakeResult::Empty => panic!(
+                    "no output matches `perform_select!` after `{}`",
+                    stringify!($e)
+                ),
+            }
+            )+
+            skipped.extend($crate::Context::take($ctx));
+        };
+        for output in skipped.into_iter().rev() {
+            $crate::Context::put_front($ctx, output);
+        }
+        selected
+    }};
+}
+
+/// Yields the idle effect while the context is empty.
+///
+/// ```
+/// #![feature(generators)]
+/// use std::{rc::Rc, cell::Cell};
+/// use aeiou::{Context, IntoBlock, wait_any};
+///
+/// aeiou::simple_effects! {
+///     Effects { Idle }
+///     Outputs { Tick }
+/// }
+///
+/// let idle = Rc::new(Cell::new(0));
+/// let tick = (|context: Context<Outputs>| {
+///     move || {
+///         context.put(Outputs::Tick);
+///         wait_any!(&context, Effects::Idle);
+///         context.take();
+///         wait_any!(&context, Effects::Idle);
+///         context.take()
+///     }
+/// })
+/// .into_block()
+/// .add_handler({
+///     let idle = idle.clone();
+///     move |Effects::Idle| {
+///         idle.set(idle.get() + 1);
+///         Ok(Outputs::Tick)
+///     }
+/// })
+/// .assert_handled()
+/// .run();
+/// assert_eq!(tick, Some(Outputs::Tick));
+/// assert_eq!(idle.get(), 1);
+/// ```
+#[macro_export]
+macro_rules! wait_any {
+    ($ctx:expr, $idle:expr) => {{
+        while $crate::Context::is_empty($ctx) {
+            yield $idle;
+        }
+    }};
+}
+
+/// Takes the next output, yielding the idle effect while the context is empty.
+/// Evaluates to `None` once the context is empty and closed.
+///
+/// ```
+/// #![feature(generators)]
+/// use aeiou::{Context, IntoBlock, recv};
+///
+/// aeiou::simple_effects! {
+///     Effects { Idle }
+///     Outputs { Tick(u32) }
+/// }
+///
+/// let ticks = (|context: Context<Outputs>| {
+///     context.put(Outputs::Tick(1));
+///     context.put(Outputs::Tick(2));
+///     context.close();
+///     move || {
+///         let mut ticks = Vec::new();
+///         while let Some(output) = recv!(&context, Effects::Idle) {
+///             if let Outputs::Tick(n) = output {
+///                 ticks.push(n);
+///             }
+///         }
+///         ticks
+///     }
+/// })
+/// .into_block()
+/// .assert_handled()
+/// .run();
+/// assert_eq!(ticks, [1, 2]);
+/// ```
+#[macro_export]
+macro_rules! recv {
+    ($ctx:expr, $idle:expr) => {{
+        loop {
+            if let Some(output) = $crate::Context::take($ctx) {
+                break Some(output);
+            }
+            if $crate::Context::is_closed($ctx) {
+                break None;
+            }
+            yield $idle;
+        }
+    }};
+}
+
+// Drains every pending output, the second form yields `idle` until there is
+// one, an empty batch then means the context is closed and drained.
+#[macro_export]
+macro_rules! take_all {
+    ($ctx:expr) => {
+        $crate::Context::take_batch($ctx, usize::MAX)
+    };
+    ($ctx:expr, $idle:expr) => {{
+        loop {
+            let batch = $crate::Context::take_batch($ctx, usize::MAX);
+            if !batch.is_empty() || $crate::Context::is_closed($ctx) && $crate::Context::is_empty($ctx) {
+                break batch;
+            }
+            yield $idle;
+        }
+    }};
+}
+
+// Narrows the context to the field of a single field variant.
+#[macro_export]
+macro_rules! narrow_context {
+    ($parent:expr, $($variant:ident)::+) => {
+        $crate::Context::narrow(&$parent, $($variant)::+, |output| match output {
+            $($variant)::+(value) => Ok(value),
+            #[allow(unreachable_patterns)]
+            other => Err(other),
+        })
+    };
+}
+
+// Performs the flush barrier and waits for its acknowledgement, every effect
+// performed before it has reached its handler by then.
+#[macro_export]
+macro_rules! flush {
+    ($ctx:expr) => {{
+        yield $crate::flush_barrier($ctx);
+        $crate::flush_acknowledged($ctx)
+    }};
+}
+
+// Performs a fire-and-forget effect and takes its acknowledgement, the output
+// enum derives `Ack`. Another output in front of the queue is `Mismatched`.
+#[macro_export]
+macro_rules! perform_ack {
+    ($e:expr, $ctx:expr) => {{
+        let effect = $e;
+        let expected = $crate::ack_expected($ctx, &effect);
+        yield effect;
+        $crate::ack_acknowledged($ctx, expected)
+    }};
+}
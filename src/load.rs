@@ -0,0 +1,279 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    ops::{Generator, GeneratorState},
+};
+use super::{block::Block, computation::Effect};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadEffect {
+    Query,
+}
+
+pub trait LoadQuery
+where
+    Self: Sized,
+{
+    fn into_load(self) -> Result<LoadEffect, Self>;
+}
+
+// the derive takes the unit output variant answering the effect variants
+// listed in its `#[sheddable(..)]`, the effect enum is in `#[input(..)]`
+pub trait Sheddable
+where
+    Self: Sized + Effect,
+{
+    // the default response, `None` for effects that must reach a handler
+    fn shed(effect: &Self::Input) -> Option<Self>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LoadMetric {
+    EffectsPerRound,
+    QueueDepth,
+    StallRounds,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShedPolicy {
+    pub metric: LoadMetric,
+    pub threshold: f64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct LoadReport {
+    pub effects_per_round: f64,
+    pub queue_depth: f64,
+    pub stall_rounds: f64,
+    pub rounds: u64,
+    pub shed: u64,
+}
+
+impl LoadReport {
+    pub fn metric(&self, metric: LoadMetric) -> f64 {
+        match metric {
+            LoadMetric::EffectsPerRound => self.effects_per_round,
+            LoadMetric::QueueDepth => self.queue_depth,
+            LoadMetric::StallRounds => self.stall_rounds,
+        }
+    }
+}
+
+#[derive(Default)]
+struct LoadState {
+    alpha: f64,
+    report: LoadReport,
+}
+
+impl LoadState {
+    fn sample(&mut self, effects: u64, depth: usize) {
+        let alpha = self.alpha;
+        let ema = |value: &mut f64, sample: f64| *value += alpha * (sample - *value);
+        let report = &mut self.report;
+        ema(&mut report.effects_per_round, effects as f64);
+        ema(&mut report.queue_depth, depth as f64);
+        ema(
+            &mut report.stall_rounds,
+            if effects == 0 { 1.0 } else { 0.0 },
+        );
+        report.rounds += 1;
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    fn load_state(&self) -> Rc<RefCell<LoadState>> {
+        self.context()
+            .extension(|| RefCell::new(LoadState::default()))
+    }
+
+    pub fn with_load_metrics(
+        self,
+        half_life_rounds: u32,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        E: From<LoadReport> + 'static,
+        E::Input: LoadQuery,
+    {
+        let state = self.load_state();
+        state.borrow_mut().alpha = 1.0 - 0.5f64.powf(1.0 / f64::from(half_life_rounds.max(1)));
+        let context = self.context();
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            move || loop {
+                let start = context.produced();
+                let mut queries = 0;
+                let effect = loop {
+                    match s.resume() {
+                        GeneratorState::Complete(r) => return r,
+                        GeneratorState::Yielded(effect) => match effect.into_load() {
+                            Ok(LoadEffect::Query) => {
+                                queries += 1;
+                                context.put(state.borrow().report.into());
+                            },
+                            Err(effect) => break effect,
+                        },
+                    }
+                };
+                let effects = context.produced() - start - queries;
+                state.borrow_mut().sample(effects, context.len());
+                yield effect;
+            }
+        };
+        Block::new(context, generator)
+    }
+
+    pub fn shed_when(
+        self,
+        policy: ShedPolicy,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        E: Sheddable + 'static,
+    {
+        let state = self.load_state();
+        let context = self.context();
+        let mut s = self;
+        let generator = move || loop {
+            match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => {
+                    let overloaded = state.borrow().report.metric(policy.metric) > policy.threshold;
+                    match E::shed(&effect).filter(|_| overloaded) {
+                        Some(default) => {
+                            state.borrow_mut().report.shed += 1;
+                            s.put(default);
+                        },
+                        None => yield effect,
+                    }
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::GeneratorState};
+    use crate::{Context, Effect, IntoBlock};
+    use super::{LoadEffect, LoadQuery, LoadReport, Sheddable, ShedPolicy, LoadMetric};
+
+    #[derive(Debug)]
+    enum Effects {
+        Work(u32),
+        Prefetch(u32),
+        Load,
+        Idle,
+    }
+
+    impl LoadQuery for Effects {
+        fn into_load(self) -> Result<LoadEffect, Self> {
+            match self {
+                Effects::Load => Ok(LoadEffect::Query),
+                e => Err(e),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    enum Outputs {
+        Done,
+        Skipped,
+        Report(LoadReport),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl From<LoadReport> for Outputs {
+        fn from(v: LoadReport) -> Self {
+            Outputs::Report(v)
+        }
+    }
+
+    impl Sheddable for Outputs {
+        fn shed(effect: &Effects) -> Option<Self> {
+            match effect {
+                Effects::Prefetch(_) => Some(Outputs::Skipped),
+                _ => None,
+            }
+        }
+    }
+
+    fn query(context: &Context<Outputs>) -> LoadReport {
+        match context.take() {
+            Some(Outputs::Report(report)) => report,
+            _ => panic!("expected load report"),
+        }
+    }
+
+    #[test]
+    fn congested() {
+        let handled = Rc::new(RefCell::new(Vec::new()));
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let computation = {
+            let reports = reports.clone();
+            move |context: Context<Outputs>| {
+                move || {
+                    // light, then congested, then stalled
+                    for &(rounds, per_round) in &[(8, 1), (8, 10), (8, 0)] {
+                        for _ in 0..rounds {
+                            for i in 0..per_round {
+                                yield Effects::Work(i);
+                                assert!(matches!(context.take(), Some(Outputs::Done)));
+                                yield Effects::Prefetch(i);
+                                context.take();
+                            }
+                            yield Effects::Idle;
+                        }
+                        yield Effects::Load;
+                        reports.borrow_mut().push(query(&context));
+                    }
+                }
+            }
+        };
+        let mut block = computation
+            .into_block()
+            .shed_when(ShedPolicy {
+                metric: LoadMetric::EffectsPerRound,
+                threshold: 6.0,
+            })
+            .add_handler({
+                let handled = handled.clone();
+                move |effect| match effect {
+                    Effects::Work(_) | Effects::Prefetch(_) => {
+                        handled
+                            .borrow_mut()
+                            .push(matches!(effect, Effects::Work(_)));
+                        Ok(Outputs::Done)
+                    },
+                    e => Err(e),
+                }
+            })
+            .with_load_metrics(2);
+        while let GeneratorState::Yielded(Effects::Idle) = block.resume() {}
+
+        let reports = reports.borrow();
+        assert!(reports[1].effects_per_round > reports[0].effects_per_round);
+        assert!(reports[2].effects_per_round < reports[1].effects_per_round);
+        assert!(reports[2].stall_rounds > reports[1].stall_rounds);
+        assert!(reports[1].shed > 0);
+        assert_eq!(reports[0].shed, 0);
+
+        let handled = handled.borrow();
+        let work = handled.iter().filter(|work| **work).count();
+        let prefetch = handled.len() - work;
+        assert_eq!(work, 8 + 80);
+        assert_eq!(prefetch as u64, 8 + 80 - reports[2].shed);
+    }
+}
@@ -0,0 +1,441 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{fmt, ops::Generator};
+use super::{
+    block::Block,
+    computation::{Effect, Handler},
+    routing,
+};
+
+// what a handler declares about itself, the lists are sorted
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capability {
+    // the name of the plugin or the tag of the handler
+    pub tag: String,
+    pub version: Option<String>,
+    // the variants it handles, `None` if it is offered everything
+    pub claims: Option<Vec<String>>,
+    pub provides: Vec<String>,
+    pub requires: Vec<String>,
+    pub health_check: bool,
+}
+
+impl Capability {
+    pub(crate) fn new<E>(
+        tag: &str,
+        version: &str,
+        claims: Option<&[&str]>,
+        handler: &dyn Handler<E>,
+    ) -> Self
+    where
+        E: Effect,
+    {
+        fn sorted<T>(items: impl Iterator<Item = T>) -> Vec<String>
+        where
+            T: ToString,
+        {
+            let mut items = items.map(|item| item.to_string()).collect::<Vec<_>>();
+            items.sort();
+            items
+        }
+
+        Capability {
+            tag: tag.to_string(),
+            version: Some(version.to_string()).filter(|v| !v.is_empty()),
+            claims: claims.map(|claims| sorted(claims.iter())),
+            provides: sorted(handler.provides().iter()),
+            requires: sorted(handler.requires().iter()),
+            health_check: handler.checks_health(),
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |items: &[String]| match items {
+            [] => "-".to_string(),
+            items => items.join(", "),
+        };
+        writeln!(f, "{}", self.tag)?;
+        writeln!(f, "  version: {}", self.version.as_deref().unwrap_or("-"))?;
+        match &self.claims {
+            Some(claims) => writeln!(f, "  claims: {}", list(claims))?,
+            None => writeln!(f, "  claims: all")?,
+        }
+        writeln!(f, "  provides: {}", list(&self.provides))?;
+        writeln!(f, "  requires: {}", list(&self.requires))?;
+        let health_check = if self.health_check { "yes" } else { "no" };
+        writeln!(f, "  health check: {}", health_check)
+    }
+}
+
+// The handlers of a registry or of a block, known before any effect is
+// performed and the same on every run of one configuration. The text is
+// meant for a checked-in baseline, so is the serialized form.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapabilitiesManifest {
+    pub handlers: Vec<Capability>,
+}
+
+impl fmt::Display for CapabilitiesManifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for handler in &self.handlers {
+            write!(f, "{}", handler)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CapabilityChange {
+    // a handler of the baseline the other manifest lacks, and the reverse
+    Missing(String),
+    Added(String),
+    Claims {
+        tag: String,
+        from: Option<Vec<String>>,
+        to: Option<Vec<String>>,
+    },
+    Version {
+        tag: String,
+        from: Option<String>,
+        to: Option<String>,
+    },
+    Provides {
+        tag: String,
+        from: Vec<String>,
+        to: Vec<String>,
+    },
+    Requires {
+        tag: String,
+        from: Vec<String>,
+        to: Vec<String>,
+    },
+    HealthCheck {
+        tag: String,
+        to: bool,
+    },
+}
+
+impl fmt::Display for CapabilityChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CapabilityChange::Missing(tag) => write!(f, "handler `{}` is missing", tag),
+            CapabilityChange::Added(tag) => write!(f, "handler `{}` is added", tag),
+            CapabilityChange::Claims { tag, from, to } => {
+                write!(f, "`{}` claims {:?} instead of {:?}", tag, to, from)
+            },
+            CapabilityChange::Version { tag, from, to } => {
+                write!(f, "`{}` is version {:?} instead of {:?}", tag, to, from)
+            },
+            CapabilityChange::Provides { tag, from, to } => {
+                write!(f, "`{}` provides {:?} instead of {:?}", tag, to, from)
+            },
+            CapabilityChange::Requires { tag, from, to } => {
+                write!(f, "`{}` requires {:?} instead of {:?}", tag, to, from)
+            },
+            CapabilityChange::HealthCheck { tag, to: true } => {
+                write!(f, "`{}` checks its health now", tag)
+            },
+            CapabilityChange::HealthCheck { tag, to: false } => {
+                write!(f, "`{}` no longer checks its health", tag)
+            },
+        }
+    }
+}
+
+impl CapabilitiesManifest {
+    // What changed from `self` to `other`, the handlers are matched by tag.
+    // The changes of the handlers of `self` come in their order, the added
+    // handlers last.
+    pub fn diff(&self, other: &CapabilitiesManifest) -> Vec<CapabilityChange> {
+        let mut changes = Vec::new();
+        for from in &self.handlers {
+            let tag = from.tag.clone();
+            let to = match other.handlers.iter().find(|h| h.tag == from.tag) {
+                Some(to) => to,
+                None => {
+                    changes.push(CapabilityChange::Missing(tag));
+                    continue;
+                },
+            };
+            if from.claims != to.claims {
+                changes.push(CapabilityChange::Claims {
+                    tag: tag.clone(),
+                    from: from.claims.clone(),
+                    to: to.claims.clone(),
+                });
+            }
+            if from.version != to.version {
+                changes.push(CapabilityChange::Version {
+                    tag: tag.clone(),
+                    from: from.version.clone(),
+                    to: to.version.clone(),
+                });
+            }
+            if from.provides != to.provides {
+                changes.push(CapabilityChange::Provides {
+                    tag: tag.clone(),
+                    from: from.provides.clone(),
+                    to: to.provides.clone(),
+                });
+            }
+            if from.requires != to.requires {
+                changes.push(CapabilityChange::Requires {
+                    tag: tag.clone(),
+                    from: from.requires.clone(),
+                    to: to.requires.clone(),
+                });
+            }
+            if from.health_check != to.health_check {
+                changes.push(CapabilityChange::HealthCheck {
+                    tag,
+                    to: to.health_check,
+                });
+            }
+        }
+        for to in &other.handlers {
+            if !self.handlers.iter().any(|h| h.tag == to.tag) {
+                changes.push(CapabilityChange::Added(to.tag.clone()));
+            }
+        }
+        changes
+    }
+
+    // for a startup check or a test against a checked-in baseline, panics
+    // listing every change
+    pub fn assert_compatible_with(&self, baseline: &CapabilitiesManifest) {
+        let changes = baseline.diff(self);
+        if !changes.is_empty() {
+            let changes = changes.iter().map(ToString::to_string).collect::<Vec<_>>();
+            panic!(
+                "the handlers drifted from the baseline, {}",
+                changes.join(", ")
+            );
+        }
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + 'static,
+    E::Input: 'static,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // The tagged handlers in installation order, the untagged ones have no
+    // name to compare. A handler filtered by variant claims the variants.
+    pub fn manifest(&self) -> CapabilitiesManifest {
+        let context = self.context();
+        let (links, _) = routing::chain(&context);
+        let handlers = routing::with_tagged(&context, |handlers| {
+            handlers
+                .iter()
+                .zip(links)
+                .map(|((tag, handler), (_, variants))| {
+                    Capability::new(tag.name(), "", variants.as_deref(), &**handler)
+                })
+                .collect()
+        });
+        CapabilitiesManifest { handlers }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Generator;
+    use crate::{
+        Context, Effect, EffectName, Handler, HandlerTag, HealthStatus, IntoBlock, filter_variants,
+        plugin::{HandlerPlugin, PluginConfig, Registry, ResourceKey},
+    };
+    use super::CapabilityChange;
+
+    #[derive(Debug, Clone, Copy)]
+    enum Effects {
+        Get(u32),
+        Put(u32),
+        Now,
+    }
+
+    impl EffectName for Effects {
+        fn effect_name(&self) -> &'static str {
+            match self {
+                Effects::Get(_) => "Get",
+                Effects::Put(_) => "Put",
+                Effects::Now => "Now",
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Value(u32),
+        Time(u64),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    const CLOCK: ResourceKey = ResourceKey("clock");
+
+    struct Clock;
+
+    impl Handler<Outputs> for Clock {
+        fn handle(&mut self, effect: Effects) -> Result<Outputs, Effects> {
+            match effect {
+                Effects::Now => Ok(Outputs::Time(0)),
+                other => Err(other),
+            }
+        }
+
+        fn health_check(&mut self) -> HealthStatus {
+            HealthStatus::Healthy
+        }
+
+        fn checks_health(&self) -> bool {
+            true
+        }
+
+        fn provides(&self) -> &'static [ResourceKey] {
+            &[CLOCK]
+        }
+    }
+
+    struct ClockPlugin;
+
+    impl HandlerPlugin<Outputs> for ClockPlugin {
+        fn name(&self) -> &'static str {
+            "clock"
+        }
+
+        fn claims(&self) -> &'static [&'static str] {
+            &["Now"]
+        }
+
+        fn version(&self) -> &'static str {
+            "1.2.0"
+        }
+
+        fn build(&self, _: &PluginConfig) -> Box<dyn Handler<Outputs>> {
+            Box::new(Clock)
+        }
+    }
+
+    struct StorePlugin(&'static [&'static str]);
+
+    impl HandlerPlugin<Outputs> for StorePlugin {
+        fn name(&self) -> &'static str {
+            "store"
+        }
+
+        fn claims(&self) -> &'static [&'static str] {
+            self.0
+        }
+
+        fn build(&self, _: &PluginConfig) -> Box<dyn Handler<Outputs>> {
+            Box::new(|effect| match effect {
+                Effects::Get(key) | Effects::Put(key) => Ok(Outputs::Value(key)),
+                other => Err(other),
+            })
+        }
+    }
+
+    fn registry(store_claims: &'static [&'static str]) -> Registry<Outputs> {
+        let mut registry = Registry::new(PluginConfig::new());
+        registry
+            .register(ClockPlugin)
+            .register(StorePlugin(store_claims));
+        registry
+    }
+
+    #[test]
+    fn one_claim_differs() {
+        let staging = registry(&["Get", "Put"]).manifest();
+        let prod = registry(&["Get"]).manifest();
+        let changes = staging.diff(&prod);
+        assert_eq!(
+            changes,
+            [CapabilityChange::Claims {
+                tag: "store".to_string(),
+                from: Some(vec!["Get".to_string(), "Put".to_string()]),
+                to: Some(vec!["Get".to_string()]),
+            }]
+        );
+    }
+
+    #[test]
+    fn matching_registries_compatible() {
+        let baseline = registry(&["Put", "Get"]).manifest();
+        let manifest = registry(&["Get", "Put"]).manifest();
+        manifest.assert_compatible_with(&baseline);
+
+        let clock = &manifest.handlers[0];
+        assert_eq!(clock.version.as_deref(), Some("1.2.0"));
+        assert_eq!(clock.provides, ["clock"]);
+        assert!(clock.health_check);
+        let store = &manifest.handlers[1];
+        assert_eq!(store.version, None);
+        assert!(!store.health_check);
+        assert_eq!(
+            manifest.to_string(),
+            "clock\n  version: 1.2.0\n  claims: Now\n  provides: clock\n  requires: -\n  \
+             health check: yes\nstore\n  version: -\n  claims: Get, Put\n  provides: -\n  \
+             requires: -\n  health check: no\n"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "handler `clock` is missing")]
+    fn missing_handler_incompatible() {
+        let baseline = registry(&["Get"]).manifest();
+        let mut manifest = baseline.clone();
+        manifest.handlers.remove(0);
+        manifest.assert_compatible_with(&baseline);
+    }
+
+    #[test]
+    fn tagged_chain() {
+        fn stored(
+            context: Context<Outputs>,
+        ) -> impl Unpin + Generator<(), Yield = Effects, Return = Vec<Outputs>> {
+            move || {
+                for effect in [Effects::Put(1), Effects::Get(1), Effects::Now] {
+                    yield effect;
+                }
+                std::iter::from_fn(|| context.take()).collect()
+            }
+        }
+
+        let block = stored
+            .into_block()
+            .add_handler_tagged(Clock, HandlerTag::new("clock"))
+            .add_handler_tagged_filtered(
+                filter_variants!(Effects::Get | Effects::Put),
+                |effect| match effect {
+                    Effects::Get(key) | Effects::Put(key) => Ok(Outputs::Value(key)),
+                    other => Err(other),
+                },
+                HandlerTag::new("store"),
+            );
+        let manifest = block.manifest();
+        let claims = manifest
+            .handlers
+            .iter()
+            .map(|h| (h.tag.as_str(), h.claims.clone()))
+            .collect::<Vec<_>>();
+        let store = Some(vec!["Get".to_string(), "Put".to_string()]);
+        assert_eq!(claims, [("clock", None), ("store", store)]);
+        assert!(manifest.handlers[0].health_check);
+        let outputs = block.assert_handled().run();
+        assert_eq!(
+            outputs,
+            [Outputs::Value(1), Outputs::Value(1), Outputs::Time(0)]
+        );
+    }
+}
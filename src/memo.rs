@@ -0,0 +1,250 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    mem,
+    rc::Rc,
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    ops::{Generator, GeneratorState},
+};
+use super::{accounting::Counter, block::Block, computation::Effect, flush, idempotency::Idempotent};
+
+// shared with whoever knows when an answer is stale, a handler or the application
+#[derive(Clone, Default)]
+pub struct InvalidationHandle(Rc<RefCell<BTreeSet<u64>>>);
+
+impl InvalidationHandle {
+    // the next effect with the key goes to the handler again
+    pub fn invalidate(&self, key: u64) {
+        self.0.borrow_mut().insert(key);
+    }
+}
+
+#[derive(Clone)]
+pub enum MemoPolicy {
+    Forever,
+    // every effect passing the layer is a round, an answer is reused for the
+    // rounds after the one it was given in
+    ForRounds(u64),
+    UntilInvalidated(InvalidationHandle),
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + Clone,
+    E::Input: Idempotent,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Answers an effect with a key from the cache, only a miss goes further and
+    // its response fills the cache. The key is usually the `EffectFingerprint`
+    // of the effect, `None` and effects that are not idempotent are never
+    // cached. A flush barrier always goes further and the cache is dropped
+    // after it. Hits are counted as `Counter::MemoHits`.
+    pub fn memoize<K>(
+        self,
+        policy: MemoPolicy,
+        key: K,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        K: Fn(&E::Input) -> Option<u64>,
+    {
+        let context = self.context();
+        let mut cache = BTreeMap::<u64, (u64, E)>::new();
+        let mut round = 0;
+        let mut barriers = flush::barriers_performed(&context);
+        let mut s = self;
+        let generator = move || loop {
+            let effect = match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => effect,
+            };
+            round += 1;
+            let context = s.context();
+            if flush::barriers_performed(&context) != barriers {
+                barriers = flush::barriers_performed(&context);
+                cache.clear();
+            }
+            if flush::barrier_in_flight(&context) {
+                yield effect;
+                continue;
+            }
+            if let MemoPolicy::UntilInvalidated(handle) = &policy {
+                for key in mem::take(&mut *handle.0.borrow_mut()) {
+                    cache.remove(&key);
+                }
+            }
+            let key = match key(&effect) {
+                Some(key) if effect.is_idempotent() => key,
+                _ => {
+                    yield effect;
+                    continue;
+                },
+            };
+            let fresh = |given: u64| match &policy {
+                MemoPolicy::ForRounds(rounds) => round - given < *rounds,
+                _ => true,
+            };
+            match cache.get(&key) {
+                Some((given, output)) if fresh(*given) => {
+                    s.context().account(|a| a.bump(Counter::MemoHits));
+                    s.put(output.clone());
+                },
+                _ => {
+                    let before = context.produced();
+                    yield effect;
+                    if context.produced() != before {
+                        if let Some(output) = context.take_last() {
+                            cache.insert(key, (round, output.clone()));
+                            context.put(output);
+                        }
+                    }
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, hash::Hasher, ops::Generator};
+    use crate::{
+        Context, Counter, Effect, EffectFingerprint, Idempotent, IntoBlock, StableHasher, perform,
+    };
+    use super::{InvalidationHandle, MemoPolicy};
+
+    #[derive(Debug)]
+    enum Effects {
+        Stat(&'static str),
+        Query(&'static str),
+        Touch(&'static str),
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Outputs {
+        Size(u64),
+        Setting(u32),
+        Touched,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl Idempotent for Effects {
+        fn is_idempotent(&self) -> bool {
+            !matches!(self, Effects::Touch(_))
+        }
+    }
+
+    // settings are never keyed, touching is keyed but not idempotent
+    fn key(effect: &Effects) -> Option<u64> {
+        let path = match effect {
+            Effects::Stat(path) | Effects::Touch(path) => path,
+            Effects::Query(_) => return None,
+        };
+        let mut hasher = StableHasher::default();
+        path.fingerprint(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    type Calls = Rc<RefCell<Vec<&'static str>>>;
+
+    // stats the config every iteration, touches it in the third one
+    fn looped(
+        context: Context<Outputs>,
+        invalidation: Option<InvalidationHandle>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = Vec<Outputs>> {
+        move || {
+            let mut seen = Vec::new();
+            for iteration in 0..6 {
+                perform!(Effects::Stat("config"));
+                seen.extend(context.take());
+                if iteration == 2 {
+                    perform!(Effects::Touch("config"));
+                    context.take();
+                    if let Some(handle) = &invalidation {
+                        handle.invalidate(key(&Effects::Stat("config")).unwrap());
+                    }
+                }
+            }
+            seen
+        }
+    }
+
+    fn handler(calls: &Calls) -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+        let calls = calls.clone();
+        move |effect| match effect {
+            Effects::Stat(path) => {
+                calls.borrow_mut().push("stat");
+                Ok(Outputs::Size(path.len() as u64))
+            },
+            Effects::Query(_) => {
+                calls.borrow_mut().push("query");
+                Ok(Outputs::Setting(1))
+            },
+            Effects::Touch(_) => {
+                calls.borrow_mut().push("touch");
+                Ok(Outputs::Touched)
+            },
+        }
+    }
+
+    fn run(policy: MemoPolicy, invalidation: Option<InvalidationHandle>) -> (Calls, u64) {
+        let calls = Calls::default();
+        let (block, accounting) = (move |context| looped(context, invalidation))
+            .into_block()
+            .memoize(policy, key)
+            .add_handler(handler(&calls))
+            .assert_handled()
+            .with_accounting();
+        let seen = block.run();
+        assert_eq!(seen, vec![Outputs::Size(6); 6]);
+        (calls, accounting.report().count(Counter::MemoHits))
+    }
+
+    #[test]
+    fn forever() {
+        let (calls, hits) = run(MemoPolicy::Forever, None);
+        assert_eq!(*calls.borrow(), ["stat", "touch"]);
+        if cfg!(feature = "diagnostics") {
+            assert_eq!(hits, 5);
+        }
+    }
+
+    #[test]
+    fn for_rounds() {
+        // seven rounds with the touch, the stat reaches the handler in the
+        // first, the third, the fifth and the seventh
+        let (calls, _) = run(MemoPolicy::ForRounds(2), None);
+        assert_eq!(*calls.borrow(), ["stat", "stat", "touch", "stat", "stat"]);
+    }
+
+    #[test]
+    fn until_invalidated() {
+        let handle = InvalidationHandle::default();
+        let policy = MemoPolicy::UntilInvalidated(handle.clone());
+        let (calls, _) = run(policy, Some(handle));
+        assert_eq!(*calls.borrow(), ["stat", "touch", "stat"]);
+    }
+
+    #[test]
+    fn not_keyed() {
+        let calls = Calls::default();
+        (|context: Context<Outputs>| {
+            move || {
+                for _ in 0..3 {
+                    perform!(Effects::Query("verbose"));
+                    context.take();
+                }
+            }
+        })
+        .into_block()
+        .memoize(MemoPolicy::Forever, key)
+        .add_handler(handler(&calls))
+        .assert_handled()
+        .run();
+        assert_eq!(*calls.borrow(), ["query"; 3]);
+    }
+}
@@ -0,0 +1,536 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::VecDeque,
+    panic::Location,
+    time::Instant,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    accounting::Counter,
+    block::Block,
+    blocking::BlockingWait,
+    coalesce::Mergeable,
+    computation::{Effect, EffectName},
+    deadline::HasDeadline,
+    dry_run::{Classify, EffectClass},
+    explain::EffectDescription,
+    idempotency::{Idempotent, IdempotencyToken},
+    identity::Identity,
+    size_limit::{MarkTruncated, Truncation},
+    two_phase::OpId,
+};
+
+// What the layers know about an effect besides the effect itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EffectMeta {
+    // higher first
+    pub priority: u8,
+    pub deadline: Option<Instant>,
+    pub token: Option<IdempotencyToken>,
+    // like `Context::scope_path`, empty outside of scopes
+    pub scope: String,
+    pub site: Option<&'static Location<'static>>,
+    // set by `with_size_limits` on an effect it shrank
+    pub truncated: Option<Truncation>,
+    // set by `stamp_family` on an effect of a `Sum`, see `Effect::family`
+    pub family: Option<usize>,
+    // only `stamp_meta` and the task schedulers set them
+    pub(crate) identity: Option<Identity>,
+    pub(crate) cause: Option<u64>,
+}
+
+impl EffectMeta {
+    // the identity of the block or the task performing the effect
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    // the sequence number of the output the performer took last before it
+    // performed the effect, `None` if it took none, see `causality`
+    pub fn cause(&self) -> Option<u64> {
+        self.cause
+    }
+
+    // The metadata of two effects `coalesce` merged, `self` is the earlier one.
+    // The merged effect is as urgent as the most urgent of them, the higher
+    // priority and the earlier deadline, the rest is the one of the earlier.
+    pub fn merged(self, later: EffectMeta) -> EffectMeta {
+        let deadline = match (self.deadline, later.deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        EffectMeta {
+            priority: self.priority.max(later.priority),
+            deadline,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WithMeta<I> {
+    pub effect: I,
+    pub meta: EffectMeta,
+}
+
+impl<I> WithMeta<I> {
+    #[track_caller]
+    pub fn new(effect: I) -> Self {
+        WithMeta {
+            effect,
+            meta: EffectMeta {
+                site: Some(Location::caller()),
+                ..EffectMeta::default()
+            },
+        }
+    }
+
+    pub fn with_priority(self, priority: u8) -> Self {
+        let meta = EffectMeta {
+            priority,
+            ..self.meta
+        };
+        WithMeta { meta, ..self }
+    }
+
+    pub fn with_deadline(self, deadline: Instant) -> Self {
+        let meta = EffectMeta {
+            deadline: Some(deadline),
+            ..self.meta
+        };
+        WithMeta { meta, ..self }
+    }
+
+    pub fn into_inner(self) -> I {
+        self.effect
+    }
+}
+
+// how the queues of the layers order what waits in them
+pub trait HasPriority {
+    // higher first
+    fn priority(&self) -> u8 {
+        0
+    }
+
+    // earlier first among the same priority, those without come last
+    fn due(&self) -> Option<Instant> {
+        None
+    }
+}
+
+impl<I> HasPriority for WithMeta<I> {
+    fn priority(&self) -> u8 {
+        self.meta.priority
+    }
+
+    fn due(&self) -> Option<Instant> {
+        self.meta.deadline
+    }
+}
+
+impl<I> HasDeadline for WithMeta<I> {
+    fn deadline(&self) -> Option<Instant> {
+        self.meta.deadline
+    }
+
+    fn set_deadline(&mut self, deadline: Instant) {
+        self.meta.deadline = Some(deadline);
+    }
+}
+
+impl<I> Idempotent for WithMeta<I>
+where
+    I: Idempotent,
+{
+    fn is_idempotent(&self) -> bool {
+        self.effect.is_idempotent()
+    }
+}
+
+impl<I> Mergeable for WithMeta<I>
+where
+    I: Mergeable,
+{
+    fn mergeable(&self) -> bool {
+        self.effect.mergeable()
+    }
+}
+
+impl<I> EffectName for WithMeta<I>
+where
+    I: EffectName,
+{
+    fn effect_name(&self) -> &'static str {
+        self.effect.effect_name()
+    }
+
+    fn describe(&self) -> EffectDescription {
+        self.effect.describe()
+    }
+}
+
+impl<I> MarkTruncated for WithMeta<I> {
+    fn mark_truncated(&mut self, truncation: Truncation) {
+        self.meta.truncated = Some(truncation);
+    }
+}
+
+impl<I> Classify for WithMeta<I>
+where
+    I: Classify,
+{
+    fn effect_class(&self) -> EffectClass {
+        self.effect.effect_class()
+    }
+}
+
+impl<I> BlockingWait for WithMeta<I>
+where
+    I: BlockingWait,
+{
+    fn waits_for(&self) -> Option<OpId> {
+        self.effect.waits_for()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueOrder {
+    Fifo,
+    Priority,
+}
+
+// a higher priority effect still waiting after `rounds` lower ones left first
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Inversion {
+    pub waiting: u8,
+    pub behind: u8,
+    pub rounds: u64,
+}
+
+struct Entry<T> {
+    item: T,
+    priority: u8,
+    due: Option<Instant>,
+    // the pops before this one was pushed
+    since: u64,
+    reported: bool,
+}
+
+type OnInversion = Box<dyn FnMut(Inversion) + Send>;
+
+// The queue the layers hold effects in. A round is one pop, the detector
+// looks at what is left behind every pop.
+pub struct EffectQueue<T> {
+    order: QueueOrder,
+    entries: VecDeque<Entry<T>>,
+    popped: u64,
+    inversions: u64,
+    detector: Option<(u64, OnInversion)>,
+}
+
+impl<T> EffectQueue<T>
+where
+    T: HasPriority,
+{
+    pub fn new(order: QueueOrder) -> Self {
+        EffectQueue {
+            order,
+            entries: VecDeque::new(),
+            popped: 0,
+            inversions: 0,
+            detector: None,
+        }
+    }
+
+    // reports an effect once it waited more than `rounds` behind lower ones
+    pub fn on_inversion<F>(&mut self, rounds: u64, f: F)
+    where
+        F: FnMut(Inversion) + Send + 'static,
+    {
+        self.detector = Some((rounds, Box::new(f)));
+    }
+
+    pub fn push(&mut self, item: T) {
+        let entry = Entry {
+            priority: item.priority(),
+            due: item.due(),
+            item,
+            since: self.popped,
+            reported: false,
+        };
+        let position = match self.order {
+            QueueOrder::Fifo => self.entries.len(),
+            QueueOrder::Priority => {
+                let key = |e: &Entry<T>| (e.priority, e.due.map(std::cmp::Reverse));
+                let new = key(&entry);
+                self.entries
+                    .iter()
+                    .position(|e| key(e) < new)
+                    .unwrap_or(self.entries.len())
+            },
+        };
+        self.entries.insert(position, entry);
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        let entry = self.entries.pop_front()?;
+        self.popped += 1;
+        if let Some((rounds, report)) = &mut self.detector {
+            for waiting in &mut self.entries {
+                let waited = self.popped - waiting.since;
+                if !waiting.reported && waiting.priority > entry.priority && waited > *rounds {
+                    waiting.reported = true;
+                    self.inversions += 1;
+                    report(Inversion {
+                        waiting: waiting.priority,
+                        behind: entry.priority,
+                        rounds: waited,
+                    });
+                }
+            }
+        }
+        Some(entry.item)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn inversions(&self) -> u64 {
+        self.inversions
+    }
+}
+
+impl<E, G, I> Block<E, G>
+where
+    E: Effect<Input = WithMeta<I>>,
+    G: Unpin + Generator<(), Yield = WithMeta<I>>,
+{
+    // Fills in what the block knows and the effect does not: the token of
+    // `idempotency_tokens` inside and the scope path. The identity and the
+    // cause are always the ones of the block, whatever the effect carries.
+    // The layers outside get the metadata as it is, `retry` delivers it again
+    // unchanged.
+    pub fn stamp_meta(
+        self,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = WithMeta<I>>> {
+        let context = self.context();
+        let mut s = self;
+        let generator = move || loop {
+            match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(mut effect) => {
+                    let meta = &mut effect.meta;
+                    if meta.token.is_none() {
+                        meta.token = s.context().token();
+                    }
+                    if meta.scope.is_empty() {
+                        meta.scope = s.context().scope_path();
+                    }
+                    meta.identity = s.context().identity();
+                    meta.cause = s.context().cause();
+                    if let Some(identity) = &meta.identity {
+                        s.context()
+                            .account(|a| a.bump_for(identity, Counter::Performed));
+                    }
+                    yield effect;
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        cell::RefCell,
+        sync::{mpsc, Arc, Mutex},
+    };
+    use crate::{
+        Context, Effect, Idempotent, IdempotencyToken, IntoBlock, Handler, OpId, TwoPhase, perform,
+        blocking::{BlockingWait, HasBlockingOutcome, WorkerFailure, blocking_adapter},
+    };
+    use super::{EffectMeta, EffectQueue, HasPriority, Inversion, QueueOrder, WithMeta};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Send(u32),
+    }
+
+    impl Idempotent for Effects {}
+
+    impl BlockingWait for Effects {
+        fn waits_for(&self) -> Option<OpId> {
+            None
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Sent,
+        Dropped,
+        Accepted(OpId),
+        Deferred(WithMeta<Effects>),
+    }
+
+    impl Effect for Outputs {
+        type Input = WithMeta<Effects>;
+    }
+
+    impl TwoPhase for Outputs {
+        fn ack(id: OpId) -> Self {
+            Outputs::Accepted(id)
+        }
+
+        fn is_ack(&self) -> Option<OpId> {
+            match self {
+                Outputs::Accepted(id) => Some(*id),
+                _ => None,
+            }
+        }
+
+        fn is_completion(&self) -> Option<OpId> {
+            None
+        }
+    }
+
+    impl HasBlockingOutcome for Outputs {
+        fn completed(_: OpId, _: Result<Self, WorkerFailure<WithMeta<Effects>>>) -> Self {
+            Outputs::Sent
+        }
+
+        fn queue_full(effect: WithMeta<Effects>) -> Self {
+            Outputs::Deferred(effect)
+        }
+    }
+
+    struct Job(u32, u8);
+
+    impl HasPriority for Job {
+        fn priority(&self) -> u8 {
+            self.1
+        }
+    }
+
+    fn drain(queue: &mut EffectQueue<Job>) -> Vec<u32> {
+        std::iter::from_fn(|| queue.pop())
+            .map(|Job(n, _)| n)
+            .collect()
+    }
+
+    #[test]
+    fn inversion_in_fifo() {
+        let inversions = Arc::new(Mutex::new(Vec::new()));
+        let mut queue = EffectQueue::new(QueueOrder::Fifo);
+        queue.on_inversion(2, {
+            let inversions = inversions.clone();
+            move |i| inversions.lock().unwrap().push(i)
+        });
+        for n in 0..5 {
+            queue.push(Job(n, 1));
+        }
+        queue.push(Job(5, 9));
+        assert_eq!(drain(&mut queue), [0, 1, 2, 3, 4, 5]);
+        let expected = Inversion {
+            waiting: 9,
+            behind: 1,
+            rounds: 3,
+        };
+        assert_eq!(*inversions.lock().unwrap(), [expected]);
+        assert_eq!(queue.inversions(), 1);
+
+        // the same effects in a queue that knows about priorities
+        let mut queue = EffectQueue::new(QueueOrder::Priority);
+        queue.on_inversion(2, |i| panic!("{:?}", i));
+        for n in 0..5 {
+            queue.push(Job(n, 1));
+        }
+        queue.push(Job(5, 9));
+        queue.push(Job(6, 1));
+        assert_eq!(drain(&mut queue), [5, 0, 1, 2, 3, 4, 6]);
+    }
+
+    #[test]
+    fn meta_survives_retry() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        (|context: Context<Outputs>| {
+            move || {
+                let _scope = context.enter_scope("uplink");
+                perform!(WithMeta::new(Effects::Send(1)).with_priority(7));
+                assert_eq!(context.take(), Some(Outputs::Sent));
+            }
+        })
+        .into_block()
+        .idempotency_tokens()
+        .stamp_meta()
+        .retry(3, |o| *o == Outputs::Dropped)
+        .add_handler({
+            let seen = seen.clone();
+            move |effect: WithMeta<Effects>| {
+                seen.borrow_mut().push(effect.meta);
+                // the first delivery is lost
+                if seen.borrow().len() == 1 {
+                    Ok(Outputs::Dropped)
+                } else {
+                    Ok(Outputs::Sent)
+                }
+            }
+        })
+        .assert_handled()
+        .run();
+        let seen = seen.borrow();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0], seen[1]);
+        let EffectMeta {
+            priority,
+            token,
+            scope,
+            site,
+            ..
+        } = seen[0].clone();
+        assert_eq!(priority, 7);
+        assert_eq!(token, Some(IdempotencyToken(0)));
+        assert_eq!(site.map(|l| l.file()), Some(file!()));
+        if cfg!(feature = "diagnostics") {
+            assert_eq!(scope, "uplink");
+        }
+    }
+
+    #[test]
+    fn meta_survives_defer() {
+        let (started, worker_started) = mpsc::channel();
+        let (release, gate) = mpsc::channel::<()>();
+        let handler = move |_| {
+            started.send(()).unwrap();
+            gate.recv().unwrap();
+            Ok(Outputs::Sent)
+        };
+        let (mut adapter, worker) = blocking_adapter(handler, 1);
+        adapter.handle(WithMeta::new(Effects::Send(1))).unwrap();
+        worker_started.recv().unwrap();
+        adapter.handle(WithMeta::new(Effects::Send(2))).unwrap();
+        // no room, the effect comes back to be yielded again
+        let effect = WithMeta::new(Effects::Send(3)).with_priority(3);
+        let expected = effect.clone();
+        let deferred = match adapter.handle(effect) {
+            Ok(Outputs::Deferred(effect)) => effect,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(deferred, expected);
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        drop(adapter);
+        assert_eq!(worker.join(), Ok(()));
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    computation::{Effect, Handler},
+};
+
+pub enum MirrorOutcome<E> {
+    Agree(E),
+    Diverged { chosen: E, report: DivergenceReport },
+    Unhandled,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DivergenceReport {
+    pub outputs: Vec<String>,
+}
+
+impl DivergenceReport {
+    pub fn from_results<E>(results: &[Result<E, E::Input>]) -> Self
+    where
+        E: Effect + fmt::Debug,
+        E::Input: fmt::Debug,
+    {
+        let outputs = results
+            .iter()
+            .enumerate()
+            .map(|(index, result)| match result {
+                Ok(output) => format!("handler {}: {:?}", index, output),
+                Err(effect) => format!("handler {} declined {:?}", index, effect),
+            })
+            .collect();
+        DivergenceReport { outputs }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorPanic {
+    pub handler: usize,
+    pub message: String,
+}
+
+#[derive(Default)]
+struct Log {
+    divergences: Vec<DivergenceReport>,
+    panics: Vec<MirrorPanic>,
+}
+
+#[derive(Clone)]
+pub struct MirrorLog(Rc<RefCell<Log>>);
+
+impl MirrorLog {
+    pub fn divergences(&self) -> Vec<DivergenceReport> {
+        self.0.borrow().divergences.clone()
+    }
+
+    pub fn panics(&self) -> Vec<MirrorPanic> {
+        self.0.borrow().panics.clone()
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    E::Input: Clone,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    #[allow(clippy::type_complexity)]
+    pub fn add_mirrored_handlers<R>(
+        self,
+        handlers: Vec<Box<dyn Handler<E>>>,
+        resolve: R,
+    ) -> (
+        Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>,
+        MirrorLog,
+    )
+    where
+        R: FnMut(Vec<Result<E, E::Input>>) -> MirrorOutcome<E>,
+    {
+        let log = MirrorLog(Rc::default());
+        let context = self.context();
+        let mut handlers = handlers;
+        let mut resolve = resolve;
+        let mut s = self;
+        let generator = {
+            let log = log.clone();
+            move || loop {
+                let effect = match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(effect) => effect,
+                };
+                let results = handlers
+                    .iter_mut()
+                    .enumerate()
+                    .map(|(index, handler)| {
+                        let copy = effect.clone();
+                        panic::catch_unwind(AssertUnwindSafe(|| handler.handle(copy)))
+                            .unwrap_or_else(|payload| {
+                                let message = payload
+                                    .downcast_ref::<&str>()
+                                    .map(ToString::to_string)
+                                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                                    .unwrap_or_default();
+                                log.0.borrow_mut().panics.push(MirrorPanic {
+                                    handler: index,
+                                    message,
+                                });
+                                Err(effect.clone())
+                            })
+                    })
+                    .collect();
+                match resolve(results) {
+                    MirrorOutcome::Agree(output) => s.put(output),
+                    MirrorOutcome::Diverged { chosen, report } => {
+                        log.0.borrow_mut().divergences.push(report);
+                        s.put(chosen);
+                    },
+                    MirrorOutcome::Unhandled => yield effect,
+                }
+            }
+        };
+        (Block::new(context, generator), log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{Context, Effect, Handler, IntoBlock};
+    use super::{MirrorOutcome, DivergenceReport, MirrorPanic};
+
+    #[derive(Debug, Clone)]
+    enum Effects {
+        Balance(&'static str),
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Outputs {
+        Balance(u64),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    fn backend(balance: u64) -> Box<dyn Handler<Outputs>> {
+        Box::new(move |Effects::Balance(_)| Ok(Outputs::Balance(balance)))
+    }
+
+    fn client(
+        context: Context<Outputs>,
+        seen: Rc<RefCell<Vec<Outputs>>>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            yield Effects::Balance("alice");
+            seen.borrow_mut().extend(context.take());
+        }
+    }
+
+    fn consensus(results: Vec<Result<Outputs, Effects>>) -> MirrorOutcome<Outputs> {
+        let report = DivergenceReport::from_results(&results);
+        let mut outputs = results.into_iter().filter_map(Result::ok);
+        let first = match outputs.next() {
+            Some(first) => first,
+            None => return MirrorOutcome::Unhandled,
+        };
+        if outputs.all(|o| o == first) && report.outputs.iter().all(|o| !o.contains("declined")) {
+            MirrorOutcome::Agree(first)
+        } else {
+            MirrorOutcome::Diverged {
+                chosen: first,
+                report,
+            }
+        }
+    }
+
+    #[test]
+    fn agree() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let (block, log) = (|context| client(context, seen.clone()))
+            .into_block()
+            .add_mirrored_handlers(vec![backend(10), backend(10)], consensus);
+        block.assert_handled().run();
+        assert_eq!(*seen.borrow(), vec![Outputs::Balance(10)]);
+        assert!(log.divergences().is_empty());
+    }
+
+    #[test]
+    fn diverged() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let panicking: Box<dyn Handler<Outputs>> = Box::new(|_| panic!("shadow crashed"));
+        let (block, log) = (|context| client(context, seen.clone()))
+            .into_block()
+            .add_mirrored_handlers(vec![backend(10), backend(12), panicking], consensus);
+        block.assert_handled().run();
+        assert_eq!(*seen.borrow(), vec![Outputs::Balance(10)]);
+        assert_eq!(
+            log.divergences(),
+            vec![DivergenceReport {
+                outputs: vec![
+                    "handler 0: Balance(10)".to_string(),
+                    "handler 1: Balance(12)".to_string(),
+                    "handler 2 declined Balance(\"alice\")".to_string(),
+                ],
+            }],
+        );
+        assert_eq!(
+            log.panics(),
+            vec![MirrorPanic {
+                handler: 2,
+                message: "shadow crashed".to_string(),
+            }],
+        );
+    }
+
+    #[test]
+    fn pass_through() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let declining: Box<dyn Handler<Outputs>> = Box::new(Err);
+        let (block, _) = (|context| client(context, seen.clone()))
+            .into_block()
+            .add_mirrored_handlers(vec![declining], consensus);
+        block
+            .add_handler(|Effects::Balance(_)| Ok(Outputs::Balance(7)))
+            .assert_handled()
+            .run();
+        assert_eq!(*seen.borrow(), vec![Outputs::Balance(7)]);
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use super::{ack::Ack, computation::Effect};
+
+enum Strategy<E>
+where
+    E: Effect,
+{
+    Panic,
+    Ack(fn(&E::Input) -> Option<E>),
+    Skip,
+}
+
+// What a mock generated by `#[derive(MockDefaults)]` does with a variant it
+// has no closure for.
+pub struct MockDefault<E>(Strategy<E>)
+where
+    E: Effect;
+
+impl<E> MockDefault<E>
+where
+    E: Effect,
+{
+    // the default, the test fails naming the unexpected variant
+    pub fn panic_with_variant_name() -> Self {
+        MockDefault(Strategy::Panic)
+    }
+
+    // the acknowledgement `Ack::ack_for` gives, the rest panics
+    pub fn ack_if_possible() -> Self
+    where
+        E: Ack,
+    {
+        MockDefault(Strategy::Ack(E::ack_for))
+    }
+
+    // the effect falls through to the next handler, its variant is recorded
+    pub fn record_and_skip() -> Self {
+        MockDefault(Strategy::Skip)
+    }
+}
+
+// the state of a generated mock besides its closures
+#[doc(hidden)]
+pub struct MockFallback<E>
+where
+    E: Effect,
+{
+    strategy: MockDefault<E>,
+    skipped: Vec<&'static str>,
+}
+
+impl<E> Default for MockFallback<E>
+where
+    E: Effect,
+{
+    fn default() -> Self {
+        MockFallback {
+            strategy: MockDefault::panic_with_variant_name(),
+            skipped: Vec::new(),
+        }
+    }
+}
+
+impl<E> MockFallback<E>
+where
+    E: Effect,
+{
+    pub fn set(&mut self, strategy: MockDefault<E>) {
+        self.strategy = strategy;
+    }
+
+    pub fn skipped(&self) -> &[&'static str] {
+        &self.skipped
+    }
+
+    pub fn respond(&mut self, variant: &'static str, effect: E::Input) -> Result<E, E::Input> {
+        match &self.strategy.0 {
+            Strategy::Ack(ack) => match ack(&effect) {
+                Some(ack) => Ok(ack),
+                None => panic!(
+                    "unexpected effect `{}` on the mock, it is not acknowledged",
+                    variant
+                ),
+            },
+            Strategy::Skip => {
+                self.skipped.push(variant);
+                Err(effect)
+            },
+            Strategy::Panic => panic!("unexpected effect `{}` on the mock", variant),
+        }
+    }
+}
@@ -0,0 +1,425 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    ops::Generator,
+};
+#[cfg(feature = "diagnostics")]
+use std::{mem, ops::GeneratorState};
+use super::{
+    block::Block,
+    computation::Effect,
+    accounting::{Accounting, Category},
+};
+
+const DEFAULT_MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObserverConfig {
+    name: &'static str,
+    max_depth: usize,
+}
+
+impl ObserverConfig {
+    // the name an `ObserverLoop` reports
+    pub fn named(name: &'static str) -> Self {
+        ObserverConfig {
+            name,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    // what an observer defers is one deeper than the output it observed, the
+    // outputs of the computation and of the handlers are at depth zero
+    pub fn max_depth(self, depth: usize) -> Self {
+        ObserverConfig {
+            max_depth: depth,
+            ..self
+        }
+    }
+}
+
+// the panic payload of an observer deferring deeper than its limit, most
+// likely reacting to its own outputs
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ObserverLoop {
+    pub observer: &'static str,
+    pub depth: usize,
+    pub max_depth: usize,
+}
+
+impl fmt::Display for ObserverLoop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "observer `{}` deferred at depth {}, deeper than {}",
+            self.observer, self.depth, self.max_depth
+        )
+    }
+}
+
+impl Error for ObserverLoop {}
+
+enum Deferral<E>
+where
+    E: Effect,
+{
+    Put(E),
+    Effect(E::Input),
+}
+
+#[cfg_attr(not(feature = "diagnostics"), allow(dead_code))]
+struct Deferred<E>
+where
+    E: Effect,
+{
+    depth: usize,
+    // the sequence number of the observed output
+    cause: u64,
+    deferral: Deferral<E>,
+}
+
+// shared by the observers of a context, the innermost layer delivers what
+// all of them deferred, so the order is kept and nothing is delivered in the
+// round it was deferred
+#[cfg_attr(not(feature = "diagnostics"), allow(dead_code))]
+struct Observing<E>
+where
+    E: Effect,
+{
+    pending: RefCell<VecDeque<Deferred<E>>>,
+    // the depth of the deferral being delivered
+    depth: Cell<usize>,
+    layers: Cell<usize>,
+    // the one of the context, taken when the innermost layer first runs
+    accounting: RefCell<Option<Accounting>>,
+}
+
+impl<E> Default for Observing<E>
+where
+    E: Effect,
+{
+    fn default() -> Self {
+        Observing {
+            pending: RefCell::new(VecDeque::new()),
+            depth: Cell::new(0),
+            layers: Cell::new(0),
+            accounting: RefCell::new(None),
+        }
+    }
+}
+
+// what an observer may do instead of touching the context it observes
+pub struct ObserverCtx<'a, E>
+where
+    E: Effect,
+{
+    config: ObserverConfig,
+    depth: usize,
+    cause: u64,
+    observing: &'a Observing<E>,
+}
+
+impl<'a, E> ObserverCtx<'a, E>
+where
+    E: Effect,
+{
+    pub fn observer(&self) -> &'static str {
+        self.config.name
+    }
+
+    // the depth of the deferred values
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn defer_put(&mut self, value: E) {
+        self.defer(Deferral::Put(value))
+    }
+
+    // the effect is yielded with the observed output as its cause, its
+    // response arrives in the context like any other output
+    pub fn defer_effect(&mut self, effect: E::Input) {
+        self.defer(Deferral::Effect(effect))
+    }
+
+    fn defer(&mut self, deferral: Deferral<E>) {
+        if self.depth > self.config.max_depth {
+            std::panic::panic_any(ObserverLoop {
+                observer: self.config.name,
+                depth: self.depth,
+                max_depth: self.config.max_depth,
+            });
+        }
+        self.observing.pending.borrow_mut().push_back(Deferred {
+            depth: self.depth,
+            cause: self.cause,
+            deferral,
+        });
+        if let Some(accounting) = &*self.observing.accounting.borrow() {
+            accounting.insert(Category::Deferred);
+        }
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+impl<E, G> Block<E, G>
+where
+    E: Effect + 'static,
+    E::Input: 'static,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // `observer` sees every output put in the context, after the observers
+    // registered before it. It must not touch the context, what it defers is
+    // delivered in the order it was deferred right before the computation is
+    // resumed next, never from inside a put. Without diagnostics the block is
+    // returned as is.
+    pub fn observe<F>(
+        self,
+        config: ObserverConfig,
+        observer: F,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        F: FnMut(&E, &mut ObserverCtx<'_, E>) + 'static,
+    {
+        let context = self.context();
+        let observing = context.extension(Observing::<E>::default);
+        let innermost = observing.layers.get() == 0;
+        observing.layers.set(observing.layers.get() + 1);
+        context.add_watch(Box::new({
+            let observing = observing.clone();
+            let mut observer = observer;
+            move |seq, output: &E| {
+                let mut ctx = ObserverCtx {
+                    config,
+                    depth: observing.depth.get() + 1,
+                    cause: seq,
+                    observing: &observing,
+                };
+                observer(output, &mut ctx);
+            }
+        }));
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            move || loop {
+                if innermost && observing.accounting.borrow().is_none() {
+                    *observing.accounting.borrow_mut() = context.accounting();
+                }
+                // the deferrals these cause wait for the next round
+                let pending = if innermost {
+                    mem::take(&mut *observing.pending.borrow_mut())
+                } else {
+                    VecDeque::new()
+                };
+                for Deferred {
+                    depth,
+                    cause,
+                    deferral,
+                } in pending
+                {
+                    context.account(|a| a.remove(Category::Deferred));
+                    observing.depth.set(depth);
+                    match deferral {
+                        Deferral::Put(value) => context.put(value),
+                        Deferral::Effect(effect) => {
+                            let performer = context.cause();
+                            context.set_cause(Some(cause));
+                            yield effect;
+                            context.set_cause(performer);
+                        },
+                    }
+                    observing.depth.set(0);
+                }
+                match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(effect) => yield effect,
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(not(feature = "diagnostics"))]
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<()>,
+{
+    #[inline(always)]
+    pub fn observe<F>(self, config: ObserverConfig, observer: F) -> Self
+    where
+        F: FnMut(&E, &mut ObserverCtx<'_, E>) + 'static,
+    {
+        let _ = (config, observer);
+        self
+    }
+}
+
+#[cfg(all(test, feature = "diagnostics"))]
+mod tests {
+    use std::{
+        rc::Rc,
+        cell::RefCell,
+        iter,
+        panic::{self, AssertUnwindSafe},
+        ops::Generator,
+    };
+    use crate::{Category, Context, Effect, HistoryConfig, IntoBlock};
+    use super::{ObserverConfig, ObserverLoop};
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Op {
+        Measure(u32),
+        Repaint,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Out {
+        Latency(u32),
+        Crossed(u32),
+        Repainted,
+        Tick(u32),
+    }
+
+    impl Effect for Out {
+        type Input = Op;
+    }
+
+    fn handler(op: Op) -> Result<Out, Op> {
+        match op {
+            Op::Measure(ms) => Ok(Out::Latency(ms)),
+            Op::Repaint => Ok(Out::Repainted),
+        }
+    }
+
+    // what the computation found after each measurement
+    fn measure(
+        context: Context<Out>,
+        samples: Vec<u32>,
+        seen: Rc<RefCell<Vec<Vec<Out>>>>,
+    ) -> impl Unpin + Generator<(), Yield = Op, Return = ()> {
+        move || {
+            for ms in samples {
+                yield Op::Measure(ms);
+                seen.borrow_mut()
+                    .push(iter::from_fn(|| context.take()).collect());
+            }
+        }
+    }
+
+    #[test]
+    fn derived_output_next_round() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let computation = {
+            let seen = seen.clone();
+            move |context| measure(context, vec![50, 150, 70], seen)
+        };
+        let (block, history) = computation
+            .into_block()
+            .observe(ObserverConfig::named("metrics"), |output, ctx| {
+                if let Out::Latency(ms) = output {
+                    if *ms > 100 {
+                        ctx.defer_put(Out::Crossed(*ms));
+                    }
+                }
+            })
+            .observe(ObserverConfig::named("ui"), |output, ctx| {
+                if let Out::Crossed(_) = output {
+                    ctx.defer_effect(Op::Repaint);
+                }
+            })
+            .with_history(HistoryConfig::new(16));
+        let (block, accounting) = block
+            .add_handler(handler)
+            .assert_handled()
+            .with_accounting();
+        block.run();
+        accounting.assert_quiescent();
+        assert_eq!(accounting.report().get(Category::Deferred).high_water, 1);
+
+        // the crossing once, in the round after its latency, and the repaint
+        // in the round after the crossing
+        let expected = vec![
+            vec![Out::Latency(50)],
+            vec![Out::Latency(150), Out::Crossed(150)],
+            vec![Out::Latency(70), Out::Repainted],
+        ];
+        assert_eq!(*seen.borrow(), expected);
+
+        // the crossing is the third output put
+        let trace = history.raw();
+        let effects = trace.iter().map(|e| e.effect.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            effects,
+            ["Measure(50)", "Measure(150)", "Measure(70)", "Repaint"]
+        );
+        assert_eq!(trace[3].cause, Some(3));
+    }
+
+    #[test]
+    fn feedback_loop_limited() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let computation = {
+            let seen = seen.clone();
+            move |context| measure(context, vec![10; 8], seen)
+        };
+        let block = computation
+            .into_block()
+            .observe(ObserverConfig::named("quiet"), |_, _| ())
+            .observe(
+                ObserverConfig::named("echo").max_depth(3),
+                |output, ctx| match output {
+                    Out::Tick(n) => ctx.defer_put(Out::Tick(n + 1)),
+                    Out::Latency(_) => ctx.defer_put(Out::Tick(1)),
+                    _ => (),
+                },
+            )
+            .add_handler(handler)
+            .assert_handled();
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| block.run())).unwrap_err();
+        let error = payload.downcast_ref::<ObserverLoop>().unwrap();
+        assert_eq!(error.observer, "echo");
+        assert_eq!((error.depth, error.max_depth), (4, 3));
+        // the third tick is at depth three, the observer trips seeing it
+        let seen = seen.borrow().concat();
+        assert!(seen.contains(&Out::Tick(2)));
+        assert!(!seen.contains(&Out::Tick(3)));
+    }
+
+    #[test]
+    fn registration_order() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let computation = {
+            let seen = seen.clone();
+            move |context| measure(context, vec![1, 2], seen)
+        };
+        computation
+            .into_block()
+            .observe(ObserverConfig::named("first"), |output, ctx| {
+                if let Out::Latency(ms) = output {
+                    ctx.defer_put(Out::Tick(ms * 10 + 1));
+                }
+            })
+            .observe(ObserverConfig::named("second"), |output, ctx| {
+                if let Out::Latency(ms) = output {
+                    ctx.defer_put(Out::Tick(ms * 10 + 2));
+                }
+            })
+            .add_handler(handler)
+            .assert_handled()
+            .run();
+
+        let expected = vec![
+            vec![Out::Latency(1), Out::Tick(11), Out::Tick(12)],
+            vec![Out::Latency(2), Out::Tick(21), Out::Tick(22)],
+        ];
+        assert_eq!(*seen.borrow(), expected);
+    }
+}
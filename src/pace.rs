@@ -0,0 +1,217 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread::{self, Thread},
+    time::{Duration, Instant},
+    ops::{Generator, GeneratorState},
+};
+use super::{block::Block, computation::Effect, deadline, stats};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaceConfig {
+    interval: Duration,
+    early_wake: bool,
+}
+
+impl PaceConfig {
+    pub fn per_second(rounds: u32) -> Self {
+        assert!(rounds > 0, "zero rounds per second");
+        PaceConfig::interval(Duration::from_secs(1) / rounds)
+    }
+
+    // the least time from the start of a round to the start of the next one
+    pub fn interval(interval: Duration) -> Self {
+        PaceConfig {
+            interval,
+            early_wake: false,
+        }
+    }
+
+    // `PaceHandle::wake` starts the next round without waiting for its time
+    pub fn allow_early_wake(self) -> Self {
+        PaceConfig {
+            early_wake: true,
+            ..self
+        }
+    }
+}
+
+struct Shared {
+    woken: AtomicBool,
+    rounds: AtomicU64,
+    thread: Thread,
+}
+
+impl Shared {
+    // parked rather than asleep only if an early wake may end the wait
+    fn wait_until(&self, start: Instant, early_wake: bool) {
+        loop {
+            if early_wake && self.woken.load(Ordering::SeqCst) {
+                return;
+            }
+            let now = deadline::now();
+            if now >= start {
+                return;
+            }
+            if early_wake {
+                deadline::park(start - now);
+            } else {
+                deadline::sleep(start - now);
+            }
+        }
+    }
+}
+
+// Wakes the thread running a paced block, from a handler, a source or any
+// other thread. A wake is spent by the next round, early or not.
+#[derive(Clone)]
+pub struct PaceHandle(Arc<Shared>);
+
+impl PaceHandle {
+    pub fn wake(&self) {
+        self.0.woken.store(true, Ordering::SeqCst);
+        self.0.thread.unpark();
+    }
+
+    pub fn rounds(&self) -> u64 {
+        self.0.rounds.load(Ordering::SeqCst)
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Every resume of the block is a round, it starts no earlier than the
+    // interval after the start of the previous one. The wait goes through
+    // the installed time source, a late round does not make the next ones
+    // catch up. Must run on the thread that calls `paced`.
+    pub fn paced(
+        self,
+        config: PaceConfig,
+    ) -> (
+        Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>,
+        PaceHandle,
+    ) {
+        let shared = Arc::new(Shared {
+            woken: AtomicBool::new(false),
+            rounds: AtomicU64::new(0),
+            thread: thread::current(),
+        });
+        let context = self.context();
+        let mut s = self;
+        let generator = {
+            let shared = shared.clone();
+            let context = context.clone();
+            move || {
+                let mut next = None;
+                loop {
+                    if let Some(start) = next {
+                        let waiting = deadline::now();
+                        shared.wait_until(start, config.early_wake);
+                        stats::parked(&context, deadline::now().saturating_duration_since(waiting));
+                    }
+                    shared.woken.store(false, Ordering::SeqCst);
+                    next = Some(deadline::now() + config.interval);
+                    shared.rounds.fetch_add(1, Ordering::SeqCst);
+                    match s.resume() {
+                        GeneratorState::Complete(r) => return r,
+                        GeneratorState::Yielded(effect) => yield effect,
+                    }
+                }
+            }
+        };
+        (Block::new(context, generator), PaceHandle(shared))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, time::Duration, ops::Generator};
+    use crate::{
+        Context, Effect, IntoBlock,
+        deadline::{self, TestClock},
+    };
+    use super::PaceConfig;
+
+    #[derive(Debug)]
+    struct Step;
+
+    struct Stepped;
+
+    impl Effect for Stepped {
+        type Input = Step;
+    }
+
+    // steps until `span` passed on the clock
+    fn simulation(
+        context: Context<Stepped>,
+        span: Duration,
+    ) -> impl Unpin + Generator<(), Yield = Step, Return = u32> {
+        move || {
+            let end = deadline::now() + span;
+            let mut steps = 0;
+            while deadline::now() < end {
+                yield Step;
+                context.take();
+                steps += 1;
+            }
+            steps
+        }
+    }
+
+    #[test]
+    fn rounds_in_span() {
+        let _clock = TestClock::install();
+        let (block, handle) = (|context| simulation(context, Duration::from_secs(1)))
+            .into_block()
+            .paced(PaceConfig::per_second(50));
+        let steps = block.add_handler(|Step| Ok(Stepped)).assert_handled().run();
+        TestClock::uninstall();
+        assert_eq!(steps, 50);
+        assert_eq!(handle.rounds(), 51);
+    }
+
+    // the milliseconds since the start at which every step is handled, the
+    // third one wakes the next round early
+    fn starts(config: PaceConfig) -> Vec<u128> {
+        let clock = TestClock::install();
+        let origin = clock.now();
+        let (block, handle) = (|context| simulation(context, Duration::from_millis(500)))
+            .into_block()
+            .paced(config);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        block
+            .add_handler({
+                let log = log.clone();
+                move |Step| {
+                    log.borrow_mut()
+                        .push((deadline::now() - origin).as_millis());
+                    if log.borrow().len() == 3 {
+                        handle.wake();
+                    }
+                    Ok(Stepped)
+                }
+            })
+            .assert_handled()
+            .run();
+        TestClock::uninstall();
+        log.take()
+    }
+
+    #[test]
+    fn early_wake() {
+        let config = PaceConfig::interval(Duration::from_millis(100));
+        assert_eq!(starts(config), [0, 100, 200, 300, 400]);
+        assert_eq!(
+            starts(config.allow_early_wake()),
+            [0, 100, 200, 200, 300, 400]
+        );
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    cell::{Cell, RefCell},
+    ops::{Generator, GeneratorState},
+};
+use super::{block::Block, computation::Effect};
+
+pub enum Event<'a, E>
+where
+    E: Effect,
+{
+    Effect(&'a E::Input),
+    Output(&'a E),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PauseReason<I, R> {
+    Predicate,
+    Completed(R),
+    Unhandled(I),
+}
+
+type Stop<E> = Box<dyn FnMut(Event<'_, E>) -> bool>;
+
+struct PauseState<E>
+where
+    E: Effect,
+{
+    stop: RefCell<Option<Stop<E>>>,
+    requested: Cell<bool>,
+    idle: Cell<bool>,
+}
+
+impl<E> Default for PauseState<E>
+where
+    E: Effect,
+{
+    fn default() -> Self {
+        PauseState {
+            stop: RefCell::new(None),
+            requested: Cell::new(false),
+            idle: Cell::new(false),
+        }
+    }
+}
+
+impl<E> PauseState<E>
+where
+    E: Effect,
+{
+    fn check(&self, event: Event<'_, E>) {
+        if let Some(stop) = self.stop.borrow_mut().as_mut() {
+            if stop(event) {
+                self.requested.set(true);
+            }
+        }
+    }
+}
+
+// yields the idle effect up to `run_until`, handlers must not claim it
+macro_rules! pause_point {
+    ($state:expr, $idle:expr) => {
+        if $state.requested.get() {
+            $state.idle.set(true);
+            yield $idle();
+            $state.idle.set(false);
+        }
+    };
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + 'static,
+    E::Input: 'static,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Install right after `into_block`, so effects are seen before any handler
+    // and the computation is stopped before it consumes a watched output.
+    pub fn pausable<F>(
+        self,
+        idle: F,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        F: Fn() -> E::Input,
+    {
+        let context = self.context();
+        let state = context.extension(PauseState::<E>::default);
+        context.add_watch(Box::new({
+            let state = state.clone();
+            move |_, output| state.check(Event::Output(output))
+        }));
+        let mut s = self;
+        let generator = move || loop {
+            pause_point!(state, idle);
+            match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => {
+                    state.check(Event::Effect(&effect));
+                    pause_point!(state, idle);
+                    yield effect;
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
+
+    pub fn run_until<F>(&mut self, stop: F) -> PauseReason<E::Input, G::Return>
+    where
+        F: FnMut(Event<'_, E>) -> bool + 'static,
+    {
+        let state = self.context().extension(PauseState::<E>::default);
+        *state.stop.borrow_mut() = Some(Box::new(stop));
+        let reason = match self.resume() {
+            GeneratorState::Complete(r) => PauseReason::Completed(r),
+            GeneratorState::Yielded(_) if state.idle.get() => {
+                state.requested.set(false);
+                PauseReason::Predicate
+            },
+            GeneratorState::Yielded(effect) => PauseReason::Unhandled(effect),
+        };
+        *state.stop.borrow_mut() = None;
+        reason
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{Context, Effect, IntoBlock};
+    use super::{Event, PauseReason};
+
+    #[derive(Debug, PartialEq)]
+    enum Effects {
+        Connect,
+        Send(u8),
+        Idle,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Connected,
+        Sent,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    #[derive(Default)]
+    struct Peer {
+        connected: bool,
+        sent: Vec<u8>,
+    }
+
+    fn client(
+        context: Context<Outputs>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = usize> {
+        move || {
+            yield Effects::Connect;
+            assert_eq!(context.take(), Some(Outputs::Connected));
+            for byte in 0..3 {
+                yield Effects::Send(byte);
+            }
+            context.len()
+        }
+    }
+
+    fn block(
+        peer: Rc<RefCell<Peer>>,
+    ) -> crate::Block<Outputs, impl Unpin + Generator<(), Yield = Effects, Return = usize>> {
+        client
+            .into_block()
+            .pausable(|| Effects::Idle)
+            .add_handler(move |effect| match effect {
+                Effects::Connect => {
+                    peer.borrow_mut().connected = true;
+                    Ok(Outputs::Connected)
+                },
+                Effects::Send(byte) => {
+                    peer.borrow_mut().sent.push(byte);
+                    Ok(Outputs::Sent)
+                },
+                e => Err(e),
+            })
+    }
+
+    #[test]
+    fn pause_on_connected() {
+        let peer = Rc::new(RefCell::new(Peer::default()));
+        let mut block = block(peer.clone());
+        let reason = block.run_until(|event| matches!(event, Event::Output(Outputs::Connected)));
+        assert_eq!(reason, PauseReason::Predicate);
+        assert!(peer.borrow().connected);
+        assert!(peer.borrow().sent.is_empty());
+        assert_eq!(block.run_until(|_| false), PauseReason::Completed(3));
+        assert_eq!(peer.borrow().sent, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn never_fires() {
+        let peer = Rc::new(RefCell::new(Peer::default()));
+        let mut block = block(peer.clone());
+        let reason = block.run_until(|event| matches!(event, Event::Effect(Effects::Send(9))));
+        assert_eq!(reason, PauseReason::Completed(3));
+        assert_eq!(peer.borrow().sent, vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,909 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    any::Any,
+    collections::BTreeMap,
+    fmt,
+    error::Error,
+    str::FromStr,
+    iter::FromIterator,
+    ops::{Generator, GeneratorState, ControlFlow},
+};
+use super::{
+    block::Block,
+    computation::{Effect, Handler},
+    manifest::{Capability, CapabilitiesManifest},
+    schema::EffectSchema,
+};
+
+// Flat string settings, plugins usually prefix their keys with their name.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PluginConfig(BTreeMap<String, String>);
+
+impl PluginConfig {
+    pub fn new() -> Self {
+        PluginConfig::default()
+    }
+
+    pub fn with<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.insert(key, value);
+        self
+    }
+
+    pub fn insert<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.0.insert(key.into(), value.into());
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    // a value that does not parse is the same as a missing one
+    pub fn parse<T>(&self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+    {
+        self.get(key)?.parse().ok()
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for PluginConfig
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    fn from_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        PluginConfig(
+            iter.into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        )
+    }
+}
+
+// Names a shared handle one handler deposits for the others, like the time
+// source of a clock handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ResourceKey(pub &'static str);
+
+impl fmt::Display for ResourceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+// what the handlers initialized so far deposited, passed to `Handler::init_with`
+#[derive(Default)]
+pub struct Resources(BTreeMap<ResourceKey, Rc<dyn Any>>);
+
+impl Resources {
+    pub fn new() -> Self {
+        Resources::default()
+    }
+
+    // the previous handle under the key is replaced
+    pub fn insert<T>(&mut self, key: ResourceKey, resource: Rc<T>)
+    where
+        T: Any,
+    {
+        self.0.insert(key, resource);
+    }
+
+    // `None` if nothing is under the key or it is of another type
+    pub fn get<T>(&self, key: ResourceKey) -> Option<Rc<T>>
+    where
+        T: Any,
+    {
+        self.0.get(&key)?.clone().downcast().ok()
+    }
+
+    pub fn contains(&self, key: ResourceKey) -> bool {
+        self.0.contains_key(&key)
+    }
+
+    // the handle is released once the handlers holding a clone drop it
+    pub fn remove(&mut self, key: ResourceKey) -> bool {
+        self.0.remove(&key).is_some()
+    }
+}
+
+// A handler provided by another crate, the binary registers it by value and
+// picks it by name. The claims are the names of the effect variants it handles.
+pub trait HandlerPlugin<E>
+where
+    E: Effect,
+{
+    fn name(&self) -> &'static str;
+
+    fn claims(&self) -> &'static [&'static str];
+
+    // usually `env!("CARGO_PKG_VERSION")` of the plugin crate, the manifest
+    // leaves an empty one out
+    fn version(&self) -> &'static str {
+        ""
+    }
+
+    fn build(&self, config: &PluginConfig) -> Box<dyn Handler<E>>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryError {
+    UnknownPlugin(String),
+    DuplicateName(&'static str),
+    DuplicateClaim {
+        variant: &'static str,
+        first: &'static str,
+        second: &'static str,
+    },
+    MissingProvider {
+        resource: ResourceKey,
+        required_by: &'static str,
+    },
+    // each one requires what the next one provides, the last one the first
+    Cycle(Vec<&'static str>),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::UnknownPlugin(name) => write!(f, "no plugin named `{}`", name),
+            RegistryError::DuplicateName(name) => {
+                write!(f, "more than one plugin is named `{}`", name)
+            },
+            RegistryError::DuplicateClaim {
+                variant,
+                first,
+                second,
+            } => write!(
+                f,
+                "`{}` is claimed by both `{}` and `{}`",
+                variant, first, second
+            ),
+            RegistryError::MissingProvider {
+                resource,
+                required_by,
+            } => write!(
+                f,
+                "`{}` requires `{}`, no plugin provides it",
+                required_by, resource
+            ),
+            RegistryError::Cycle(names) => {
+                write!(f, "the plugins depend on each other: ")?;
+                for name in names {
+                    write!(f, "`{}` -> ", name)?;
+                }
+                write!(f, "`{}`", names[0])
+            },
+        }
+    }
+}
+
+impl Error for RegistryError {}
+
+pub struct Registry<E>
+where
+    E: Effect,
+{
+    plugins: Vec<Box<dyn HandlerPlugin<E>>>,
+    config: PluginConfig,
+    strict: bool,
+}
+
+impl<E> Default for Registry<E>
+where
+    E: Effect,
+{
+    fn default() -> Self {
+        Registry {
+            plugins: Vec::new(),
+            config: PluginConfig::default(),
+            strict: false,
+        }
+    }
+}
+
+impl<E> Registry<E>
+where
+    E: Effect,
+{
+    pub fn new(config: PluginConfig) -> Self {
+        Registry {
+            config,
+            ..Registry::default()
+        }
+    }
+
+    // an effect variant claimed by two of the assembled plugins is an error,
+    // otherwise the one installed first handles it
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    pub fn register<P>(&mut self, plugin: P) -> &mut Self
+    where
+        P: HandlerPlugin<E> + 'static,
+    {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.plugins.iter().map(|p| p.name()).collect()
+    }
+
+    fn plugins(&self, order: &[&str]) -> Result<Vec<&dyn HandlerPlugin<E>>, RegistryError> {
+        let plugins = order
+            .iter()
+            .map(|name| {
+                let mut found = self.plugins.iter().filter(|p| p.name() == *name);
+                match (found.next(), found.next()) {
+                    (Some(plugin), None) => Ok(plugin.as_ref()),
+                    (Some(plugin), Some(_)) => Err(RegistryError::DuplicateName(plugin.name())),
+                    (None, _) => Err(RegistryError::UnknownPlugin(name.to_string())),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if self.strict {
+            let mut claimed = BTreeMap::new();
+            for plugin in &plugins {
+                for variant in plugin.claims() {
+                    if let Some(first) = claimed.insert(*variant, plugin.name()) {
+                        return Err(RegistryError::DuplicateClaim {
+                            variant,
+                            first,
+                            second: plugin.name(),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(plugins)
+    }
+
+    // Init order of the handlers, the providers of a resource before those
+    // requiring it, otherwise as given.
+    fn init_order(
+        names: &[&'static str],
+        handlers: &[Box<dyn Handler<E>>],
+    ) -> Result<Vec<usize>, RegistryError> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Mark {
+            New,
+            Visiting,
+            Done,
+        }
+
+        struct Sort<'a, E>
+        where
+            E: Effect,
+        {
+            names: &'a [&'static str],
+            handlers: &'a [Box<dyn Handler<E>>],
+            marks: Vec<Mark>,
+            path: Vec<usize>,
+            order: Vec<usize>,
+        }
+
+        impl<E> Sort<'_, E>
+        where
+            E: Effect,
+        {
+            fn visit(&mut self, index: usize) -> Result<(), RegistryError> {
+                match self.marks[index] {
+                    Mark::Done => return Ok(()),
+                    Mark::Visiting => {
+                        let start = self.path.iter().position(|i| *i == index).unwrap_or(0);
+                        let cycle = self.path[start..].iter().map(|i| self.names[*i]);
+                        return Err(RegistryError::Cycle(cycle.collect()));
+                    },
+                    Mark::New => (),
+                }
+                self.marks[index] = Mark::Visiting;
+                self.path.push(index);
+                for resource in self.handlers[index].requires() {
+                    let providers = (0..self.handlers.len())
+                        .filter(|i| self.handlers[*i].provides().contains(resource))
+                        .collect::<Vec<_>>();
+                    if providers.is_empty() {
+                        return Err(RegistryError::MissingProvider {
+                            resource: *resource,
+                            required_by: self.names[index],
+                        });
+                    }
+                    for provider in providers {
+                        self.visit(provider)?;
+                    }
+                }
+                self.path.pop();
+                self.marks[index] = Mark::Done;
+                self.order.push(index);
+                Ok(())
+            }
+        }
+
+        let mut sort = Sort {
+            names,
+            handlers,
+            marks: vec![Mark::New; handlers.len()],
+            path: Vec::new(),
+            order: Vec::new(),
+        };
+        for index in 0..handlers.len() {
+            sort.visit(index)?;
+        }
+        Ok(sort.order)
+    }
+
+    // Every registered plugin in the order of registration. The handlers are
+    // built with the config to ask them, they are not initialized.
+    pub fn manifest(&self) -> CapabilitiesManifest {
+        let handlers = self
+            .plugins
+            .iter()
+            .map(|plugin| {
+                let handler = plugin.build(&self.config);
+                let claims = Some(plugin.claims());
+                Capability::new(plugin.name(), plugin.version(), claims, &*handler)
+            })
+            .collect();
+        CapabilitiesManifest { handlers }
+    }
+
+    // the variants of the schema none of the named plugins claims
+    pub fn unclaimed(
+        &self,
+        order: &[&str],
+        schema: &EffectSchema,
+    ) -> Result<Vec<String>, RegistryError> {
+        let plugins = self.plugins(order)?;
+        Ok(schema
+            .variants
+            .iter()
+            .filter(|v| {
+                !plugins
+                    .iter()
+                    .any(|p| p.claims().contains(&v.name.as_str()))
+            })
+            .map(|v| v.name.clone())
+            .collect())
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // The plugins are offered an effect in the given order, as if each one
+    // was installed with `add_handler` after the previous one. They are
+    // initialized here with the resources of their providers. Once the block
+    // completes, after the layers inside it yielded their last effects, they
+    // finish in reverse of that order. A handler is dropped right after its
+    // `finish`, together with the resources it provides, so a provider
+    // outlives every handler requiring it.
+    pub fn add_registry(
+        self,
+        registry: &Registry<E>,
+        order: &[&str],
+    ) -> Result<
+        Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>,
+        RegistryError,
+    > {
+        let plugins = registry.plugins(order)?;
+        let names = plugins.iter().map(|p| p.name()).collect::<Vec<_>>();
+        let mut handlers = plugins
+            .into_iter()
+            .map(|plugin| plugin.build(&registry.config))
+            .collect::<Vec<_>>();
+        let init_order = Registry::init_order(&names, &handlers)?;
+        let mut resources = Resources::new();
+        for index in &init_order {
+            handlers[*index].init_with(&mut resources);
+        }
+        // taken out when they finish
+        let mut handlers = handlers.into_iter().map(Some).collect::<Vec<_>>();
+        let context = self.context();
+        let mut s = self;
+        let generator = move || loop {
+            match s.resume() {
+                GeneratorState::Complete(r) => {
+                    for index in init_order.iter().rev() {
+                        if let Some(mut handler) = handlers[*index].take() {
+                            handler.finish();
+                            for key in handler.provides() {
+                                resources.remove(*key);
+                            }
+                        }
+                    }
+                    return r;
+                },
+                GeneratorState::Yielded(effect) => {
+                    let mut handlers = handlers.iter_mut().flatten();
+                    let offered =
+                        handlers.try_fold(effect, |effect, handler| match handler.handle(effect) {
+                            Ok(output) => ControlFlow::Break(output),
+                            Err(effect) => ControlFlow::Continue(effect),
+                        });
+                    match offered {
+                        ControlFlow::Break(output) => s.put(output),
+                        ControlFlow::Continue(effect) => yield effect,
+                    }
+                },
+            }
+        };
+        Ok(Block::new(context, generator))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{
+        Context, Effect, Handler, IntoBlock, Session, WithSession, perform,
+        schema::{EffectSchema, VariantSchema},
+    };
+    use super::{HandlerPlugin, PluginConfig, Registry, RegistryError, ResourceKey, Resources};
+
+    #[derive(Debug)]
+    pub enum Effects {
+        Send(u16, &'static str),
+        Count(&'static str),
+        // no plugin claims it
+        #[allow(dead_code)]
+        Sleep,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum Outputs {
+        Sent(String),
+        Counted,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    // a dropped session is counted under its name
+    impl WithSession for Effects {
+        type Key = &'static str;
+
+        fn key(&self) -> Option<&Self::Key> {
+            None
+        }
+
+        fn close_session(key: Self::Key) -> Self {
+            Effects::Count(key)
+        }
+    }
+
+    // stands for a separate `tcp-handler` crate
+    mod tcp_handler {
+        use crate::{
+            Handler,
+            plugin::{HandlerPlugin, PluginConfig},
+        };
+        use super::{Effects, Outputs};
+
+        pub struct TcpPlugin;
+
+        impl HandlerPlugin<Outputs> for TcpPlugin {
+            fn name(&self) -> &'static str {
+                "tcp"
+            }
+
+            fn claims(&self) -> &'static [&'static str] {
+                &["Send"]
+            }
+
+            fn build(&self, config: &PluginConfig) -> Box<dyn Handler<Outputs>> {
+                let host = config.get("tcp.host").unwrap_or("localhost").to_string();
+                Box::new(move |effect| match effect {
+                    Effects::Send(port, message) => {
+                        Ok(Outputs::Sent(format!("{}:{} {}", host, port, message)))
+                    },
+                    other => Err(other),
+                })
+            }
+        }
+    }
+
+    // stands for a separate `metrics-handler` crate, it also counts sends
+    mod metrics_handler {
+        use std::{rc::Rc, cell::RefCell};
+        use crate::{
+            Handler,
+            plugin::{HandlerPlugin, PluginConfig},
+        };
+        use super::{Effects, Outputs};
+
+        pub struct MetricsPlugin(pub Rc<RefCell<Vec<String>>>);
+
+        impl HandlerPlugin<Outputs> for MetricsPlugin {
+            fn name(&self) -> &'static str {
+                "metrics"
+            }
+
+            fn claims(&self) -> &'static [&'static str] {
+                &["Count", "Send"]
+            }
+
+            fn build(&self, _: &PluginConfig) -> Box<dyn Handler<Outputs>> {
+                let seen = self.0.clone();
+                Box::new(move |effect| match effect {
+                    Effects::Count(name) => {
+                        seen.borrow_mut().push(name.to_string());
+                        Ok(Outputs::Counted)
+                    },
+                    Effects::Send(..) => {
+                        seen.borrow_mut().push("send".to_string());
+                        Ok(Outputs::Sent("counted".to_string()))
+                    },
+                    other => Err(other),
+                })
+            }
+        }
+    }
+
+    use self::{tcp_handler::TcpPlugin, metrics_handler::MetricsPlugin};
+
+    fn registry(seen: &Rc<RefCell<Vec<String>>>) -> Registry<Outputs> {
+        let mut registry = Registry::new(PluginConfig::new().with("tcp.host", "example"));
+        registry
+            .register(TcpPlugin)
+            .register(MetricsPlugin(seen.clone()));
+        registry
+    }
+
+    fn computation(
+        context: Context<Outputs>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = Vec<Outputs>> {
+        move || {
+            perform!(Effects::Count("start"));
+            perform!(Effects::Send(80, "hi"));
+            std::iter::from_fn(|| context.take()).collect()
+        }
+    }
+
+    #[test]
+    fn order() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let registry = registry(&seen);
+        let outputs = computation
+            .into_block()
+            .add_registry(&registry, &["tcp", "metrics"])
+            .unwrap()
+            .assert_handled()
+            .run();
+        assert_eq!(
+            outputs,
+            [Outputs::Counted, Outputs::Sent("example:80 hi".to_string())]
+        );
+        assert_eq!(*seen.borrow(), ["start"]);
+
+        let outputs = computation
+            .into_block()
+            .add_registry(&registry, &["metrics", "tcp"])
+            .unwrap()
+            .assert_handled()
+            .run();
+        assert_eq!(outputs[1], Outputs::Sent("counted".to_string()));
+    }
+
+    #[test]
+    fn errors() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let unknown = computation
+            .into_block()
+            .add_registry(&registry(&seen), &["tcp", "dns"])
+            .err();
+        assert_eq!(
+            unknown,
+            Some(RegistryError::UnknownPlugin("dns".to_string()))
+        );
+
+        let strict = registry(&seen).strict();
+        let duplicate = computation
+            .into_block()
+            .add_registry(&strict, &["tcp", "metrics"])
+            .err();
+        assert_eq!(
+            duplicate,
+            Some(RegistryError::DuplicateClaim {
+                variant: "Send",
+                first: "tcp",
+                second: "metrics",
+            }),
+        );
+        assert!(computation
+            .into_block()
+            .add_registry(&strict, &["metrics"])
+            .is_ok());
+    }
+
+    #[test]
+    fn coverage() {
+        let variant = |name: &str| VariantSchema {
+            name: name.to_string(),
+            fields: Vec::new(),
+            part: None,
+            doc: String::new(),
+        };
+        let schema = EffectSchema {
+            name: "Effects".to_string(),
+            input: None,
+            doc: String::new(),
+            variants: vec![variant("Send"), variant("Count"), variant("Sleep")],
+        };
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let registry = registry(&seen);
+        assert_eq!(
+            registry.unclaimed(&["tcp"], &schema),
+            Ok(vec!["Count".to_string(), "Sleep".to_string()]),
+        );
+        assert_eq!(
+            registry.unclaimed(&["tcp", "metrics"], &schema),
+            Ok(vec!["Sleep".to_string()]),
+        );
+    }
+
+    const CLOCK: ResourceKey = ResourceKey("clock");
+    const SOCKETS: ResourceKey = ResourceKey("sockets");
+    const SESSIONS: ResourceKey = ResourceKey("sessions");
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    // the clock deposits the time, the meter stamps the counts with it
+    struct Node {
+        name: &'static str,
+        provides: &'static [ResourceKey],
+        requires: &'static [ResourceKey],
+        log: Log,
+    }
+
+    struct NodeHandler {
+        name: &'static str,
+        provides: &'static [ResourceKey],
+        requires: &'static [ResourceKey],
+        log: Log,
+        held: Vec<Rc<Deposit>>,
+    }
+
+    // logs its release
+    struct Deposit {
+        key: ResourceKey,
+        time: u32,
+        log: Log,
+    }
+
+    impl Drop for Deposit {
+        fn drop(&mut self) {
+            self.log.borrow_mut().push(format!("release {}", self.key));
+        }
+    }
+
+    impl HandlerPlugin<Outputs> for Node {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn claims(&self) -> &'static [&'static str] {
+            &[]
+        }
+
+        fn build(&self, _: &PluginConfig) -> Box<dyn Handler<Outputs>> {
+            Box::new(NodeHandler {
+                name: self.name,
+                provides: self.provides,
+                requires: self.requires,
+                log: self.log.clone(),
+                held: Vec::new(),
+            })
+        }
+    }
+
+    impl Handler<Outputs> for NodeHandler {
+        fn handle(&mut self, effect: Effects) -> Result<Outputs, Effects> {
+            match (self.name, effect) {
+                ("meter", Effects::Count(name)) => {
+                    let clock = self.held.iter().find(|d| d.key == CLOCK);
+                    let time = clock.map_or(0, |d| d.time);
+                    self.log.borrow_mut().push(format!("{}@{}", name, time));
+                    Ok(Outputs::Counted)
+                },
+                ("meter", Effects::Send(..)) | ("tcp", Effects::Send(..)) => {
+                    Ok(Outputs::Sent(self.name.to_string()))
+                },
+                (_, effect) => Err(effect),
+            }
+        }
+
+        fn provides(&self) -> &'static [ResourceKey] {
+            self.provides
+        }
+
+        fn requires(&self) -> &'static [ResourceKey] {
+            self.requires
+        }
+
+        fn init_with(&mut self, resources: &mut Resources) {
+            self.log.borrow_mut().push(format!("init {}", self.name));
+            for key in self.provides {
+                let log = self.log.clone();
+                resources.insert(
+                    *key,
+                    Rc::new(Deposit {
+                        key: *key,
+                        time: 7,
+                        log,
+                    }),
+                );
+            }
+            for key in self.requires {
+                self.held.extend(resources.get(*key));
+            }
+        }
+
+        fn finish(&mut self) {
+            self.log.borrow_mut().push(format!("finish {}", self.name));
+        }
+    }
+
+    fn node(
+        name: &'static str,
+        provides: &'static [ResourceKey],
+        requires: &'static [ResourceKey],
+        log: &Log,
+    ) -> Node {
+        Node {
+            name,
+            provides,
+            requires,
+            log: log.clone(),
+        }
+    }
+
+    fn nodes(log: &Log) -> Registry<Outputs> {
+        let node = |name, provides, requires| node(name, provides, requires, log);
+        let mut registry = Registry::new(PluginConfig::new());
+        registry
+            .register(node("tcp", &[], &[]))
+            .register(node("meter", &[], &[CLOCK]))
+            .register(node("clock", &[CLOCK], &[]))
+            // depend on each other
+            .register(node("tls", &[SESSIONS], &[SOCKETS]))
+            .register(node("sockets", &[SOCKETS], &[SESSIONS]));
+        registry
+    }
+
+    #[test]
+    fn init_order() {
+        let log = Log::default();
+        let outputs = computation
+            .into_block()
+            .add_registry(&nodes(&log), &["tcp", "meter", "clock"])
+            .unwrap()
+            .assert_handled()
+            .run();
+        // initialized after the clock, still offered the effects before it
+        // and after the tcp handler
+        assert_eq!(
+            outputs,
+            [Outputs::Counted, Outputs::Sent("tcp".to_string())]
+        );
+        assert_eq!(
+            *log.borrow(),
+            [
+                "init tcp",
+                "init clock",
+                "init meter",
+                "start@7",
+                "finish meter",
+                "finish clock",
+                "release clock",
+                "finish tcp",
+            ],
+        );
+    }
+
+    #[test]
+    fn teardown_order() {
+        let log = Log::default();
+        let mut registry = Registry::new(PluginConfig::new());
+        registry
+            .register(node("app", &[], &[SESSIONS], &log))
+            .register(node("tls", &[SESSIONS], &[SOCKETS], &log))
+            .register(node("sockets", &[SOCKETS], &[], &log));
+        computation
+            .into_block()
+            .add_registry(&registry, &["app", "tls", "sockets"])
+            .unwrap()
+            .add_handler(|_| Ok::<_, Effects>(Outputs::Counted))
+            .assert_handled()
+            .run();
+        // the sessions go with the tls handler, the app released them before
+        assert_eq!(
+            *log.borrow(),
+            [
+                "init sockets",
+                "init tls",
+                "init app",
+                "finish app",
+                "finish tls",
+                "release sessions",
+                "finish sockets",
+                "release sockets",
+            ],
+        );
+    }
+
+    #[test]
+    fn drained_before_finish() {
+        let log = Log::default();
+        let output = (|context: Context<Outputs>| {
+            move || {
+                let _session = Session::<Effects>::open(&context, "conn");
+                perform!(Effects::Send(80, "hi"));
+                context.take()
+            }
+        })
+        .into_block()
+        .close_sessions()
+        .add_registry(&nodes(&log), &["meter", "clock"])
+        .unwrap()
+        .assert_handled()
+        .run();
+        assert_eq!(output, Some(Outputs::Sent("meter".to_string())));
+        // the session dropped on return is closed while the clock is there
+        assert_eq!(
+            *log.borrow(),
+            [
+                "init clock",
+                "init meter",
+                "conn@7",
+                "finish meter",
+                "finish clock",
+                "release clock",
+            ],
+        );
+    }
+
+    #[test]
+    fn dependency_errors() {
+        let log = Log::default();
+        let missing = computation
+            .into_block()
+            .add_registry(&nodes(&log), &["tcp", "meter"])
+            .err();
+        assert_eq!(
+            missing,
+            Some(RegistryError::MissingProvider {
+                resource: CLOCK,
+                required_by: "meter",
+            }),
+        );
+        let cycle = computation
+            .into_block()
+            .add_registry(&nodes(&log), &["clock", "tls", "sockets"])
+            .err();
+        assert_eq!(cycle, Some(RegistryError::Cycle(vec!["tls", "sockets"])));
+        assert_eq!(
+            cycle.unwrap().to_string(),
+            "the plugins depend on each other: `tls` -> `sockets` -> `tls`"
+        );
+        assert!(log.borrow().is_empty());
+    }
+}
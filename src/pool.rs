@@ -0,0 +1,556 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+use super::{
+    accounting::{Accounting, Counter},
+    blocking::BlockingWait,
+    computation::{Effect, Handler},
+    deadline::{self, Rounds},
+    two_phase::{OpId, TwoPhase},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoolOp<K> {
+    // takes an idle resource of the key, or opens one
+    Acquire(K),
+    // works on an acquired resource, goes to the inner handler
+    Use(K),
+    // gives the resource back, it stays open for the next acquisition
+    Release(K),
+    // not in the family, goes to the inner handler
+    Other,
+}
+
+// The shape of an open/use/close effect family. An acquisition the inner
+// handler answers holds a resource until it is released, unless `is_open`
+// tells the answer is a failure.
+pub trait Poolable<K>
+where
+    Self: Effect,
+{
+    fn classify(effect: &Self::Input) -> PoolOp<K>;
+    // the effect opening a resource for an acquisition that waited in the queue
+    fn open(key: K) -> Self::Input;
+    // the effect closing an idle resource
+    fn close(key: K) -> Self::Input;
+    // answers an acquisition with an idle resource
+    fn reused(key: &K) -> Self;
+    fn released(key: &K) -> Self;
+
+    fn is_open(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    // the resources open at once, idle or acquired
+    pub max_open: usize,
+    // an idle resource is closed once it idles for so many rounds
+    pub max_idle_rounds: u64,
+    // the acquisitions waiting for a resource, one more is declined
+    pub acquire_queue_depth: usize,
+}
+
+// what the acquisition at the front of the queue gets once capacity frees up
+enum Grant<K> {
+    // the resource released by the previous holder
+    Handover(K),
+    // room to open a new one
+    Room(K),
+}
+
+// Bounds the resources open through the inner handler and keeps the released
+// ones for reuse. An acquisition over the bound is answered with
+// `TwoPhase::ack`, the wait effect for it is declined until it gets a
+// resource, in the queue order. Idle resources expire on the rounds of the
+// time source, they are closed through the inner handler the next time this
+// one handles an effect.
+pub struct PoolHandler<H, K> {
+    inner: H,
+    config: PoolConfig,
+    // counts the room granted to the queue too
+    open: usize,
+    // the idle since times of each key, the longest idle first
+    idle: BTreeMap<K, VecDeque<Instant>>,
+    queue: VecDeque<(OpId, K)>,
+    granted: BTreeMap<OpId, Grant<K>>,
+    next: u64,
+    accounting: Option<Accounting>,
+}
+
+impl<H, K> PoolHandler<H, K>
+where
+    K: Clone + Ord,
+{
+    pub fn new(inner: H, config: PoolConfig) -> Self {
+        PoolHandler {
+            inner,
+            config,
+            open: 0,
+            idle: BTreeMap::new(),
+            queue: VecDeque::new(),
+            granted: BTreeMap::new(),
+            next: 0,
+            accounting: None,
+        }
+    }
+
+    // bumps `Counter::PoolOpened`, `PoolReused`, `PoolQueued` and `PoolExpired`
+    pub fn report_to(self, accounting: &Accounting) -> Self {
+        PoolHandler {
+            accounting: Some(accounting.clone()),
+            ..self
+        }
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    // the resources open, idle or acquired
+    pub fn open(&self) -> usize {
+        self.open
+    }
+
+    pub fn idle(&self) -> usize {
+        self.idle.values().map(VecDeque::len).sum()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn count(&self, counter: Counter) {
+        if let Some(accounting) = &self.accounting {
+            count(accounting, counter);
+        }
+    }
+
+    // the most recently released first, it is the least likely to be stale
+    fn take_idle(&mut self, key: &K) -> bool {
+        let idle = match self.idle.get_mut(key) {
+            Some(idle) => idle,
+            None => return false,
+        };
+        idle.pop_back();
+        if idle.is_empty() {
+            self.idle.remove(key);
+        }
+        true
+    }
+
+    fn grant_room(&mut self) {
+        while self.open < self.config.max_open {
+            match self.queue.pop_front() {
+                Some((id, key)) => {
+                    self.open += 1;
+                    self.granted.insert(id, Grant::Room(key));
+                },
+                None => break,
+            }
+        }
+    }
+
+    fn close<E>(&mut self, key: K)
+    where
+        E: Poolable<K>,
+        H: Handler<E>,
+    {
+        // nobody waits for the response
+        let _ = self.inner.handle(E::close(key));
+        self.open -= 1;
+    }
+
+    fn expire<E>(&mut self)
+    where
+        E: Poolable<K>,
+        H: Handler<E>,
+    {
+        let limit = Duration::from(Rounds(self.config.max_idle_rounds));
+        let now = deadline::now();
+        let mut expired = Vec::new();
+        for (key, idle) in &mut self.idle {
+            while idle.front().map_or(false, |since| {
+                now.saturating_duration_since(*since) >= limit
+            }) {
+                idle.pop_front();
+                expired.push(key.clone());
+            }
+        }
+        if expired.is_empty() {
+            return;
+        }
+        self.idle.retain(|_, idle| !idle.is_empty());
+        for key in expired {
+            self.close::<E>(key);
+            self.count(Counter::PoolExpired);
+        }
+        self.grant_room();
+    }
+
+    // `reserved` when the room is counted already
+    fn open_with<E>(&mut self, effect: E::Input, reserved: bool) -> Result<E, E::Input>
+    where
+        E: Poolable<K>,
+        H: Handler<E>,
+    {
+        match self.inner.handle(effect) {
+            Ok(output) if output.is_open() => {
+                if !reserved {
+                    self.open += 1;
+                }
+                self.count(Counter::PoolOpened);
+                Ok(output)
+            },
+            result => {
+                if reserved {
+                    self.open -= 1;
+                    self.grant_room();
+                }
+                result
+            },
+        }
+    }
+
+    fn release<E>(&mut self, key: K)
+    where
+        E: Poolable<K>,
+        H: Handler<E>,
+    {
+        match self.queue.front() {
+            Some((_, front)) if *front == key => {
+                let (id, key) = self.queue.pop_front().expect("checked above");
+                self.granted.insert(id, Grant::Handover(key));
+                self.count(Counter::PoolReused);
+            },
+            // the queue waits for another key, the released one makes room
+            Some(_) => {
+                self.close::<E>(key);
+                self.grant_room();
+            },
+            None => self.idle.entry(key).or_default().push_back(deadline::now()),
+        }
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+fn count(accounting: &Accounting, counter: Counter) {
+    accounting.bump(counter);
+}
+
+#[cfg(not(feature = "diagnostics"))]
+#[inline(always)]
+fn count(accounting: &Accounting, counter: Counter) {
+    let _ = (accounting, counter);
+}
+
+impl<E, H, K> Handler<E> for PoolHandler<H, K>
+where
+    E: Poolable<K> + TwoPhase,
+    E::Input: BlockingWait,
+    H: Handler<E>,
+    K: Clone + Ord,
+{
+    fn handle(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        self.expire::<E>();
+        if let Some(id) = effect.waits_for() {
+            return match self.granted.remove(&id) {
+                Some(Grant::Handover(key)) => Ok(E::reused(&key)),
+                Some(Grant::Room(key)) => self.open_with(E::open(key), true),
+                // still queued, or not one of ours
+                None => Err(effect),
+            };
+        }
+        match E::classify(&effect) {
+            PoolOp::Acquire(key) => {
+                if self.take_idle(&key) {
+                    self.count(Counter::PoolReused);
+                    Ok(E::reused(&key))
+                } else if self.open < self.config.max_open {
+                    self.open_with(effect, false)
+                } else if self.queue.len() < self.config.acquire_queue_depth {
+                    let id = OpId(self.next);
+                    self.next += 1;
+                    self.queue.push_back((id, key));
+                    self.count(Counter::PoolQueued);
+                    Ok(E::ack(id))
+                } else {
+                    Err(effect)
+                }
+            },
+            PoolOp::Release(key) => {
+                let output = E::released(&key);
+                self.release::<E>(key);
+                Ok(output)
+            },
+            PoolOp::Use(_) | PoolOp::Other => self.inner.handle(effect),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc, time::Duration};
+    use crate::{
+        Effect, Handler, OpId, TwoPhase,
+        blocking::BlockingWait,
+        deadline::{self, TestClock},
+    };
+    use super::{PoolConfig, PoolHandler, PoolOp, Poolable};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Connect(&'static str),
+        Send(&'static str),
+        Disconnect(&'static str),
+        Close(&'static str),
+        Wait(OpId),
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Connected(&'static str),
+        Reused(&'static str),
+        Sent,
+        Disconnected,
+        Closed,
+        Queued(OpId),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl TwoPhase for Outputs {
+        fn ack(id: OpId) -> Self {
+            Outputs::Queued(id)
+        }
+
+        fn is_ack(&self) -> Option<OpId> {
+            match self {
+                Outputs::Queued(id) => Some(*id),
+                _ => None,
+            }
+        }
+
+        fn is_completion(&self) -> Option<OpId> {
+            None
+        }
+    }
+
+    impl BlockingWait for Effects {
+        fn waits_for(&self) -> Option<OpId> {
+            match self {
+                Effects::Wait(id) => Some(*id),
+                _ => None,
+            }
+        }
+    }
+
+    impl Poolable<&'static str> for Outputs {
+        fn classify(effect: &Effects) -> PoolOp<&'static str> {
+            match effect {
+                Effects::Connect(addr) => PoolOp::Acquire(*addr),
+                Effects::Send(addr) => PoolOp::Use(*addr),
+                Effects::Disconnect(addr) => PoolOp::Release(*addr),
+                _ => PoolOp::Other,
+            }
+        }
+
+        fn open(key: &'static str) -> Effects {
+            Effects::Connect(key)
+        }
+
+        fn close(key: &'static str) -> Effects {
+            Effects::Close(key)
+        }
+
+        fn reused(key: &&'static str) -> Self {
+            Outputs::Reused(key)
+        }
+
+        fn released(_: &&'static str) -> Self {
+            Outputs::Disconnected
+        }
+    }
+
+    // the effects that reach the network
+    #[derive(Default, Clone)]
+    struct Net(Rc<RefCell<Vec<Effects>>>);
+
+    impl Net {
+        fn handler(&self) -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+            let log = self.0.clone();
+            move |effect| {
+                log.borrow_mut().push(effect.clone());
+                match effect {
+                    Effects::Connect(addr) => Ok(Outputs::Connected(addr)),
+                    Effects::Send(_) => Ok(Outputs::Sent),
+                    Effects::Close(_) => Ok(Outputs::Closed),
+                    other => Err(other),
+                }
+            }
+        }
+
+        fn connects(&self) -> usize {
+            self.count(|e| matches!(e, Effects::Connect(_)))
+        }
+
+        fn closes(&self) -> usize {
+            self.count(|e| matches!(e, Effects::Close(_)))
+        }
+
+        fn count(&self, f: impl Fn(&Effects) -> bool) -> usize {
+            self.0.borrow().iter().filter(|e| f(e)).count()
+        }
+    }
+
+    fn config(max_open: usize, max_idle_rounds: u64, acquire_queue_depth: usize) -> PoolConfig {
+        PoolConfig {
+            max_open,
+            max_idle_rounds,
+            acquire_queue_depth,
+        }
+    }
+
+    #[test]
+    fn reuse() {
+        let net = Net::default();
+        let mut pool = PoolHandler::new(net.handler(), config(2, 100, 0));
+        for _ in 0..5 {
+            match pool.handle(Effects::Connect("a")).unwrap() {
+                Outputs::Connected("a") | Outputs::Reused("a") => (),
+                other => panic!("{:?}", other),
+            }
+            assert_eq!(pool.handle(Effects::Send("a")), Ok(Outputs::Sent));
+            assert_eq!(
+                pool.handle(Effects::Disconnect("a")),
+                Ok(Outputs::Disconnected)
+            );
+        }
+        assert_eq!(net.connects(), 1);
+        assert_eq!((pool.open(), pool.idle()), (1, 1));
+        // the release never reaches the network
+        assert_eq!(net.count(|e| matches!(e, Effects::Disconnect(_))), 0);
+    }
+
+    // a client connects, sends twice and disconnects, one step a turn
+    #[derive(Debug)]
+    enum Client {
+        Connecting,
+        Waiting(OpId),
+        Sending(u32),
+        Done,
+    }
+
+    #[test]
+    fn bounded_under_pressure() {
+        let net = Net::default();
+        let mut pool = PoolHandler::new(net.handler(), config(3, 100, 16));
+        let mut clients = (0..10).map(|_| Client::Connecting).collect::<Vec<_>>();
+        let mut high_water = 0;
+        while clients.iter().any(|c| !matches!(c, Client::Done)) {
+            for client in &mut clients {
+                *client = match *client {
+                    Client::Connecting => match pool.handle(Effects::Connect("a")).unwrap() {
+                        Outputs::Queued(id) => Client::Waiting(id),
+                        _ => Client::Sending(2),
+                    },
+                    Client::Waiting(id) => match pool.handle(Effects::Wait(id)) {
+                        Ok(_) => Client::Sending(2),
+                        Err(_) => Client::Waiting(id),
+                    },
+                    Client::Sending(0) => {
+                        pool.handle(Effects::Disconnect("a")).unwrap();
+                        Client::Done
+                    },
+                    Client::Sending(n) => {
+                        pool.handle(Effects::Send("a")).unwrap();
+                        Client::Sending(n - 1)
+                    },
+                    Client::Done => Client::Done,
+                };
+                high_water = high_water.max(pool.open());
+            }
+        }
+        assert_eq!(high_water, 3);
+        assert_eq!(net.connects(), 3);
+        assert_eq!(net.count(|e| matches!(e, Effects::Send(_))), 20);
+    }
+
+    #[test]
+    fn expiry() {
+        let clock = TestClock::install();
+        let net = Net::default();
+        let mut pool = PoolHandler::new(net.handler(), config(2, 5, 0));
+        pool.handle(Effects::Connect("a")).unwrap();
+        pool.handle(Effects::Disconnect("a")).unwrap();
+
+        clock.advance(Duration::from(deadline::Rounds(4)));
+        assert_eq!(pool.handle(Effects::Send("b")), Ok(Outputs::Sent));
+        assert_eq!(net.closes(), 0);
+        assert_eq!(pool.idle(), 1);
+
+        // the fifth round after the release closes it
+        clock.advance(Duration::from(deadline::Rounds(1)));
+        assert_eq!(pool.handle(Effects::Send("b")), Ok(Outputs::Sent));
+        assert_eq!(net.closes(), 1);
+        assert_eq!((pool.open(), pool.idle()), (0, 0));
+
+        // the next acquisition opens a new one
+        assert_eq!(
+            pool.handle(Effects::Connect("a")),
+            Ok(Outputs::Connected("a"))
+        );
+        TestClock::uninstall();
+    }
+
+    #[test]
+    fn queue_order() {
+        let net = Net::default();
+        let mut pool = PoolHandler::new(net.handler(), config(1, 100, 3));
+        assert_eq!(
+            pool.handle(Effects::Connect("a")),
+            Ok(Outputs::Connected("a"))
+        );
+        let queued = ["a", "b", "c"]
+            .iter()
+            .map(|addr| match pool.handle(Effects::Connect(addr)) {
+                Ok(Outputs::Queued(id)) => id,
+                other => panic!("{:?}", other),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pool.handle(Effects::Connect("d")),
+            Err(Effects::Connect("d"))
+        );
+        assert_eq!(
+            pool.handle(Effects::Wait(queued[0])),
+            Err(Effects::Wait(queued[0]))
+        );
+        pool.handle(Effects::Disconnect("a")).unwrap();
+
+        // the last queued is asked first, still they complete in the queue order
+        let mut order = Vec::new();
+        for _ in 0..queued.len() {
+            for &id in queued.iter().rev() {
+                match pool.handle(Effects::Wait(id)) {
+                    Ok(Outputs::Connected(addr)) | Ok(Outputs::Reused(addr)) => {
+                        order.push(addr);
+                        pool.handle(Effects::Disconnect(addr)).unwrap();
+                    },
+                    Ok(other) => panic!("{:?}", other),
+                    Err(_) => (),
+                }
+            }
+        }
+        assert_eq!(order, ["a", "b", "c"]);
+        // "a" is handed over rather than opened again
+        assert_eq!(net.connects(), 3);
+    }
+}
@@ -0,0 +1,383 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::VecDeque,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    accounting::{Category, Counter},
+    block::Block,
+    computation::Effect,
+    context::Context,
+    fingerprint::{self, EffectFingerprint},
+    idempotency::Idempotent,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefetchConfig {
+    max_ahead: usize,
+    warm_up: usize,
+    max_pending: Option<usize>,
+}
+
+impl PrefetchConfig {
+    // at most `max_ahead` speculative effects wait for the computation to
+    // perform them
+    pub fn ahead(max_ahead: usize) -> Self {
+        PrefetchConfig {
+            max_ahead,
+            warm_up: 2,
+            max_pending: None,
+        }
+    }
+
+    // the sequential performs seen before the speculation starts
+    pub fn warm_up(self, performs: usize) -> Self {
+        PrefetchConfig {
+            warm_up: performs,
+            ..self
+        }
+    }
+
+    // nothing is speculated while the context holds this many outputs, the
+    // computation does not keep up with the ones it has
+    pub fn max_pending(self, outputs: usize) -> Self {
+        PrefetchConfig {
+            max_pending: Some(outputs),
+            ..self
+        }
+    }
+
+    fn held_back<T>(&self, context: &Context<T>) -> bool {
+        self.max_pending
+            .map_or(false, |limit| context.len() >= limit)
+    }
+}
+
+// an effect yielded ahead of the computation and the outputs put while it
+// was handled, none if the response comes later
+struct Speculated<T> {
+    fingerprint: u64,
+    outputs: Vec<T>,
+}
+
+fn discard<T>(context: &Context<T>, ahead: &mut VecDeque<Speculated<T>>) {
+    for speculated in ahead.drain(..) {
+        context.account(|a| {
+            a.remove(Category::Prefetched);
+            a.bump(Counter::PrefetchDiscarded);
+        });
+        for output in speculated.outputs {
+            context.orphan(output);
+        }
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Once the computation performed `warm_up` effects in a row, each one the
+    // successor of the previous by `next`, yields the successors of the last
+    // one before resuming it, and keeps their outputs until it performs them.
+    // A perform of an effect speculated already is not yielded, the kept
+    // outputs are put, a response still to come arrives as it would. A perform
+    // `next` knows of other than the expected one drops the speculation, the
+    // kept outputs go to the orphan callback. Effects `next` gives nothing for
+    // pass untouched, an effect that is not idempotent is never speculated.
+    pub fn prefetch<F>(
+        self,
+        config: PrefetchConfig,
+        next: F,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        E::Input: EffectFingerprint + Idempotent,
+        F: Fn(&E::Input) -> Option<E::Input>,
+    {
+        let context = self.context();
+        let mut ahead = VecDeque::<Speculated<E>>::new();
+        // the successor of the last effect performed or speculated
+        let mut tail = None::<E::Input>;
+        let mut sequential = 0;
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            move || loop {
+                let effect = match s.resume() {
+                    GeneratorState::Complete(r) => {
+                        discard(&context, &mut ahead);
+                        return r;
+                    },
+                    GeneratorState::Yielded(effect) => effect,
+                };
+                let fingerprint = fingerprint::of(&effect);
+                if ahead.front().map(|front| front.fingerprint) == Some(fingerprint) {
+                    let speculated = ahead.pop_front().expect("checked above");
+                    context.account(|a| {
+                        a.remove(Category::Prefetched);
+                        a.bump(Counter::PrefetchHits);
+                    });
+                    for output in speculated.outputs {
+                        context.put(output);
+                    }
+                    sequential += 1;
+                } else {
+                    let successor = next(&effect);
+                    if successor.is_some() {
+                        let expected = tail.as_ref().map(fingerprint::of);
+                        if ahead.is_empty() && expected == Some(fingerprint) {
+                            sequential += 1;
+                        } else {
+                            discard(&context, &mut ahead);
+                            sequential = 0;
+                        }
+                        tail = successor;
+                    }
+                    yield effect;
+                }
+
+                while sequential >= config.warm_up
+                    && ahead.len() < config.max_ahead
+                    && !config.held_back(&context)
+                {
+                    let candidate = match tail.take() {
+                        Some(candidate) if candidate.is_idempotent() => candidate,
+                        other => {
+                            tail = other;
+                            break;
+                        },
+                    };
+                    tail = next(&candidate);
+                    let fingerprint = fingerprint::of(&candidate);
+                    let before = context.produced();
+                    yield candidate;
+                    let mut outputs = Vec::new();
+                    for _ in before..context.produced() {
+                        outputs.extend(context.take_last());
+                    }
+                    outputs.reverse();
+                    context.account(|a| a.insert(Category::Prefetched));
+                    ahead.push_back(Speculated {
+                        fingerprint,
+                        outputs,
+                    });
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        cell::{Cell, RefCell},
+        hash::{Hash, Hasher},
+        ops::Generator,
+    };
+    use crate::{Category, Context, Counter, Effect, EffectFingerprint, Idempotent, IntoBlock};
+    use super::PrefetchConfig;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Hash)]
+    enum Op {
+        Fetch(u32),
+        Wait,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Out {
+        Item(u32),
+        Scheduled(u32),
+        Waited,
+    }
+
+    impl Effect for Out {
+        type Input = Op;
+    }
+
+    impl Idempotent for Op {}
+
+    impl EffectFingerprint for Op {
+        fn fingerprint<H>(&self, hasher: &mut H)
+        where
+            H: Hasher,
+        {
+            Hash::hash(self, hasher)
+        }
+    }
+
+    fn successor(op: &Op) -> Option<Op> {
+        match op {
+            Op::Fetch(n) => Some(Op::Fetch(n + 1)),
+            Op::Wait => None,
+        }
+    }
+
+    fn fetch_all(
+        context: Context<Out>,
+        items: Vec<u32>,
+    ) -> impl Unpin + Generator<(), Yield = Op, Return = ()> {
+        move || {
+            for n in items {
+                yield Op::Fetch(n);
+                assert_eq!(context.take(), Some(Out::Item(n)));
+            }
+        }
+    }
+
+    // Every handled effect is a round, an item comes three rounds after its
+    // fetch, the consumer waits for it one round at a time.
+    #[test]
+    fn sequential_consumer_stops_waiting() {
+        const DELAY: u64 = 3;
+
+        let round = Rc::new(Cell::new(0));
+        let scheduled = Rc::new(RefCell::new(Vec::<(u64, u32)>::new()));
+        let waits = Rc::new(RefCell::new(Vec::new()));
+        let consumer = {
+            let waits = waits.clone();
+            move |context: Context<Out>| {
+                move || {
+                    for n in 0..8 {
+                        yield Op::Fetch(n);
+                        assert!(context.take_if(|o| *o == Out::Scheduled(n)).is_some());
+                        let mut waited = 0;
+                        while context.take_if(|o| *o == Out::Item(n)).is_none() {
+                            waited += 1;
+                            yield Op::Wait;
+                            assert_eq!(context.take_if(|o| *o == Out::Waited), Some(Out::Waited));
+                        }
+                        waits.borrow_mut().push(waited);
+                    }
+                }
+            }
+        };
+        let (block, accounting) = consumer
+            .into_block()
+            .add_source({
+                let round = round.clone();
+                let scheduled = scheduled.clone();
+                move || {
+                    let mut scheduled = scheduled.borrow_mut();
+                    let due = scheduled.iter().position(|(at, _)| *at <= round.get())?;
+                    Some(Out::Item(scheduled.remove(due).1))
+                }
+            })
+            .prefetch(PrefetchConfig::ahead(3), successor)
+            .with_accounting();
+        block
+            .add_handler(move |op: Op| {
+                round.set(round.get() + 1);
+                match op {
+                    Op::Fetch(n) => {
+                        scheduled.borrow_mut().push((round.get() + DELAY, n));
+                        Ok(Out::Scheduled(n))
+                    },
+                    Op::Wait => Ok(Out::Waited),
+                }
+            })
+            .assert_handled()
+            .run();
+
+        // the full delay during the warm-up, nothing after it
+        assert_eq!(*waits.borrow(), [3, 3, 0, 0, 0, 0, 0, 0]);
+        if cfg!(feature = "diagnostics") {
+            let report = accounting.report();
+            assert_eq!(report.count(Counter::PrefetchHits), 5);
+            // the ones past the last item
+            assert_eq!(report.count(Counter::PrefetchDiscarded), 3);
+        }
+    }
+
+    #[test]
+    fn jump_discards_speculation() {
+        let handled = Rc::new(RefCell::new(Vec::new()));
+        let orphans = Rc::new(RefCell::new(Vec::new()));
+        let block = (|context: Context<Out>| fetch_all(context, vec![0, 1, 2, 3, 10, 11]))
+            .into_block()
+            .prefetch(PrefetchConfig::ahead(2), successor);
+        block.context().on_orphan({
+            let orphans = orphans.clone();
+            move |output| orphans.borrow_mut().push(output)
+        });
+        block
+            .add_handler({
+                let handled = handled.clone();
+                move |op: Op| {
+                    handled.borrow_mut().push(op);
+                    match op {
+                        Op::Fetch(n) => Ok(Out::Item(n)),
+                        Op::Wait => Ok(Out::Waited),
+                    }
+                }
+            })
+            .assert_handled()
+            .run();
+
+        // 3 is served from the speculation, 4 and 5 are dropped at the jump,
+        // the warm-up starts over from 10
+        let fetched = [0, 1, 2, 3, 4, 5, 10, 11];
+        assert_eq!(*handled.borrow(), fetched.map(Op::Fetch));
+        assert_eq!(*orphans.borrow(), [Out::Item(4), Out::Item(5)]);
+    }
+
+    #[test]
+    fn ahead_is_bounded() {
+        let handled = Rc::new(RefCell::new(0));
+        let (block, accounting) = (|context: Context<Out>| fetch_all(context, (0..10).collect()))
+            .into_block()
+            .prefetch(PrefetchConfig::ahead(3).warm_up(1), successor)
+            .with_accounting();
+        block
+            .add_handler({
+                let handled = handled.clone();
+                move |op: Op| {
+                    *handled.borrow_mut() += 1;
+                    match op {
+                        Op::Fetch(n) => Ok(Out::Item(n)),
+                        Op::Wait => Ok(Out::Waited),
+                    }
+                }
+            })
+            .assert_handled()
+            .run();
+
+        // the consumer and the three ahead of it at the end
+        assert_eq!(*handled.borrow(), 13);
+        if cfg!(feature = "diagnostics") {
+            let report = accounting.report();
+            assert_eq!(report.get(Category::Prefetched).high_water, 3);
+            assert_eq!(report.get(Category::Prefetched).live, 0);
+            assert_eq!(report.count(Counter::PrefetchHits), 8);
+        }
+    }
+
+    #[test]
+    fn pending_outputs_hold_back() {
+        let handled = Rc::new(RefCell::new(Vec::new()));
+        let block = (|context: Context<Out>| fetch_all(context, (0..6).collect()))
+            .into_block()
+            .prefetch(PrefetchConfig::ahead(3).max_pending(1), successor);
+        block
+            .add_handler({
+                let handled = handled.clone();
+                move |op: Op| {
+                    handled.borrow_mut().push(op);
+                    match op {
+                        Op::Fetch(n) => Ok(Out::Item(n)),
+                        Op::Wait => Ok(Out::Waited),
+                    }
+                }
+            })
+            .assert_handled()
+            .run();
+
+        // the item of every fetch waits in the context while the layer could
+        // speculate
+        let fetched = [0, 1, 2, 3, 4, 5];
+        assert_eq!(*handled.borrow(), fetched.map(Op::Fetch));
+    }
+}
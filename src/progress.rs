@@ -0,0 +1,566 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::{Cell, RefCell},
+    marker::PhantomData,
+    ops::{Generator, GeneratorState},
+    sync::{Arc, Mutex, PoisonError},
+};
+use super::{
+    block::Block,
+    computation::Select,
+    context::Context,
+    two_phase::{OpId, TwoPhase},
+};
+
+// progress outputs are tagged with the operation like the completion is
+pub trait HasProgress
+where
+    Self: TwoPhase,
+{
+    type Progress;
+
+    fn progress(id: OpId, progress: Self::Progress) -> Self;
+    fn is_progress(&self) -> Option<OpId>;
+}
+
+pub struct Progressing<ProgressPart, ResultPart> {
+    id: OpId,
+    phantom_data: PhantomData<(ProgressPart, ResultPart)>,
+}
+
+impl<ProgressPart, ResultPart> Progressing<ProgressPart, ResultPart> {
+    pub fn new(id: OpId) -> Self {
+        Progressing {
+            id,
+            phantom_data: PhantomData,
+        }
+    }
+
+    pub fn id(&self) -> OpId {
+        self.id
+    }
+
+    pub fn progress<E>(&self, context: &Context<E>) -> Option<ProgressPart>
+    where
+        E: HasProgress + Select<ProgressPart>,
+    {
+        let v = context.take_if(|o| o.is_progress() == Some(self.id))?;
+        context.put_front(v);
+        E::take(context)
+    }
+
+    // progress of this operation that nobody took is orphaned with the result,
+    // otherwise it would stay in front of the queue
+    pub fn result<E>(&self, context: &Context<E>) -> Option<ResultPart>
+    where
+        E: HasProgress + Select<ResultPart>,
+    {
+        let v = context.take_if(|o| o.is_completion() == Some(self.id))?;
+        while let Some(stale) = context.take_if(|o| o.is_progress() == Some(self.id)) {
+            context.orphan(stale);
+        }
+        context.put_front(v);
+        E::take(context)
+    }
+}
+
+pub struct ProgressReporter<E> {
+    id: OpId,
+    context: Context<E>,
+    done: Rc<Cell<bool>>,
+}
+
+impl<E> Clone for ProgressReporter<E> {
+    fn clone(&self) -> Self {
+        ProgressReporter {
+            id: self.id,
+            context: self.context.clone(),
+            done: self.done.clone(),
+        }
+    }
+}
+
+impl<E> ProgressReporter<E>
+where
+    E: HasProgress,
+{
+    pub fn id(&self) -> OpId {
+        self.id
+    }
+
+    // progress reported after `finish` goes to the orphan callback
+    pub fn report(&self, progress: E::Progress) {
+        let output = E::progress(self.id, progress);
+        if self.done.get() {
+            self.context.orphan(output);
+        } else {
+            self.context.put(output);
+        }
+    }
+
+    pub fn finish(&self, output: E) {
+        self.done.set(true);
+        self.context.put(output);
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: HasProgress,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    pub fn add_progress_handler<H>(
+        self,
+        start: H,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        H: FnMut(E::Input, ProgressReporter<E>) -> Result<(), E::Input>,
+    {
+        let context = self.context();
+        let mut start = start;
+        let mut next = 0;
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            move || loop {
+                match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(effect) => {
+                        let reporter = ProgressReporter {
+                            id: OpId(next),
+                            context: context.clone(),
+                            done: Rc::default(),
+                        };
+                        match start(effect, reporter) {
+                            Ok(()) => {
+                                s.put(E::ack(OpId(next)));
+                                next += 1;
+                            },
+                            Err(unhandled) => yield unhandled,
+                        }
+                    },
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+// The milestones of a context, those of a task count for its parent too.
+#[derive(Default)]
+struct Milestones {
+    total: Cell<u64>,
+    reached: Cell<u64>,
+    live_tasks: Cell<usize>,
+    parent: RefCell<Option<Rc<Milestones>>>,
+}
+
+impl Milestones {
+    fn up<F>(self: &Rc<Self>, f: F)
+    where
+        F: Fn(&Milestones),
+    {
+        let mut node = Some(self.clone());
+        while let Some(milestones) = node {
+            f(&milestones);
+            node = milestones.parent.borrow().clone();
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn declare_milestones<T>(context: &Context<T>, total: u64) {
+    let milestones = context.extension(Milestones::default);
+    milestones.up(|m| m.total.set(m.total.get() + total));
+}
+
+#[doc(hidden)]
+pub fn reach_milestone<T>(context: &Context<T>) {
+    let milestones = context.extension(Milestones::default);
+    milestones.up(|m| m.reached.set(m.reached.get() + 1));
+}
+
+pub(crate) fn link_task<T>(parent: &Context<T>, task: &Context<T>) {
+    let parent = parent.extension(Milestones::default);
+    *task.extension(Milestones::default).parent.borrow_mut() = Some(parent);
+}
+
+pub(crate) fn live_tasks<T>(context: &Context<T>, live: usize) {
+    context.extension(Milestones::default).live_tasks.set(live);
+}
+
+pub(crate) fn live<T>(context: &Context<T>) -> usize {
+    context
+        .find_extension::<Milestones>()
+        .map_or(0, |milestones| milestones.live_tasks.get())
+}
+
+// the snapshot of the last `with_progress` layer installed
+#[derive(Default)]
+struct Published(RefCell<Option<ProgressHandle>>);
+
+pub(crate) fn published<T>(context: &Context<T>) -> Option<ProgressSnapshot> {
+    let published = context.find_extension::<Published>()?;
+    let handle = published.0.borrow().clone();
+    handle.as_ref().map(ProgressHandle::snapshot)
+}
+
+// Adds to the milestones the computation is going to reach, a task adds to
+// those of its scheduler as well.
+#[macro_export]
+macro_rules! milestones {
+    ($ctx:expr, total = $n:expr) => {
+        $crate::declare_milestones($ctx, $n)
+    };
+}
+
+#[macro_export]
+macro_rules! milestone {
+    ($ctx:expr) => {
+        $crate::reach_milestone($ctx)
+    };
+}
+
+// How far the computation is, as of the end of the last round.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct ProgressSnapshot {
+    pub rounds: u64,
+    // reached and declared, `None` if nothing is declared
+    pub milestones: Option<(u64, u64)>,
+    // the effect the computation waits on, if it waits
+    pub pending_effects: usize,
+    pub live_tasks: usize,
+    pub unconsumed_outputs: usize,
+    pub complete: bool,
+}
+
+impl ProgressSnapshot {
+    // the part of the declared milestones reached, 1.0 once it completes
+    pub fn fraction(&self) -> Option<f64> {
+        if self.complete {
+            return Some(1.0);
+        }
+        let (reached, total) = self.milestones?;
+        Some((reached as f64 / total as f64).min(1.0))
+    }
+}
+
+// Cloned and sent to another thread the handle reads what the last round left.
+#[derive(Clone, Default)]
+pub struct ProgressHandle(Arc<Mutex<ProgressSnapshot>>);
+
+impl ProgressHandle {
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    G: Unpin + Generator<()>,
+{
+    // the snapshot is replaced whole when a round ends, never while it runs
+    pub fn with_progress(
+        self,
+    ) -> (
+        Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = G::Yield>>,
+        ProgressHandle,
+    ) {
+        let context = self.context();
+        let handle = ProgressHandle::default();
+        let shared = handle.0.clone();
+        *context.extension(Published::default).0.borrow_mut() = Some(handle.clone());
+        let milestones = context.extension(Milestones::default);
+        let mut rounds = 0;
+        let mut s = self;
+        let generator = move || loop {
+            let step = s.resume();
+            rounds += 1;
+            let complete = matches!(step, GeneratorState::Complete(_));
+            let total = milestones.total.get();
+            let snapshot = ProgressSnapshot {
+                rounds,
+                milestones: Some((milestones.reached.get(), total)).filter(|_| total > 0),
+                pending_effects: usize::from(!complete),
+                live_tasks: milestones.live_tasks.get(),
+                unconsumed_outputs: s.context().len(),
+                complete,
+            };
+            *shared.lock().unwrap_or_else(PoisonError::into_inner) = snapshot;
+            match step {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(y) => yield y,
+            }
+        };
+        (Block::new(context, generator), handle)
+    }
+}
+
+#[macro_export]
+macro_rules! perform_with_progress {
+    ($e:expr, $ctx:expr) => {{
+        yield $e;
+        let id = $ctx
+            .take_if(|o| $crate::TwoPhase::is_ack(o).is_some())
+            .and_then(|o| $crate::TwoPhase::is_ack(&o))
+            .expect("effect with progress is not acknowledged");
+        $crate::Progressing::new(id)
+    }};
+}
+
+#[macro_export]
+macro_rules! await_result {
+    ($handle:expr, $ctx:expr, $idle:expr) => {{
+        loop {
+            if let Some(v) = $crate::Progressing::result(&$handle, $ctx) {
+                break v;
+            }
+            yield $idle;
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::GeneratorState, thread};
+    use crate::{
+        Context, Effect, Select, IntoBlock, perform, perform_with_progress, await_result,
+        milestones, milestone,
+    };
+    use super::{OpId, TwoPhase, HasProgress, Progressing};
+
+    #[derive(Debug)]
+    enum Effects {
+        Download(&'static str),
+        Idle,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Accepted(OpId),
+        Progress(OpId, u8),
+        Downloaded(OpId, &'static str),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl TwoPhase for Outputs {
+        fn ack(id: OpId) -> Self {
+            Outputs::Accepted(id)
+        }
+
+        fn is_ack(&self) -> Option<OpId> {
+            match self {
+                Outputs::Accepted(id) => Some(*id),
+                _ => None,
+            }
+        }
+
+        fn is_completion(&self) -> Option<OpId> {
+            match self {
+                Outputs::Downloaded(id, _) => Some(*id),
+                _ => None,
+            }
+        }
+    }
+
+    impl HasProgress for Outputs {
+        type Progress = u8;
+
+        fn progress(id: OpId, progress: u8) -> Self {
+            Outputs::Progress(id, progress)
+        }
+
+        fn is_progress(&self) -> Option<OpId> {
+            match self {
+                Outputs::Progress(id, _) => Some(*id),
+                _ => None,
+            }
+        }
+    }
+
+    struct Percent(u8);
+
+    struct File(&'static str);
+
+    impl Select<Percent> for Outputs {
+        fn take(output: &Context<Self>) -> Option<Percent> {
+            match output.take()? {
+                Outputs::Progress(_, p) => Some(Percent(p)),
+                other => {
+                    output.put_front(other);
+                    None
+                },
+            }
+        }
+    }
+
+    impl Select<File> for Outputs {
+        fn take(output: &Context<Self>) -> Option<File> {
+            match output.take()? {
+                Outputs::Downloaded(_, name) => Some(File(name)),
+                other => {
+                    output.put_front(other);
+                    None
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn interleaved_and_late() {
+        let reporters = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let computation = {
+            let seen = seen.clone();
+            move |context: Context<Outputs>| {
+                move || {
+                    let a: Progressing<Percent, File> =
+                        perform_with_progress!(Effects::Download("a"), &context);
+                    let b: Progressing<Percent, File> =
+                        perform_with_progress!(Effects::Download("b"), &context);
+                    let File(first) = loop {
+                        while let Some(Percent(p)) = a.progress(&context) {
+                            seen.borrow_mut().push(("a", p));
+                        }
+                        while let Some(Percent(p)) = b.progress(&context) {
+                            seen.borrow_mut().push(("b", p));
+                        }
+                        if let Some(file) = a.result(&context) {
+                            break file;
+                        }
+                        yield Effects::Idle;
+                    };
+                    let File(second) = await_result!(b, &context, Effects::Idle);
+                    (first, second)
+                }
+            }
+        };
+        let mut block = computation.into_block().add_progress_handler({
+            let reporters = reporters.clone();
+            move |effect, reporter| match effect {
+                Effects::Download(name) => {
+                    reporters.borrow_mut().push((name, reporter));
+                    Ok(())
+                },
+                e => Err(e),
+            }
+        });
+        let orphans = Rc::new(RefCell::new(Vec::new()));
+        block.context().on_orphan({
+            let orphans = orphans.clone();
+            move |o| orphans.borrow_mut().push(o)
+        });
+        // `None` finishes the download
+        let mut steps = vec![
+            (1, Some(50)),
+            (0, Some(30)),
+            (0, Some(60)),
+            (0, None),
+            (0, Some(99)),
+            (1, None),
+        ]
+        .into_iter();
+        let result = loop {
+            match block.resume() {
+                GeneratorState::Yielded(Effects::Idle) => {
+                    let (index, progress) = steps.next().expect("script is long enough");
+                    let reporters = reporters.borrow();
+                    let (name, reporter) = &reporters[index];
+                    match progress {
+                        Some(p) => reporter.report(p),
+                        None => reporter.finish(Outputs::Downloaded(reporter.id(), name)),
+                    }
+                },
+                GeneratorState::Yielded(e) => panic!("unhandled: {:?}", e),
+                GeneratorState::Complete(result) => break result,
+            }
+        };
+        assert_eq!(result, ("a", "b"));
+        assert_eq!(*seen.borrow(), [("b", 50), ("a", 30), ("a", 60)]);
+        assert_eq!(*orphans.borrow(), [Outputs::Progress(OpId(0), 99)]);
+    }
+
+    #[test]
+    fn ten_milestones() {
+        let (block, handle) = (|context: Context<Outputs>| {
+            move || {
+                milestones!(&context, total = 10);
+                for _ in 0..10 {
+                    milestone!(&context);
+                    perform!(Effects::Idle);
+                    context.take();
+                }
+            }
+        })
+        .into_block()
+        .with_progress();
+        let fractions = Rc::new(RefCell::new(Vec::new()));
+        block
+            .add_handler({
+                let fractions = fractions.clone();
+                let handle = handle.clone();
+                move |_| {
+                    fractions.borrow_mut().extend(handle.snapshot().fraction());
+                    Ok(Outputs::Accepted(OpId(0)))
+                }
+            })
+            .assert_handled()
+            .run();
+        let fractions = fractions.borrow();
+        assert_eq!(fractions.len(), 10);
+        assert!(fractions.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(fractions[9], 1.0);
+        let last = handle.snapshot();
+        assert!(last.complete);
+        assert_eq!(last.fraction(), Some(1.0));
+        assert_eq!(last.milestones, Some((10, 10)));
+        assert_eq!(last.pending_effects, 0);
+    }
+
+    #[test]
+    fn never_torn() {
+        const ROUNDS: u64 = 2000;
+        let (block, handle) = (|context: Context<Outputs>| {
+            move || {
+                milestones!(&context, total = 2 * ROUNDS);
+                for _ in 0..ROUNDS {
+                    // a reader in the middle would see an odd count
+                    milestone!(&context);
+                    thread::yield_now();
+                    milestone!(&context);
+                    perform!(Effects::Idle);
+                    context.take();
+                }
+            }
+        })
+        .into_block()
+        .with_progress();
+        let reader = thread::spawn(move || loop {
+            let snapshot = handle.snapshot();
+            if snapshot.complete {
+                break;
+            }
+            if let Some((reached, total)) = snapshot.milestones {
+                assert_eq!(reached, 2 * snapshot.rounds);
+                assert_eq!(total, 2 * ROUNDS);
+                assert_eq!(snapshot.pending_effects, 1);
+            }
+        });
+        block
+            .add_handler(|_| Ok(Outputs::Accepted(OpId(0))))
+            .assert_handled()
+            .run();
+        reader.join().unwrap();
+    }
+}
@@ -0,0 +1,605 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::BTreeMap,
+    ops::{Generator, GeneratorState},
+    time::{Duration, Instant},
+    error::Error,
+    fmt,
+};
+use super::{
+    accounting::{Accounting, Counter},
+    block::Block,
+    computation::{Effect, EffectName, Handler, Select, TakeResult},
+    context::Context,
+    deadline::{self, Rounds},
+    identity::Identity,
+    meta::WithMeta,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ResourceKind(pub &'static str);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ReservationId(pub u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaDenied {
+    pub kind: ResourceKind,
+    pub requested: u64,
+    pub remaining: u64,
+}
+
+impl fmt::Display for QuotaDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} requested, {} remains",
+            self.requested, self.kind.0, self.remaining,
+        )
+    }
+}
+
+impl Error for QuotaDenied {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuotaEffect {
+    Query(ResourceKind),
+    Reserve(ResourceKind, u64),
+    // closes the reservation, the unused amount goes back to the budget
+    Release(ReservationId, u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuotaOutput {
+    Remaining(u64),
+    Reserved(ReservationId),
+    Denied(QuotaDenied),
+    Released,
+}
+
+impl Effect for QuotaOutput {
+    type Input = QuotaEffect;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Remaining(pub u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reserved(pub ReservationId);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Released;
+
+impl Select<Remaining> for QuotaOutput {
+    fn try_take(output: &Context<Self>) -> TakeResult<Remaining, Self> {
+        match output.take() {
+            None => TakeResult::Empty,
+            Some(QuotaOutput::Remaining(amount)) => TakeResult::Matched(Remaining(amount)),
+            Some(other) => TakeResult::Mismatched(other),
+        }
+    }
+}
+
+impl Select<Reserved> for QuotaOutput {
+    fn try_take(output: &Context<Self>) -> TakeResult<Reserved, Self> {
+        match output.take() {
+            None => TakeResult::Empty,
+            Some(QuotaOutput::Reserved(id)) => TakeResult::Matched(Reserved(id)),
+            Some(other) => TakeResult::Mismatched(other),
+        }
+    }
+}
+
+impl Select<QuotaDenied> for QuotaOutput {
+    fn try_take(output: &Context<Self>) -> TakeResult<QuotaDenied, Self> {
+        match output.take() {
+            None => TakeResult::Empty,
+            Some(QuotaOutput::Denied(denied)) => TakeResult::Matched(denied),
+            Some(other) => TakeResult::Mismatched(other),
+        }
+    }
+}
+
+impl Select<Released> for QuotaOutput {
+    fn try_take(output: &Context<Self>) -> TakeResult<Released, Self> {
+        match output.take() {
+            None => TakeResult::Empty,
+            Some(QuotaOutput::Released) => TakeResult::Matched(Released),
+            Some(other) => TakeResult::Mismatched(other),
+        }
+    }
+}
+
+// the effects of the quota family in an effect enum, and who pays for them
+pub trait QuotaRequest
+where
+    Self: Sized,
+{
+    fn into_quota(self) -> Result<QuotaEffect, Self>;
+
+    // `None` pays from the budgets shared by the effects without an identity
+    fn payer(&self) -> Option<Identity> {
+        None
+    }
+}
+
+impl QuotaRequest for QuotaEffect {
+    fn into_quota(self) -> Result<QuotaEffect, Self> {
+        Ok(self)
+    }
+}
+
+impl<I> QuotaRequest for WithMeta<I>
+where
+    I: QuotaRequest,
+{
+    fn into_quota(self) -> Result<QuotaEffect, Self> {
+        let WithMeta { effect, meta } = self;
+        effect
+            .into_quota()
+            .map_err(|effect| WithMeta { effect, meta })
+    }
+
+    fn payer(&self) -> Option<Identity> {
+        self.meta.identity().cloned()
+    }
+}
+
+type Account = (Option<Identity>, ResourceKind);
+
+struct Reservation {
+    account: Account,
+    amount: u64,
+    used: bool,
+    since: Instant,
+}
+
+#[derive(Default)]
+struct QuotaState {
+    budgets: BTreeMap<ResourceKind, u64>,
+    budgets_for: BTreeMap<(Identity, ResourceKind), u64>,
+    expire_after: Option<Rounds>,
+    remaining: BTreeMap<Account, u64>,
+    reservations: BTreeMap<ReservationId, Reservation>,
+    next: u64,
+    accounting: Option<Accounting>,
+}
+
+#[cfg(feature = "diagnostics")]
+fn count(accounting: &Accounting, counter: Counter) {
+    accounting.bump(counter);
+}
+
+#[cfg(not(feature = "diagnostics"))]
+#[inline(always)]
+fn count(accounting: &Accounting, counter: Counter) {
+    let _ = (accounting, counter);
+}
+
+impl QuotaState {
+    // `None` for a kind without a budget, it is not bounded
+    fn remaining(&mut self, account: &Account) -> Option<&mut u64> {
+        let (payer, kind) = account;
+        let budget = payer
+            .as_ref()
+            .and_then(|payer| self.budgets_for.get(&(payer.clone(), *kind)))
+            .or_else(|| self.budgets.get(kind))
+            .cloned()?;
+        Some(self.remaining.entry(account.clone()).or_insert(budget))
+    }
+
+    // the reservations never used give their amount back
+    fn expire(&mut self) {
+        let limit = match self.expire_after {
+            Some(rounds) => Duration::from(rounds),
+            None => return,
+        };
+        let now = deadline::now();
+        let expired = self
+            .reservations
+            .iter()
+            .filter(|(_, r)| !r.used && now.saturating_duration_since(r.since) >= limit)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            let reservation = self.reservations.remove(&id).expect("found above");
+            if let Some(remaining) = self.remaining(&reservation.account) {
+                *remaining += reservation.amount;
+            }
+            if let Some(accounting) = &self.accounting {
+                count(accounting, Counter::QuotaExpired);
+            }
+        }
+    }
+
+    fn handle(&mut self, payer: Option<Identity>, effect: QuotaEffect) -> QuotaOutput {
+        self.expire();
+        match effect {
+            QuotaEffect::Query(kind) => {
+                let remaining = self.remaining(&(payer, kind)).map_or(u64::MAX, |r| *r);
+                QuotaOutput::Remaining(remaining)
+            },
+            QuotaEffect::Reserve(kind, amount) => {
+                let account = (payer, kind);
+                if let Err(denied) = self.take(&account, amount) {
+                    return QuotaOutput::Denied(denied);
+                }
+                let id = ReservationId(self.next);
+                self.next += 1;
+                let reservation = Reservation {
+                    account,
+                    amount,
+                    used: false,
+                    since: deadline::now(),
+                };
+                self.reservations.insert(id, reservation);
+                QuotaOutput::Reserved(id)
+            },
+            // an expired or released reservation is released already
+            QuotaEffect::Release(id, unused) => {
+                if let Some(reservation) = self.reservations.remove(&id) {
+                    if let Some(remaining) = self.remaining(&reservation.account) {
+                        *remaining += unused.min(reservation.amount);
+                    }
+                }
+                QuotaOutput::Released
+            },
+        }
+    }
+
+    fn take(&mut self, account: &Account, amount: u64) -> Result<(), QuotaDenied> {
+        match self.remaining(account) {
+            None => Ok(()),
+            Some(remaining) if *remaining >= amount => {
+                *remaining -= amount;
+                Ok(())
+            },
+            Some(remaining) => Err(QuotaDenied {
+                kind: account.1,
+                requested: amount,
+                remaining: *remaining,
+            }),
+        }
+    }
+
+    // from the reservations of the payer first, the oldest first, then from
+    // the budget, all of it or nothing
+    fn charge(&mut self, account: Account, cost: u64) -> Result<(), QuotaDenied> {
+        self.expire();
+        let reserved = self
+            .reservations
+            .values()
+            .filter(|r| r.account == account)
+            .map(|r| r.amount)
+            .sum::<u64>();
+        let from_budget = cost.saturating_sub(reserved);
+        self.take(&account, from_budget)?;
+        let mut left = cost - from_budget;
+        for reservation in self.reservations.values_mut() {
+            if left == 0 {
+                break;
+            }
+            if reservation.account == account {
+                let taken = left.min(reservation.amount);
+                reservation.amount -= taken;
+                reservation.used = true;
+                left -= taken;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Fixed budgets for each resource kind, every identity has its own and the
+// effects without one share theirs. The clones share the budgets, one goes
+// to `add_handler` and one to `enforce_quota`. Reservations never used expire
+// on the rounds of the time source, see `expire_after`.
+#[derive(Clone, Default)]
+pub struct FixedQuotaHandler(Rc<RefCell<QuotaState>>);
+
+impl FixedQuotaHandler {
+    pub fn new() -> Self {
+        FixedQuotaHandler::default()
+    }
+
+    pub fn budget(self, kind: ResourceKind, amount: u64) -> Self {
+        self.0.borrow_mut().budgets.insert(kind, amount);
+        self
+    }
+
+    // instead of the one of `budget` for this identity
+    pub fn budget_for(self, identity: Identity, kind: ResourceKind, amount: u64) -> Self {
+        self.0
+            .borrow_mut()
+            .budgets_for
+            .insert((identity, kind), amount);
+        self
+    }
+
+    pub fn expire_after(self, rounds: Rounds) -> Self {
+        self.0.borrow_mut().expire_after = Some(rounds);
+        self
+    }
+
+    // bumps `Counter::QuotaExpired`
+    pub fn report_to(self, accounting: &Accounting) -> Self {
+        self.0.borrow_mut().accounting = Some(accounting.clone());
+        self
+    }
+
+    // what the payer has left of the kind, `None` when it is not bounded
+    pub fn remaining(&self, payer: Option<Identity>, kind: ResourceKind) -> Option<u64> {
+        let mut state = self.0.borrow_mut();
+        state.expire();
+        state.remaining(&(payer, kind)).map(|r| *r)
+    }
+
+    pub fn reservations(&self) -> usize {
+        self.0.borrow().reservations.len()
+    }
+}
+
+impl<E> Handler<E> for FixedQuotaHandler
+where
+    E: Effect + From<QuotaOutput>,
+    E::Input: QuotaRequest,
+{
+    fn handle(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        let payer = effect.payer();
+        let effect = effect.into_quota()?;
+        Ok(E::from(self.0.borrow_mut().handle(payer, effect)))
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + From<QuotaOutput>,
+    E::Input: EffectName + QuotaRequest,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Charges the effects of the named variants to the budgets of `quota`,
+    // from the reservations of the payer first. An effect over the budget is
+    // answered with `QuotaOutput::Denied` and never reaches the handlers.
+    pub fn enforce_quota(
+        self,
+        quota: &FixedQuotaHandler,
+        charges: &[(&'static str, ResourceKind, u64)],
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>> {
+        let quota = quota.clone();
+        let charges = charges
+            .iter()
+            .map(|(name, kind, cost)| (*name, (*kind, *cost)))
+            .collect::<BTreeMap<_, _>>();
+        let context = self.context();
+        let mut s = self;
+        let generator = move || loop {
+            let effect = match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => effect,
+            };
+            let charged = match charges.get(effect.effect_name()) {
+                Some((kind, cost)) => {
+                    let account = (effect.payer(), *kind);
+                    quota.0.borrow_mut().charge(account, *cost)
+                },
+                None => Ok(()),
+            };
+            match charged {
+                Ok(()) => yield effect,
+                Err(denied) => s.put(E::from(QuotaOutput::Denied(denied))),
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::Cell, ops::Generator};
+    use crate::{
+        Accounting, Context, Counter, Effect, EffectName, Identity, IntoBlock, WithMeta,
+        deadline::{RoundCounter, Rounds},
+    };
+    use super::{
+        FixedQuotaHandler, QuotaDenied, QuotaEffect, QuotaOutput, QuotaRequest, ReservationId,
+        ResourceKind,
+    };
+
+    const API: ResourceKind = ResourceKind("api");
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Quota(QuotaEffect),
+        Call,
+    }
+
+    impl EffectName for Effects {
+        fn effect_name(&self) -> &'static str {
+            match self {
+                Effects::Quota(_) => "Quota",
+                Effects::Call => "Call",
+            }
+        }
+    }
+
+    impl QuotaRequest for Effects {
+        fn into_quota(self) -> Result<QuotaEffect, Self> {
+            match self {
+                Effects::Quota(effect) => Ok(effect),
+                other => Err(other),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Outputs {
+        Quota(QuotaOutput),
+        Called,
+    }
+
+    impl Effect for Outputs {
+        type Input = WithMeta<Effects>;
+    }
+
+    impl From<QuotaOutput> for Outputs {
+        fn from(output: QuotaOutput) -> Self {
+            Outputs::Quota(output)
+        }
+    }
+
+    fn perform(
+        context: Context<Outputs>,
+        effects: Vec<Effects>,
+    ) -> impl Unpin + Generator<(), Yield = WithMeta<Effects>, Return = Vec<Outputs>> {
+        move || {
+            let mut outputs = Vec::new();
+            for effect in effects {
+                yield WithMeta::new(effect);
+                outputs.extend(context.take());
+            }
+            outputs
+        }
+    }
+
+    fn run(
+        quota: &FixedQuotaHandler,
+        identity: Option<Identity>,
+        effects: Vec<Effects>,
+    ) -> (Vec<Outputs>, u32) {
+        let calls = Rc::new(Cell::new(0));
+        let computation = move |context: Context<Outputs>| perform(context, effects);
+        let block = match identity {
+            Some(identity) => computation.into_block_as(identity),
+            None => computation.into_block(),
+        };
+        let outputs = block
+            .stamp_meta()
+            .enforce_quota(quota, &[("Call", API, 1)])
+            .add_handler(quota.clone())
+            .add_handler({
+                let calls = calls.clone();
+                move |_: WithMeta<Effects>| {
+                    calls.set(calls.get() + 1);
+                    Ok(Outputs::Called)
+                }
+            })
+            .assert_handled()
+            .run();
+        (outputs, calls.get())
+    }
+
+    fn quota(output: QuotaOutput) -> Outputs {
+        Outputs::Quota(output)
+    }
+
+    #[test]
+    fn reserve_consume_release() {
+        let handler = FixedQuotaHandler::new().budget(API, 10);
+        let effects = vec![
+            Effects::Quota(QuotaEffect::Query(API)),
+            Effects::Quota(QuotaEffect::Reserve(API, 4)),
+            Effects::Quota(QuotaEffect::Query(API)),
+            Effects::Quota(QuotaEffect::Reserve(API, 7)),
+            // the calls are paid from the reservation
+            Effects::Call,
+            Effects::Call,
+            Effects::Call,
+            Effects::Quota(QuotaEffect::Query(API)),
+            Effects::Quota(QuotaEffect::Release(ReservationId(0), 1)),
+            Effects::Quota(QuotaEffect::Query(API)),
+        ];
+        let (outputs, calls) = run(&handler, None, effects);
+        assert_eq!(
+            outputs,
+            [
+                quota(QuotaOutput::Remaining(10)),
+                quota(QuotaOutput::Reserved(ReservationId(0))),
+                quota(QuotaOutput::Remaining(6)),
+                quota(QuotaOutput::Denied(QuotaDenied {
+                    kind: API,
+                    requested: 7,
+                    remaining: 6,
+                })),
+                Outputs::Called,
+                Outputs::Called,
+                Outputs::Called,
+                quota(QuotaOutput::Remaining(6)),
+                quota(QuotaOutput::Released),
+                quota(QuotaOutput::Remaining(7)),
+            ],
+        );
+        assert_eq!(calls, 3);
+        assert_eq!(handler.reservations(), 0);
+    }
+
+    #[test]
+    fn enforcement_denies() {
+        let handler = FixedQuotaHandler::new().budget(API, 3);
+        let (outputs, calls) = run(&handler, None, vec![Effects::Call; 4]);
+        let denied = QuotaDenied {
+            kind: API,
+            requested: 1,
+            remaining: 0,
+        };
+        assert_eq!(
+            outputs[..3],
+            [Outputs::Called, Outputs::Called, Outputs::Called]
+        );
+        assert_eq!(outputs[3], quota(QuotaOutput::Denied(denied)));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn identities_isolated() {
+        let a = Identity::new("tenant-a");
+        let b = Identity::new("tenant-b");
+        let handler = FixedQuotaHandler::new()
+            .budget(API, 2)
+            .budget_for(b.clone(), API, 5);
+        let (_, calls) = run(&handler, Some(a.clone()), vec![Effects::Call; 3]);
+        assert_eq!(calls, 2);
+        let query = vec![Effects::Quota(QuotaEffect::Query(API))];
+        let (outputs, _) = run(&handler, Some(b.clone()), query.clone());
+        assert_eq!(outputs, [quota(QuotaOutput::Remaining(5))]);
+        let (outputs, _) = run(&handler, None, query);
+        assert_eq!(outputs, [quota(QuotaOutput::Remaining(2))]);
+        assert_eq!(handler.remaining(Some(a), API), Some(0));
+        assert_eq!(handler.remaining(Some(b), API), Some(5));
+        assert_eq!(handler.remaining(None, ResourceKind("disk")), None);
+    }
+
+    #[test]
+    fn reservations_expire() {
+        let _clock = RoundCounter::install();
+        let accounting = Accounting::default();
+        let handler = FixedQuotaHandler::new()
+            .budget(API, 10)
+            .expire_after(Rounds(3))
+            .report_to(&accounting);
+        let mut effects = vec![Effects::Quota(QuotaEffect::Reserve(API, 5))];
+        effects.extend(vec![Effects::Quota(QuotaEffect::Query(API)); 3]);
+        let outputs = (move |context: Context<Outputs>| perform(context, effects))
+            .into_block()
+            .count_rounds()
+            .add_handler(handler.clone())
+            .assert_handled()
+            .run();
+        // reserved in round 0, never used and back in round 3
+        assert_eq!(
+            outputs,
+            [
+                quota(QuotaOutput::Reserved(ReservationId(0))),
+                quota(QuotaOutput::Remaining(5)),
+                quota(QuotaOutput::Remaining(5)),
+                quota(QuotaOutput::Remaining(10)),
+            ],
+        );
+        assert_eq!(handler.reservations(), 0);
+        let expired = if cfg!(feature = "diagnostics") { 1 } else { 0 };
+        assert_eq!(accounting.report().count(Counter::QuotaExpired), expired);
+    }
+}
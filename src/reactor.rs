@@ -0,0 +1,292 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    fmt,
+    error::Error,
+    ops::{Generator, GeneratorState},
+};
+use super::{block::Block, computation::Effect, latency::Custody};
+
+const DEFAULT_BUDGET: usize = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReactorError<T> {
+    // `poll_effects` was called from inside its own sink
+    Reentrant,
+    // the computation completed, the event is given back
+    Finished(T),
+}
+
+impl<T> fmt::Display for ReactorError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReactorError::Reentrant => write!(f, "effects are polled from inside the sink"),
+            ReactorError::Finished(_) => write!(f, "the computation is finished"),
+        }
+    }
+}
+
+impl<T> Error for ReactorError<T> where T: fmt::Debug {}
+
+// the effects waiting for the host, in custody of the reactor
+struct Pending<I>(RefCell<VecDeque<I>>);
+
+impl<I> Custody for Pending<I> {
+    fn name(&self) -> &'static str {
+        "reactor"
+    }
+
+    fn held(&self) -> usize {
+        self.0.borrow().len()
+    }
+}
+
+struct Inner<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    block: RefCell<Block<E, G>>,
+    pending: Rc<Pending<E::Input>>,
+    result: RefCell<Option<G::Return>>,
+    budget: usize,
+    started: Cell<bool>,
+    finished: Cell<bool>,
+    busy: Cell<bool>,
+}
+
+// Drives a block from inside a host event loop. The computation is resumed
+// only while it has outputs to take, at most `budget` times per call, and
+// the effects it performs wait in a queue until the host answers them.
+pub struct Reactor<E, G>(Rc<Inner<E, G>>)
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>;
+
+impl<E, G> Clone for Reactor<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    fn clone(&self) -> Self {
+        Reactor(self.0.clone())
+    }
+}
+
+impl<E, G> Reactor<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    pub fn new(block: Block<E, G>) -> Self
+    where
+        E::Input: 'static,
+    {
+        Self::with_budget(block, DEFAULT_BUDGET)
+    }
+
+    pub fn with_budget(block: Block<E, G>, budget: usize) -> Self
+    where
+        E::Input: 'static,
+    {
+        let pending = Rc::new(Pending(RefCell::new(VecDeque::new())));
+        block.context().add_custody(&pending);
+        let reactor = Reactor(Rc::new(Inner {
+            block: RefCell::new(block),
+            pending,
+            result: RefCell::new(None),
+            budget,
+            started: Cell::new(false),
+            finished: Cell::new(false),
+            busy: Cell::new(false),
+        }));
+        reactor.drive();
+        reactor
+    }
+
+    // From inside the sink of `poll_effects` the event is only queued, it
+    // is driven when `poll_effects` returns.
+    pub fn on_event(&self, output: E) -> Result<(), ReactorError<E>> {
+        if self.is_finished() {
+            return Err(ReactorError::Finished(output));
+        }
+        self.0.block.borrow().put(output);
+        if !self.0.busy.get() {
+            self.drive();
+        }
+        Ok(())
+    }
+
+    // Offers every queued effect to the sink once. Like a handler the sink
+    // gives back an effect it declines, it stays queued for the next call.
+    // `Ok(None)` takes the effect, its response comes later with `on_event`.
+    // Effects performed after the responses are taken wait for the next call.
+    pub fn poll_effects<S>(&self, sink: S) -> Result<(), ReactorError<E>>
+    where
+        S: FnMut(E::Input) -> Result<Option<E>, E::Input>,
+    {
+        if self.0.busy.replace(true) {
+            return Err(ReactorError::Reentrant);
+        }
+        let mut sink = sink;
+        let effects = self.0.pending.0.replace(VecDeque::new());
+        let mut declined = VecDeque::new();
+        for effect in effects {
+            // the sink may queue events, so the block is not borrowed over it
+            match sink(effect) {
+                Ok(Some(output)) => self.0.block.borrow().put(output),
+                Ok(None) => (),
+                Err(effect) => declined.push_back(effect),
+            }
+        }
+        *self.0.pending.0.borrow_mut() = declined;
+        self.0.busy.set(false);
+        self.drive();
+        Ok(())
+    }
+
+    pub fn pending(&self) -> usize {
+        self.0.pending.0.borrow().len()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.0.finished.get()
+    }
+
+    pub fn take_result(&self) -> Option<G::Return> {
+        self.0.result.borrow_mut().take()
+    }
+
+    fn drive(&self) {
+        if self.is_finished() || self.0.busy.replace(true) {
+            return;
+        }
+        let mut block = self.0.block.borrow_mut();
+        for _ in 0..self.0.budget {
+            if self.0.started.get() && block.context().is_empty() {
+                break;
+            }
+            self.0.started.set(true);
+            match block.resume() {
+                GeneratorState::Yielded(effect) => self.0.pending.0.borrow_mut().push_back(effect),
+                GeneratorState::Complete(r) => {
+                    *self.0.result.borrow_mut() = Some(r);
+                    self.0.finished.set(true);
+                    break;
+                },
+            }
+        }
+        self.0.busy.set(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{Context, Effect, IntoBlock};
+    use super::{Reactor, ReactorError};
+
+    #[derive(Debug, PartialEq)]
+    enum Effects {
+        NextEvent,
+        Draw(usize),
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Key(char),
+        Drawn(usize),
+        Quit,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    #[derive(Default)]
+    struct Seen {
+        keys: Vec<char>,
+        drawn: Vec<usize>,
+    }
+
+    fn editor(
+        context: Context<Outputs>,
+        seen: Rc<RefCell<Seen>>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = usize> {
+        move || loop {
+            let next = context.take();
+            let mut seen = seen.borrow_mut();
+            match next {
+                Some(Outputs::Key(key)) => {
+                    seen.keys.push(key);
+                    let frame = seen.keys.len();
+                    drop(seen);
+                    yield Effects::Draw(frame);
+                },
+                Some(Outputs::Drawn(frame)) => seen.drawn.push(frame),
+                Some(Outputs::Quit) => return seen.keys.len(),
+                None => {
+                    drop(seen);
+                    yield Effects::NextEvent;
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn interleaved_exactly_once() {
+        let seen = Rc::new(RefCell::new(Seen::default()));
+        let reactor = Reactor::new((|context| editor(context, seen.clone())).into_block());
+        let keys = "effects".chars().collect::<Vec<_>>();
+        let mut polls = 0;
+        // every other poll the host is busy and declines drawing
+        let mut sink = |effect| {
+            polls += 1;
+            match effect {
+                Effects::NextEvent => Ok(None),
+                Effects::Draw(frame) if polls % 2 == 0 => Ok(Some(Outputs::Drawn(frame))),
+                effect => Err(effect),
+            }
+        };
+        for key in &keys {
+            reactor.on_event(Outputs::Key(*key)).unwrap();
+            reactor.poll_effects(&mut sink).unwrap();
+        }
+        while reactor.pending() > 0 {
+            reactor.poll_effects(&mut sink).unwrap();
+        }
+        reactor.on_event(Outputs::Quit).unwrap();
+        assert_eq!(reactor.take_result(), Some(keys.len()));
+        assert_eq!(
+            reactor.on_event(Outputs::Quit),
+            Err(ReactorError::Finished(Outputs::Quit)),
+        );
+
+        let mut drawn = seen.borrow().drawn.clone();
+        drawn.sort_unstable();
+        assert_eq!(seen.borrow().keys, keys);
+        assert_eq!(drawn, (1..=keys.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reentrant() {
+        let seen = Rc::new(RefCell::new(Seen::default()));
+        let reactor = Reactor::new((|context| editor(context, seen.clone())).into_block());
+        reactor
+            .poll_effects(|effect| {
+                assert_eq!(effect, Effects::NextEvent);
+                assert_eq!(reactor.poll_effects(Err), Err(ReactorError::Reentrant));
+                reactor.on_event(Outputs::Key('x')).unwrap();
+                // queued, the computation is resumed after the sink
+                assert!(seen.borrow().keys.is_empty());
+                Ok(None)
+            })
+            .unwrap();
+        assert_eq!(seen.borrow().keys, ['x']);
+        assert_eq!(reactor.pending(), 1);
+    }
+}
@@ -0,0 +1,400 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+use super::{
+    blocking::{
+        BlockingAdapter, BlockingWait, BlockingWorker, HasBlockingOutcome, WorkerFailure,
+        blocking_adapter,
+    },
+    computation::{Effect, Handler, Select, TakeResult},
+    context::Context,
+    deadline,
+    meta::HasPriority,
+    sim::VirtualNet,
+    two_phase::{OpId, TwoPhase},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolveEffect {
+    Lookup(String, u16),
+    // forgets what is cached for the name, on every port
+    Flush(String),
+    // waits for a lookup the resolver thread has queued
+    Wait(OpId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolveErrorKind {
+    // the name has no address
+    NotFound,
+    InvalidName,
+    Io(io::ErrorKind),
+    // the resolver thread is gone
+    Unavailable,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolveOutput {
+    Resolved(Vec<SocketAddr>),
+    ResolveFailed(ResolveErrorKind),
+    Flushed,
+    // the lookup is queued on the resolver thread, wait for it
+    Queued(OpId),
+    // the queue of the resolver thread is full, yield the lookup again later
+    Busy(ResolveEffect),
+    Done(OpId, Result<Vec<SocketAddr>, ResolveErrorKind>),
+}
+
+impl Effect for ResolveOutput {
+    type Input = ResolveEffect;
+}
+
+// the answer of a lookup, at once or after the wait
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolution(pub Result<Vec<SocketAddr>, ResolveErrorKind>);
+
+impl Select<Resolution> for ResolveOutput {
+    fn try_take(output: &Context<Self>) -> TakeResult<Resolution, Self> {
+        match output.take() {
+            None => TakeResult::Empty,
+            Some(ResolveOutput::Resolved(addrs)) => TakeResult::Matched(Resolution(Ok(addrs))),
+            Some(ResolveOutput::ResolveFailed(kind)) => TakeResult::Matched(Resolution(Err(kind))),
+            Some(ResolveOutput::Done(_, result)) => TakeResult::Matched(Resolution(result)),
+            Some(other) => TakeResult::Mismatched(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Queued(pub OpId);
+
+impl Select<Queued> for ResolveOutput {
+    fn try_take(output: &Context<Self>) -> TakeResult<Queued, Self> {
+        match output.take() {
+            None => TakeResult::Empty,
+            Some(ResolveOutput::Queued(id)) => TakeResult::Matched(Queued(id)),
+            Some(other) => TakeResult::Mismatched(other),
+        }
+    }
+}
+
+impl TwoPhase for ResolveOutput {
+    fn ack(id: OpId) -> Self {
+        ResolveOutput::Queued(id)
+    }
+
+    fn is_ack(&self) -> Option<OpId> {
+        match self {
+            ResolveOutput::Queued(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    fn is_completion(&self) -> Option<OpId> {
+        match self {
+            ResolveOutput::Done(id, _) => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+impl HasBlockingOutcome for ResolveOutput {
+    fn completed(id: OpId, outcome: Result<Self, WorkerFailure<ResolveEffect>>) -> Self {
+        let result = match outcome {
+            Ok(ResolveOutput::Resolved(addrs)) => Ok(addrs),
+            Ok(ResolveOutput::ResolveFailed(kind)) => Err(kind),
+            // declined, or the worker panicked
+            _ => Err(ResolveErrorKind::Unavailable),
+        };
+        ResolveOutput::Done(id, result)
+    }
+
+    fn queue_full(effect: ResolveEffect) -> Self {
+        ResolveOutput::Busy(effect)
+    }
+}
+
+impl BlockingWait for ResolveEffect {
+    fn waits_for(&self) -> Option<OpId> {
+        match self {
+            ResolveEffect::Wait(id) => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+impl HasPriority for ResolveEffect {}
+
+fn failure(error: io::Error) -> ResolveErrorKind {
+    match error.kind() {
+        io::ErrorKind::InvalidInput => ResolveErrorKind::InvalidName,
+        kind => ResolveErrorKind::Io(kind),
+    }
+}
+
+// Resolves with `ToSocketAddrs`, it blocks, so it runs behind `std_resolver`.
+#[derive(Default)]
+pub struct StdResolverHandler;
+
+impl Handler<ResolveOutput> for StdResolverHandler {
+    fn handle(&mut self, effect: ResolveEffect) -> Result<ResolveOutput, ResolveEffect> {
+        match effect {
+            ResolveEffect::Lookup(name, port) => {
+                let output = match (name.as_str(), port).to_socket_addrs() {
+                    Ok(addrs) => {
+                        let addrs = addrs.collect::<Vec<_>>();
+                        if addrs.is_empty() {
+                            ResolveOutput::ResolveFailed(ResolveErrorKind::NotFound)
+                        } else {
+                            ResolveOutput::Resolved(addrs)
+                        }
+                    },
+                    Err(error) => ResolveOutput::ResolveFailed(failure(error)),
+                };
+                Ok(output)
+            },
+            ResolveEffect::Flush(_) => Ok(ResolveOutput::Flushed),
+            other => Err(other),
+        }
+    }
+}
+
+// the std resolver on a thread of its own, so a slow lookup does not stall
+// the thread driving the block
+pub fn std_resolver(queue_depth: usize) -> (BlockingAdapter<ResolveOutput>, BlockingWorker) {
+    blocking_adapter(StdResolverHandler, queue_depth)
+}
+
+// Resolves the names it is given, for tests and simulations, the others are
+// not found. It counts the lookups it answers.
+#[derive(Default, Clone)]
+pub struct StaticResolverHandler {
+    names: BTreeMap<String, Vec<IpAddr>>,
+    lookups: usize,
+}
+
+impl StaticResolverHandler {
+    pub fn new(names: BTreeMap<String, Vec<IpAddr>>) -> Self {
+        StaticResolverHandler { names, lookups: 0 }
+    }
+
+    // the named clients of the network, they are there when this is called
+    pub fn for_net(net: &VirtualNet) -> Self {
+        let names = net
+            .names()
+            .into_iter()
+            .map(|(name, id)| (name, vec![VirtualNet::ip(id)]))
+            .collect();
+        StaticResolverHandler::new(names)
+    }
+
+    pub fn lookups(&self) -> usize {
+        self.lookups
+    }
+}
+
+impl Handler<ResolveOutput> for StaticResolverHandler {
+    fn handle(&mut self, effect: ResolveEffect) -> Result<ResolveOutput, ResolveEffect> {
+        match effect {
+            ResolveEffect::Lookup(name, port) => {
+                self.lookups += 1;
+                let output = match self.names.get(&name) {
+                    Some(ips) => ResolveOutput::Resolved(
+                        ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect(),
+                    ),
+                    None => ResolveOutput::ResolveFailed(ResolveErrorKind::NotFound),
+                };
+                Ok(output)
+            },
+            ResolveEffect::Flush(_) => Ok(ResolveOutput::Flushed),
+            other => Err(other),
+        }
+    }
+}
+
+type CacheKey = (String, u16);
+
+type Answer = Result<Vec<SocketAddr>, ResolveErrorKind>;
+
+// Caches the answers of the inner resolver on the time source, a failure
+// for `negative_ttl`. A lookup the inner resolver queued is cached when its
+// wait is answered. `Flush` is answered here, the inner resolver does not
+// see it.
+pub struct CachingResolver<H> {
+    inner: H,
+    ttl: Duration,
+    negative_ttl: Duration,
+    cache: BTreeMap<CacheKey, (Instant, Answer)>,
+    pending: BTreeMap<OpId, CacheKey>,
+    hits: u64,
+}
+
+impl<H> CachingResolver<H> {
+    pub fn new(inner: H, ttl: Duration, negative_ttl: Duration) -> Self {
+        CachingResolver {
+            inner,
+            ttl,
+            negative_ttl,
+            cache: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            hits: 0,
+        }
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    // the lookups answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    fn insert(&mut self, key: CacheKey, result: Answer) {
+        let ttl = match &result {
+            Ok(_) => self.ttl,
+            // the inner resolver is gone, it is not the name that fails
+            Err(ResolveErrorKind::Unavailable) => return,
+            Err(_) => self.negative_ttl,
+        };
+        self.cache.insert(key, (deadline::now() + ttl, result));
+    }
+}
+
+impl<H> Handler<ResolveOutput> for CachingResolver<H>
+where
+    H: Handler<ResolveOutput>,
+{
+    fn handle(&mut self, effect: ResolveEffect) -> Result<ResolveOutput, ResolveEffect> {
+        match effect {
+            ResolveEffect::Lookup(name, port) => {
+                let key = (name, port);
+                match self.cache.get(&key) {
+                    Some((expires, result)) if deadline::now() < *expires => {
+                        self.hits += 1;
+                        return Ok(match result.clone() {
+                            Ok(addrs) => ResolveOutput::Resolved(addrs),
+                            Err(kind) => ResolveOutput::ResolveFailed(kind),
+                        });
+                    },
+                    Some(_) => {
+                        self.cache.remove(&key);
+                    },
+                    None => (),
+                }
+                let output = self
+                    .inner
+                    .handle(ResolveEffect::Lookup(key.0.clone(), key.1))?;
+                match &output {
+                    ResolveOutput::Resolved(addrs) => self.insert(key, Ok(addrs.clone())),
+                    ResolveOutput::ResolveFailed(kind) => self.insert(key, Err(*kind)),
+                    ResolveOutput::Queued(id) => {
+                        self.pending.insert(*id, key);
+                    },
+                    _ => (),
+                }
+                Ok(output)
+            },
+            ResolveEffect::Wait(id) => {
+                let output = self.inner.handle(ResolveEffect::Wait(id))?;
+                if let ResolveOutput::Done(id, result) = &output {
+                    if let Some(key) = self.pending.remove(id) {
+                        self.insert(key, result.clone());
+                    }
+                }
+                Ok(output)
+            },
+            ResolveEffect::Flush(name) => {
+                self.cache.retain(|(n, _), _| *n != name);
+                // a lookup in flight may be stale already
+                self.pending.retain(|_, (n, _)| *n != name);
+                Ok(ResolveOutput::Flushed)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+    use crate::{Handler, deadline::TestClock};
+    use super::{
+        CachingResolver, ResolveEffect, ResolveErrorKind, ResolveOutput, StaticResolverHandler,
+    };
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    fn resolver() -> CachingResolver<StaticResolverHandler> {
+        let mut names = BTreeMap::new();
+        names.insert("db".to_string(), vec![IP]);
+        let inner = StaticResolverHandler::new(names);
+        CachingResolver::new(inner, Duration::from_secs(30), Duration::from_secs(5))
+    }
+
+    fn lookup(name: &str) -> ResolveEffect {
+        ResolveEffect::Lookup(name.to_string(), 5432)
+    }
+
+    #[test]
+    fn hits_skip_the_inner() {
+        let mut resolver = resolver();
+        let resolved = ResolveOutput::Resolved(vec![SocketAddr::new(IP, 5432)]);
+        for _ in 0..3 {
+            assert_eq!(resolver.handle(lookup("db")), Ok(resolved.clone()));
+        }
+        assert_eq!((resolver.inner().lookups(), resolver.hits()), (1, 2));
+
+        // another port is another entry, a flush forgets both
+        resolver
+            .handle(ResolveEffect::Lookup("db".to_string(), 80))
+            .unwrap();
+        assert_eq!(
+            resolver.handle(ResolveEffect::Flush("db".to_string())),
+            Ok(ResolveOutput::Flushed)
+        );
+        assert_eq!(resolver.handle(lookup("db")), Ok(resolved));
+        assert_eq!(resolver.inner().lookups(), 3);
+    }
+
+    #[test]
+    fn ttl_expiry() {
+        let clock = TestClock::install();
+        let mut resolver = resolver();
+        resolver.handle(lookup("db")).unwrap();
+        clock.advance(Duration::from_secs(29));
+        resolver.handle(lookup("db")).unwrap();
+        assert_eq!(resolver.inner().lookups(), 1);
+        // thirty seconds after the first lookup it is resolved again
+        clock.advance(Duration::from_secs(1));
+        resolver.handle(lookup("db")).unwrap();
+        assert_eq!(resolver.inner().lookups(), 2);
+        TestClock::uninstall();
+    }
+
+    #[test]
+    fn negative_cache() {
+        let clock = TestClock::install();
+        let mut resolver = resolver();
+        let failed = ResolveOutput::ResolveFailed(ResolveErrorKind::NotFound);
+        for _ in 0..4 {
+            assert_eq!(resolver.handle(lookup("cache")), Ok(failed.clone()));
+            clock.advance(Duration::from_secs(1));
+        }
+        assert_eq!(resolver.inner().lookups(), 1);
+        // the failure is remembered for five seconds only
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(resolver.handle(lookup("cache")), Ok(failed));
+        assert_eq!(resolver.inner().lookups(), 2);
+        TestClock::uninstall();
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    cell::{Cell, RefCell},
+    error::Error,
+    fmt,
+};
+
+thread_local! {
+    static NEXT_RUN: Cell<u64> = Cell::new(0);
+    // the runs being resumed on this thread, the innermost last
+    static RESUMING: RefCell<Vec<u64>> = RefCell::new(Vec::new());
+}
+
+// The panic payload of a computation or a handler using the context of a
+// block that completed, most likely a clone captured from an earlier run.
+// The runs driving the block when it completed may still use it, so may the
+// code outside of any run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ContextReuse {
+    // the run the context was made for, see `Block::run_id`
+    pub owner: u64,
+    pub user: u64,
+    // `put` or `take`
+    pub access: &'static str,
+}
+
+impl fmt::Display for ContextReuse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` on the context of run #{} by run #{}, after run #{} completed",
+            self.access, self.owner, self.user, self.owner
+        )
+    }
+}
+
+impl Error for ContextReuse {}
+
+pub(crate) fn next_run() -> u64 {
+    NEXT_RUN.with(|next| next.replace(next.get() + 1))
+}
+
+pub(crate) fn current() -> Option<u64> {
+    RESUMING.with(|runs| runs.borrow().last().cloned())
+}
+
+pub(crate) fn running() -> Vec<u64> {
+    RESUMING.with(|runs| runs.borrow().clone())
+}
+
+// a resume of the run begins, `leave` when it ends
+pub(crate) fn enter(run: u64) {
+    RESUMING.with(|runs| runs.borrow_mut().push(run));
+}
+
+pub(crate) fn leave() {
+    RESUMING.with(|runs| runs.borrow_mut().pop());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        cell::RefCell,
+        ops::Generator,
+        panic::{self, AssertUnwindSafe},
+    };
+    use crate::{Context, Counter, Effect, IntoBlock, perform};
+    use super::ContextReuse;
+
+    #[derive(Debug, PartialEq)]
+    enum Effects {
+        Get,
+        Ping,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Got(u32),
+        Pong,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    fn handler(effect: Effects) -> Result<Outputs, Effects> {
+        match effect {
+            Effects::Get => Ok(Outputs::Got(1)),
+            Effects::Ping => Ok(Outputs::Pong),
+        }
+    }
+
+    // never takes the pong
+    fn pinging(_: Context<Outputs>) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            perform!(Effects::Ping);
+        }
+    }
+
+    #[test]
+    fn stale_context() {
+        let first = pinging.into_block().add_handler(handler).assert_handled();
+        let (stale, owner) = (first.context(), first.run_id());
+        first.run();
+        // would take the pong of the first run instead of its own output
+        let second = (move |_: Context<Outputs>| {
+            move || {
+                perform!(Effects::Get);
+                stale.take()
+            }
+        })
+        .into_block()
+        .add_handler(handler)
+        .assert_handled();
+        let user = second.run_id();
+        assert_ne!(owner, user);
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| second.run())).unwrap_err();
+        let error = payload.downcast_ref::<ContextReuse>().unwrap();
+        assert_eq!(
+            (error.owner, error.user, error.access),
+            (owner, user, "take")
+        );
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "`take` on the context of run #{} by run #{}, after run #{} completed",
+                owner, user, owner
+            )
+        );
+    }
+
+    #[test]
+    fn leftovers() {
+        let (block, accounting) = pinging
+            .into_block()
+            .add_handler(handler)
+            .assert_handled()
+            .with_accounting();
+        let context = block.context();
+        let ((), left) = block.run_drained();
+        assert_eq!(left, [Outputs::Pong]);
+        assert!(context.is_empty());
+        let counted = if cfg!(feature = "diagnostics") { 1 } else { 0 };
+        assert_eq!(accounting.report().count(Counter::LeftoverOutputs), counted);
+    }
+
+    #[test]
+    fn views_and_drivers() {
+        let inner_left = Rc::new(RefCell::new(Vec::new()));
+        let outer = (|context: Context<Outputs>| {
+            move || {
+                let got = context.narrow(Outputs::Got, |output| match output {
+                    Outputs::Got(n) => Ok(n),
+                    other => Err(other),
+                });
+                perform!(Effects::Get);
+                got.take()
+            }
+        })
+        .into_block()
+        .add_handler({
+            let inner_left = inner_left.clone();
+            move |effect| {
+                // the handler drove the inner block, it reads what was left
+                let inner = pinging.into_block().add_handler(handler).assert_handled();
+                let context = inner.context();
+                inner.run();
+                inner_left.borrow_mut().extend(context.take());
+                handler(effect)
+            }
+        })
+        .assert_handled();
+        let observer = outer.context();
+        assert_eq!(outer.run(), Some(1));
+        assert_eq!(*inner_left.borrow(), [Outputs::Pong]);
+        observer.put(Outputs::Pong);
+        assert_eq!(observer.take(), Some(Outputs::Pong));
+    }
+}
@@ -0,0 +1,429 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::BTreeMap,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    computation::{Effect, EffectFilter, EffectName, Handler},
+    context::Context,
+    health::Recheck,
+    teardown::HandlerFinished,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HandlerTag(&'static str);
+
+impl HandlerTag {
+    pub fn new(name: &'static str) -> Self {
+        HandlerTag(name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.0
+    }
+}
+
+type Route<I> = (Box<dyn Fn(&I) -> bool>, HandlerTag);
+
+// a tag of the chain with the variants its handler is filtered by
+pub(crate) type Link = (HandlerTag, Option<Vec<&'static str>>);
+
+// answers an effect reaching the handlers after `finish_handlers`
+type Rejection<E> = fn(HandlerFinished) -> E;
+
+struct Entry<E>
+where
+    E: Effect,
+{
+    tag: HandlerTag,
+    handler: Box<dyn Handler<E>>,
+    filter: Option<Box<dyn EffectFilter<E::Input>>>,
+}
+
+struct HandlerList<E>
+where
+    E: Effect,
+{
+    installed: bool,
+    handlers: Vec<Entry<E>>,
+    routes: Vec<Route<E::Input>>,
+    // Handlers filtered by variant are found through the index, the rest are
+    // always consulted. The name is known once such a handler is installed.
+    name: Option<fn(&E::Input) -> &'static str>,
+    index: BTreeMap<&'static str, Vec<usize>>,
+    unindexed: Vec<usize>,
+    // set by `run_checked`
+    recheck: Option<Recheck>,
+    // set by `finish_handlers`
+    finished: Option<Rejection<E>>,
+}
+
+impl<E> Entry<E>
+where
+    E: Effect,
+{
+    fn offer(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        match &self.filter {
+            Some(filter) if !filter.admits(&effect) => Err(effect),
+            _ => self.handler.handle(effect),
+        }
+    }
+}
+
+impl<E> HandlerList<E>
+where
+    E: Effect,
+{
+    fn push(&mut self, entry: Entry<E>) {
+        let index = self.handlers.len();
+        match entry.filter.as_ref().and_then(|f| f.variants()) {
+            Some(variants) => {
+                for variant in variants {
+                    self.index.entry(variant).or_default().push(index);
+                }
+            },
+            None => self.unindexed.push(index),
+        }
+        self.handlers.push(entry);
+    }
+
+    // in installation order, like the linear chain
+    fn candidates(&self, effect: &E::Input) -> Vec<usize> {
+        let indexed = self
+            .name
+            .and_then(|name| self.index.get(name(effect)))
+            .map_or(&[][..], Vec::as_slice);
+        let mut candidates = indexed
+            .iter()
+            .chain(&self.unindexed)
+            .cloned()
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+        candidates
+    }
+
+    // the handler the effect would be offered first
+    fn first(&self, effect: &E::Input) -> Option<HandlerTag> {
+        let routed = self
+            .routes
+            .iter()
+            .filter(|(predicate, _)| predicate(effect))
+            .find(|(_, tag)| self.handlers.iter().any(|e| e.tag == *tag));
+        match routed {
+            Some((_, tag)) => Some(*tag),
+            None => {
+                let index = *self.candidates(effect).first()?;
+                Some(self.handlers[index].tag)
+            },
+        }
+    }
+
+    fn dispatch(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        if let Some(rejection) = self.finished {
+            return match self.first(&effect) {
+                Some(handler) => Ok(rejection(HandlerFinished { handler })),
+                None => Err(effect),
+            };
+        }
+        let mut effect = effect;
+        let mut offered = Vec::new();
+        for (predicate, tag) in &self.routes {
+            if !predicate(&effect) {
+                continue;
+            }
+            let index = match self.handlers.iter().position(|e| e.tag == *tag) {
+                Some(index) if !offered.contains(&index) => index,
+                _ => continue,
+            };
+            offered.push(index);
+            match self.handlers[index].offer(effect) {
+                Ok(output) => return Ok(output),
+                Err(declined) => effect = declined,
+            }
+        }
+        for index in self.candidates(&effect) {
+            if offered.contains(&index) {
+                continue;
+            }
+            match self.handlers[index].offer(effect) {
+                Ok(output) => return Ok(output),
+                Err(declined) => effect = declined,
+            }
+        }
+        Err(effect)
+    }
+
+    // between two dispatches
+    fn recheck(&mut self) {
+        if self.finished.is_some() {
+            return;
+        }
+        if let Some(recheck) = &mut self.recheck {
+            let handlers = self
+                .handlers
+                .iter_mut()
+                .map(|e| (e.tag, e.handler.as_mut() as &mut dyn Handler<E>));
+            recheck.round(handlers);
+        }
+    }
+}
+
+// the tagged handlers in installation order, for the health protocol
+pub(crate) fn with_tagged<E, F, R>(context: &Context<E>, f: F) -> R
+where
+    E: Effect + 'static,
+    E::Input: 'static,
+    F: FnOnce(&mut [(HandlerTag, &mut dyn Handler<E>)]) -> R,
+{
+    match context.find_extension::<RefCell<HandlerList<E>>>() {
+        Some(list) => {
+            let mut list = list.borrow_mut();
+            let mut handlers = list
+                .handlers
+                .iter_mut()
+                .map(|e| (e.tag, e.handler.as_mut() as &mut dyn Handler<E>))
+                .collect::<Vec<_>>();
+            f(&mut handlers)
+        },
+        None => f(&mut []),
+    }
+}
+
+// in reverse installation order, once, later effects are rejected
+pub(crate) fn finish_tagged<E>(context: &Context<E>, rejection: Rejection<E>)
+where
+    E: Effect + 'static,
+    E::Input: 'static,
+{
+    if let Some(list) = context.find_extension::<RefCell<HandlerList<E>>>() {
+        let mut list = list.borrow_mut();
+        if list.finished.is_some() {
+            return;
+        }
+        for entry in list.handlers.iter_mut().rev() {
+            entry.handler.finish();
+        }
+        list.finished = Some(rejection);
+    }
+}
+
+pub(crate) fn set_recheck<E>(context: &Context<E>, recheck: Recheck)
+where
+    E: Effect + 'static,
+    E::Input: 'static,
+{
+    if let Some(list) = context.find_extension::<RefCell<HandlerList<E>>>() {
+        list.borrow_mut().recheck = Some(recheck);
+    }
+}
+
+// the chain in installation order and the number of routes
+pub(crate) fn chain<E>(context: &Context<E>) -> (Vec<Link>, usize)
+where
+    E: Effect + 'static,
+    E::Input: 'static,
+{
+    match context.find_extension::<RefCell<HandlerList<E>>>() {
+        Some(list) => {
+            let list = list.borrow();
+            let handlers = list
+                .handlers
+                .iter()
+                .map(|e| {
+                    let variants = e.filter.as_ref().and_then(|f| f.variants());
+                    (e.tag, variants.map(<[_]>::to_vec))
+                })
+                .collect();
+            (handlers, list.routes.len())
+        },
+        None => (Vec::new(), 0),
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + 'static,
+    E::Input: 'static,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    fn handler_list(&self) -> Rc<RefCell<HandlerList<E>>> {
+        self.context().extension(|| {
+            RefCell::new(HandlerList {
+                installed: false,
+                handlers: Vec::new(),
+                routes: Vec::new(),
+                name: None,
+                index: BTreeMap::new(),
+                unindexed: Vec::new(),
+                recheck: None,
+                finished: None,
+            })
+        })
+    }
+
+    pub fn route<P>(self, predicate: P, to: HandlerTag) -> Self
+    where
+        P: Fn(&E::Input) -> bool + 'static,
+    {
+        let list = self.handler_list();
+        list.borrow_mut().routes.push((Box::new(predicate), to));
+        self
+    }
+
+    pub fn add_handler_tagged<H>(
+        self,
+        handler: H,
+        tag: HandlerTag,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        H: Handler<E> + 'static,
+    {
+        self.install(Entry {
+            tag,
+            handler: Box::new(handler),
+            filter: None,
+        })
+    }
+
+    pub fn add_handler_tagged_filtered<F, H>(
+        self,
+        filter: F,
+        handler: H,
+        tag: HandlerTag,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        E::Input: EffectName,
+        F: EffectFilter<E::Input> + 'static,
+        H: Handler<E> + 'static,
+    {
+        self.handler_list().borrow_mut().name = Some(<E::Input as EffectName>::effect_name);
+        self.install(Entry {
+            tag,
+            handler: Box::new(handler),
+            filter: Some(Box::new(filter)),
+        })
+    }
+
+    fn install(
+        self,
+        entry: Entry<E>,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>> {
+        let list = self.handler_list();
+        let owner = {
+            let mut l = list.borrow_mut();
+            l.push(entry);
+            !std::mem::replace(&mut l.installed, true)
+        };
+        let context = self.context();
+        let mut s = self;
+        let generator = move || loop {
+            if owner {
+                list.borrow_mut().recheck();
+            }
+            match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effects) if !owner => yield effects,
+                GeneratorState::Yielded(effects) => {
+                    let result = list.borrow_mut().dispatch(effects);
+                    match result {
+                        Ok(handled) => s.put(handled),
+                        Err(unhandled) => yield unhandled,
+                    }
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{Context, Effect, IntoBlock};
+    use super::HandlerTag;
+
+    #[derive(Debug)]
+    enum Effects {
+        ReadTcp(u16),
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Read(&'static str, u16),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    fn reader(
+        context: Context<Outputs>,
+        log: Rc<RefCell<Vec<Outputs>>>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            for port in [80, 8224, 443, 8224] {
+                yield Effects::ReadTcp(port);
+                log.borrow_mut().push(context.take().unwrap());
+            }
+        }
+    }
+
+    fn generic(name: &'static str) -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+        move |Effects::ReadTcp(port)| Ok(Outputs::Read(name, port))
+    }
+
+    #[test]
+    fn routed_to_later_handler() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let debug = HandlerTag::new("debug");
+        (|context| reader(context, log.clone()))
+            .into_block()
+            .add_handler_tagged(generic("tcp"), HandlerTag::new("tcp"))
+            .add_handler_tagged(generic("debug"), debug)
+            .route(|Effects::ReadTcp(port)| *port == 8224, debug)
+            .assert_handled()
+            .run();
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                Outputs::Read("tcp", 80),
+                Outputs::Read("debug", 8224),
+                Outputs::Read("tcp", 443),
+                Outputs::Read("debug", 8224),
+            ],
+        );
+    }
+
+    #[test]
+    fn declined_route_falls_through() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let offered = Rc::new(RefCell::new(0));
+        let quiet = HandlerTag::new("quiet");
+        (|context| reader(context, log.clone()))
+            .into_block()
+            .route(|_| true, quiet)
+            .add_handler_tagged(generic("tcp"), HandlerTag::new("tcp"))
+            .add_handler_tagged(
+                {
+                    let offered = offered.clone();
+                    move |e| {
+                        *offered.borrow_mut() += 1;
+                        Err::<Outputs, _>(e)
+                    }
+                },
+                quiet,
+            )
+            .assert_handled()
+            .run();
+        assert_eq!(*offered.borrow(), 4);
+        assert!(log
+            .borrow()
+            .iter()
+            .all(|Outputs::Read(name, _)| *name == "tcp"));
+    }
+}
@@ -0,0 +1,703 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::VecDeque,
+    fmt, thread,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    chaos::Rng,
+    computation::EffectName,
+    context::Context,
+    escalation::IncidentLog,
+    latency::Responds,
+    meta::{EffectMeta, HasPriority},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Detail {
+    #[default]
+    Effect,
+    EffectAndResponse,
+    // the scope path and the metadata of the effect as well
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Sampled,
+    Class,
+    // waited for its response more rounds than `slower_than`, with its age
+    Slow(u32),
+    Incident,
+    // unwinding through the layer, a failed invariant panics
+    Panic,
+    // in the rolling summary when another trigger fired
+    LeadUp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capture {
+    // the number of the effect in the layer from 0, `None` for an incident or a panic
+    pub seq: Option<u64>,
+    pub round: u64,
+    // formatted, only the class of the effect for `LeadUp`
+    pub effect: String,
+    pub trigger: Trigger,
+    pub response: Option<String>,
+    pub meta: Option<EffectMeta>,
+}
+
+// The base rate samples one in `one_in` effects on average, the gaps between
+// the sampled effects come from the seed. The triggers capture what they see
+// whatever the base rate, and promote the effects of the rolling summary.
+#[derive(Clone)]
+pub struct SampleConfig {
+    one_in: u64,
+    seed: u64,
+    classes: Vec<&'static str>,
+    slow: Option<u32>,
+    incidents: Option<IncidentLog>,
+    panics: bool,
+    detail: Detail,
+    capacity: usize,
+    lead_up: usize,
+}
+
+impl SampleConfig {
+    // zero samples nothing, only the triggers capture
+    pub fn new(one_in: u64, seed: u64) -> Self {
+        SampleConfig {
+            one_in,
+            seed,
+            classes: Vec::new(),
+            slow: None,
+            incidents: None,
+            panics: false,
+            detail: Detail::default(),
+            capacity: 256,
+            lead_up: 16,
+        }
+    }
+
+    // the class is the `EffectName` of the effect
+    pub fn always_class(self, name: &'static str) -> Self {
+        let mut classes = self.classes;
+        classes.push(name);
+        SampleConfig { classes, ..self }
+    }
+
+    // captures an effect once it waits more than `k_rounds`, like `with_latency_slo`
+    pub fn slower_than(self, k_rounds: u32) -> Self {
+        SampleConfig {
+            slow: Some(k_rounds),
+            ..self
+        }
+    }
+
+    // the log of `add_handler_escalated`, read every round
+    pub fn incidents(self, log: IncidentLog) -> Self {
+        SampleConfig {
+            incidents: Some(log),
+            ..self
+        }
+    }
+
+    pub fn panics(self) -> Self {
+        SampleConfig {
+            panics: true,
+            ..self
+        }
+    }
+
+    pub fn detail(self, detail: Detail) -> Self {
+        SampleConfig { detail, ..self }
+    }
+
+    // the captures kept, the oldest are dropped first
+    pub fn capacity(self, capacity: usize) -> Self {
+        SampleConfig { capacity, ..self }
+    }
+
+    // the effects promoted when a trigger fires
+    pub fn lead_up(self, lead_up: usize) -> Self {
+        SampleConfig { lead_up, ..self }
+    }
+}
+
+struct Summary {
+    seq: u64,
+    round: u64,
+    class: &'static str,
+    captured: bool,
+}
+
+struct Ring {
+    capacity: usize,
+    lead_up: usize,
+    captures: VecDeque<Capture>,
+    dropped: u64,
+    summary: VecDeque<Summary>,
+    effects: u64,
+    round: u64,
+}
+
+impl Ring {
+    fn capture(&mut self, capture: Capture) {
+        self.captures.push_back(capture);
+        while self.captures.len() > self.capacity {
+            self.captures.pop_front();
+            self.dropped += 1;
+        }
+    }
+
+    fn remember(&mut self, summary: Summary) {
+        if self.lead_up == 0 {
+            return;
+        }
+        if self.summary.len() == self.lead_up {
+            self.summary.pop_front();
+        }
+        self.summary.push_back(summary);
+    }
+
+    // the summary of the triggering effect is not promoted, it is captured in full
+    fn promote(&mut self, except: Option<u64>) {
+        let promoted = self
+            .summary
+            .iter_mut()
+            .filter(|s| !s.captured && Some(s.seq) != except)
+            .map(|s| {
+                s.captured = true;
+                Capture {
+                    seq: Some(s.seq),
+                    round: s.round,
+                    effect: s.class.to_string(),
+                    trigger: Trigger::LeadUp,
+                    response: None,
+                    meta: None,
+                }
+            })
+            .collect::<Vec<_>>();
+        for capture in promoted {
+            self.capture(capture);
+        }
+    }
+
+    #[cfg(feature = "diagnostics")]
+    fn respond(&mut self, seq: u64, response: String) {
+        self.captures
+            .iter_mut()
+            .filter(|c| c.seq == Some(seq) && c.response.is_none())
+            .for_each(|c| c.response = Some(response.clone()));
+    }
+}
+
+#[derive(Clone)]
+pub struct Captures(Rc<RefCell<Ring>>);
+
+impl Captures {
+    pub fn captures(&self) -> Vec<Capture> {
+        self.0.borrow().captures.iter().cloned().collect()
+    }
+
+    // the captures the bounded buffer had no room for
+    pub fn dropped(&self) -> u64 {
+        self.0.borrow().dropped
+    }
+
+    // every effect through the layer, captured or not
+    pub fn effects(&self) -> u64 {
+        self.0.borrow().effects
+    }
+
+    // the effects in the rolling summary, never more than `lead_up`
+    pub fn rolling(&self) -> usize {
+        self.0.borrow().summary.len()
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn export<W>(&self, w: W) -> std::io::Result<()>
+    where
+        W: std::io::Write,
+    {
+        let ring = self.0.borrow();
+        let captures = ring
+            .captures
+            .iter()
+            .map(|c| {
+                serde_json::json!({
+                    "seq": c.seq,
+                    "round": c.round,
+                    "effect": c.effect,
+                    "trigger": format!("{:?}", c.trigger),
+                    "response": c.response,
+                    "scope": c.meta.as_ref().map(|m| &m.scope),
+                    "priority": c.meta.as_ref().map(|m| m.priority),
+                    "token": c.meta.as_ref().and_then(|m| m.token).map(|t| t.0),
+                })
+            })
+            .collect::<Vec<_>>();
+        let summary = serde_json::json!({
+            "effects": ring.effects,
+            "rounds": ring.round,
+            "dropped": ring.dropped,
+            "captures": captures,
+        });
+        serde_json::to_writer(w, &summary).map_err(Into::into)
+    }
+}
+
+// captures the panic unwinding through the layer with its lead-up
+struct OnPanic(Rc<RefCell<Ring>>);
+
+impl Drop for OnPanic {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        if let Ok(mut ring) = self.0.try_borrow_mut() {
+            ring.promote(None);
+            let round = ring.round;
+            ring.capture(Capture {
+                seq: None,
+                round,
+                effect: String::new(),
+                trigger: Trigger::Panic,
+                response: None,
+                meta: None,
+            });
+        }
+    }
+}
+
+struct Waiting<I> {
+    seq: u64,
+    since: u64,
+    effect: I,
+    meta: Option<EffectMeta>,
+    // its capture waits for the response
+    capture: bool,
+    reported: bool,
+}
+
+fn meta<T, I>(context: &Context<T>, effect: &I) -> EffectMeta
+where
+    I: HasPriority,
+{
+    EffectMeta {
+        priority: effect.priority(),
+        deadline: effect.due(),
+        token: context.token(),
+        scope: context.scope_path(),
+        site: None,
+        truncated: None,
+        family: None,
+        identity: context.identity(),
+        cause: context.cause(),
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Responds + fmt::Debug + 'static,
+    E::Input: Clone + fmt::Debug + EffectName + HasPriority + 'static,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // An effect that is not captured costs the count, the check of the base
+    // rate and its slot in the rolling summary, it is formatted only once a
+    // trigger promotes it. The layer goes right after `into_block` like
+    // `with_latency_slo`, the slow trigger needs to see the effects before
+    // the handlers answer them.
+    pub fn sample_capture(
+        self,
+        config: SampleConfig,
+    ) -> (
+        Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>,
+        Captures,
+    ) {
+        let context = self.context();
+        let ring = Rc::new(RefCell::new(Ring {
+            capacity: config.capacity,
+            lead_up: config.lead_up,
+            captures: VecDeque::new(),
+            dropped: 0,
+            summary: VecDeque::with_capacity(config.lead_up),
+            effects: 0,
+            round: 0,
+        }));
+        let waiting = Rc::new(RefCell::new(Vec::<Waiting<E::Input>>::new()));
+        // without diagnostics there are no watches, so no responses and no slow
+        // effects, the sampled and promoted effects are still captured
+        #[cfg(feature = "diagnostics")]
+        context.add_watch(Box::new({
+            let ring = ring.clone();
+            let waiting = waiting.clone();
+            move |_, output: &E| {
+                let mut waiting = waiting.borrow_mut();
+                let answered = waiting.iter().position(|w| output.responds_to(&w.effect));
+                if let Some(w) = answered.map(|i| waiting.remove(i)) {
+                    if w.capture {
+                        ring.borrow_mut().respond(w.seq, format!("{:?}", output));
+                    }
+                }
+            }
+        }));
+        let SampleConfig {
+            one_in,
+            seed,
+            classes,
+            slow,
+            incidents,
+            panics,
+            detail,
+            ..
+        } = config;
+        let mut rng = Rng(seed);
+        let mut gap = move || match one_in {
+            0 => None,
+            n => Some(rng.next_u64() % (2 * n - 1)),
+        };
+        let mut next_sample = gap();
+        let mut reported_incidents = 0;
+        let mut s = self;
+        let generator = {
+            let ring = ring.clone();
+            move || {
+                let _on_panic = if panics {
+                    Some(OnPanic(ring.clone()))
+                } else {
+                    None
+                };
+                loop {
+                    let round = {
+                        let mut ring = ring.borrow_mut();
+                        ring.round += 1;
+                        ring.round
+                    };
+                    if let Some(log) = &incidents {
+                        let new = log.since(reported_incidents);
+                        reported_incidents += new.len();
+                        let mut ring = ring.borrow_mut();
+                        for incident in new {
+                            ring.promote(None);
+                            ring.capture(Capture {
+                                seq: None,
+                                round,
+                                effect: format!("{:?}", incident),
+                                trigger: Trigger::Incident,
+                                response: None,
+                                meta: None,
+                            });
+                        }
+                    }
+                    if let Some(k_rounds) = slow {
+                        let mut waiting = waiting.borrow_mut();
+                        let mut ring = ring.borrow_mut();
+                        for w in waiting.iter_mut() {
+                            let age = round - w.since;
+                            if w.reported || age <= u64::from(k_rounds) {
+                                continue;
+                            }
+                            w.reported = true;
+                            w.capture |= detail >= Detail::EffectAndResponse;
+                            ring.promote(Some(w.seq));
+                            ring.capture(Capture {
+                                seq: Some(w.seq),
+                                round: w.since,
+                                effect: format!("{:?}", w.effect),
+                                trigger: Trigger::Slow(age as u32),
+                                response: None,
+                                meta: w.meta.clone(),
+                            });
+                        }
+                        waiting.retain(|w| w.capture || !w.reported);
+                    }
+                    let effect = match s.resume() {
+                        GeneratorState::Complete(r) => return r,
+                        GeneratorState::Yielded(effect) => effect,
+                    };
+                    let mut ring = ring.borrow_mut();
+                    let seq = ring.effects;
+                    ring.effects += 1;
+                    let class = effect.effect_name();
+                    let sampled = next_sample == Some(seq);
+                    if sampled {
+                        next_sample = gap().map(|gap| seq + 1 + gap);
+                    }
+                    let trigger = if classes.contains(&class) {
+                        ring.promote(None);
+                        Some(Trigger::Class)
+                    } else if sampled {
+                        Some(Trigger::Sampled)
+                    } else {
+                        None
+                    };
+                    let captured = trigger.is_some();
+                    let meta = (detail == Detail::Full && (captured || slow.is_some()))
+                        .then(|| meta(&s.context(), &effect));
+                    if let Some(trigger) = trigger {
+                        ring.capture(Capture {
+                            seq: Some(seq),
+                            round,
+                            effect: format!("{:?}", effect),
+                            trigger,
+                            response: None,
+                            meta: meta.clone(),
+                        });
+                    }
+                    ring.remember(Summary {
+                        seq,
+                        round,
+                        class,
+                        captured,
+                    });
+                    drop(ring);
+                    let capture = captured && detail >= Detail::EffectAndResponse;
+                    let tracked = cfg!(feature = "diagnostics") && E::expects_response(&effect);
+                    if tracked && (capture || slow.is_some()) {
+                        waiting.borrow_mut().push(Waiting {
+                            seq,
+                            since: round,
+                            effect: effect.clone(),
+                            meta,
+                            capture,
+                            reported: false,
+                        });
+                    }
+                    yield effect;
+                }
+            }
+        };
+        (Block::new(context, generator), Captures(ring))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Generator;
+    use crate::{Context, Effect, EffectName, HasPriority, IntoBlock, Responds};
+    use super::{Capture, Captures, Detail, SampleConfig, Trigger};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        Get(u32),
+        Slow,
+        Boom,
+        Idle,
+    }
+
+    impl EffectName for Effects {
+        fn effect_name(&self) -> &'static str {
+            match self {
+                Effects::Get(_) => "Get",
+                Effects::Slow => "Slow",
+                Effects::Boom => "Boom",
+                Effects::Idle => "Idle",
+            }
+        }
+    }
+
+    impl HasPriority for Effects {}
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Got(u32),
+        Slowed,
+        Idled,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl Responds for Outputs {
+        fn responds_to(&self, effect: &Effects) -> bool {
+            match (self, effect) {
+                (Outputs::Got(n), Effects::Get(m)) => n == m,
+                (Outputs::Slowed, Effects::Slow) => true,
+                (Outputs::Idled, Effects::Boom) => true,
+                _ => false,
+            }
+        }
+
+        fn expects_response(effect: &Effects) -> bool {
+            *effect != Effects::Idle
+        }
+    }
+
+    // waits for the slow one with idle effects
+    fn script(
+        context: Context<Outputs>,
+        effects: Vec<Effects>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            for effect in effects {
+                let slow = effect == Effects::Slow;
+                yield effect;
+                while slow && context.take() != Some(Outputs::Slowed) {
+                    yield Effects::Idle;
+                }
+                context.take();
+            }
+        }
+    }
+
+    // answers the slow effect after four idle ones
+    fn handler() -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+        let mut idles = None;
+        move |effect| match effect {
+            Effects::Get(n) => Ok(Outputs::Got(n)),
+            Effects::Slow => {
+                idles = Some(0);
+                Ok(Outputs::Idled)
+            },
+            Effects::Boom => Ok(Outputs::Idled),
+            Effects::Idle => match idles.as_mut() {
+                Some(n) if *n == 3 => Ok(Outputs::Slowed),
+                Some(n) => {
+                    *n += 1;
+                    Ok(Outputs::Idled)
+                },
+                None => Ok(Outputs::Idled),
+            },
+        }
+    }
+
+    fn run(effects: Vec<Effects>, config: SampleConfig) -> Captures {
+        let (block, captures) = (move |context| script(context, effects))
+            .into_block()
+            .sample_capture(config);
+        block.add_handler(handler()).assert_handled().run();
+        captures
+    }
+
+    fn gets(n: u32) -> Vec<Effects> {
+        (0..n).map(Effects::Get).collect()
+    }
+
+    fn seqs(captures: &[Capture]) -> Vec<(Option<u64>, Trigger)> {
+        captures.iter().map(|c| (c.seq, c.trigger)).collect()
+    }
+
+    #[test]
+    fn deterministic_sampling() {
+        let sampled = |seed| seqs(&run(gets(1000), SampleConfig::new(10, seed)).captures());
+        let first = sampled(7);
+        assert_eq!(first, sampled(7));
+        assert_ne!(first, sampled(8));
+        assert!((50..150).contains(&first.len()));
+        assert!(first.iter().all(|(_, t)| *t == Trigger::Sampled));
+        let all = run(
+            gets(5),
+            SampleConfig::new(1, 0).detail(Detail::EffectAndResponse),
+        );
+        let responses = all
+            .captures()
+            .into_iter()
+            .map(|c| c.response)
+            .collect::<Vec<_>>();
+        assert_eq!(responses[4], Some("Got(4)".to_string()));
+        assert!(run(gets(5), SampleConfig::new(0, 0)).captures().is_empty());
+    }
+
+    #[test]
+    fn lead_up() {
+        let mut effects = gets(10);
+        effects.push(Effects::Boom);
+        let captures = run(
+            effects,
+            SampleConfig::new(0, 0).always_class("Boom").lead_up(3),
+        );
+        assert_eq!(
+            seqs(&captures.captures()),
+            [
+                (Some(7), Trigger::LeadUp),
+                (Some(8), Trigger::LeadUp),
+                (Some(9), Trigger::LeadUp),
+                (Some(10), Trigger::Class),
+            ],
+        );
+        assert_eq!(captures.captures()[0].effect, "Get");
+        assert_eq!(captures.captures()[3].effect, "Boom");
+
+        // the slow one is found three rounds after it is yielded, two idle
+        // effects later, the gets before it are out of the summary
+        let mut effects = gets(5);
+        effects.push(Effects::Slow);
+        let config = SampleConfig::new(0, 0)
+            .slower_than(2)
+            .lead_up(3)
+            .detail(Detail::EffectAndResponse);
+        let captures = run(effects, config).captures();
+        assert_eq!(
+            seqs(&captures),
+            [
+                (Some(6), Trigger::LeadUp),
+                (Some(7), Trigger::LeadUp),
+                (Some(5), Trigger::Slow(3)),
+            ],
+        );
+        assert_eq!(captures[2].response.as_deref(), Some("Slowed"));
+    }
+
+    #[test]
+    fn bounded() {
+        let mut effects = gets(100);
+        effects.extend([Effects::Boom, Effects::Boom]);
+        let (block, captures) = (move |context| script(context, effects))
+            .into_block()
+            .sample_capture(
+                SampleConfig::new(1, 0)
+                    .always_class("Boom")
+                    .lead_up(4)
+                    .capacity(10),
+            );
+        let mut handler = handler();
+        let watched = captures.clone();
+        block
+            .add_handler(move |effect| {
+                assert!(watched.rolling() <= 4);
+                handler(effect)
+            })
+            .assert_handled()
+            .run();
+        assert_eq!(captures.effects(), 102);
+        assert_eq!(captures.rolling(), 4);
+        assert_eq!(captures.captures().len(), 10);
+        assert_eq!(captures.dropped(), 92);
+    }
+
+    #[test]
+    fn panic_with_lead_up() {
+        let mut effects = gets(3);
+        effects.push(Effects::Boom);
+        let (block, captures) = (move |context| script(context, effects))
+            .into_block()
+            .sample_capture(SampleConfig::new(0, 0).panics().lead_up(2));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            block
+                .add_handler(|effect| match effect {
+                    Effects::Boom => panic!("deliberate"),
+                    Effects::Get(n) => Ok(Outputs::Got(n)),
+                    _ => Ok(Outputs::Idled),
+                })
+                .assert_handled()
+                .run()
+        }));
+        assert!(result.is_err());
+        assert_eq!(
+            seqs(&captures.captures()),
+            [
+                (Some(2), Trigger::LeadUp),
+                (Some(3), Trigger::LeadUp),
+                (None, Trigger::Panic),
+            ],
+        );
+    }
+}
@@ -0,0 +1,474 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    fmt, mem,
+    time::Duration,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    computation::Effect,
+    context::Context,
+    deadline::{self, TestClock},
+};
+
+type Matcher<'a, I> = Box<dyn Fn(&I) -> bool + 'a>;
+
+type StateCheck<'a, E> = Box<dyn FnOnce(&Context<E>) + 'a>;
+
+type Responder<'a, E> = Box<dyn FnMut(usize, <E as Effect>::Input) -> Option<E> + 'a>;
+
+struct Expectation<'a, E>
+where
+    E: Effect,
+{
+    label: &'static str,
+    count: usize,
+    seen: usize,
+    matches: Matcher<'a, E::Input>,
+    respond: Responder<'a, E>,
+}
+
+enum Step<'a, E>
+where
+    E: Effect,
+{
+    Expect(Expectation<'a, E>),
+    // the expectations of the group may be met in any order
+    Unordered(Vec<Expectation<'a, E>>),
+    Advance(Duration),
+    Inject(E),
+    AssertState(StateCheck<'a, E>),
+    Complete,
+}
+
+// The handler side of a test, played against a computation. Every effect the
+// computation performs has to meet the next expectation of the script, the
+// response of the expectation goes to the context like from a handler. Use
+// the `scenario!` macro rather than the methods.
+pub struct Scenario<'a, E>
+where
+    E: Effect,
+{
+    steps: Vec<Step<'a, E>>,
+}
+
+impl<E> Default for Scenario<'_, E>
+where
+    E: Effect,
+{
+    fn default() -> Self {
+        Scenario { steps: Vec::new() }
+    }
+}
+
+// The script and the computation disagree. The steps are counted from one,
+// `actual` is `None` when the computation completed instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub step: usize,
+    pub expected: String,
+    pub actual: Option<String>,
+    // the step and the effect for every effect that met its expectation
+    pub trace: Vec<(usize, String)>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let actual = self.actual.as_deref().unwrap_or("completion");
+        writeln!(
+            f,
+            "scenario diverged at step {}: expected `{}`, got `{}`",
+            self.step, self.expected, actual,
+        )?;
+        for (step, effect) in &self.trace {
+            writeln!(f, "  {:>3} {}", step, effect)?;
+        }
+        writeln!(f, "- {:>3} {}", self.step, self.expected)?;
+        write!(f, "+ {:>3} {}", self.step, actual)
+    }
+}
+
+impl<'a, E> Scenario<'a, E>
+where
+    E: Effect,
+{
+    pub fn new() -> Self {
+        Scenario::default()
+    }
+
+    fn step(mut self, step: Step<'a, E>) -> Self {
+        if let Some(Step::Complete) = self.steps.last() {
+            panic!("`expect_complete` must be the last step");
+        }
+        self.steps.push(step);
+        self
+    }
+
+    // `respond` gets the effect, without a response nothing goes to the context
+    pub fn expect<M, R>(self, label: &'static str, matches: M, respond: R) -> Self
+    where
+        M: Fn(&E::Input) -> bool + 'a,
+        R: FnOnce(E::Input) -> Option<E> + 'a,
+    {
+        let mut respond = Some(respond);
+        self.expect_many(label, 1, matches, move |_, effect| {
+            respond.take().expect("expected once")(effect)
+        })
+    }
+
+    // `respond` also gets the index of the effect among the `count`
+    pub fn expect_many<M, R>(
+        self,
+        label: &'static str,
+        count: usize,
+        matches: M,
+        respond: R,
+    ) -> Self
+    where
+        M: Fn(&E::Input) -> bool + 'a,
+        R: FnMut(usize, E::Input) -> Option<E> + 'a,
+    {
+        self.step(Step::Expect(Expectation {
+            label,
+            count,
+            seen: 0,
+            matches: Box::new(matches),
+            respond: Box::new(respond),
+        }))
+    }
+
+    // the expectations of `group` in any order, it has nothing but expectations
+    pub fn unordered(self, group: Scenario<'a, E>) -> Self {
+        let group = group
+            .steps
+            .into_iter()
+            .map(|step| match step {
+                Step::Expect(expectation) => expectation,
+                _ => panic!("only expectations go in an `unordered` group"),
+            })
+            .collect();
+        self.step(Step::Unordered(group))
+    }
+
+    // moves the installed clock, a run with such a step installs a `TestClock`
+    // if there is none
+    pub fn advance_time(self, by: Duration) -> Self {
+        self.step(Step::Advance(by))
+    }
+
+    // puts the output in the context before the computation is resumed
+    pub fn inject(self, output: E) -> Self {
+        self.step(Step::Inject(output))
+    }
+
+    pub fn assert_state<F>(self, check: F) -> Self
+    where
+        F: FnOnce(&Context<E>) + 'a,
+    {
+        self.step(Step::AssertState(Box::new(check)))
+    }
+
+    // the computation completes here, a script without it ends with it anyway
+    pub fn expect_complete(self) -> Self {
+        self.step(Step::Complete)
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl<E> Scenario<'_, E>
+where
+    E: Effect,
+    E::Input: fmt::Debug,
+{
+    // panics with the divergence
+    pub fn run<G>(self, block: Block<E, G>) -> G::Return
+    where
+        G: Unpin + Generator<(), Yield = E::Input>,
+    {
+        self.try_run(block)
+            .unwrap_or_else(|divergence| panic!("{}", divergence))
+    }
+
+    pub fn try_run<G>(self, block: Block<E, G>) -> Result<G::Return, Divergence>
+    where
+        G: Unpin + Generator<(), Yield = E::Input>,
+    {
+        let mut steps = self.steps;
+        if !matches!(steps.last(), Some(Step::Complete)) {
+            steps.push(Step::Complete);
+        }
+        let clock =
+            steps.iter().any(|step| matches!(step, Step::Advance(_))) && !deadline::is_installed();
+        if clock {
+            TestClock::install();
+        }
+        let result = Runner {
+            block,
+            trace: Vec::new(),
+        }
+        .play(steps);
+        if clock {
+            TestClock::uninstall();
+        }
+        result
+    }
+}
+
+struct Runner<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    block: Block<E, G>,
+    trace: Vec<(usize, String)>,
+}
+
+impl<E, G> Runner<E, G>
+where
+    E: Effect,
+    E::Input: fmt::Debug,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    fn diverged(&mut self, step: usize, expected: String, actual: Option<String>) -> Divergence {
+        Divergence {
+            step,
+            expected,
+            actual,
+            trace: mem::take(&mut self.trace),
+        }
+    }
+
+    // the next effect, the completion is a divergence
+    fn effect(&mut self, step: usize, expected: &str) -> Result<E::Input, Divergence> {
+        match self.block.resume() {
+            GeneratorState::Yielded(effect) => Ok(effect),
+            GeneratorState::Complete(_) => Err(self.diverged(step, expected.to_string(), None)),
+        }
+    }
+
+    fn meet(&mut self, step: usize, expectation: &mut Expectation<'_, E>, effect: E::Input) {
+        self.trace.push((step, format!("{:?}", effect)));
+        let index = expectation.seen;
+        expectation.seen += 1;
+        if let Some(output) = (expectation.respond)(index, effect) {
+            self.block.put(output);
+        }
+    }
+
+    fn play(mut self, steps: Vec<Step<'_, E>>) -> Result<G::Return, Divergence> {
+        for (step, action) in (1..).zip(steps) {
+            match action {
+                Step::Expect(mut expectation) => {
+                    while expectation.seen < expectation.count {
+                        let expected = match expectation.count {
+                            1 => expectation.label.to_string(),
+                            count => format!(
+                                "{} ({} of {})",
+                                expectation.label,
+                                expectation.seen + 1,
+                                count,
+                            ),
+                        };
+                        let effect = self.effect(step, &expected)?;
+                        if !(expectation.matches)(&effect) {
+                            let actual = Some(format!("{:?}", effect));
+                            return Err(self.diverged(step, expected, actual));
+                        }
+                        self.meet(step, &mut expectation, effect);
+                    }
+                },
+                Step::Unordered(mut group) => loop {
+                    let remaining = group
+                        .iter()
+                        .filter(|e| e.seen < e.count)
+                        .map(|e| e.label)
+                        .collect::<Vec<_>>();
+                    if remaining.is_empty() {
+                        break;
+                    }
+                    let expected = format!("any of {}", remaining.join(", "));
+                    let effect = self.effect(step, &expected)?;
+                    match group
+                        .iter_mut()
+                        .find(|e| e.seen < e.count && (e.matches)(&effect))
+                    {
+                        Some(expectation) => self.meet(step, expectation, effect),
+                        None => {
+                            let actual = Some(format!("{:?}", effect));
+                            return Err(self.diverged(step, expected, actual));
+                        },
+                    }
+                },
+                Step::Advance(by) => deadline::sleep(by),
+                Step::Inject(output) => self.block.put(output),
+                Step::AssertState(check) => check(&self.block.context()),
+                Step::Complete => match self.block.resume() {
+                    GeneratorState::Complete(r) => return Ok(r),
+                    GeneratorState::Yielded(effect) => {
+                        let actual = Some(format!("{:?}", effect));
+                        return Err(self.diverged(step, "completion".to_string(), actual));
+                    },
+                },
+            }
+        }
+        unreachable!("the script ends with the completion")
+    }
+}
+
+/// Scripts the handlers of a test, steps are separated by `;`. The patterns
+/// match a reference to the effect, their bindings are references.
+///
+/// ```
+/// #![feature(generators)]
+/// use aeiou::{Context, IntoBlock, perform, scenario};
+///
+/// aeiou::simple_effects! {
+///     Effects { Read(u8), Write(u8) }
+///     Outputs { Data(u8), Written }
+///     parts { Data(u8) => Byte }
+/// }
+///
+/// let sum = (|context: Context<Outputs>| {
+///     move || {
+///         let Byte(a) = perform!(Effects::Read(0), &context);
+///         let Byte(b) = perform!(Effects::Read(1), &context);
+///         perform!(Effects::Write(a + b));
+///         a + b
+///     }
+/// })
+/// .into_block();
+/// let sum = scenario! {
+///     expect_many 2 matching Effects::Read(_) => respond_each |i| Outputs::Data(i as u8 + 3);
+///     expect Effects::Write(n) if *n == 7 => respond Outputs::Written;
+///     expect_complete;
+/// }
+/// .run(sum);
+/// assert_eq!(sum, 7);
+/// ```
+///
+/// The other steps are `expect PATTERN => no_response`, `advance_time DURATION`,
+/// `inject OUTPUT`, `assert_state |context| EXPR` and `unordered { EXPECTATIONS }`.
+/// The duration is a `Duration` or a literal like `5s` or `250ms`.
+#[macro_export]
+macro_rules! scenario {
+    ($($steps:tt)*) => {{
+        // the matchers do not use the bindings of the patterns
+        #[allow(unused_variables)]
+        let scenario = $crate::scenario_steps!($crate::Scenario::new(); $($steps)*);
+        scenario
+    }};
+}
+
+// the duration of a literal in `advance_time`, it is not a number of the
+// language, the macro gets its text
+#[doc(hidden)]
+pub fn duration_literal(literal: &str) -> Duration {
+    let split = literal
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(literal.len());
+    let (value, unit) = literal.split_at(split);
+    let value = value
+        .parse::<u64>()
+        .unwrap_or_else(|_| panic!("`{}` is not a duration", literal));
+    match unit {
+        "ns" => Duration::from_nanos(value),
+        "us" => Duration::from_micros(value),
+        "ms" => Duration::from_millis(value),
+        "s" => Duration::from_secs(value),
+        "m" => Duration::from_secs(value * 60),
+        "h" => Duration::from_secs(value * 60 * 60),
+        _ => panic!("`{}` needs a unit, one of ns, us, ms, s, m or h", literal),
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! scenario_steps {
+    ($s:expr;) => {
+        $s
+    };
+    ($s:expr; expect $p:pat $(if $g:expr)? => respond $o:expr $(; $($rest:tt)*)?) => {
+        $crate::scenario_steps!(
+            $s.expect(
+                stringify!($p),
+                |effect| matches!(effect, $p $(if $g)?),
+                |effect| match &effect {
+                    $p $(if $g)? => Some($o),
+                    #[allow(unreachable_patterns)]
+                    _ => unreachable!(),
+                },
+            );
+            $($($rest)*)?
+        )
+    };
+    ($s:expr; expect $p:pat $(if $g:expr)? => no_response $(; $($rest:tt)*)?) => {
+        $crate::scenario_steps!(
+            $s.expect(stringify!($p), |effect| matches!(effect, $p $(if $g)?), |_| None);
+            $($($rest)*)?
+        )
+    };
+    (
+        $s:expr;
+        expect_many $n:tt matching $p:pat $(if $g:expr)? => respond_each |$i:ident| $o:expr
+        $(; $($rest:tt)*)?
+    ) => {
+        $crate::scenario_steps!(
+            $s.expect_many(
+                stringify!($p),
+                $n,
+                |effect| matches!(effect, $p $(if $g)?),
+                |$i, effect| match &effect {
+                    $p $(if $g)? => Some($o),
+                    #[allow(unreachable_patterns)]
+                    _ => unreachable!(),
+                },
+            );
+            $($($rest)*)?
+        )
+    };
+    (
+        $s:expr;
+        expect_many $n:tt matching $p:pat $(if $g:expr)? => no_response
+        $(; $($rest:tt)*)?
+    ) => {
+        $crate::scenario_steps!(
+            $s.expect_many(
+                stringify!($p),
+                $n,
+                |effect| matches!(effect, $p $(if $g)?),
+                |_, _| None,
+            );
+            $($($rest)*)?
+        )
+    };
+    ($s:expr; unordered { $($group:tt)* } $(; $($rest:tt)*)?) => {
+        $crate::scenario_steps!($s.unordered($crate::scenario!($($group)*)); $($($rest)*)?)
+    };
+    ($s:expr; advance_time $d:literal $(; $($rest:tt)*)?) => {
+        $crate::scenario_steps!(
+            $s.advance_time($crate::duration_literal(stringify!($d)));
+            $($($rest)*)?
+        )
+    };
+    ($s:expr; advance_time $d:expr $(; $($rest:tt)*)?) => {
+        $crate::scenario_steps!($s.advance_time($d); $($($rest)*)?)
+    };
+    ($s:expr; inject $o:expr $(; $($rest:tt)*)?) => {
+        $crate::scenario_steps!($s.inject($o); $($($rest)*)?)
+    };
+    ($s:expr; assert_state |$c:ident| $check:expr $(; $($rest:tt)*)?) => {
+        $crate::scenario_steps!($s.assert_state(|$c| $check); $($($rest)*)?)
+    };
+    ($s:expr; expect_complete $(; $($rest:tt)*)?) => {
+        $crate::scenario_steps!($s.expect_complete(); $($($rest)*)?)
+    };
+}
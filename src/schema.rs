@@ -0,0 +1,139 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::fmt;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldSchema {
+    pub name: Option<String>,
+    pub ty: String,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VariantSchema {
+    pub name: String,
+    pub fields: Vec<FieldSchema>,
+    pub part: Option<String>,
+    pub doc: String,
+}
+
+// `input` is set for output enums, it names the effect type they respond to
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EffectSchema {
+    pub name: String,
+    pub input: Option<String>,
+    pub doc: String,
+    pub variants: Vec<VariantSchema>,
+}
+
+impl EffectSchema {
+    pub fn variant(&self, name: &str) -> Option<&VariantSchema> {
+        self.variants.iter().find(|v| v.name == name)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("schema is plain json")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaChange {
+    AddedVariant(String),
+    RemovedVariant(String),
+    ChangedPayload {
+        variant: String,
+        old: Vec<FieldSchema>,
+        new: Vec<FieldSchema>,
+    },
+    ChangedPart {
+        variant: String,
+        old: Option<String>,
+        new: Option<String>,
+    },
+    ChangedInput {
+        old: Option<String>,
+        new: Option<String>,
+    },
+    ChangedDoc(String),
+}
+
+impl SchemaChange {
+    pub fn is_breaking(&self) -> bool {
+        !matches!(
+            self,
+            SchemaChange::AddedVariant(_) | SchemaChange::ChangedDoc(_)
+        )
+    }
+}
+
+impl fmt::Display for SchemaChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.is_breaking() {
+            "breaking"
+        } else {
+            "additive"
+        };
+        match self {
+            SchemaChange::AddedVariant(name) => write!(f, "{}: added variant `{}`", kind, name),
+            SchemaChange::RemovedVariant(name) => {
+                write!(f, "{}: removed variant `{}`", kind, name)
+            },
+            SchemaChange::ChangedPayload { variant, .. } => {
+                write!(f, "{}: changed payload of `{}`", kind, variant)
+            },
+            SchemaChange::ChangedPart { variant, .. } => {
+                write!(f, "{}: changed part of `{}`", kind, variant)
+            },
+            SchemaChange::ChangedInput { .. } => write!(f, "{}: changed input type", kind),
+            SchemaChange::ChangedDoc(variant) => {
+                write!(f, "{}: changed documentation of `{}`", kind, variant)
+            },
+        }
+    }
+}
+
+pub fn schema_diff(old: &EffectSchema, new: &EffectSchema) -> Vec<SchemaChange> {
+    let mut changes = Vec::new();
+    if old.input != new.input {
+        changes.push(SchemaChange::ChangedInput {
+            old: old.input.clone(),
+            new: new.input.clone(),
+        });
+    }
+    for variant in &old.variants {
+        let current = match new.variant(&variant.name) {
+            Some(current) => current,
+            None => {
+                changes.push(SchemaChange::RemovedVariant(variant.name.clone()));
+                continue;
+            },
+        };
+        if variant.fields != current.fields {
+            changes.push(SchemaChange::ChangedPayload {
+                variant: variant.name.clone(),
+                old: variant.fields.clone(),
+                new: current.fields.clone(),
+            });
+        }
+        if variant.part != current.part {
+            changes.push(SchemaChange::ChangedPart {
+                variant: variant.name.clone(),
+                old: variant.part.clone(),
+                new: current.part.clone(),
+            });
+        }
+        if variant.doc != current.doc {
+            changes.push(SchemaChange::ChangedDoc(variant.name.clone()));
+        }
+    }
+    for variant in &new.variants {
+        if old.variant(&variant.name).is_none() {
+            changes.push(SchemaChange::AddedVariant(variant.name.clone()));
+        }
+    }
+    changes
+}
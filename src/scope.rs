@@ -0,0 +1,163 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::borrow::Cow;
+#[cfg(feature = "diagnostics")]
+use std::{rc::Rc, cell::RefCell};
+use super::context::Context;
+
+// a static name is not copied, entering a scope in a steady loop does not allocate
+#[cfg(feature = "diagnostics")]
+#[derive(Default)]
+struct Scopes(RefCell<Vec<Cow<'static, str>>>);
+
+// without diagnostics the guard is empty and `scope_path` is always empty
+pub struct ScopeGuard {
+    #[cfg(feature = "diagnostics")]
+    scopes: Rc<Scopes>,
+    #[cfg(feature = "diagnostics")]
+    depth: usize,
+}
+
+#[cfg(feature = "diagnostics")]
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        // truncate rather than pop, an inner guard that was leaked must not shift the parent
+        self.scopes.0.borrow_mut().truncate(self.depth);
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+impl<T> Context<T> {
+    pub fn enter_scope<N>(&self, name: N) -> ScopeGuard
+    where
+        N: Into<Cow<'static, str>>,
+    {
+        let scopes = self.extension(Scopes::default);
+        let depth = {
+            let mut stack = scopes.0.borrow_mut();
+            stack.push(name.into());
+            stack.len() - 1
+        };
+        ScopeGuard { scopes, depth }
+    }
+
+    pub fn scope_path(&self) -> String {
+        self.extension(Scopes::default).0.borrow().join(" > ")
+    }
+
+    // reads the path later without holding the context
+    pub(crate) fn scope_reader(&self) -> impl Fn() -> String {
+        let scopes = self.extension(Scopes::default);
+        move || scopes.0.borrow().join(" > ")
+    }
+}
+
+#[cfg(not(feature = "diagnostics"))]
+impl<T> Context<T> {
+    #[inline(always)]
+    pub fn enter_scope<N>(&self, name: N) -> ScopeGuard
+    where
+        N: Into<Cow<'static, str>>,
+    {
+        let _ = name;
+        ScopeGuard {}
+    }
+
+    pub fn scope_path(&self) -> String {
+        String::new()
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+#[macro_export]
+macro_rules! scope {
+    ($ctx:expr, $($name:tt)+) => {
+        let _scope = $crate::Context::enter_scope($ctx, format!($($name)+));
+    };
+}
+
+// the name is not formatted at all
+#[cfg(not(feature = "diagnostics"))]
+#[macro_export]
+macro_rules! scope {
+    ($ctx:expr, $($name:tt)+) => {
+        let _ = $ctx;
+    };
+}
+
+#[cfg(all(test, feature = "diagnostics"))]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{Context, Effect, IntoBlock, scope};
+
+    #[derive(Debug)]
+    enum Effects {
+        Read,
+        Log,
+    }
+
+    struct Outputs;
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    fn server(
+        context: Context<Outputs>,
+        paths: Rc<RefCell<Vec<String>>>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            scope!(&context, "root");
+            for connection in 0..2 {
+                scope!(&context, "connection({})", connection);
+                'handshake: {
+                    scope!(&context, "handshake");
+                    if connection == 0 {
+                        break 'handshake;
+                    }
+                    yield Effects::Log;
+                }
+                paths.borrow_mut().push(context.scope_path());
+                scope!(&context, "read_line");
+                yield Effects::Read;
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "unhandled: Read in root > connection(1) > read_line")]
+    fn unhandled_path() {
+        let paths = Rc::default();
+        (|context| server(context, paths))
+            .into_block()
+            .add_handler({
+                let mut reads = 0;
+                move |effect| match effect {
+                    Effects::Log => Ok(Outputs),
+                    Effects::Read if reads == 0 => {
+                        reads += 1;
+                        Ok(Outputs)
+                    },
+                    e => Err(e),
+                }
+            })
+            .assert_handled()
+            .run();
+    }
+
+    #[test]
+    fn early_return_keeps_parent() {
+        let paths = Rc::new(RefCell::new(Vec::new()));
+        let block = (|context| server(context, paths.clone()))
+            .into_block()
+            .add_handler(|_| Ok(Outputs));
+        let context = block.context();
+        block.assert_handled().run();
+        assert_eq!(
+            *paths.borrow(),
+            vec!["root > connection(0)", "root > connection(1)"],
+        );
+        assert_eq!(context.scope_path(), "");
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::{Cell, RefCell},
+    ops::{Deref, DerefMut},
+    error::Error,
+    fmt,
+};
+use super::context::Context;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScopeExpired {
+    // the yields of the computation when it was put, and now
+    pub put_at: u64,
+    pub now: u64,
+}
+
+impl fmt::Display for ScopeExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "scoped output put at yield {} is used at yield {}, \
+             it is valid only until the next yield",
+            self.put_at, self.now,
+        )
+    }
+}
+
+impl Error for ScopeExpired {}
+
+// An output the handler lends to the computation rather than gives, it is not
+// copied into the context. It is valid until the computation yields again,
+// the view does not borrow, so holding it longer is caught here.
+pub struct Scoped<X> {
+    value: Rc<X>,
+    put_at: u64,
+    epoch: Rc<Cell<u64>>,
+}
+
+impl<X> Clone for Scoped<X> {
+    fn clone(&self) -> Self {
+        Scoped {
+            value: self.value.clone(),
+            put_at: self.put_at,
+            epoch: self.epoch.clone(),
+        }
+    }
+}
+
+impl<X> fmt::Debug for Scoped<X>
+where
+    X: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Ok(value) => f.debug_tuple("Scoped").field(value).finish(),
+            Err(_) => f.debug_tuple("Scoped").field(&"expired").finish(),
+        }
+    }
+}
+
+impl<X> Scoped<X> {
+    pub fn get(&self) -> Result<&X, ScopeExpired> {
+        let now = self.epoch.get();
+        if now == self.put_at {
+            Ok(&self.value)
+        } else {
+            Err(ScopeExpired {
+                put_at: self.put_at,
+                now,
+            })
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.get().is_err()
+    }
+}
+
+impl<T> Context<T> {
+    /// Puts an output lending `value` for the rest of this round, `wrap` makes
+    /// it an output, usually it is the variant. The computation reads it through
+    /// `Scoped::get` before it yields next, after that it gets `ScopeExpired`.
+    ///
+    /// ```
+    /// use aeiou::{BufferPool, Context, Scoped};
+    ///
+    /// let pool = BufferPool::new(4096);
+    /// let context = Context::<Scoped<_>>::empty();
+    /// let mut buffer = pool.take();
+    /// buffer.extend_from_slice(b"payload");
+    /// context.put_scoped(buffer, |view| view);
+    /// let view = context.take().unwrap();
+    /// assert_eq!(&view.get().unwrap()[..], b"payload");
+    /// ```
+    pub fn put_scoped<X, F>(&self, value: X, wrap: F)
+    where
+        F: FnOnce(Scoped<X>) -> T,
+    {
+        let epoch = self.epoch();
+        let view = Scoped {
+            value: Rc::new(value),
+            put_at: epoch.get(),
+            epoch,
+        };
+        self.put(wrap(view));
+    }
+}
+
+#[derive(Default)]
+struct PoolState {
+    free: RefCell<Vec<Vec<u8>>>,
+    allocated: Cell<usize>,
+    reused: Cell<usize>,
+    outstanding: Cell<usize>,
+}
+
+// Buffers a handler reads into and lends with `Context::put_scoped`, a buffer
+// goes back to the pool when the last view of it drops.
+#[derive(Clone)]
+pub struct BufferPool {
+    capacity: usize,
+    state: Rc<PoolState>,
+}
+
+impl BufferPool {
+    // the capacity a new buffer is allocated with
+    pub fn new(capacity: usize) -> Self {
+        BufferPool {
+            capacity,
+            state: Rc::default(),
+        }
+    }
+
+    // an empty buffer, a free one if there is any
+    pub fn take(&self) -> PooledBuf {
+        let state = &self.state;
+        let data = match state.free.borrow_mut().pop() {
+            Some(data) => {
+                state.reused.set(state.reused.get() + 1);
+                data
+            },
+            None => {
+                state.allocated.set(state.allocated.get() + 1);
+                Vec::with_capacity(self.capacity)
+            },
+        };
+        state.outstanding.set(state.outstanding.get() + 1);
+        PooledBuf {
+            data,
+            pool: state.clone(),
+        }
+    }
+
+    // the buffers allocated and reused so far, and the ones not back yet
+    pub fn allocated(&self) -> usize {
+        self.state.allocated.get()
+    }
+
+    pub fn reused(&self) -> usize {
+        self.state.reused.get()
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.state.outstanding.get()
+    }
+}
+
+pub struct PooledBuf {
+    data: Vec<u8>,
+    pool: Rc<PoolState>,
+}
+
+impl fmt::Debug for PooledBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledBuf")
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.data
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        let mut data = std::mem::take(&mut self.data);
+        data.clear();
+        self.pool.free.borrow_mut().push(data);
+        self.pool.outstanding.set(self.pool.outstanding.get() - 1);
+    }
+}
@@ -0,0 +1,313 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    pin::Pin,
+    collections::VecDeque,
+    marker::PhantomData,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::{Block, IntoBlock},
+    computation::{Effect, Select},
+    context::Context,
+    sealed::Sealed,
+};
+
+type Continuation<E> = Box<dyn FnOnce(&Context<E>, Script<E>) -> Script<E>>;
+
+pub struct ScriptStep<E>
+where
+    E: Effect,
+{
+    effect: E::Input,
+    then: Option<Continuation<E>>,
+}
+
+impl<E> ScriptStep<E>
+where
+    E: Effect,
+{
+    pub fn new(effect: E::Input) -> Self {
+        ScriptStep { effect, then: None }
+    }
+
+    // steps returned by `f` run right after this one, before the rest of the script
+    pub fn expecting<P, F>(effect: E::Input, f: F) -> Self
+    where
+        E: Select<P> + 'static,
+        P: 'static,
+        F: FnOnce(P, Script<E>) -> Script<E> + 'static,
+    {
+        let then = Box::new(move |context: &Context<E>, script| {
+            let part = E::take(context).expect("unexpected output for script step");
+            f(part, script)
+        });
+        ScriptStep {
+            effect,
+            then: Some(then),
+        }
+    }
+}
+
+pub struct Script<E>
+where
+    E: Effect,
+{
+    steps: VecDeque<ScriptStep<E>>,
+}
+
+impl<E> Default for Script<E>
+where
+    E: Effect,
+{
+    fn default() -> Self {
+        Script {
+            steps: VecDeque::new(),
+        }
+    }
+}
+
+impl<E> From<Vec<ScriptStep<E>>> for Script<E>
+where
+    E: Effect,
+{
+    fn from(steps: Vec<ScriptStep<E>>) -> Self {
+        Script {
+            steps: steps.into(),
+        }
+    }
+}
+
+impl<E> Script<E>
+where
+    E: Effect,
+{
+    pub fn new() -> Self {
+        Script::default()
+    }
+
+    pub fn step(mut self, step: ScriptStep<E>) -> Self {
+        self.steps.push_back(step);
+        self
+    }
+
+    pub fn perform(self, effect: E::Input) -> Self {
+        self.step(ScriptStep::new(effect))
+    }
+
+    pub fn expecting<P>(self) -> Expecting<E, P> {
+        Expecting {
+            script: self,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+pub struct Expecting<E, P>
+where
+    E: Effect,
+{
+    script: Script<E>,
+    phantom: PhantomData<P>,
+}
+
+impl<E, P> Expecting<E, P>
+where
+    E: Select<P> + 'static,
+    P: 'static,
+{
+    // attaches `f` to the last performed effect, panics if there is none
+    pub fn then<F>(self, f: F) -> Script<E>
+    where
+        F: FnOnce(P, Script<E>) -> Script<E> + 'static,
+    {
+        let mut script = self.script;
+        let last = script
+            .steps
+            .pop_back()
+            .expect("`expecting` must follow `perform`");
+        script.step(ScriptStep::expecting(last.effect, f))
+    }
+}
+
+pub struct ScriptRunner<E>
+where
+    E: Effect,
+{
+    context: Context<E>,
+    steps: VecDeque<ScriptStep<E>>,
+    pending: Option<Continuation<E>>,
+    produced: Option<u64>,
+}
+
+// the runner is never structurally pinned, effects are moved out on each resume
+impl<E> Unpin for ScriptRunner<E> where E: Effect {}
+
+impl<E> Generator<()> for ScriptRunner<E>
+where
+    E: Effect,
+{
+    type Yield = E::Input;
+    type Return = ();
+
+    fn resume(self: Pin<&mut Self>, _: ()) -> GeneratorState<Self::Yield, Self::Return> {
+        let this = self.get_mut();
+        // nothing can observe the responses to steps without `expecting`
+        if let Some(before) = this.produced.take() {
+            for _ in before..this.context.produced() {
+                this.context.take_last();
+            }
+        }
+        if let Some(then) = this.pending.take() {
+            let more = then(&this.context, Script::new());
+            for step in more.steps.into_iter().rev() {
+                this.steps.push_front(step);
+            }
+        }
+        match this.steps.pop_front() {
+            Some(ScriptStep { effect, then }) => {
+                if then.is_none() {
+                    this.produced = Some(this.context.produced());
+                }
+                this.pending = then;
+                GeneratorState::Yielded(effect)
+            },
+            None => GeneratorState::Complete(()),
+        }
+    }
+}
+
+impl<E> Sealed<(E, ScriptRunner<E>)> for Script<E> where E: Effect {}
+
+impl<E> IntoBlock<E, ScriptRunner<E>> for Script<E>
+where
+    E: Effect,
+{
+    fn into_block(self) -> Block<E, ScriptRunner<E>> {
+        let context = Context::empty();
+        let runner = ScriptRunner {
+            context: context.clone(),
+            steps: self.steps,
+            pending: None,
+            produced: None,
+        };
+        Block::new(context, runner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell};
+    use crate::{Context, Effect, Select, IntoBlock, perform};
+    use super::{Script, ScriptStep};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Effects {
+        ConnectTcp(u16),
+        WriteTcp(u16, String),
+        ReadTcp(u16),
+        Print(String),
+    }
+
+    enum Outputs {
+        Connected,
+        Written,
+        Read(String),
+        Printed,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    struct Read(String);
+
+    impl Select<Read> for Outputs {
+        fn take(output: &Context<Self>) -> Option<Read> {
+            match output.take()? {
+                Outputs::Read(data) => Some(Read(data)),
+                _ => None,
+            }
+        }
+    }
+
+    fn handler(log: Rc<RefCell<Vec<Effects>>>) -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+        move |effect| {
+            log.borrow_mut().push(effect.clone());
+            Ok(match effect {
+                Effects::ConnectTcp(_) => Outputs::Connected,
+                Effects::WriteTcp(..) => Outputs::Written,
+                Effects::ReadTcp(_) => Outputs::Read("3".to_string()),
+                Effects::Print(_) => Outputs::Printed,
+            })
+        }
+    }
+
+    fn trace(script: Script<Outputs>) -> Vec<Effects> {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        script
+            .into_block()
+            .add_handler(handler(log.clone()))
+            .assert_handled()
+            .run();
+        let log = log.borrow().clone();
+        log
+    }
+
+    #[test]
+    fn same_trace_as_generator() {
+        let client = |_: Context<Outputs>| {
+            move || {
+                perform!(Effects::ConnectTcp(8224));
+                perform!(Effects::WriteTcp(8224, "hello world!\n".to_string()));
+            }
+        };
+        let log = Rc::new(RefCell::new(Vec::new()));
+        client
+            .into_block()
+            .add_handler(handler(log.clone()))
+            .assert_handled()
+            .run();
+        let script = Script::new()
+            .perform(Effects::ConnectTcp(8224))
+            .perform(Effects::WriteTcp(8224, "hello world!\n".to_string()));
+        assert_eq!(trace(script), *log.borrow());
+    }
+
+    #[test]
+    fn extended_at_runtime() {
+        let script = Script::from(vec![
+            ScriptStep::new(Effects::ConnectTcp(1)),
+            ScriptStep::new(Effects::ReadTcp(1)),
+        ])
+        .expecting::<Read>()
+        .then(|Read(count), script| {
+            let count = count.parse().unwrap();
+            (0..count).fold(script, |script, i| {
+                script.perform(Effects::Print(format!("line {}", i)))
+            })
+        })
+        .perform(Effects::WriteTcp(1, "bye".to_string()));
+        assert_eq!(script.len(), 3);
+        assert_eq!(
+            trace(script),
+            vec![
+                Effects::ConnectTcp(1),
+                Effects::ReadTcp(1),
+                Effects::Print("line 0".to_string()),
+                Effects::Print("line 1".to_string()),
+                Effects::Print("line 2".to_string()),
+                Effects::WriteTcp(1, "bye".to_string()),
+            ],
+        );
+    }
+}
@@ -0,0 +1,322 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    error::Error,
+    fmt, mem,
+    rc::Rc,
+    cell::{Cell, RefCell},
+    collections::BTreeSet,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    computation::Effect,
+    context::Context,
+    accounting::{Accounting, Category},
+};
+
+// the part answering the opening effect
+pub trait SessionKey {
+    type Key;
+
+    fn session_key(&self) -> Self::Key;
+}
+
+// The effect enum knows which of its fields is the session key, and how to
+// close a session whose handle is dropped.
+pub trait WithSession: Sized {
+    type Key;
+
+    fn key(&self) -> Option<&Self::Key>;
+
+    fn close_session(key: Self::Key) -> Self;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionError {
+    Closed { generation: u64 },
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Closed { generation } => {
+                write!(f, "session of generation {} is closed", generation)
+            },
+        }
+    }
+}
+
+impl Error for SessionError {}
+
+// the open generations of the context and the close effects of the dropped
+// sessions, waiting for `close_sessions`
+struct Sessions<I> {
+    next: Cell<u64>,
+    open: RefCell<BTreeSet<u64>>,
+    cleanups: RefCell<Vec<I>>,
+    // the one of the context the last session was opened in
+    accounting: RefCell<Option<Accounting>>,
+}
+
+impl<I> Sessions<I> {
+    fn new() -> Self {
+        Sessions {
+            next: Cell::new(0),
+            open: RefCell::new(BTreeSet::new()),
+            cleanups: RefCell::new(Vec::new()),
+            accounting: RefCell::new(None),
+        }
+    }
+
+    // a session is a registered cleanup until its close effect is performed
+    fn account<F>(&self, f: F)
+    where
+        F: FnOnce(&Accounting),
+    {
+        if let Some(accounting) = &*self.accounting.borrow() {
+            f(accounting)
+        }
+    }
+
+    fn close(&self, generation: u64) -> Result<(), SessionError> {
+        if self.open.borrow_mut().remove(&generation) {
+            Ok(())
+        } else {
+            Err(SessionError::Closed { generation })
+        }
+    }
+}
+
+// shared by the clones of a handle, the last one dropped closes the session
+struct Guard<I>
+where
+    I: WithSession,
+    I::Key: Clone,
+{
+    generation: u64,
+    key: I::Key,
+    sessions: Rc<Sessions<I>>,
+}
+
+impl<I> Drop for Guard<I>
+where
+    I: WithSession,
+    I::Key: Clone,
+{
+    fn drop(&mut self) {
+        if self.sessions.close(self.generation).is_ok() {
+            let effect = I::close_session(self.key.clone());
+            self.sessions.cleanups.borrow_mut().push(effect);
+        }
+    }
+}
+
+pub struct Session<I>
+where
+    I: WithSession,
+    I::Key: Clone,
+{
+    guard: Rc<Guard<I>>,
+}
+
+impl<I> Clone for Session<I>
+where
+    I: WithSession,
+    I::Key: Clone,
+{
+    fn clone(&self) -> Self {
+        Session {
+            guard: self.guard.clone(),
+        }
+    }
+}
+
+impl<I> fmt::Debug for Session<I>
+where
+    I: WithSession,
+    I::Key: Clone + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("generation", &self.guard.generation)
+            .field("key", &self.guard.key)
+            .field("open", &self.is_open())
+            .finish()
+    }
+}
+
+impl<I> Session<I>
+where
+    I: WithSession + 'static,
+    I::Key: Clone,
+{
+    // usually through `open_session!`
+    pub fn open<E>(context: &Context<E>, key: I::Key) -> Self
+    where
+        E: Effect<Input = I>,
+    {
+        let sessions = context.extension(Sessions::<I>::new);
+        let generation = sessions.next.get();
+        sessions.next.set(generation + 1);
+        sessions.open.borrow_mut().insert(generation);
+        *sessions.accounting.borrow_mut() = context.accounting();
+        sessions.account(|a| a.insert(Category::Cleanups));
+        Session {
+            guard: Rc::new(Guard {
+                generation,
+                key,
+                sessions,
+            }),
+        }
+    }
+}
+
+impl<I> Session<I>
+where
+    I: WithSession,
+    I::Key: Clone,
+{
+    pub fn generation(&self) -> u64 {
+        self.guard.generation
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.guard
+            .sessions
+            .open
+            .borrow()
+            .contains(&self.guard.generation)
+    }
+
+    pub fn key(&self) -> Result<&I::Key, SessionError> {
+        if self.is_open() {
+            Ok(&self.guard.key)
+        } else {
+            Err(SessionError::Closed {
+                generation: self.guard.generation,
+            })
+        }
+    }
+
+    // the effect with the key of the session injected
+    pub fn effect<F>(&self, constructor: F) -> Result<I, SessionError>
+    where
+        F: FnOnce(I::Key) -> I,
+    {
+        self.key().cloned().map(constructor)
+    }
+
+    // The effect closing the session, any clone of the handle is stale from
+    // now on and nothing is closed on drop.
+    pub fn close_with<F>(self, constructor: F) -> Result<I, SessionError>
+    where
+        F: FnOnce(I::Key) -> I,
+    {
+        self.guard.sessions.close(self.guard.generation)?;
+        self.guard
+            .sessions
+            .account(|a| a.remove(Category::Cleanups));
+        Ok(constructor(self.guard.key.clone()))
+    }
+
+    pub fn close(self) -> Result<I, SessionError> {
+        self.close_with(I::close_session)
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    E::Input: WithSession + 'static,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Yields the close effect of every session dropped without `close!`, right
+    // after the resume that dropped it. Their responses go to the orphan
+    // callback.
+    pub fn close_sessions(
+        self,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>> {
+        let context = self.context();
+        let sessions = context.extension(Sessions::<E::Input>::new);
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            move || loop {
+                let state = s.resume();
+                let cleanups = mem::take(&mut *sessions.cleanups.borrow_mut());
+                for effect in cleanups {
+                    sessions.account(|a| a.remove(Category::Cleanups));
+                    let before = context.produced();
+                    yield effect;
+                    for _ in before..context.produced() {
+                        if let Some(response) = context.take_last() {
+                            context.orphan(response);
+                        }
+                    }
+                }
+                match state {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(effect) => yield effect,
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+// Performs the opening effect and evaluates to the session keyed by the part
+// answering it.
+#[macro_export]
+macro_rules! open_session {
+    ($ctx:expr, $e:expr => $part_ty:ty) => {{
+        let part: $part_ty = $crate::perform!($e, $ctx);
+        $crate::Session::open($ctx, $crate::SessionKey::session_key(&part))
+    }};
+}
+
+// Evaluates to `Err(SessionError)` if the session is closed, nothing is
+// yielded then. With a context the part is taken like in `perform!`.
+#[macro_export]
+macro_rules! perform_in {
+    ($session:expr, $constructor:expr, $ctx:expr) => {
+        match $crate::Session::effect(&$session, $constructor) {
+            ::std::result::Result::Ok(effect) => {
+                yield effect;
+                ::std::result::Result::Ok($crate::take_part!(
+                    $crate::Select::try_take($ctx),
+                    $constructor
+                ))
+            },
+            ::std::result::Result::Err(error) => ::std::result::Result::Err(error),
+        }
+    };
+    ($session:expr, $constructor:expr) => {
+        match $crate::Session::effect(&$session, $constructor) {
+            ::std::result::Result::Ok(effect) => {
+                yield effect;
+                ::std::result::Result::Ok(())
+            },
+            ::std::result::Result::Err(error) => ::std::result::Result::Err(error),
+        }
+    };
+}
+
+// Consumes the handle and performs the close variant, or the given
+// constructor, evaluates to the response.
+#[macro_export]
+macro_rules! close {
+    ($session:expr, $ctx:expr) => {
+        $crate::close!($session, $ctx, $crate::WithSession::close_session)
+    };
+    ($session:expr, $ctx:expr, $constructor:expr) => {
+        match $crate::Session::close_with($session, $constructor) {
+            ::std::result::Result::Ok(effect) => {
+                yield effect;
+                ::std::result::Result::Ok($crate::Context::take($ctx))
+            },
+            ::std::result::Result::Err(error) => ::std::result::Result::Err(error),
+        }
+    };
+}
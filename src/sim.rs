@@ -0,0 +1,228 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+use super::deadline::TestClock;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnId(pub u32);
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetEffect {
+    Accept,
+    Read(ConnId),
+    Write(ConnId, Vec<u8>),
+    Close(ConnId),
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetOutput {
+    Accepted(ConnId),
+    // every client is accepted, nobody connects anymore
+    Shutdown,
+    Data(Vec<u8>),
+    // nothing to read this round
+    Pending,
+    // the client sent its whole script or the connection is closed,
+    // until it is closed the client still receives
+    Closed,
+    Written,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientStep {
+    Send(Vec<u8>),
+    // the reads pending on the waiting client advance the clock, if there is one
+    Wait(Duration),
+}
+
+impl ClientStep {
+    pub fn send<B>(bytes: B) -> Self
+    where
+        B: Into<Vec<u8>>,
+    {
+        ClientStep::Send(bytes.into())
+    }
+}
+
+#[derive(Default)]
+struct Client {
+    steps: VecDeque<ClientStep>,
+    received: Vec<u8>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Inner {
+    clients: BTreeMap<ConnId, Client>,
+    connecting: VecDeque<ConnId>,
+    names: BTreeMap<String, ConnId>,
+    clock: Option<TestClock>,
+}
+
+// An in-memory network of scripted clients, every read gets the next step of
+// the client's script. It runs on one thread and is the same on every run.
+#[derive(Clone, Default)]
+pub struct VirtualNet(Rc<RefCell<Inner>>);
+
+impl VirtualNet {
+    pub fn new() -> Self {
+        VirtualNet::default()
+    }
+
+    pub fn with_clock(clock: TestClock) -> Self {
+        let net = VirtualNet::default();
+        net.0.borrow_mut().clock = Some(clock);
+        net
+    }
+
+    // clients are accepted in the order they connect
+    pub fn connect<I>(&self, steps: I) -> ConnId
+    where
+        I: IntoIterator<Item = ClientStep>,
+    {
+        let mut inner = self.0.borrow_mut();
+        let id = ConnId(inner.clients.len() as u32);
+        inner.clients.insert(
+            id,
+            Client {
+                steps: steps.into_iter().collect(),
+                ..Client::default()
+            },
+        );
+        inner.connecting.push_back(id);
+        id
+    }
+
+    // a client the others find by name, see `resolve::StaticResolverHandler::for_net`
+    pub fn connect_named<I>(&self, name: &str, steps: I) -> ConnId
+    where
+        I: IntoIterator<Item = ClientStep>,
+    {
+        let id = self.connect(steps);
+        self.0.borrow_mut().names.insert(name.to_string(), id);
+        id
+    }
+
+    pub fn names(&self) -> Vec<(String, ConnId)> {
+        let inner = self.0.borrow();
+        inner.names.iter().map(|(n, id)| (n.clone(), *id)).collect()
+    }
+
+    // the address of a client is made of its id
+    pub fn ip(id: ConnId) -> IpAddr {
+        let [_, _, high, low] = id.0.to_be_bytes();
+        IpAddr::V4(Ipv4Addr::new(10, 0, high, low))
+    }
+
+    pub fn conn_at(ip: IpAddr) -> Option<ConnId> {
+        match ip {
+            IpAddr::V4(ip) => match ip.octets() {
+                [10, 0, high, low] => Some(ConnId(u32::from(u16::from_be_bytes([high, low])))),
+                _ => None,
+            },
+            IpAddr::V6(_) => None,
+        }
+    }
+
+    pub fn received(&self, id: ConnId) -> Vec<u8> {
+        self.0
+            .borrow()
+            .clients
+            .get(&id)
+            .map(|c| c.received.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn is_closed(&self, id: ConnId) -> bool {
+        self.0.borrow().clients.get(&id).map_or(true, |c| c.closed)
+    }
+
+    pub fn handle(&self, effect: NetEffect) -> NetOutput {
+        let mut inner = self.0.borrow_mut();
+        let Inner {
+            clients,
+            connecting,
+            clock,
+            ..
+        } = &mut *inner;
+        match effect {
+            NetEffect::Accept => match connecting.pop_front() {
+                Some(id) => NetOutput::Accepted(id),
+                None => NetOutput::Shutdown,
+            },
+            NetEffect::Read(id) => match clients.get_mut(&id) {
+                Some(client) if !client.closed => match client.steps.pop_front() {
+                    Some(ClientStep::Send(bytes)) => NetOutput::Data(bytes),
+                    Some(ClientStep::Wait(by)) => {
+                        if let Some(clock) = clock {
+                            clock.advance(by);
+                        }
+                        NetOutput::Pending
+                    },
+                    None => NetOutput::Closed,
+                },
+                _ => NetOutput::Closed,
+            },
+            NetEffect::Write(id, bytes) => match clients.get_mut(&id) {
+                Some(client) if !client.closed => {
+                    client.received.extend_from_slice(&bytes);
+                    NetOutput::Written
+                },
+                _ => NetOutput::Closed,
+            },
+            NetEffect::Close(id) => {
+                if let Some(client) = clients.get_mut(&id) {
+                    client.closed = true;
+                }
+                NetOutput::Closed
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::deadline::TestClock;
+    use super::{VirtualNet, ClientStep, NetEffect, NetOutput, ConnId};
+
+    #[test]
+    fn scripted_client() {
+        let clock = TestClock::install();
+        let start = clock.now();
+        let net = VirtualNet::with_clock(clock.clone());
+        let id = net.connect(vec![
+            ClientStep::send("ping"),
+            ClientStep::Wait(Duration::from_secs(2)),
+        ]);
+        assert_eq!(net.handle(NetEffect::Accept), NetOutput::Accepted(id));
+        assert_eq!(net.handle(NetEffect::Accept), NetOutput::Shutdown);
+        assert_eq!(
+            net.handle(NetEffect::Read(id)),
+            NetOutput::Data(b"ping".to_vec())
+        );
+        let write = NetEffect::Write(id, b"pong".to_vec());
+        assert_eq!(net.handle(write.clone()), NetOutput::Written);
+        assert_eq!(net.handle(NetEffect::Read(id)), NetOutput::Pending);
+        assert_eq!(clock.now() - start, Duration::from_secs(2));
+        assert_eq!(net.handle(NetEffect::Read(id)), NetOutput::Closed);
+        assert!(!net.is_closed(id));
+        assert_eq!(net.handle(write.clone()), NetOutput::Written);
+        assert_eq!(net.handle(NetEffect::Close(id)), NetOutput::Closed);
+        assert_eq!(net.handle(write), NetOutput::Closed);
+        assert_eq!(net.received(id), b"pongpong");
+        assert!(net.is_closed(id));
+        assert!(net.is_closed(ConnId(7)));
+        TestClock::uninstall();
+    }
+}
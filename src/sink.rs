@@ -0,0 +1,357 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::{Cell, RefCell},
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block, computation::Handler, context::Context, progress::HasProgress, two_phase::OpId,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkState {
+    Ready,
+    // the chunk is taken, but the handler should wait for the sink to be polled
+    Full,
+}
+
+type Ready<E> = Box<dyn FnMut(&ResponseSink<E>)>;
+
+struct Shared<E> {
+    done: Cell<bool>,
+    ready: RefCell<Option<Ready<E>>>,
+}
+
+// The response of one operation in chunks, sent as progress of the operation
+// and ended with the completion. At most `limit` outputs of the operation wait
+// in the context, a handler that sees `SinkState::Full` goes on in `on_ready`.
+pub struct ResponseSink<E> {
+    id: OpId,
+    limit: usize,
+    context: Context<E>,
+    shared: Rc<Shared<E>>,
+}
+
+impl<E> Clone for ResponseSink<E> {
+    fn clone(&self) -> Self {
+        ResponseSink {
+            id: self.id,
+            limit: self.limit,
+            context: self.context.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<E> ResponseSink<E> {
+    pub fn id(&self) -> OpId {
+        self.id
+    }
+
+    // the completion, chunks sent after it go to the orphan callback
+    pub fn finish(&self, output: E) {
+        self.shared.done.set(true);
+        self.shared.ready.borrow_mut().take();
+        self.context.put(output);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.shared.done.get()
+    }
+
+    // called by the streaming layer every round the sink has room, until the
+    // sink is finished
+    pub fn on_ready<F>(&self, f: F)
+    where
+        F: FnMut(&ResponseSink<E>) + 'static,
+    {
+        *self.shared.ready.borrow_mut() = Some(Box::new(f));
+    }
+
+    fn poll(&self) {
+        let ready = self.shared.ready.borrow_mut().take();
+        if let Some(mut ready) = ready {
+            ready(self);
+            let mut slot = self.shared.ready.borrow_mut();
+            if slot.is_none() && !self.is_done() {
+                *slot = Some(ready);
+            }
+        }
+    }
+}
+
+impl<E> ResponseSink<E>
+where
+    E: HasProgress,
+{
+    pub fn send(&self, chunk: E::Progress) -> SinkState {
+        let output = E::progress(self.id, chunk);
+        if self.is_done() {
+            self.context.orphan(output);
+            return SinkState::Ready;
+        }
+        self.context.put(output);
+        self.state()
+    }
+
+    pub fn state(&self) -> SinkState {
+        // the acknowledgement waits in the context too until it is taken
+        let pending = self
+            .context
+            .count_if(|o| o.is_progress() == Some(self.id) || o.is_ack() == Some(self.id));
+        if pending >= self.limit {
+            SinkState::Full
+        } else {
+            SinkState::Ready
+        }
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: HasProgress,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Like `add_progress_handler`, the effect is acknowledged with an `OpId`
+    // and `Handler::handle_streaming` gets the sink of the operation. Before
+    // every resume the sinks that have room again are polled.
+    pub fn add_streaming_handler<H>(
+        self,
+        limit: usize,
+        handler: H,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>
+    where
+        H: Handler<E>,
+    {
+        let context = self.context();
+        let mut handler = handler;
+        let mut sinks = Vec::<ResponseSink<E>>::new();
+        let mut next = 0;
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            move || loop {
+                sinks.retain(|sink| !sink.is_done());
+                for sink in &sinks {
+                    if sink.state() == SinkState::Ready {
+                        sink.poll();
+                    }
+                }
+                match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(effect) => {
+                        let sink = ResponseSink {
+                            id: OpId(next),
+                            limit,
+                            context: context.clone(),
+                            shared: Rc::new(Shared {
+                                done: Cell::new(false),
+                                ready: RefCell::new(None),
+                            }),
+                        };
+                        match handler.handle_streaming(effect, sink.clone()) {
+                            Ok(()) => {
+                                s.put(E::ack(OpId(next)));
+                                sinks.push(sink);
+                                next += 1;
+                            },
+                            Err(unhandled) => yield unhandled,
+                        }
+                    },
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::{Generator, GeneratorState};
+    use crate::{
+        Block, Category, Context, Effect, Handler, IntoBlock, Progressing, Select, TakeResult,
+        TwoPhase, perform_with_progress,
+    };
+    use super::{OpId, HasProgress, ResponseSink, SinkState};
+
+    #[derive(Debug)]
+    enum Effects {
+        ReadAll(u32, u32),
+        Idle,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Accepted(OpId),
+        Chunk(OpId, u32),
+        Eof(OpId),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl TwoPhase for Outputs {
+        fn ack(id: OpId) -> Self {
+            Outputs::Accepted(id)
+        }
+
+        fn is_ack(&self) -> Option<OpId> {
+            match self {
+                Outputs::Accepted(id) => Some(*id),
+                _ => None,
+            }
+        }
+
+        fn is_completion(&self) -> Option<OpId> {
+            match self {
+                Outputs::Eof(id) => Some(*id),
+                _ => None,
+            }
+        }
+    }
+
+    impl HasProgress for Outputs {
+        type Progress = u32;
+
+        fn progress(id: OpId, chunk: u32) -> Self {
+            Outputs::Chunk(id, chunk)
+        }
+
+        fn is_progress(&self) -> Option<OpId> {
+            match self {
+                Outputs::Chunk(id, _) => Some(*id),
+                _ => None,
+            }
+        }
+    }
+
+    struct Chunk(u32);
+
+    struct Eof;
+
+    impl Select<Chunk> for Outputs {
+        fn try_take(output: &Context<Self>) -> TakeResult<Chunk, Self> {
+            match output.take() {
+                Some(Outputs::Chunk(_, n)) => TakeResult::Matched(Chunk(n)),
+                Some(other) => TakeResult::Mismatched(other),
+                None => TakeResult::Empty,
+            }
+        }
+    }
+
+    impl Select<Eof> for Outputs {
+        fn try_take(output: &Context<Self>) -> TakeResult<Eof, Self> {
+            match output.take() {
+                Some(Outputs::Eof(_)) => TakeResult::Matched(Eof),
+                Some(other) => TakeResult::Mismatched(other),
+                None => TakeResult::Empty,
+            }
+        }
+    }
+
+    // streams the numbers of the range as fast as the sink lets it
+    struct Reader;
+
+    impl Handler<Outputs> for Reader {
+        fn handle(&mut self, effect: Effects) -> Result<Outputs, Effects> {
+            Err(effect)
+        }
+
+        fn handle_streaming(
+            &mut self,
+            effect: Effects,
+            sink: ResponseSink<Outputs>,
+        ) -> Result<(), Effects> {
+            let (mut next, end) = match effect {
+                Effects::ReadAll(start, end) => (start, end),
+                other => return Err(other),
+            };
+            sink.on_ready(move |sink| {
+                while next < end {
+                    next += 1;
+                    if sink.send(next - 1) == SinkState::Full {
+                        return;
+                    }
+                }
+                sink.finish(Outputs::Eof(sink.id()));
+            });
+            Ok(())
+        }
+    }
+
+    type Stream = Progressing<Chunk, Eof>;
+
+    // takes at most `per_round` chunks of each stream before it yields
+    fn reader(
+        context: Context<Outputs>,
+        ranges: Vec<(u32, u32)>,
+        per_round: usize,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = Vec<Vec<u32>>> {
+        move || {
+            let mut streams = Vec::<(Stream, Vec<u32>, bool)>::new();
+            for (start, end) in ranges.clone() {
+                let stream = perform_with_progress!(Effects::ReadAll(start, end), &context);
+                streams.push((stream, Vec::new(), false));
+            }
+            while streams.iter().any(|(_, _, done)| !done) {
+                for (stream, chunks, done) in streams.iter_mut().filter(|(_, _, done)| !done) {
+                    for _ in 0..per_round {
+                        match stream.progress(&context) {
+                            Some(Chunk(n)) => chunks.push(n),
+                            // the completion drops the chunks left, there are none now
+                            None => {
+                                *done = stream.result(&context).is_some();
+                                break;
+                            },
+                        }
+                    }
+                }
+                yield Effects::Idle;
+            }
+            streams.into_iter().map(|(_, chunks, _)| chunks).collect()
+        }
+    }
+
+    fn drive<G>(block: Block<Outputs, G>) -> G::Return
+    where
+        G: Unpin + Generator<(), Yield = Effects>,
+    {
+        let mut block = block;
+        loop {
+            match block.resume() {
+                GeneratorState::Complete(r) => break r,
+                GeneratorState::Yielded(Effects::Idle) => (),
+                GeneratorState::Yielded(e) => panic!("unhandled: {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn bounded() {
+        let (block, accounting) = (|context| reader(context, vec![(0, 1000)], 3))
+            .into_block()
+            .add_streaming_handler(10, Reader)
+            .with_accounting();
+        let streams = drive(block);
+        assert_eq!(streams, [(0..1000).collect::<Vec<_>>()]);
+        if cfg!(feature = "diagnostics") {
+            assert!(accounting.report().get(Category::Outputs).high_water <= 10);
+        }
+    }
+
+    #[test]
+    fn streams_do_not_cross() {
+        let streams = drive(
+            (|context| reader(context, vec![(0, 50), (1000, 1050)], 2))
+                .into_block()
+                .add_streaming_handler(4, Reader),
+        );
+        assert_eq!(
+            streams,
+            [(0..50).collect::<Vec<_>>(), (1000..1050).collect()]
+        );
+    }
+}
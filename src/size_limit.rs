@@ -0,0 +1,485 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    accounting::Counter,
+    block::Block,
+    computation::{Effect, EffectName},
+};
+
+// the size `#[derive(PayloadSize)]` measures, the sum of the `len()` of the
+// fields marked `#[payload]`
+pub trait PayloadSize {
+    fn payload_size(&self) -> usize;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PayloadTooLarge {
+    // the variant name of the rejected output or effect
+    pub class: &'static str,
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of size {} exceeds the limit {}",
+            self.class, self.size, self.limit
+        )
+    }
+}
+
+impl Error for PayloadTooLarge {}
+
+pub trait HasPayloadTooLarge
+where
+    Self: Effect,
+{
+    fn payload_too_large(error: PayloadTooLarge) -> Self;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Truncation {
+    pub from: usize,
+    pub to: usize,
+}
+
+// where a truncated value records it, `WithMeta` keeps it in the metadata
+pub trait MarkTruncated {
+    fn mark_truncated(&mut self, truncation: Truncation) {
+        let _ = truncation;
+    }
+}
+
+enum Policy<T> {
+    Reject,
+    Truncate(Box<dyn Fn(T, usize) -> T>),
+    Allow,
+}
+
+pub struct SizeLimit<T> {
+    max: usize,
+    policy: Policy<T>,
+}
+
+impl<T> SizeLimit<T> {
+    // a larger output becomes a `PayloadTooLarge` output, a larger effect is
+    // answered with it and never yielded
+    pub fn reject(max: usize) -> Self {
+        SizeLimit {
+            max,
+            policy: Policy::Reject,
+        }
+    }
+
+    // `shrink` is given the value and the limit
+    pub fn truncate<F>(max: usize, shrink: F) -> Self
+    where
+        F: Fn(T, usize) -> T + 'static,
+    {
+        SizeLimit {
+            max,
+            policy: Policy::Truncate(Box::new(shrink)),
+        }
+    }
+
+    // only counted
+    pub fn allow(max: usize) -> Self {
+        SizeLimit {
+            max,
+            policy: Policy::Allow,
+        }
+    }
+}
+
+enum Checked<T> {
+    Fits(T),
+    Oversized(T),
+    Truncated(T),
+    Rejected(PayloadTooLarge),
+}
+
+impl<T> Checked<T> {
+    fn counter(&self) -> Option<Counter> {
+        match self {
+            Checked::Fits(_) => None,
+            Checked::Oversized(_) => Some(Counter::PayloadOversized),
+            Checked::Truncated(_) => Some(Counter::PayloadTruncated),
+            Checked::Rejected(_) => Some(Counter::PayloadRejected),
+        }
+    }
+}
+
+struct Limits<T> {
+    measure: fn(&T) -> usize,
+    default: Option<SizeLimit<T>>,
+    classes: BTreeMap<&'static str, SizeLimit<T>>,
+}
+
+impl<T> Limits<T>
+where
+    T: EffectName + MarkTruncated,
+{
+    fn new(measure: fn(&T) -> usize) -> Self {
+        Limits {
+            measure,
+            default: None,
+            classes: BTreeMap::new(),
+        }
+    }
+
+    // a rejected value is dropped here
+    fn check(&self, value: T) -> Checked<T> {
+        let class = value.effect_name();
+        let limit = match self.classes.get(class).or(self.default.as_ref()) {
+            Some(limit) => limit,
+            None => return Checked::Fits(value),
+        };
+        let size = (self.measure)(&value);
+        if size <= limit.max {
+            return Checked::Fits(value);
+        }
+        match &limit.policy {
+            Policy::Reject => Checked::Rejected(PayloadTooLarge {
+                class,
+                size,
+                limit: limit.max,
+            }),
+            Policy::Truncate(shrink) => {
+                let mut value = shrink(value, limit.max);
+                let to = (self.measure)(&value);
+                value.mark_truncated(Truncation { from: size, to });
+                Checked::Truncated(value)
+            },
+            Policy::Allow => Checked::Oversized(value),
+        }
+    }
+}
+
+// The limits of the outputs and of the effects of a block, by the variant
+// name with a default for the others. Without a limit nothing is measured.
+pub struct SizeLimits<E>
+where
+    E: Effect,
+{
+    outputs: Limits<E>,
+    effects: Limits<E::Input>,
+}
+
+impl<E> SizeLimits<E>
+where
+    E: Effect + EffectName + MarkTruncated,
+    E::Input: EffectName + MarkTruncated,
+{
+    pub fn new(outputs: fn(&E) -> usize, effects: fn(&E::Input) -> usize) -> Self {
+        SizeLimits {
+            outputs: Limits::new(outputs),
+            effects: Limits::new(effects),
+        }
+    }
+
+    pub fn outputs(mut self, limit: SizeLimit<E>) -> Self {
+        self.outputs.default = Some(limit);
+        self
+    }
+
+    pub fn effects(mut self, limit: SizeLimit<E::Input>) -> Self {
+        self.effects.default = Some(limit);
+        self
+    }
+
+    pub fn output_class(mut self, class: &'static str, limit: SizeLimit<E>) -> Self {
+        self.outputs.classes.insert(class, limit);
+        self
+    }
+
+    pub fn effect_class(mut self, class: &'static str, limit: SizeLimit<E::Input>) -> Self {
+        self.effects.classes.insert(class, limit);
+        self
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + EffectName + HasPayloadTooLarge + MarkTruncated + 'static,
+    E::Input: EffectName + MarkTruncated,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Install right after `into_block`. The outputs are checked as they are
+    // put, before the watches of the context see them, the effects as the
+    // computation yields them, before the layers outside. A rejected effect
+    // is answered with the rejection in place of a response.
+    pub fn with_size_limits(
+        self,
+        limits: SizeLimits<E>,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>> {
+        let SizeLimits { outputs, effects } = limits;
+        let context = self.context();
+        context.set_guard(Box::new(move |output| {
+            let checked = outputs.check(output);
+            let counter = checked.counter();
+            let output = match checked {
+                Checked::Fits(output) | Checked::Oversized(output) | Checked::Truncated(output) => {
+                    output
+                },
+                Checked::Rejected(error) => E::payload_too_large(error),
+            };
+            (output, counter)
+        }));
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            move || loop {
+                let effect = match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(effect) => effect,
+                };
+                let checked = effects.check(effect);
+                if let Some(counter) = checked.counter() {
+                    context.account(|a| a.bump(counter));
+                }
+                match checked {
+                    Checked::Fits(effect)
+                    | Checked::Oversized(effect)
+                    | Checked::Truncated(effect) => yield effect,
+                    Checked::Rejected(error) => context.put(E::payload_too_large(error)),
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{Context, Counter, Effect, EffectName, IntoBlock, ObserverConfig, WithMeta};
+    use super::{HasPayloadTooLarge, MarkTruncated, PayloadTooLarge, SizeLimit, SizeLimits, Truncation};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Op {
+        Upload(Vec<u8>),
+        Fetch(usize),
+        Log(String),
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Out {
+        Stored(usize),
+        Blob(Vec<u8>, Option<Truncation>),
+        Logged,
+        TooLarge(PayloadTooLarge),
+    }
+
+    impl Effect for Out {
+        type Input = WithMeta<Op>;
+    }
+
+    impl EffectName for Op {
+        fn effect_name(&self) -> &'static str {
+            match self {
+                Op::Upload(_) => "Upload",
+                Op::Fetch(_) => "Fetch",
+                Op::Log(_) => "Log",
+            }
+        }
+    }
+
+    impl EffectName for Out {
+        fn effect_name(&self) -> &'static str {
+            match self {
+                Out::Stored(_) => "Stored",
+                Out::Blob(..) => "Blob",
+                Out::Logged => "Logged",
+                Out::TooLarge(_) => "TooLarge",
+            }
+        }
+    }
+
+    impl HasPayloadTooLarge for Out {
+        fn payload_too_large(error: PayloadTooLarge) -> Self {
+            Out::TooLarge(error)
+        }
+    }
+
+    impl MarkTruncated for Out {
+        fn mark_truncated(&mut self, truncation: Truncation) {
+            if let Out::Blob(_, flag) = self {
+                *flag = Some(truncation);
+            }
+        }
+    }
+
+    fn measure_output(output: &Out) -> usize {
+        match output {
+            Out::Blob(data, _) => data.len(),
+            _ => 0,
+        }
+    }
+
+    fn measure_effect(effect: &WithMeta<Op>) -> usize {
+        match &effect.effect {
+            Op::Upload(data) => data.len(),
+            Op::Log(line) => line.len(),
+            Op::Fetch(_) => 0,
+        }
+    }
+
+    // the effects the handler got, with their metadata
+    type Handled = Rc<RefCell<Vec<WithMeta<Op>>>>;
+
+    fn handler(handled: &Handled) -> impl FnMut(WithMeta<Op>) -> Result<Out, WithMeta<Op>> {
+        let handled = handled.clone();
+        move |effect| {
+            handled.borrow_mut().push(effect.clone());
+            Ok(match effect.effect {
+                Op::Upload(data) => Out::Stored(data.len()),
+                Op::Fetch(size) => Out::Blob(vec![7; size], None),
+                Op::Log(_) => Out::Logged,
+            })
+        }
+    }
+
+    fn perform_all(
+        context: Context<Out>,
+        ops: Vec<Op>,
+        outputs: Rc<RefCell<Vec<Out>>>,
+    ) -> impl Unpin + Generator<(), Yield = WithMeta<Op>, Return = ()> {
+        move || {
+            for op in ops {
+                yield WithMeta::new(op);
+                outputs.borrow_mut().extend(context.take());
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_rejected() {
+        let outputs = Rc::new(RefCell::new(Vec::new()));
+        let handled = Handled::default();
+        let traced = Rc::new(RefCell::new(Vec::new()));
+        let limits = SizeLimits::new(measure_output, measure_effect)
+            .outputs(SizeLimit::reject(16))
+            .effects(SizeLimit::reject(16));
+        let ops = vec![Op::Fetch(1 << 20), Op::Upload(vec![0; 1000]), Op::Fetch(4)];
+        let (block, accounting) = {
+            let outputs = outputs.clone();
+            move |context| perform_all(context, ops, outputs)
+        }
+        .into_block()
+        .with_size_limits(limits)
+        .observe(ObserverConfig::named("trace"), {
+            let traced = traced.clone();
+            move |output, _| traced.borrow_mut().push(format!("{:?}", output))
+        })
+        .with_accounting();
+        block.add_handler(handler(&handled)).assert_handled().run();
+
+        let expected = vec![
+            Out::TooLarge(PayloadTooLarge {
+                class: "Blob",
+                size: 1 << 20,
+                limit: 16,
+            }),
+            Out::TooLarge(PayloadTooLarge {
+                class: "Upload",
+                size: 1000,
+                limit: 16,
+            }),
+            Out::Blob(vec![7; 4], None),
+        ];
+        assert_eq!(*outputs.borrow(), expected);
+        // the upload never left the block, the large blob was never traced
+        assert_eq!(handled.borrow().len(), 2);
+        assert!(traced.borrow().iter().all(|t| t.len() < 100));
+        if cfg!(feature = "diagnostics") {
+            assert_eq!(traced.borrow().len(), 3);
+            assert_eq!(accounting.report().count(Counter::PayloadRejected), 2);
+        }
+    }
+
+    #[test]
+    fn truncated_and_flagged() {
+        let outputs = Rc::new(RefCell::new(Vec::new()));
+        let handled = Handled::default();
+        let limits = SizeLimits::new(measure_output, measure_effect)
+            .outputs(SizeLimit::truncate(16, |output: Out, max| match output {
+                Out::Blob(mut data, flag) => {
+                    data.truncate(max);
+                    Out::Blob(data, flag)
+                },
+                output => output,
+            }))
+            .effects(SizeLimit::truncate(8, |mut effect: WithMeta<Op>, max| {
+                if let Op::Upload(data) = &mut effect.effect {
+                    data.truncate(max);
+                }
+                effect
+            }));
+        let ops = vec![Op::Fetch(100), Op::Upload(vec![1; 100])];
+        {
+            let outputs = outputs.clone();
+            move |context| perform_all(context, ops, outputs)
+        }
+        .into_block()
+        .with_size_limits(limits)
+        .add_handler(handler(&handled))
+        .assert_handled()
+        .run();
+
+        let blob = Out::Blob(vec![7; 16], Some(Truncation { from: 100, to: 16 }));
+        assert_eq!(*outputs.borrow(), [blob, Out::Stored(8)]);
+        let handled = handled.borrow();
+        assert_eq!(handled[0].meta.truncated, None);
+        assert_eq!(
+            handled[1].meta.truncated,
+            Some(Truncation { from: 100, to: 8 })
+        );
+    }
+
+    #[test]
+    fn class_overrides_default() {
+        let outputs = Rc::new(RefCell::new(Vec::new()));
+        let handled = Handled::default();
+        let limits = SizeLimits::new(measure_output, measure_effect)
+            .outputs(SizeLimit::reject(8))
+            .output_class("Blob", SizeLimit::allow(8))
+            .effects(SizeLimit::reject(8))
+            .effect_class("Log", SizeLimit::reject(64));
+        let ops = vec![
+            Op::Log("twenty characters ok".to_string()),
+            Op::Upload(vec![0; 20]),
+            Op::Fetch(20),
+        ];
+        let (block, accounting) = {
+            let outputs = outputs.clone();
+            move |context| perform_all(context, ops, outputs)
+        }
+        .into_block()
+        .with_size_limits(limits)
+        .with_accounting();
+        block.add_handler(handler(&handled)).assert_handled().run();
+
+        let rejected = Out::TooLarge(PayloadTooLarge {
+            class: "Upload",
+            size: 20,
+            limit: 8,
+        });
+        let expected = vec![Out::Logged, rejected, Out::Blob(vec![7; 20], None)];
+        assert_eq!(*outputs.borrow(), expected);
+        if cfg!(feature = "diagnostics") {
+            let report = accounting.report();
+            assert_eq!(report.count(Counter::PayloadRejected), 1);
+            assert_eq!(report.count(Counter::PayloadOversized), 1);
+        }
+    }
+}
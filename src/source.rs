@@ -0,0 +1,403 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::ops::{Generator, GeneratorState};
+use super::block::Block;
+
+// a source is cut off after this many items in a round, whatever its budget
+const POLL_CAP: usize = 1024;
+
+// Polled before every round until it has nothing, a source that is done is
+// not polled again. Any `FnMut() -> Option<T>` closure is a source.
+pub trait Source<T> {
+    fn poll(&mut self) -> Option<T>;
+
+    fn is_done(&self) -> bool {
+        false
+    }
+}
+
+impl<T, F> Source<T> for F
+where
+    F: FnMut() -> Option<T>,
+{
+    fn poll(&mut self) -> Option<T> {
+        self()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourcePoll<T> {
+    Item(T),
+    Empty,
+    // the source is not polled again
+    Terminated,
+}
+
+// The source of `with_sources`. A `Source` terminates once it is done and
+// has nothing more.
+pub trait OutputSource<T> {
+    fn poll(&mut self) -> SourcePoll<T>;
+}
+
+impl<T, S> OutputSource<T> for S
+where
+    S: Source<T>,
+{
+    fn poll(&mut self) -> SourcePoll<T> {
+        match Source::poll(self) {
+            Some(item) => SourcePoll::Item(item),
+            None if self.is_done() => SourcePoll::Terminated,
+            None => SourcePoll::Empty,
+        }
+    }
+}
+
+// how many items each source may put in a round
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceFairness {
+    // one item of every source
+    RoundRobin,
+    // as many items of every source as its weight
+    WeightedBudget(Vec<u32>),
+    // every source until it is empty, in the order they are given, like
+    // `add_source` does
+    DrainInOrder,
+}
+
+impl SourceFairness {
+    fn budget(&self, index: usize) -> usize {
+        let budget = match self {
+            SourceFairness::RoundRobin => 1,
+            SourceFairness::WeightedBudget(weights) => weights[index] as usize,
+            SourceFairness::DrainInOrder => POLL_CAP,
+        };
+        budget.min(POLL_CAP)
+    }
+}
+
+struct Polled<T> {
+    index: usize,
+    source: Box<dyn OutputSource<T>>,
+    // polled after the budget ran out, it goes first in the next round
+    held: Option<T>,
+}
+
+impl<T, G> Block<T, G>
+where
+    G: Unpin + Generator<()>,
+{
+    pub fn add_source<S>(
+        self,
+        source: S,
+    ) -> Block<T, impl Unpin + Generator<(), Return = G::Return, Yield = G::Yield>>
+    where
+        S: Source<T>,
+    {
+        let context = self.context();
+        context.open_source();
+        let mut source = Some(source);
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            move || loop {
+                if let Some(active) = source.as_mut() {
+                    while let Some(output) = active.poll() {
+                        context.put(output);
+                    }
+                    if active.is_done() {
+                        source = None;
+                        context.source_done();
+                    }
+                }
+                match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(y) => yield y,
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+impl<T, G> Block<T, G>
+where
+    G: Unpin + Generator<()>,
+{
+    // Polls the sources before every round, each one as far as `policy`
+    // allows. A terminated source is dropped, the context closes once all of
+    // them are. The accounting gets the stats of every source by its index.
+    pub fn with_sources(
+        self,
+        sources: Vec<Box<dyn OutputSource<T>>>,
+        policy: SourceFairness,
+    ) -> Block<T, impl Unpin + Generator<(), Return = G::Return, Yield = G::Yield>> {
+        if let SourceFairness::WeightedBudget(weights) = &policy {
+            assert_eq!(weights.len(), sources.len(), "a weight for every source");
+        }
+        let context = self.context();
+        context.open_source();
+        let mut sources = sources
+            .into_iter()
+            .enumerate()
+            .map(|(index, source)| Polled {
+                index,
+                source,
+                held: None,
+            })
+            .collect::<Vec<_>>();
+        let mut open = true;
+        let mut round = 0;
+        let mut s = self;
+        let generator = {
+            let context = context.clone();
+            move || loop {
+                if open {
+                    round += 1;
+                    let mut i = 0;
+                    while i < sources.len() {
+                        let polled = &mut sources[i];
+                        let budget = policy.budget(polled.index);
+                        let mut delivered = 0;
+                        let mut terminated = false;
+                        while delivered < budget {
+                            let output = match polled.held.take() {
+                                Some(output) => output,
+                                None => match polled.source.poll() {
+                                    SourcePoll::Item(output) => output,
+                                    SourcePoll::Empty => break,
+                                    SourcePoll::Terminated => {
+                                        terminated = true;
+                                        break;
+                                    },
+                                },
+                            };
+                            context.put(output);
+                            delivered += 1;
+                        }
+                        // the budget ran out, one more poll tells if the
+                        // source had to wait
+                        let mut starved = false;
+                        if !terminated && delivered == budget {
+                            if polled.held.is_some() {
+                                starved = true;
+                            } else {
+                                match polled.source.poll() {
+                                    SourcePoll::Item(output) => {
+                                        polled.held = Some(output);
+                                        starved = true;
+                                    },
+                                    SourcePoll::Empty => (),
+                                    SourcePoll::Terminated => terminated = true,
+                                }
+                            }
+                        }
+                        let index = polled.index;
+                        context.account(|a| {
+                            a.source(index, |stats| {
+                                stats.delivered += delivered as u64;
+                                stats.starved += starved as u64;
+                                if terminated {
+                                    stats.terminated = Some(round);
+                                }
+                            })
+                        });
+                        if terminated {
+                            sources.remove(i);
+                        } else {
+                            i += 1;
+                        }
+                    }
+                    if sources.is_empty() {
+                        open = false;
+                        context.source_done();
+                    }
+                }
+                match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(y) => yield y,
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, collections::VecDeque};
+    use crate::{Context, Effect, IntoBlock, recv};
+    use super::{OutputSource, SourceFairness, SourcePoll};
+
+    #[derive(Debug)]
+    enum Effects {
+        Idle,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        A(u32),
+        B(u32),
+        Idled,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    // never empty
+    fn flood(output: fn(u32) -> Outputs) -> Box<dyn OutputSource<Outputs>> {
+        let mut next = 0;
+        Box::new(move || {
+            next += 1;
+            Some(output(next - 1))
+        })
+    }
+
+    struct Finite(VecDeque<Outputs>);
+
+    impl OutputSource<Outputs> for Finite {
+        fn poll(&mut self) -> SourcePoll<Outputs> {
+            match self.0.pop_front() {
+                Some(output) => SourcePoll::Item(output),
+                None => SourcePoll::Terminated,
+            }
+        }
+    }
+
+    #[test]
+    fn round_robin_interleaves() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let slow = Rc::new(RefCell::new(VecDeque::new()));
+        let computation = {
+            let seen = seen.clone();
+            move |context: Context<Outputs>| {
+                move || {
+                    while seen.borrow().len() < 7 {
+                        while let Some(output) = context.take() {
+                            if output != Outputs::Idled {
+                                seen.borrow_mut().push(output);
+                            }
+                        }
+                        yield Effects::Idle;
+                    }
+                }
+            }
+        };
+        let sources = vec![flood(Outputs::A), {
+            let slow = slow.clone();
+            Box::new(move || slow.borrow_mut().pop_front()) as Box<dyn OutputSource<_>>
+        }];
+        let (block, accounting) = computation
+            .into_block()
+            .with_sources(sources, SourceFairness::RoundRobin)
+            .with_accounting();
+        let mut idles = 0;
+        block
+            .add_handler(move |Effects::Idle| {
+                idles += 1;
+                if idles % 2 == 0 {
+                    slow.borrow_mut().push_back(Outputs::B(idles / 2 - 1));
+                }
+                Ok(Outputs::Idled)
+            })
+            .assert_handled()
+            .run();
+
+        // the slow one gets its item in right after it has it
+        let expected = [
+            Outputs::A(0),
+            Outputs::A(1),
+            Outputs::A(2),
+            Outputs::B(0),
+            Outputs::A(3),
+            Outputs::A(4),
+            Outputs::B(1),
+        ];
+        assert_eq!(*seen.borrow(), expected);
+        if cfg!(feature = "diagnostics") {
+            let report = accounting.report();
+            // it is polled once more before the computation returns
+            assert_eq!(
+                (report.source(0).delivered, report.source(0).starved),
+                (6, 6)
+            );
+            assert_eq!(
+                (report.source(1).delivered, report.source(1).starved),
+                (2, 0)
+            );
+        }
+    }
+
+    #[test]
+    fn weighted_ratio() {
+        let seen = Rc::new(RefCell::new([0, 0]));
+        let computation = {
+            let seen = seen.clone();
+            move |context: Context<Outputs>| {
+                move || {
+                    for _ in 0..10 {
+                        while let Some(output) = context.take() {
+                            match output {
+                                Outputs::A(_) => seen.borrow_mut()[0] += 1,
+                                Outputs::B(_) => seen.borrow_mut()[1] += 1,
+                                Outputs::Idled => (),
+                            }
+                        }
+                        yield Effects::Idle;
+                    }
+                }
+            }
+        };
+        computation
+            .into_block()
+            .with_sources(
+                vec![flood(Outputs::A), flood(Outputs::B)],
+                SourceFairness::WeightedBudget(vec![3, 1]),
+            )
+            .add_handler(|Effects::Idle| Ok(Outputs::Idled))
+            .assert_handled()
+            .run();
+
+        assert_eq!(*seen.borrow(), [30, 10]);
+    }
+
+    #[test]
+    fn exhausted_sources_close() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let computation = {
+            let seen = seen.clone();
+            move |context: Context<Outputs>| {
+                move || {
+                    while let Some(output) = recv!(&context, Effects::Idle) {
+                        if output != Outputs::Idled {
+                            seen.borrow_mut().push(output);
+                        }
+                    }
+                }
+            }
+        };
+        let sources = vec![
+            Box::new(Finite(vec![Outputs::A(1), Outputs::A(2)].into())) as Box<dyn OutputSource<_>>,
+            Box::new(Finite(vec![Outputs::B(1)].into())),
+        ];
+        let (block, accounting) = computation
+            .into_block()
+            .with_sources(sources, SourceFairness::RoundRobin)
+            .with_accounting();
+        block
+            .add_handler(|Effects::Idle| Ok(Outputs::Idled))
+            .assert_handled()
+            .run();
+
+        assert_eq!(
+            *seen.borrow(),
+            [Outputs::A(1), Outputs::B(1), Outputs::A(2)],
+        );
+        if cfg!(feature = "diagnostics") {
+            let report = accounting.report();
+            assert_eq!(report.source(0).terminated, Some(2));
+            assert_eq!(report.source(1).terminated, Some(1));
+        }
+    }
+}
@@ -0,0 +1,393 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    io::{self, Read},
+    sync::mpsc::{Receiver, TryRecvError},
+};
+use super::source::Source;
+
+// the terminal output of a source, `None` is a clean end of the stream
+pub trait HasDisconnected {
+    fn disconnected(error: Option<io::Error>) -> Self;
+}
+
+pub struct MpscSource<T, F> {
+    rx: Receiver<T>,
+    map: F,
+    done: bool,
+}
+
+impl<T, F> MpscSource<T, F> {
+    pub fn new(rx: Receiver<T>, map: F) -> Self {
+        MpscSource {
+            rx,
+            map,
+            done: false,
+        }
+    }
+}
+
+impl<T, E, F> Source<E> for MpscSource<T, F>
+where
+    E: HasDisconnected,
+    F: Fn(T) -> E,
+{
+    fn poll(&mut self) -> Option<E> {
+        if self.done {
+            return None;
+        }
+        match self.rx.try_recv() {
+            Ok(item) => Some((self.map)(item)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.done = true;
+                Some(E::disconnected(None))
+            },
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+// one item per round, then the terminal output
+pub struct IterSource<I> {
+    iter: Option<I>,
+    delivered: bool,
+}
+
+impl<I> IterSource<I> {
+    pub fn new<S>(iter: S) -> Self
+    where
+        S: IntoIterator<IntoIter = I>,
+    {
+        IterSource {
+            iter: Some(iter.into_iter()),
+            delivered: false,
+        }
+    }
+}
+
+impl<I> Source<I::Item> for IterSource<I>
+where
+    I: Iterator,
+    I::Item: HasDisconnected,
+{
+    fn poll(&mut self) -> Option<I::Item> {
+        if self.delivered {
+            self.delivered = false;
+            return None;
+        }
+        let iter = self.iter.as_mut()?;
+        self.delivered = true;
+        match iter.next() {
+            Some(item) => Some(item),
+            None => {
+                self.iter = None;
+                Some(<I::Item>::disconnected(None))
+            },
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.iter.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    // the frame excludes the `\n` and a `\r` before it,
+    // trailing bytes without a newline are the last frame
+    Lines,
+    // a big endian `u32` length before every frame
+    LengthPrefixed,
+}
+
+impl Framing {
+    fn split(&self, buffer: &mut Vec<u8>, eof: bool) -> Option<Vec<u8>> {
+        match self {
+            Framing::Lines => {
+                let mut frame = match buffer.iter().position(|b| *b == b'\n') {
+                    Some(end) => {
+                        let mut frame = buffer.drain(..=end).collect::<Vec<_>>();
+                        frame.pop();
+                        frame
+                    },
+                    None if eof && !buffer.is_empty() => std::mem::take(buffer),
+                    None => return None,
+                };
+                if frame.last() == Some(&b'\r') {
+                    frame.pop();
+                }
+                Some(frame)
+            },
+            Framing::LengthPrefixed => {
+                let header = buffer.get(..4)?;
+                let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+                let end = 4 + length as usize;
+                if buffer.len() < end {
+                    return None;
+                }
+                let frame = buffer[4..end].to_vec();
+                buffer.drain(..end);
+                Some(frame)
+            },
+        }
+    }
+}
+
+// Frames bytes that arrive in chunks from a transport that is not `Read`,
+// such as the responses to read effects.
+#[derive(Debug, Clone)]
+pub struct FrameDecoder {
+    framing: Framing,
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new(framing: Framing) -> Self {
+        FrameDecoder {
+            framing,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        self.framing.split(&mut self.buffer, false)
+    }
+
+    // at the end of the stream, what `next_frame` keeps waiting for;
+    // the error is the bytes of a frame cut by the end
+    pub fn finish(&mut self) -> Result<Option<Vec<u8>>, Vec<u8>> {
+        let frame = self.framing.split(&mut self.buffer, true);
+        if frame.is_none() && !self.buffer.is_empty() {
+            return Err(std::mem::take(&mut self.buffer));
+        }
+        Ok(frame)
+    }
+}
+
+// The reader must be non-blocking, `WouldBlock` means nothing this round. A
+// read error or a frame cut by the end of the stream is the terminal output.
+pub struct ReadSource<R, F> {
+    reader: R,
+    decoder: FrameDecoder,
+    map: F,
+    eof: bool,
+    done: bool,
+}
+
+impl<R, F> ReadSource<R, F> {
+    pub fn new(reader: R, framing: Framing, map: F) -> Self {
+        ReadSource {
+            reader,
+            decoder: FrameDecoder::new(framing),
+            map,
+            eof: false,
+            done: false,
+        }
+    }
+}
+
+impl<R, E, F> Source<E> for ReadSource<R, F>
+where
+    R: Read,
+    E: HasDisconnected,
+    F: Fn(Vec<u8>) -> E,
+{
+    fn poll(&mut self) -> Option<E> {
+        if self.done {
+            return None;
+        }
+        let mut chunk = [0; 0x1000];
+        loop {
+            if self.eof {
+                return match self.decoder.finish() {
+                    Ok(Some(frame)) => Some((self.map)(frame)),
+                    Ok(None) => {
+                        self.done = true;
+                        Some(E::disconnected(None))
+                    },
+                    Err(_) => {
+                        self.done = true;
+                        Some(E::disconnected(Some(io::ErrorKind::UnexpectedEof.into())))
+                    },
+                };
+            }
+            if let Some(frame) = self.decoder.next_frame() {
+                return Some((self.map)(frame));
+            }
+            match self.reader.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(read) => self.decoder.push(&chunk[..read]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => (),
+                Err(error) => {
+                    self.done = true;
+                    return Some(E::disconnected(Some(error)));
+                },
+            }
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, rc::Rc, cell::RefCell, sync::mpsc};
+    use crate::{Context, Effect, IntoBlock, BoxedGenerator};
+    use super::{HasDisconnected, MpscSource, ReadSource, Framing, FrameDecoder};
+
+    #[derive(Debug)]
+    enum Effects {
+        Idle,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Item(u32),
+        Frame(String),
+        Idled,
+        Disconnected(Option<io::ErrorKind>),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl HasDisconnected for Outputs {
+        fn disconnected(error: Option<io::Error>) -> Self {
+            Outputs::Disconnected(error.map(|e| e.kind()))
+        }
+    }
+
+    // collects every output until the terminal one, idling when there is none
+    fn collect(
+        seen: Rc<RefCell<Vec<Outputs>>>,
+    ) -> impl FnOnce(Context<Outputs>) -> BoxedGenerator<Effects, ()> {
+        move |context| {
+            Box::new(move || loop {
+                match context.take() {
+                    None | Some(Outputs::Idled) => yield Effects::Idle,
+                    Some(output) => {
+                        let last = matches!(output, Outputs::Disconnected(_));
+                        seen.borrow_mut().push(output);
+                        if last {
+                            break;
+                        }
+                    },
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn mpsc_in_order() {
+        let (tx, rx) = mpsc::channel();
+        let mut tx = Some(tx);
+        let mut next = 0;
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        collect(seen.clone())
+            .into_block()
+            .add_source(MpscSource::new(rx, Outputs::Item))
+            .add_handler(move |Effects::Idle| {
+                next += 1;
+                match next {
+                    1 => (),
+                    2..=3 => tx.as_ref().unwrap().send(next).unwrap(),
+                    _ => drop(tx.take()),
+                }
+                Ok(Outputs::Idled)
+            })
+            .assert_handled()
+            .run();
+        assert_eq!(
+            *seen.borrow(),
+            [
+                Outputs::Item(2),
+                Outputs::Item(3),
+                Outputs::Disconnected(None)
+            ],
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_source_lines() {
+        use std::{io::Write, os::unix::net::UnixStream};
+
+        let (reader, writer) = UnixStream::pair().unwrap();
+        reader.set_nonblocking(true).unwrap();
+        let mut writer = Some(writer);
+        let mut chunks = vec![&b"hel"[..], b"lo\r\nwor", b"ld\n\nl", b"ast"].into_iter();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let source = ReadSource::new(reader, Framing::Lines, |frame| {
+            Outputs::Frame(String::from_utf8(frame).unwrap())
+        });
+        collect(seen.clone())
+            .into_block()
+            .add_source(source)
+            .add_handler(move |Effects::Idle| {
+                match chunks.next() {
+                    Some(chunk) => writer.as_mut().unwrap().write_all(chunk).unwrap(),
+                    None => drop(writer.take()),
+                }
+                Ok(Outputs::Idled)
+            })
+            .assert_handled()
+            .run();
+        let frames = ["hello", "world", "", "last"];
+        let mut expected = frames
+            .iter()
+            .map(|f| Outputs::Frame(f.to_string()))
+            .collect::<Vec<_>>();
+        expected.push(Outputs::Disconnected(None));
+        assert_eq!(*seen.borrow(), expected);
+    }
+
+    #[test]
+    fn length_prefix_cut() {
+        let bytes = [&[0, 0, 0, 2][..], b"ab", &[0, 0, 0, 5], b"cd"].concat();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let source = ReadSource::new(&bytes[..], Framing::LengthPrefixed, |frame| {
+            Outputs::Frame(String::from_utf8(frame).unwrap())
+        });
+        collect(seen.clone())
+            .into_block()
+            .add_source(source)
+            .add_handler(|Effects::Idle| Ok(Outputs::Idled))
+            .assert_handled()
+            .run();
+        assert_eq!(
+            *seen.borrow(),
+            [
+                Outputs::Frame("ab".to_string()),
+                Outputs::Disconnected(Some(io::ErrorKind::UnexpectedEof)),
+            ],
+        );
+    }
+
+    #[test]
+    fn decoder_chunks() {
+        let mut decoder = FrameDecoder::new(Framing::Lines);
+        decoder.push(b"GET a\r\nSE");
+        assert_eq!(decoder.next_frame(), Some(b"GET a".to_vec()));
+        assert_eq!(decoder.next_frame(), None);
+        decoder.push(b"T a 1");
+        assert_eq!(decoder.finish(), Ok(Some(b"SET a 1".to_vec())));
+        assert_eq!(decoder.finish(), Ok(None));
+
+        let mut decoder = FrameDecoder::new(Framing::LengthPrefixed);
+        decoder.push(&[0, 0, 0, 3, b'x']);
+        assert_eq!(decoder.finish(), Err(vec![0, 0, 0, 3, b'x']));
+    }
+}
@@ -0,0 +1,462 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{fmt::Write, ops::Generator, time::Duration};
+#[cfg(feature = "diagnostics")]
+use std::{
+    rc::Rc,
+    cell::{Cell, RefCell},
+    time::Instant,
+    ops::GeneratorState,
+};
+use super::{block::Block, context::Context};
+#[cfg(feature = "diagnostics")]
+use super::deadline;
+
+const BUCKETS: usize = 64;
+
+// The samples counted in 64 buckets with power-of-two boundaries, the bucket
+// `i` holds the values from `2^(i - 1)` up to `2^i - 1`, the first one only
+// zero and the last one everything above. Recording a sample is a shift and
+// an increment, the percentiles are as precise as the buckets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn bucket(value: u64) -> usize {
+        ((64 - value.leading_zeros()) as usize).min(BUCKETS - 1)
+    }
+
+    // the largest value the bucket holds
+    pub fn upper(bucket: usize) -> u64 {
+        match bucket {
+            0 => 0,
+            b if b >= BUCKETS - 1 => u64::MAX,
+            b => (1 << b) - 1,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.buckets[Self::bucket(value)] += 1;
+        self.count += 1;
+        self.max = self.max.max(value);
+    }
+
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    // the upper boundary of the bucket the sample of the rank is in, no more
+    // than the largest sample, zero without samples
+    pub fn percentile(&self, percent: u32) -> u64 {
+        let rank = ((self.count * u64::from(percent) + 99) / 100).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::upper(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.max = self.max.max(other.max);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    // the wall time of the round by the installed time source, microseconds
+    RoundTime,
+    // the effects the computation yielded in the round
+    Effects,
+    // the outputs put since the previous round ended
+    Outputs,
+    TasksResumed,
+    // the time `paced` waited for the start of the round, microseconds
+    Parked,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 5] = [
+        Metric::RoundTime,
+        Metric::Effects,
+        Metric::Outputs,
+        Metric::TasksResumed,
+        Metric::Parked,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::RoundTime => "round_us",
+            Metric::Effects => "effects",
+            Metric::Outputs => "outputs",
+            Metric::TasksResumed => "tasks",
+            Metric::Parked => "parked_us",
+        }
+    }
+}
+
+// a histogram of every metric, a sample of each per round
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsReport([Histogram; 5]);
+
+impl StatsReport {
+    pub fn rounds(&self) -> u64 {
+        self.get(Metric::RoundTime).count()
+    }
+
+    pub fn get(&self, metric: Metric) -> &Histogram {
+        &self.0[metric as usize]
+    }
+
+    #[cfg_attr(not(feature = "diagnostics"), allow(dead_code))]
+    fn record(&mut self, metric: Metric, value: u64) {
+        self.0[metric as usize].record(value);
+    }
+
+    // the reports of several runs add up like their samples would
+    pub fn merge(&mut self, other: &StatsReport) {
+        for (histogram, other) in self.0.iter_mut().zip(other.0.iter()) {
+            histogram.merge(other);
+        }
+    }
+
+    pub fn render_text(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(
+            text,
+            "{:<10}{:>8}{:>8}{:>8}{:>8}{:>8}",
+            "metric", "samples", "p50", "p90", "p99", "max"
+        );
+        for &metric in &Metric::ALL {
+            let histogram = self.get(metric);
+            let _ = writeln!(
+                text,
+                "{:<10}{:>8}{:>8}{:>8}{:>8}{:>8}",
+                metric.name(),
+                histogram.count(),
+                histogram.percentile(50),
+                histogram.percentile(90),
+                histogram.percentile(99),
+                histogram.max(),
+            );
+        }
+        text
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+#[derive(Default)]
+struct Recorder {
+    report: RefCell<StatsReport>,
+    effects: Cell<u64>,
+    tasks: Cell<u64>,
+    parked: Cell<Duration>,
+    // what the context produced when the previous round ended
+    produced: Cell<u64>,
+}
+
+#[cfg(feature = "diagnostics")]
+pub(crate) struct Round {
+    recorder: Rc<Recorder>,
+    start: Instant,
+}
+
+#[cfg(not(feature = "diagnostics"))]
+pub(crate) enum Round {}
+
+#[cfg(feature = "diagnostics")]
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros().min(u128::from(u64::MAX)) as u64
+}
+
+// only the outermost resume of the block is a round
+#[cfg(feature = "diagnostics")]
+pub(crate) fn begin_round<T>(context: &Context<T>) -> Option<Round> {
+    if context.is_resuming() {
+        return None;
+    }
+    let recorder = context.find_extension::<Recorder>()?;
+    recorder.effects.set(0);
+    recorder.tasks.set(0);
+    recorder.parked.set(Duration::default());
+    Some(Round {
+        recorder,
+        start: deadline::now(),
+    })
+}
+
+#[cfg(feature = "diagnostics")]
+pub(crate) fn end_round<T>(context: &Context<T>, round: Option<Round>) {
+    let Round { recorder, start } = match round {
+        Some(round) => round,
+        None => return,
+    };
+    let produced = context.produced();
+    let elapsed = deadline::now().saturating_duration_since(start);
+    let mut report = recorder.report.borrow_mut();
+    report.record(Metric::RoundTime, micros(elapsed));
+    report.record(Metric::Effects, recorder.effects.get());
+    report.record(
+        Metric::Outputs,
+        produced - recorder.produced.replace(produced),
+    );
+    report.record(Metric::TasksResumed, recorder.tasks.get());
+    report.record(Metric::Parked, micros(recorder.parked.get()));
+}
+
+#[cfg(feature = "diagnostics")]
+pub(crate) fn task_resumed<T>(context: &Context<T>) {
+    if let Some(recorder) = context.find_extension::<Recorder>() {
+        recorder.tasks.set(recorder.tasks.get() + 1);
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+pub(crate) fn parked<T>(context: &Context<T>, duration: Duration) {
+    if let Some(recorder) = context.find_extension::<Recorder>() {
+        recorder.parked.set(recorder.parked.get() + duration);
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+fn recorded<T>(context: &Context<T>) -> StatsReport {
+    context
+        .find_extension::<Recorder>()
+        .map(|recorder| recorder.report.borrow().clone())
+        .unwrap_or_default()
+}
+
+#[cfg(not(feature = "diagnostics"))]
+#[inline(always)]
+pub(crate) fn begin_round<T>(context: &Context<T>) -> Option<Round> {
+    let _ = context;
+    None
+}
+
+#[cfg(not(feature = "diagnostics"))]
+#[inline(always)]
+pub(crate) fn end_round<T>(context: &Context<T>, round: Option<Round>) {
+    let _ = (context, round);
+}
+
+#[cfg(not(feature = "diagnostics"))]
+#[inline(always)]
+pub(crate) fn task_resumed<T>(context: &Context<T>) {
+    let _ = context;
+}
+
+#[cfg(not(feature = "diagnostics"))]
+#[inline(always)]
+pub(crate) fn parked<T>(context: &Context<T>, duration: Duration) {
+    let _ = (context, duration);
+}
+
+#[cfg(not(feature = "diagnostics"))]
+fn recorded<T>(context: &Context<T>) -> StatsReport {
+    let _ = context;
+    StatsReport::default()
+}
+
+#[cfg(feature = "diagnostics")]
+impl<T, G> Block<T, G>
+where
+    G: Unpin + Generator<()>,
+{
+    // Every resume of the block by its driver is a round, a `run` is a single
+    // one. The layer goes right after `into_block`, so it counts every effect
+    // of the computation, also the ones the handlers answer within the round.
+    // Without diagnostics the block is returned as is.
+    pub fn with_stats(
+        self,
+    ) -> Block<T, impl Unpin + Generator<(), Return = G::Return, Yield = G::Yield>> {
+        let context = self.context();
+        let recorder = context.extension(Recorder::default);
+        recorder.produced.set(context.produced());
+        let mut s = self;
+        let generator = move || loop {
+            match s.resume() {
+                GeneratorState::Complete(r) => return r,
+                GeneratorState::Yielded(effect) => {
+                    recorder.effects.set(recorder.effects.get() + 1);
+                    yield effect;
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(not(feature = "diagnostics"))]
+impl<T, G> Block<T, G>
+where
+    G: Unpin + Generator<()>,
+{
+    #[inline(always)]
+    pub fn with_stats(self) -> Self {
+        self
+    }
+}
+
+impl<T, G> Block<T, G>
+where
+    G: Unpin + Generator<()>,
+{
+    // what `with_stats` recorded so far, empty without it
+    pub fn stats(&self) -> StatsReport {
+        recorded(&self.context())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        time::Duration,
+        ops::{Generator, GeneratorState},
+    };
+    use crate::{Context, Effect, IntoBlock, deadline::TestClock, perform};
+    use super::{Histogram, Metric, StatsReport};
+
+    #[test]
+    fn buckets() {
+        let mut histogram = Histogram::default();
+        for value in [0, 1, 2, 3, 4, 7, 8, 1000, u64::MAX] {
+            histogram.record(value);
+        }
+        let expected = [(0, 1), (1, 1), (2, 2), (3, 2), (4, 1), (10, 1), (63, 1)];
+        for (bucket, count) in histogram.buckets().iter().enumerate() {
+            let expected = expected.iter().find(|(b, _)| *b == bucket);
+            assert_eq!(*count, expected.map_or(0, |(_, c)| *c), "bucket {}", bucket);
+        }
+        assert_eq!((Histogram::upper(3), Histogram::upper(10)), (7, 1023));
+        assert_eq!((histogram.count(), histogram.max()), (9, u64::MAX));
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.percentile(50), 0);
+        (1..=100).for_each(|value| histogram.record(value));
+        // 1, 2..=3, 4..=7, .., 32..=63 and 64..=100, 37 of them
+        assert_eq!(histogram.buckets()[7], 37);
+        assert_eq!(histogram.percentile(0), 1);
+        assert_eq!(histogram.percentile(10), 15);
+        assert_eq!(histogram.percentile(50), 63);
+        // the bucket goes up to 127, the largest sample is less
+        assert_eq!(histogram.percentile(90), 100);
+        assert_eq!(histogram.percentile(99), 100);
+    }
+
+    #[test]
+    fn merge_associative() {
+        let report = |values: &[u64]| {
+            let mut report = StatsReport::default();
+            for &value in values {
+                for &metric in &Metric::ALL {
+                    report.record(metric, value);
+                }
+            }
+            report
+        };
+        let (a, b, c) = (report(&[0, 5]), report(&[17, 3, 3]), report(&[1 << 40]));
+        let mut left = a.clone();
+        left.merge(&b);
+        left.merge(&c);
+        let mut right = b.clone();
+        right.merge(&c);
+        let mut merged = a.clone();
+        merged.merge(&right);
+        assert_eq!(left, merged);
+        assert_eq!(left.rounds(), 6);
+        assert_eq!(left, report(&[0, 5, 17, 3, 3, 1 << 40]));
+    }
+
+    #[derive(Debug)]
+    enum Effects {
+        // takes the microseconds of the simulated clock
+        Work(u64),
+        Tick,
+    }
+
+    #[derive(Debug)]
+    enum Outputs {
+        Done,
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    // a round of work in bursts, the tick ends the round
+    fn bursts(
+        context: Context<Outputs>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            for &burst in &[1, 3, 0, 2] {
+                for _ in 0..burst {
+                    perform!(Effects::Work(10));
+                    context.take();
+                }
+                perform!(Effects::Tick);
+            }
+        }
+    }
+
+    #[test]
+    fn simulated_run() {
+        let clock = TestClock::install();
+        let mut block = bursts.into_block().with_stats().add_handler({
+            let clock = clock.clone();
+            move |effect| match effect {
+                Effects::Work(us) => {
+                    clock.advance(Duration::from_micros(us));
+                    Ok(Outputs::Done)
+                },
+                tick => Err(tick),
+            }
+        });
+        while let GeneratorState::Yielded(Effects::Tick) = block.resume() {}
+        TestClock::uninstall();
+        let stats = block.stats();
+        if !cfg!(feature = "diagnostics") {
+            assert_eq!(stats, StatsReport::default());
+            return;
+        }
+        // the last round completes the computation
+        assert_eq!(stats.rounds(), 5);
+        let effects = stats.get(Metric::Effects);
+        assert_eq!(&effects.buckets()[..4], [1, 1, 2, 1]);
+        assert_eq!(
+            stats.render_text(),
+            include_str!("../tests/stats_report.txt")
+        );
+    }
+}
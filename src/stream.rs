@@ -0,0 +1,273 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    ops::{Generator, GeneratorState},
+    task::{self, Poll, Wake, Waker},
+};
+use futures_core::Stream;
+use super::{block::Block, context::Context, source::Source};
+
+struct Noop;
+
+impl Wake for Noop {
+    fn wake(self: Arc<Self>) {}
+}
+
+pub struct OutputStream<T, G>
+where
+    G: Unpin + Generator<()>,
+{
+    context: Context<T>,
+    block: Option<Block<T, G>>,
+    budget: usize,
+}
+
+impl<T, G> OutputStream<T, G>
+where
+    G: Unpin + Generator<()>,
+{
+    // Like the budget of a `Reactor`, how many times a poll may resume the
+    // block looking for an output, one by default. A poll that used all of
+    // them wakes the task to be polled again.
+    pub fn budget(self, resumes: usize) -> Self {
+        OutputStream {
+            budget: resumes.max(1),
+            ..self
+        }
+    }
+}
+
+impl<T, G> Stream for OutputStream<T, G>
+where
+    G: Unpin + Generator<()>,
+{
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<T>> {
+        let s = self.get_mut();
+        if let Some(output) = s.context.take() {
+            return Poll::Ready(Some(output));
+        }
+        let block = match s.block.as_mut() {
+            Some(block) => block,
+            None => return Poll::Ready(None),
+        };
+        s.context.set_waker(cx.waker());
+        for _ in 0..s.budget {
+            match block.resume() {
+                GeneratorState::Complete(_) => {
+                    s.block = None;
+                    return Poll::Ready(s.context.take());
+                },
+                GeneratorState::Yielded(_) => {
+                    let parked = s.context.unpark();
+                    if let Some(output) = s.context.take() {
+                        return Poll::Ready(Some(output));
+                    }
+                    // a fed stream wakes the task once it has an item
+                    if parked {
+                        return Poll::Pending;
+                    }
+                },
+            }
+        }
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+// the stream of `feed_from_stream` as a source, pending parks the block
+struct StreamSource<T, S> {
+    context: Context<T>,
+    stream: Option<S>,
+    noop: Waker,
+}
+
+impl<T, S> Source<T> for StreamSource<T, S>
+where
+    S: Unpin + Stream<Item = T>,
+{
+    fn poll(&mut self) -> Option<T> {
+        let waker = self.context.waker().unwrap_or_else(|| self.noop.clone());
+        let stream = self.stream.as_mut()?;
+        let mut cx = task::Context::from_waker(&waker);
+        match Pin::new(stream).poll_next(&mut cx) {
+            Poll::Ready(Some(item)) => Some(item),
+            Poll::Ready(None) => {
+                self.stream = None;
+                None
+            },
+            Poll::Pending => {
+                self.context.park();
+                None
+            },
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.stream.is_none()
+    }
+}
+
+impl<T, G> Block<T, G>
+where
+    G: Unpin + Generator<()>,
+{
+    pub fn output_stream(self) -> OutputStream<T, G> {
+        OutputStream {
+            context: self.context(),
+            block: Some(self),
+            budget: 1,
+        }
+    }
+
+    // Adds the stream like `add_source` adds a source, so the context closes
+    // once it ends.
+    pub fn feed_from_stream<S>(
+        self,
+        stream: S,
+    ) -> Block<T, impl Unpin + Generator<(), Return = G::Return, Yield = G::Yield>>
+    where
+        S: Unpin + Stream<Item = T>,
+    {
+        let source = StreamSource {
+            context: self.context(),
+            stream: Some(stream),
+            noop: Waker::from(Arc::new(Noop)),
+        };
+        self.add_source(source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, thread, time::Duration, task};
+    use futures::{executor, stream, channel::mpsc, task::noop_waker_ref, StreamExt};
+    use crate::{Context, Effect, IntoBlock, perform, wait_any};
+
+    #[derive(Debug)]
+    enum Effects {
+        Ping(u32),
+        Idle,
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Outputs {
+        Pong(u32),
+        Item(u32),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    fn handler(effect: Effects) -> Result<Outputs, Effects> {
+        match effect {
+            Effects::Ping(x) => Ok(Outputs::Pong(x)),
+            e => Err(e),
+        }
+    }
+
+    #[test]
+    fn unconsumed_outputs() {
+        let computation = |context: Context<Outputs>| {
+            move || {
+                yield Effects::Ping(1);
+                assert_eq!(context.take(), Some(Outputs::Pong(1)));
+                perform!(Effects::Ping(2));
+                yield Effects::Idle;
+                perform!(Effects::Ping(3));
+            }
+        };
+        let block = computation.into_block().add_handler(handler);
+        let outputs = executor::block_on(block.output_stream().collect::<Vec<_>>());
+        assert_eq!(outputs, vec![Outputs::Pong(2), Outputs::Pong(3)]);
+    }
+
+    #[test]
+    fn resumes_per_poll() {
+        let computation = |_: Context<Outputs>| {
+            move || {
+                for _ in 0..3 {
+                    yield Effects::Idle;
+                }
+                perform!(Effects::Ping(1));
+            }
+        };
+        let pending_polls = |budget| {
+            let block = computation.into_block().add_handler(handler);
+            let mut outputs = block.output_stream().budget(budget);
+            let mut cx = task::Context::from_waker(noop_waker_ref());
+            let mut pending = 0;
+            while outputs.poll_next_unpin(&mut cx).is_pending() {
+                pending += 1;
+            }
+            pending
+        };
+        assert_eq!(pending_polls(1), 3);
+        assert_eq!(pending_polls(4), 0);
+    }
+
+    #[test]
+    fn fed_stream() {
+        let observed = Rc::new(RefCell::new(Vec::new()));
+        let computation = {
+            let observed = observed.clone();
+            move |context: Context<Outputs>| {
+                move || {
+                    for _ in 0..3 {
+                        wait_any!(&context, Effects::Idle);
+                        observed.borrow_mut().push(context.take().unwrap());
+                    }
+                }
+            }
+        };
+        let items = stream::iter(vec![1, 2, 3]).map(Outputs::Item);
+        let block = computation
+            .into_block()
+            .feed_from_stream(items)
+            .add_handler(handler);
+        let outputs = executor::block_on(block.output_stream().collect::<Vec<_>>());
+        assert!(outputs.is_empty());
+        assert_eq!(
+            *observed.borrow(),
+            vec![Outputs::Item(1), Outputs::Item(2), Outputs::Item(3)],
+        );
+    }
+
+    #[test]
+    fn parked_while_waiting() {
+        let (tx, rx) = mpsc::unbounded();
+        let sender = thread::spawn(move || {
+            for i in 0..3 {
+                thread::sleep(Duration::from_millis(20));
+                tx.unbounded_send(Outputs::Item(i)).unwrap();
+            }
+        });
+        let idle = Rc::new(RefCell::new(0));
+        let computation = |context: Context<Outputs>| {
+            move || {
+                for i in 0..3 {
+                    wait_any!(&context, Effects::Idle);
+                    assert_eq!(context.take(), Some(Outputs::Item(i)));
+                }
+            }
+        };
+        let block = computation.into_block().feed_from_stream(rx).add_handler({
+            let idle = idle.clone();
+            move |effect| {
+                if let Effects::Idle = effect {
+                    *idle.borrow_mut() += 1;
+                }
+                handler(effect)
+            }
+        });
+        let outputs = executor::block_on(block.output_stream().collect::<Vec<_>>());
+        sender.join().unwrap();
+        assert!(outputs.is_empty());
+        assert!(*idle.borrow() <= 4);
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    error::Error,
+    fmt,
+    ops::{Generator, GeneratorState},
+};
+use super::{
+    block::Block,
+    computation::{Effect, Handler},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapError {
+    // the handler tried to swap itself while handling an effect
+    Reentrant,
+}
+
+impl fmt::Display for SwapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwapError::Reentrant => write!(f, "handler swapped from inside its own dispatch"),
+        }
+    }
+}
+
+impl Error for SwapError {}
+
+pub struct SwapHandle<E>(Rc<RefCell<Box<dyn Handler<E>>>>);
+
+impl<E> Clone for SwapHandle<E> {
+    fn clone(&self) -> Self {
+        SwapHandle(self.0.clone())
+    }
+}
+
+impl<E> SwapHandle<E>
+where
+    E: Effect,
+{
+    pub fn swap(&self, new: Box<dyn Handler<E>>) -> Result<Box<dyn Handler<E>>, SwapError> {
+        let mut slot = self.0.try_borrow_mut().map_err(|_| SwapError::Reentrant)?;
+        slot.finish();
+        let old = std::mem::replace(&mut *slot, new);
+        slot.init();
+        Ok(old)
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    pub fn swappable_handler(
+        self,
+        initial: Box<dyn Handler<E>>,
+    ) -> (
+        Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>,
+        SwapHandle<E>,
+    ) {
+        let handle = SwapHandle(Rc::new(RefCell::new(initial)));
+        let context = self.context();
+        let mut s = self;
+        let generator = {
+            let slot = handle.0.clone();
+            move || {
+                slot.borrow_mut().init();
+                loop {
+                    let effect = match s.resume() {
+                        GeneratorState::Complete(r) => {
+                            slot.borrow_mut().finish();
+                            return r;
+                        },
+                        GeneratorState::Yielded(effect) => effect,
+                    };
+                    let result = slot.borrow_mut().handle(effect);
+                    match result {
+                        Ok(output) => s.put(output),
+                        Err(effect) => yield effect,
+                    }
+                }
+            }
+        };
+        (Block::new(context, generator), handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{Context, Effect, Handler, IntoBlock};
+    use super::{SwapHandle, SwapError};
+
+    #[derive(Debug)]
+    enum Effects {
+        Query(u32),
+        Reload,
+    }
+
+    struct Outputs;
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    struct Counting {
+        name: &'static str,
+        log: Log,
+        handle: Rc<RefCell<Option<SwapHandle<Outputs>>>>,
+    }
+
+    impl Handler<Outputs> for Counting {
+        fn handle(&mut self, effect: Effects) -> Result<Outputs, Effects> {
+            match effect {
+                Effects::Query(n) => {
+                    self.log.borrow_mut().push(format!("{} {}", self.name, n));
+                    if let Some(handle) = &*self.handle.borrow() {
+                        let blank = Box::new(Err);
+                        assert_eq!(handle.swap(blank).err(), Some(SwapError::Reentrant));
+                    }
+                    Ok(Outputs)
+                },
+                e => Err(e),
+            }
+        }
+
+        fn init(&mut self) {
+            self.log.borrow_mut().push(format!("init {}", self.name));
+        }
+
+        fn finish(&mut self) {
+            self.log.borrow_mut().push(format!("finish {}", self.name));
+        }
+    }
+
+    fn queries(_: Context<Outputs>) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            yield Effects::Query(1);
+            yield Effects::Query(2);
+            yield Effects::Reload;
+            yield Effects::Query(3);
+        }
+    }
+
+    #[test]
+    fn swap_mid_run() {
+        let log = Log::default();
+        let handle = Rc::new(RefCell::new(None));
+        let counting = |name| {
+            Box::new(Counting {
+                name,
+                log: log.clone(),
+                handle: handle.clone(),
+            })
+        };
+        let (block, swap) = queries.into_block().swappable_handler(counting("old"));
+        *handle.borrow_mut() = Some(swap.clone());
+        let mut new = Some(counting("new"));
+        block
+            .add_handler(move |effect| match effect {
+                Effects::Reload => {
+                    let new = new.take().unwrap();
+                    swap.swap(new).map(|_| Outputs).map_err(|_| Effects::Reload)
+                },
+                e => Err(e),
+            })
+            .assert_handled()
+            .run();
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                "init old",
+                "old 1",
+                "old 2",
+                "finish old",
+                "init new",
+                "new 3",
+                "finish new"
+            ],
+        );
+    }
+}
@@ -0,0 +1,2153 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    any::Any,
+    cell::RefCell,
+    pin::Pin,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    ops::{Generator, GeneratorState},
+    collections::{BTreeMap, VecDeque, btree_map::Entry},
+    ops::Bound,
+};
+use either::Either;
+use super::{
+    block::Block,
+    cancel::CancelToken,
+    context::Context,
+    accounting::{Category, Counter},
+    identity::{self, Identity},
+    meta::EffectMeta,
+    progress, stats,
+};
+
+pub trait TaskId {
+    type Id: Eq + Ord + Clone;
+
+    fn task_id(&self) -> Self::Id;
+
+    // appended to the identity of the spawning block, a task without a name
+    // performs as the block
+    fn task_name(&self) -> Option<String> {
+        None
+    }
+}
+
+// What the body of a task needs, apart from the id it is scheduled by. The
+// task is consumed once its id and name are taken, so the args need not be
+// `Clone`.
+pub trait TaskArgs
+where
+    Self: TaskId,
+{
+    type Args;
+
+    fn task_args(self) -> Self::Args;
+}
+
+#[allow(clippy::wrong_self_convention)]
+/// A request either spawns a task or carries an effect for the handlers.
+///
+/// ```
+/// #![feature(generators, never_type)]
+/// use std::{rc::Rc, cell::RefCell};
+/// use aeiou::{Context, IntoBlock, perform_task, tasks::{Request, TaskId}};
+///
+/// enum Req {
+///     Spawn(Job),
+///     Work(u32),
+/// }
+///
+/// struct Job(u32);
+///
+/// impl TaskId for Job {
+///     type Id = u32;
+///
+///     fn task_id(&self) -> u32 {
+///         self.0
+///     }
+/// }
+///
+/// impl Request for Req {
+///     type Task = Job;
+///     type Effect = u32;
+///
+///     fn is_task(self) -> Result<Job, Self> {
+///         match self {
+///             Req::Spawn(job) => Ok(job),
+///             other => Err(other),
+///         }
+///     }
+///
+///     fn is_effect(self) -> Result<u32, Self> {
+///         match self {
+///             Req::Work(n) => Ok(n),
+///             other => Err(other),
+///         }
+///     }
+/// }
+///
+/// struct Done;
+///
+/// let done = Rc::new(RefCell::new(Vec::new()));
+/// (|_: Context<Done>| {
+///     move || {
+///         yield Req::Spawn(Job(1));
+///         yield Req::Spawn(Job(2));
+///     }
+/// })
+/// .into_block()
+/// .spawn(|Job(n)| move || perform_task!(Req::Work(n * 10)))
+/// .add_handler_({
+///     let done = done.clone();
+///     move |n| {
+///         done.borrow_mut().push(n);
+///         Ok::<_, !>(Done)
+///     }
+/// })
+/// .run();
+/// done.borrow_mut().sort();
+/// assert_eq!(*done.borrow(), [10, 20]);
+/// ```
+pub trait Request
+where
+    Self: Sized,
+{
+    type Task: TaskId;
+    type Effect;
+
+    fn is_task(self) -> Result<Self::Task, Self>;
+    fn is_effect(self) -> Result<Self::Effect, Self>;
+
+    // performed by a task under `spawn_answering`, the root answers it
+    fn is_root_question(&self) -> bool {
+        false
+    }
+
+    // where the scheduler stamps the identity of the performer and the cause
+    // of the effect, the effects without metadata are not attributed
+    fn meta_mut(&mut self) -> Option<&mut EffectMeta> {
+        None
+    }
+}
+
+fn stamp<R>(mut request: R, identity: Option<Identity>, cause: Option<u64>) -> R
+where
+    R: Request,
+{
+    if let Some(meta) = request.meta_mut() {
+        meta.identity = identity;
+        meta.cause = cause;
+    }
+    request
+}
+
+impl TaskId for ! {
+    type Id = !;
+
+    fn task_id(&self) -> Self::Id {
+        *self
+    }
+}
+
+impl Request for ! {
+    type Task = !;
+    type Effect = !;
+
+    fn is_task(self) -> Result<Self::Task, Self> {
+        self
+    }
+
+    fn is_effect(self) -> Result<Self::Effect, Self> {
+        self
+    }
+}
+
+// What a task yields to its scheduler. `Id` names the tasks of the scheduler,
+// the `TaskId::Id` of the task, or the `TaskHandle` for `spawn_tracked`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskAction<Effect, Output, Id> {
+    // goes up to the handlers, the response comes back like for the root
+    Perform(Effect),
+    // goes to the root
+    Emit(Output),
+    // lets the other tasks run
+    Pending,
+    // goes to the task, to the `on_orphan` callback if it is not running
+    SendTo(Id, Output),
+    // a request carrying a task, it joins the tasks of this scheduler
+    SpawnChild(Effect),
+    // finishes the task like returning from it, reserved
+    Complete,
+}
+
+// the deprecation window of the task bodies yielding `Left` for an effect
+// and `Right` for an output
+impl<Effect, Output, Id> From<Either<Effect, Output>> for TaskAction<Effect, Output, Id> {
+    fn from(either: Either<Effect, Output>) -> Self {
+        match either {
+            Either::Left(effect) => TaskAction::Perform(effect),
+            Either::Right(output) => TaskAction::Emit(output),
+        }
+    }
+}
+
+// the task body yielding `Either`, for the places that need `TaskAction`
+#[deprecated(note = "yield `TaskAction` from the task")]
+pub fn from_either<T, Effect, Output, Id>(
+    task: T,
+) -> impl Unpin + Generator<(), Return = T::Return, Yield = TaskAction<Effect, Output, Id>>
+where
+    T: Unpin + Generator<(), Yield = Either<Effect, Output>>,
+{
+    let mut task = task;
+    move || loop {
+        let state = Pin::new(&mut task).resume(());
+        match state {
+            GeneratorState::Yielded(y) => yield y.into(),
+            GeneratorState::Complete(r) => return r,
+        }
+    }
+}
+
+const NOT_A_TASK: &str = "`TaskAction::SpawnChild` with a request carrying no task";
+
+// Performs the effect from a task, evaluates to the selected part of the
+// response like `perform!`.
+#[macro_export]
+macro_rules! perform_task {
+    ($e:expr, $ctx:expr) => {
+        $crate::perform!($crate::tasks::TaskAction::Perform($e), $ctx)
+    };
+    ($e:expr) => {
+        $crate::perform!($crate::tasks::TaskAction::Perform($e))
+    };
+}
+
+#[macro_export]
+macro_rules! emit {
+    ($output:expr) => {
+        yield $crate::tasks::TaskAction::Emit($output)
+    };
+}
+
+#[macro_export]
+macro_rules! yield_now {
+    () => {
+        yield $crate::tasks::TaskAction::Pending
+    };
+}
+
+#[macro_export]
+macro_rules! send_to {
+    ($id:expr, $output:expr) => {
+        yield $crate::tasks::TaskAction::SendTo($id, $output)
+    };
+}
+
+// The task after `cursor`. The schedulers resume the tasks in place with it,
+// tasks that complete are removed between the calls, so a round does not
+// rebuild the map.
+fn next_task<'a, K, V>(
+    tasks: &'a mut BTreeMap<K, V>,
+    cursor: Option<&K>,
+) -> Option<(&'a K, &'a mut V)>
+where
+    K: Ord,
+{
+    match cursor {
+        None => tasks.iter_mut().next(),
+        Some(key) => tasks
+            .range_mut((Bound::Excluded(key), Bound::Unbounded))
+            .next(),
+    }
+}
+
+// The handle for a task, the key is given back if a running task has it.
+fn track<K>(
+    keys: &mut BTreeMap<K, TaskHandle>,
+    next: &mut u64,
+    key: Option<K>,
+) -> Result<(TaskHandle, Option<K>), K>
+where
+    K: Clone + Ord,
+{
+    if let Some(key) = key.clone() {
+        if keys.contains_key(&key) {
+            return Err(key);
+        }
+        keys.insert(key, TaskHandle(*next));
+    }
+    let handle = TaskHandle(*next);
+    *next += 1;
+    Ok((handle, key))
+}
+
+// dropping the running task mid-execution would lose its state silently
+const COLLISION: &str =
+    "a task with this id is already running, use `spawn_tracked` to reject duplicates";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskHandle(u64);
+
+impl TaskHandle {
+    pub fn id(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskRef<K> {
+    Key(K),
+    Handle(TaskHandle),
+}
+
+pub trait TaskKey {
+    type Key: Clone + Ord;
+
+    fn task_key(&self) -> Option<Self::Key>;
+}
+
+#[allow(clippy::wrong_self_convention)]
+pub trait TrackedRequest
+where
+    Self: Sized,
+{
+    type Task: TaskKey;
+
+    fn is_task(self) -> Result<Self::Task, Self>;
+
+    fn is_cancel(self) -> Result<TaskRef<<Self::Task as TaskKey>::Key>, Self> {
+        Err(self)
+    }
+}
+
+pub trait TrackedOutput<K> {
+    fn spawned(handle: TaskHandle, key: Option<K>) -> Self;
+    fn duplicate_task(key: K) -> Self;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Supervision {
+    #[default]
+    Never,
+    RestartOnPanic {
+        max: u32,
+        within_rounds: u32,
+    },
+    EscalateToRoot,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SpawnOptions {
+    supervision: Supervision,
+    batch_budget: Option<usize>,
+    cancel: Option<CancelToken>,
+    question_rounds: Option<u32>,
+    fail_fast: bool,
+}
+
+impl SpawnOptions {
+    pub fn supervision(self, supervision: Supervision) -> Self {
+        SpawnOptions {
+            supervision,
+            ..self
+        }
+    }
+
+    // bounds `take_batch` in the context of each task, per resume
+    pub fn batch_budget(self, budget: usize) -> Self {
+        SpawnOptions {
+            batch_budget: Some(budget),
+            ..self
+        }
+    }
+
+    // Once the token is cancelled the tasks are dropped, each one the next
+    // time the scheduler gets to it. The response to an effect in flight is
+    // withdrawn, the root gets `FailureKind::Cancelled`. The handlers see the
+    // token with the effects of the tasks that have no token of their own.
+    pub fn with_cancel_token(self, token: CancelToken) -> Self {
+        SpawnOptions {
+            cancel: Some(token),
+            ..self
+        }
+    }
+
+    // A task asking the root waits at most so many rounds for the answer, then
+    // it gets `Unanswered`. Without it the task waits for as long as the root runs.
+    pub fn question_timeout(self, rounds: u32) -> Self {
+        SpawnOptions {
+            question_rounds: Some(rounds),
+            ..self
+        }
+    }
+
+    // A panic of a task under `spawn_contained` unwinds through the scheduler
+    // like under `spawn_supervised`, for the tests that would rather see it.
+    pub fn fail_fast(self) -> Self {
+        SpawnOptions {
+            fail_fast: true,
+            ..self
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .map_or(false, CancelToken::is_cancelled)
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailureKind {
+    Panic(String),
+    Error(String),
+    Cancelled,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskFailed<Id>(pub Id, pub FailureKind);
+
+pub trait HasTaskFailed<Id> {
+    fn task_failed(failed: TaskFailed<Id>) -> Self;
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PanicSummary {
+    // the payload of the panic, if it is a `&str` or a `String`
+    pub message: Option<String>,
+    // the scope path of the root where the task ran, empty without diagnostics
+    pub scope: String,
+}
+
+// a task under `spawn_contained` panicked and is dropped, in the root context
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskPanicked<Id>(pub Id, pub PanicSummary);
+
+pub trait HasTaskPanicked<Id> {
+    fn task_panicked(panicked: TaskPanicked<Id>) -> Self;
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> Option<String> {
+    payload
+        .downcast_ref::<&str>()
+        .map(ToString::to_string)
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+}
+
+// the effect of a task for the root, in the root context
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question<Id, Effect>(pub Id, pub Effect);
+
+// the root did not answer in time, in the context of the task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unanswered;
+
+pub trait HasQuestion<Id, Effect> {
+    fn question(question: Question<Id, Effect>) -> Self;
+    fn unanswered(unanswered: Unanswered) -> Self;
+}
+
+// the answers of the root waiting for the scheduler, in the root context
+struct Answers<Id, Output>(RefCell<VecDeque<(Id, Output)>>);
+
+impl<Id, Output> Default for Answers<Id, Output> {
+    fn default() -> Self {
+        Answers(RefCell::new(VecDeque::new()))
+    }
+}
+
+// the root answering the question of the task `id`, see `answer!`
+pub fn answer<Id, Output>(context: &Context<Output>, id: Id, output: Output)
+where
+    Id: 'static,
+    Output: 'static,
+{
+    let answers = context.extension(Answers::<Id, Output>::default);
+    answers.0.borrow_mut().push_back((id, output));
+}
+
+fn answers<Id, Output>(context: &Context<Output>) -> Vec<(Id, Output)>
+where
+    Id: 'static,
+    Output: 'static,
+{
+    context
+        .find_extension::<Answers<Id, Output>>()
+        .map_or_else(Vec::new, |answers| {
+            answers.0.borrow_mut().drain(..).collect()
+        })
+}
+
+// Answers the question of a task, the output goes to the context of the task
+// and the task is resumed in this round.
+#[macro_export]
+macro_rules! answer {
+    ($ctx:expr, $id:expr, $output:expr) => {
+        $crate::tasks::answer(&$ctx, $id, $output)
+    };
+}
+
+// how the supervising scheduler asks the root, `None` for `spawn_supervised`
+struct Asking<Id, Effect, Output> {
+    question: fn(Question<Id, Effect>) -> Output,
+    unanswered: fn(Unanswered) -> Output,
+    answers: fn(&Context<Output>) -> Vec<(Id, Output)>,
+}
+
+fn task_context<Output>(
+    options: &SpawnOptions,
+    parent: &Context<Output>,
+    name: Option<String>,
+) -> Context<Output> {
+    let context = Context::empty();
+    context.set_identity(identity::task_identity(parent.identity(), name));
+    // the outputs of the task are numbered with the ones of the block, the
+    // task starts from the output the spawner took last
+    context.share_sequence(parent);
+    context.set_cause(parent.cause());
+    if let Some(budget) = options.batch_budget {
+        context.set_batch_budget(budget);
+    }
+    progress::link_task(parent, &context);
+    context
+}
+
+struct Supervised<Task, T, Output> {
+    task: Task,
+    generator: T,
+    context: Context<Output>,
+    restarts: VecDeque<u64>,
+    // the round the task asked the root, it is not resumed until answered
+    asked: Option<u64>,
+}
+
+impl<Task, T, Output> Supervised<Task, T, Output>
+where
+    Task: TaskId,
+{
+    fn admit(
+        tasks: &mut BTreeMap<Task::Id, Self>,
+        task: Task,
+        generator: T,
+        context: Context<Output>,
+        parent: &Context<Output>,
+    ) {
+        match tasks.entry(task.task_id()) {
+            Entry::Occupied(_) => panic!("{}", COLLISION),
+            Entry::Vacant(slot) => {
+                slot.insert(Supervised {
+                    task,
+                    generator,
+                    context,
+                    restarts: VecDeque::new(),
+                    asked: None,
+                });
+                parent.account(|a| a.insert(Category::Tasks));
+                progress::live_tasks(parent, tasks.len());
+            },
+        }
+    }
+}
+
+impl<Output, G> Block<Output, G>
+where
+    G: Unpin + Generator<(), Return = ()>,
+    G::Yield: Request,
+{
+    pub fn spawn<F, T, Y>(
+        self,
+        task_gen: F,
+    ) -> Block<Output, impl Generator<(), Return = (), Yield = G::Yield>>
+    where
+        F: Fn(<G::Yield as Request>::Task) -> T,
+        T: Unpin + Generator<(), Return = (), Yield = Y>,
+        Y: Into<TaskAction<G::Yield, Output, <<G::Yield as Request>::Task as TaskId>::Id>>,
+    {
+        let context = self.context();
+        let accounting = context.clone();
+        let generator = move || {
+            let mut block = Some(self);
+            let mut tasks = BTreeMap::new();
+            // the generators share the context, each has its own cause
+            let mut cause = None;
+            loop {
+                if let Some(g) = block.as_mut() {
+                    accounting.set_cause(cause);
+                    let state = g.resume();
+                    cause = accounting.cause();
+                    match state {
+                        GeneratorState::Complete(()) => {
+                            let _ = block.take();
+                        },
+                        GeneratorState::Yielded(y) => match y.is_task() {
+                            Ok(task) => match tasks.entry(task.task_id()) {
+                                Entry::Occupied(_) => panic!("{}", COLLISION),
+                                Entry::Vacant(slot) => {
+                                    let identity = identity::task_identity(
+                                        accounting.identity(),
+                                        task.task_name(),
+                                    );
+                                    slot.insert((identity, cause, task_gen(task)));
+                                    accounting.account(|a| a.insert(Category::Tasks));
+                                    progress::live_tasks(&accounting, tasks.len());
+                                },
+                            },
+                            Err(y) => yield stamp(y, accounting.identity(), cause),
+                        },
+                    }
+                }
+                let mut cursor = None;
+                while let Some((id, (identity, task_cause, task))) =
+                    next_task(&mut tasks, cursor.as_ref())
+                {
+                    let id = id.clone();
+                    let identity = identity.clone();
+                    accounting.set_cause(*task_cause);
+                    stats::task_resumed(&accounting);
+                    let state = Pin::new(task).resume(());
+                    *task_cause = accounting.cause();
+                    let task_cause = *task_cause;
+                    cursor = Some(id.clone());
+                    let action = match state {
+                        GeneratorState::Complete(()) => TaskAction::Complete,
+                        GeneratorState::Yielded(y) => y.into(),
+                    };
+                    match action {
+                        TaskAction::Perform(further) => {
+                            let _scope = accounting.enter_scope("task");
+                            yield stamp(further, identity, task_cause);
+                        },
+                        TaskAction::Emit(output) => {
+                            if let Some(block) = block.as_ref() {
+                                block.put(output);
+                            }
+                        },
+                        TaskAction::Pending => (),
+                        // the tasks read the context of the root
+                        TaskAction::SendTo(target, output) => match block.as_ref() {
+                            Some(block) if tasks.contains_key(&target) => block.put(output),
+                            _ => accounting.orphan(output),
+                        },
+                        TaskAction::SpawnChild(request) => match request.is_task() {
+                            Ok(task) => match tasks.entry(task.task_id()) {
+                                Entry::Occupied(_) => panic!("{}", COLLISION),
+                                Entry::Vacant(slot) => {
+                                    let identity = identity::task_identity(
+                                        accounting.identity(),
+                                        task.task_name(),
+                                    );
+                                    slot.insert((identity, task_cause, task_gen(task)));
+                                    accounting.account(|a| a.insert(Category::Tasks));
+                                    progress::live_tasks(&accounting, tasks.len());
+                                },
+                            },
+                            Err(_) => panic!("{}", NOT_A_TASK),
+                        },
+                        TaskAction::Complete => {
+                            tasks.remove(&id);
+                            accounting.account(|a| a.remove(Category::Tasks));
+                            progress::live_tasks(&accounting, tasks.len());
+                        },
+                    }
+                }
+
+                if block.is_none() && tasks.is_empty() {
+                    break;
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+
+    // like `spawn`, the constructor gets the args of the task instead of it
+    pub fn spawn_with<F, T, Y>(
+        self,
+        task_gen: F,
+    ) -> Block<Output, impl Generator<(), Return = (), Yield = G::Yield>>
+    where
+        <G::Yield as Request>::Task: TaskArgs,
+        F: Fn(<<G::Yield as Request>::Task as TaskArgs>::Args) -> T,
+        T: Unpin + Generator<(), Return = (), Yield = Y>,
+        Y: Into<TaskAction<G::Yield, Output, <<G::Yield as Request>::Task as TaskId>::Id>>,
+    {
+        self.spawn(move |task: <G::Yield as Request>::Task| task_gen(task.task_args()))
+    }
+
+    // Unlike `spawn`, every task incarnation gets its own context, responses to
+    // the effects it yields are moved there from the parent context.
+    pub fn spawn_supervised<F, T, Y, Failure>(
+        self,
+        options: SpawnOptions,
+        task_gen: F,
+    ) -> Block<Output, impl Generator<(), Return = (), Yield = G::Yield>>
+    where
+        F: Fn(<G::Yield as Request>::Task, Context<Output>) -> T,
+        T: Unpin + Generator<(), Return = Result<(), Failure>, Yield = Y>,
+        Y: Into<TaskAction<G::Yield, Output, <<G::Yield as Request>::Task as TaskId>::Id>>,
+        <G::Yield as Request>::Task: Clone,
+        Failure: fmt::Debug,
+        Output: HasTaskFailed<<<G::Yield as Request>::Task as TaskId>::Id>,
+    {
+        self.supervise(options, task_gen, None, None)
+    }
+
+    // Like `spawn_supervised`, but the effects of the tasks that are root
+    // questions go to the root as `Question` outputs instead of the handlers.
+    // The task waits until the root answers with `answer!`, the questions are
+    // in the root context in the order they are asked.
+    pub fn spawn_answering<F, T, Y, Failure>(
+        self,
+        options: SpawnOptions,
+        task_gen: F,
+    ) -> Block<Output, impl Generator<(), Return = (), Yield = G::Yield>>
+    where
+        F: Fn(<G::Yield as Request>::Task, Context<Output>) -> T,
+        T: Unpin + Generator<(), Return = Result<(), Failure>, Yield = Y>,
+        Y: Into<TaskAction<G::Yield, Output, <<G::Yield as Request>::Task as TaskId>::Id>>,
+        <G::Yield as Request>::Task: Clone,
+        <<G::Yield as Request>::Task as TaskId>::Id: 'static,
+        Failure: fmt::Debug,
+        Output: HasTaskFailed<<<G::Yield as Request>::Task as TaskId>::Id>
+            + HasQuestion<<<G::Yield as Request>::Task as TaskId>::Id, G::Yield>
+            + 'static,
+    {
+        let asking = Asking {
+            question: Output::question,
+            unanswered: Output::unanswered,
+            answers: answers::<_, Output>,
+        };
+        self.supervise(options, task_gen, Some(asking), None)
+    }
+
+    // Like `spawn_supervised`, but a panic of a task does not unwind through
+    // the scheduler. The task is dropped like a cancelled one, its context and
+    // whatever its generator holds with it, and the root gets `TaskPanicked`.
+    // The root and the other tasks go on. The tasks are resumed under
+    // `AssertUnwindSafe`, a task sharing state with the root must leave it
+    // consistent when it panics. With `SpawnOptions::fail_fast` the panic
+    // unwinds as before, a supervision other than `Never` takes precedence.
+    pub fn spawn_contained<F, T, Y, Failure>(
+        self,
+        options: SpawnOptions,
+        task_gen: F,
+    ) -> Block<Output, impl Generator<(), Return = (), Yield = G::Yield>>
+    where
+        F: Fn(<G::Yield as Request>::Task, Context<Output>) -> T,
+        T: Unpin + Generator<(), Return = Result<(), Failure>, Yield = Y>,
+        Y: Into<TaskAction<G::Yield, Output, <<G::Yield as Request>::Task as TaskId>::Id>>,
+        <G::Yield as Request>::Task: Clone,
+        Failure: fmt::Debug,
+        Output: HasTaskFailed<<<G::Yield as Request>::Task as TaskId>::Id>
+            + HasTaskPanicked<<<G::Yield as Request>::Task as TaskId>::Id>,
+    {
+        self.supervise(options, task_gen, None, Some(Output::task_panicked))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn supervise<F, T, Y, Failure>(
+        self,
+        options: SpawnOptions,
+        task_gen: F,
+        asking: Option<Asking<<<G::Yield as Request>::Task as TaskId>::Id, G::Yield, Output>>,
+        panicked: Option<fn(TaskPanicked<<<G::Yield as Request>::Task as TaskId>::Id>) -> Output>,
+    ) -> Block<Output, impl Generator<(), Return = (), Yield = G::Yield>>
+    where
+        F: Fn(<G::Yield as Request>::Task, Context<Output>) -> T,
+        T: Unpin + Generator<(), Return = Result<(), Failure>, Yield = Y>,
+        Y: Into<TaskAction<G::Yield, Output, <<G::Yield as Request>::Task as TaskId>::Id>>,
+        <G::Yield as Request>::Task: Clone,
+        Failure: fmt::Debug,
+        Output: HasTaskFailed<<<G::Yield as Request>::Task as TaskId>::Id>,
+    {
+        let context = self.context();
+        let parent = context.clone();
+        let generator = move || {
+            let mut block = Some(self);
+            let mut tasks = BTreeMap::new();
+            // the responses to the effect of a task on their way to its context
+            let mut responses = Vec::new();
+            let mut round = 0;
+            loop {
+                round += 1;
+                if let Some(g) = block.as_mut() {
+                    match g.resume() {
+                        GeneratorState::Complete(()) => {
+                            let _ = block.take();
+                        },
+                        GeneratorState::Yielded(y) => match y.is_task() {
+                            Ok(task) => {
+                                let context = task_context(&options, &parent, task.task_name());
+                                let generator = task_gen(task.clone(), context.clone());
+                                Supervised::admit(&mut tasks, task, generator, context, &parent);
+                            },
+                            Err(y) => yield stamp(y, parent.identity(), parent.cause()),
+                        },
+                    }
+                }
+                // an answer for a task that is not waiting is too late
+                for (id, answer) in asking.iter().flat_map(|a| (a.answers)(&parent)) {
+                    match tasks.get_mut(&id) {
+                        Some(entry) if entry.asked.is_some() => {
+                            entry.asked = None;
+                            entry.context.put(answer);
+                        },
+                        _ => parent.orphan(answer),
+                    }
+                }
+                let mut cursor = None;
+                while let Some((id, entry)) = next_task(&mut tasks, cursor.as_ref()) {
+                    let id = id.clone();
+                    cursor = Some(id.clone());
+                    if let (Some(asking), Some(asked)) = (&asking, entry.asked) {
+                        let timeout = options
+                            .question_rounds
+                            .map_or(false, |rounds| round >= asked + u64::from(rounds));
+                        if !timeout && !options.is_cancelled() {
+                            continue;
+                        }
+                        entry.asked = None;
+                        entry.context.put((asking.unanswered)(Unanswered));
+                    }
+                    let state = if options.is_cancelled() {
+                        None
+                    } else {
+                        entry.context.begin_resume();
+                        stats::task_resumed(&parent);
+                        let state = panic::catch_unwind(AssertUnwindSafe(|| {
+                            Pin::new(&mut entry.generator).resume(())
+                        }));
+                        entry.context.end_resume();
+                        Some(state)
+                    };
+                    let kind = match state {
+                        None => FailureKind::Cancelled,
+                        Some(Ok(GeneratorState::Yielded(y))) => {
+                            match y.into() {
+                                TaskAction::Perform(question)
+                                    if asking.is_some() && question.is_root_question() =>
+                                {
+                                    let asking =
+                                        asking.as_ref().expect("asking is checked in the guard");
+                                    match block.as_ref() {
+                                        Some(block) => {
+                                            block.put((asking.question)(Question(id, question)));
+                                            entry.asked = Some(round);
+                                        },
+                                        // nobody is there to answer
+                                        None => entry.context.put((asking.unanswered)(Unanswered)),
+                                    }
+                                    continue;
+                                },
+                                TaskAction::Perform(further) => {
+                                    let context = entry.context.clone();
+                                    let _scope = parent.enter_scope("task");
+                                    let before = parent.produced();
+                                    parent.set_cancel_token(
+                                        context.cancel_token().or_else(|| options.cancel.clone()),
+                                    );
+                                    // the cause of the task while its effect is in flight
+                                    let root_cause = parent.cause();
+                                    parent.set_cause(context.cause());
+                                    yield stamp(further, context.identity(), context.cause());
+                                    parent.set_cause(root_cause);
+                                    parent.set_cancel_token(None);
+                                    responses.extend(
+                                        (before..parent.produced())
+                                            .filter_map(|_| parent.take_last_numbered()),
+                                    );
+                                    if !options.is_cancelled() {
+                                        for (seq, response) in responses.drain(..).rev() {
+                                            context.put_numbered(seq, response);
+                                        }
+                                        continue;
+                                    }
+                                    // withdrawn in the round the token is cancelled
+                                    for (_, response) in responses.drain(..).rev() {
+                                        parent.orphan(response);
+                                    }
+                                    tasks.remove(&id);
+                                    progress::live_tasks(&parent, tasks.len());
+                                    parent.account(|a| {
+                                        a.remove(Category::Tasks);
+                                        a.bump(Counter::CancelledEffects);
+                                        a.bump(Counter::CancelledTasks);
+                                    });
+                                    if let Some(block) = block.as_ref() {
+                                        let failed = TaskFailed(id, FailureKind::Cancelled);
+                                        block.put(Output::task_failed(failed));
+                                    }
+                                    continue;
+                                },
+                                TaskAction::Emit(output) => {
+                                    if let Some(block) = block.as_ref() {
+                                        block.put(output);
+                                    }
+                                    continue;
+                                },
+                                TaskAction::Pending => continue,
+                                TaskAction::SendTo(target, output) => {
+                                    match tasks.get(&target) {
+                                        Some(target) => target.context.put(output),
+                                        None => parent.orphan(output),
+                                    }
+                                    continue;
+                                },
+                                TaskAction::SpawnChild(request) => {
+                                    let task = request
+                                        .is_task()
+                                        .unwrap_or_else(|_| panic!("{}", NOT_A_TASK));
+                                    let context = task_context(&options, &parent, task.task_name());
+                                    context.set_cause(entry.context.cause());
+                                    let generator = task_gen(task.clone(), context.clone());
+                                    Supervised::admit(
+                                        &mut tasks, task, generator, context, &parent,
+                                    );
+                                    continue;
+                                },
+                                TaskAction::Complete => {
+                                    tasks.remove(&id);
+                                    progress::live_tasks(&parent, tasks.len());
+                                    parent.account(|a| a.remove(Category::Tasks));
+                                    continue;
+                                },
+                            }
+                        },
+                        Some(Ok(GeneratorState::Complete(Ok(())))) => {
+                            tasks.remove(&id);
+                            progress::live_tasks(&parent, tasks.len());
+                            parent.account(|a| a.remove(Category::Tasks));
+                            continue;
+                        },
+                        Some(Ok(GeneratorState::Complete(Err(error)))) => {
+                            FailureKind::Error(format!("{:?}", error))
+                        },
+                        Some(Err(payload)) => match (options.supervision, panicked) {
+                            (Supervision::Never, Some(panicked)) if !options.fail_fast => {
+                                // the root is where it was when the task was resumed
+                                let scope = {
+                                    let _scope = parent.enter_scope("task");
+                                    parent.scope_path()
+                                };
+                                let summary = PanicSummary {
+                                    message: panic_message(&*payload),
+                                    scope,
+                                };
+                                tasks.remove(&id);
+                                progress::live_tasks(&parent, tasks.len());
+                                parent.account(|a| {
+                                    a.remove(Category::Tasks);
+                                    a.bump(Counter::PanickedTasks);
+                                });
+                                if let Some(block) = block.as_ref() {
+                                    block.put(panicked(TaskPanicked(id, summary)));
+                                }
+                                continue;
+                            },
+                            (Supervision::Never, _) => panic::resume_unwind(payload),
+                            _ => FailureKind::Panic(panic_message(&*payload).unwrap_or_default()),
+                        },
+                    };
+                    let restart = match (&kind, options.supervision) {
+                        (
+                            FailureKind::Panic(_),
+                            Supervision::RestartOnPanic { max, within_rounds },
+                        ) => {
+                            while entry
+                                .restarts
+                                .front()
+                                .map_or(false, |r| r + u64::from(within_rounds) <= round)
+                            {
+                                entry.restarts.pop_front();
+                            }
+                            entry.restarts.len() < max as usize
+                        },
+                        _ => false,
+                    };
+                    if restart {
+                        entry.restarts.push_back(round);
+                        entry.asked = None;
+                        entry.context = task_context(&options, &parent, entry.task.task_name());
+                        entry.generator = task_gen(entry.task.clone(), entry.context.clone());
+                        continue;
+                    }
+                    tasks.remove(&id);
+                    progress::live_tasks(&parent, tasks.len());
+                    parent.account(|a| a.remove(Category::Tasks));
+                    if kind == FailureKind::Cancelled {
+                        parent.account(|a| a.bump(Counter::CancelledTasks));
+                    }
+                    // only errors get here without supervision, panics are resumed above,
+                    // an error is never taken for a normal completion
+                    if let Some(block) = block.as_ref() {
+                        block.put(Output::task_failed(TaskFailed(id, kind)));
+                    }
+                }
+
+                if block.is_none() && tasks.is_empty() {
+                    break;
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+
+    pub fn add_handler_<Handler, NewYield>(
+        self,
+        handler: Handler,
+    ) -> Block<Output, impl Generator<(), Return = (), Yield = NewYield>>
+    where
+        Handler: FnMut(<G::Yield as Request>::Effect) -> Result<Output, NewYield>,
+        NewYield: Request,
+    {
+        let context = self.context();
+        let handler = RefCell::new(handler);
+        let mut s = self;
+        let generator = move || loop {
+            match s.resume() {
+                GeneratorState::Complete(()) => break,
+                GeneratorState::Yielded(y) => {
+                    if let Ok(effect) = y.is_effect() {
+                        let mut h = handler.borrow_mut();
+                        match h(effect) {
+                            Ok(output) => s.put(output),
+                            Err(y) => {
+                                drop(h);
+                                yield y;
+                            },
+                        }
+                    }
+                },
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+impl<Output, G> Block<Output, G>
+where
+    G: Unpin + Generator<(), Return = ()>,
+    G::Yield: TrackedRequest,
+{
+    // Tasks get a handle from the scheduler, the root learns it from a `spawned`
+    // output. A task with the key of a running task is rejected, not replaced.
+    pub fn spawn_tracked<F, T, Y>(
+        self,
+        task_gen: F,
+    ) -> Block<Output, impl Generator<(), Return = (), Yield = G::Yield>>
+    where
+        F: Fn(<G::Yield as TrackedRequest>::Task, TaskHandle) -> T,
+        T: Unpin + Generator<(), Return = (), Yield = Y>,
+        Y: Into<TaskAction<G::Yield, Output, TaskHandle>>,
+        Output: TrackedOutput<<<G::Yield as TrackedRequest>::Task as TaskKey>::Key>,
+    {
+        let context = self.context();
+        let accounting = context.clone();
+        let generator = move || {
+            let mut block = Some(self);
+            let mut tasks = BTreeMap::new();
+            let mut keys = BTreeMap::new();
+            let mut next = 0;
+            loop {
+                if let Some(g) = block.as_mut() {
+                    match g.resume() {
+                        GeneratorState::Complete(()) => {
+                            let _ = block.take();
+                        },
+                        GeneratorState::Yielded(y) => match y.is_task() {
+                            Ok(task) => match track(&mut keys, &mut next, task.task_key()) {
+                                Ok((handle, key)) => {
+                                    tasks.insert(handle, (key.clone(), task_gen(task, handle)));
+                                    accounting.account(|a| a.insert(Category::Tasks));
+                                    progress::live_tasks(&accounting, tasks.len());
+                                    g.put(Output::spawned(handle, key));
+                                },
+                                Err(key) => {
+                                    g.put(Output::duplicate_task(key));
+                                    continue;
+                                },
+                            },
+                            Err(y) => match y.is_cancel() {
+                                Ok(task) => {
+                                    let handle = match task {
+                                        TaskRef::Handle(handle) => Some(handle),
+                                        TaskRef::Key(key) => keys.get(&key).cloned(),
+                                    };
+                                    if let Some((key, _)) = handle.and_then(|h| tasks.remove(&h)) {
+                                        if let Some(key) = key {
+                                            keys.remove(&key);
+                                        }
+                                        accounting.account(|a| a.remove(Category::Tasks));
+                                        progress::live_tasks(&accounting, tasks.len());
+                                    }
+                                },
+                                Err(y) => yield y,
+                            },
+                        },
+                    }
+                }
+                let mut cursor = None;
+                while let Some((&handle, (_, task))) = next_task(&mut tasks, cursor.as_ref()) {
+                    cursor = Some(handle);
+                    stats::task_resumed(&accounting);
+                    let action = match Pin::new(task).resume(()) {
+                        GeneratorState::Complete(()) => TaskAction::Complete,
+                        GeneratorState::Yielded(y) => y.into(),
+                    };
+                    match action {
+                        TaskAction::Perform(further) => {
+                            let _scope = accounting.enter_scope("task");
+                            yield further;
+                        },
+                        TaskAction::Emit(output) => {
+                            if let Some(block) = block.as_ref() {
+                                block.put(output);
+                            }
+                        },
+                        TaskAction::Pending => (),
+                        // the tasks read the context of the root
+                        TaskAction::SendTo(target, output) => match block.as_ref() {
+                            Some(block) if tasks.contains_key(&target) => block.put(output),
+                            _ => accounting.orphan(output),
+                        },
+                        // the handle goes to the root context, the task reads it there
+                        TaskAction::SpawnChild(request) => {
+                            let task = request
+                                .is_task()
+                                .unwrap_or_else(|_| panic!("{}", NOT_A_TASK));
+                            let output = match track(&mut keys, &mut next, task.task_key()) {
+                                Ok((handle, key)) => {
+                                    tasks.insert(handle, (key.clone(), task_gen(task, handle)));
+                                    accounting.account(|a| a.insert(Category::Tasks));
+                                    progress::live_tasks(&accounting, tasks.len());
+                                    Output::spawned(handle, key)
+                                },
+                                Err(key) => Output::duplicate_task(key),
+                            };
+                            if let Some(block) = block.as_ref() {
+                                block.put(output);
+                            }
+                        },
+                        TaskAction::Complete => {
+                            if let Some((Some(key), _)) = tasks.remove(&handle) {
+                                keys.remove(&key);
+                            }
+                            accounting.account(|a| a.remove(Category::Tasks));
+                            progress::live_tasks(&accounting, tasks.len());
+                        },
+                    }
+                }
+
+                if block.is_none() && tasks.is_empty() {
+                    break;
+                }
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{SocketAddr, TcpListener, TcpStream},
+        collections::BTreeMap,
+        io::{Read, Write},
+    };
+
+    use either::Either;
+
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use crate::{IntoBlock, Context, Category, CancelToken, Counter};
+    use crate::{emit, perform_task, send_to, yield_now};
+    use super::{
+        TaskId, TaskArgs, Request, SpawnOptions, Supervision, TaskFailed, FailureKind,
+        HasTaskFailed, TaskHandle, TaskRef, TaskKey, TrackedRequest, TrackedOutput, TaskAction,
+        Question, Unanswered, HasQuestion, TaskPanicked, HasTaskPanicked, PanicSummary,
+    };
+
+    #[derive(Debug)]
+    enum Req {
+        Ping(u32),
+        Idle,
+        Spawn(Job),
+    }
+
+    #[derive(Debug, Clone)]
+    struct Job {
+        id: u32,
+        panics: Rc<RefCell<u32>>,
+        fresh: Rc<RefCell<Vec<bool>>>,
+    }
+
+    impl TaskId for Job {
+        type Id = u32;
+
+        fn task_id(&self) -> Self::Id {
+            self.id
+        }
+    }
+
+    impl Request for Req {
+        type Task = Job;
+        type Effect = Req;
+
+        fn is_task(self) -> Result<Self::Task, Self> {
+            match self {
+                Req::Spawn(job) => Ok(job),
+                s => Err(s),
+            }
+        }
+
+        fn is_effect(self) -> Result<Self::Effect, Self> {
+            Ok(self)
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Response {
+        Pong(u32),
+        Idled,
+        Done(u32),
+        Failed(TaskFailed<u32>),
+        Panicked(TaskPanicked<u32>),
+    }
+
+    impl HasTaskFailed<u32> for Response {
+        fn task_failed(failed: TaskFailed<u32>) -> Self {
+            Response::Failed(failed)
+        }
+    }
+
+    impl HasTaskPanicked<u32> for Response {
+        fn task_panicked(panicked: TaskPanicked<u32>) -> Self {
+            Response::Panicked(panicked)
+        }
+    }
+
+    fn flaky(
+        job: Job,
+        context: Context<Response>,
+    ) -> impl Unpin + Generator<(), Yield = TaskAction<Req, Response, u32>, Return = Result<(), ()>>
+    {
+        move || {
+            job.fresh.borrow_mut().push(context.is_empty());
+            perform_task!(Req::Ping(job.id));
+            if *job.panics.borrow() > 0 {
+                *job.panics.borrow_mut() -= 1;
+                // the pong is left unconsumed in this incarnation's context
+                panic!("job {} crashed", job.id);
+            }
+            assert_eq!(context.take(), Some(Response::Pong(job.id)));
+            emit!(Response::Done(job.id));
+            Ok(())
+        }
+    }
+
+    fn supervise(panics: u32, max: u32) -> (Option<Response>, Vec<bool>) {
+        let job = Job {
+            id: 7,
+            panics: Rc::new(RefCell::new(panics)),
+            fresh: Rc::default(),
+        };
+        let fresh = job.fresh.clone();
+        let result = Rc::new(RefCell::new(None));
+        let root = {
+            let result = result.clone();
+            move |context: Context<Response>| {
+                move || {
+                    yield Req::Spawn(job);
+                    loop {
+                        match context.take() {
+                            Some(Response::Idled) | None => yield Req::Idle,
+                            outcome => {
+                                *result.borrow_mut() = outcome;
+                                break;
+                            },
+                        }
+                    }
+                }
+            }
+        };
+        let options = SpawnOptions::default().supervision(Supervision::RestartOnPanic {
+            max,
+            within_rounds: 100,
+        });
+        root.into_block()
+            .spawn_supervised(options, flaky)
+            .add_handler_(|effect| match effect {
+                Req::Ping(id) => Ok::<_, !>(Response::Pong(id)),
+                _ => Ok(Response::Idled),
+            })
+            .run();
+        let result = result.borrow_mut().take();
+        let fresh = fresh.borrow().clone();
+        (result, fresh)
+    }
+
+    #[test]
+    fn restarted_after_panics() {
+        let (result, fresh) = supervise(2, 3);
+        assert_eq!(result, Some(Response::Done(7)));
+        assert_eq!(fresh, vec![true, true, true]);
+    }
+
+    #[test]
+    fn escalated_over_budget() {
+        let (result, _) = supervise(3, 2);
+        assert_eq!(
+            result,
+            Some(Response::Failed(TaskFailed(
+                7,
+                FailureKind::Panic("job 7 crashed".to_string()),
+            ))),
+        );
+    }
+
+    // counts the drops, the cleanup of a task is the drop of what it holds
+    struct Cleanup(Rc<RefCell<Vec<u32>>>, u32);
+
+    impl Drop for Cleanup {
+        fn drop(&mut self) {
+            self.0.borrow_mut().push(self.1);
+        }
+    }
+
+    fn contained(options: SpawnOptions) -> (Vec<Response>, Vec<u32>) {
+        let cleanups = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let root = {
+            let seen = seen.clone();
+            move |context: Context<Response>| {
+                move || {
+                    for id in 1..=3 {
+                        yield Req::Spawn(job(id));
+                    }
+                    while seen.borrow().len() < 3 {
+                        match context.take() {
+                            Some(Response::Idled) | None => yield Req::Idle,
+                            Some(outcome) => seen.borrow_mut().push(outcome),
+                        }
+                    }
+                }
+            }
+        };
+        let task = {
+            let cleanups = cleanups.clone();
+            move |job: Job, context: Context<Response>| {
+                let cleanup = Cleanup(cleanups.clone(), job.id);
+                move || {
+                    let _cleanup = cleanup;
+                    perform_task!(Req::Ping(job.id));
+                    if job.id == 2 {
+                        panic!("job {} crashed", job.id);
+                    }
+                    context.take();
+                    emit!(Response::Done(job.id));
+                    Ok::<_, ()>(())
+                }
+            }
+        };
+        root.into_block()
+            .spawn_contained(options, task)
+            .add_handler_(|effect| match effect {
+                Req::Ping(id) => Ok::<_, !>(Response::Pong(id)),
+                _ => Ok(Response::Idled),
+            })
+            .run();
+        let seen = seen.borrow_mut().drain(..).collect();
+        let cleanups = cleanups.borrow().clone();
+        (seen, cleanups)
+    }
+
+    #[test]
+    fn panic_contained_to_its_task() {
+        let (seen, mut cleanups) = contained(SpawnOptions::default());
+        let scope = if cfg!(feature = "diagnostics") { "task" } else { "" };
+        assert_eq!(
+            seen,
+            [
+                Response::Done(1),
+                Response::Panicked(TaskPanicked(
+                    2,
+                    PanicSummary {
+                        message: Some("job 2 crashed".to_string()),
+                        scope: scope.to_string(),
+                    },
+                )),
+                Response::Done(3),
+            ],
+        );
+        cleanups.sort();
+        assert_eq!(cleanups, [1, 2, 3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "job 2 crashed")]
+    fn panic_fails_fast() {
+        contained(SpawnOptions::default().fail_fast());
+    }
+
+    #[test]
+    fn cancelled_with_parent_token() {
+        let job = |id| Job {
+            id,
+            panics: Rc::default(),
+            fresh: Rc::default(),
+        };
+        let parent = CancelToken::new();
+        let orphans = Rc::new(RefCell::new(Vec::new()));
+        let failed = Rc::new(RefCell::new(Vec::new()));
+        let pinged = Rc::new(RefCell::new(Vec::new()));
+        let root = {
+            let orphans = orphans.clone();
+            let failed = failed.clone();
+            move |context: Context<Response>| {
+                move || {
+                    context.on_orphan(move |o| orphans.borrow_mut().push(o));
+                    yield Req::Spawn(job(1));
+                    yield Req::Spawn(job(2));
+                    while failed.borrow().len() < 2 {
+                        match context.take() {
+                            Some(Response::Failed(f)) => failed.borrow_mut().push(f),
+                            _ => yield Req::Idle,
+                        }
+                    }
+                }
+            }
+        };
+        let options = SpawnOptions::default().with_cancel_token(parent.child());
+        let (block, accounting) = root
+            .into_block()
+            .spawn_supervised(options, flaky)
+            .add_handler_({
+                let pinged = pinged.clone();
+                move |effect| match effect {
+                    Req::Ping(id) => {
+                        pinged.borrow_mut().push(id);
+                        assert!(parent.cancel());
+                        assert!(!parent.cancel());
+                        Ok::<_, !>(Response::Pong(id))
+                    },
+                    _ => Ok(Response::Idled),
+                }
+            })
+            .with_accounting();
+        block.run();
+        // the pong is withdrawn in the round of the ping, the second task never runs
+        assert_eq!(*pinged.borrow(), [1]);
+        assert_eq!(*orphans.borrow(), [Response::Pong(1)]);
+        assert_eq!(
+            *failed.borrow(),
+            [
+                TaskFailed(1, FailureKind::Cancelled),
+                TaskFailed(2, FailureKind::Cancelled),
+            ],
+        );
+        if cfg!(feature = "diagnostics") {
+            let report = accounting.report();
+            assert_eq!(report.count(Counter::CancelledTasks), 2);
+            assert_eq!(report.count(Counter::CancelledEffects), 1);
+            assert_eq!(report.get(Category::Tasks).live, 0);
+        }
+    }
+
+    fn job(id: u32) -> Job {
+        Job {
+            id,
+            panics: Rc::default(),
+            fresh: Rc::default(),
+        }
+    }
+
+    #[test]
+    fn messages_between_tasks() {
+        let done = Rc::new(RefCell::new(None));
+        let root = {
+            let done = done.clone();
+            move |context: Context<Response>| {
+                move || {
+                    yield Req::Spawn(job(1));
+                    loop {
+                        match context.take() {
+                            Some(Response::Done(id)) => break *done.borrow_mut() = Some(id),
+                            _ => yield Req::Idle,
+                        }
+                    }
+                }
+            }
+        };
+        root.into_block()
+            .spawn_supervised(
+                SpawnOptions::default(),
+                |task: Job, context: Context<Response>| {
+                    move || {
+                        if task.id == 1 {
+                            yield TaskAction::SpawnChild(Req::Spawn(job(2)));
+                            // nobody has the id 3, the pong goes nowhere
+                            send_to!(3, Response::Pong(1));
+                            send_to!(2, Response::Pong(1));
+                            return Ok::<_, ()>(());
+                        }
+                        loop {
+                            match context.take() {
+                                Some(Response::Pong(from)) => {
+                                    emit!(Response::Done(from));
+                                    return Ok(());
+                                },
+                                _ => yield_now!(),
+                            }
+                        }
+                    }
+                },
+            )
+            .add_handler_(|_| Ok::<_, !>(Response::Idled))
+            .run();
+        assert_eq!(*done.borrow(), Some(1));
+    }
+
+    // the task bodies yielding `Either` keep working
+    #[test]
+    #[allow(deprecated)]
+    fn either_bodies() {
+        let pinged = Rc::new(RefCell::new(Vec::new()));
+        (|_: Context<Response>| {
+            move || {
+                yield Req::Spawn(job(1));
+                yield Req::Spawn(job(2));
+            }
+        })
+        .into_block()
+        .spawn(|job| move || yield Either::Left(Req::Ping(job.id)))
+        .add_handler_({
+            let pinged = pinged.clone();
+            move |effect| {
+                if let Req::Ping(id) = effect {
+                    pinged.borrow_mut().push(id);
+                }
+                Ok::<_, !>(Response::Idled)
+            }
+        })
+        .run();
+        assert_eq!(*pinged.borrow(), [1, 2]);
+
+        let task = |job: Job, _| {
+            super::from_either(move || {
+                yield Either::Left(Req::Ping(job.id));
+                yield Either::Right(Response::Done(job.id));
+                Ok::<_, ()>(())
+            })
+        };
+        let action: TaskAction<Req, _, u32> = Either::Right(Response::Idled).into();
+        assert!(matches!(action, TaskAction::Emit(Response::Idled)));
+        let done = Rc::new(RefCell::new(None));
+        (|context: Context<Response>| {
+            let done = done.clone();
+            move || {
+                yield Req::Spawn(job(3));
+                loop {
+                    match context.take() {
+                        Some(Response::Done(id)) => break *done.borrow_mut() = Some(id),
+                        _ => yield Req::Idle,
+                    }
+                }
+            }
+        })
+        .into_block()
+        .spawn_supervised(SpawnOptions::default(), task)
+        .add_handler_(|_| Ok::<_, !>(Response::Idled))
+        .run();
+        assert_eq!(*done.borrow(), Some(3));
+    }
+
+    #[test]
+    fn simple_tcp() {
+        #[derive(Debug)]
+        enum Req {
+            ThrowEffect(Effect),
+            Spawn(Task),
+        }
+
+        #[derive(Debug)]
+        enum Effect {
+            Listen(u16),
+            Accept,
+            Connect(SocketAddr),
+            Read(SocketAddr, Vec<u8>, usize),
+            Write(SocketAddr, Vec<u8>, usize),
+        }
+
+        enum Response {
+            Listening,
+            Accepted(SocketAddr),
+            Connected(SocketAddr),
+            DidRead(SocketAddr, Vec<u8>, usize),
+            DidWrite(SocketAddr, Vec<u8>, usize),
+        }
+
+        #[derive(Debug)]
+        pub struct Task(SocketAddr, bool);
+
+        impl TaskId for Task {
+            type Id = SocketAddr;
+
+            fn task_id(&self) -> Self::Id {
+                self.0
+            }
+        }
+
+        impl Request for Req {
+            type Task = Task;
+            type Effect = Effect;
+
+            fn is_task(self) -> Result<Self::Task, Self> {
+                match self {
+                    Req::Spawn(task) => Ok(task),
+                    s => Err(s),
+                }
+            }
+
+            fn is_effect(self) -> Result<Self::Effect, Self> {
+                match self {
+                    Req::ThrowEffect(effect) => Ok(effect),
+                    s => Err(s),
+                }
+            }
+        }
+
+        let g = move |context: Context<Response>| {
+            move || {
+                yield Req::ThrowEffect(Effect::Listen(8224));
+                yield Req::ThrowEffect(Effect::Connect(([127, 0, 0, 1], 8224).into()));
+                loop {
+                    match context.take() {
+                        Some(Response::Accepted(addr)) => yield Req::Spawn(Task(addr, true)),
+                        Some(Response::Connected(addr)) => {
+                            yield Req::ThrowEffect(Effect::Accept);
+                            yield Req::Spawn(Task(addr, false));
+                        },
+                        Some(Response::DidRead(addr, data, offset)) => {
+                            println!(
+                                "{} -> {:?}",
+                                addr,
+                                std::str::from_utf8(&data[..offset]).unwrap()
+                            );
+                            break;
+                        },
+                        _ => (),
+                    }
+                }
+            }
+        };
+
+        let (block, accounting) = g
+            .into_block()
+            .spawn(move |Task(addr, incoming)| {
+                move || {
+                    println!("new: {}, incoming: {}", addr, incoming);
+                    if incoming {
+                        perform_task!(Req::ThrowEffect(Effect::Read(addr, vec![0; 0x10], 0)));
+                    } else {
+                        perform_task!(Req::ThrowEffect(Effect::Write(
+                            addr,
+                            b"hello, world\n".to_vec(),
+                            0,
+                        )));
+                    }
+                }
+            })
+            .add_handler_({
+                let mut listener = None::<TcpListener>;
+                let mut streams = BTreeMap::new();
+                move |effect: Effect| match effect {
+                    Effect::Listen(port) => {
+                        listener = Some(
+                            TcpListener::bind::<SocketAddr>(([0, 0, 0, 0], port).into()).unwrap(),
+                        );
+                        Ok(Response::Listening)
+                    },
+                    Effect::Accept => {
+                        let (s, addr) = listener.as_ref().unwrap().accept().unwrap();
+                        streams.insert(addr, s);
+                        Ok(Response::Accepted(addr))
+                    },
+                    Effect::Connect(addr) => {
+                        streams.insert(addr, TcpStream::connect(addr).unwrap());
+                        Ok(Response::Connected(addr))
+                    },
+                    Effect::Read(addr, mut buffer, mut offset) => {
+                        if let Some(stream) = streams.get_mut(&addr) {
+                            offset += stream.read(&mut buffer[offset..]).unwrap();
+                        }
+                        Ok(Response::DidRead(addr, buffer, offset))
+                    },
+                    Effect::Write(addr, buffer, mut offset) => {
+                        if let Some(stream) = streams.get_mut(&addr) {
+                            offset += stream.write(&buffer[offset..]).unwrap();
+                        }
+                        Ok(Response::DidWrite(addr, buffer, offset))
+                    },
+                }
+            })
+            .with_accounting();
+        block.run();
+        accounting.assert_quiescent();
+        if cfg!(feature = "diagnostics") {
+            assert_eq!(accounting.report().get(Category::Tasks).high_water, 2);
+        }
+    }
+
+    #[derive(Debug)]
+    enum Tracked {
+        Spawn(Work),
+        Cancel(TaskRef<u8>),
+        Tick(u32),
+        Idle,
+    }
+
+    #[derive(Debug)]
+    struct Work {
+        key: Option<u8>,
+        tag: u32,
+    }
+
+    impl TaskKey for Work {
+        type Key = u8;
+
+        fn task_key(&self) -> Option<u8> {
+            self.key
+        }
+    }
+
+    impl TrackedRequest for Tracked {
+        type Task = Work;
+
+        fn is_task(self) -> Result<Work, Self> {
+            match self {
+                Tracked::Spawn(work) => Ok(work),
+                s => Err(s),
+            }
+        }
+
+        fn is_cancel(self) -> Result<TaskRef<u8>, Self> {
+            match self {
+                Tracked::Cancel(task) => Ok(task),
+                s => Err(s),
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Scheduled {
+        Spawned(TaskHandle, Option<u8>),
+        Duplicate(u8),
+        Ticked,
+    }
+
+    impl crate::Effect for Scheduled {
+        type Input = Tracked;
+    }
+
+    impl TrackedOutput<u8> for Scheduled {
+        fn spawned(handle: TaskHandle, key: Option<u8>) -> Self {
+            Scheduled::Spawned(handle, key)
+        }
+
+        fn duplicate_task(key: u8) -> Self {
+            Scheduled::Duplicate(key)
+        }
+    }
+
+    fn tracked<G>(root: impl FnOnce(Context<Scheduled>) -> G) -> Vec<u32>
+    where
+        G: Unpin + Generator<(), Yield = Tracked, Return = ()>,
+    {
+        let ticks = Rc::new(RefCell::new(Vec::new()));
+        root.into_block()
+            .spawn_tracked(|Work { tag, .. }, _| {
+                move || loop {
+                    yield Either::Left(Tracked::Tick(tag));
+                }
+            })
+            .add_handler({
+                let ticks = ticks.clone();
+                move |effect| match effect {
+                    Tracked::Tick(tag) => {
+                        ticks.borrow_mut().push(tag);
+                        Ok(Scheduled::Ticked)
+                    },
+                    e => Err(e),
+                }
+            })
+            .add_handler(|effect| match effect {
+                Tracked::Idle => Ok(Scheduled::Ticked),
+                e => Err(e),
+            })
+            .assert_handled()
+            .run();
+        let ticks = ticks.borrow().clone();
+        ticks
+    }
+
+    fn outputs(context: &Context<Scheduled>) -> Vec<Scheduled> {
+        std::iter::from_fn(|| context.take())
+            .filter(|o| *o != Scheduled::Ticked)
+            .collect()
+    }
+
+    #[test]
+    fn duplicate_key_rejected() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let ticks = tracked({
+            let seen = seen.clone();
+            move |context| {
+                move || {
+                    yield Tracked::Spawn(Work {
+                        key: Some(1),
+                        tag: 1,
+                    });
+                    yield Tracked::Spawn(Work {
+                        key: Some(1),
+                        tag: 2,
+                    });
+                    yield Tracked::Idle;
+                    seen.borrow_mut().extend(outputs(&context));
+                    yield Tracked::Cancel(TaskRef::Key(1));
+                }
+            }
+        });
+        assert_eq!(
+            *seen.borrow(),
+            vec![
+                Scheduled::Spawned(TaskHandle(0), Some(1)),
+                Scheduled::Duplicate(1),
+            ],
+        );
+        assert!(!ticks.is_empty());
+        assert!(ticks.iter().all(|tag| *tag == 1));
+    }
+
+    #[test]
+    fn cancel_keyless_by_handle() {
+        let ticks = tracked(move |context| {
+            move || {
+                yield Tracked::Spawn(Work { key: None, tag: 3 });
+                let handle = loop {
+                    match outputs(&context).pop() {
+                        Some(Scheduled::Spawned(handle, None)) => break handle,
+                        _ => yield Tracked::Idle,
+                    }
+                };
+                yield Tracked::Cancel(TaskRef::Handle(handle));
+                for _ in 0..3 {
+                    yield Tracked::Idle;
+                }
+            }
+        });
+        assert_eq!(ticks, vec![3]);
+    }
+
+    #[test]
+    fn milestones_of_tasks() {
+        let counted = |id, context: Context<Response>| {
+            let Job { id, .. } = id;
+            move || {
+                crate::milestones!(&context, total = 2);
+                crate::milestone!(&context);
+                yield Either::Left(Req::Ping(id));
+                context.take();
+                crate::milestone!(&context);
+                Ok::<_, ()>(())
+            }
+        };
+        let root = |context: Context<Response>| {
+            move || {
+                crate::milestones!(&context, total = 1);
+                for id in 0..3 {
+                    yield Req::Spawn(Job {
+                        id,
+                        panics: Rc::default(),
+                        fresh: Rc::default(),
+                    });
+                }
+                crate::milestone!(&context);
+            }
+        };
+        let (block, handle) = root
+            .into_block()
+            .spawn_supervised(SpawnOptions::default(), counted)
+            .with_progress();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        block
+            .add_handler_({
+                let seen = seen.clone();
+                let handle = handle.clone();
+                move |effect| {
+                    let snapshot = handle.snapshot();
+                    seen.borrow_mut()
+                        .push((snapshot.live_tasks, snapshot.milestones));
+                    match effect {
+                        Req::Ping(id) => Ok::<_, !>(Response::Pong(id)),
+                        _ => Ok(Response::Idled),
+                    }
+                }
+            })
+            .run();
+        // a task is spawned every round, the one before it finishes meanwhile
+        assert_eq!(
+            *seen.borrow(),
+            [(1, Some((1, 3))), (1, Some((3, 5))), (1, Some((5, 7)))],
+        );
+        let last = handle.snapshot();
+        assert_eq!(last.milestones, Some((7, 7)));
+        assert_eq!(last.live_tasks, 0);
+        assert_eq!(last.fraction(), Some(1.0));
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Conn {
+        Spawn(Link),
+        Route(&'static str),
+        Tick,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Link(u32);
+
+    impl TaskId for Link {
+        type Id = u32;
+
+        fn task_id(&self) -> u32 {
+            self.0
+        }
+    }
+
+    impl Request for Conn {
+        type Task = Link;
+        type Effect = Conn;
+
+        fn is_task(self) -> Result<Link, Self> {
+            match self {
+                Conn::Spawn(link) => Ok(link),
+                s => Err(s),
+            }
+        }
+
+        fn is_effect(self) -> Result<Conn, Self> {
+            Ok(self)
+        }
+
+        fn is_root_question(&self) -> bool {
+            matches!(self, Conn::Route(_))
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Routed {
+        Asked(Question<u32, Conn>),
+        Via(u32),
+        Unanswered,
+        Ticked,
+        Done(u32, Option<u32>),
+        Failed(TaskFailed<u32>),
+    }
+
+    impl HasTaskFailed<u32> for Routed {
+        fn task_failed(failed: TaskFailed<u32>) -> Self {
+            Routed::Failed(failed)
+        }
+    }
+
+    impl HasQuestion<u32, Conn> for Routed {
+        fn question(question: Question<u32, Conn>) -> Self {
+            Routed::Asked(question)
+        }
+
+        fn unanswered(_: Unanswered) -> Self {
+            Routed::Unanswered
+        }
+    }
+
+    // asks for its route, the first resume after the question has the answer
+    fn connection(
+        link: Link,
+        context: Context<Routed>,
+    ) -> impl Unpin + Generator<(), Yield = TaskAction<Conn, Routed, u32>, Return = Result<(), ()>>
+    {
+        move || {
+            let dest = if link.0 == 1 { "a" } else { "b" };
+            perform_task!(Conn::Route(dest));
+            let via = match context.take() {
+                Some(Routed::Via(via)) => Some(via),
+                Some(Routed::Unanswered) => None,
+                other => panic!("resumed before the answer, {:?}", other),
+            };
+            emit!(Routed::Done(link.0, via));
+            Ok(())
+        }
+    }
+
+    fn ticking(ticks: Rc<RefCell<Vec<Conn>>>) -> impl FnMut(Conn) -> Result<Routed, !> {
+        move |effect| {
+            ticks.borrow_mut().push(effect);
+            Ok(Routed::Ticked)
+        }
+    }
+
+    #[test]
+    fn root_answers_out_of_order() {
+        let done = Rc::new(RefCell::new(Vec::new()));
+        let root = {
+            let done = done.clone();
+            move |context: Context<Routed>| {
+                move || {
+                    yield Conn::Spawn(Link(1));
+                    yield Conn::Spawn(Link(2));
+                    let mut asked = Vec::new();
+                    while asked.len() < 2 {
+                        match context.take() {
+                            Some(Routed::Asked(question)) => asked.push(question),
+                            _ => yield Conn::Tick,
+                        }
+                    }
+                    assert_eq!(
+                        asked,
+                        [Question(1, Conn::Route("a")), Question(2, Conn::Route("b")),],
+                    );
+                    crate::answer!(context, 2u32, Routed::Via(20));
+                    crate::answer!(context, 1u32, Routed::Via(10));
+                    while done.borrow().len() < 2 {
+                        match context.take() {
+                            Some(Routed::Done(id, via)) => done.borrow_mut().push((id, via)),
+                            _ => yield Conn::Tick,
+                        }
+                    }
+                }
+            }
+        };
+        let effects = Rc::new(RefCell::new(Vec::new()));
+        root.into_block()
+            .spawn_answering(SpawnOptions::default(), connection)
+            .add_handler_(ticking(effects.clone()))
+            .run();
+        done.borrow_mut().sort();
+        assert_eq!(*done.borrow(), [(1, Some(10)), (2, Some(20))]);
+        // the questions never reach the handlers
+        assert!(effects.borrow().iter().all(|e| *e == Conn::Tick));
+    }
+
+    #[test]
+    fn ignored_question_times_out() {
+        let done = Rc::new(RefCell::new(None));
+        let effects = Rc::new(RefCell::new(Vec::new()));
+        let root = {
+            let done = done.clone();
+            let effects = effects.clone();
+            move |context: Context<Routed>| {
+                move || {
+                    yield Conn::Spawn(Link(1));
+                    loop {
+                        match context.take() {
+                            Some(Routed::Done(id, via)) => {
+                                let ticks = effects.borrow().len();
+                                break *done.borrow_mut() = Some((id, via, ticks));
+                            },
+                            _ => yield Conn::Tick,
+                        }
+                    }
+                }
+            }
+        };
+        root.into_block()
+            .spawn_answering(SpawnOptions::default().question_timeout(3), connection)
+            .add_handler_(ticking(effects))
+            .run();
+        // asked in the first round and resumed in the fourth, the root reads
+        // the output in the fifth after its fourth tick
+        assert_eq!(*done.borrow(), Some((1, None, 4)));
+    }
+
+    #[test]
+    fn typed_args() {
+        enum Serve {
+            Spawn(Worker),
+            Listen(u16, &'static str),
+        }
+
+        // scheduled by the name, the body only needs the port and the banner
+        struct Worker {
+            name: &'static str,
+            port: u16,
+            banner: &'static str,
+        }
+
+        impl TaskId for Worker {
+            type Id = &'static str;
+
+            fn task_id(&self) -> Self::Id {
+                self.name
+            }
+        }
+
+        impl TaskArgs for Worker {
+            type Args = (u16, &'static str);
+
+            fn task_args(self) -> Self::Args {
+                (self.port, self.banner)
+            }
+        }
+
+        impl Request for Serve {
+            type Task = Worker;
+            type Effect = (u16, &'static str);
+
+            fn is_task(self) -> Result<Self::Task, Self> {
+                match self {
+                    Serve::Spawn(worker) => Ok(worker),
+                    other => Err(other),
+                }
+            }
+
+            fn is_effect(self) -> Result<Self::Effect, Self> {
+                match self {
+                    Serve::Listen(port, banner) => Ok((port, banner)),
+                    other => Err(other),
+                }
+            }
+        }
+
+        let listening = Rc::new(RefCell::new(Vec::new()));
+        (|_: Context<()>| {
+            move || {
+                yield Serve::Spawn(Worker {
+                    name: "http",
+                    port: 80,
+                    banner: "plain",
+                });
+                yield Serve::Spawn(Worker {
+                    name: "https",
+                    port: 443,
+                    banner: "tls",
+                });
+            }
+        })
+        .into_block()
+        .spawn_with(|(port, banner)| move || perform_task!(Serve::Listen(port, banner)))
+        .add_handler_({
+            let listening = listening.clone();
+            move |listen| {
+                listening.borrow_mut().push(listen);
+                Ok::<_, !>(())
+            }
+        })
+        .run();
+        listening.borrow_mut().sort();
+        assert_eq!(*listening.borrow(), [(80, "plain"), (443, "tls")]);
+    }
+}
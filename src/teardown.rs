@@ -0,0 +1,149 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{error::Error, fmt, ops::Generator};
+use super::{
+    block::Block,
+    computation::Effect,
+    routing::{self, HandlerTag},
+};
+
+// the handler an effect would have been offered first had finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HandlerFinished {
+    pub handler: HandlerTag,
+}
+
+impl fmt::Display for HandlerFinished {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "handler `{}` has finished", self.handler.name())
+    }
+}
+
+impl Error for HandlerFinished {}
+
+pub trait HasHandlerFinished
+where
+    Self: Effect,
+{
+    fn handler_finished(error: HandlerFinished) -> Self;
+}
+
+impl<E, G> Block<E, G>
+where
+    E: HasHandlerFinished + 'static,
+    E::Input: 'static,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Finishes the tagged handlers in reverse installation order, for a block
+    // driven by `resume` that stops before its computation completes. Call it
+    // once the layers inside yielded their last effects. An effect reaching
+    // the handlers later is answered with `HandlerFinished` instead of being
+    // offered, one no handler would have been offered is yielded as before.
+    pub fn finish_handlers(&self) {
+        routing::finish_tagged(&self.context(), E::handler_finished);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        cell::RefCell,
+        ops::{Generator, GeneratorState},
+    };
+    use crate::{Context, Effect, Handler, HandlerTag, IntoBlock, perform};
+    use super::{HandlerFinished, HasHandlerFinished};
+
+    #[derive(Debug)]
+    enum Effects {
+        Get(u32),
+        Send(&'static str),
+        Pause,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Got(u32),
+        Sent(&'static str),
+        Finished(HandlerFinished),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl HasHandlerFinished for Outputs {
+        fn handler_finished(error: HandlerFinished) -> Self {
+            Outputs::Finished(error)
+        }
+    }
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    struct Service {
+        name: &'static str,
+        log: Log,
+    }
+
+    impl Handler<Outputs> for Service {
+        fn handle(&mut self, effect: Effects) -> Result<Outputs, Effects> {
+            match (self.name, effect) {
+                ("store", Effects::Get(key)) => Ok(Outputs::Got(key)),
+                ("net", Effects::Send(message)) => Ok(Outputs::Sent(message)),
+                (_, effect) => Err(effect),
+            }
+        }
+
+        fn finish(&mut self) {
+            self.log.borrow_mut().push(format!("finish {}", self.name));
+        }
+    }
+
+    fn client(
+        context: Context<Outputs>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = Vec<Outputs>> {
+        move || {
+            let mut seen = Vec::new();
+            for effect in [Effects::Get(1), Effects::Pause, Effects::Send("bye")] {
+                perform!(effect);
+                seen.extend(context.take());
+            }
+            seen
+        }
+    }
+
+    #[test]
+    fn late_effect_rejected() {
+        let log = Log::default();
+        let net = HandlerTag::new("net");
+        let service = |name| Service {
+            name,
+            log: log.clone(),
+        };
+        let mut block = client
+            .into_block()
+            .add_handler_tagged(service("store"), HandlerTag::new("store"))
+            .add_handler_tagged(service("net"), net)
+            .route(|effect| matches!(effect, Effects::Send(_)), net);
+        assert!(matches!(
+            block.resume(),
+            GeneratorState::Yielded(Effects::Pause)
+        ));
+        block.finish_handlers();
+        // the second time does nothing
+        block.finish_handlers();
+        assert_eq!(*log.borrow(), ["finish net", "finish store"]);
+
+        let error = HandlerFinished { handler: net };
+        match block.resume() {
+            GeneratorState::Complete(seen) => {
+                assert_eq!(seen, [Outputs::Got(1), Outputs::Finished(error.clone())])
+            },
+            GeneratorState::Yielded(effect) => panic!("{:?}", effect),
+        }
+        assert_eq!(error.to_string(), "handler `net` has finished");
+        assert_eq!(log.borrow().len(), 2);
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::ops::Generator;
+use super::{
+    block::{Block, IntoBlockWith},
+    context::Context,
+};
+
+// A computation kept to be instantiated many times, each block gets a fresh
+// context. The default arguments are cloned for every `instantiate`, the ones
+// given to `instantiate_with` are moved in, so they need not be `Clone`.
+pub struct Template<F, Args> {
+    constructor: F,
+    args: Args,
+}
+
+impl<F, Args> Template<F, Args> {
+    pub fn new(constructor: F, args: Args) -> Self {
+        Template { constructor, args }
+    }
+
+    pub fn instantiate_with<T, G>(&self, args: Args) -> Block<T, G>
+    where
+        F: Fn(Args, Context<T>) -> G,
+        G: Unpin + Generator<()>,
+    {
+        (&self.constructor).into_block_with(args)
+    }
+
+    pub fn instantiate<T, G>(&self) -> Block<T, G>
+    where
+        Args: Clone,
+        F: Fn(Args, Context<T>) -> G,
+        G: Unpin + Generator<()>,
+    {
+        self.instantiate_with(self.args.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        cell::{Cell, RefCell},
+        ops::Generator,
+    };
+    use crate::{Block, Context, Effect, IntoBlockWith, perform};
+    use super::Template;
+
+    #[derive(Debug, PartialEq)]
+    enum Effects {
+        Listen(u16),
+        Accept,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Listening,
+        Accepted(u32),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    // the clones the template made so far
+    #[derive(Default)]
+    struct Config {
+        port: u16,
+        clones: Rc<Cell<usize>>,
+    }
+
+    impl Clone for Config {
+        fn clone(&self) -> Self {
+            self.clones.set(self.clones.get() + 1);
+            Config {
+                port: self.port,
+                clones: self.clones.clone(),
+            }
+        }
+    }
+
+    fn server(
+        config: Config,
+        context: Context<Outputs>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = Option<Outputs>> {
+        move || {
+            perform!(Effects::Listen(config.port));
+            assert_eq!(context.take(), Some(Outputs::Listening));
+            perform!(Effects::Accept);
+            context.take()
+        }
+    }
+
+    fn listened<G>(block: Block<Outputs, G>) -> Vec<Effects>
+    where
+        G: Unpin + Generator<(), Yield = Effects, Return = Option<Outputs>>,
+    {
+        let listened = Rc::new(RefCell::new(Vec::new()));
+        let accepted = block
+            .add_handler({
+                let listened = listened.clone();
+                move |effect| match effect {
+                    Effects::Listen(_) => {
+                        listened.borrow_mut().push(effect);
+                        Ok(Outputs::Listening)
+                    },
+                    Effects::Accept => Ok(Outputs::Accepted(1)),
+                }
+            })
+            .assert_handled()
+            .run();
+        assert_eq!(accepted, Some(Outputs::Accepted(1)));
+        listened.replace(Vec::new())
+    }
+
+    #[test]
+    fn two_ports() {
+        let config = |port| Config {
+            port,
+            ..Default::default()
+        };
+        let http = listened(server.into_block_with(config(80)));
+        let https = listened(server.into_block_with(config(443)));
+        assert_eq!(
+            (http, https),
+            (vec![Effects::Listen(80)], vec![Effects::Listen(443)])
+        );
+    }
+
+    #[test]
+    fn cloned_for_default() {
+        let clones = Rc::new(Cell::new(0));
+        let template = Template::new(
+            server,
+            Config {
+                port: 8080,
+                clones: clones.clone(),
+            },
+        );
+        assert_eq!(listened(template.instantiate()), [Effects::Listen(8080)]);
+        assert_eq!(listened(template.instantiate()), [Effects::Listen(8080)]);
+        assert_eq!(clones.get(), 2);
+        let config = Config {
+            port: 9090,
+            clones: clones.clone(),
+        };
+        assert_eq!(
+            listened(template.instantiate_with(config)),
+            [Effects::Listen(9090)]
+        );
+        assert_eq!(clones.get(), 2);
+    }
+
+    // not `Clone`, fine without a template
+    #[test]
+    fn moved_args() {
+        struct Port(u16);
+        let block = (|Port(port), _: Context<Outputs>| {
+            move || {
+                perform!(Effects::Listen(port));
+                perform!(Effects::Accept);
+                Some(Outputs::Accepted(1))
+            }
+        })
+        .into_block_with(Port(22));
+        assert_eq!(listened(block), [Effects::Listen(22)]);
+    }
+}
@@ -0,0 +1,780 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::VecDeque,
+    fmt,
+    error::Error,
+    ops::{Generator, GeneratorState},
+    panic::{self, AssertUnwindSafe},
+};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use serde_json::Value;
+use super::{
+    block::Block,
+    codec::{Codec, CodecError},
+    computation::{Effect, Handler},
+    context::Context,
+    idempotency::IdempotencyToken,
+    health::HealthStatus,
+    plugin::{ResourceKey, Resources},
+};
+pub use super::differential::TailDiff;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recording {
+    pub version: u32,
+    pub events: Vec<Value>,
+}
+
+impl Recording {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("recording is plain json")
+    }
+
+    pub fn from_json(s: &str) -> Result<Self, MigrateError> {
+        serde_json::from_str(s).map_err(|e| MigrateError::Decode {
+            index: None,
+            message: e.to_string(),
+        })
+    }
+
+    pub fn encode<C>(&self, codec: &C) -> Result<Vec<u8>, CodecError>
+    where
+        C: Codec,
+    {
+        let mut bytes = Vec::new();
+        codec.encode(self, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn decode<C>(codec: &C, bytes: &[u8]) -> Result<Self, CodecError>
+    where
+        C: Codec,
+    {
+        codec.decode(bytes)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrateError {
+    UnknownVersion {
+        found: u32,
+        current: u32,
+    },
+    MissingStep {
+        from: u32,
+        to: u32,
+    },
+    Step {
+        from: u32,
+        message: String,
+    },
+    Decode {
+        index: Option<usize>,
+        message: String,
+    },
+}
+
+impl fmt::Display for MigrateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrateError::UnknownVersion { found, current } => write!(
+                f,
+                "recording version {} is newer than current version {}",
+                found, current
+            ),
+            MigrateError::MissingStep { from, to } => {
+                write!(f, "no migration from version {} towards {}", from, to)
+            },
+            MigrateError::Step { from, message } => {
+                write!(f, "migration from version {} failed: {}", from, message)
+            },
+            MigrateError::Decode {
+                index: Some(index),
+                message,
+            } => write!(f, "cannot decode event {}: {}", index, message),
+            MigrateError::Decode {
+                index: None,
+                message,
+            } => write!(f, "cannot decode recording: {}", message),
+        }
+    }
+}
+
+impl Error for MigrateError {}
+
+pub type MigrationStep = fn(Value, u32) -> Result<Value, MigrateError>;
+
+#[derive(Default, Clone)]
+pub struct MigrationChain(Vec<(u32, MigrationStep)>);
+
+impl MigrationChain {
+    pub fn new() -> Self {
+        MigrationChain::default()
+    }
+
+    pub fn step(mut self, from: u32, step: MigrationStep) -> Self {
+        self.0.push((from, step));
+        self
+    }
+
+    pub fn migrate(&self, event: Value, from: u32, to: u32) -> Result<Value, MigrateError> {
+        if from > to {
+            return Err(MigrateError::UnknownVersion {
+                found: from,
+                current: to,
+            });
+        }
+        let mut event = event;
+        for version in from..to {
+            let step = self
+                .0
+                .iter()
+                .find(|(f, _)| *f == version)
+                .map(|(_, step)| *step)
+                .ok_or(MigrateError::MissingStep { from: version, to })?;
+            event = step(event, version)?;
+        }
+        Ok(event)
+    }
+}
+
+#[macro_export]
+macro_rules! migrations {
+    ($($from:expr => $step:expr),* $(,)?) => {
+        $crate::trace::MigrationChain::new()$(.step($from, $step))*
+    };
+}
+
+#[derive(Clone)]
+pub struct RecordingHandle(Rc<RefCell<Recording>>);
+
+impl RecordingHandle {
+    fn new(version: u32) -> Self {
+        RecordingHandle(Rc::new(RefCell::new(Recording {
+            version,
+            events: Vec::new(),
+        })))
+    }
+
+    fn push<E>(&self, output: &E)
+    where
+        E: Serialize,
+    {
+        let event = serde_json::to_value(output).expect("recorded output must serialize");
+        self.0.borrow_mut().events.push(event);
+    }
+
+    pub fn recording(&self) -> Recording {
+        self.0.borrow().clone()
+    }
+}
+
+pub struct Recorder<H> {
+    inner: H,
+    recording: RecordingHandle,
+}
+
+pub fn record<H>(handler: H, version: u32) -> (Recorder<H>, RecordingHandle) {
+    let recording = RecordingHandle::new(version);
+    let recorder = Recorder {
+        inner: handler,
+        recording: recording.clone(),
+    };
+    (recorder, recording)
+}
+
+impl<E, H> Handler<E> for Recorder<H>
+where
+    E: Effect + Serialize,
+    H: Handler<E>,
+{
+    fn handle(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        let output = self.inner.handle(effect)?;
+        self.recording.push(&output);
+        Ok(output)
+    }
+
+    fn handle_idempotent(
+        &mut self,
+        token: IdempotencyToken,
+        effect: E::Input,
+    ) -> Result<E, E::Input> {
+        let output = self.inner.handle_idempotent(token, effect)?;
+        self.recording.push(&output);
+        Ok(output)
+    }
+
+    fn init(&mut self) {
+        self.inner.init()
+    }
+
+    fn finish(&mut self) {
+        self.inner.finish()
+    }
+
+    fn health_check(&mut self) -> HealthStatus {
+        self.inner.health_check()
+    }
+
+    fn provides(&self) -> &'static [ResourceKey] {
+        self.inner.provides()
+    }
+
+    fn requires(&self) -> &'static [ResourceKey] {
+        self.inner.requires()
+    }
+
+    fn init_with(&mut self, resources: &mut Resources) {
+        self.inner.init_with(resources)
+    }
+
+    fn flush(&mut self) -> Result<(), E::Input> {
+        self.inner.flush()
+    }
+}
+
+pub struct ReplayHandler<E>(VecDeque<E>);
+
+impl<E> ReplayHandler<E>
+where
+    E: DeserializeOwned,
+{
+    pub fn new(recording: Recording, current: u32) -> Result<Self, MigrateError> {
+        Self::with_migrations(recording, current, &MigrationChain::new())
+    }
+
+    pub fn with_migrations(
+        recording: Recording,
+        current: u32,
+        chain: &MigrationChain,
+    ) -> Result<Self, MigrateError> {
+        let Recording { version, events } = recording;
+        if version > current {
+            return Err(MigrateError::UnknownVersion {
+                found: version,
+                current,
+            });
+        }
+        events
+            .into_iter()
+            .enumerate()
+            .map(|(index, event)| {
+                let event = chain.migrate(event, version, current)?;
+                serde_json::from_value(event).map_err(|e| MigrateError::Decode {
+                    index: Some(index),
+                    message: e.to_string(),
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(ReplayHandler)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl<E> Handler<E> for ReplayHandler<E>
+where
+    E: Effect,
+{
+    fn handle(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        self.0.pop_front().ok_or(effect)
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + Serialize,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    // Install right after `into_block`, the recording keeps every output the
+    // handlers answer with, the ones the computation puts itself are left out.
+    pub fn persistent_versioned(
+        self,
+        version: u32,
+    ) -> (
+        Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>,
+        RecordingHandle,
+    ) {
+        let recording = RecordingHandle::new(version);
+        let context = self.context();
+        let mut s = self;
+        let generator = {
+            let recording = recording.clone();
+            let context = context.clone();
+            move || loop {
+                match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(effect) => {
+                        let mark = context.produced();
+                        yield effect;
+                        // the answers are the last ones queued
+                        let answered = (context.produced() - mark) as usize;
+                        let skip = context.len().saturating_sub(answered);
+                        let mut index = 0;
+                        context.iter_with(|output| {
+                            if index >= skip {
+                                recording.push(output);
+                            }
+                            index += 1;
+                        });
+                    },
+                }
+            }
+        };
+        (Block::new(context, generator), recording)
+    }
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect + DeserializeOwned,
+    G: Unpin + Generator<(), Yield = E::Input>,
+    G::Yield: fmt::Debug,
+{
+    // Answers the first effects from a recording of an earlier run, migrated
+    // to `current`, the handlers added after it take over once it runs out.
+    // Installed after `persistent_versioned`, the new recording starts with
+    // the migrated events.
+    pub fn resume_persistent(
+        self,
+        recording: Recording,
+        current: u32,
+        chain: &MigrationChain,
+    ) -> Result<
+        Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>>,
+        MigrateError,
+    > {
+        let replay = ReplayHandler::with_migrations(recording, current, chain)?;
+        Ok(self.add_handler(replay))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DivergenceOutcome<I, R> {
+    Completed(R),
+    Diverged { diff: TailDiff<I>, result: R },
+    Panicked(String),
+    Unhandled(I),
+    CutOff { rounds: usize },
+}
+
+enum End<I, R> {
+    Completed(R),
+    Panicked(String),
+    Unhandled(I),
+    CutOff,
+}
+
+struct Run<I, R> {
+    effects: Vec<I>,
+    end: End<I, R>,
+}
+
+type Live<E> = Box<dyn FnMut() -> Box<dyn Handler<E>>>;
+
+// Without a live handler the responses recorded after the mutated step are
+// given back by position, an effect past the end of the recording is unhandled.
+pub struct Explorer<E, F> {
+    constructor: F,
+    responses: Vec<E>,
+    live: Option<Live<E>>,
+    budget: usize,
+}
+
+impl<E, F, G> Explorer<E, F>
+where
+    E: Effect + Clone,
+    E::Input: Clone + PartialEq,
+    F: Fn(Context<E>) -> G,
+    G: Unpin + Generator<(), Yield = E::Input>,
+{
+    pub fn new(constructor: F, recording: &ReplayHandler<E>) -> Self {
+        Explorer {
+            constructor,
+            responses: recording.0.iter().cloned().collect(),
+            live: None,
+            budget: 1024,
+        }
+    }
+
+    // the handler is created anew for every mutation
+    pub fn live<H, L>(mut self, mut live: L) -> Self
+    where
+        H: Handler<E> + 'static,
+        L: FnMut() -> H + 'static,
+    {
+        self.live = Some(Box::new(move || Box::new(live())));
+        self
+    }
+
+    // rounds are counted per exploration, every resume is a round
+    pub fn budget(mut self, rounds: usize) -> Self {
+        self.budget = rounds;
+        self
+    }
+
+    pub fn explore<M>(&mut self, mutations: M) -> Vec<DivergenceOutcome<E::Input, G::Return>>
+    where
+        M: IntoIterator<Item = (usize, E)>,
+    {
+        let original = self.drive(None).effects;
+        mutations
+            .into_iter()
+            .map(|(step, response)| {
+                let Run { effects, end } = self.drive(Some((step, response)));
+                match end {
+                    End::Completed(result) => {
+                        match TailDiff::between(&original, &effects, step + 1) {
+                            None => DivergenceOutcome::Completed(result),
+                            Some(diff) => DivergenceOutcome::Diverged { diff, result },
+                        }
+                    },
+                    End::Panicked(message) => DivergenceOutcome::Panicked(message),
+                    End::Unhandled(effect) => DivergenceOutcome::Unhandled(effect),
+                    End::CutOff => DivergenceOutcome::CutOff {
+                        rounds: self.budget,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    fn drive(&mut self, mutation: Option<(usize, E)>) -> Run<E::Input, G::Return> {
+        let context = Context::empty();
+        let mut block = Block::new(context.clone(), (self.constructor)(context));
+        let mut live = match (&mutation, &mut self.live) {
+            (Some(_), Some(live)) => Some(live()),
+            _ => None,
+        };
+        if let Some(live) = &mut live {
+            live.init();
+        }
+        let mut effects = Vec::new();
+        let end = loop {
+            if effects.len() >= self.budget {
+                break End::CutOff;
+            }
+            let effect = match panic::catch_unwind(AssertUnwindSafe(|| block.resume())) {
+                Ok(GeneratorState::Complete(result)) => break End::Completed(result),
+                Ok(GeneratorState::Yielded(effect)) => effect,
+                Err(payload) => {
+                    break End::Panicked(
+                        payload
+                            .downcast_ref::<&str>()
+                            .map(ToString::to_string)
+                            .or_else(|| payload.downcast_ref::<String>().cloned())
+                            .unwrap_or_default(),
+                    )
+                },
+            };
+            let index = effects.len();
+            effects.push(effect.clone());
+            let response = match (&mutation, &mut live) {
+                (Some((step, response)), _) if index == *step => Ok(response.clone()),
+                (Some((step, _)), Some(live)) if index > *step => live.handle(effect),
+                _ => self.responses.get(index).cloned().ok_or(effect),
+            };
+            match response {
+                Ok(response) => block.put(response),
+                Err(effect) => break End::Unhandled(effect),
+            }
+        };
+        if let Some(live) = &mut live {
+            live.finish();
+        }
+        Run { effects, end }
+    }
+}
+
+pub fn explore<E, F, G, M>(
+    constructor: F,
+    recording: &ReplayHandler<E>,
+    mutations: M,
+) -> Vec<DivergenceOutcome<E::Input, G::Return>>
+where
+    E: Effect + Clone,
+    E::Input: Clone + PartialEq,
+    F: Fn(Context<E>) -> G,
+    G: Unpin + Generator<(), Yield = E::Input>,
+    M: IntoIterator<Item = (usize, E)>,
+{
+    Explorer::new(constructor, recording).explore(mutations)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::Generator};
+    use serde::{Serialize, Deserialize};
+    use serde_json::Value;
+    use crate::{Context, Effect, Select, IntoBlock, perform, migrations};
+    use super::{
+        Recording, ReplayHandler, MigrateError, DivergenceOutcome, TailDiff, Explorer, record,
+        explore,
+    };
+
+    #[derive(Debug)]
+    enum Effects {
+        Greet,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Outputs {
+        Greeting { message: String },
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    struct Greeting(String);
+
+    impl Select<Greeting> for Outputs {
+        fn take(output: &Context<Self>) -> Option<Greeting> {
+            match output.take()? {
+                Outputs::Greeting { message } => Some(Greeting(message)),
+            }
+        }
+    }
+
+    const V1: &str =
+        r#"{"version":1,"events":[{"Greeting":{"text":"hi"}},{"Greeting":{"text":"bye"}}]}"#;
+
+    fn rename_text(mut event: Value, _: u32) -> Result<Value, MigrateError> {
+        if let Some(fields) = event.get_mut("Greeting").and_then(Value::as_object_mut) {
+            if let Some(text) = fields.remove("text") {
+                fields.insert("message".to_string(), text);
+            }
+        }
+        Ok(event)
+    }
+
+    fn greeter(
+        context: Context<Outputs>,
+        seen: Rc<RefCell<Vec<String>>>,
+    ) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+        move || {
+            for _ in 0..2 {
+                let Greeting(message) = perform!(Effects::Greet, &context);
+                seen.borrow_mut().push(message);
+            }
+        }
+    }
+
+    #[test]
+    fn replay_migrated() {
+        let recording = Recording::from_json(V1).unwrap();
+        let handler =
+            ReplayHandler::<Outputs>::with_migrations(recording, 2, &migrations![1 => rename_text])
+                .unwrap();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        (|context| greeter(context, seen.clone()))
+            .into_block()
+            .add_handler(handler)
+            .assert_handled()
+            .run();
+        assert_eq!(*seen.borrow(), vec!["hi".to_string(), "bye".to_string()]);
+    }
+
+    #[test]
+    fn record_then_replay() {
+        let (recorder, handle) = record(
+            |Effects::Greet| {
+                Ok(Outputs::Greeting {
+                    message: "hey".to_string(),
+                })
+            },
+            2,
+        );
+        (|context| greeter(context, Default::default()))
+            .into_block()
+            .add_handler(recorder)
+            .assert_handled()
+            .run();
+        let json = handle.recording().to_json();
+        let handler =
+            ReplayHandler::<Outputs>::new(Recording::from_json(&json).unwrap(), 2).unwrap();
+        assert_eq!(handler.remaining(), 2);
+    }
+
+    #[test]
+    fn resume_migrated_session() {
+        // the session stopped after the first greeting
+        let mut recording = Recording::from_json(V1).unwrap();
+        recording.events.truncate(1);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let (block, handle) = (|context| greeter(context, seen.clone()))
+            .into_block()
+            .persistent_versioned(2);
+        block
+            .resume_persistent(recording, 2, &migrations![1 => rename_text])
+            .unwrap()
+            .add_handler(|Effects::Greet| {
+                Ok(Outputs::Greeting {
+                    message: "live".to_string(),
+                })
+            })
+            .assert_handled()
+            .run();
+        assert_eq!(*seen.borrow(), vec!["hi".to_string(), "live".to_string()]);
+        assert_eq!(
+            handle.recording().to_json(),
+            r#"{"version":2,"events":[{"Greeting":{"message":"hi"}},{"Greeting":{"message":"live"}}]}"#,
+        );
+    }
+
+    #[test]
+    fn resume_with_gap() {
+        let recording = Recording::from_json(V1).unwrap();
+        let resumed = (|context| greeter(context, Default::default()))
+            .into_block()
+            .resume_persistent(recording, 3, &migrations![1 => rename_text]);
+        assert_eq!(
+            resumed.err(),
+            Some(MigrateError::MissingStep { from: 2, to: 3 })
+        );
+    }
+
+    #[test]
+    fn migration_errors() {
+        let recording = Recording::from_json(V1).unwrap();
+        let gap = ReplayHandler::<Outputs>::with_migrations(
+            recording.clone(),
+            3,
+            &migrations![1 => rename_text],
+        );
+        assert_eq!(
+            gap.err(),
+            Some(MigrateError::MissingStep { from: 2, to: 3 })
+        );
+
+        let future = Recording {
+            version: 4,
+            ..recording
+        };
+        let unknown = ReplayHandler::<Outputs>::new(future, 2);
+        assert_eq!(
+            unknown.err(),
+            Some(MigrateError::UnknownVersion {
+                found: 4,
+                current: 2
+            }),
+        );
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Steps {
+        Ask,
+        Log,
+        Alarm,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    enum Answers {
+        Number(u32),
+        Ack,
+    }
+
+    impl Effect for Answers {
+        type Input = Steps;
+    }
+
+    struct Number(u32);
+
+    impl Select<Number> for Answers {
+        fn take(output: &Context<Self>) -> Option<Number> {
+            match output.take()? {
+                Answers::Number(n) => Some(Number(n)),
+                other => {
+                    output.put_front(other);
+                    None
+                },
+            }
+        }
+    }
+
+    struct Ack;
+
+    impl Select<Ack> for Answers {
+        fn take(output: &Context<Self>) -> Option<Ack> {
+            match output.take()? {
+                Answers::Ack => Some(Ack),
+                other => {
+                    output.put_front(other);
+                    None
+                },
+            }
+        }
+    }
+
+    fn branching(
+        context: Context<Answers>,
+    ) -> impl Unpin + Generator<(), Yield = Steps, Return = u32> {
+        move || {
+            let Number(n) = perform!(Steps::Ask, &context);
+            if n == 0 {
+                loop {
+                    let Ack = perform!(Steps::Log, &context);
+                }
+            }
+            let Ack = if n > 10 {
+                perform!(Steps::Alarm, &context)
+            } else {
+                perform!(Steps::Log, &context)
+            };
+            n
+        }
+    }
+
+    fn recorded() -> ReplayHandler<Answers> {
+        let recording = Recording {
+            version: 1,
+            events: vec![
+                serde_json::to_value(Answers::Number(3)).unwrap(),
+                serde_json::to_value(Answers::Ack).unwrap(),
+            ],
+        };
+        ReplayHandler::new(recording, 1).unwrap()
+    }
+
+    #[test]
+    fn explore_branch() {
+        let outcomes = explore(
+            branching,
+            &recorded(),
+            vec![(0, Answers::Number(5)), (0, Answers::Number(20))],
+        );
+        assert_eq!(
+            outcomes,
+            vec![
+                DivergenceOutcome::Completed(5),
+                DivergenceOutcome::Diverged {
+                    diff: TailDiff {
+                        step: 1,
+                        original: vec![Steps::Log],
+                        explored: vec![Steps::Alarm],
+                    },
+                    result: 20,
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn explore_cut_off() {
+        let outcomes = Explorer::new(branching, &recorded())
+            .live(|| |_| Ok(Answers::Ack))
+            .budget(100)
+            .explore(Some((0, Answers::Number(0))));
+        assert_eq!(outcomes, vec![DivergenceOutcome::CutOff { rounds: 100 }]);
+
+        // the recording alone runs out of responses
+        let outcomes = explore(branching, &recorded(), Some((0, Answers::Number(0))));
+        assert_eq!(outcomes, vec![DivergenceOutcome::Unhandled(Steps::Log)]);
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    marker::PhantomData,
+    ops::{Generator, GeneratorState},
+    pin::Pin,
+};
+use either::Either;
+use super::{block::Block, computation::Effect, context::Context, fallible::BoxedGenerator};
+
+type Frame<I> = BoxedGenerator<Either<I, PushFrame<I>>, Box<dyn Any>>;
+
+// asks `trampoline` to run a callee on its stack of frames
+pub struct PushFrame<I> {
+    id: u64,
+    frame: Frame<I>,
+}
+
+// where the callee's return value is found once it is done
+pub struct FrameId<R> {
+    id: u64,
+    phantom_data: PhantomData<R>,
+}
+
+#[derive(Default)]
+struct Frames {
+    next: Cell<u64>,
+    returned: RefCell<BTreeMap<u64, Box<dyn Any>>>,
+}
+
+#[doc(hidden)]
+pub fn push<E, I, G>(
+    context: &Context<E>,
+    callee: G,
+) -> (Either<I, PushFrame<I>>, FrameId<G::Return>)
+where
+    I: 'static,
+    G: Unpin + Generator<(), Yield = Either<I, PushFrame<I>>> + 'static,
+    G::Return: 'static,
+{
+    let frames = context.extension(Frames::default);
+    let id = frames.next.get();
+    frames.next.set(id + 1);
+    let frame: Frame<I> = Box::new(move || -> Box<dyn Any> { Box::new(crate::call!(callee)) });
+    let handle = FrameId {
+        id,
+        phantom_data: PhantomData,
+    };
+    (Either::Right(PushFrame { id, frame }), handle)
+}
+
+#[doc(hidden)]
+pub fn returned<E, R>(context: &Context<E>, id: FrameId<R>) -> R
+where
+    R: 'static,
+{
+    let frames = context.extension(Frames::default);
+    let value = frames
+        .returned
+        .borrow_mut()
+        .remove(&id.id)
+        .expect("the frame is resumed only after its callee returned");
+    *value.downcast().expect("the frame id is typed")
+}
+
+impl<E, G> Block<E, G>
+where
+    E: Effect,
+    G: Unpin + Generator<(), Yield = Either<E::Input, PushFrame<E::Input>>>,
+{
+    // Runs the callees of `call_boxed!` in a flat loop, only the top frame is
+    // resumed, so the depth of the calls costs heap and not stack. The value a
+    // callee returns is kept by its frame id until the caller takes it.
+    pub fn trampoline(
+        self,
+    ) -> Block<E, impl Unpin + Generator<(), Return = G::Return, Yield = E::Input>> {
+        let context = self.context();
+        let frames = context.extension(Frames::default);
+        let mut stack = Vec::<(u64, Frame<E::Input>)>::new();
+        let mut s = self;
+        let generator = move || loop {
+            let step = match stack.last_mut() {
+                None => match s.resume() {
+                    GeneratorState::Complete(r) => return r,
+                    GeneratorState::Yielded(step) => step,
+                },
+                Some((id, frame)) => {
+                    s.context().flush_deferred();
+                    match Pin::new(frame).resume(()) {
+                        GeneratorState::Complete(value) => {
+                            frames.returned.borrow_mut().insert(*id, value);
+                            stack.pop();
+                            continue;
+                        },
+                        GeneratorState::Yielded(step) => step,
+                    }
+                },
+            };
+            match step {
+                Either::Left(effect) => yield effect,
+                Either::Right(PushFrame { id, frame }) => stack.push((id, frame)),
+            }
+        };
+        Block::new(context, generator)
+    }
+}
+
+// Like `call!`, but the callee runs as a frame of the `trampoline` layer
+// instead of inside the caller, for recursion that goes deep. The callee yields
+// `Either<effect, PushFrame>` like the caller does.
+#[macro_export]
+macro_rules! call_boxed {
+    ($callee:expr, $ctx:expr) => {{
+        let (push, id) = $crate::trampoline::push($ctx, $callee);
+        yield push;
+        $crate::trampoline::returned($ctx, id)
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, ops::Generator};
+    use either::Either;
+    use crate::{Context, Effect, IntoBlock, perform};
+    use super::PushFrame;
+
+    #[derive(Debug)]
+    enum Effects {
+        Probe(u32),
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outputs {
+        Probed(u32),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    type Step = Either<Effects, PushFrame<Effects>>;
+
+    // counts the levels on the way back up
+    fn descend(
+        context: Context<Outputs>,
+        level: u32,
+    ) -> impl Unpin + Generator<(), Yield = Step, Return = u32> {
+        move || {
+            if level == 0 {
+                return 0;
+            }
+            let below = call_boxed!(descend(context.clone(), level - 1), &context);
+            below + 1
+        }
+    }
+
+    #[test]
+    fn deep() {
+        // the inline `call!` nests a native frame per level, with a stack this
+        // small it overflows long before; the trampoline does not care
+        let levels = thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(|| {
+                (|context| descend(context, 10_000))
+                    .into_block()
+                    .trampoline()
+                    .assert_handled()
+                    .run()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(levels, 10_000);
+    }
+
+    // every level probes before and after the call, each response has to
+    // reach the frame that performed the probe
+    fn probing(
+        context: Context<Outputs>,
+        level: u32,
+    ) -> impl Unpin + Generator<(), Yield = Step, Return = Vec<u32>> {
+        move || {
+            yield Either::Left(Effects::Probe(level));
+            let before = context.take();
+            assert_eq!(before, Some(Outputs::Probed(level)));
+            let mut trail = if level == 0 {
+                vec![]
+            } else {
+                call_boxed!(probing(context.clone(), level - 1), &context)
+            };
+            yield Either::Left(Effects::Probe(level));
+            assert_eq!(context.take(), Some(Outputs::Probed(level)));
+            trail.push(level);
+            trail
+        }
+    }
+
+    #[test]
+    fn effects_at_depth() {
+        let root = |context: Context<Outputs>| {
+            move || {
+                perform!(Either::Left(Effects::Probe(100)));
+                let first = context.take();
+                let trail = call_boxed!(probing(context.clone(), 5), &context);
+                (first, trail)
+            }
+        };
+        let (first, trail) = root
+            .into_block()
+            .trampoline()
+            .add_handler(|Effects::Probe(level)| Ok(Outputs::Probed(level)))
+            .assert_handled()
+            .run();
+        assert_eq!(first, Some(Outputs::Probed(100)));
+        assert_eq!(trail, [0, 1, 2, 3, 4, 5]);
+    }
+}
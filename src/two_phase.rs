@@ -0,0 +1,272 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    marker::PhantomData,
+    collections::{BTreeSet, VecDeque},
+};
+use super::{
+    computation::{Effect, Handler, Select},
+    context::Context,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OpId(pub u64);
+
+pub trait TwoPhase
+where
+    Self: Effect,
+{
+    fn ack(id: OpId) -> Self;
+    fn is_ack(&self) -> Option<OpId>;
+    fn is_completion(&self) -> Option<OpId>;
+}
+
+pub struct Operation<Part> {
+    id: OpId,
+    phantom_data: PhantomData<Part>,
+}
+
+impl<Part> Operation<Part> {
+    pub fn new(id: OpId) -> Self {
+        Operation {
+            id,
+            phantom_data: PhantomData,
+        }
+    }
+
+    pub fn id(&self) -> OpId {
+        self.id
+    }
+
+    pub fn select<E>(&self, context: &Context<E>) -> Option<Part>
+    where
+        E: Select<Part>,
+    {
+        E::take(context)
+    }
+}
+
+struct State<E> {
+    next: u64,
+    in_flight: BTreeSet<OpId>,
+    ready: VecDeque<E>,
+    orphan: Option<Box<dyn FnMut(OpId, E)>>,
+}
+
+pub struct Completer<E>(Rc<RefCell<State<E>>>);
+
+impl<E> Clone for Completer<E> {
+    fn clone(&self) -> Self {
+        Completer(self.0.clone())
+    }
+}
+
+impl<E> Completer<E> {
+    pub fn complete(&self, id: OpId, output: E) {
+        let mut state = self.0.borrow_mut();
+        if state.in_flight.remove(&id) {
+            state.ready.push_back(output);
+        } else if let Some(mut orphan) = state.orphan.take() {
+            drop(state);
+            orphan(id, output);
+            self.0.borrow_mut().orphan = Some(orphan);
+        }
+    }
+
+    pub fn in_flight(&self) -> Vec<OpId> {
+        self.0.borrow().in_flight.iter().cloned().collect()
+    }
+
+    pub fn on_orphan<F>(&self, f: F)
+    where
+        F: FnMut(OpId, E) + 'static,
+    {
+        self.0.borrow_mut().orphan = Some(Box::new(f));
+    }
+
+    pub fn source(&self) -> impl FnMut() -> Option<E> {
+        let state = self.0.clone();
+        move || state.borrow_mut().ready.pop_front()
+    }
+}
+
+pub struct TwoPhaseHandler<E, H> {
+    start: H,
+    state: Rc<RefCell<State<E>>>,
+}
+
+impl<E, H> TwoPhaseHandler<E, H>
+where
+    E: TwoPhase,
+    H: FnMut(E::Input, OpId) -> Result<(), E::Input>,
+{
+    pub fn new(start: H) -> (Self, Completer<E>) {
+        let state = Rc::new(RefCell::new(State {
+            next: 0,
+            in_flight: BTreeSet::new(),
+            ready: VecDeque::new(),
+            orphan: None,
+        }));
+        let completer = Completer(state.clone());
+        (TwoPhaseHandler { start, state }, completer)
+    }
+}
+
+impl<E, H> Handler<E> for TwoPhaseHandler<E, H>
+where
+    E: TwoPhase,
+    H: FnMut(E::Input, OpId) -> Result<(), E::Input>,
+{
+    fn handle(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        let id = OpId(self.state.borrow().next);
+        (self.start)(effect, id)?;
+        let mut state = self.state.borrow_mut();
+        state.next += 1;
+        state.in_flight.insert(id);
+        Ok(E::ack(id))
+    }
+}
+
+#[macro_export]
+macro_rules! perform_two_phase {
+    ($e:expr, $ctx:expr) => {{
+        yield $e;
+        let id = $ctx
+            .take_if(|o| $crate::TwoPhase::is_ack(o).is_some())
+            .and_then(|o| $crate::TwoPhase::is_ack(&o))
+            .expect("two-phase effect is not acknowledged");
+        $crate::Operation::new(id)
+    }};
+}
+
+#[macro_export]
+macro_rules! await_done {
+    ($op:expr, $ctx:expr, $idle:expr) => {{
+        let id = $crate::Operation::id(&$op);
+        loop {
+            if let Some(v) = $ctx.take_if(|o| $crate::TwoPhase::is_completion(o) == Some(id)) {
+                $ctx.put_front(v);
+                break $crate::Operation::select(&$op, $ctx).unwrap();
+            }
+            yield $idle;
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, ops::GeneratorState};
+    use crate::{Context, Effect, Select, IntoBlock, Operation, perform_two_phase, await_done};
+    use super::{OpId, TwoPhase, TwoPhaseHandler};
+
+    #[derive(Debug)]
+    enum Effects {
+        StartUpload(&'static str),
+        Idle,
+    }
+
+    #[derive(Debug)]
+    enum Outputs {
+        Accepted(OpId),
+        Uploaded(OpId, &'static str),
+    }
+
+    impl Effect for Outputs {
+        type Input = Effects;
+    }
+
+    impl TwoPhase for Outputs {
+        fn ack(id: OpId) -> Self {
+            Outputs::Accepted(id)
+        }
+
+        fn is_ack(&self) -> Option<OpId> {
+            match self {
+                Outputs::Accepted(id) => Some(*id),
+                _ => None,
+            }
+        }
+
+        fn is_completion(&self) -> Option<OpId> {
+            match self {
+                Outputs::Uploaded(id, _) => Some(*id),
+                _ => None,
+            }
+        }
+    }
+
+    struct Uploaded(&'static str);
+
+    impl Select<Uploaded> for Outputs {
+        fn take(output: &Context<Self>) -> Option<Uploaded> {
+            match output.take()? {
+                Outputs::Uploaded(_, name) => Some(Uploaded(name)),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_order() {
+        let names = Rc::new(RefCell::new(Vec::new()));
+        let (handler, completer) = TwoPhaseHandler::new({
+            let names = names.clone();
+            move |effect, id| match effect {
+                Effects::StartUpload(name) => {
+                    names.borrow_mut().push((id, name));
+                    Ok(())
+                },
+                e => Err(e),
+            }
+        });
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let computation = {
+            let results = results.clone();
+            move |context: Context<Outputs>| {
+                move || {
+                    let first: Operation<Uploaded> =
+                        perform_two_phase!(Effects::StartUpload("a"), &context);
+                    let second: Operation<Uploaded> =
+                        perform_two_phase!(Effects::StartUpload("b"), &context);
+                    let Uploaded(a) = await_done!(first, &context, Effects::Idle);
+                    let Uploaded(b) = await_done!(second, &context, Effects::Idle);
+                    results.borrow_mut().push(a);
+                    results.borrow_mut().push(b);
+                }
+            }
+        };
+        let mut block = computation
+            .into_block()
+            .add_source(completer.source())
+            .add_handler(handler);
+        loop {
+            match block.resume() {
+                GeneratorState::Yielded(Effects::Idle) => {
+                    if let Some((id, name)) = names.borrow_mut().pop() {
+                        completer.complete(id, Outputs::Uploaded(id, name));
+                    }
+                },
+                GeneratorState::Yielded(e) => panic!("unhandled: {:?}", e),
+                GeneratorState::Complete(()) => break,
+            }
+        }
+        assert_eq!(*results.borrow(), vec!["a", "b"]);
+        assert!(completer.in_flight().is_empty());
+    }
+
+    #[test]
+    fn orphan() {
+        let (_, completer) = TwoPhaseHandler::<Outputs, _>::new(|e, _| Err(e));
+        let orphans = Rc::new(RefCell::new(Vec::new()));
+        completer.on_orphan({
+            let orphans = orphans.clone();
+            move |id, _| orphans.borrow_mut().push(id)
+        });
+        completer.complete(OpId(7), Outputs::Uploaded(OpId(7), "x"));
+        assert_eq!(*orphans.borrow(), vec![OpId(7)]);
+        assert!(completer.source()().is_none());
+    }
+}
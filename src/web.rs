@@ -0,0 +1,184 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    rc::Rc,
+    cell::RefCell,
+    collections::VecDeque,
+    ops::{Generator, GeneratorState},
+};
+use js_sys::{Function, Object, Promise, Reflect};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use super::{
+    block::Block,
+    computation::{Effect, Handler},
+    two_phase::{OpId, TwoPhase, TwoPhaseHandler, Completer},
+};
+
+pub struct BrowserSource<T>(Rc<RefCell<VecDeque<T>>>);
+
+impl<T> Clone for BrowserSource<T> {
+    fn clone(&self) -> Self {
+        BrowserSource(self.0.clone())
+    }
+}
+
+impl<T> Default for BrowserSource<T> {
+    fn default() -> Self {
+        BrowserSource(Rc::new(RefCell::new(VecDeque::new())))
+    }
+}
+
+impl<T> BrowserSource<T> {
+    pub fn new() -> Self {
+        BrowserSource::default()
+    }
+
+    pub fn push(&self, event: T) {
+        self.0.borrow_mut().push_back(event);
+    }
+
+    pub fn into_source(self) -> impl FnMut() -> Option<T> {
+        move || self.0.borrow_mut().pop_front()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpEffect {
+    Get(String),
+    Post(String, String),
+}
+
+pub trait HttpRequest
+where
+    Self: Sized,
+{
+    fn into_http(self) -> Result<HttpEffect, Self>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fetched {
+    pub id: OpId,
+    pub result: Result<HttpResponse, String>,
+}
+
+type Start<E> = Box<dyn FnMut(<E as Effect>::Input, OpId) -> Result<(), <E as Effect>::Input>>;
+
+pub struct FetchHandler<E>(TwoPhaseHandler<E, Start<E>>)
+where
+    E: Effect;
+
+impl<E> FetchHandler<E>
+where
+    E: TwoPhase + From<Fetched> + 'static,
+    E::Input: HttpRequest,
+{
+    pub fn new() -> (Self, Completer<E>) {
+        let slot = Rc::new(RefCell::new(None::<Completer<E>>));
+        let start: Start<E> = {
+            let slot = slot.clone();
+            Box::new(move |effect, id| {
+                let request = effect.into_http()?;
+                let completer = slot.borrow().clone().expect("completer is installed");
+                spawn_local(async move {
+                    let result = fetch(request).await;
+                    completer.complete(id, Fetched { id, result }.into());
+                });
+                Ok(())
+            })
+        };
+        let (handler, completer) = TwoPhaseHandler::new(start);
+        *slot.borrow_mut() = Some(completer.clone());
+        (FetchHandler(handler), completer)
+    }
+}
+
+impl<E> Handler<E> for FetchHandler<E>
+where
+    E: TwoPhase,
+{
+    fn handle(&mut self, effect: E::Input) -> Result<E, E::Input> {
+        self.0.handle(effect)
+    }
+}
+
+fn describe(value: JsValue) -> String {
+    value.as_string().unwrap_or_else(|| format!("{:?}", value))
+}
+
+fn global_function(name: &str) -> Option<Function> {
+    Reflect::get(&js_sys::global(), &name.into())
+        .ok()?
+        .dyn_into()
+        .ok()
+}
+
+async fn fetch(request: HttpEffect) -> Result<HttpResponse, String> {
+    let init = Object::new();
+    let (method, url) = match request {
+        HttpEffect::Get(url) => ("GET", url),
+        HttpEffect::Post(url, body) => {
+            Reflect::set(&init, &"body".into(), &body.into()).map_err(describe)?;
+            ("POST", url)
+        },
+    };
+    Reflect::set(&init, &"method".into(), &method.into()).map_err(describe)?;
+    let fetch = global_function("fetch").ok_or("fetch is not available")?;
+    let response = fetch
+        .call2(&JsValue::UNDEFINED, &url.into(), &init)
+        .map_err(describe)?;
+    let response = JsFuture::from(Promise::from(response))
+        .await
+        .map_err(describe)?;
+    let status = Reflect::get(&response, &"status".into())
+        .map_err(describe)?
+        .as_f64()
+        .unwrap_or_default() as u16;
+    let text = Reflect::get(&response, &"text".into())
+        .map_err(describe)?
+        .dyn_into::<Function>()
+        .map_err(describe)?
+        .call0(&response)
+        .map_err(describe)?;
+    let body = JsFuture::from(Promise::from(text))
+        .await
+        .map_err(describe)?
+        .as_string()
+        .unwrap_or_default();
+    Ok(HttpResponse { status, body })
+}
+
+fn request_frame(callback: &JsValue) {
+    let global = js_sys::global();
+    let scheduled = match global_function("requestAnimationFrame") {
+        Some(raf) => raf.call1(&global, callback),
+        // no animation frames outside of a window, e.g. in node or a worker
+        None => global_function("setTimeout")
+            .expect("cannot schedule a frame")
+            .call2(&global, callback, &16.into()),
+    };
+    scheduled.expect("cannot schedule a frame");
+}
+
+impl<T, G> Block<T, G>
+where
+    T: 'static,
+    G: Unpin + Generator<()> + 'static,
+{
+    pub fn run_on_animation_frame(self) {
+        let mut block = self;
+        let callback = Closure::once_into_js(move |_: JsValue| {
+            if let GeneratorState::Yielded(_) = block.resume() {
+                block.run_on_animation_frame();
+            }
+        });
+        request_frame(&callback);
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators, generator_trait)]
+
+use std::{rc::Rc, cell::RefCell, ops::Generator};
+use aeiou::{
+    Ack, Context, Effect, IntoBlock, Select, TakeResult, acking_handler, perform, perform_ack,
+    take_all,
+};
+
+#[derive(Debug)]
+enum Effects {
+    ReadTcp(u16),
+    Print(String),
+    LogMetric(&'static str, u64),
+}
+
+#[derive(Debug, PartialEq, Effect, Select, Ack)]
+#[input(Effects)]
+enum Outputs {
+    #[part(Data)]
+    ReadTcp(String),
+    #[ack(Print)]
+    Printed,
+    #[ack(LogMetric)]
+    Logged,
+}
+
+struct Data(String);
+
+type Printer = Rc<RefCell<Vec<String>>>;
+
+// the server of hello world, it prints what it reads
+fn server(context: Context<Outputs>) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+    move || {
+        let Data(data) = perform!(Effects::ReadTcp(8224), &context);
+        perform!(Effects::Print(data));
+    }
+}
+
+fn reader(effect: Effects) -> Result<Outputs, Effects> {
+    match effect {
+        Effects::ReadTcp(_) => Ok(Outputs::ReadTcp("hello world!\n".to_string())),
+        other => Err(other),
+    }
+}
+
+// the outputs left in the context when the server is done
+fn run<H>(printer: H) -> Vec<Outputs>
+where
+    H: FnMut(Effects) -> Result<Outputs, Effects>,
+{
+    let block = server.into_block().add_handler(reader).add_handler(printer);
+    let context = block.context();
+    block.assert_handled().run();
+    take_all!(&context)
+}
+
+#[test]
+fn hello_world_printer() {
+    let by_hand = Printer::default();
+    let outputs = run({
+        let by_hand = by_hand.clone();
+        move |effect| match effect {
+            Effects::Print(msg) => {
+                by_hand.borrow_mut().push(msg);
+                Ok(Outputs::Printed)
+            },
+            _ => Err(effect),
+        }
+    });
+    let acking = Printer::default();
+    let acked = run(acking_handler({
+        let acking = acking.clone();
+        move |effect| {
+            if let Effects::Print(msg) = effect {
+                acking.borrow_mut().push(msg);
+            }
+        }
+    }));
+    assert_eq!(*by_hand.borrow(), ["hello world!\n"]);
+    assert_eq!(*acking.borrow(), *by_hand.borrow());
+    assert_eq!(acked, outputs);
+    assert_eq!(acked, [Outputs::Printed]);
+}
+
+#[test]
+fn acknowledged() {
+    let taken = (|context: Context<Outputs>| {
+        move || {
+            let printed = perform_ack!(Effects::Print("hi".to_string()), &context);
+            let logged = perform_ack!(Effects::LogMetric("requests", 1), &context);
+            (printed, logged)
+        }
+    })
+    .into_block()
+    .add_handler(acking_handler(|_| ()))
+    .assert_handled()
+    .run();
+    assert_eq!(taken, (TakeResult::Matched(()), TakeResult::Matched(())));
+}
+
+#[test]
+fn wrong_ack() {
+    let taken = (|context: Context<Outputs>| {
+        move || perform_ack!(Effects::LogMetric("requests", 1), &context)
+    })
+    .into_block()
+    // acks every effect as printed
+    .add_handler(|_| Ok(Outputs::of::<Printed>()))
+    .assert_handled()
+    .run();
+    assert_eq!(taken, TakeResult::Mismatched(Outputs::Printed));
+}
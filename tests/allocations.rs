@@ -0,0 +1,145 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// a binary of its own, the global allocator counts for every test in it
+
+#![feature(generators, generator_trait)]
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    ops::{Generator, GeneratorState},
+};
+use either::Either;
+use aeiou::{
+    Context, IntoBlock,
+    tasks::{Request, TaskId, SpawnOptions, TaskFailed, HasTaskFailed},
+};
+
+// per thread, the tests run in threads of their own
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn allocations() -> u64 {
+    ALLOCATIONS.with(Cell::get)
+}
+
+const TASKS: u32 = 10;
+const ITERATIONS: u32 = 100;
+// the root spawns a task per round of the scheduler, the first responses
+// to each task grow its context
+const WARM_UP: u64 = 100;
+
+#[derive(Debug)]
+enum Effects {
+    Spawn(u32),
+    Mix(u32),
+    // the end of a block of audio, goes to the driver
+    Tick,
+}
+
+#[derive(Debug)]
+enum Outputs {
+    Mixed(u32),
+    Failed,
+}
+
+#[derive(Clone)]
+struct Voice(u32);
+
+impl TaskId for Voice {
+    type Id = u32;
+
+    fn task_id(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Request for Effects {
+    type Task = Voice;
+    type Effect = Effects;
+
+    fn is_task(self) -> Result<Voice, Self> {
+        match self {
+            Effects::Spawn(n) => Ok(Voice(n)),
+            e => Err(e),
+        }
+    }
+
+    fn is_effect(self) -> Result<Effects, Self> {
+        Ok(self)
+    }
+}
+
+impl HasTaskFailed<u32> for Outputs {
+    fn task_failed(_: TaskFailed<u32>) -> Self {
+        Outputs::Failed
+    }
+}
+
+fn voice(
+    Voice(n): Voice,
+    context: Context<Outputs>,
+) -> impl Unpin + Generator<(), Yield = Either<Effects, Outputs>, Return = Result<(), ()>> {
+    move || {
+        let mut sum = 0;
+        for _ in 0..ITERATIONS {
+            yield Either::Left(Effects::Mix(n));
+            if let Some(Outputs::Mixed(sample)) = context.take() {
+                sum += sample;
+            }
+            yield Either::Left(Effects::Tick);
+        }
+        assert_eq!(sum, n * ITERATIONS);
+        Ok(())
+    }
+}
+
+#[test]
+fn steady_rounds_do_not_allocate() {
+    let (block, tracking) = (|_: Context<Outputs>| {
+        move || {
+            for n in 0..TASKS {
+                yield Effects::Spawn(n);
+            }
+        }
+    })
+    .into_block()
+    .spawn_supervised(SpawnOptions::default(), voice)
+    .add_handler_(|effect| match effect {
+        Effects::Mix(n) => Ok(Outputs::Mixed(n)),
+        other => Err(other),
+    })
+    .with_alloc_tracking(WARM_UP, allocations);
+    let mut block = block;
+    let mut ticks = 0;
+    while let GeneratorState::Yielded(effect) = block.resume() {
+        assert!(matches!(effect, Effects::Tick));
+        ticks += 1;
+    }
+    assert_eq!(ticks, TASKS * ITERATIONS);
+    assert!(tracking.allocations() > 0);
+    assert_eq!(tracking.steady(), 0);
+}
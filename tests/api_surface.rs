@@ -0,0 +1,204 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// The exported items, read from the sources and compared with the golden
+// file next to this one. A change of the surface fails here, after a
+// deliberate one regenerate the file with `API_SURFACE=overwrite`. An item
+// under `#[cfg(..)]` is listed with its conditions, those of its module
+// included, so the file is the same whatever features the test is built with.
+
+use std::{collections::BTreeSet, env, fs, path::Path};
+
+const GOLDEN: &str = "tests/api_surface.txt";
+
+const KINDS: [&str; 8] = [
+    "struct", "enum", "trait", "fn", "type", "const", "static", "mod",
+];
+
+fn source(path: &str) -> String {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    fs::read_to_string(root.join(path)).unwrap_or_default()
+}
+
+// the statements starting at the first column, up to their body or their end,
+// with the `#[cfg(..)]` attributes right above them
+fn statements(text: &str) -> Vec<(Vec<String>, String)> {
+    let mut statements = Vec::new();
+    let mut cfgs = Vec::new();
+    let mut current: Option<String> = None;
+    for line in text.lines() {
+        match &mut current {
+            Some(statement) => statement.push_str(line.trim()),
+            None if line.starts_with("pub ") => current = Some(line.to_string()),
+            None => {
+                if line.starts_with("#[cfg(") {
+                    cfgs.push(line.trim_end().to_string());
+                } else if !(line.starts_with("#[") || line.starts_with("//")) {
+                    cfgs.clear();
+                }
+                continue;
+            },
+        }
+        let statement = current.as_ref().unwrap();
+        if statement.starts_with("pub use") && statement.ends_with(';')
+            || !statement.starts_with("pub use")
+        {
+            statements.push((cfgs.split_off(0), current.take().unwrap()));
+        }
+    }
+    statements
+}
+
+// the line of the golden file, the conditions in a stable order
+fn entry(kind: &str, path: &str, cfgs: &[String]) -> String {
+    let mut cfgs = cfgs.to_vec();
+    cfgs.sort();
+    cfgs.dedup();
+    cfgs.iter()
+        .fold(format!("{} {}", kind, path), |entry, cfg| entry + " " + cfg)
+}
+
+fn name_of(rest: &str) -> &str {
+    let end = rest
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(rest.len());
+    &rest[..end]
+}
+
+// the kind and the name of an item that is not a `use`
+fn item(statement: &str) -> Option<(&str, &str)> {
+    let rest = statement.strip_prefix("pub ")?;
+    let rest = rest.strip_prefix("unsafe ").unwrap_or(rest);
+    KINDS.iter().find_map(|kind| {
+        let name = rest.strip_prefix(kind)?.strip_prefix(' ')?;
+        Some((*kind, name_of(name)))
+    })
+}
+
+// the kind of the item and its conditions in the module
+fn kind_in(module: &str, name: &str) -> (String, Vec<String>) {
+    statements(&source(&format!("src/{}.rs", module)))
+        .iter()
+        .find_map(|(cfgs, s)| match item(s) {
+            Some((kind, n)) if n == name => Some((kind.to_string(), cfgs.clone())),
+            _ => None,
+        })
+        .unwrap_or_else(|| ("use".to_string(), Vec::new()))
+}
+
+// `pub use self::module::{A, b as c}`, or one name without the braces
+fn uses(statement: &str) -> Vec<(String, Vec<String>, String)> {
+    let path = statement
+        .trim_start_matches("pub use ")
+        .trim_end_matches(';')
+        .trim_start_matches("self::")
+        .trim_start_matches("super::");
+    let (module, names) = match path.find('{') {
+        Some(brace) => (
+            path[..brace].trim_end_matches("::"),
+            path[brace + 1..].trim_end_matches('}'),
+        ),
+        None => path.split_at(path.rfind("::").map_or(0, |i| i + 2)),
+    };
+    let module = module.trim_end_matches("::").to_string();
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(|n| {
+            let mut parts = n.split(" as ");
+            let original = parts.next().unwrap();
+            let exported = parts.next().unwrap_or(original);
+            let module = module.split("::").last().unwrap_or_default().to_string();
+            let (kind, cfgs) = kind_in(&module, original);
+            (kind, cfgs, exported.to_string())
+        })
+        .collect()
+}
+
+// the conditions of the enclosing modules are in `outer`
+fn module_surface(prefix: &str, outer: &[String], text: &str, surface: &mut BTreeSet<String>) {
+    for (cfgs, statement) in statements(text) {
+        let cfgs = [outer, &cfgs].concat();
+        if statement.starts_with("pub use") {
+            if statement.ends_with("::*;") {
+                let glob = statement
+                    .trim_start_matches("pub use ")
+                    .trim_end_matches(';');
+                surface.insert(entry("use", &format!("{}{}", prefix, glob), &cfgs));
+                continue;
+            }
+            for (kind, inner, name) in uses(&statement) {
+                let path = format!("{}{}", prefix, name);
+                surface.insert(entry(&kind, &path, &[&cfgs[..], &inner].concat()));
+            }
+        } else if let Some((kind, name)) = item(&statement) {
+            surface.insert(entry(kind, &format!("{}{}", prefix, name), &cfgs));
+            if kind == "mod" && statement.ends_with(';') {
+                let inner = source(&format!("src/{}.rs", name));
+                module_surface(&format!("{}{}::", prefix, name), &cfgs, &inner, surface);
+            }
+        }
+    }
+}
+
+fn macros(surface: &mut BTreeSet<String>) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    for entry in fs::read_dir(root.join("src")).unwrap() {
+        let text = fs::read_to_string(entry.unwrap().path()).unwrap();
+        let mut exported = false;
+        for line in text.lines() {
+            if line.trim() == "#[macro_export]" {
+                exported = true;
+            } else if let Some(rest) = line.trim().strip_prefix("macro_rules! ") {
+                if exported {
+                    surface.insert(format!("macro {}!", name_of(rest)));
+                }
+                exported = false;
+            }
+        }
+    }
+    let mut lines = source("macros/src/lib.rs")
+        .lines()
+        .map(str::to_string)
+        .collect::<Vec<_>>()
+        .into_iter();
+    while let Some(line) = lines.next() {
+        if let Some(rest) = line.strip_prefix("#[proc_macro_derive(") {
+            surface.insert(format!("derive {}", name_of(rest)));
+        } else if line == "#[proc_macro_attribute]" {
+            let rest = lines.next().unwrap_or_default();
+            let name = rest.trim_start_matches("pub fn ");
+            surface.insert(format!("attribute {}", name_of(name)));
+        } else if line == "#[proc_macro]" {
+            let rest = lines.next().unwrap_or_default();
+            let name = rest.trim_start_matches("pub fn ");
+            surface.insert(format!("macro {}!", name_of(name)));
+        }
+    }
+}
+
+#[test]
+fn surface_is_unchanged() {
+    let mut surface = BTreeSet::new();
+    module_surface("", &[], &source("src/lib.rs"), &mut surface);
+    macros(&mut surface);
+    let actual = surface.into_iter().collect::<Vec<_>>().join("\n") + "\n";
+    if env::var("API_SURFACE").as_deref() == Ok("overwrite") {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        fs::write(root.join(GOLDEN), &actual).unwrap();
+        return;
+    }
+    let expected = source(GOLDEN);
+    if actual != expected {
+        let expected = expected.lines().collect::<BTreeSet<_>>();
+        let actual = actual.lines().collect::<BTreeSet<_>>();
+        let added = actual.difference(&expected).collect::<Vec<_>>();
+        let removed = expected.difference(&actual).collect::<Vec<_>>();
+        panic!(
+            "the public API changed, added: {:?}, removed: {:?}, \
+             rerun with `API_SURFACE=overwrite` if it is deliberate",
+            added, removed,
+        );
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![feature(generators, generator_trait)]
+
+use std::{
+    collections::VecDeque,
+    ops::{Generator, GeneratorState},
+};
+use aeiou::{Block, Context, Effect, IntoBlock, Select, Source, take_all};
+
+#[derive(Debug)]
+enum Effects {
+    Idle,
+}
+
+#[derive(Debug, PartialEq)]
+enum Outputs {
+    Reading(u32),
+    Alarm,
+}
+
+impl Effect for Outputs {
+    type Input = Effects;
+}
+
+struct Alarm;
+
+impl Select<Alarm> for Outputs {
+    fn take(output: &Context<Self>) -> Option<Alarm> {
+        match output.take()? {
+            Outputs::Alarm => Some(Alarm),
+            other => {
+                output.put_front(other);
+                None
+            },
+        }
+    }
+}
+
+// the batches the computation got and how many times it was resumed
+fn drive<G>(block: Block<Outputs, G>) -> (G::Return, usize)
+where
+    G: Unpin + Generator<Yield = Effects>,
+{
+    let mut block = block;
+    let mut resumes = 1;
+    loop {
+        match block.resume() {
+            GeneratorState::Complete(r) => break (r, resumes),
+            GeneratorState::Yielded(Effects::Idle) => resumes += 1,
+        }
+    }
+}
+
+fn collect(
+    context: Context<Outputs>,
+) -> impl Unpin + Generator<Yield = Effects, Return = Vec<Vec<Outputs>>> {
+    move || {
+        let mut batches = Vec::new();
+        loop {
+            let batch = take_all!(&context, Effects::Idle);
+            if batch.is_empty() {
+                break batches;
+            }
+            batches.push(batch);
+        }
+    }
+}
+
+// delivers everything in one round and is done right away
+struct Burst(VecDeque<Outputs>);
+
+impl Source<Outputs> for Burst {
+    fn poll(&mut self) -> Option<Outputs> {
+        self.0.pop_front()
+    }
+
+    fn is_done(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[test]
+fn order_in_one_resume() {
+    let burst = Burst((0..1000).map(Outputs::Reading).collect());
+    let (batches, resumes) = drive(collect.into_block().add_source(burst));
+    assert_eq!(resumes, 1);
+    assert_eq!(batches.len(), 1);
+    assert_eq!(
+        batches[0],
+        (0..1000).map(Outputs::Reading).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn budget_bounds_each_resume() {
+    let block = collect.into_block();
+    let context = block.context();
+    context.set_batch_budget(2);
+    (0..5).for_each(|n| context.put(Outputs::Reading(n)));
+    context.close();
+    let (batches, resumes) = drive(block);
+    let sizes = batches.iter().map(Vec::len).collect::<Vec<_>>();
+    assert_eq!(sizes, [2, 2, 1]);
+    assert_eq!(resumes, 3);
+}
+
+#[test]
+fn mixed_with_select() {
+    let (taken, _) = drive(
+        (|context: Context<Outputs>| {
+            context.put(Outputs::Alarm);
+            context.put(Outputs::Reading(1));
+            context.put(Outputs::Alarm);
+            context.put(Outputs::Reading(2));
+            context.put(Outputs::Reading(3));
+            move || {
+                let first = <Outputs as Select<Alarm>>::take(&context).is_some();
+                let batch = context.take_batch(2);
+                // a reading is at the front now, the select puts it back
+                let second = <Outputs as Select<Alarm>>::take(&context).is_some();
+                let rest = take_all!(&context);
+                if false {
+                    yield Effects::Idle;
+                }
+                (first, batch, second, rest)
+            }
+        })
+        .into_block(),
+    );
+    assert_eq!(
+        taken,
+        (
+            true,
+            vec![Outputs::Reading(1), Outputs::Alarm],
+            false,
+            vec![Outputs::Reading(2), Outputs::Reading(3)],
+        ),
+    );
+}
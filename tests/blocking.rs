@@ -0,0 +1,235 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![feature(generators, generator_trait)]
+
+use std::{
+    thread,
+    sync::atomic::{AtomicUsize, Ordering},
+    ops::{Generator, GeneratorState},
+    time::{Duration, Instant},
+};
+use either::Either;
+use aeiou::{
+    Context, Effect, HasPriority, IntoBlock, OpId, TwoPhase,
+    blocking::{BlockingWait, HasBlockingOutcome, WorkerFailure, blocking_adapter},
+    tasks::{Request, TaskId, SpawnOptions, TaskFailed, HasTaskFailed},
+};
+
+const TASKS: u32 = 10;
+// on the worker, one after another
+const SLOW: Duration = Duration::from_millis(40);
+// on the driving thread, while the worker is busy
+const FAST: Duration = Duration::from_millis(4);
+const FAST_PER_TASK: u32 = 10;
+
+// only the test with no room in the queue sees it full
+static FULL: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, PartialEq)]
+enum Effects {
+    Spawn(u32),
+    // a query of a database client written in the blocking style
+    Query(u32),
+    Fast,
+    Wait(OpId),
+}
+
+#[derive(Debug)]
+enum Outputs {
+    Accepted(OpId),
+    Full(Effects),
+    Row(u32),
+    Completed(OpId, Result<u32, WorkerFailure<Effects>>),
+    Fast,
+    Failed,
+}
+
+impl Effect for Outputs {
+    type Input = Effects;
+}
+
+impl TwoPhase for Outputs {
+    fn ack(id: OpId) -> Self {
+        Outputs::Accepted(id)
+    }
+
+    fn is_ack(&self) -> Option<OpId> {
+        match self {
+            Outputs::Accepted(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    fn is_completion(&self) -> Option<OpId> {
+        match self {
+            Outputs::Completed(id, _) => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+impl HasBlockingOutcome for Outputs {
+    fn completed(id: OpId, outcome: Result<Self, WorkerFailure<Effects>>) -> Self {
+        let outcome = outcome.map(|output| match output {
+            Outputs::Row(n) => n,
+            other => panic!("the database answered {:?}", other),
+        });
+        Outputs::Completed(id, outcome)
+    }
+
+    fn queue_full(effect: Effects) -> Self {
+        FULL.fetch_add(1, Ordering::SeqCst);
+        Outputs::Full(effect)
+    }
+}
+
+impl BlockingWait for Effects {
+    fn waits_for(&self) -> Option<OpId> {
+        match self {
+            Effects::Wait(id) => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+impl HasPriority for Effects {}
+
+#[derive(Clone)]
+struct Client(u32);
+
+impl TaskId for Client {
+    type Id = u32;
+
+    fn task_id(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Request for Effects {
+    type Task = Client;
+    type Effect = Effects;
+
+    fn is_task(self) -> Result<Client, Self> {
+        match self {
+            Effects::Spawn(n) => Ok(Client(n)),
+            e => Err(e),
+        }
+    }
+
+    fn is_effect(self) -> Result<Effects, Self> {
+        Ok(self)
+    }
+}
+
+impl HasTaskFailed<u32> for Outputs {
+    fn task_failed(_: TaskFailed<u32>) -> Self {
+        Outputs::Failed
+    }
+}
+
+fn database(effect: Effects) -> Result<Outputs, Effects> {
+    match effect {
+        Effects::Query(n) => {
+            thread::sleep(SLOW);
+            Ok(Outputs::Row(n * n))
+        },
+        other => Err(other),
+    }
+}
+
+fn client(
+    Client(n): Client,
+    context: Context<Outputs>,
+) -> impl Unpin + Generator<(), Yield = Either<Effects, Outputs>, Return = Result<(), ()>> {
+    move || {
+        let mut query = Effects::Query(n);
+        let id = loop {
+            yield Either::Left(query);
+            match context.take() {
+                Some(Outputs::Accepted(id)) => break id,
+                Some(Outputs::Full(effect)) => {
+                    query = effect;
+                    yield Either::Left(Effects::Fast);
+                    context.take();
+                },
+                other => panic!("unexpected {:?}", other),
+            }
+        };
+        for _ in 0..FAST_PER_TASK {
+            yield Either::Left(Effects::Fast);
+            assert!(matches!(context.take(), Some(Outputs::Fast)));
+        }
+        loop {
+            yield Either::Left(Effects::Wait(id));
+            if let Some(Outputs::Completed(_, row)) = context.take() {
+                assert_eq!(row, Ok(n * n));
+                break Ok(());
+            }
+        }
+    }
+}
+
+#[test]
+fn slow_effects_run_beside_fast_ones() {
+    let (adapter, worker) = blocking_adapter(database, TASKS as usize);
+    let start = Instant::now();
+    let mut block = (|_: Context<Outputs>| {
+        move || {
+            for n in 0..TASKS {
+                yield Effects::Spawn(n);
+            }
+        }
+    })
+    .into_block()
+    .spawn_supervised(SpawnOptions::default(), client)
+    .add_handler(|effect| match effect {
+        Effects::Fast => {
+            thread::sleep(FAST);
+            Ok(Outputs::Fast)
+        },
+        other => Err(other),
+    })
+    .add_handler(adapter);
+    while let GeneratorState::Yielded(effect) = block.resume() {
+        assert!(matches!(effect, Effects::Wait(_)));
+        thread::yield_now();
+    }
+    let elapsed = start.elapsed();
+    drop(block);
+    worker.join().unwrap();
+
+    let slow = SLOW * TASKS;
+    let fast = FAST * TASKS * FAST_PER_TASK;
+    // one after another it would take both
+    assert!(elapsed >= slow);
+    assert!(elapsed < slow + fast * 3 / 4, "{:?}", elapsed);
+}
+
+// the worker takes a query only while it is idle, the others come back and
+// are yielded again
+#[test]
+fn full_queue_defers() {
+    let (adapter, worker) = blocking_adapter(database, 0);
+    let mut block = (|_: Context<Outputs>| {
+        move || {
+            for n in 0..3 {
+                yield Effects::Spawn(n);
+            }
+        }
+    })
+    .into_block()
+    .spawn_supervised(SpawnOptions::default(), client)
+    .add_handler(|effect| match effect {
+        Effects::Fast => Ok(Outputs::Fast),
+        other => Err(other),
+    })
+    .add_handler(adapter);
+    while let GeneratorState::Yielded(effect) = block.resume() {
+        assert!(matches!(effect, Effects::Wait(_)));
+        thread::yield_now();
+    }
+    drop(block);
+    worker.join().unwrap();
+    assert!(FULL.load(Ordering::SeqCst) > 0);
+}
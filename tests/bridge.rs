@@ -0,0 +1,312 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// The reference client runs as another process, the test binary is started
+// again with the fixture variable and runs only `reference_client`.
+
+#![cfg(all(feature = "bridge", unix))]
+#![feature(generators, generator_trait)]
+
+#[path = "bridge/client.rs"]
+mod client;
+
+use std::{
+    env, fs,
+    io::{BufRead, BufReader},
+    ops::Generator,
+    path::PathBuf,
+    process::{self, Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+use serde::{Serialize, Deserialize};
+use aeiou::{
+    Context, Effect, IntoBlock, Idempotent, perform,
+    bridge::{
+        BridgeError, BridgeFailure, BridgeListener, BridgeServer, BridgeTimeout, HasBridgeFailure,
+        SocketBridgeHandler,
+    },
+    deadline::HasDeadline,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Effects {
+    Add(i32, i32),
+    Unknown,
+    Slow(u64),
+    Flaky,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Outputs {
+    Sum(i32),
+    Local,
+    Bridge(BridgeFailure),
+}
+
+impl Effect for Outputs {
+    type Input = Effects;
+}
+
+impl HasBridgeFailure for Outputs {
+    fn bridge_failure(failure: BridgeFailure) -> Self {
+        Outputs::Bridge(failure)
+    }
+}
+
+impl Idempotent for Effects {}
+
+impl HasDeadline for Effects {
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    fn set_deadline(&mut self, _: Instant) {}
+}
+
+#[test]
+fn reference_client() {
+    if let Some(mode) = client::mode() {
+        client::main(&mode);
+    }
+}
+
+// killed with the test
+struct Fixture(Child);
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn fixture(mode: &str) -> Fixture {
+    let child = Command::new(env::current_exe().unwrap())
+        .args(["reference_client", "--exact", "--nocapture"])
+        .env(client::FIXTURE, mode)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    Fixture(child)
+}
+
+// the address the listening client printed, after the name of its test
+fn listening() -> (Fixture, String) {
+    let mut fixture = fixture("listen");
+    let stdout = BufReader::new(fixture.0.stdout.take().unwrap());
+    let addr = stdout
+        .lines()
+        .find_map(|line| Some(line.unwrap().split_once("addr=")?.1.to_string()))
+        .unwrap();
+    (fixture, addr)
+}
+
+fn socket_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("aeiou-bridge-{}-{}.sock", name, process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+type Asking = Box<dyn Unpin + Generator<(), Yield = Effects, Return = Vec<Outputs>>>;
+
+// yields the effects in turn, returns what came back
+fn ask(effects: Vec<Effects>) -> impl FnOnce(Context<Outputs>) -> Asking {
+    move |context| {
+        Box::new(move || {
+            let mut outputs = Vec::new();
+            for effect in effects {
+                perform!(effect);
+                outputs.extend(context.take());
+            }
+            outputs
+        })
+    }
+}
+
+#[test]
+fn round_trip() {
+    let (_fixture, addr) = listening();
+    let handler = SocketBridgeHandler::connect(&addr).unwrap();
+    let outputs = ask(vec![Effects::Add(2, 3), Effects::Add(-1, 1)])
+        .into_block()
+        .add_handler(handler)
+        .assert_handled()
+        .run();
+    assert_eq!(outputs, [Outputs::Sum(5), Outputs::Sum(0)]);
+}
+
+#[test]
+fn decline_falls_through() {
+    let (_fixture, addr) = listening();
+    let handler = SocketBridgeHandler::connect(&addr).unwrap();
+    let outputs = ask(vec![Effects::Unknown, Effects::Add(1, 1)])
+        .into_block()
+        .add_handler(handler)
+        .add_handler(|effect| match effect {
+            Effects::Unknown => Ok(Outputs::Local),
+            other => Err(other),
+        })
+        .assert_handled()
+        .run();
+    assert_eq!(outputs, [Outputs::Local, Outputs::Sum(2)]);
+}
+
+#[test]
+fn timeout_then_reconnect() {
+    let (_fixture, addr) = listening();
+    let timeout = Duration::from_millis(100);
+    let handler = SocketBridgeHandler::connect(&addr)
+        .unwrap()
+        .timeout(timeout);
+    let start = Instant::now();
+    let outputs = ask(vec![Effects::Slow(2_000), Effects::Add(1, 2)])
+        .into_block()
+        .add_handler(handler)
+        .assert_handled()
+        .run();
+    let expected = BridgeTimeout {
+        effect: r#"{"Slow":2000}"#.to_string(),
+        after: timeout,
+    };
+    assert_eq!(
+        outputs,
+        [
+            Outputs::Bridge(BridgeFailure::Timeout(expected)),
+            Outputs::Sum(3)
+        ],
+    );
+    // the slow answer was never waited for
+    assert!(start.elapsed() < Duration::from_millis(2_000));
+}
+
+#[test]
+fn reconnect_with_retry() {
+    let (_fixture, addr) = listening();
+    let backoff = Duration::from_millis(20);
+    let handler = SocketBridgeHandler::connect(&addr)
+        .unwrap()
+        .backoff(backoff);
+    let start = Instant::now();
+    let outputs = ask(vec![Effects::Flaky, Effects::Add(4, 4)])
+        .into_block()
+        .retry(3, |output| {
+            matches!(output, Outputs::Bridge(BridgeFailure::Disconnected))
+        })
+        .add_handler(handler)
+        .assert_handled()
+        .run();
+    assert_eq!(outputs, [Outputs::Sum(7), Outputs::Sum(8)]);
+    assert!(start.elapsed() >= backoff);
+}
+
+#[test]
+fn wrong_address() {
+    let path = socket_path("nobody");
+    let error = SocketBridgeHandler::<Outputs>::connect(path.to_str().unwrap()).err();
+    assert!(matches!(error, Some(BridgeError::Io(_))));
+}
+
+#[test]
+fn serve_to_remote_handler() {
+    let path = socket_path("serve");
+    let listener = BridgeListener::bind(path.to_str().unwrap()).unwrap();
+    let _fixture = fixture(&format!("connect={}", path.display()));
+    let mut make = || ask(vec![Effects::Add(20, 22), Effects::Slow(1)]).into_block();
+    let outputs = BridgeServer::serve_one(&mut make, &listener).unwrap();
+    assert_eq!(outputs, [Outputs::Sum(42), Outputs::Sum(0)]);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn serve_declined() {
+    let path = socket_path("declined");
+    let listener = BridgeListener::bind(path.to_str().unwrap()).unwrap();
+    let _fixture = fixture(&format!("connect={}", path.display()));
+    let mut make = || ask(vec![Effects::Unknown]).into_block();
+    let error = BridgeServer::serve_one(&mut make, &listener).err();
+    assert!(matches!(error, Some(BridgeError::Unhandled(effect)) if effect == "Unknown"));
+    fs::remove_file(path).unwrap();
+}
+
+// the remote side in a thread of the test, speaking MessagePack
+#[cfg(feature = "rmp")]
+#[test]
+fn msgpack_round_trip() {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::{UnixListener, UnixStream},
+        thread,
+    };
+    use serde::de::DeserializeOwned;
+    use aeiou::codec::MsgpackCodec;
+
+    // the bridge frames, `done` is not sent to this side
+    #[allow(dead_code)]
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Frame<T> {
+        Bridge(u32),
+        Effect(T),
+        Output(T),
+        Unhandled(bool),
+        Done(bool),
+    }
+
+    fn read<T>(stream: &mut UnixStream) -> Option<Frame<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).ok()?;
+        let mut bytes = vec![0; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut bytes).ok()?;
+        Some(rmp_serde::from_slice(&bytes).unwrap())
+    }
+
+    fn write<T>(stream: &mut UnixStream, frame: &Frame<T>)
+    where
+        T: Serialize,
+    {
+        let bytes = rmp_serde::to_vec_named(frame).unwrap();
+        stream
+            .write_all(&(bytes.len() as u32).to_be_bytes())
+            .unwrap();
+        stream.write_all(&bytes).unwrap();
+    }
+
+    let path = socket_path("msgpack");
+    let listener = UnixListener::bind(&path).unwrap();
+    let answerer = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        match read::<()>(&mut stream) {
+            Some(Frame::Bridge(version)) => write(&mut stream, &Frame::<()>::Bridge(version)),
+            _ => panic!("expected the handshake"),
+        }
+        // until the handler is dropped
+        while let Some(Frame::Effect(effect)) = read::<Effects>(&mut stream) {
+            let frame = match effect {
+                Effects::Add(a, b) => Frame::Output(Outputs::Sum(a + b)),
+                _ => Frame::Unhandled(true),
+            };
+            write(&mut stream, &frame);
+        }
+    });
+
+    let handler = SocketBridgeHandler::connect_with(path.to_str().unwrap(), MsgpackCodec).unwrap();
+    let outputs = ask(vec![
+        Effects::Add(2, 3),
+        Effects::Unknown,
+        Effects::Add(-1, 1),
+    ])
+    .into_block()
+    .add_handler(handler)
+    .add_handler(|effect| match effect {
+        Effects::Unknown => Ok(Outputs::Local),
+        other => Err(other),
+    })
+    .assert_handled()
+    .run();
+    assert_eq!(outputs, [Outputs::Sum(5), Outputs::Local, Outputs::Sum(0)]);
+    answerer.join().unwrap();
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,95 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+// The reference client, a handler in another process. It speaks the
+// protocol on its own, without the crate, like the client of any other
+// language would. `AEIOU_BRIDGE_FIXTURE=listen` answers every connection
+// and prints the address, `AEIOU_BRIDGE_FIXTURE=connect=<address>` answers
+// one computation of a server.
+//
+// `{"Add":[a,b]}` is answered `{"Sum":a+b}`, `"Unknown"` is declined,
+// `{"Slow":ms}` sleeps the milliseconds first, `"Flaky"` closes the first
+// connection it arrives on.
+
+use std::{
+    env,
+    io::{self, Read, Write},
+    net::TcpListener,
+    os::unix::net::UnixStream,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+use serde_json::{Value, json};
+
+pub const FIXTURE: &str = "AEIOU_BRIDGE_FIXTURE";
+
+static FLAKED: AtomicBool = AtomicBool::new(false);
+
+fn read_frame(stream: &mut impl Read) -> io::Result<Value> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let mut bytes = vec![0; u32::from_be_bytes(length) as usize];
+    stream.read_exact(&mut bytes)?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+fn write_frame(stream: &mut impl Write, frame: Value) -> io::Result<()> {
+    let bytes = serde_json::to_vec(&frame)?;
+    stream.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(&bytes)
+}
+
+// `None` closes the connection
+fn answer(effect: &Value) -> Option<Value> {
+    if let Some([a, b]) = effect["Add"].as_array().map(Vec::as_slice) {
+        let sum = a.as_i64().unwrap() + b.as_i64().unwrap();
+        return Some(json!({ "output": { "Sum": sum } }));
+    }
+    if let Some(ms) = effect["Slow"].as_u64() {
+        thread::sleep(Duration::from_millis(ms));
+        return Some(json!({ "output": { "Sum": 0 } }));
+    }
+    match effect.as_str() {
+        Some("Flaky") if !FLAKED.swap(true, Ordering::SeqCst) => None,
+        Some("Flaky") => Some(json!({ "output": { "Sum": 7 } })),
+        _ => Some(json!({ "unhandled": true })),
+    }
+}
+
+fn session(mut stream: impl Read + Write) -> io::Result<()> {
+    let hello = read_frame(&mut stream)?;
+    assert_eq!(hello, json!({ "bridge": 1 }));
+    write_frame(&mut stream, hello)?;
+    loop {
+        let frame = read_frame(&mut stream)?;
+        if frame.get("done").is_some() {
+            return Ok(());
+        }
+        match answer(&frame["effect"]) {
+            Some(response) => write_frame(&mut stream, response)?,
+            None => return Ok(()),
+        }
+    }
+}
+
+pub fn main(mode: &str) {
+    if let Some(path) = mode.strip_prefix("connect=") {
+        session(UnixStream::connect(path).unwrap()).unwrap();
+        return;
+    }
+    assert_eq!(mode, "listen");
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    println!("addr={}", listener.local_addr().unwrap());
+    io::stdout().flush().unwrap();
+    for stream in listener.incoming() {
+        let stream = stream.unwrap();
+        // the handler gives up on slow answers, the next connection must
+        // not wait for them
+        thread::spawn(move || session(stream));
+    }
+}
+
+pub fn mode() -> Option<String> {
+    env::var(FIXTURE).ok()
+}
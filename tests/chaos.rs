@@ -0,0 +1,108 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators, generator_trait)]
+
+use std::ops::Generator;
+use aeiou::{
+    Context, Effect, EffectName, IntoBlock, Select, perform,
+    chaos::{ChaosHandler, ChaosPlan, Fault, FaultKind, FaultLog},
+};
+
+#[derive(Debug, EffectName)]
+enum Effects {
+    Read,
+    Log { line: &'static str },
+}
+
+#[derive(Debug, Clone, PartialEq, Effect, Select)]
+#[input(Effects)]
+enum Outputs {
+    #[part(Data)]
+    Data(u32),
+    Logged,
+}
+
+struct Data(u32);
+
+fn handler(effect: Effects) -> Result<Outputs, Effects> {
+    match effect {
+        Effects::Read => Ok(Outputs::Data(7)),
+        Effects::Log { line } => {
+            assert!(!line.is_empty());
+            Ok(Outputs::Logged)
+        },
+    }
+}
+
+fn retrying(
+    attempts: usize,
+) -> impl Fn(Context<Outputs>) -> Box<dyn Unpin + Generator<Return = Result<u32, ()>, Yield = Effects>>
+{
+    move |context: Context<Outputs>| {
+        Box::new(move || {
+            perform!(Effects::Log { line: "reading" });
+            context.take();
+            for _ in 0..attempts {
+                perform!(Effects::Read);
+                if let Some(Data(data)) = Select::take(&context) {
+                    return Ok(data);
+                }
+            }
+            Err(())
+        })
+    }
+}
+
+fn run(attempts: usize, plan: ChaosPlan<Outputs>, seed: u64) -> (Result<u32, ()>, FaultLog) {
+    let chaos = ChaosHandler::wrap(handler, plan, seed);
+    let log = chaos.log();
+    let result = retrying(attempts)
+        .into_block()
+        .add_chaos_handler(chaos)
+        .assert_handled()
+        .run();
+    (result, log)
+}
+
+fn drop_heavy() -> ChaosPlan<Outputs> {
+    ChaosPlan::new().inject("Read", Fault::Drop, 0.8)
+}
+
+#[test]
+fn deterministic_log() {
+    let plan = || {
+        ChaosPlan::new()
+            .inject("Read", Fault::Drop, 0.2)
+            .inject("Read", Fault::Delay(2), 0.2)
+            .inject("Read", Fault::Duplicate, 0.2)
+            .inject("Read", Fault::corrupt(|_| Outputs::Logged), 0.2)
+    };
+    let (_, first) = run(20, plan(), 42);
+    let (_, second) = run(20, plan(), 42);
+    assert!(!first.is_empty());
+    assert_eq!(first.entries(), second.entries());
+    assert!(first.entries().iter().all(|e| e.effect == "Read"));
+    assert_eq!(first.to_string(), second.to_string());
+}
+
+#[test]
+fn retry_survives_drops() {
+    let (result, log) = run(1, drop_heavy(), 1);
+    assert_eq!(result, Err(()));
+    assert_eq!(log.entries()[0].fault, FaultKind::Drop);
+
+    let (result, log) = run(64, drop_heavy(), 1);
+    assert_eq!(result, Ok(7), "injected:\n{}", log);
+    assert!(log.entries().iter().all(|e| e.fault == FaultKind::Drop));
+}
+
+#[test]
+fn delayed_response_released() {
+    let plan = ChaosPlan::new().inject("Read", Fault::Delay(3), 1.0);
+    let (result, log) = run(8, plan, 0);
+    assert_eq!(result, Ok(7));
+    assert_eq!(log.entries()[0].fault, FaultKind::Delay(3));
+    assert_eq!(log.len(), 3);
+}
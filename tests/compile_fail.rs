@@ -0,0 +1,14 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+
+#[test]
+fn state_machine() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
+
+#[test]
+fn effect_checks() {
+    trybuild::TestCases::new().pass("tests/ui/pass/*.rs");
+}
@@ -0,0 +1,81 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators, generator_trait)]
+
+use std::{rc::Rc, cell::RefCell};
+use aeiou::{Classify, Context, Effect, EffectClass, IntoBlock, perform};
+
+#[derive(Debug, Classify)]
+enum Effects {
+    Stat(&'static str),
+    #[mutating]
+    Remove(&'static str),
+    #[mutating]
+    SendMail {
+        to: &'static str,
+    },
+}
+
+#[derive(Debug, PartialEq, Effect)]
+#[input(Effects)]
+enum Outputs {
+    Size(u64),
+    Done,
+}
+
+#[test]
+fn derived_classes() {
+    assert_eq!(Effects::Stat("a").effect_class(), EffectClass::ReadOnly);
+    assert_eq!(Effects::Remove("a").effect_class(), EffectClass::Mutating);
+    assert_eq!(
+        Effects::SendMail { to: "ops" }.effect_class(),
+        EffectClass::Mutating
+    );
+}
+
+#[test]
+fn dry_run_without_closure() {
+    let stats = Rc::new(RefCell::new(0));
+    let (block, report) = (|context: Context<Outputs>| {
+        move || {
+            for path in ["big.log", "small.log"] {
+                perform!(Effects::Stat(path));
+                if context.take() == Some(Outputs::Size(1 << 20)) {
+                    perform!(Effects::Remove(path));
+                    assert_eq!(context.take(), Some(Outputs::Done));
+                }
+            }
+            perform!(Effects::SendMail { to: "ops" });
+            assert_eq!(context.take(), Some(Outputs::Done));
+        }
+    })
+    .into_block()
+    .dry_run_classified(|_| Outputs::Done);
+    block
+        .add_handler({
+            let stats = stats.clone();
+            move |effect| match effect {
+                Effects::Stat(path) => {
+                    *stats.borrow_mut() += 1;
+                    Ok(Outputs::Size(if path == "big.log" { 1 << 20 } else { 1 }))
+                },
+                Effects::SendMail { to } => panic!("mailed {} in a dry run", to),
+                effect => Err(effect),
+            }
+        })
+        .assert_handled()
+        .run();
+
+    assert_eq!(*stats.borrow(), 2);
+    let suppressed = report
+        .suppressed()
+        .into_iter()
+        .map(|e| e.effect)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        suppressed,
+        ["Remove(\"big.log\")", "SendMail { to: \"ops\" }"]
+    );
+}
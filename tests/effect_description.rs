@@ -0,0 +1,83 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators, generator_trait)]
+
+use std::net::SocketAddr;
+use aeiou::{Context, Effect, EffectName, IntoBlock, perform};
+
+#[derive(Debug, EffectName)]
+enum Effects {
+    /// Reads what the peer sent so far.
+    ReadTcp(SocketAddr),
+    /// Writes the whole message.
+    ///
+    /// Answered once the kernel took every byte,
+    /// not when the peer read it.
+    WriteTcp {
+        peer: SocketAddr,
+        message: String,
+    },
+    Idle,
+}
+
+#[derive(Debug, Effect)]
+#[input(Effects)]
+enum Outputs {
+    Written,
+}
+
+fn peer() -> SocketAddr {
+    ([127, 0, 0, 1], 52114).into()
+}
+
+#[test]
+fn from_doc_comments() {
+    let read = Effects::ReadTcp(peer()).describe();
+    assert_eq!(read.name, "ReadTcp");
+    assert_eq!(read.summary, "Reads what the peer sent so far.");
+    assert_eq!(read.details(), "");
+    assert_eq!(read.fields, ["0"]);
+
+    let write = Effects::WriteTcp {
+        peer: peer(),
+        message: String::new(),
+    }
+    .describe();
+    assert_eq!(write.summary, "Writes the whole message.");
+    assert_eq!(
+        write.details(),
+        "Answered once the kernel took every byte,\nnot when the peer read it."
+    );
+    assert_eq!(write.fields, ["peer", "message"]);
+
+    let idle = Effects::Idle.describe();
+    assert_eq!((idle.name, idle.summary, idle.details()), ("Idle", "", ""));
+    assert!(idle.fields.is_empty());
+}
+
+#[test]
+#[should_panic(expected = "unhandled: ReadTcp(127.0.0.1:52114) (Reads what the peer sent so far.)")]
+fn unhandled_report() {
+    (|context: Context<Outputs>| {
+        move || {
+            perform!(Effects::WriteTcp {
+                peer: peer(),
+                message: "hello".to_string(),
+            });
+            assert!(context.take().is_some());
+            perform!(Effects::ReadTcp(peer()));
+        }
+    })
+    .into_block()
+    .add_handler(|effect| match effect {
+        Effects::WriteTcp { peer: to, message } => {
+            assert_eq!((to, message.as_str()), (peer(), "hello"));
+            Ok(Outputs::Written)
+        },
+        other => Err(other),
+    })
+    .assert_handled_described()
+    .run();
+}
@@ -0,0 +1,169 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators, generator_trait, never_type)]
+
+use std::{
+    rc::Rc,
+    cell::{Cell, RefCell},
+    ops::Generator,
+};
+use either::Either;
+use aeiou::{
+    Context, Effect, Select, IntoBlock, FallibleBlock, perform_try, call,
+    tasks::{Request, TaskId, SpawnOptions, Supervision, TaskFailed, FailureKind, HasTaskFailed},
+};
+
+#[derive(Debug)]
+enum Effects {
+    ReadTcp(u16),
+    Spawn(u16),
+    Idle,
+}
+
+#[derive(Debug, PartialEq, Effect, Select)]
+#[input(Effects)]
+enum Outputs {
+    #[part(Data)]
+    Data(Vec<u8>),
+    #[part(AppError)]
+    AppError(&'static str),
+    Failed(TaskFailed<u16>),
+    Idled,
+}
+
+#[derive(Debug, PartialEq)]
+struct Data(Vec<u8>);
+
+#[derive(Debug, PartialEq)]
+struct AppError(&'static str);
+
+fn tcp(effect: Effects) -> Result<Outputs, Effects> {
+    match effect {
+        Effects::ReadTcp(80) => Ok(Outputs::Data(b"GET".to_vec())),
+        Effects::ReadTcp(_) => Ok(Outputs::AppError("connection refused")),
+        e => Err(e),
+    }
+}
+
+fn read_both(
+    context: Context<Outputs>,
+    second: u16,
+) -> impl Unpin + Generator<(), Yield = Effects, Return = Result<usize, AppError>> {
+    move || {
+        let Data(first) = perform_try!(Effects::ReadTcp(80), &context)?;
+        let Data(rest) = perform_try!(Effects::ReadTcp(second), &context)?;
+        Ok(first.len() + rest.len())
+    }
+}
+
+#[test]
+fn error_out_of_run() {
+    let run = |second| {
+        let block: FallibleBlock<_, _, _, _> = (move |context| read_both(context, second))
+            .into_block()
+            .boxed();
+        block.add_handler(tcp).assert_handled().run()
+    };
+    assert_eq!(run(80), Ok(6));
+    assert_eq!(run(22), Err(AppError("connection refused")));
+}
+
+#[test]
+fn callee_error_at_call_site() {
+    let reached = Rc::new(Cell::new(false));
+    let caller = {
+        let reached = reached.clone();
+        move |context: Context<Outputs>| {
+            move || {
+                let first = call!(read_both(context.clone(), 80))?;
+                let second = call!(read_both(context.clone(), 22))?;
+                reached.set(true);
+                Ok::<_, AppError>(first + second)
+            }
+        }
+    };
+    let result = caller.into_block().add_handler(tcp).assert_handled().run();
+    assert_eq!(result, Err(AppError("connection refused")));
+    assert!(!reached.get());
+}
+
+#[derive(Debug, Clone)]
+struct Port(u16);
+
+impl TaskId for Port {
+    type Id = u16;
+
+    fn task_id(&self) -> u16 {
+        self.0
+    }
+}
+
+impl Request for Effects {
+    type Task = Port;
+    type Effect = Effects;
+
+    fn is_task(self) -> Result<Port, Self> {
+        match self {
+            Effects::Spawn(port) => Ok(Port(port)),
+            e => Err(e),
+        }
+    }
+
+    fn is_effect(self) -> Result<Effects, Self> {
+        Ok(self)
+    }
+}
+
+impl HasTaskFailed<u16> for Outputs {
+    fn task_failed(failed: TaskFailed<u16>) -> Self {
+        Outputs::Failed(failed)
+    }
+}
+
+fn connection(
+    Port(port): Port,
+    context: Context<Outputs>,
+) -> impl Unpin + Generator<(), Yield = Either<Effects, Outputs>, Return = Result<(), AppError>> {
+    move || {
+        let Data(_) = perform_try!(Either::Left(Effects::ReadTcp(port)), &context)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn task_error_reaches_root() {
+    for supervision in [Supervision::Never, Supervision::EscalateToRoot] {
+        let failed = Rc::new(RefCell::new(None));
+        let root = {
+            let failed = failed.clone();
+            move |context: Context<Outputs>| {
+                move || {
+                    yield Effects::Spawn(22);
+                    loop {
+                        match context.take() {
+                            Some(Outputs::Failed(f)) => {
+                                *failed.borrow_mut() = Some(f);
+                                break;
+                            },
+                            _ => yield Effects::Idle,
+                        }
+                    }
+                }
+            }
+        };
+        root.into_block()
+            .spawn_supervised(SpawnOptions::default().supervision(supervision), connection)
+            .add_handler_(|effect| match tcp(effect) {
+                Ok(output) => Ok::<_, !>(output),
+                Err(_) => Ok(Outputs::Idled),
+            })
+            .run();
+        let error = format!("{:?}", AppError("connection refused"));
+        assert_eq!(
+            failed.borrow_mut().take(),
+            Some(TaskFailed(22, FailureKind::Error(error))),
+        );
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators, generator_trait)]
+
+use std::{rc::Rc, cell::RefCell, ops::Generator};
+use aeiou::{Context, Effect, EffectName, HandlerTag, IntoBlock, filter_variants};
+
+#[derive(Debug, Clone, Copy, EffectName)]
+enum Effects {
+    ReadTcp(u16),
+    WriteTcp(u16),
+    Timer(u32),
+    Log(u8),
+}
+
+impl Effects {
+    fn value(&self) -> u32 {
+        match *self {
+            Effects::ReadTcp(v) | Effects::WriteTcp(v) => u32::from(v),
+            Effects::Timer(v) => v,
+            Effects::Log(v) => u32::from(v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Effect)]
+#[input(Effects)]
+enum Outputs {
+    Handled(usize, u32),
+}
+
+fn replay(
+    workload: Vec<Effects>,
+    log: Rc<RefCell<Vec<Outputs>>>,
+) -> impl FnOnce(Context<Outputs>) -> Box<dyn Unpin + Generator<Yield = Effects, Return = ()>> {
+    move |context| {
+        Box::new(move || {
+            for effect in workload {
+                yield effect;
+                log.borrow_mut().push(context.take().unwrap());
+            }
+        })
+    }
+}
+
+// handler `i` takes the values divisible by `i + 2`, it counts its calls
+fn handler(
+    i: usize,
+    calls: Rc<RefCell<Vec<usize>>>,
+) -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+    move |effect| {
+        calls.borrow_mut()[i] += 1;
+        if effect.value() % (i as u32 + 2) == 0 {
+            Ok(Outputs::Handled(i, effect.value()))
+        } else {
+            Err(effect)
+        }
+    }
+}
+
+fn last(effect: Effects) -> Result<Outputs, Effects> {
+    Ok(Outputs::Handled(usize::MAX, effect.value()))
+}
+
+fn even_timer(effect: &Effects) -> bool {
+    matches!(effect, Effects::Timer(t) if t % 2 == 0)
+}
+
+#[test]
+fn skipped_never_invoked() {
+    let calls = Rc::new(RefCell::new(vec![0; 2]));
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let workload = vec![Effects::Log(3), Effects::ReadTcp(4), Effects::Timer(9)];
+    replay(workload, log.clone())
+        .into_block()
+        .add_handler_filtered(
+            filter_variants!(Effects::ReadTcp | Effects::WriteTcp),
+            handler(0, calls.clone()),
+        )
+        .add_handler_filtered(filter_variants!(Effects::Log), handler(1, calls.clone()))
+        .add_handler(last)
+        .assert_handled()
+        .run();
+    assert_eq!(*calls.borrow(), [1, 1]);
+    assert_eq!(
+        *log.borrow(),
+        [
+            Outputs::Handled(1, 3),
+            Outputs::Handled(0, 4),
+            Outputs::Handled(usize::MAX, 9),
+        ],
+    );
+}
+
+fn workload(seed: u64, len: usize) -> Vec<Effects> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let value = (state >> 8) as u16 % 64;
+            match state % 4 {
+                0 => Effects::ReadTcp(value),
+                1 => Effects::WriteTcp(value),
+                2 => Effects::Timer(u32::from(value)),
+                _ => Effects::Log(value as u8),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn indexed_same_as_linear() {
+    let workload = workload(0x2545_f491_4f6c_dd1d, 500);
+
+    let linear_calls = Rc::new(RefCell::new(vec![0; 6]));
+    let linear = Rc::new(RefCell::new(Vec::new()));
+    let c = &linear_calls;
+    replay(workload.clone(), linear.clone())
+        .into_block()
+        .add_handler_filtered(
+            filter_variants!(Effects::ReadTcp | Effects::WriteTcp),
+            handler(0, c.clone()),
+        )
+        .add_handler_filtered(even_timer, handler(1, c.clone()))
+        .add_handler_filtered(filter_variants!(Effects::Log), handler(2, c.clone()))
+        .add_handler(handler(3, c.clone()))
+        .add_handler_filtered(
+            filter_variants!(Effects::Timer | Effects::Log),
+            handler(4, c.clone()),
+        )
+        .add_handler_filtered(filter_variants!(Effects::WriteTcp), handler(5, c.clone()))
+        .add_handler(last)
+        .assert_handled()
+        .run();
+
+    let indexed_calls = Rc::new(RefCell::new(vec![0; 6]));
+    let indexed = Rc::new(RefCell::new(Vec::new()));
+    let c = &indexed_calls;
+    let tag = HandlerTag::new;
+    replay(workload, indexed.clone())
+        .into_block()
+        .add_handler_tagged_filtered(
+            filter_variants!(Effects::ReadTcp | Effects::WriteTcp),
+            handler(0, c.clone()),
+            tag("0"),
+        )
+        .add_handler_tagged_filtered(even_timer, handler(1, c.clone()), tag("1"))
+        .add_handler_tagged_filtered(
+            filter_variants!(Effects::Log),
+            handler(2, c.clone()),
+            tag("2"),
+        )
+        .add_handler_tagged(handler(3, c.clone()), tag("3"))
+        .add_handler_tagged_filtered(
+            filter_variants!(Effects::Timer | Effects::Log),
+            handler(4, c.clone()),
+            tag("4"),
+        )
+        .add_handler_tagged_filtered(
+            filter_variants!(Effects::WriteTcp),
+            handler(5, c.clone()),
+            tag("5"),
+        )
+        .add_handler_tagged(last, tag("last"))
+        .assert_handled()
+        .run();
+
+    assert_eq!(*linear.borrow(), *indexed.borrow());
+    assert_eq!(*linear_calls.borrow(), *indexed_calls.borrow());
+    assert!(linear.borrow().iter().any(|Outputs::Handled(i, _)| *i == 5));
+}
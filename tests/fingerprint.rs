@@ -0,0 +1,92 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators, generator_trait)]
+
+use std::ops::Generator;
+use aeiou::{Context, Effect, EffectFingerprint, IntoBlock, perform};
+
+#[derive(Debug, Clone, EffectFingerprint)]
+enum Address {
+    Local(u16),
+    Remote { host: String, port: u16 },
+}
+
+#[derive(Debug, Clone, EffectFingerprint)]
+enum Effects<B> {
+    Connect(Address),
+    Write(Address, #[fingerprint(skip)] B),
+    Close {
+        address: Address,
+        #[fingerprint(skip)]
+        at: u64,
+    },
+}
+
+#[derive(Effect)]
+#[input(Effects<Vec<u8>>)]
+enum Outputs {
+    Done,
+}
+
+fn session(
+    _: Context<Outputs>,
+    payload: Vec<u8>,
+    at: u64,
+    swapped: bool,
+) -> impl Unpin + Generator<(), Yield = Effects<Vec<u8>>, Return = ()> {
+    move || {
+        let remote = || Address::Remote {
+            host: "peer".to_string(),
+            port: 8224,
+        };
+        if swapped {
+            perform!(Effects::Write(remote(), payload.clone()));
+            perform!(Effects::Connect(remote()));
+        } else {
+            perform!(Effects::Connect(remote()));
+            perform!(Effects::Write(remote(), payload.clone()));
+        }
+        perform!(Effects::Write(Address::Local(1), payload));
+        perform!(Effects::Close {
+            address: remote(),
+            at,
+        });
+    }
+}
+
+fn run(payload: &[u8], at: u64, swapped: bool) -> (u64, u64) {
+    let (block, trace) = (|context| session(context, payload.to_vec(), at, swapped))
+        .into_block()
+        .traced();
+    let (block, fingerprint) = block.fingerprinted();
+    block
+        .add_handler(|effect| match effect {
+            Effects::Close { at: 0, .. } => Err(effect),
+            _ => Ok(Outputs::Done),
+        })
+        .assert_handled()
+        .run();
+    (trace.fingerprint(), fingerprint.value())
+}
+
+#[test]
+fn skipped_fields_ignored() {
+    let (a, _) = run(b"first buffer", 1, false);
+    let (b, _) = run(b"second", 2, false);
+    assert_eq!(a, b);
+}
+
+#[test]
+fn order_matters() {
+    let (a, _) = run(b"buffer", 1, false);
+    let (b, _) = run(b"buffer", 1, true);
+    assert_ne!(a, b);
+}
+
+#[test]
+fn streaming_equals_trace() {
+    let (traced, streamed) = run(b"buffer", 1, false);
+    assert_eq!(traced, streamed);
+}
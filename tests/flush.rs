@@ -0,0 +1,149 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators, generator_trait)]
+
+use std::{rc::Rc, cell::RefCell, ops::Generator};
+use aeiou::{
+    Context, Effect, Handler, HasFlush, HasFlushed, Flush, Flushed, Mergeable, IntoBlock, perform,
+    flush,
+};
+
+#[derive(Debug, Clone, PartialEq, HasFlush)]
+enum Effects {
+    Write(u16, Vec<u8>),
+    Flush(Flush),
+}
+
+impl Mergeable for Effects {
+    fn mergeable(&self) -> bool {
+        matches!(self, Effects::Write(..))
+    }
+}
+
+#[derive(Debug, Effect, HasFlushed)]
+#[input(Effects)]
+enum Outputs {
+    Written,
+    Flushed(Flushed),
+}
+
+type Events = Rc<RefCell<Vec<String>>>;
+
+// answers writes right away and holds them until the barrier
+#[derive(Default)]
+struct Batch(Vec<Effects>);
+
+impl Handler<Outputs> for Batch {
+    fn handle(&mut self, effect: Effects) -> Result<Outputs, Effects> {
+        match effect {
+            Effects::Write(..) => {
+                self.0.push(effect);
+                Ok(Outputs::Written)
+            },
+            other => Err(other),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Effects> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(self.0.remove(0))
+        }
+    }
+}
+
+fn record(events: &Events) -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+    let events = events.clone();
+    move |effect| match effect {
+        Effects::Write(port, data) => {
+            let data = String::from_utf8(data).unwrap();
+            events.borrow_mut().push(format!("{}: {}", port, data));
+            Ok(Outputs::Written)
+        },
+        other => Err(other),
+    }
+}
+
+fn merge(last: Effects, next: Effects) -> Result<Effects, (Effects, Effects)> {
+    match (last, next) {
+        (Effects::Write(a, mut data), Effects::Write(b, more)) if a == b => {
+            data.extend(more);
+            Ok(Effects::Write(a, data))
+        },
+        pair => Err(pair),
+    }
+}
+
+type Batches = &'static [&'static [(u16, &'static str)]];
+
+fn writes(
+    context: Context<Outputs>,
+    events: Events,
+    batches: Batches,
+) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+    move || {
+        for batch in batches {
+            for (port, data) in *batch {
+                perform!(Effects::Write(*port, data.as_bytes().to_vec()));
+            }
+            let Flushed = flush!(&context);
+            events.borrow_mut().push("flushed".to_string());
+        }
+    }
+}
+
+#[test]
+fn batched_before_ack() {
+    let events = Events::default();
+    let batches: Batches = &[&[(1, "a"), (2, "b")], &[(1, "c")]];
+    (|context| writes(context, events.clone(), batches))
+        .into_block()
+        .add_flushing_handler(Batch::default())
+        .add_handler(record(&events))
+        .acknowledge_flush()
+        .assert_handled()
+        .run();
+    assert_eq!(
+        *events.borrow(),
+        ["1: a", "2: b", "flushed", "1: c", "flushed"]
+    );
+}
+
+// the inner batch releases into the coalescing layer, which holds the merged
+// write until the barrier reaches it, and the outer batch holds that in turn
+#[test]
+fn stacked_layers() {
+    let events = Events::default();
+    let batches: Batches = &[&[(1, "a"), (1, "b"), (2, "c")], &[(1, "d")]];
+    (|context| writes(context, events.clone(), batches))
+        .into_block()
+        .add_flushing_handler(Batch::default())
+        .coalesce(16, merge)
+        .add_flushing_handler(Batch::default())
+        .add_handler(record(&events))
+        .acknowledge_flush()
+        .assert_handled()
+        .run();
+    assert_eq!(
+        *events.borrow(),
+        ["1: ab", "2: c", "flushed", "1: d", "flushed"]
+    );
+}
+
+#[test]
+fn nothing_pending() {
+    let events = Events::default();
+    let batches: Batches = &[&[]];
+    (|context| writes(context, events.clone(), batches))
+        .into_block()
+        .add_flushing_handler(Batch::default())
+        .coalesce(16, merge)
+        .add_handler(record(&events))
+        .acknowledge_flush()
+        .assert_handled()
+        .run();
+    assert_eq!(*events.borrow(), ["flushed"]);
+}
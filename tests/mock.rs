@@ -0,0 +1,95 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators, generator_trait)]
+
+use std::ops::Generator;
+use aeiou::{Ack, Context, Effect, Handler, IntoBlock, MockDefault, MockDefaults, Select};
+
+#[derive(Debug, MockDefaults)]
+#[output(Outputs)]
+enum Effects {
+    ReadTcp(u16),
+    Print(String),
+    LogMetric { name: &'static str, value: u64 },
+}
+
+#[derive(Debug, PartialEq, Effect, Select, Ack)]
+#[input(Effects)]
+enum Outputs {
+    #[part(Data)]
+    ReadTcp(String),
+    #[ack(Print)]
+    Printed,
+}
+
+struct Data(String);
+
+fn echo(
+    context: Context<Outputs>,
+    metric: bool,
+) -> impl Unpin + Generator<(), Yield = Effects, Return = String> {
+    move || {
+        yield Effects::ReadTcp(8224);
+        let data = match context.take() {
+            Some(Outputs::ReadTcp(data)) => data,
+            other => panic!("{:?}", other),
+        };
+        yield Effects::Print(data.clone());
+        let _ = context.take();
+        if metric {
+            yield Effects::LogMetric {
+                name: "echoed",
+                value: 1,
+            };
+        }
+        data
+    }
+}
+
+fn mock() -> OutputsMock {
+    OutputsMock::default()
+        .on_read_tcp(|port| Outputs::ReadTcp(format!("port {}", port)))
+        .on_print(|_| Outputs::Printed)
+}
+
+#[test]
+fn overridden_variants() {
+    let data = (|context| echo(context, false))
+        .into_block()
+        .add_handler(mock())
+        .assert_handled()
+        .run();
+    assert_eq!(data, "port 8224");
+}
+
+#[test]
+#[should_panic(expected = "unexpected effect `LogMetric` on the mock")]
+fn panics_naming_the_variant() {
+    (|context| echo(context, true))
+        .into_block()
+        .add_handler(mock())
+        .assert_handled()
+        .run();
+}
+
+#[test]
+fn ack_and_skip() {
+    let mut acking = OutputsMock::default().defaults(MockDefault::ack_if_possible());
+    assert_eq!(
+        acking.handle(Effects::Print("hi".to_string())).ok(),
+        Some(Outputs::Printed),
+    );
+    let mut skipping = mock().defaults(MockDefault::record_and_skip());
+    let metric = Effects::LogMetric {
+        name: "echoed",
+        value: 1,
+    };
+    assert!(matches!(
+        skipping.handle(metric),
+        Err(Effects::LogMetric { value: 1, .. })
+    ));
+    assert!(skipping.handle(Effects::ReadTcp(80)).is_ok());
+    assert_eq!(skipping.skipped(), ["LogMetric"]);
+}
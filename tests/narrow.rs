@@ -0,0 +1,82 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators, generator_trait)]
+
+use std::ops::Generator;
+use aeiou::{Context, Effect, Select, IntoBlock, perform, narrow_context};
+
+#[derive(Debug)]
+enum Effects {
+    Read,
+}
+
+#[derive(Debug, PartialEq, Effect)]
+#[input(Effects)]
+enum Outputs {
+    Conn(ConnOutput),
+    ConfigChanged(u32),
+}
+
+#[derive(Debug, PartialEq, Effect, Select)]
+#[input(Effects)]
+enum ConnOutput {
+    #[part(Data)]
+    Data(Vec<u8>),
+    Closed,
+}
+
+struct Data(Vec<u8>);
+
+#[test]
+fn foreign_pass_through() {
+    let context = Context::empty();
+    let conn = narrow_context!(context, Outputs::Conn);
+    context.put(Outputs::ConfigChanged(1));
+    context.put(Outputs::Conn(ConnOutput::Data(b"a".to_vec())));
+    context.put(Outputs::ConfigChanged(2));
+    conn.put(ConnOutput::Closed);
+    assert_eq!(conn.len(), 2);
+    assert_eq!(conn.take(), Some(ConnOutput::Data(b"a".to_vec())));
+    assert_eq!(conn.take(), Some(ConnOutput::Closed));
+    assert_eq!(conn.take(), None);
+    assert_eq!(context.take(), Some(Outputs::ConfigChanged(1)));
+    assert_eq!(context.take(), Some(Outputs::ConfigChanged(2)));
+    assert!(context.is_empty());
+}
+
+// only knows the connection outputs
+fn connection(
+    context: Context<ConnOutput>,
+) -> impl Unpin + Generator<(), Yield = Effects, Return = Vec<Vec<u8>>> {
+    move || {
+        let Data(first) = perform!(Effects::Read, &context);
+        let mut received = vec![first];
+        loop {
+            yield Effects::Read;
+            match context.take() {
+                Some(ConnOutput::Data(data)) => received.push(data),
+                _ => break received,
+            }
+        }
+    }
+}
+
+#[test]
+fn full_width_handlers() {
+    let mut chunks = vec![b"hello".to_vec(), b"world".to_vec()].into_iter();
+    let block = (|context| connection(narrow_context!(context, Outputs::Conn))).into_block();
+    let parent = block.context();
+    parent.put(Outputs::ConfigChanged(1));
+    let received = block
+        .add_handler(move |Effects::Read| match chunks.next() {
+            Some(data) => Ok(Outputs::Conn(ConnOutput::Data(data))),
+            None => Ok(Outputs::Conn(ConnOutput::Closed)),
+        })
+        .assert_handled()
+        .run();
+    assert_eq!(received, [b"hello".to_vec(), b"world".to_vec()]);
+    assert_eq!(parent.take(), Some(Outputs::ConfigChanged(1)));
+    assert!(parent.is_empty());
+}
@@ -0,0 +1,48 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+
+use aeiou::PayloadSize;
+
+#[derive(PayloadSize)]
+enum Effects {
+    Upload {
+        #[payload]
+        body: Vec<u8>,
+        #[payload]
+        name: String,
+        retries: u32,
+    },
+    Log(#[payload] String),
+    Tick,
+}
+
+#[derive(PayloadSize)]
+struct Frame {
+    header: Vec<u8>,
+    #[payload]
+    body: Vec<u8>,
+}
+
+#[test]
+fn sums_payload_fields() {
+    let upload = Effects::Upload {
+        body: vec![0; 100],
+        name: "report".to_string(),
+        retries: 3,
+    };
+    // the retries are not payload
+    assert_eq!(upload.payload_size(), 106);
+    assert!(matches!(upload, Effects::Upload { retries: 3, .. }));
+    assert_eq!(Effects::Log("twelve chars".to_string()).payload_size(), 12);
+    assert_eq!(Effects::Tick.payload_size(), 0);
+
+    let frame = Frame {
+        header: vec![0; 8],
+        body: vec![0; 32],
+    };
+    // the header is not payload, it is counted apart
+    assert_eq!(frame.payload_size(), 32);
+    assert_eq!(frame.header.len() + frame.payload_size(), 40);
+}
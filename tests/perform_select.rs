@@ -0,0 +1,89 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators)]
+
+use std::{rc::Rc, cell::RefCell};
+use aeiou::{Context, Effect, Select, IntoBlock, perform_select};
+
+#[derive(Debug)]
+enum Effects {
+    ReadTcp(u16),
+}
+
+#[derive(Debug, PartialEq, Effect, Select)]
+#[input(Effects)]
+enum Outputs {
+    #[part(Data)]
+    Data(Vec<u8>),
+    #[part(WouldBlock)]
+    WouldBlock(()),
+    #[part(IoError)]
+    IoError(&'static str),
+    #[part(Unrelated)]
+    Unrelated(u32),
+}
+
+struct Data(Vec<u8>);
+struct WouldBlock(());
+struct IoError(&'static str);
+struct Unrelated(u32);
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Ok(Vec<u8>),
+    Retry,
+    Fail(&'static str),
+}
+
+fn read(pending: Option<Outputs>, response: Outputs) -> (Vec<Outcome>, Vec<Outputs>) {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let rest = Rc::new(RefCell::new(Vec::new()));
+    let computation = {
+        let seen = seen.clone();
+        let rest = rest.clone();
+        move |context: Context<Outputs>| {
+            move || {
+                if let Some(pending) = pending {
+                    context.put(pending);
+                }
+                let outcome = perform_select!(Effects::ReadTcp(8224), &context, {
+                    ok: Data => Outcome::Ok(ok.0),
+                    _retry: WouldBlock => Outcome::Retry,
+                    fail: IoError => Outcome::Fail(fail.0),
+                });
+                seen.borrow_mut().push(outcome);
+                while let Some(output) = context.take() {
+                    rest.borrow_mut().push(output);
+                }
+            }
+        }
+    };
+    let mut response = Some(response);
+    computation
+        .into_block()
+        .add_handler(move |Effects::ReadTcp(_)| Ok(response.take().unwrap()))
+        .assert_handled()
+        .run();
+    let seen = seen.take();
+    let rest = rest.take();
+    (seen, rest)
+}
+
+#[test]
+fn each_arm() {
+    let (seen, _) = read(None, Outputs::Data(b"ping".to_vec()));
+    assert_eq!(seen, vec![Outcome::Ok(b"ping".to_vec())]);
+    let (seen, _) = read(None, Outputs::WouldBlock(()));
+    assert_eq!(seen, vec![Outcome::Retry]);
+    let (seen, _) = read(None, Outputs::IoError("reset"));
+    assert_eq!(seen, vec![Outcome::Fail("reset")]);
+}
+
+#[test]
+fn unrelated_survives() {
+    let (seen, rest) = read(Some(Outputs::Unrelated(7)), Outputs::WouldBlock(()));
+    assert_eq!(seen, vec![Outcome::Retry]);
+    assert_eq!(rest, vec![Outputs::Unrelated(7)]);
+}
@@ -0,0 +1,141 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![feature(generators, generator_trait)]
+
+use std::{io, rc::Rc, cell::RefCell, collections::VecDeque, ops::Generator};
+use aeiou::{
+    Context, Effect, IntoBlock, Source, perform, recv,
+    sources::{HasDisconnected, IterSource},
+};
+
+#[derive(Debug)]
+enum Effects {
+    WriteTcp(u16, String),
+    Print(String),
+    Idle,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Outputs {
+    Accepted(u16),
+    Data(u16, String),
+    Disconnected,
+    Done,
+}
+
+impl Effect for Outputs {
+    type Input = Effects;
+}
+
+impl HasDisconnected for Outputs {
+    fn disconnected(_: Option<io::Error>) -> Self {
+        Outputs::Disconnected
+    }
+}
+
+type Log = Rc<RefCell<Vec<String>>>;
+
+// the hello world server as a loop over whatever arrives, it has no idea
+// when the clients are gone
+fn server(context: Context<Outputs>) -> impl Unpin + Generator<(), Yield = Effects, Return = u32> {
+    move || {
+        let mut served = 0;
+        while let Some(output) = recv!(&context, Effects::Idle) {
+            match output {
+                Outputs::Accepted(port) => perform!(Effects::Print(format!("accepted {}", port))),
+                Outputs::Data(port, data) => {
+                    perform!(Effects::Print(data));
+                    perform!(Effects::WriteTcp(port, "hello world!\n".to_string()));
+                    served += 1;
+                },
+                Outputs::Disconnected => perform!(Effects::Print("disconnected".to_string())),
+                Outputs::Done => (),
+            }
+        }
+        served
+    }
+}
+
+fn handler(log: &Log) -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+    let log = log.clone();
+    move |effect| {
+        match effect {
+            Effects::WriteTcp(port, data) => log.borrow_mut().push(format!("{} <- {}", port, data)),
+            Effects::Print(line) => log.borrow_mut().push(line),
+            Effects::Idle => (),
+        }
+        Ok(Outputs::Done)
+    }
+}
+
+#[test]
+fn server_terminates() {
+    let log = Log::default();
+    let session = vec![
+        Outputs::Accepted(8224),
+        Outputs::Data(8224, "hi".to_string()),
+        Outputs::Data(8224, "bye".to_string()),
+    ];
+    let served = server
+        .into_block()
+        .add_source(IterSource::new(session))
+        .add_handler(handler(&log))
+        .assert_handled()
+        .run();
+    assert_eq!(served, 2);
+    assert_eq!(
+        *log.borrow(),
+        [
+            "accepted 8224",
+            "hi",
+            "8224 <- hello world!\n",
+            "bye",
+            "8224 <- hello world!\n",
+            "disconnected",
+        ],
+    );
+}
+
+// delivers everything in one round and is done right away
+struct Burst(VecDeque<Outputs>);
+
+impl Source<Outputs> for Burst {
+    fn poll(&mut self) -> Option<Outputs> {
+        self.0.pop_front()
+    }
+
+    fn is_done(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[test]
+fn pending_before_none() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let burst = (0..3).map(Outputs::Accepted).collect();
+    (|context: Context<Outputs>| {
+        let seen = seen.clone();
+        move || {
+            while let Some(output) = recv!(&context, Effects::Idle) {
+                // closed as soon as the burst is put, the outputs are still there
+                assert!(context.is_closed());
+                seen.borrow_mut().push(output);
+            }
+            assert!(context.is_empty());
+        }
+    })
+    .into_block()
+    .add_source(Burst(burst))
+    .add_handler(handler(&Log::default()))
+    .assert_handled()
+    .run();
+    assert_eq!(
+        *seen.borrow(),
+        [
+            Outputs::Accepted(0),
+            Outputs::Accepted(1),
+            Outputs::Accepted(2)
+        ],
+    );
+}
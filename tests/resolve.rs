@@ -0,0 +1,77 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "resolve")]
+#![feature(generators, generator_trait)]
+
+use std::{
+    ops::{Generator, GeneratorState},
+    time::Duration,
+};
+use aeiou::{
+    Context, Effect, Handler, IntoBlock, perform,
+    resolve::{CachingResolver, ResolveEffect, ResolveOutput, StaticResolverHandler},
+    sim::{ClientStep, NetEffect, NetOutput, VirtualNet},
+};
+
+#[derive(Debug)]
+enum Req {
+    Resolve(ResolveEffect),
+    Net(NetEffect),
+}
+
+#[derive(Debug)]
+enum Output {
+    Resolve(ResolveOutput),
+    Net(NetOutput),
+}
+
+impl Effect for Output {
+    type Input = Req;
+}
+
+// pings the node named `db`, it knows no address
+fn client(context: Context<Output>) -> impl Unpin + Generator<(), Yield = Req, Return = Vec<u8>> {
+    move || {
+        perform!(Req::Resolve(ResolveEffect::Lookup("db".to_string(), 5432)));
+        let addr = match context.take() {
+            Some(Output::Resolve(ResolveOutput::Resolved(addrs))) => addrs[0],
+            other => panic!("not resolved: {:?}", other),
+        };
+        assert_eq!(addr.port(), 5432);
+        let id = VirtualNet::conn_at(addr.ip()).expect("a simulated node");
+        perform!(Req::Net(NetEffect::Write(id, b"PING\n".to_vec())));
+        assert!(matches!(
+            context.take(),
+            Some(Output::Net(NetOutput::Written))
+        ));
+        perform!(Req::Net(NetEffect::Read(id)));
+        match context.take() {
+            Some(Output::Net(NetOutput::Data(data))) => data,
+            other => panic!("no reply: {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn peer_by_name() {
+    let net = VirtualNet::new();
+    net.connect(vec![]);
+    let db = net.connect_named("db", vec![ClientStep::send("PONG\n")]);
+    let inner = StaticResolverHandler::for_net(&net);
+    let mut resolver = CachingResolver::new(inner, Duration::from_secs(60), Duration::from_secs(5));
+
+    let mut block = client.into_block();
+    let reply = loop {
+        match block.resume() {
+            GeneratorState::Yielded(Req::Resolve(effect)) => {
+                block.put(Output::Resolve(resolver.handle(effect).unwrap()))
+            },
+            GeneratorState::Yielded(Req::Net(effect)) => block.put(Output::Net(net.handle(effect))),
+            GeneratorState::Complete(reply) => break reply,
+        }
+    };
+    assert_eq!(reply, b"PONG\n");
+    assert_eq!(net.received(db), b"PING\n");
+    assert_eq!(resolver.inner().lookups(), 1);
+}
@@ -0,0 +1,182 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators, generator_trait)]
+
+use std::{net::SocketAddr, ops::Generator, time::Duration};
+use aeiou::{Context, Divergence, Effect, IntoBlock, Select, deadline, perform, scenario};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Effects {
+    ListenTcp(u16),
+    ReadTcp(SocketAddr),
+    Print(String),
+    Now,
+}
+
+#[derive(Debug, Clone, PartialEq, Effect, Select)]
+#[input(Effects)]
+enum EffectsOutput {
+    #[part(AcceptedTcp)]
+    ListenedTcp(SocketAddr),
+    #[part(ReadTcp)]
+    ReadTcp(String),
+    Printed,
+    #[part(Elapsed)]
+    Elapsed(Duration),
+    ConfigChanged,
+}
+
+struct AcceptedTcp(SocketAddr);
+
+struct ReadTcp(String);
+
+struct Elapsed(Duration);
+
+// the server of the hello world example
+fn server(
+    context: Context<EffectsOutput>,
+) -> impl Unpin + Generator<(), Yield = Effects, Return = ()> {
+    move || {
+        let AcceptedTcp(addr) = perform!(Effects::ListenTcp(8224), &context);
+        let ReadTcp(data) = perform!(Effects::ReadTcp(addr), &context);
+        perform!(Effects::Print(data));
+    }
+}
+
+fn client() -> SocketAddr {
+    ([127, 0, 0, 1], 50000).into()
+}
+
+#[test]
+fn hello_world_server() {
+    let addr = client();
+    let scenario = scenario! {
+        expect Effects::ListenTcp(8224) => respond EffectsOutput::ListenedTcp(addr);
+        expect Effects::ReadTcp(a) if *a == addr
+            => respond EffectsOutput::ReadTcp("hello world!\n".to_string());
+        expect Effects::Print(line) if line == "hello world!\n" => no_response;
+        expect_complete;
+    };
+    scenario.run(server.into_block());
+}
+
+fn diverge(scenario: aeiou::Scenario<'_, EffectsOutput>) -> Divergence {
+    scenario
+        .try_run(server.into_block())
+        .expect_err("the scenario must diverge")
+}
+
+#[test]
+fn wrong_effect_names_the_step() {
+    let addr = client();
+    let divergence = diverge(scenario! {
+        expect Effects::ListenTcp(8224) => respond EffectsOutput::ListenedTcp(addr);
+        expect Effects::Print(_) => no_response;
+    });
+    assert_eq!(divergence.step, 2);
+    assert_eq!(divergence.expected, "Effects::Print(_)");
+    assert_eq!(
+        divergence.actual,
+        Some(format!("{:?}", Effects::ReadTcp(addr))),
+    );
+    assert_eq!(divergence.trace, [(1, "ListenTcp(8224)".to_string())]);
+    let message = divergence.to_string();
+    assert!(message.starts_with("scenario diverged at step 2: expected `Effects::Print(_)`"));
+    assert!(message.contains("-   2 Effects::Print(_)"));
+    assert!(message.contains(&format!("+   2 {:?}", Effects::ReadTcp(addr))));
+}
+
+#[test]
+fn early_completion_and_leftover_effects() {
+    let addr = client();
+    let divergence = diverge(scenario! {
+        expect Effects::ListenTcp(_) => respond EffectsOutput::ListenedTcp(addr);
+        expect Effects::ReadTcp(_) => respond EffectsOutput::ReadTcp("x".to_string());
+        expect Effects::Print(_) => no_response;
+        expect Effects::Now => no_response;
+    });
+    assert_eq!(divergence.step, 4);
+    assert_eq!(divergence.actual, None);
+    assert!(divergence.to_string().contains("got `completion`"));
+
+    // the script ends before the computation does
+    let divergence = diverge(scenario! {
+        expect Effects::ListenTcp(_) => respond EffectsOutput::ListenedTcp(addr);
+    });
+    assert_eq!(divergence.step, 2);
+    assert_eq!(divergence.expected, "completion");
+    assert_eq!(
+        divergence.actual,
+        Some(format!("{:?}", Effects::ReadTcp(addr))),
+    );
+}
+
+#[test]
+#[should_panic(expected = "scenario diverged at step 1")]
+fn run_panics_on_divergence() {
+    let scenario = scenario! {
+        expect Effects::Now => no_response;
+    };
+    scenario.run(server.into_block());
+}
+
+// reads three addresses in any order, then waits for the config
+fn reader(
+    context: Context<EffectsOutput>,
+) -> impl Unpin + Generator<(), Yield = Effects, Return = Vec<String>> {
+    move || {
+        let mut lines = Vec::new();
+        for port in [1, 2, 3] {
+            let addr = ([127, 0, 0, 1], port).into();
+            let ReadTcp(line) = perform!(Effects::ReadTcp(addr), &context);
+            lines.push(line);
+        }
+        perform!(Effects::Print(lines.concat()));
+        assert_eq!(context.take(), Some(EffectsOutput::Printed));
+        let Elapsed(elapsed) = perform!(Effects::Now, &context);
+        assert_eq!(context.take(), Some(EffectsOutput::ConfigChanged));
+        lines.push(format!("{}s", elapsed.as_secs()));
+        lines
+    }
+}
+
+#[test]
+fn many_unordered_and_time() {
+    let start = deadline::now();
+    let lines = scenario! {
+        expect_many 2 matching Effects::ReadTcp(_)
+            => respond_each |i| EffectsOutput::ReadTcp(i.to_string());
+        unordered {
+            expect Effects::Print(line) if line == "01c" => respond EffectsOutput::Printed;
+            expect Effects::ReadTcp(_) => respond EffectsOutput::ReadTcp("c".to_string());
+        };
+        // the computation did not take the response yet
+        assert_state |context| assert_eq!(context.len(), 1);
+        advance_time 4500ms;
+        advance_time Duration::from_millis(500);
+        expect Effects::Now => respond EffectsOutput::Elapsed(deadline::now() - start);
+        inject EffectsOutput::ConfigChanged;
+        expect_complete;
+    }
+    .run(reader.into_block());
+    assert_eq!(lines, ["0", "1", "c", "5s"]);
+}
+
+#[test]
+fn unordered_divergence_lists_the_remaining() {
+    let divergence = scenario! {
+        expect_many 2 matching Effects::ReadTcp(_)
+            => respond_each |i| EffectsOutput::ReadTcp(i.to_string());
+        unordered {
+            expect Effects::Print(_) => no_response;
+            expect Effects::Now => no_response;
+        };
+    }
+    .try_run(reader.into_block())
+    .expect_err("the third read is not in the group");
+    assert_eq!(divergence.step, 2);
+    assert_eq!(divergence.expected, "any of Effects::Print(_), Effects::Now");
+    assert_eq!(divergence.trace.len(), 2);
+}
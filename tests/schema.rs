@@ -0,0 +1,104 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+
+use std::net::SocketAddr;
+use aeiou::{
+    Effect, EffectSchema, Select,
+    schema::{self, FieldSchema, SchemaChange},
+};
+
+/// Effects of the hello world client and server.
+#[derive(Debug, EffectSchema)]
+pub enum Effects {
+    /// Accept one connection on the port.
+    ListenTcp(u16),
+    ConnectTcp(SocketAddr),
+    ReadTcp(SocketAddr),
+    /// Write the whole message.
+    WriteTcp(SocketAddr, String),
+    Print(String),
+}
+
+#[derive(Effect, Select, EffectSchema)]
+#[input(Effects)]
+pub enum EffectsOutput {
+    #[part(AcceptedTcp)]
+    ListenedTcp(SocketAddr),
+    ConnectedTcp(SocketAddr),
+    #[part(ReadTcp)]
+    ReadTcp(String),
+    WrittenTcp,
+    Printed,
+}
+
+pub struct AcceptedTcp(SocketAddr);
+
+pub struct ReadTcp(String);
+
+fn field(ty: &str) -> FieldSchema {
+    FieldSchema {
+        name: None,
+        ty: ty.to_string(),
+    }
+}
+
+#[test]
+fn hello_world() {
+    let effects = Effects::schema();
+    assert_eq!(effects.doc, "Effects of the hello world client and server.");
+    assert_eq!(effects.input, None);
+    let names = effects
+        .variants
+        .iter()
+        .map(|v| v.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        ["ListenTcp", "ConnectTcp", "ReadTcp", "WriteTcp", "Print"]
+    );
+    let listen = effects.variant("ListenTcp").unwrap();
+    assert_eq!(listen.doc, "Accept one connection on the port.");
+    assert_eq!(listen.fields, vec![field("u16")]);
+    let write = effects.variant("WriteTcp").unwrap();
+    assert_eq!(write.fields, vec![field("SocketAddr"), field("String")]);
+
+    let outputs = EffectsOutput::schema();
+    assert_eq!(outputs.input.as_deref(), Some("Effects"));
+    let parts = outputs
+        .variants
+        .iter()
+        .filter_map(|v| Some((v.name.as_str(), v.part.as_deref()?)))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        parts,
+        [("ListenedTcp", "AcceptedTcp"), ("ReadTcp", "ReadTcp")]
+    );
+    assert!(outputs.variant("Printed").unwrap().fields.is_empty());
+    #[cfg(feature = "serde")]
+    assert!(outputs.to_json().contains(r#""part":"AcceptedTcp""#));
+}
+
+#[test]
+fn diff() {
+    let old = Effects::schema();
+    let mut new = old.clone();
+    new.variants.retain(|v| v.name != "Print");
+    new.variants.push(schema::VariantSchema {
+        name: "Log".to_string(),
+        fields: vec![field("String")],
+        part: None,
+        doc: String::new(),
+    });
+    let changes = schema::schema_diff(&old, &new);
+    assert_eq!(
+        changes,
+        vec![
+            SchemaChange::RemovedVariant("Print".to_string()),
+            SchemaChange::AddedVariant("Log".to_string()),
+        ],
+    );
+    assert!(changes[0].is_breaking());
+    assert!(!changes[1].is_breaking());
+}
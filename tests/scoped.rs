@@ -0,0 +1,97 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators, generator_trait)]
+
+use std::ops::{Generator, GeneratorState};
+use aeiou::{BufferPool, Context, Effect, IntoBlock, PooledBuf, Scoped, Select, perform};
+
+const SIZE: usize = 1 << 20;
+
+#[derive(Debug)]
+enum Effects {
+    ReadTcp(usize),
+    Print(String),
+}
+
+#[derive(Debug, Effect, Select)]
+#[input(Effects)]
+enum Outputs {
+    #[part(borrowed)]
+    ReadTcp(Scoped<PooledBuf>),
+    #[part(Printed)]
+    Printed(usize),
+}
+
+struct Printed(usize);
+
+// answers the reads from the pool, returns where each read landed
+fn drive<G>(block: &mut aeiou::Block<Outputs, G>, pool: &BufferPool) -> (G::Return, Vec<usize>)
+where
+    G: Unpin + Generator<(), Yield = Effects>,
+{
+    let mut lent = Vec::new();
+    loop {
+        match block.resume() {
+            GeneratorState::Yielded(Effects::ReadTcp(n)) => {
+                let mut buffer = pool.take();
+                buffer.resize(n, 0xab);
+                lent.push(buffer.as_ptr() as usize);
+                block.context().put_scoped(buffer, Outputs::ReadTcp);
+            },
+            GeneratorState::Yielded(Effects::Print(line)) => {
+                block.put(Outputs::Printed(line.len()))
+            },
+            GeneratorState::Complete(r) => break (r, lent),
+        }
+    }
+}
+
+#[test]
+fn large_reads_are_not_copied() {
+    let reader = |context: Context<Outputs>| {
+        move || {
+            let mut seen = Vec::new();
+            for _ in 0..3 {
+                let view: Scoped<PooledBuf> = perform!(Effects::ReadTcp(SIZE), &context);
+                let buffer = view.get().unwrap();
+                assert!(buffer.iter().all(|b| *b == 0xab));
+                seen.push(buffer.as_ptr() as usize);
+            }
+            seen
+        }
+    };
+    let pool = BufferPool::new(SIZE);
+    let (seen, lent) = drive(&mut reader.into_block(), &pool);
+    // the computation read the buffers the handler filled
+    assert_eq!(seen, lent);
+    // each view dropped before the next read, so one buffer served them all
+    assert_eq!(
+        (pool.allocated(), pool.reused(), pool.outstanding()),
+        (1, 2, 0)
+    );
+}
+
+#[test]
+fn held_across_a_yield() {
+    let holder = |context: Context<Outputs>| {
+        move || {
+            let view: Scoped<PooledBuf> = perform!(Effects::ReadTcp(4), &context);
+            assert_eq!(view.get().map(|b| b.len()), Ok(4));
+            // the owned path is as before
+            let Printed(n) = perform!(Effects::Print("held".to_string()), &context);
+            assert_eq!(n, 4);
+            view.get().map(|b| b.len())
+        }
+    };
+    let pool = BufferPool::new(16);
+    let (result, _) = drive(&mut holder.into_block(), &pool);
+    let expired = result.unwrap_err();
+    assert!(expired.now > expired.put_at);
+    assert!(expired
+        .to_string()
+        .contains("it is valid only until the next yield"));
+    // the expired view is gone with the computation, the buffer is back
+    assert_eq!(pool.outstanding(), 0);
+}
@@ -0,0 +1,192 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators, generator_trait)]
+
+use std::{rc::Rc, cell::RefCell, ops::Generator};
+use aeiou::{
+    Context, Effect, IntoBlock, Select, SessionError, SessionKey, WithSession, Category, close,
+    open_session, perform_in,
+};
+
+#[derive(Debug, Clone, PartialEq, WithSession)]
+enum Effects {
+    Begin,
+    Exec(#[session] u32, &'static str),
+    Commit(#[session] u32),
+    #[session(close)]
+    Rollback(#[session] u32),
+}
+
+#[derive(Debug, PartialEq, Effect, Select)]
+#[input(Effects)]
+enum Outputs {
+    #[part(Begun)]
+    Begun(u32),
+    #[part(Rows)]
+    Rows(usize),
+    Done,
+}
+
+struct Begun(u32);
+
+impl SessionKey for Begun {
+    type Key = u32;
+
+    fn session_key(&self) -> u32 {
+        self.0
+    }
+}
+
+struct Rows(usize);
+
+type Log = Rc<RefCell<Vec<Effects>>>;
+
+// records every effect, the transactions are numbered from 7
+fn database(log: &Log) -> impl FnMut(Effects) -> Result<Outputs, Effects> {
+    let log = log.clone();
+    let mut next = 7;
+    move |effect| {
+        log.borrow_mut().push(effect.clone());
+        Ok(match effect {
+            Effects::Begin => {
+                next += 1;
+                Outputs::Begun(next - 1)
+            },
+            Effects::Exec(_, sql) => Outputs::Rows(sql.len()),
+            Effects::Commit(_) | Effects::Rollback(_) => Outputs::Done,
+        })
+    }
+}
+
+fn transfer(
+    context: Context<Outputs>,
+    funds: bool,
+) -> impl Unpin + Generator<(), Yield = Effects, Return = Result<usize, &'static str>> {
+    move || {
+        let tx = open_session!(&context, Effects::Begin => Begun);
+        let Rows(debited) = perform_in!(tx, |key| Effects::Exec(key, "debit"), &context).unwrap();
+        if !funds {
+            return Err("insufficient funds");
+        }
+        let Rows(credited) = perform_in!(tx, |key| Effects::Exec(key, "credit"), &context).unwrap();
+        let committed = close!(tx, &context, Effects::Commit).unwrap();
+        assert_eq!(committed, Some(Outputs::Done));
+        Ok(debited + credited)
+    }
+}
+
+#[test]
+fn commits_explicitly() {
+    let log = Log::default();
+    let rows = (|context| transfer(context, true))
+        .into_block()
+        .close_sessions()
+        .add_handler(database(&log))
+        .assert_handled()
+        .run();
+    assert_eq!(rows, Ok(11));
+    assert_eq!(
+        *log.borrow(),
+        [
+            Effects::Begin,
+            Effects::Exec(7, "debit"),
+            Effects::Exec(7, "credit"),
+            Effects::Commit(7),
+        ],
+    );
+}
+
+#[test]
+fn rolled_back_on_early_return() {
+    let log = Log::default();
+    let block = (|context| transfer(context, false))
+        .into_block()
+        .close_sessions()
+        .add_handler(database(&log));
+    let context = block.context();
+    let rows = block.assert_handled().run();
+    assert_eq!(rows, Err("insufficient funds"));
+    assert_eq!(
+        *log.borrow(),
+        [
+            Effects::Begin,
+            Effects::Exec(7, "debit"),
+            Effects::Rollback(7)
+        ],
+    );
+    // the response to the rollback is not left for anyone to take
+    assert_eq!(context.take(), None);
+}
+
+#[test]
+fn injected_keys() {
+    let log = Log::default();
+    let stale = (|context: Context<Outputs>| {
+        move || {
+            let a = open_session!(&context, Effects::Begin => Begun);
+            let b = open_session!(&context, Effects::Begin => Begun);
+            for (session, sql) in [(a.clone(), "a"), (b.clone(), "b"), (a.clone(), "a")] {
+                let Rows(_) =
+                    perform_in!(session, |key| Effects::Exec(key, sql), &context).unwrap();
+            }
+            let clone = a.clone();
+            close!(a, &context).unwrap();
+            assert!(!clone.is_open());
+            // `b` is dropped open at the end
+            perform_in!(clone, |key| Effects::Exec(key, "stale"))
+        }
+    })
+    .into_block()
+    .close_sessions()
+    .add_handler(database(&log))
+    .assert_handled()
+    .run();
+    assert_eq!(stale, Err(SessionError::Closed { generation: 0 }));
+    assert_eq!(
+        *log.borrow(),
+        [
+            Effects::Begin,
+            Effects::Begin,
+            Effects::Exec(7, "a"),
+            Effects::Exec(8, "b"),
+            Effects::Exec(7, "a"),
+            Effects::Rollback(7),
+            Effects::Rollback(8),
+        ],
+    );
+    assert_eq!(Effects::Exec(8, "b").key(), Some(&8));
+    assert_eq!(Effects::Begin.key(), None);
+}
+
+#[test]
+fn cleanups_accounted() {
+    let log = Log::default();
+    let (block, accounting) = (|context| transfer(context, false))
+        .into_block()
+        .close_sessions()
+        .add_handler(database(&log))
+        .assert_handled()
+        .with_accounting();
+    assert_eq!(block.run(), Err("insufficient funds"));
+    accounting.assert_quiescent();
+    if cfg!(feature = "diagnostics") {
+        assert_eq!(accounting.report().get(Category::Cleanups).high_water, 1);
+    }
+}
+
+#[cfg(feature = "diagnostics")]
+#[test]
+#[should_panic(expected = "registered cleanups: 1")]
+fn cleanup_never_performed() {
+    let log = Log::default();
+    // nothing yields the rollback of the dropped session
+    let (block, accounting) = (|context| transfer(context, false))
+        .into_block()
+        .add_handler(database(&log))
+        .assert_handled()
+        .with_accounting();
+    assert_eq!(block.run(), Err("insufficient funds"));
+    accounting.assert_quiescent();
+}
@@ -0,0 +1,61 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators)]
+
+use aeiou::{Effect, IntoBlock, state_machine};
+
+#[derive(Debug)]
+enum Effects {
+    Connect(u16),
+}
+
+#[derive(Effect)]
+#[input(Effects)]
+enum Outputs {
+    Connected(u16),
+    Failed(&'static str),
+}
+
+fn handshake(failures: usize) -> (Result<u16, &'static str>, usize) {
+    let mut failures = failures;
+    let mut calls = 0;
+    let result = state_machine! {
+        Effects => Outputs;
+        let mut attempts = 0;
+        start Connecting(8224);
+        state Connecting(port: u16) {
+            perform Connect(port);
+            on Connected(port) => Ready(port);
+            on Failed(_) if attempts < 2 => { attempts += 1; } Connecting(port + 1);
+            on Failed(reason) => Error(reason);
+        }
+        terminal Ready(port: u16) => Ok(port);
+        terminal Error(reason: &'static str) => Err(reason);
+    }
+    .into_block()
+    .add_handler(|Effects::Connect(port)| {
+        calls += 1;
+        if failures > 0 {
+            failures -= 1;
+            Ok(Outputs::Failed("refused"))
+        } else {
+            Ok(Outputs::Connected(port))
+        }
+    })
+    .assert_handled()
+    .run();
+    (result, calls)
+}
+
+#[test]
+fn retries_then_ready() {
+    assert_eq!(handshake(0), (Ok(8224), 1));
+    assert_eq!(handshake(2), (Ok(8226), 3));
+}
+
+#[test]
+fn gives_up() {
+    assert_eq!(handshake(5), (Err("refused"), 3));
+}
@@ -0,0 +1,103 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "derive")]
+#![feature(generators)]
+
+use aeiou::{Context, Effect, Select, IntoBlock, TakeResult, perform};
+
+#[derive(Debug)]
+enum Effects {
+    Read(u16),
+}
+
+#[derive(Debug, PartialEq, Effect, Select)]
+#[input(Effects)]
+enum Outputs {
+    #[part(Data)]
+    Data(Vec<u8>),
+    Closed,
+}
+
+#[derive(Debug, PartialEq)]
+struct Data(Vec<u8>);
+
+// no `Debug`, the message names the type instead
+#[allow(dead_code)]
+#[derive(Effect, Select)]
+#[input(Effects)]
+enum Opaque {
+    #[part(Data)]
+    Data(Vec<u8>),
+    Closed,
+}
+
+#[test]
+fn outcomes() {
+    let context = Context::empty();
+    assert_eq!(
+        <Outputs as Select<Data>>::try_take(&context),
+        TakeResult::Empty
+    );
+
+    context.put(Outputs::Data(vec![1]));
+    context.put(Outputs::Closed);
+    assert_eq!(
+        <Outputs as Select<Data>>::try_take(&context),
+        TakeResult::Matched(Data(vec![1])),
+    );
+    assert_eq!(
+        <Outputs as Select<Data>>::try_take(&context),
+        TakeResult::Mismatched(Outputs::Closed),
+    );
+    assert!(context.is_empty());
+
+    // `take` puts the mismatched output back
+    context.put(Outputs::Closed);
+    assert_eq!(<Outputs as Select<Data>>::take(&context), None);
+    assert_eq!(context.take(), Some(Outputs::Closed));
+}
+
+#[test]
+#[should_panic(expected = "unexpected output after `Effects::Read(1)`: Closed")]
+fn mismatched_message() {
+    (|context: Context<Outputs>| {
+        move || {
+            let Data(_) = perform!(Effects::Read(1), &context);
+        }
+    })
+    .into_block()
+    .add_handler(|Effects::Read(_)| Ok(Outputs::Closed))
+    .assert_handled()
+    .run();
+}
+
+#[test]
+#[should_panic(
+    expected = "unexpected output after `Effects::Read(2)`: a value of `take_result::Opaque`"
+)]
+fn mismatched_without_debug() {
+    (|context: Context<Opaque>| {
+        move || {
+            let Data(_) = perform!(Effects::Read(2), &context);
+        }
+    })
+    .into_block()
+    .add_handler(|Effects::Read(_)| Ok(Opaque::Closed))
+    .assert_handled()
+    .run();
+}
+
+#[test]
+#[should_panic(expected = "no output in the context after `Effects::Read(3)`")]
+fn empty_message() {
+    let mut block = (|context: Context<Outputs>| {
+        move || {
+            let Data(_) = perform!(Effects::Read(3), &context);
+        }
+    })
+    .into_block();
+    let _ = block.resume();
+    // resumed without a response
+    let _ = block.resume();
+}
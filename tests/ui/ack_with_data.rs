@@ -0,0 +1,14 @@
+use aeiou::{Ack, Effect};
+
+enum Effects {
+    Print(String),
+}
+
+#[derive(Effect, Ack)]
+#[input(Effects)]
+enum Outputs {
+    #[ack(Print)]
+    Printed(usize),
+}
+
+fn main() {}
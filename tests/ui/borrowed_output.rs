@@ -0,0 +1,9 @@
+use aeiou::Select;
+
+#[derive(Select)]
+enum Outputs<'a> {
+    #[part(Data)]
+    Read(&'a [u8]),
+}
+
+fn main() {}
@@ -0,0 +1,14 @@
+use aeiou::{Effect, Select};
+
+pub enum Effects {
+    Read,
+}
+
+#[derive(Effect, Select)]
+#[input(Effects)]
+enum Outputs {
+    #[part(borrowed)]
+    Done,
+}
+
+fn main() {}
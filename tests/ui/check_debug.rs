@@ -0,0 +1,14 @@
+use aeiou::Effect;
+
+pub enum Effects {
+    Read,
+}
+
+#[derive(Effect)]
+#[input(Effects)]
+#[effect(checks(debug))]
+pub enum Outputs {
+    Done,
+}
+
+fn main() {}
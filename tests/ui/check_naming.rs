@@ -0,0 +1,10 @@
+use aeiou::Select;
+
+#[derive(Select)]
+#[effect(checks(naming = "snake_case_parts"))]
+enum Outputs {
+    #[part(parts::data)]
+    Data(u8),
+}
+
+fn main() {}
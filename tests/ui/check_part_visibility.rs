@@ -0,0 +1,17 @@
+use aeiou::{Effect, Select};
+
+pub enum Effects {
+    Read,
+}
+
+#[derive(Effect, Select)]
+#[input(Effects)]
+#[effect(checks(part_visibility))]
+pub enum Outputs {
+    #[part(Data)]
+    Data(u8),
+}
+
+struct Data(u8);
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aeiou::Select;
+
+#[derive(Select)]
+#[effect(deny_unknown_attrs)]
+enum Outputs {
+    Data(#[part(Data)] u8),
+}
+
+fn main() {}
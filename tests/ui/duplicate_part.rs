@@ -0,0 +1,27 @@
+#![feature(generators)]
+
+use aeiou::{Context, Effect, Select, perform_select};
+
+enum Effects {
+    Read,
+}
+
+#[derive(Effect, Select)]
+#[input(Effects)]
+enum Outputs {
+    #[part(Data)]
+    Data(u8),
+}
+
+struct Data(u8);
+
+fn main() {
+    let _ = |context: Context<Outputs>| {
+        move || {
+            let _ = perform_select!(Effects::Read, &context, {
+                first: Data => first.0,
+                second: Data => second.0,
+            });
+        }
+    };
+}
@@ -0,0 +1,10 @@
+use aeiou::EffectFingerprint;
+
+#[derive(EffectFingerprint)]
+enum Effects {
+    Read(u16),
+    #[fingerprint(skip)]
+    Tick,
+}
+
+fn main() {}
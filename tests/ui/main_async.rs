@@ -0,0 +1,4 @@
+#[aeiou::main(effect = Outputs)]
+async fn server(context: Context<Outputs>) {}
+
+fn main() {}
@@ -0,0 +1,4 @@
+#[aeiou::main(effect = Outputs)]
+fn server(port: u16) {}
+
+fn main() {}
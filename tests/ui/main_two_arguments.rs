@@ -0,0 +1,4 @@
+#[aeiou::main(effect = Outputs)]
+fn server(context: Context<Outputs>, port: u16) {}
+
+fn main() {}
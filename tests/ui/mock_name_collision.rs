@@ -0,0 +1,17 @@
+use aeiou::{Effect, MockDefaults};
+
+struct Outputs;
+
+impl Effect for Outputs {
+    type Input = Effects;
+}
+
+#[derive(MockDefaults)]
+#[output(Outputs)]
+#[allow(non_camel_case_types)]
+enum Effects {
+    ReadTcp(u16),
+    Read_Tcp(u16),
+}
+
+fn main() {}
@@ -0,0 +1,15 @@
+use aeiou::{Effect, MockDefaults};
+
+struct Outputs;
+
+impl Effect for Outputs {
+    type Input = Effects;
+}
+
+#[derive(MockDefaults)]
+#[output(Outputs)]
+struct Effects {
+    port: u16,
+}
+
+fn main() {}
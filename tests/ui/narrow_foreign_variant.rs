@@ -0,0 +1,41 @@
+#![feature(generators)]
+
+use aeiou::{Context, Effect, Select, perform, narrow_context};
+
+enum Effects {
+    Read,
+}
+
+#[derive(Effect)]
+#[input(Effects)]
+enum Outputs {
+    Conn(ConnOutput),
+    ConfigChanged(u32),
+}
+
+#[derive(Effect, Select)]
+#[input(Effects)]
+enum ConnOutput {
+    #[part(Data)]
+    Data(u8),
+}
+
+struct Data(u8);
+
+// written against the narrow type, the root variant is not there
+fn connection(context: Context<ConnOutput>) -> u32 {
+    match context.take() {
+        Some(Outputs::ConfigChanged(version)) => version,
+        _ => 0,
+    }
+}
+
+fn main() {
+    let _ = |context: Context<Outputs>| {
+        let conn = narrow_context!(context, Outputs::Conn);
+        move || {
+            let Data(_) = perform!(Effects::Read, &conn);
+            connection(conn.clone())
+        }
+    };
+}
@@ -0,0 +1,25 @@
+use aeiou::{Effect, Select};
+
+pub enum Effects {
+    Read,
+}
+
+pub mod parts {
+    pub struct Data(pub u8);
+}
+
+// the `Debug` derive comes before, the check does not see it in the attributes
+#[derive(Debug, Effect, Select)]
+#[input(Effects)]
+#[effect(checks(debug, part_visibility, naming = "snake_case_parts"))]
+#[effect(deny_unknown_attrs)]
+pub enum Outputs {
+    #[part(parts::Data)]
+    Data(u8),
+    Done,
+}
+
+fn main() {
+    let _ = Effects::Read;
+    let _ = Outputs::Done;
+}
@@ -0,0 +1,37 @@
+use aeiou::{Effect, Select};
+
+pub enum Effects {
+    Read,
+}
+
+// The probe only catches what the compiler calls a private type in a public
+// interface. A `pub` part in a private module cannot be named outside of the
+// crate, yet it passes.
+mod hidden {
+    pub struct Data(pub u8);
+}
+
+#[derive(Effect, Select)]
+#[input(Effects)]
+#[effect(checks(part_visibility))]
+pub enum Outputs {
+    #[part(hidden::Data)]
+    Data(u8),
+}
+
+// A private enum is not probed at all, its parts can be anything.
+struct Private(u8);
+
+#[derive(Effect, Select)]
+#[input(Effects)]
+#[effect(checks(part_visibility))]
+enum Internal {
+    #[part(Private)]
+    Private(u8),
+}
+
+fn main() {
+    let _ = Effects::Read;
+    let _ = Outputs::Data(0);
+    let _ = Internal::Private(0);
+}
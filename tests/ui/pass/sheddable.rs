@@ -0,0 +1,23 @@
+use aeiou::{Effect, Sheddable};
+
+pub enum Effects {
+    Prefetch(u32),
+    Hint,
+    Write(u32),
+}
+
+#[derive(Debug, PartialEq, Effect, Sheddable)]
+#[input(Effects)]
+pub enum Outputs {
+    #[sheddable(Prefetch, Hint)]
+    Skipped,
+    Written,
+}
+
+fn main() {
+    assert_eq!(Outputs::shed(&Effects::Prefetch(1)), Some(Outputs::Skipped));
+    assert_eq!(Outputs::shed(&Effects::Hint), Some(Outputs::Skipped));
+    // a required effect always reaches a handler
+    assert_eq!(Outputs::shed(&Effects::Write(1)), None);
+    let _ = Outputs::Written;
+}
@@ -0,0 +1,18 @@
+#![feature(generators, generator_trait)]
+
+use std::ops::Generator;
+use aeiou::{Block, IntoBlock};
+
+struct Mine;
+
+// only closures and scripts become blocks, a type of its own cannot
+impl<G> IntoBlock<(), G> for Mine
+where
+    G: Unpin + Generator<()>,
+{
+    fn into_block(self) -> Block<(), G> {
+        unimplemented!()
+    }
+}
+
+fn main() {}
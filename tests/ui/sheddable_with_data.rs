@@ -0,0 +1,14 @@
+use aeiou::{Effect, Sheddable};
+
+enum Effects {
+    Prefetch(u32),
+}
+
+#[derive(Effect, Sheddable)]
+#[input(Effects)]
+enum Outputs {
+    #[sheddable(Prefetch)]
+    Skipped(u32),
+}
+
+fn main() {}
@@ -0,0 +1,29 @@
+#![feature(generators)]
+
+use aeiou::{Effect, state_machine};
+
+enum Effects {
+    Ping,
+}
+
+#[derive(Effect)]
+#[input(Effects)]
+enum Outputs {
+    Pong,
+}
+
+fn main() {
+    let _ = state_machine! {
+        Effects => Outputs;
+        start Pinging;
+        state Pinging {
+            perform Ping;
+            on Pong => Done;
+        }
+        state Lost {
+            perform Ping;
+            on Pong => Done;
+        }
+        terminal Done => ();
+    };
+}
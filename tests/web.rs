@@ -0,0 +1,137 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+#![cfg(all(feature = "wasm", target_arch = "wasm32"))]
+#![feature(generators, generator_trait)]
+
+use std::{rc::Rc, cell::RefCell, ops::GeneratorState};
+use aeiou::{
+    Context, Effect, Select, IntoBlock, OpId, TwoPhase, Operation, perform_two_phase, await_done,
+    web::{FetchHandler, Fetched, HttpEffect, HttpRequest, HttpResponse},
+};
+use js_sys::Promise;
+use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::JsFuture;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+#[derive(Debug)]
+enum Effects {
+    Http(HttpEffect),
+    Idle,
+}
+
+impl HttpRequest for Effects {
+    fn into_http(self) -> Result<HttpEffect, Self> {
+        match self {
+            Effects::Http(request) => Ok(request),
+            e => Err(e),
+        }
+    }
+}
+
+enum Outputs {
+    Accepted(OpId),
+    Fetched(Fetched),
+}
+
+impl Effect for Outputs {
+    type Input = Effects;
+}
+
+impl From<Fetched> for Outputs {
+    fn from(v: Fetched) -> Self {
+        Outputs::Fetched(v)
+    }
+}
+
+impl TwoPhase for Outputs {
+    fn ack(id: OpId) -> Self {
+        Outputs::Accepted(id)
+    }
+
+    fn is_ack(&self) -> Option<OpId> {
+        match self {
+            Outputs::Accepted(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    fn is_completion(&self) -> Option<OpId> {
+        match self {
+            Outputs::Fetched(fetched) => Some(fetched.id),
+            _ => None,
+        }
+    }
+}
+
+struct Response(Result<HttpResponse, String>);
+
+impl Select<Response> for Outputs {
+    fn take(output: &Context<Self>) -> Option<Response> {
+        match output.take()? {
+            Outputs::Fetched(fetched) => Some(Response(fetched.result)),
+            _ => None,
+        }
+    }
+}
+
+#[wasm_bindgen_test]
+async fn fetch_handler_mocked() {
+    js_sys::eval(
+        "globalThis.fetch = (url, init) => Promise.resolve({ \
+            status: url.endsWith('/missing') ? 404 : 200, \
+            text: () => Promise.resolve(init.method + ' ' + url + ' ' + (init.body || '')), \
+        })",
+    )
+    .unwrap();
+
+    let (fetch, completer) = FetchHandler::<Outputs>::new();
+    let results = Rc::new(RefCell::new(Vec::new()));
+    let computation = {
+        let results = results.clone();
+        move |context: Context<Outputs>| {
+            move || {
+                let get: Operation<Response> = perform_two_phase!(
+                    Effects::Http(HttpEffect::Get("/missing".to_string())),
+                    &context
+                );
+                let post: Operation<Response> = perform_two_phase!(
+                    Effects::Http(HttpEffect::Post("/count".to_string(), "1".to_string())),
+                    &context
+                );
+                let Response(post) = await_done!(post, &context, Effects::Idle);
+                let Response(get) = await_done!(get, &context, Effects::Idle);
+                results.borrow_mut().push(post.unwrap());
+                results.borrow_mut().push(get.unwrap());
+            }
+        }
+    };
+    let mut block = computation
+        .into_block()
+        .add_source(completer.source())
+        .add_handler(fetch);
+    loop {
+        match block.resume() {
+            GeneratorState::Yielded(Effects::Idle) => {
+                JsFuture::from(Promise::resolve(&JsValue::NULL))
+                    .await
+                    .unwrap();
+            },
+            GeneratorState::Yielded(e) => panic!("unhandled: {:?}", e),
+            GeneratorState::Complete(()) => break,
+        }
+    }
+    assert_eq!(
+        *results.borrow(),
+        vec![
+            HttpResponse {
+                status: 200,
+                body: "POST /count 1".to_string(),
+            },
+            HttpResponse {
+                status: 404,
+                body: "GET /missing ".to_string(),
+            },
+        ],
+    );
+}